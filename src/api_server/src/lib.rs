@@ -266,12 +266,22 @@ impl ApiServer {
                     &METRICS.latencies_us.diff_create_snapshot,
                     "create diff snapshot",
                 )),
+                SnapshotType::Live => Some((
+                    &METRICS.latencies_us.live_create_snapshot,
+                    "create live snapshot",
+                )),
             },
             VmmAction::LoadSnapshot(_) => {
                 Some((&METRICS.latencies_us.load_snapshot, "load snapshot"))
             }
             VmmAction::Pause => Some((&METRICS.latencies_us.pause_vm, "pause vm")),
             VmmAction::Resume => Some((&METRICS.latencies_us.resume_vm, "resume vm")),
+            VmmAction::SendMigration(_) => {
+                Some((&METRICS.latencies_us.send_migration, "send migration"))
+            }
+            VmmAction::ReceiveMigration(_) => {
+                Some((&METRICS.latencies_us.receive_migration, "receive migration"))
+            }
             _ => None,
         };
 
@@ -319,7 +329,7 @@ mod tests {
     use vmm::rpc_interface::VmmActionError;
     use vmm::seccomp_filters::{get_filters, SeccompConfig};
     use vmm::vmm_config::instance_info::InstanceInfo;
-    use vmm::vmm_config::snapshot::CreateSnapshotParams;
+    use vmm::vmm_config::snapshot::{CreateSnapshotParams, MemFileFormat};
 
     use super::*;
 
@@ -355,6 +365,8 @@ mod tests {
                 snapshot_type: SnapshotType::Diff,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                mem_file_format: MemFileFormat::Raw,
+                diff_manifest_path: None,
                 version: None,
             })),
             start_time_us,
@@ -369,6 +381,8 @@ mod tests {
                 snapshot_type: SnapshotType::Diff,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                mem_file_format: MemFileFormat::Raw,
+                diff_manifest_path: None,
                 version: None,
             })),
             start_time_us,
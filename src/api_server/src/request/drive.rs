@@ -67,14 +67,17 @@ pub(crate) fn parse_patch_drive(
     // Validate request - we need to have at least one parameter set:
     // - path_on_host
     // - rate_limiter
+    // - refresh_size
     if block_device_update_cfg.path_on_host.is_none()
         && block_device_update_cfg.rate_limiter.is_none()
+        && !block_device_update_cfg.refresh_size
     {
         METRICS.patch_api_requests.drive_fails.inc();
         return Err(Error::Generic(
             StatusCode::BadRequest,
             String::from(
-                "Please specify at least one property to patch: path_on_host, rate_limiter.",
+                "Please specify at least one property to patch: path_on_host, rate_limiter, \
+                 refresh_size.",
             ),
         ));
     }
@@ -210,6 +213,27 @@ mod tests {
         }"#;
         // Validate that parse_patch_drive fails for invalid rate limiter cfg.
         assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_err());
+
+        let body = r#"{
+            "drive_id": "foo",
+            "refresh_size": true
+        }"#;
+        // Validate that refreshing just the size works.
+        #[allow(clippy::match_wild_err_arm)]
+        match vmm_action_from_request(parse_patch_drive(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::UpdateBlockDevice(cfg) => {
+                assert!(cfg.refresh_size);
+                assert!(cfg.path_on_host.is_none());
+            }
+            _ => panic!("Test failed: Invalid parameters"),
+        };
+
+        let body = r#"{
+            "drive_id": "foo",
+            "refresh_size": false
+        }"#;
+        // Must fail since there is nothing to update.
+        assert!(parse_patch_drive(&Body::new(body), Some(&"foo")).is_err());
     }
 
     #[test]
@@ -245,6 +269,9 @@ mod tests {
                 "is_read_only": true,
                 "cache_type": "Unsafe",
                 "io_engine": "Sync",
+                "num_queues": 4,
+                "image_format": "Qcow2",
+                "overlay_path_on_host": "overlay",
                 "rate_limiter": {
                     "bandwidth": {
                         "size": 0,
@@ -0,0 +1,99 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use vmm::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+use crate::request::{Body, Method, StatusCode};
+
+pub(crate) fn parse_put_migration(
+    body: &Body,
+    request_type_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    match request_type_from_path {
+        Some(&request_type) => match request_type {
+            "send" => Ok(ParsedRequest::new_sync(VmmAction::SendMigration(
+                serde_json::from_slice::<SendMigrationParams>(body.raw())?,
+            ))),
+            "receive" => Ok(ParsedRequest::new_sync(VmmAction::ReceiveMigration(
+                serde_json::from_slice::<ReceiveMigrationParams>(body.raw())?,
+            ))),
+            _ => Err(Error::InvalidPathMethod(
+                format!("/migration/{}", request_type),
+                Method::Put,
+            )),
+        },
+        None => Err(Error::Generic(
+            StatusCode::BadRequest,
+            "Missing migration operation type.".to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_put_migration() {
+        let body = r#"{
+                "socket_path": "foo"
+              }"#;
+        let expected_cfg = SendMigrationParams {
+            socket_path: Some(PathBuf::from("foo")),
+            socket_fd: None,
+        };
+        match vmm_action_from_request(parse_put_migration(&Body::new(body), Some(&"send")).unwrap())
+        {
+            VmmAction::SendMigration(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{
+                "socket_path": "foo",
+                "resume_vm": true
+              }"#;
+        let expected_cfg = ReceiveMigrationParams {
+            socket_path: Some(PathBuf::from("foo")),
+            socket_fd: None,
+            enable_diff_snapshots: false,
+            resume_vm: true,
+        };
+        match vmm_action_from_request(
+            parse_put_migration(&Body::new(body), Some(&"receive")).unwrap(),
+        ) {
+            VmmAction::ReceiveMigration(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{
+                "socket_fd": 3
+              }"#;
+        let expected_cfg = SendMigrationParams {
+            socket_path: None,
+            socket_fd: Some(3),
+        };
+        match vmm_action_from_request(parse_put_migration(&Body::new(body), Some(&"send")).unwrap())
+        {
+            VmmAction::SendMigration(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        // Unknown fields are rejected.
+        let body = r#"{
+                "socket_path": "foo",
+                "resume_vm": true
+              }"#;
+        assert!(parse_put_migration(&Body::new(body), Some(&"send")).is_err());
+
+        let body = r#"{
+                "socket_path": "foo"
+              }"#;
+        assert!(parse_put_migration(&Body::new(body), Some(&"invalid")).is_err());
+        assert!(parse_put_migration(&Body::new(body), None).is_err());
+    }
+}
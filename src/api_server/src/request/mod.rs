@@ -9,9 +9,11 @@ pub mod instance_info;
 pub mod logger;
 pub mod machine_configuration;
 pub mod metrics;
+pub mod migration;
 pub mod mmds;
 pub mod net;
 pub mod snapshot;
+pub mod unplug;
 pub mod version;
 pub mod vsock;
 pub use micro_http::{
@@ -2,7 +2,9 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use logger::{IncMetric, METRICS};
-use vmm::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceUpdateConfig};
+use vmm::vmm_config::net::{
+    NetworkCaptureConfig, NetworkInterfaceConfig, NetworkInterfaceUpdateConfig,
+};
 
 use super::super::VmmAction;
 use crate::parsed_request::{checked_id, Error, ParsedRequest};
@@ -40,6 +42,39 @@ pub(crate) fn parse_put_net(
     )))
 }
 
+pub(crate) fn parse_put_net_capture(
+    body: &Body,
+    id_from_path: Option<&&str>,
+) -> Result<ParsedRequest, Error> {
+    METRICS.put_api_requests.network_count.inc();
+    let id = if let Some(id) = id_from_path {
+        checked_id(id)?
+    } else {
+        METRICS.put_api_requests.network_fails.inc();
+        return Err(Error::EmptyID);
+    };
+
+    let capture_cfg =
+        serde_json::from_slice::<NetworkCaptureConfig>(body.raw()).map_err(|err| {
+            METRICS.put_api_requests.network_fails.inc();
+            err
+        })?;
+    if id != capture_cfg.iface_id {
+        METRICS.put_api_requests.network_fails.inc();
+        return Err(Error::Generic(
+            StatusCode::BadRequest,
+            format!(
+                "The id from the path [{}] does not match the id from the body [{}]!",
+                id,
+                capture_cfg.iface_id.as_str()
+            ),
+        ));
+    }
+    Ok(ParsedRequest::new_sync(VmmAction::SetNetworkCapture(
+        capture_cfg,
+    )))
+}
+
 pub(crate) fn parse_patch_net(
     body: &Body,
     id_from_path: Option<&&str>,
@@ -118,6 +153,32 @@ mod tests {
         assert!(parse_put_net(&Body::new(body), Some(&"foo")).is_err());
     }
 
+    #[test]
+    fn test_parse_put_net_capture_request() {
+        let body = r#"{
+                "iface_id": "foo",
+                "state": "Started",
+                "path_on_host": "/tmp/foo.pcap",
+                "max_bytes": 1048576
+              }"#;
+        assert!(parse_put_net_capture(&Body::new(body), Some(&"bar")).is_err());
+        assert!(parse_put_net_capture(&Body::new(body), None).is_err());
+
+        let capture_cfg = serde_json::from_str::<NetworkCaptureConfig>(body).unwrap();
+        match vmm_action_from_request(
+            parse_put_net_capture(&Body::new(body), Some(&"foo")).unwrap(),
+        ) {
+            VmmAction::SetNetworkCapture(cfg) => assert_eq!(cfg, capture_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        // The state of the capture is required.
+        let body = r#"{
+                "iface_id": "foo"
+              }"#;
+        assert!(parse_put_net_capture(&Body::new(body), Some(&"foo")).is_err());
+    }
+
     #[test]
     fn test_parse_patch_net_request() {
         let body = r#"{
@@ -157,5 +218,18 @@ mod tests {
             }
         }"#;
         assert!(parse_patch_net(&Body::new(body), Some(&"foo")).is_err());
+
+        // 5. Success case for the link state.
+        let body = r#"{
+                "iface_id": "foo",
+                "link_up": false
+        }"#;
+        match vmm_action_from_request(parse_patch_net(&Body::new(body), Some(&"foo")).unwrap()) {
+            VmmAction::UpdateNetworkInterface(netif) => {
+                assert_eq!(netif.link_up, Some(false));
+                assert!(netif.rx_rate_limiter.is_none());
+            }
+            _ => panic!("Test failed."),
+        }
     }
 }
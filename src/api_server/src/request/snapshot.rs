@@ -21,6 +21,10 @@ pub const MISSING_FIELD: &str =
 /// Only specifying one of them is allowed.
 pub const TOO_MANY_FIELDS: &str =
     "too many fields: either `mem_backend` or `mem_file_path` exclusively is required";
+/// The `diff_layers` field has been specified for a backend type other than `Layered`,
+/// or is empty for the `Layered` backend type.
+pub const INVALID_DIFF_LAYERS: &str =
+    "invalid field: `diff_layers` is required by, and only allowed for, the `Layered` backend type";
 
 pub(crate) fn parse_put_snapshot(
     body: &Body,
@@ -66,6 +70,16 @@ fn parse_put_snapshot_load(body: &Body) -> Result<ParsedRequest, Error> {
         _ => {}
     }
 
+    // Ensure that diff layers are only given to the layered backend, which requires them.
+    if let Some(backend_cfg) = &snapshot_config.mem_backend {
+        let is_layered = backend_cfg.backend_type == MemBackendType::Layered;
+        if is_layered == backend_cfg.diff_layers.is_empty() {
+            return Err(Error::SerdeJson(serde_json::Error::custom(
+                INVALID_DIFF_LAYERS,
+            )));
+        }
+    }
+
     // Check for the presence of deprecated `mem_file_path` field and create
     // deprecation message if found.
     let mut deprecation_message = None;
@@ -85,6 +99,7 @@ fn parse_put_snapshot_load(body: &Body) -> Result<ParsedRequest, Error> {
                 // either `mem_file_path` or `mem_backend` field is always specified.
                 backend_path: snapshot_config.mem_file_path.unwrap(),
                 backend_type: MemBackendType::File,
+                diff_layers: vec![],
             }
         }
     };
@@ -94,6 +109,8 @@ fn parse_put_snapshot_load(body: &Body) -> Result<ParsedRequest, Error> {
         mem_backend,
         enable_diff_snapshots: snapshot_config.enable_diff_snapshots,
         resume_vm: snapshot_config.resume_vm,
+        device_overrides: snapshot_config.device_overrides,
+        clone_identity: snapshot_config.clone_identity,
     };
 
     // Construct the `ParsedRequest` object.
@@ -109,7 +126,10 @@ fn parse_put_snapshot_load(body: &Body) -> Result<ParsedRequest, Error> {
 
 #[cfg(test)]
 mod tests {
-    use vmm::vmm_config::snapshot::{MemBackendConfig, MemBackendType};
+    use utils::net::mac::MacAddr;
+    use vmm::vmm_config::snapshot::{
+        CloneIdentity, DeviceOverrides, MemBackendConfig, MemBackendType, MemLayerConfig,
+    };
 
     use super::*;
     use crate::parsed_request::tests::{depr_action_from_req, vmm_action_from_request};
@@ -118,7 +138,7 @@ mod tests {
     fn test_parse_put_snapshot() {
         use std::path::PathBuf;
 
-        use vmm::vmm_config::snapshot::SnapshotType;
+        use vmm::vmm_config::snapshot::{MemFileFormat, SnapshotType};
 
         let mut body = r#"{
                 "snapshot_type": "Diff",
@@ -131,6 +151,8 @@ mod tests {
             snapshot_type: SnapshotType::Diff,
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
+            mem_file_format: MemFileFormat::Raw,
+            diff_manifest_path: None,
             version: Some(String::from("0.23.0")),
         };
 
@@ -150,6 +172,52 @@ mod tests {
             snapshot_type: SnapshotType::Full,
             snapshot_path: PathBuf::from("foo"),
             mem_file_path: PathBuf::from("bar"),
+            mem_file_format: MemFileFormat::Raw,
+            diff_manifest_path: None,
+            version: None,
+        };
+
+        match vmm_action_from_request(
+            parse_put_snapshot(&Body::new(body), Some(&"create")).unwrap(),
+        ) {
+            VmmAction::CreateSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_type": "Live",
+                "snapshot_path": "foo",
+                "mem_file_path": "bar"
+              }"#;
+
+        expected_cfg = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Live,
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            mem_file_format: MemFileFormat::Raw,
+            diff_manifest_path: None,
+            version: None,
+        };
+
+        match vmm_action_from_request(
+            parse_put_snapshot(&Body::new(body), Some(&"create")).unwrap(),
+        ) {
+            VmmAction::CreateSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_file_path": "bar",
+                "mem_file_format": "Compressed"
+              }"#;
+
+        expected_cfg = CreateSnapshotParams {
+            snapshot_type: SnapshotType::Full,
+            snapshot_path: PathBuf::from("foo"),
+            mem_file_path: PathBuf::from("bar"),
+            mem_file_format: MemFileFormat::Compressed,
+            diff_manifest_path: None,
             version: None,
         };
 
@@ -180,9 +248,12 @@ mod tests {
             mem_backend: MemBackendConfig {
                 backend_path: PathBuf::from("bar"),
                 backend_type: MemBackendType::File,
+                diff_layers: vec![],
             },
             enable_diff_snapshots: false,
             resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            clone_identity: None,
         };
 
         let mut parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
@@ -210,9 +281,12 @@ mod tests {
             mem_backend: MemBackendConfig {
                 backend_path: PathBuf::from("bar"),
                 backend_type: MemBackendType::File,
+                diff_layers: vec![],
             },
             enable_diff_snapshots: true,
             resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            clone_identity: None,
         };
 
         let mut parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
@@ -239,9 +313,12 @@ mod tests {
             mem_backend: MemBackendConfig {
                 backend_path: PathBuf::from("bar"),
                 backend_type: MemBackendType::Uffd,
+                diff_layers: vec![],
             },
             enable_diff_snapshots: false,
             resume_vm: true,
+            device_overrides: DeviceOverrides::default(),
+            clone_identity: None,
         };
 
         let mut parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
@@ -254,6 +331,88 @@ mod tests {
             _ => panic!("Test failed."),
         }
 
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_backend": {
+                    "backend_path": "bar",
+                    "backend_type": "Layered",
+                    "diff_layers": [
+                        {
+                            "mem_file_path": "layer1",
+                            "diff_manifest_path": "layer1.manifest"
+                        },
+                        {
+                            "mem_file_path": "layer2"
+                        }
+                    ]
+                }
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_backend: MemBackendConfig {
+                backend_path: PathBuf::from("bar"),
+                backend_type: MemBackendType::Layered,
+                diff_layers: vec![
+                    MemLayerConfig {
+                        mem_file_path: PathBuf::from("layer1"),
+                        diff_manifest_path: Some(PathBuf::from("layer1.manifest")),
+                    },
+                    MemLayerConfig {
+                        mem_file_path: PathBuf::from("layer2"),
+                        diff_manifest_path: None,
+                    },
+                ],
+            },
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            clone_identity: None,
+        };
+
+        let parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
+        match vmm_action_from_request(parsed_request) {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        // Diff layers are required by the layered backend.
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_backend": {
+                    "backend_path": "bar",
+                    "backend_type": "Layered"
+                }
+              }"#;
+
+        assert_eq!(
+            parse_put_snapshot(&Body::new(body), Some(&"load"))
+                .err()
+                .unwrap()
+                .to_string(),
+            Error::SerdeJson(serde_json::Error::custom(INVALID_DIFF_LAYERS.to_string()))
+                .to_string()
+        );
+
+        // Diff layers are only allowed for the layered backend.
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_backend": {
+                    "backend_path": "bar",
+                    "backend_type": "File",
+                    "diff_layers": [{ "mem_file_path": "layer1" }]
+                }
+              }"#;
+
+        assert_eq!(
+            parse_put_snapshot(&Body::new(body), Some(&"load"))
+                .err()
+                .unwrap()
+                .to_string(),
+            Error::SerdeJson(serde_json::Error::custom(INVALID_DIFF_LAYERS.to_string()))
+                .to_string()
+        );
+
         body = r#"{
                 "snapshot_path": "foo",
                 "mem_file_path": "bar",
@@ -265,9 +424,12 @@ mod tests {
             mem_backend: MemBackendConfig {
                 backend_path: PathBuf::from("bar"),
                 backend_type: MemBackendType::File,
+                diff_layers: vec![],
             },
             enable_diff_snapshots: false,
             resume_vm: true,
+            device_overrides: DeviceOverrides::default(),
+            clone_identity: None,
         };
 
         let parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
@@ -276,6 +438,81 @@ mod tests {
             _ => panic!("Test failed."),
         }
 
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_backend": {
+                    "backend_path": "bar",
+                    "backend_type": "File"
+                },
+                "device_overrides": {
+                    "drives": { "rootfs": "/srv/clone1/rootfs.ext4" },
+                    "network_interfaces": { "eth0": "tap1" },
+                    "vsock_uds_path": "/srv/clone1/v.sock"
+                }
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_backend: MemBackendConfig {
+                backend_path: PathBuf::from("bar"),
+                backend_type: MemBackendType::File,
+                diff_layers: vec![],
+            },
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            device_overrides: DeviceOverrides {
+                drives: [("rootfs".to_string(), "/srv/clone1/rootfs.ext4".to_string())].into(),
+                network_interfaces: [("eth0".to_string(), "tap1".to_string())].into(),
+                vsock_uds_path: Some("/srv/clone1/v.sock".to_string()),
+            },
+            clone_identity: None,
+        };
+
+        let parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
+        match vmm_action_from_request(parsed_request) {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        body = r#"{
+                "snapshot_path": "foo",
+                "mem_backend": {
+                    "backend_path": "bar",
+                    "backend_type": "File"
+                },
+                "clone_identity": {
+                    "guest_macs": { "eth0": "06:00:ac:10:00:02" },
+                    "mmds_key": "clone"
+                }
+              }"#;
+
+        expected_cfg = LoadSnapshotParams {
+            snapshot_path: PathBuf::from("foo"),
+            mem_backend: MemBackendConfig {
+                backend_path: PathBuf::from("bar"),
+                backend_type: MemBackendType::File,
+                diff_layers: vec![],
+            },
+            enable_diff_snapshots: false,
+            resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            clone_identity: Some(CloneIdentity {
+                guest_macs: [(
+                    "eth0".to_string(),
+                    MacAddr::parse_str("06:00:ac:10:00:02").unwrap(),
+                )]
+                .into(),
+                vsock_guest_cid: None,
+                mmds_key: Some("clone".to_string()),
+            }),
+        };
+
+        let parsed_request = parse_put_snapshot(&Body::new(body), Some(&"load")).unwrap();
+        match vmm_action_from_request(parsed_request) {
+            VmmAction::LoadSnapshot(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
         body = r#"{
                 "snapshot_path": "foo",
                 "mem_backend": {
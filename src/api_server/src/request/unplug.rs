@@ -0,0 +1,55 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use vmm::vmm_config::hotplug::UnplugDeviceConfig;
+
+use super::super::VmmAction;
+use crate::parsed_request::{Error, ParsedRequest};
+use crate::request::Body;
+
+pub(crate) fn parse_put_unplug(body: &Body) -> Result<ParsedRequest, Error> {
+    Ok(ParsedRequest::new_sync(VmmAction::UnplugDevice(
+        serde_json::from_slice::<UnplugDeviceConfig>(body.raw())?,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsed_request::tests::vmm_action_from_request;
+
+    #[test]
+    fn test_parse_put_unplug() {
+        let body = r#"{
+                "device_type": "Drive",
+                "drive_id": "scratch"
+              }"#;
+        let expected_cfg = UnplugDeviceConfig::Drive {
+            drive_id: "scratch".to_string(),
+        };
+        match vmm_action_from_request(parse_put_unplug(&Body::new(body)).unwrap()) {
+            VmmAction::UnplugDevice(cfg) => assert_eq!(cfg, expected_cfg),
+            _ => panic!("Test failed."),
+        }
+
+        let body = r#"{
+                "device_type": "Vsock"
+              }"#;
+        match vmm_action_from_request(parse_put_unplug(&Body::new(body)).unwrap()) {
+            VmmAction::UnplugDevice(cfg) => assert_eq!(cfg, UnplugDeviceConfig::Vsock),
+            _ => panic!("Test failed."),
+        }
+
+        // The id of the device must match its type.
+        let body = r#"{
+                "device_type": "NetworkInterface",
+                "drive_id": "scratch"
+              }"#;
+        assert!(parse_put_unplug(&Body::new(body)).is_err());
+
+        let body = r#"{
+                "device_type": "Balloon"
+              }"#;
+        assert!(parse_put_unplug(&Body::new(body)).is_err());
+    }
+}
@@ -7,6 +7,7 @@ use std::ops::Range;
 
 use kvm_bindings::kvm_device_attr;
 use kvm_ioctls::DeviceFd;
+use serde::{Serialize, Serializer};
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 
@@ -18,14 +19,14 @@ pub struct GicRegState<T: Versionize> {
 }
 
 /// Structure for serializing the state of the Vgic ICC regs
-#[derive(Debug, Default, Versionize)]
+#[derive(Debug, Default, Serialize, Versionize)]
 pub struct VgicSysRegsState {
     pub main_icc_regs: Vec<GicRegState<u64>>,
     pub ap_icc_regs: Vec<Option<GicRegState<u64>>>,
 }
 
 /// Structure used for serializing the state of the GIC registers.
-#[derive(Debug, Default, Versionize)]
+#[derive(Debug, Default, Serialize, Versionize)]
 pub struct GicState {
     /// The state of the distributor registers.
     pub dist: Vec<GicRegState<u32>>,
@@ -34,12 +35,18 @@ pub struct GicState {
 }
 
 /// Structure used for serializing the state of the GIC registers for a specific vCPU.
-#[derive(Debug, Default, Versionize)]
+#[derive(Debug, Default, Serialize, Versionize)]
 pub struct GicVcpuState {
     pub rdist: Vec<GicRegState<u32>>,
     pub icc: VgicSysRegsState,
 }
 
+impl<T: Versionize + Serialize> Serialize for GicRegState<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        Serialize::serialize(&self.chunks, serializer)
+    }
+}
+
 impl<T: Versionize> Versionize for GicRegState<T> {
     fn serialize<W: std::io::Write>(
         &self,
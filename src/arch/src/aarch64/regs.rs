@@ -10,6 +10,7 @@ use std::{fmt, fs, mem, result, u32};
 
 use kvm_bindings::*;
 use kvm_ioctls::VcpuFd;
+use serde::{Serialize, Serializer};
 use versionize::*;
 use versionize_derive::Versionize;
 use vm_memory::GuestMemoryMmap;
@@ -19,20 +20,31 @@ use super::get_fdt_addr;
 /// Struct describing a saved aarch64 register.
 ///
 /// Used for interacting with `KVM_GET/SET_ONE_REG`.
-#[derive(Debug, Clone, Versionize, PartialEq, Eq)]
+#[derive(Debug, Clone, Versionize, PartialEq, Eq, Serialize)]
 pub struct Aarch64Register {
     /// The KVM register ID.
     ///
     /// See https://docs.kernel.org/virt/kvm/api.html?highlight=kvm_set_one_reg#kvm-set-one-reg
+    #[serde(serialize_with = "serialize_hex")]
     pub id: u64,
 
     /// The value of the register.
     ///
     /// 128 bit wide, as we want to restore the V0-V31 FP SIMD registers,
     /// which are this wide.
+    #[serde(serialize_with = "serialize_hex")]
     pub value: u128,
 }
 
+// Register IDs and values are easier to read in hexadecimal, and 128 bit values
+// do not fit in JSON numbers.
+fn serialize_hex<T: fmt::LowerHex, S: Serializer>(
+    value: &T,
+    serializer: S,
+) -> result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:#x}", value))
+}
+
 /// Errors thrown while setting aarch64 registers.
 #[derive(Debug)]
 pub enum Error {
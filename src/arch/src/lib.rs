@@ -9,6 +9,7 @@
 //! Supported platforms: x86_64 and aarch64.
 use std::{fmt, result};
 
+use serde::Serialize;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 
@@ -38,7 +39,7 @@ pub use crate::x86_64::{
 pub type Result<T> = result::Result<T, Error>;
 
 /// Types of devices that can get attached to this platform.
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Copy, Versionize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Copy, Serialize, Versionize)]
 pub enum DeviceType {
     /// Device Type: Virtio.
     Virtio(u32),
@@ -9,19 +9,23 @@ use std::convert::From;
 use std::fs::{File, OpenOptions};
 use std::io::{Seek, SeekFrom, Write};
 use std::os::linux::fs::MetadataExt;
-use std::path::PathBuf;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::{cmp, result};
 
-use block_io::FileEngine;
-use logger::{error, warn, IncMetric, METRICS};
+use block_io::nbd::NbdUri;
+use block_io::{direct_io, FileEngine, NbdFileEngine, OverlayImage, Qcow2Image};
+use logger::{error, warn, BlockDriveMetrics, IncMetric, METRICS};
 use rate_limiter::{BucketUpdate, RateLimiter};
 use serde::{Deserialize, Serialize};
 use utils::eventfd::EventFd;
 use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
+use utils::time::ClockType;
 use virtio_gen::virtio_blk::{
-    VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_RO, VIRTIO_BLK_ID_BYTES, VIRTIO_F_VERSION_1,
+    VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ,
+    VIRTIO_BLK_F_RO, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_BLK_ID_BYTES, VIRTIO_F_VERSION_1,
 };
 use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::GuestMemoryMmap;
@@ -29,7 +33,10 @@ use vm_memory::GuestMemoryMmap;
 use super::super::{ActivateResult, DeviceState, Queue, VirtioDevice, TYPE_BLOCK};
 use super::io::async_io;
 use super::request::*;
-use super::{io as block_io, Error, CONFIG_SPACE_SIZE, QUEUE_SIZES, SECTOR_SHIFT, SECTOR_SIZE};
+use super::{
+    io as block_io, is_nbd_uri, Error, CONFIG_SPACE_SIZE, MAX_DISCARD_SECTORS, MAX_NUM_QUEUES,
+    QUEUE_SIZE, SECTOR_SHIFT, SECTOR_SIZE,
+};
 use crate::virtio::{IrqTrigger, IrqType};
 
 /// Configuration options for disk caching.
@@ -42,6 +49,11 @@ pub enum CacheType {
     /// flush requests coming from the guest will be performed using
     /// `fsync`.
     Writeback,
+    /// The backing file is opened with `O_DIRECT`, so guest data bypasses
+    /// the host page cache. Flushing mechanic will be advertised to the
+    /// guest driver and flush requests will be performed using `fsync`.
+    /// Only supported for raw disk images without overlay.
+    Direct,
 }
 
 impl Default for CacheType {
@@ -50,7 +62,7 @@ impl Default for CacheType {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum FileEngineType {
     /// Use an Async engine, based on io_uring.
     Async,
@@ -73,11 +85,38 @@ impl FileEngineType {
     }
 }
 
+/// Format of the disk image backing a block device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum ImageFormat {
+    /// The disk image is exposed to the guest as is.
+    Raw,
+    /// The disk image is a qcow2 image, which may have a chain of read-only backing files.
+    /// Only supported by the Sync engine.
+    Qcow2,
+}
+
+impl Default for ImageFormat {
+    fn default() -> Self {
+        Self::Raw
+    }
+}
+
+// Id and size of a disk, and its IO engines.
+type DiskEngines = (
+    [u8; VIRTIO_BLK_ID_BYTES as usize],
+    u64,
+    Vec<FileEngine<PendingRequest>>,
+);
+
 /// Helper object for setting up all `Block` fields derived from its backing file.
 pub(crate) struct DiskProperties {
     cache_type: CacheType,
     file_path: String,
-    file_engine: FileEngine<PendingRequest>,
+    image_format: ImageFormat,
+    overlay_path: Option<String>,
+    // One IO engine per virtio queue, each with its own handle of the backing file or its own
+    // connection to the NBD server, or sharing the qcow2 image or the overlay.
+    file_engines: Vec<FileEngine<PendingRequest>>,
     nsectors: u64,
     image_id: [u8; VIRTIO_BLK_ID_BYTES as usize],
 }
@@ -88,15 +127,39 @@ impl DiskProperties {
         is_disk_read_only: bool,
         cache_type: CacheType,
         file_engine_type: FileEngineType,
+        num_queues: usize,
+        image_format: ImageFormat,
+        overlay_path: Option<String>,
     ) -> result::Result<Self, Error> {
-        let mut disk_image = OpenOptions::new()
-            .read(true)
-            .write(!is_disk_read_only)
-            .open(PathBuf::from(&disk_image_path))
-            .map_err(Error::BackingFile)?;
-        let disk_size = disk_image
-            .seek(SeekFrom::End(0))
-            .map_err(Error::BackingFile)? as u64;
+        // Direct IO needs the alignment of the transfers to be controlled, which is not the case
+        // of the metadata of qcow2 images and overlays.
+        let direct_io = cache_type == CacheType::Direct;
+        if direct_io && (image_format != ImageFormat::Raw || overlay_path.is_some()) {
+            return Err(Error::UnsupportedDirectIo);
+        }
+
+        let (image_id, disk_size, file_engines) = if is_nbd_uri(&disk_image_path) {
+            // NBD exports are accessed through sockets, as raw disks.
+            if direct_io || image_format != ImageFormat::Raw || overlay_path.is_some() {
+                return Err(Error::UnsupportedNbdDrive);
+            }
+            Self::nbd_file_engines(
+                &disk_image_path,
+                is_disk_read_only,
+                file_engine_type,
+                num_queues,
+            )?
+        } else {
+            Self::image_file_engines(
+                &disk_image_path,
+                is_disk_read_only,
+                direct_io,
+                file_engine_type,
+                num_queues,
+                image_format,
+                overlay_path.as_deref(),
+            )?
+        };
 
         // We only support disk size, which uses the first two words of the configuration space.
         // If the image is not a multiple of the sector size, the tail bits are not exposed.
@@ -111,30 +174,212 @@ impl DiskProperties {
         Ok(Self {
             cache_type,
             nsectors: disk_size >> SECTOR_SHIFT,
-            image_id: Self::build_disk_image_id(&disk_image),
+            image_id,
             file_path: disk_image_path,
-            file_engine: FileEngine::from_file(disk_image, file_engine_type)
-                .map_err(Error::FileEngine)?,
+            image_format,
+            overlay_path,
+            file_engines,
         })
     }
 
-    pub fn file_engine(&self) -> &FileEngine<PendingRequest> {
-        &self.file_engine
+    // Opens a disk image file, and returns its id, its size, and an engine per queue.
+    fn image_file_engines(
+        disk_image_path: &str,
+        is_disk_read_only: bool,
+        direct_io: bool,
+        file_engine_type: FileEngineType,
+        num_queues: usize,
+        image_format: ImageFormat,
+        overlay_path: Option<&str>,
+    ) -> result::Result<DiskEngines, Error> {
+        // The disk image is never written when the writes are redirected to an overlay.
+        let disk_image = OpenOptions::new()
+            .read(true)
+            .write(!is_disk_read_only && overlay_path.is_none())
+            .custom_flags(if direct_io { libc::O_DIRECT } else { 0 })
+            .open(PathBuf::from(disk_image_path))
+            .map_err(Error::BackingFile)?;
+
+        let engines = match (image_format, overlay_path) {
+            (ImageFormat::Raw, None) => {
+                let image_id = Self::build_disk_image_id(&disk_image);
+                let (disk_size, file_engines) =
+                    Self::raw_file_engines(disk_image, file_engine_type, num_queues, direct_io)?;
+                (image_id, disk_size, file_engines)
+            }
+            (ImageFormat::Qcow2, None) => {
+                let image_id = Self::build_disk_image_id(&disk_image);
+                let (disk_size, file_engines) = Self::qcow2_file_engines(
+                    disk_image,
+                    Path::new(disk_image_path),
+                    is_disk_read_only,
+                    file_engine_type,
+                    num_queues,
+                )?;
+                (image_id, disk_size, file_engines)
+            }
+            (ImageFormat::Raw, Some(overlay_path)) => {
+                let overlay = OpenOptions::new()
+                    .read(true)
+                    .write(!is_disk_read_only)
+                    .open(PathBuf::from(overlay_path))
+                    .map_err(Error::BackingFile)?;
+                // The disks sharing a base image are told apart by their overlay.
+                let image_id = Self::build_disk_image_id(&overlay);
+                let (disk_size, file_engines) = Self::overlay_file_engines(
+                    disk_image,
+                    overlay,
+                    is_disk_read_only,
+                    file_engine_type,
+                    num_queues,
+                )?;
+                (image_id, disk_size, file_engines)
+            }
+            (ImageFormat::Qcow2, Some(_)) => return Err(Error::UnsupportedOverlayFormat),
+        };
+        Ok(engines)
+    }
+
+    // Connects to an NBD export, and returns its id, its size, and an engine per queue, each with
+    // its own connection to the server.
+    fn nbd_file_engines(
+        uri: &str,
+        is_disk_read_only: bool,
+        file_engine_type: FileEngineType,
+        num_queues: usize,
+    ) -> result::Result<DiskEngines, Error> {
+        let nbd_error = |err| Error::FileEngine(block_io::Error::Nbd(err));
+        let uri = NbdUri::parse(uri).map_err(nbd_error)?;
+
+        let mut disk_size = 0;
+        let mut file_engines = Vec::with_capacity(num_queues);
+        for _ in 0..num_queues {
+            let engine = NbdFileEngine::connect(&uri, is_disk_read_only).map_err(nbd_error)?;
+            // The writes of a connection are only guaranteed to be seen, and flushed, by the
+            // other connections if the server allows several of them.
+            if num_queues > 1 && !engine.supports_multi_conn() {
+                return Err(nbd_error(block_io::nbd::Error::MultiConnUnsupported));
+            }
+            disk_size = engine.size();
+            file_engines.push(
+                FileEngine::from_nbd_engine(engine, file_engine_type).map_err(Error::FileEngine)?,
+            );
+        }
+        Ok((Self::build_nbd_image_id(&uri), disk_size, file_engines))
+    }
+
+    // Returns the size of a raw disk image, and an engine per queue, each with its own handle of
+    // the image file.
+    fn raw_file_engines(
+        mut disk_image: File,
+        file_engine_type: FileEngineType,
+        num_queues: usize,
+        direct_io: bool,
+    ) -> result::Result<(u64, Vec<FileEngine<PendingRequest>>), Error> {
+        let disk_size = disk_image
+            .seek(SeekFrom::End(0))
+            .map_err(Error::BackingFile)? as u64;
+        let direct_io_alignment = if direct_io {
+            Some(direct_io::alignment(&disk_image).map_err(Error::BackingFile)?)
+        } else {
+            None
+        };
+
+        let mut file_engines = Vec::with_capacity(num_queues);
+        for _ in 1..num_queues {
+            let file = disk_image.try_clone().map_err(Error::BackingFile)?;
+            file_engines.push(
+                FileEngine::from_file(file, file_engine_type, direct_io_alignment)
+                    .map_err(Error::FileEngine)?,
+            );
+        }
+        file_engines.push(
+            FileEngine::from_file(disk_image, file_engine_type, direct_io_alignment)
+                .map_err(Error::FileEngine)?,
+        );
+        Ok((disk_size, file_engines))
+    }
+
+    // Returns the virtual size of a qcow2 disk image, and an engine per queue, all sharing the
+    // image.
+    fn qcow2_file_engines(
+        disk_image: File,
+        disk_image_path: &Path,
+        is_disk_read_only: bool,
+        file_engine_type: FileEngineType,
+        num_queues: usize,
+    ) -> result::Result<(u64, Vec<FileEngine<PendingRequest>>), Error> {
+        let image = Qcow2Image::from_file(disk_image, disk_image_path, is_disk_read_only)
+            .map_err(|err| Error::FileEngine(block_io::Error::Qcow2(err)))?;
+        let disk_size = image.size();
+
+        let image = Arc::new(Mutex::new(image));
+        let file_engines = (0..num_queues)
+            .map(|_| FileEngine::from_qcow2_image(image.clone(), file_engine_type))
+            .collect::<result::Result<Vec<_>, _>>()
+            .map_err(Error::FileEngine)?;
+        Ok((disk_size, file_engines))
+    }
+
+    // Returns the size of the base image, and an engine per queue, all sharing the overlay.
+    fn overlay_file_engines(
+        base_image: File,
+        overlay: File,
+        is_disk_read_only: bool,
+        file_engine_type: FileEngineType,
+        num_queues: usize,
+    ) -> result::Result<(u64, Vec<FileEngine<PendingRequest>>), Error> {
+        let image = OverlayImage::from_files(base_image, overlay, is_disk_read_only)
+            .map_err(|err| Error::FileEngine(block_io::Error::Overlay(err)))?;
+        let disk_size = image.size();
+
+        let image = Arc::new(Mutex::new(image));
+        let file_engines = (0..num_queues)
+            .map(|_| FileEngine::from_overlay_image(image.clone(), file_engine_type))
+            .collect::<result::Result<Vec<_>, _>>()
+            .map_err(Error::FileEngine)?;
+        Ok((disk_size, file_engines))
+    }
+
+    pub fn file_engines(&self) -> &[FileEngine<PendingRequest>] {
+        &self.file_engines
     }
 
-    pub fn file_engine_mut(&mut self) -> &mut FileEngine<PendingRequest> {
-        &mut self.file_engine
+    pub fn file_engine_mut(&mut self, queue_index: usize) -> &mut FileEngine<PendingRequest> {
+        &mut self.file_engines[queue_index]
     }
 
     #[cfg(test)]
     pub fn file(&self) -> &File {
-        self.file_engine.file()
+        self.file_engines[0]
+            .file()
+            .expect("The backing file is shared by the engines.")
     }
 
     pub fn nsectors(&self) -> u64 {
         self.nsectors
     }
 
+    /// Re-reads the size of a raw disk image, which may have been resized on the host.
+    pub fn update_disk_size(&mut self) -> result::Result<(), Error> {
+        // The virtual size of qcow2 images and overlays is recorded in their headers.
+        let file = match (self.image_format, &self.overlay_path) {
+            (ImageFormat::Raw, None) => self.file_engines[0].file(),
+            _ => None,
+        }
+        .ok_or(Error::UnsupportedResize)?;
+        let disk_size = file.metadata().map_err(Error::GetFileMetadata)?.len();
+        if disk_size % SECTOR_SIZE != 0 {
+            warn!(
+                "Disk size {} is not a multiple of sector size {}; the remainder will not be \
+                 visible to the guest.",
+                disk_size, SECTOR_SIZE
+            );
+        }
+        self.nsectors = disk_size >> SECTOR_SHIFT;
+        Ok(())
+    }
+
     pub fn image_id(&self) -> &[u8] {
         &self.image_id
     }
@@ -168,20 +413,67 @@ impl DiskProperties {
         default_id
     }
 
+    fn build_nbd_image_id(uri: &NbdUri) -> [u8; VIRTIO_BLK_ID_BYTES as usize] {
+        // The export name identifies the disk, unless it is the default export.
+        let mut image_id = [0; VIRTIO_BLK_ID_BYTES as usize];
+        let export_name = match uri.export_name.as_str() {
+            "" => "nbd",
+            export_name => export_name,
+        };
+        let bytes_to_copy = cmp::min(export_name.len(), VIRTIO_BLK_ID_BYTES as usize);
+        image_id[..bytes_to_copy].copy_from_slice(&export_name.as_bytes()[..bytes_to_copy]);
+        image_id
+    }
+
+    /// Whether discard and write zeroes requests are supported, which is only the case of raw
+    /// disk image files without overlay, and of NBD exports accepting write zeroes requests.
+    pub fn supports_discard(&self) -> bool {
+        match &self.file_engines[0] {
+            FileEngine::Nbd(engine) => engine.supports_write_zeroes(),
+            _ => self.image_format == ImageFormat::Raw && self.overlay_path.is_none(),
+        }
+    }
+
     /// Backing file path.
     pub fn file_path(&self) -> &String {
         &self.file_path
     }
 
+    /// Format of the backing file.
+    pub fn image_format(&self) -> ImageFormat {
+        self.image_format
+    }
+
+    /// Path of the file the writes are redirected to, if any.
+    pub fn overlay_path(&self) -> Option<&String> {
+        self.overlay_path.as_ref()
+    }
+
+    /// The block size the guest must use, when the transfers to the backing file are direct IO.
+    pub fn blk_size(&self) -> Option<u32> {
+        self.file_engines[0].direct_io_alignment()
+    }
+
     /// Provides vec containing the virtio block configuration space
     /// buffer. The config space is populated with the disk size based
-    /// on the backing file size.
+    /// on the backing file size, with the block size, with the number
+    /// of queues, and with the limits of discard and write zeroes requests.
     pub fn virtio_block_config_space(&self) -> Vec<u8> {
         // The config space is little endian.
-        let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
-        for i in 0..CONFIG_SPACE_SIZE {
-            config.push((self.nsectors >> (8 * i)) as u8);
+        let mut config = vec![0u8; CONFIG_SPACE_SIZE];
+        config[0..8].copy_from_slice(&self.nsectors.to_le_bytes());
+        if let Some(blk_size) = self.blk_size() {
+            config[20..24].copy_from_slice(&blk_size.to_le_bytes());
         }
+        config[34..36].copy_from_slice(&(self.file_engines.len() as u16).to_le_bytes());
+        // max_discard_sectors, max_discard_seg and discard_sector_alignment.
+        config[36..40].copy_from_slice(&MAX_DISCARD_SECTORS.to_le_bytes());
+        config[40..44].copy_from_slice(&1u32.to_le_bytes());
+        config[44..48].copy_from_slice(&1u32.to_le_bytes());
+        // max_write_zeroes_sectors, max_write_zeroes_seg and write_zeroes_may_unmap.
+        config[48..52].copy_from_slice(&MAX_DISCARD_SECTORS.to_le_bytes());
+        config[52..56].copy_from_slice(&1u32.to_le_bytes());
+        config[56] = 1;
         config
     }
 
@@ -203,7 +495,7 @@ pub struct Block {
 
     // Transport related fields.
     pub(crate) queues: Vec<Queue>,
-    pub(crate) queue_evts: [EventFd; 1],
+    pub(crate) queue_evts: Vec<EventFd>,
     pub(crate) device_state: DeviceState,
     pub(crate) irq_trigger: IrqTrigger,
 
@@ -212,14 +504,27 @@ pub struct Block {
     pub(crate) partuuid: Option<String>,
     pub(crate) root_device: bool,
     pub(crate) rate_limiter: RateLimiter,
-    is_io_engine_throttled: bool,
+    // Whether the IO engine of each queue is full and must complete requests first.
+    is_io_engine_throttled: Vec<bool>,
+    // Monotonic timestamps, in microseconds, of the moment the queues got throttled, by the
+    // rate limiter or by each IO engine.
+    rate_limiter_throttled_since_us: Option<u64>,
+    io_engine_throttled_since_us: Vec<u64>,
+    pub(crate) metrics: Arc<BlockDriveMetrics>,
+}
+
+fn elapsed_time_us(start_time_us: u64) -> u64 {
+    utils::time::get_time_us(ClockType::Monotonic).saturating_sub(start_time_us)
 }
 
 macro_rules! unwrap_async_file_engine_or_return {
     ($file_engine: expr) => {
         match $file_engine {
             FileEngine::Async(engine) => engine,
-            FileEngine::Sync(_) => {
+            FileEngine::Sync(_)
+            | FileEngine::Qcow2(_)
+            | FileEngine::Overlay(_)
+            | FileEngine::Nbd(_) => {
                 error!("The block device doesn't use an async IO engine");
                 return;
             }
@@ -228,9 +533,12 @@ macro_rules! unwrap_async_file_engine_or_return {
 }
 
 impl Block {
-    /// Create a new virtio block device that operates on the given file.
+    /// Create a new virtio block device that operates on the given file, with
+    /// `num_queues` virtio queues.
     ///
-    /// The given file must be seekable and sizable.
+    /// The given file must be seekable and sizable, and hold a disk image in
+    /// `image_format`. When an `overlay_path` is given, the writes are redirected
+    /// to that file and the given file is only read.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
@@ -241,29 +549,55 @@ impl Block {
         is_disk_root: bool,
         rate_limiter: RateLimiter,
         file_engine_type: FileEngineType,
+        num_queues: usize,
+        image_format: ImageFormat,
+        overlay_path: Option<String>,
     ) -> result::Result<Block, Error> {
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+
         let disk_properties = DiskProperties::new(
             disk_image_path,
             is_disk_read_only,
             cache_type,
             file_engine_type,
+            num_queues,
+            image_format,
+            overlay_path,
         )?;
 
         let mut avail_features = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
 
-        if cache_type == CacheType::Writeback {
+        if cache_type == CacheType::Writeback || cache_type == CacheType::Direct {
             avail_features |= 1u64 << VIRTIO_BLK_F_FLUSH;
         }
 
         if is_disk_read_only {
             avail_features |= 1u64 << VIRTIO_BLK_F_RO;
+        } else if disk_properties.supports_discard() {
+            avail_features |= (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
         };
 
-        let queue_evts = [EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?];
+        if num_queues > 1 {
+            avail_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
+
+        // Direct IO transfers must be aligned to the logical block size of the host storage, so
+        // the guest is told to use it as its own.
+        if disk_properties.blk_size().is_some() {
+            avail_features |= 1u64 << VIRTIO_BLK_F_BLK_SIZE;
+        }
+
+        let mut queue_evts = Vec::with_capacity(num_queues);
+        for _ in 0..num_queues {
+            queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
+        }
 
-        let queues = QUEUE_SIZES.iter().map(|&s| Queue::new(s)).collect();
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
 
         Ok(Block {
+            metrics: METRICS.block_drives.get(&id),
             id,
             root_device: is_disk_root,
             partuuid,
@@ -277,35 +611,48 @@ impl Block {
             device_state: DeviceState::Inactive,
             irq_trigger: IrqTrigger::new().map_err(Error::IrqTrigger)?,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
-            is_io_engine_throttled: false,
+            is_io_engine_throttled: vec![false; num_queues],
+            rate_limiter_throttled_since_us: None,
+            io_engine_throttled_since_us: vec![0; num_queues],
         })
     }
 
-    pub(crate) fn process_queue_event(&mut self) {
+    pub(crate) fn process_queue_event(&mut self, queue_index: usize) {
         METRICS.block.queue_event_count.inc();
-        if let Err(err) = self.queue_evts[0].read() {
+        if let Err(err) = self.queue_evts[queue_index].read() {
             error!("Failed to get queue event: {:?}", err);
             METRICS.block.event_fails.inc();
         } else if self.rate_limiter.is_blocked() {
             METRICS.block.rate_limiter_throttled_events.inc();
-        } else if self.is_io_engine_throttled {
+        } else if self.is_io_engine_throttled[queue_index] {
             METRICS.block.io_engine_throttled_events.inc();
         } else {
-            self.process_virtio_queues();
+            self.process_queue(queue_index);
         }
     }
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        self.process_queue(0);
+        for queue_index in 0..self.queues.len() {
+            self.process_queue(queue_index);
+        }
     }
 
     pub(crate) fn process_rate_limiter_event(&mut self) {
         METRICS.block.rate_limiter_event_count.inc();
         // Upon rate limiter event, call the rate limiter handler
-        // and restart processing the queue.
+        // and restart processing the queues, which share the rate limiter.
         if self.rate_limiter.event_handler().is_ok() {
-            self.process_queue(0);
+            if let Some(throttled_since_us) = self.rate_limiter_throttled_since_us.take() {
+                self.metrics
+                    .rate_limiter_throttled_time_us
+                    .add(elapsed_time_us(throttled_since_us) as usize);
+            }
+            for queue_index in 0..self.queues.len() {
+                if !self.is_io_engine_throttled[queue_index] {
+                    self.process_queue(queue_index);
+                }
+            }
         }
     }
 
@@ -342,11 +689,13 @@ impl Block {
                         // avail ring, for later processing.
                         queue.undo_pop();
                         METRICS.block.rate_limiter_throttled_events.inc();
+                        self.rate_limiter_throttled_since_us
+                            .get_or_insert_with(|| utils::time::get_time_us(ClockType::Monotonic));
                         break;
                     }
 
                     used_any = true;
-                    request.process(&mut self.disk, head.index, mem)
+                    request.process(&mut self.disk, queue_index, head.index, mem, &self.metrics)
                 }
                 Err(err) => {
                     error!("Failed to parse available descriptor chain: {:?}", err);
@@ -362,7 +711,9 @@ impl Block {
                 ProcessingResult::Submitted => {}
                 ProcessingResult::Throttled => {
                     queue.undo_pop();
-                    self.is_io_engine_throttled = true;
+                    self.is_io_engine_throttled[queue_index] = true;
+                    self.io_engine_throttled_since_us[queue_index] =
+                        utils::time::get_time_us(ClockType::Monotonic);
                     break;
                 }
                 ProcessingResult::Executed(finished) => {
@@ -377,10 +728,20 @@ impl Block {
             }
         }
 
-        if let FileEngine::Async(engine) = self.disk.file_engine_mut() {
-            if let Err(err) = engine.kick_submission_queue() {
-                error!("Error submitting pending block requests: {:?}", err);
+        match self.disk.file_engine_mut(queue_index) {
+            FileEngine::Async(engine) => {
+                if let Err(err) = engine.kick_submission_queue() {
+                    error!("Error submitting pending block requests: {:?}", err);
+                }
             }
+            FileEngine::Nbd(engine) => {
+                if let Err(err) = engine.kick_submission_queue() {
+                    error!("Error submitting pending NBD requests: {:?}", err);
+                }
+                // The requests fail right away once the connection is lost.
+                self.process_nbd_completion_queue(queue_index);
+            }
+            FileEngine::Sync(_) | FileEngine::Qcow2(_) | FileEngine::Overlay(_) => (),
         }
 
         if !used_any {
@@ -388,12 +749,12 @@ impl Block {
         }
     }
 
-    fn process_async_completion_queue(&mut self) {
-        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engine);
+    fn process_async_completion_queue(&mut self, queue_index: usize) {
+        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engines[queue_index]);
 
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
-        let queue = &mut self.queues[0];
+        let queue = &mut self.queues[queue_index];
 
         loop {
             match engine.pop(mem) {
@@ -429,19 +790,62 @@ impl Block {
         }
     }
 
-    pub fn process_async_completion_event(&mut self) {
-        let engine = unwrap_async_file_engine_or_return!(&mut self.disk.file_engine);
+    fn process_nbd_completion_queue(&mut self, queue_index: usize) {
+        let engine = match &mut self.disk.file_engines[queue_index] {
+            FileEngine::Nbd(engine) => engine,
+            _ => {
+                error!("The block device doesn't use an NBD engine");
+                return;
+            }
+        };
+
+        // This is safe since we checked in the event handler that the device is activated.
+        let mem = self.device_state.mem().unwrap();
+        let queue = &mut self.queues[queue_index];
+
+        while let Some((pending, res)) = engine.pop(mem) {
+            let res = res.map_err(|err| IoErr::FileEngine(block_io::Error::Nbd(err)));
+            let finished = pending.finish(mem, res);
 
-        if let Err(err) = engine.completion_evt().read() {
-            error!("Failed to get async completion event: {:?}", err);
-        } else {
-            self.process_async_completion_queue();
+            Self::add_used_descriptor(
+                queue,
+                finished.desc_idx,
+                finished.num_bytes_to_mem,
+                mem,
+                &self.irq_trigger,
+            );
+        }
+    }
 
-            if self.is_io_engine_throttled {
-                self.is_io_engine_throttled = false;
-                self.process_queue(0);
+    pub fn process_async_completion_event(&mut self, queue_index: usize) {
+        match &mut self.disk.file_engines[queue_index] {
+            FileEngine::Async(engine) => {
+                if let Err(err) = engine.completion_evt().read() {
+                    error!("Failed to get async completion event: {:?}", err);
+                    return;
+                }
+                self.process_async_completion_queue(queue_index);
+            }
+            FileEngine::Nbd(engine) => {
+                // The pending requests are failed if the connection is lost.
+                if let Err(err) = engine.process_socket_events() {
+                    error!("Failed to communicate with the NBD server: {:?}", err);
+                }
+                self.process_nbd_completion_queue(queue_index);
+            }
+            FileEngine::Sync(_) | FileEngine::Qcow2(_) | FileEngine::Overlay(_) => {
+                error!("The block device doesn't use an async IO engine");
+                return;
             }
         }
+
+        if self.is_io_engine_throttled[queue_index] {
+            self.is_io_engine_throttled[queue_index] = false;
+            self.metrics
+                .io_engine_throttled_time_us
+                .add(elapsed_time_us(self.io_engine_throttled_since_us[queue_index]) as usize);
+            self.process_queue(queue_index);
+        }
     }
 
     /// Update the backing file and the config space of the block device.
@@ -451,6 +855,9 @@ impl Block {
             self.is_read_only(),
             self.cache_type(),
             self.file_engine_type(),
+            self.queues.len(),
+            self.image_format(),
+            self.disk.overlay_path().cloned(),
         )?;
         self.disk = disk_properties;
         self.config_space = self.disk.virtio_block_config_space();
@@ -462,6 +869,19 @@ impl Block {
         Ok(())
     }
 
+    /// Updates the capacity of the device after its disk image was resized on the host, and
+    /// notifies the guest driver.
+    pub fn update_disk_size(&mut self) -> result::Result<(), Error> {
+        self.disk.update_disk_size()?;
+        self.config_space = self.disk.virtio_block_config_space();
+
+        // Kick the driver to pick up the new capacity.
+        self.irq_trigger.trigger_irq(IrqType::Config).unwrap();
+
+        METRICS.block.update_count.inc();
+        Ok(())
+    }
+
     /// Updates the parameters for the rate limiter
     pub fn update_rate_limiter(&mut self, bytes: BucketUpdate, ops: BucketUpdate) {
         self.rate_limiter.update_buckets(bytes, ops);
@@ -503,15 +923,35 @@ impl Block {
     }
 
     pub fn file_engine_type(&self) -> FileEngineType {
-        match self.disk.file_engine() {
-            FileEngine::Sync(_) => FileEngineType::Sync,
+        match self.disk.file_engines()[0] {
+            FileEngine::Sync(_)
+            | FileEngine::Qcow2(_)
+            | FileEngine::Overlay(_)
+            | FileEngine::Nbd(_) => FileEngineType::Sync,
             FileEngine::Async(_) => FileEngineType::Async,
         }
     }
 
+    /// Provides the format of the backing file of this block device.
+    pub fn image_format(&self) -> ImageFormat {
+        self.disk.image_format()
+    }
+
+    /// Provides the path of the file the writes to this block device are redirected to.
+    pub fn overlay_path(&self) -> Option<&String> {
+        self.disk.overlay_path()
+    }
+
+    /// Provides the number of virtio queues of this block device.
+    pub fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
     fn drain_and_flush(&mut self, discard: bool) {
-        if let Err(err) = self.disk.file_engine_mut().drain_and_flush(discard) {
-            error!("Failed to drain ops and flush block data: {:?}", err);
+        for engine in self.disk.file_engines.iter_mut() {
+            if let Err(err) = engine.drain_and_flush(discard) {
+                error!("Failed to drain ops and flush block data: {:?}", err);
+            }
         }
     }
 
@@ -521,8 +961,12 @@ impl Block {
         }
 
         self.drain_and_flush(false);
-        if let FileEngine::Async(_engine) = self.disk.file_engine_mut() {
-            self.process_async_completion_queue();
+        for queue_index in 0..self.queues.len() {
+            match self.disk.file_engines()[queue_index] {
+                FileEngine::Async(_) => self.process_async_completion_queue(queue_index),
+                FileEngine::Nbd(_) => self.process_nbd_completion_queue(queue_index),
+                FileEngine::Sync(_) | FileEngine::Qcow2(_) | FileEngine::Overlay(_) => (),
+            }
         }
     }
 }
@@ -616,14 +1060,17 @@ impl Drop for Block {
     fn drop(&mut self) {
         match self.disk.cache_type {
             CacheType::Unsafe => {
-                if let Err(err) = self.disk.file_engine_mut().drain(true) {
-                    error!("Failed to drain ops on drop: {:?}", err);
+                for engine in self.disk.file_engines.iter_mut() {
+                    if let Err(err) = engine.drain(true) {
+                        error!("Failed to drain ops on drop: {:?}", err);
+                    }
                 }
             }
-            CacheType::Writeback => {
+            CacheType::Writeback | CacheType::Direct => {
                 self.drain_and_flush(true);
             }
         };
+        METRICS.block_drives.release(&self.id);
     }
 }
 
@@ -632,6 +1079,7 @@ pub(crate) mod tests {
     use std::fs::metadata;
     use std::io::Read;
     use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::FileExt;
     use std::time::Duration;
     use std::{thread, u32};
 
@@ -642,10 +1090,14 @@ pub(crate) mod tests {
 
     use super::*;
     use crate::check_metric_after_block;
+    use crate::virtio::block::io::nbd::tests::{TestServer, ALL_FLAGS};
+    use crate::virtio::block::io::overlay::OVERLAY_MAGIC;
+    use crate::virtio::block::io::qcow2::tests::create_image;
+    use crate::virtio::block::io::qcow2::QCOW2_MAGIC;
     use crate::virtio::block::test_utils::{
-        default_block, default_engine_type_for_kv, set_queue, set_rate_limiter,
-        simulate_async_completion_event, simulate_queue_and_async_completion_events,
-        simulate_queue_event,
+        default_block, default_block_with_id, default_engine_type_for_kv, set_queue,
+        set_rate_limiter, simulate_async_completion_event,
+        simulate_queue_and_async_completion_events, simulate_queue_event,
     };
     use crate::virtio::queue::tests::*;
     use crate::virtio::test_utils::{default_mem, initialize_virtqueue, VirtQueue};
@@ -663,6 +1115,9 @@ pub(crate) mod tests {
             true,
             CacheType::Unsafe,
             default_engine_type_for_kv(),
+            1,
+            ImageFormat::Raw,
+            None,
         )
         .unwrap();
 
@@ -670,9 +1125,14 @@ pub(crate) mod tests {
         assert_eq!(disk_properties.nsectors, num_sectors);
         let cfg = disk_properties.virtio_block_config_space();
         assert_eq!(cfg.len(), CONFIG_SPACE_SIZE);
-        for (i, byte) in cfg.iter().enumerate() {
+        for (i, byte) in cfg[0..8].iter().enumerate() {
             assert_eq!(*byte, (num_sectors >> (8 * i)) as u8);
         }
+        assert_eq!(cfg[34..36], 1u16.to_le_bytes());
+        assert_eq!(cfg[36..40], MAX_DISCARD_SECTORS.to_le_bytes());
+        assert_eq!(cfg[40..44], 1u32.to_le_bytes());
+        assert_eq!(cfg[48..52], MAX_DISCARD_SECTORS.to_le_bytes());
+        assert_eq!(cfg[56], 1);
         // Testing `backing_file.virtio_block_disk_image_id()` implies
         // duplicating that logic in tests, so skipping it.
 
@@ -681,6 +1141,9 @@ pub(crate) mod tests {
             true,
             CacheType::Unsafe,
             default_engine_type_for_kv(),
+            1,
+            ImageFormat::Raw,
+            None,
         )
         .is_err());
     }
@@ -691,7 +1154,10 @@ pub(crate) mod tests {
 
         assert_eq!(block.device_type(), TYPE_BLOCK);
 
-        let features: u64 = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_RING_F_EVENT_IDX);
+        let features: u64 = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_BLK_F_DISCARD)
+            | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
 
         assert_eq!(block.avail_features_by_page(0), features as u32);
         assert_eq!(block.avail_features_by_page(1), (features >> 32) as u32);
@@ -710,18 +1176,21 @@ pub(crate) mod tests {
     fn test_virtio_read_config() {
         let block = default_block(default_engine_type_for_kv());
 
-        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        let mut actual_config_space = [0u8; 8];
         block.read_config(0, &mut actual_config_space);
         // This will read the number of sectors.
         // The block's backing file size is 0x1000, so there are 8 (4096/512) sectors.
         // The config space is little endian.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; 8] = [0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(actual_config_space, expected_config_space);
+
+        // This will read the max_discard_sectors and max_discard_seg fields.
+        block.read_config(36, &mut actual_config_space);
+        let expected_config_space: [u8; 8] = [0xff, 0xff, 0x7f, 0x00, 0x01, 0x00, 0x00, 0x00];
         assert_eq!(actual_config_space, expected_config_space);
 
         // Invalid read.
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
+        let expected_config_space: [u8; 8] = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
         actual_config_space = expected_config_space;
         block.read_config(CONFIG_SPACE_SIZE as u64 + 1, &mut actual_config_space);
 
@@ -733,11 +1202,10 @@ pub(crate) mod tests {
     fn test_virtio_write_config() {
         let mut block = default_block(default_engine_type_for_kv());
 
-        let expected_config_space: [u8; CONFIG_SPACE_SIZE] =
-            [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let expected_config_space: [u8; 8] = [0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
         block.write_config(0, &expected_config_space);
 
-        let mut actual_config_space = [0u8; CONFIG_SPACE_SIZE];
+        let mut actual_config_space = [0u8; 8];
         block.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
 
@@ -751,7 +1219,7 @@ pub(crate) mod tests {
 
         // Invalid write.
         let new_config_space = [0xd, 0xe, 0xa, 0xd, 0xb, 0xe, 0xe, 0xf];
-        block.write_config(5, &new_config_space);
+        block.write_config(CONFIG_SPACE_SIZE as u64 - 5, &new_config_space);
         // Make sure nothing got written.
         block.read_config(0, &mut actual_config_space);
         assert_eq!(actual_config_space, expected_config_space);
@@ -1296,6 +1764,515 @@ pub(crate) mod tests {
         }
     }
 
+    #[test]
+    fn test_drive_metrics() {
+        // The other tests share the metrics of the default drive id.
+        let mut block = default_block_with_id(
+            "test_drive_metrics".to_string(),
+            default_engine_type_for_kv(),
+        );
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+
+        // Read.
+        {
+            vq.dtable[1].set(0xf000, 0x1000, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE, 2);
+            mem.write_obj::<u32>(VIRTIO_BLK_T_IN, request_type_addr)
+                .unwrap();
+
+            simulate_queue_and_async_completion_events(&mut block, true);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(block.metrics.read_count.count(), 1);
+            assert_eq!(block.metrics.read_bytes.count(), 0x1000);
+            assert_eq!(block.metrics.read_latency_us.count(), 1);
+            assert_eq!(block.metrics.write_latency_us.count(), 0);
+            assert_eq!(block.metrics.queue_depth.current(), 0);
+        }
+
+        // Flush.
+        {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            vq.dtable[0].next.set(2);
+            mem.write_obj::<u32>(VIRTIO_BLK_T_FLUSH, request_type_addr)
+                .unwrap();
+
+            simulate_queue_and_async_completion_events(&mut block, true);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(block.metrics.flush_count.count(), 1);
+            assert_eq!(block.metrics.flush_latency_us.count(), 1);
+            assert_eq!(block.metrics.read_latency_us.count(), 1);
+            assert_eq!(block.metrics.queue_depth.current(), 0);
+        }
+    }
+
+    #[test]
+    fn test_discard_write_zeroes() {
+        let mut block = default_block(default_engine_type_for_kv());
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+
+        // The data descriptor holds a single read only segment.
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1]
+            .len
+            .set(std::mem::size_of::<DiscardWriteZeroesSegment>() as u32);
+
+        for (request_type, flags, metric) in [
+            (VIRTIO_BLK_T_DISCARD, 0, &METRICS.block.discard_count),
+            (
+                VIRTIO_BLK_T_WRITE_ZEROES,
+                0,
+                &METRICS.block.write_zeroes_count,
+            ),
+            (
+                VIRTIO_BLK_T_WRITE_ZEROES,
+                VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+                &METRICS.block.write_zeroes_count,
+            ),
+        ] {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+
+            block.disk.file().write_all_at(&[0xab; 2048], 0).unwrap();
+
+            mem.write_obj::<u32>(request_type, request_type_addr)
+                .unwrap();
+            // Clear the second and third sectors.
+            mem.write_obj(DiscardWriteZeroesSegment::new(1, 2, flags), data_addr)
+                .unwrap();
+
+            check_metric_after_block!(
+                metric,
+                1,
+                simulate_queue_and_async_completion_events(&mut block, true)
+            );
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            // status byte length.
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(mem.read_obj::<u32>(status_addr).unwrap(), VIRTIO_BLK_S_OK);
+
+            let mut buf = [0u8; 2048];
+            block.disk.file().read_exact_at(&mut buf, 0).unwrap();
+            assert_eq!(buf[..512], [0xab; 512]);
+            assert_eq!(buf[512..1536], [0; 1024]);
+            assert_eq!(buf[1536..], [0xab; 512]);
+            // The size of the disk is unchanged.
+            assert_eq!(block.disk.file().metadata().unwrap().len(), 0x1000);
+        }
+    }
+
+    #[test]
+    fn test_qcow2() {
+        let f = TempFile::new().unwrap();
+        create_image(f.as_file(), 0x10_0000, 12, None, None);
+        let path = f.as_path().to_str().unwrap().to_string();
+        let new_block = |file_engine_type, num_queues| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Writeback,
+                path.clone(),
+                false,
+                false,
+                RateLimiter::default(),
+                file_engine_type,
+                num_queues,
+                ImageFormat::Qcow2,
+                None,
+            )
+        };
+
+        // qcow2 images are only supported by the Sync engine.
+        assert!(matches!(
+            new_block(FileEngineType::Async, 1),
+            Err(Error::FileEngine(block_io::Error::UnsupportedImageFormat(
+                ImageFormat::Qcow2
+            )))
+        ));
+
+        // The virtual size is exposed to the guest, without discard and write zeroes support.
+        let mut block = new_block(FileEngineType::Sync, 2).unwrap();
+        assert_eq!(block.image_format(), ImageFormat::Qcow2);
+        assert_eq!(block.file_engine_type(), FileEngineType::Sync);
+        assert_eq!(block.disk.nsectors(), 0x10_0000 >> SECTOR_SHIFT);
+        assert_eq!(block.avail_features & (1u64 << VIRTIO_BLK_F_DISCARD), 0);
+        assert_eq!(
+            block.avail_features & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES),
+            0
+        );
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        // Write the last sector of the first cluster and the first one of the second cluster.
+        mem.write_obj::<RequestHeader>(RequestHeader::new(VIRTIO_BLK_T_OUT, 7), request_type_addr)
+            .unwrap();
+        vq.dtable[1].len.set(1024);
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        mem.write_slice(&[0xcd; 1024], data_addr).unwrap();
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+
+        // The image file holds the qcow2 metadata, not the guest data.
+        let mut buf = [0u8; 4];
+        f.as_file().read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, QCOW2_MAGIC);
+
+        // Read the data back.
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        mem.write_obj::<RequestHeader>(RequestHeader::new(VIRTIO_BLK_T_IN, 7), request_type_addr)
+            .unwrap();
+        mem.write_slice(&[0u8; 1024], data_addr).unwrap();
+        vq.dtable[1]
+            .flags
+            .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        let mut data = [0u8; 1024];
+        mem.read_slice(&mut data, data_addr).unwrap();
+        assert_eq!(data, [0xcd; 1024]);
+
+        // Discard requests fail.
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        mem.write_obj::<u32>(VIRTIO_BLK_T_DISCARD, request_type_addr)
+            .unwrap();
+        mem.write_obj(DiscardWriteZeroesSegment::new(0, 1, 0), data_addr)
+            .unwrap();
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1]
+            .len
+            .set(std::mem::size_of::<DiscardWriteZeroesSegment>() as u32);
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_IOERR as u8
+        );
+    }
+
+    #[test]
+    fn test_overlay() {
+        let base = TempFile::new().unwrap();
+        base.as_file().write_all_at(&[0xab; 0x2000], 0).unwrap();
+        base.as_file().set_len(0x10_0000).unwrap();
+        let overlay = TempFile::new().unwrap();
+        let new_block = |file_engine_type, image_format| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Writeback,
+                base.as_path().to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                file_engine_type,
+                1,
+                image_format,
+                Some(overlay.as_path().to_str().unwrap().to_string()),
+            )
+        };
+
+        // Overlays are only supported for raw images, by the Sync engine.
+        assert!(matches!(
+            new_block(FileEngineType::Async, ImageFormat::Raw),
+            Err(Error::FileEngine(
+                block_io::Error::UnsupportedOverlayEngine(FileEngineType::Async)
+            ))
+        ));
+        assert!(matches!(
+            new_block(FileEngineType::Sync, ImageFormat::Qcow2),
+            Err(Error::UnsupportedOverlayFormat)
+        ));
+
+        // The size of the base image is exposed to the guest, without discard and write zeroes
+        // support.
+        let mut block = new_block(FileEngineType::Sync, ImageFormat::Raw).unwrap();
+        assert_eq!(
+            block.overlay_path().unwrap(),
+            overlay.as_path().to_str().unwrap()
+        );
+        assert_eq!(block.disk.nsectors(), 0x10_0000 >> SECTOR_SHIFT);
+        assert_eq!(block.avail_features & (1u64 << VIRTIO_BLK_F_DISCARD), 0);
+        assert_eq!(
+            block.avail_features & (1u64 << VIRTIO_BLK_F_WRITE_ZEROES),
+            0
+        );
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        // Write the last sector of the first block and the first one of the second block.
+        mem.write_obj::<RequestHeader>(RequestHeader::new(VIRTIO_BLK_T_OUT, 7), request_type_addr)
+            .unwrap();
+        vq.dtable[1].len.set(1024);
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        mem.write_slice(&[0xcd; 1024], data_addr).unwrap();
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+
+        // The base image is left unmodified.
+        let mut buf = vec![0u8; 0x2000];
+        base.as_file().read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, vec![0xab; 0x2000]);
+        let mut magic = [0u8; 8];
+        overlay.as_file().read_exact_at(&mut magic, 0).unwrap();
+        assert_eq!(magic, OVERLAY_MAGIC);
+
+        // Read the data back, along with the previous sector, copied from the base image.
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        mem.write_obj::<RequestHeader>(RequestHeader::new(VIRTIO_BLK_T_IN, 6), request_type_addr)
+            .unwrap();
+        mem.write_slice(&[0u8; 1024], data_addr).unwrap();
+        vq.dtable[1]
+            .flags
+            .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        simulate_queue_event(&mut block, Some(true));
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        let mut data = [0u8; 1024];
+        mem.read_slice(&mut data, data_addr).unwrap();
+        assert_eq!(data[..512], [0xab; 512]);
+        assert_eq!(data[512..], [0xcd; 512]);
+    }
+
+    #[test]
+    fn test_direct_io() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x10_0000).unwrap();
+        let new_block = |image_format, overlay_path| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Direct,
+                f.as_path().to_str().unwrap().to_string(),
+                false,
+                false,
+                RateLimiter::default(),
+                default_engine_type_for_kv(),
+                1,
+                image_format,
+                overlay_path,
+            )
+        };
+
+        // Direct IO is only supported for raw images without overlay.
+        assert!(matches!(
+            new_block(ImageFormat::Qcow2, None),
+            Err(Error::UnsupportedDirectIo)
+        ));
+        assert!(matches!(
+            new_block(ImageFormat::Raw, Some("overlay".to_string())),
+            Err(Error::UnsupportedDirectIo)
+        ));
+
+        let mut block = match new_block(ImageFormat::Raw, None) {
+            Ok(block) => block,
+            // The file system of the temporary file does not support direct IO.
+            Err(Error::BackingFile(err)) if err.raw_os_error() == Some(libc::EINVAL) => return,
+            Err(err) => panic!("Failed to create the block device: {:?}", err),
+        };
+        assert_eq!(block.cache_type(), CacheType::Direct);
+        assert!(block.avail_features & (1u64 << VIRTIO_BLK_F_FLUSH) != 0);
+        // The guest is told to use the logical block size of the host storage.
+        let blk_size = direct_io::alignment(f.as_file()).unwrap();
+        assert!(block.avail_features & (1u64 << VIRTIO_BLK_F_BLK_SIZE) != 0);
+        let mut config = [0u8; 4];
+        block.read_config(20, &mut config);
+        assert_eq!(u32::from_le_bytes(config), blk_size);
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        // Make room for a whole block of data.
+        let status_addr = GuestAddress(0x4000);
+        vq.dtable[2].addr.set(status_addr.0);
+        // Write a block from an unaligned guest buffer, which goes through a bounce buffer.
+        let data_addr = GuestAddress(vq.dtable[1].addr.get() + 1);
+        let data = vec![0xcd; blk_size as usize];
+        let sector = u64::from(blk_size) / SECTOR_SIZE;
+        vq.dtable[1].addr.set(data_addr.0);
+        mem.write_obj::<RequestHeader>(
+            RequestHeader::new(VIRTIO_BLK_T_OUT, sector),
+            request_type_addr,
+        )
+        .unwrap();
+        vq.dtable[1].len.set(blk_size);
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        mem.write_slice(&data, data_addr).unwrap();
+        simulate_queue_and_async_completion_events(&mut block, true);
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+
+        let mut buf = vec![0u8; blk_size as usize];
+        f.as_file()
+            .read_exact_at(&mut buf, u64::from(blk_size))
+            .unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn test_nbd() {
+        let server = TestServer::new(0x10_0000, ALL_FLAGS);
+        let new_block = |file_engine_type, num_queues, image_format| {
+            Block::new(
+                "test".to_string(),
+                None,
+                CacheType::Writeback,
+                server.uri.clone(),
+                false,
+                false,
+                RateLimiter::default(),
+                file_engine_type,
+                num_queues,
+                image_format,
+                None,
+            )
+        };
+
+        // NBD exports are raw disks, accessed by the Sync engine through a connection per queue.
+        assert!(matches!(
+            new_block(FileEngineType::Async, 1, ImageFormat::Raw),
+            Err(Error::FileEngine(block_io::Error::UnsupportedNbdEngine(
+                FileEngineType::Async
+            )))
+        ));
+        assert!(matches!(
+            new_block(FileEngineType::Sync, 1, ImageFormat::Qcow2),
+            Err(Error::UnsupportedNbdDrive)
+        ));
+        assert!(matches!(
+            new_block(FileEngineType::Sync, 2, ImageFormat::Raw),
+            Err(Error::FileEngine(block_io::Error::Nbd(
+                block_io::nbd::Error::MultiConnUnsupported
+            )))
+        ));
+
+        let mut block = new_block(FileEngineType::Sync, 1, ImageFormat::Raw).unwrap();
+        assert_eq!(block.disk.nsectors(), 0x10_0000 >> SECTOR_SHIFT);
+        assert_eq!(block.file_engine_type(), FileEngineType::Sync);
+        assert_eq!(&block.disk.image_id()[..4], b"disk");
+        for feature in [
+            VIRTIO_BLK_F_FLUSH,
+            VIRTIO_BLK_F_DISCARD,
+            VIRTIO_BLK_F_WRITE_ZEROES,
+        ] {
+            assert!(block.avail_features & (1u64 << feature) != 0);
+        }
+
+        let mem = default_mem();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        set_queue(&mut block, 0, vq.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq);
+
+        let request_type_addr = GuestAddress(vq.dtable[0].addr.get());
+        let data_addr = GuestAddress(vq.dtable[1].addr.get());
+        let status_addr = GuestAddress(vq.dtable[2].addr.get());
+        mem.write_obj::<RequestHeader>(RequestHeader::new(VIRTIO_BLK_T_OUT, 3), request_type_addr)
+            .unwrap();
+        vq.dtable[1].len.set(1024);
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        mem.write_slice(&[0xcd; 1024], data_addr).unwrap();
+        simulate_queue_and_async_completion_events(&mut block, true);
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        assert_eq!(server.disk.lock().unwrap()[0x600..0xa00], [0xcd; 1024]);
+
+        // Read the data back, along with the previous sector.
+        vq.used.idx.set(0);
+        set_queue(&mut block, 0, vq.create_queue());
+        mem.write_obj::<RequestHeader>(RequestHeader::new(VIRTIO_BLK_T_IN, 2), request_type_addr)
+            .unwrap();
+        mem.write_slice(&[0xff; 1024], data_addr).unwrap();
+        vq.dtable[1]
+            .flags
+            .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        simulate_queue_and_async_completion_events(&mut block, true);
+        assert_eq!(
+            mem.read_obj::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        let mut data = [0u8; 1024];
+        mem.read_slice(&mut data, data_addr).unwrap();
+        assert_eq!(data[..512], [0u8; 512]);
+        assert_eq!(data[512..], [0xcd; 512]);
+
+        // Discard requests are sent as trims, which the test server ignores, while write zeroes
+        // requests zero the data, even when the guest allows unmapping it.
+        vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+        vq.dtable[1]
+            .len
+            .set(std::mem::size_of::<DiscardWriteZeroesSegment>() as u32);
+        for (request_type, flags, expected) in [
+            (VIRTIO_BLK_T_DISCARD, 0, 0xcd),
+            (
+                VIRTIO_BLK_T_WRITE_ZEROES,
+                VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+                0,
+            ),
+        ] {
+            vq.used.idx.set(0);
+            set_queue(&mut block, 0, vq.create_queue());
+            mem.write_obj::<u32>(request_type, request_type_addr)
+                .unwrap();
+            mem.write_obj(DiscardWriteZeroesSegment::new(3, 2, flags), data_addr)
+                .unwrap();
+            simulate_queue_and_async_completion_events(&mut block, true);
+            assert_eq!(
+                mem.read_obj::<u8>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK as u8
+            );
+            assert_eq!(server.disk.lock().unwrap()[0x600..0xa00], [expected; 1024]);
+        }
+    }
+
     #[test]
     fn test_get_device_id() {
         let mut block = default_block(default_engine_type_for_kv());
@@ -1433,25 +2410,25 @@ pub(crate) mod tests {
             // Run scenario that doesn't trigger FullSq Error: Add sq_size flush requests.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             simulate_async_completion_event(&mut block, true);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES, &mem, &vq);
 
             // Run scenario that triggers FullSqError : Add sq_size + 10 flush requests.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES + 10);
             simulate_queue_event(&mut block, Some(false));
-            assert!(block.is_io_engine_throttled);
+            assert!(block.is_io_engine_throttled[0]);
             // When the async_completion_event is triggered:
             // 1. sq_size requests should be processed processed.
             // 2. is_io_engine_throttled should be set back to false.
             // 3. process_queue() should be called again.
             simulate_async_completion_event(&mut block, true);
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES, &mem, &vq);
             // check that process_queue() was called again resulting in the processing of the
             // remaining 10 ops.
             simulate_async_completion_event(&mut block, true);
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES + 10, &mem, &vq);
         }
 
@@ -1467,22 +2444,106 @@ pub(crate) mod tests {
             // completion. Then try to push another entry.
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             thread::sleep(Duration::from_millis(150));
             add_flush_requests_batch(&mut block, &mem, &vq, IO_URING_NUM_ENTRIES);
             simulate_queue_event(&mut block, Some(false));
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             thread::sleep(Duration::from_millis(150));
 
             add_flush_requests_batch(&mut block, &mem, &vq, 1);
             simulate_queue_event(&mut block, Some(false));
-            assert!(block.is_io_engine_throttled);
+            assert!(block.is_io_engine_throttled[0]);
             simulate_async_completion_event(&mut block, true);
-            assert!(!block.is_io_engine_throttled);
+            assert!(!block.is_io_engine_throttled[0]);
             check_flush_requests_batch(IO_URING_NUM_ENTRIES * 2, &mem, &vq);
         }
     }
 
+    #[test]
+    fn test_multi_queue() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+        let mut block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            default_engine_type_for_kv(),
+            2,
+            ImageFormat::Raw,
+            None,
+        )
+        .unwrap();
+        assert!(block.avail_features & (1u64 << VIRTIO_BLK_F_MQ) != 0);
+        assert_eq!(block.queue_events().len(), 2);
+        let mut num_queues = [0u8; 2];
+        block.read_config(34, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), 2);
+
+        let mem = default_mem();
+        let vq0 = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let vq1 = VirtQueue::new(GuestAddress(0x4000), &mem, 16);
+        set_queue(&mut block, 0, vq0.create_queue());
+        set_queue(&mut block, 1, vq1.create_queue());
+        block.activate(mem.clone()).unwrap();
+        initialize_virtqueue(&vq0);
+
+        // Write a sector through the second queue.
+        vq1.avail.ring[0].set(0);
+        vq1.avail.idx.set(1);
+        vq1.dtable[0].set(0x5000, 0x1000, VIRTQ_DESC_F_NEXT, 1);
+        vq1.dtable[1].set(0x6000, 512, VIRTQ_DESC_F_NEXT, 2);
+        vq1.dtable[2].set(0x7000, 0x1000, VIRTQ_DESC_F_WRITE, 0);
+        mem.write_obj::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x5000))
+            .unwrap();
+        mem.write_slice(&[0xab; 512], GuestAddress(0x6000)).unwrap();
+
+        block.queue_evts[1].write(1).unwrap();
+        block.process_queue_event(1);
+        if let FileEngine::Async(engine) = block.disk.file_engine_mut(1) {
+            engine.drain(false).unwrap();
+            thread::sleep(Duration::from_millis(150));
+            block.process_async_completion_event(1);
+        }
+
+        // The request was completed on the second queue only.
+        assert_eq!(vq1.used.idx.get(), 1);
+        assert_eq!(vq1.used.ring[0].get().id, 0);
+        assert_eq!(
+            mem.read_obj::<u8>(GuestAddress(0x7000)).unwrap(),
+            VIRTIO_BLK_S_OK as u8
+        );
+        assert_eq!(vq0.used.idx.get(), 0);
+
+        let mut buf = [0u8; 512];
+        block.disk.file().read_exact_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [0xab; 512]);
+
+        // The number of queues is bounded.
+        for num_queues in [0, MAX_NUM_QUEUES + 1] {
+            assert!(matches!(
+                Block::new(
+                    "test".to_string(),
+                    None,
+                    CacheType::Unsafe,
+                    f.as_path().to_str().unwrap().to_string(),
+                    false,
+                    false,
+                    RateLimiter::default(),
+                    default_engine_type_for_kv(),
+                    num_queues,
+                    ImageFormat::Raw,
+                    None,
+                ),
+                Err(Error::InvalidNumQueues(n)) if n == num_queues
+            ));
+        }
+    }
+
     #[test]
     fn test_prepare_save() {
         let mut block = default_block(default_engine_type_for_kv());
@@ -1669,4 +2730,31 @@ pub(crate) mod tests {
         );
         assert_eq!(block.disk.image_id, id.as_slice());
     }
+
+    #[test]
+    fn test_update_disk_size() {
+        let mut block = default_block(default_engine_type_for_kv());
+        assert_eq!(block.disk.nsectors(), 0x1000 / SECTOR_SIZE);
+
+        // Grow the disk image on the host.
+        block.disk.file().set_len(0x2000 + 0x100).unwrap();
+        check_metric_after_block!(
+            &METRICS.block.update_count,
+            1,
+            block.update_disk_size().unwrap()
+        );
+        // The tail which does not fill a sector is not exposed.
+        assert_eq!(block.disk.nsectors(), 0x2000 / SECTOR_SIZE);
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 0x2000 / SECTOR_SIZE);
+        assert!(block.irq_trigger.has_pending_irq(IrqType::Config));
+
+        // The size of qcow2 images and overlays is not the size of their files.
+        block.disk.image_format = ImageFormat::Qcow2;
+        assert!(matches!(
+            block.update_disk_size(),
+            Err(Error::UnsupportedResize)
+        ));
+    }
 }
@@ -1,6 +1,6 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use event_manager::{EventOps, Events, MutEventSubscriber};
 use logger::{debug, error, warn};
@@ -12,19 +12,48 @@ use crate::virtio::VirtioDevice;
 
 impl Block {
     fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(err) = ops.add(Events::new(&self.queue_evts[0], EventSet::IN)) {
-            error!("Failed to register queue event: {}", err);
+        for queue_evt in self.queue_evts.iter() {
+            if let Err(err) = ops.add(Events::new(queue_evt, EventSet::IN)) {
+                error!("Failed to register queue event: {}", err);
+            }
         }
         if let Err(err) = ops.add(Events::new(&self.rate_limiter, EventSet::IN)) {
             error!("Failed to register ratelimiter event: {}", err);
         }
-        if let FileEngine::Async(engine) = self.disk.file_engine() {
-            if let Err(err) = ops.add(Events::new(engine.completion_evt(), EventSet::IN)) {
+        for engine in self.disk.file_engines() {
+            let res = match engine {
+                FileEngine::Async(engine) => {
+                    ops.add(Events::new(engine.completion_evt(), EventSet::IN))
+                }
+                // The epoll FD of the engine is readable when its socket must be written or read.
+                FileEngine::Nbd(engine) => ops.add(Events::new(engine.epoll(), EventSet::IN)),
+                FileEngine::Sync(_) | FileEngine::Qcow2(_) | FileEngine::Overlay(_) => continue,
+            };
+            if let Err(err) = res {
                 error!("Failed to register IO engine completion event: {}", err);
             }
         }
     }
 
+    // Returns the index of the queue whose queue event is `source`.
+    fn queue_evt_index(&self, source: RawFd) -> Option<usize> {
+        self.queue_evts
+            .iter()
+            .position(|queue_evt| queue_evt.as_raw_fd() == source)
+    }
+
+    // Returns the index of the queue whose IO engine completion event is `source`.
+    fn completion_evt_index(&self, source: RawFd) -> Option<usize> {
+        self.disk
+            .file_engines()
+            .iter()
+            .position(|engine| match engine {
+                FileEngine::Async(engine) => engine.completion_evt().as_raw_fd() == source,
+                FileEngine::Nbd(engine) => engine.epoll().as_raw_fd() == source,
+                FileEngine::Sync(_) | FileEngine::Qcow2(_) | FileEngine::Overlay(_) => false,
+            })
+    }
+
     fn register_activate_event(&self, ops: &mut EventOps) {
         if let Err(err) = ops.add(Events::new(&self.activate_evt, EventSet::IN)) {
             error!("Failed to register activate event: {}", err);
@@ -61,21 +90,22 @@ impl MutEventSubscriber for Block {
         }
 
         if self.is_activated() {
-            let queue_evt = self.queue_evts[0].as_raw_fd();
             let rate_limiter_evt = self.rate_limiter.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
-            let maybe_completion_fd = match self.disk.file_engine() {
-                FileEngine::Async(engine) => Some(engine.completion_evt().as_raw_fd()),
-                FileEngine::Sync(_) => None,
-            };
 
             // Looks better than C style if/else if/else.
             match source {
-                _ if queue_evt == source => self.process_queue_event(),
                 _ if rate_limiter_evt == source => self.process_rate_limiter_event(),
                 _ if activate_fd == source => self.process_activate_event(ops),
-                _ if maybe_completion_fd == Some(source) => self.process_async_completion_event(),
-                _ => warn!("Block: Spurious event received: {:?}", source),
+                _ => {
+                    if let Some(queue_index) = self.queue_evt_index(source) {
+                        self.process_queue_event(queue_index);
+                    } else if let Some(queue_index) = self.completion_evt_index(source) {
+                        self.process_async_completion_event(queue_index);
+                    } else {
+                        warn!("Block: Spurious event received: {:?}", source);
+                    }
+                }
             }
         } else {
             warn!(
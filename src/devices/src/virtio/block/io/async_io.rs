@@ -8,10 +8,13 @@ use std::os::unix::io::AsRawFd;
 use io_uring::operation::{Cqe, OpCode, Operation};
 use io_uring::restriction::Restriction;
 use io_uring::{Error as IoUringError, IoUring};
-use logger::log_dev_preview_warning;
+use logger::{error, log_dev_preview_warning};
 use utils::eventfd::EventFd;
-use vm_memory::{mark_dirty_mem, GuestAddress, GuestMemory, GuestMemoryMmap};
+use vm_memory::{
+    mark_dirty_mem, Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
+};
 
+use crate::virtio::block::io::direct_io::{is_aligned, BounceBuffer};
 use crate::virtio::block::io::UserDataError;
 use crate::virtio::block::IO_URING_NUM_ENTRIES;
 
@@ -27,6 +30,8 @@ pub enum Error {
 
 pub struct AsyncFileEngine<T> {
     file: File,
+    // The alignment of the transfers, if the file was opened with `O_DIRECT`.
+    direct_io_alignment: Option<u32>,
     ring: IoUring,
     completion_evt: EventFd,
     phantom: PhantomData<T>,
@@ -34,6 +39,9 @@ pub struct AsyncFileEngine<T> {
 
 pub struct WrappedUserData<T> {
     addr: Option<GuestAddress>,
+    // Aligned buffer the data of a direct IO transfer goes through, when the guest buffer cannot
+    // be used. It must live until the operation completes.
+    bounce_buffer: Option<BounceBuffer>,
     user_data: T,
 }
 
@@ -41,6 +49,7 @@ impl<T> WrappedUserData<T> {
     fn new(user_data: T) -> Self {
         WrappedUserData {
             addr: None,
+            bounce_buffer: None,
             user_data,
         }
     }
@@ -48,13 +57,30 @@ impl<T> WrappedUserData<T> {
     fn new_with_dirty_tracking(addr: GuestAddress, user_data: T) -> Self {
         WrappedUserData {
             addr: Some(addr),
+            bounce_buffer: None,
             user_data,
         }
     }
 
+    fn with_bounce_buffer(mut self, bounce_buffer: BounceBuffer) -> Self {
+        self.bounce_buffer = Some(bounce_buffer);
+        self
+    }
+
     fn mark_dirty_mem_and_unwrap(self, mem: &GuestMemoryMmap, count: u32) -> T {
-        if let Some(addr) = self.addr {
-            mark_dirty_mem(mem, addr, count as usize)
+        match (self.addr, &self.bounce_buffer) {
+            // The data read in the bounce buffer is copied to guest memory, which marks it dirty.
+            (Some(addr), Some(bounce_buffer)) => {
+                let count = (count as usize).min(bounce_buffer.as_slice().len());
+                if let Err(err) = mem.write_slice(&bounce_buffer.as_slice()[..count], addr) {
+                    error!(
+                        "Failed to copy the bounce buffer to guest memory: {:?}",
+                        err
+                    );
+                }
+            }
+            (Some(addr), None) => mark_dirty_mem(mem, addr, count as usize),
+            (None, _) => (),
         }
 
         self.user_data
@@ -62,7 +88,10 @@ impl<T> WrappedUserData<T> {
 }
 
 impl<T> AsyncFileEngine<T> {
-    pub fn from_file(file: File) -> Result<AsyncFileEngine<T>, Error> {
+    pub fn from_file(
+        file: File,
+        direct_io_alignment: Option<u32>,
+    ) -> Result<AsyncFileEngine<T>, Error> {
         log_dev_preview_warning("Async file IO", Option::None);
 
         let completion_evt = EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?;
@@ -76,6 +105,7 @@ impl<T> AsyncFileEngine<T> {
                 Restriction::AllowOpCode(OpCode::Read),
                 Restriction::AllowOpCode(OpCode::Write),
                 Restriction::AllowOpCode(OpCode::Fsync),
+                Restriction::AllowOpCode(OpCode::Fallocate),
             ],
             Some(completion_evt.as_raw_fd()),
         )
@@ -83,17 +113,21 @@ impl<T> AsyncFileEngine<T> {
 
         Ok(AsyncFileEngine {
             file,
+            direct_io_alignment,
             ring,
             completion_evt,
             phantom: PhantomData,
         })
     }
 
-    #[cfg(test)]
     pub fn file(&self) -> &File {
         &self.file
     }
 
+    pub fn direct_io_alignment(&self) -> Option<u32> {
+        self.direct_io_alignment
+    }
+
     pub fn completion_evt(&self) -> &EventFd {
         &self.completion_evt
     }
@@ -106,18 +140,34 @@ impl<T> AsyncFileEngine<T> {
         count: u32,
         user_data: T,
     ) -> Result<(), UserDataError<T, Error>> {
-        let buf = match mem.get_slice(addr, count as usize) {
-            Ok(slice) => slice.as_ptr(),
-            Err(err) => {
+        let mut wrapped_user_data = WrappedUserData::new_with_dirty_tracking(addr, user_data);
+        let buf = match (
+            mem.get_slice(addr, count as usize),
+            self.direct_io_alignment,
+        ) {
+            (Ok(slice), None) => slice.as_ptr(),
+            (Ok(slice), Some(alignment)) if is_aligned(slice.as_ptr(), alignment) => slice.as_ptr(),
+            (Err(err), None) => {
                 return Err(UserDataError {
-                    user_data,
+                    user_data: wrapped_user_data.user_data,
                     error: Error::GuestMemory(err),
                 });
             }
+            (_, Some(alignment)) => {
+                // The data is read in a bounce buffer, then copied to guest memory on completion.
+                if !mem.check_range(addr, count as usize) {
+                    return Err(UserDataError {
+                        user_data: wrapped_user_data.user_data,
+                        error: Error::GuestMemory(GuestMemoryError::InvalidGuestAddress(addr)),
+                    });
+                }
+                let mut bounce_buffer = BounceBuffer::new(count as usize, alignment);
+                let ptr = bounce_buffer.as_mut_ptr();
+                wrapped_user_data = wrapped_user_data.with_bounce_buffer(bounce_buffer);
+                ptr
+            }
         };
 
-        let wrapped_user_data = WrappedUserData::new_with_dirty_tracking(addr, user_data);
-
         // SAFETY: Safe because we trust that the host kernel will pass us back a completed entry
         // with this same `user_data`, so that the value will not be leaked.
         unsafe {
@@ -143,18 +193,34 @@ impl<T> AsyncFileEngine<T> {
         count: u32,
         user_data: T,
     ) -> Result<(), UserDataError<T, Error>> {
-        let buf = match mem.get_slice(addr, count as usize) {
-            Ok(slice) => slice.as_ptr(),
-            Err(err) => {
+        let mut wrapped_user_data = WrappedUserData::new(user_data);
+        let buf = match (
+            mem.get_slice(addr, count as usize),
+            self.direct_io_alignment,
+        ) {
+            (Ok(slice), None) => slice.as_ptr(),
+            (Ok(slice), Some(alignment)) if is_aligned(slice.as_ptr(), alignment) => slice.as_ptr(),
+            (Err(err), None) => {
                 return Err(UserDataError {
-                    user_data,
+                    user_data: wrapped_user_data.user_data,
                     error: Error::GuestMemory(err),
                 });
             }
+            (_, Some(alignment)) => {
+                // The data is copied from guest memory to a bounce buffer, which is written.
+                let mut bounce_buffer = BounceBuffer::new(count as usize, alignment);
+                if let Err(err) = mem.read_slice(bounce_buffer.as_mut_slice(), addr) {
+                    return Err(UserDataError {
+                        user_data: wrapped_user_data.user_data,
+                        error: Error::GuestMemory(err),
+                    });
+                }
+                let ptr = bounce_buffer.as_mut_ptr();
+                wrapped_user_data = wrapped_user_data.with_bounce_buffer(bounce_buffer);
+                ptr
+            }
         };
 
-        let wrapped_user_data = WrappedUserData::new(user_data);
-
         // SAFETY: Safe because we trust that the host kernel will pass us back a completed entry
         // with this same `user_data`, so that the value will not be leaked.
         unsafe {
@@ -172,6 +238,32 @@ impl<T> AsyncFileEngine<T> {
         })
     }
 
+    pub fn push_fallocate(
+        &mut self,
+        offset: u64,
+        len: u64,
+        mode: u32,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        let wrapped_user_data = WrappedUserData::new(user_data);
+
+        // SAFETY: Safe because we trust that the host kernel will pass us back a completed entry
+        // with this same `user_data`, so that the value will not be leaked.
+        unsafe {
+            self.ring.push(Operation::fallocate(
+                0,
+                len,
+                mode,
+                offset,
+                wrapped_user_data,
+            ))
+        }
+        .map_err(|err_tuple| UserDataError {
+            user_data: err_tuple.1.user_data,
+            error: Error::IoUring(err_tuple.0),
+        })
+    }
+
     pub fn push_flush(&mut self, user_data: T) -> Result<(), UserDataError<T, Error>> {
         let wrapped_user_data = WrappedUserData::new(user_data);
 
@@ -0,0 +1,178 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Helpers for accessing backing files opened with `O_DIRECT`.
+//!
+//! Direct IO bypasses the host page cache, but requires the file offsets, the lengths and the
+//! memory buffers of the transfers to be aligned to the logical block size of the storage. The
+//! guest is told to use that block size, so that the offsets and lengths of its requests are
+//! aligned, but guest buffers can have any address, or span guest memory regions, in which case
+//! the data goes through an aligned bounce buffer.
+
+use std::alloc::{self, Layout};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::ptr::NonNull;
+
+use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
+
+use crate::virtio::block::SECTOR_SIZE;
+
+/// Largest logical block size of the storage devices.
+const MAX_ALIGNMENT: u32 = 4096;
+
+/// Returns the alignment of the buffers, offsets and lengths of direct IO transfers to `file`,
+/// which is the logical block size of the storage.
+///
+/// The logical block size of block devices is queried from the device. For regular files, the
+/// block size of the file system is used instead, which is a multiple of the logical block size
+/// of the device holding the file system.
+pub fn alignment(file: &File) -> io::Result<u32> {
+    let block_size = if file.metadata()?.file_type().is_block_device() {
+        let mut block_size: libc::c_int = 0;
+        // SAFETY: Safe because the file descriptor is valid, `BLKSSZGET` writes an int to the
+        // provided address, and the return value is checked.
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::BLKSSZGET, &mut block_size) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        u32::try_from(block_size).unwrap_or(0)
+    } else {
+        // SAFETY: Safe because `statfs` is plain data, for which zeroes are valid.
+        let mut statfs: libc::statfs = unsafe { std::mem::zeroed() };
+        // SAFETY: Safe because the file descriptor is valid, `statfs` is large enough for the
+        // result, and the return value is checked.
+        if unsafe { libc::fstatfs(file.as_raw_fd(), &mut statfs) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        u32::try_from(statfs.f_bsize).unwrap_or(0)
+    };
+
+    // Block sizes which can't be a logical block size fall back to the largest one, which is
+    // aligned for all the devices.
+    if block_size.is_power_of_two() {
+        Ok(block_size.clamp(SECTOR_SIZE as u32, MAX_ALIGNMENT))
+    } else {
+        Ok(MAX_ALIGNMENT)
+    }
+}
+
+/// Whether a host buffer can be used for direct IO transfers aligned to `alignment`.
+pub fn is_aligned(ptr: *const u8, alignment: u32) -> bool {
+    ptr as usize % alignment as usize == 0
+}
+
+/// Returns the host address of the `count` bytes of guest memory at `addr`, if they can be used
+/// for direct IO transfers aligned to `alignment`.
+pub fn aligned_host_address(
+    mem: &GuestMemoryMmap,
+    addr: GuestAddress,
+    count: u32,
+    alignment: u32,
+) -> Option<*mut u8> {
+    let ptr = mem.get_slice(addr, count as usize).ok()?.as_ptr();
+    if is_aligned(ptr, alignment) {
+        Some(ptr)
+    } else {
+        None
+    }
+}
+
+/// Zeroed heap buffer aligned for direct IO.
+pub struct BounceBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    alignment: u32,
+}
+
+// SAFETY: The buffer is owned, like a `Vec<u8>`.
+unsafe impl Send for BounceBuffer {}
+
+impl BounceBuffer {
+    pub fn new(len: usize, alignment: u32) -> BounceBuffer {
+        let layout = Self::layout(len, alignment);
+        // SAFETY: The layout has a non zero size.
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        BounceBuffer {
+            ptr,
+            len,
+            alignment,
+        }
+    }
+
+    fn layout(len: usize, alignment: u32) -> Layout {
+        // Zero sized allocations are not allowed.
+        Layout::from_size_align(len.max(alignment as usize), alignment as usize)
+            .expect("Invalid bounce buffer size")
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr.as_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        // SAFETY: The buffer holds `len` initialized bytes.
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        // SAFETY: The buffer holds `len` initialized bytes, borrowed mutably through `self`.
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for BounceBuffer {
+    fn drop(&mut self) {
+        // SAFETY: The buffer was allocated with the same layout.
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len, self.alignment)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use utils::tempfile::TempFile;
+    use vm_memory::test_utils::create_anon_guest_memory;
+
+    use super::*;
+
+    #[test]
+    fn test_alignment() {
+        let file = TempFile::new().unwrap();
+        let alignment = alignment(file.as_file()).unwrap();
+        assert!(alignment.is_power_of_two());
+        assert!((SECTOR_SIZE as u32..=MAX_ALIGNMENT).contains(&alignment));
+    }
+
+    #[test]
+    fn test_bounce_buffer() {
+        for alignment in [512, 4096] {
+            for len in [0, 1, 512, 4096 + 3] {
+                let mut buf = BounceBuffer::new(len, alignment);
+                assert!(is_aligned(buf.as_mut_ptr(), alignment));
+                assert_eq!(buf.as_slice(), vec![0u8; len].as_slice());
+                buf.as_mut_slice().fill(0xab);
+                assert!(buf.as_slice().iter().all(|&byte| byte == 0xab));
+            }
+        }
+    }
+
+    #[test]
+    fn test_aligned_host_address() {
+        let mem = create_anon_guest_memory(
+            &[(GuestAddress(0), 0x1000), (GuestAddress(0x1000), 0x1000)],
+            false,
+        )
+        .unwrap();
+
+        assert!(aligned_host_address(&mem, GuestAddress(0x200), 0x400, 512).is_some());
+        assert!(aligned_host_address(&mem, GuestAddress(0x1000), 0x400, 4096).is_some());
+        // Unaligned guest buffers.
+        assert!(aligned_host_address(&mem, GuestAddress(0x201), 0x400, 512).is_none());
+        assert!(aligned_host_address(&mem, GuestAddress(0x200), 0x400, 4096).is_none());
+        // Guest buffers spanning regions, or beyond guest memory.
+        assert!(aligned_host_address(&mem, GuestAddress(0xe00), 0x400, 512).is_none());
+        assert!(aligned_host_address(&mem, GuestAddress(0x1e00), 0x400, 512).is_none());
+    }
+}
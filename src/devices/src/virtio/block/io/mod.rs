@@ -2,15 +2,23 @@
 // SPDX-License-Identifier: Apache-2.0
 
 pub mod async_io;
+pub mod direct_io;
+pub mod nbd;
+pub mod overlay;
+pub mod qcow2;
 pub mod sync_io;
 
 use std::fs::File;
+use std::sync::{Arc, Mutex};
 
 use vm_memory::{GuestAddress, GuestMemoryMmap};
 
 pub use self::async_io::AsyncFileEngine;
+pub use self::nbd::NbdFileEngine;
+pub use self::overlay::{OverlayFileEngine, OverlayImage};
+pub use self::qcow2::{Qcow2FileEngine, Qcow2Image};
 pub use self::sync_io::SyncFileEngine;
-use crate::virtio::block::device::FileEngineType;
+use crate::virtio::block::device::{FileEngineType, ImageFormat};
 
 #[cfg_attr(test, derive(Debug, PartialEq, Eq))]
 pub struct UserDataOk<T> {
@@ -28,7 +36,13 @@ pub enum FileEngineOk<T> {
 pub enum Error {
     Sync(sync_io::Error),
     Async(async_io::Error),
+    Qcow2(qcow2::Error),
+    Overlay(overlay::Error),
+    Nbd(nbd::Error),
     UnsupportedEngine(FileEngineType),
+    UnsupportedImageFormat(ImageFormat),
+    UnsupportedOverlayEngine(FileEngineType),
+    UnsupportedNbdEngine(FileEngineType),
     GetKernelVersion(utils::kernel_version::Error),
 }
 
@@ -52,10 +66,19 @@ pub enum FileEngine<T> {
     #[allow(unused)]
     Async(AsyncFileEngine<T>),
     Sync(SyncFileEngine),
+    Qcow2(Qcow2FileEngine),
+    Overlay(OverlayFileEngine),
+    Nbd(NbdFileEngine<T>),
 }
 
 impl<T> FileEngine<T> {
-    pub fn from_file(file: File, engine_type: FileEngineType) -> Result<FileEngine<T>, Error> {
+    /// Creates an engine accessing `file`, which was opened with `O_DIRECT` if a
+    /// `direct_io_alignment` is given for its transfers.
+    pub fn from_file(
+        file: File,
+        engine_type: FileEngineType,
+        direct_io_alignment: Option<u32>,
+    ) -> Result<FileEngine<T>, Error> {
         if !engine_type
             .is_supported()
             .map_err(Error::GetKernelVersion)?
@@ -64,17 +87,68 @@ impl<T> FileEngine<T> {
         }
         match engine_type {
             FileEngineType::Async => Ok(FileEngine::Async(
-                AsyncFileEngine::from_file(file).map_err(Error::Async)?,
+                AsyncFileEngine::from_file(file, direct_io_alignment).map_err(Error::Async)?,
             )),
-            FileEngineType::Sync => Ok(FileEngine::Sync(SyncFileEngine::from_file(file))),
+            FileEngineType::Sync => Ok(FileEngine::Sync(SyncFileEngine::from_file(
+                file,
+                direct_io_alignment,
+            ))),
         }
     }
 
-    #[cfg(test)]
-    pub fn file(&self) -> &File {
+    /// Creates an engine accessing a qcow2 image, which can be shared with other engines.
+    pub fn from_qcow2_image(
+        image: Arc<Mutex<Qcow2Image>>,
+        engine_type: FileEngineType,
+    ) -> Result<FileEngine<T>, Error> {
+        match engine_type {
+            // The clusters of qcow2 images are mapped with blocking system calls.
+            FileEngineType::Async => Err(Error::UnsupportedImageFormat(ImageFormat::Qcow2)),
+            FileEngineType::Sync => Ok(FileEngine::Qcow2(Qcow2FileEngine::new(image))),
+        }
+    }
+
+    /// Creates an engine accessing a disk through a copy-on-write overlay, which can be shared
+    /// with other engines.
+    pub fn from_overlay_image(
+        image: Arc<Mutex<OverlayImage>>,
+        engine_type: FileEngineType,
+    ) -> Result<FileEngine<T>, Error> {
+        match engine_type {
+            // Blocks are copied to the overlay with blocking system calls.
+            FileEngineType::Async => Err(Error::UnsupportedOverlayEngine(engine_type)),
+            FileEngineType::Sync => Ok(FileEngine::Overlay(OverlayFileEngine::new(image))),
+        }
+    }
+
+    /// Creates an engine sending the requests to an NBD server.
+    pub fn from_nbd_engine(
+        engine: NbdFileEngine<T>,
+        engine_type: FileEngineType,
+    ) -> Result<FileEngine<T>, Error> {
+        match engine_type {
+            // Requests are already pipelined through the socket.
+            FileEngineType::Async => Err(Error::UnsupportedNbdEngine(engine_type)),
+            FileEngineType::Sync => Ok(FileEngine::Nbd(engine)),
+        }
+    }
+
+    /// The backing file of the engine, unless it is shared with other engines.
+    pub fn file(&self) -> Option<&File> {
         match self {
-            FileEngine::Async(engine) => engine.file(),
-            FileEngine::Sync(engine) => engine.file(),
+            FileEngine::Async(engine) => Some(engine.file()),
+            FileEngine::Sync(engine) => Some(engine.file()),
+            FileEngine::Qcow2(_) | FileEngine::Overlay(_) | FileEngine::Nbd(_) => None,
+        }
+    }
+
+    /// The alignment of the transfers of the engine, if its backing file was opened with
+    /// `O_DIRECT`.
+    pub fn direct_io_alignment(&self) -> Option<u32> {
+        match self {
+            FileEngine::Async(engine) => engine.direct_io_alignment(),
+            FileEngine::Sync(engine) => engine.direct_io_alignment(),
+            FileEngine::Qcow2(_) | FileEngine::Overlay(_) | FileEngine::Nbd(_) => None,
         }
     }
 
@@ -103,6 +177,29 @@ impl<T> FileEngine<T> {
                     error: Error::Sync(err),
                 }),
             },
+            FileEngine::Qcow2(engine) => match engine.read(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Qcow2(err),
+                }),
+            },
+            FileEngine::Overlay(engine) => match engine.read(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Overlay(err),
+                }),
+            },
+            FileEngine::Nbd(engine) => {
+                match engine.push_read(offset, mem, addr, count, user_data) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: Error::Nbd(err.error),
+                    }),
+                }
+            }
         }
     }
 
@@ -131,6 +228,119 @@ impl<T> FileEngine<T> {
                     error: Error::Sync(err),
                 }),
             },
+            FileEngine::Qcow2(engine) => match engine.write(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Qcow2(err),
+                }),
+            },
+            FileEngine::Overlay(engine) => match engine.write(offset, mem, addr, count) {
+                Ok(count) => Ok(FileEngineOk::Executed(UserDataOk { user_data, count })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Overlay(err),
+                }),
+            },
+            FileEngine::Nbd(engine) => match engine.push_write(offset, mem, addr, count, user_data)
+            {
+                Ok(_) => Ok(FileEngineOk::Submitted),
+                Err(err) => Err(UserDataError {
+                    user_data: err.user_data,
+                    error: Error::Nbd(err.error),
+                }),
+            },
+        }
+    }
+
+    /// Discards `len` bytes at `offset`, which NBD servers may trim rather than zero.
+    pub fn discard(
+        &mut self,
+        offset: u64,
+        len: u64,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        match self {
+            FileEngine::Nbd(engine) if engine.supports_trim() => {
+                let res = match u32::try_from(len) {
+                    Ok(len) => engine.push_trim(offset, len, user_data),
+                    Err(_) => Err(UserDataError {
+                        user_data,
+                        error: nbd::Error::UnsupportedRequest,
+                    }),
+                };
+                match res {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: Error::Nbd(err.error),
+                    }),
+                }
+            }
+            // Servers which cannot trim write zeroes instead.
+            _ => self.fallocate(
+                offset,
+                len,
+                (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u32,
+                user_data,
+            ),
+        }
+    }
+
+    pub fn fallocate(
+        &mut self,
+        offset: u64,
+        len: u64,
+        mode: u32,
+        user_data: T,
+    ) -> Result<FileEngineOk<T>, UserDataError<T, Error>> {
+        match self {
+            FileEngine::Async(engine) => {
+                match engine.push_fallocate(offset, len, mode, user_data) {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: Error::Async(err.error),
+                    }),
+                }
+            }
+            FileEngine::Sync(engine) => match engine.fallocate(offset, len, mode) {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Sync(err),
+                }),
+            },
+            // Discard and write zeroes are not advertised for qcow2 images and overlays.
+            FileEngine::Qcow2(_) => Err(UserDataError {
+                user_data,
+                error: Error::Qcow2(qcow2::Error::UnsupportedRequest),
+            }),
+            FileEngine::Overlay(_) => Err(UserDataError {
+                user_data,
+                error: Error::Overlay(overlay::Error::UnsupportedRequest),
+            }),
+            // Zeroed ranges can be deallocated unless the guest asked to keep them allocated.
+            FileEngine::Nbd(engine) => {
+                let may_punch_hole = (mode & libc::FALLOC_FL_ZERO_RANGE as u32) == 0;
+                let res = match u32::try_from(len) {
+                    Ok(len) => engine.push_write_zeroes(offset, len, may_punch_hole, user_data),
+                    Err(_) => Err(UserDataError {
+                        user_data,
+                        error: nbd::Error::UnsupportedRequest,
+                    }),
+                };
+                match res {
+                    Ok(_) => Ok(FileEngineOk::Submitted),
+                    Err(err) => Err(UserDataError {
+                        user_data: err.user_data,
+                        error: Error::Nbd(err.error),
+                    }),
+                }
+            }
         }
     }
 
@@ -153,13 +363,48 @@ impl<T> FileEngine<T> {
                     error: Error::Sync(err),
                 }),
             },
+            FileEngine::Qcow2(engine) => match engine.flush() {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Qcow2(err),
+                }),
+            },
+            FileEngine::Overlay(engine) => match engine.flush() {
+                Ok(_) => Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                })),
+                Err(err) => Err(UserDataError {
+                    user_data,
+                    error: Error::Overlay(err),
+                }),
+            },
+            // Servers without a write cache do not accept flush requests.
+            FileEngine::Nbd(engine) if !engine.supports_flush() => {
+                Ok(FileEngineOk::Executed(UserDataOk {
+                    user_data,
+                    count: 0,
+                }))
+            }
+            FileEngine::Nbd(engine) => match engine.push_flush(user_data) {
+                Ok(_) => Ok(FileEngineOk::Submitted),
+                Err(err) => Err(UserDataError {
+                    user_data: err.user_data,
+                    error: Error::Nbd(err.error),
+                }),
+            },
         }
     }
 
     pub fn drain(&mut self, discard: bool) -> Result<(), Error> {
         match self {
             FileEngine::Async(engine) => engine.drain(discard).map_err(Error::Async),
-            FileEngine::Sync(_engine) => Ok(()),
+            FileEngine::Nbd(engine) => engine.drain(discard).map_err(Error::Nbd),
+            FileEngine::Sync(_) | FileEngine::Qcow2(_) | FileEngine::Overlay(_) => Ok(()),
         }
     }
 
@@ -167,6 +412,9 @@ impl<T> FileEngine<T> {
         match self {
             FileEngine::Async(engine) => engine.drain_and_flush(discard).map_err(Error::Async),
             FileEngine::Sync(engine) => engine.flush().map_err(Error::Sync),
+            FileEngine::Qcow2(engine) => engine.flush().map_err(Error::Qcow2),
+            FileEngine::Overlay(engine) => engine.flush().map_err(Error::Overlay),
+            FileEngine::Nbd(engine) => engine.drain_and_flush(discard).map_err(Error::Nbd),
         }
     }
 }
@@ -174,7 +422,9 @@ impl<T> FileEngine<T> {
 #[cfg(test)]
 pub mod tests {
     #![allow(clippy::undocumented_unsafe_blocks)]
+    use std::fs::OpenOptions;
     use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::OpenOptionsExt;
     use std::os::unix::io::FromRawFd;
 
     use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
@@ -233,6 +483,20 @@ pub mod tests {
         }
     }
 
+    // Waits for an operation of either engine to complete, and checks its result.
+    fn check_execution(
+        res: Result<FileEngineOk<()>, UserDataError<(), Error>>,
+        mem: &GuestMemoryMmap,
+        engine: &mut FileEngine<()>,
+        count: u32,
+    ) {
+        match res {
+            Ok(FileEngineOk::Executed(res)) => assert_eq!(res.count, count),
+            Ok(FileEngineOk::Submitted) => assert_async_execution(mem, engine, count),
+            Err(err) => panic!("Unexpected error: {:?}", err.error),
+        }
+    }
+
     fn create_mem() -> GuestMemoryMmap {
         vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), MEM_LEN)], true)
             .unwrap()
@@ -259,7 +523,8 @@ pub mod tests {
         assert!(matches!(
             FileEngine::<PendingRequest>::from_file(
                 TempFile::new().unwrap().into_file(),
-                FileEngineType::Async
+                FileEngineType::Async,
+                None
             ),
             Err(Error::UnsupportedEngine(FileEngineType::Async))
         ));
@@ -270,17 +535,19 @@ pub mod tests {
         // Check invalid file
         let mem = create_mem();
         let file = unsafe { File::from_raw_fd(-2) };
-        let mut engine = FileEngine::from_file(file, FileEngineType::Sync).unwrap();
+        let mut engine = FileEngine::from_file(file, FileEngineType::Sync, None).unwrap();
         let res = engine.read(0, &mem, GuestAddress(0), 0, ());
         assert_err!(res, Error::Sync(sync_io::Error::Seek(_e)));
         let res = engine.write(0, &mem, GuestAddress(0), 0, ());
         assert_err!(res, Error::Sync(sync_io::Error::Seek(_e)));
         let res = engine.flush(());
         assert_err!(res, Error::Sync(sync_io::Error::SyncAll(_e)));
+        let res = engine.fallocate(0, 0, 0, ());
+        assert_err!(res, Error::Sync(sync_io::Error::Fallocate(_e)));
 
         // Create backing file.
         let file = TempFile::new().unwrap().into_file();
-        let mut engine = FileEngine::from_file(file, FileEngineType::Sync).unwrap();
+        let mut engine = FileEngine::from_file(file, FileEngineType::Sync, None).unwrap();
 
         let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
             .as_bytes()
@@ -339,6 +606,19 @@ pub mod tests {
         mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
         assert_eq!(buf, data.as_slice());
 
+        // Zero range
+        let mode = (libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE) as u32;
+        assert_sync_execution!(engine.fallocate(0, 512, mode, ()), 0);
+        let mem = create_mem();
+        assert_sync_execution!(
+            engine.read(0, &mem, GuestAddress(0), FILE_LEN, ()),
+            FILE_LEN
+        );
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf[..512], [0u8; 512]);
+        assert_eq!(buf[512..], data[512..]);
+
         // Check other ops
         assert!(engine.flush(()).is_ok());
         assert!(engine.drain(true).is_ok());
@@ -351,11 +631,11 @@ pub mod tests {
 
         // Check invalid file
         let file = unsafe { File::from_raw_fd(-2) };
-        assert!(FileEngine::<()>::from_file(file, FileEngineType::Async).is_err());
+        assert!(FileEngine::<()>::from_file(file, FileEngineType::Async, None).is_err());
 
         // Create backing file.
         let file = TempFile::new().unwrap().into_file();
-        let mut engine = FileEngine::<()>::from_file(file, FileEngineType::Async).unwrap();
+        let mut engine = FileEngine::<()>::from_file(file, FileEngineType::Async, None).unwrap();
 
         let data = utils::rand::rand_alphanumerics(FILE_LEN as usize)
             .as_bytes()
@@ -401,6 +681,18 @@ pub mod tests {
         check_dirty_mem(&mem, addr, FILE_LEN);
         check_clean_mem(&mem, GuestAddress(4096), 4096);
 
+        // Punch hole
+        let mode = (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u32;
+        assert_queued!(engine.fallocate(0, 512, mode, ()));
+        assert_async_execution(&mem, &mut engine, 0);
+        let mem = create_mem();
+        assert_queued!(engine.read(0, &mem, addr, FILE_LEN, ()));
+        assert_async_execution(&mem, &mut engine, FILE_LEN as u32);
+        let mut buf = vec![0u8; FILE_LEN as usize];
+        mem.read_slice(&mut buf, GuestAddress(0)).unwrap();
+        assert_eq!(buf[..512], [0u8; 512]);
+        assert_eq!(buf[512..], data[512..]);
+
         // Check other ops
         assert_queued!(engine.flush(()));
         assert_async_execution(&mem, &mut engine, 0);
@@ -408,4 +700,86 @@ pub mod tests {
         assert!(engine.drain(true).is_ok());
         assert!(engine.drain_and_flush(true).is_ok());
     }
+
+    // Opens a temporary file of `len` bytes with `O_DIRECT`, if its file system supports it.
+    fn open_direct(len: u64) -> Option<File> {
+        let temp_file = TempFile::new().unwrap();
+        temp_file.as_file().set_len(len).unwrap();
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(temp_file.as_path())
+            .ok()
+    }
+
+    #[test]
+    fn test_direct_io() {
+        const DIRECT_FILE_LEN: u64 = 0x4000;
+
+        let mut engine_types = vec![FileEngineType::Sync];
+        if FileEngineType::Async.is_supported().unwrap() {
+            engine_types.push(FileEngineType::Async);
+        }
+
+        for engine_type in engine_types {
+            let file = match open_direct(DIRECT_FILE_LEN) {
+                Some(file) => file,
+                None => return,
+            };
+            let alignment = direct_io::alignment(&file).unwrap();
+            let block_len = alignment as usize;
+            let mut engine =
+                FileEngine::<()>::from_file(file, engine_type, Some(alignment)).unwrap();
+            let data = utils::rand::rand_alphanumerics(2 * block_len)
+                .as_bytes()
+                .to_vec();
+            let create_mem = || {
+                vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x4000)], true)
+                    .unwrap()
+            };
+
+            // Write from an aligned guest buffer, then from an unaligned one, which goes through
+            // a bounce buffer.
+            let mem = create_mem();
+            mem.write_slice(&data[..block_len], GuestAddress(0))
+                .unwrap();
+            mem.write_slice(&data[block_len..], GuestAddress(0x1001))
+                .unwrap();
+            let res = engine.write(0, &mem, GuestAddress(0), alignment, ());
+            check_execution(res, &mem, &mut engine, alignment);
+            let res = engine.write(
+                u64::from(alignment),
+                &mem,
+                GuestAddress(0x1001),
+                alignment,
+                (),
+            );
+            check_execution(res, &mem, &mut engine, alignment);
+
+            // Read back to an unaligned guest buffer, then to an aligned one.
+            for addr in [GuestAddress(0x201), GuestAddress(0x1000)] {
+                let mem = create_mem();
+                let res = engine.read(0, &mem, addr, 2 * alignment, ());
+                check_execution(res, &mem, &mut engine, 2 * alignment);
+                let mut buf = vec![0u8; 2 * block_len];
+                mem.read_slice(&mut buf, addr).unwrap();
+                assert_eq!(buf, data);
+                check_dirty_mem(&mem, addr, 2 * alignment);
+            }
+
+            // Reads stop at the end of the file.
+            let mem = create_mem();
+            let res = engine.read(
+                DIRECT_FILE_LEN - u64::from(alignment),
+                &mem,
+                GuestAddress(0x1001),
+                2 * alignment,
+                (),
+            );
+            check_execution(res, &mem, &mut engine, alignment);
+
+            assert!(engine.drain_and_flush(true).is_ok());
+        }
+    }
 }
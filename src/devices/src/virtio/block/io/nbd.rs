@@ -0,0 +1,1407 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Client of the Network Block Device (NBD) protocol, accessing a disk exported by an NBD server.
+//!
+//! The server is reached through a Unix domain socket or a TCP connection, described by an NBD
+//! URI: `nbd+unix:///<export>?socket=<path>` or `nbd://<host>[:<port>]/<export>`. The fixed
+//! newstyle handshake selects the export with `NBD_OPT_GO`, falling back to
+//! `NBD_OPT_EXPORT_NAME` for older servers, and the transmission phase uses simple replies.
+//!
+//! Requests are pipelined: they are queued in a transmit buffer, which is written to the socket
+//! once the virtio queue was processed, and their replies, identified by the handle of their
+//! request, are read as the socket becomes readable. The socket is polled through a nested epoll
+//! FD, which is registered to the event manager in place of the completion event of the async
+//! engine, and which only polls the socket for writability while requests are left to write.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use logger::error;
+use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use vm_memory::{Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap};
+
+use crate::virtio::block::io::UserDataError;
+
+/// Port NBD servers listen on, unless the URI names another one.
+pub const NBD_DEFAULT_PORT: u16 = 10809;
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const NBD_OPTS_MAGIC: u64 = 0x4948_4156_454f_5054;
+const NBD_REP_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const NBD_REQUEST_MAGIC: u32 = 0x2560_9513;
+const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+
+// Handshake flags, sent by the server and by the client.
+const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
+const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+// Options, option replies and information types.
+const NBD_OPT_EXPORT_NAME: u32 = 1;
+const NBD_OPT_GO: u32 = 7;
+const NBD_REP_ACK: u32 = 1;
+const NBD_REP_INFO: u32 = 3;
+const NBD_REP_ERR_UNSUP: u32 = (1 << 31) | 1;
+const NBD_INFO_EXPORT: u16 = 0;
+// Largest option reply read during the handshake.
+const MAX_OPTION_REPLY_LEN: u32 = 64 * 1024;
+
+// Transmission flags of the export.
+const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+const NBD_FLAG_CAN_MULTI_CONN: u16 = 1 << 8;
+
+// Commands and command flags.
+const NBD_CMD_READ: u16 = 0;
+const NBD_CMD_WRITE: u16 = 1;
+const NBD_CMD_DISC: u16 = 2;
+const NBD_CMD_FLUSH: u16 = 3;
+const NBD_CMD_TRIM: u16 = 4;
+const NBD_CMD_WRITE_ZEROES: u16 = 6;
+const NBD_CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+const REQUEST_HEADER_SIZE: usize = 28;
+const REPLY_HEADER_SIZE: usize = 16;
+// Amount of data read from the socket at once.
+const RX_CHUNK_SIZE: usize = 64 * 1024;
+// Time the server is given to reply to the pending requests, when they are drained.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+// How long each read or write of the handshake may block.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub enum Error {
+    /// The NBD URI is malformed.
+    InvalidUri(String),
+    /// Failed to connect to the server.
+    Connect(std::io::Error),
+    /// Failed to communicate with the server.
+    Socket(std::io::Error),
+    /// Failed to poll the socket.
+    Epoll(std::io::Error),
+    /// The server sent an unexpected magic value.
+    InvalidMagic(u64),
+    /// The server sent a malformed option reply.
+    InvalidOptionReply,
+    /// The server does not support the fixed newstyle handshake.
+    UnsupportedHandshake,
+    /// The server refused the export, with the given option reply type.
+    ExportRejected(u32),
+    /// The server did not describe the export.
+    MissingExportInfo,
+    /// A read-write drive was configured on a read-only export.
+    ReadOnlyExport,
+    /// The export cannot be accessed through several connections, one per virtio queue.
+    MultiConnUnsupported,
+    /// The server replied to an unknown request.
+    UnknownHandle(u64),
+    /// The request is not supported by the server.
+    UnsupportedRequest,
+    /// The connection to the server was lost.
+    Disconnected,
+    /// The server failed the request, with the given error value.
+    Server(u32),
+    /// Failed to transfer data between the server and guest memory.
+    Transfer(GuestMemoryError),
+}
+
+/// Whether `path` is an NBD URI rather than the path of a file.
+pub fn is_nbd_uri(path: &str) -> bool {
+    path.starts_with("nbd://") || path.starts_with("nbd+unix://")
+}
+
+/// Address of an NBD server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NbdAddress {
+    /// Path of a Unix domain socket.
+    Unix(PathBuf),
+    /// Host and port of a TCP server.
+    Tcp(String),
+}
+
+/// Export of an NBD server, described by an NBD URI.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NbdUri {
+    pub address: NbdAddress,
+    pub export_name: String,
+}
+
+impl NbdUri {
+    pub fn parse(uri: &str) -> Result<NbdUri, Error> {
+        let invalid = || Error::InvalidUri(uri.to_string());
+
+        if let Some(rest) = uri.strip_prefix("nbd+unix://") {
+            // The authority is empty, and the socket is given as a query parameter.
+            let (path, query) = rest.split_once('?').ok_or_else(invalid)?;
+            let export_name = match path {
+                "" => "",
+                path => path.strip_prefix('/').ok_or_else(invalid)?,
+            };
+            let socket = query
+                .split('&')
+                .find_map(|param| param.strip_prefix("socket="))
+                .filter(|socket| !socket.is_empty())
+                .ok_or_else(invalid)?;
+            return Ok(NbdUri {
+                address: NbdAddress::Unix(PathBuf::from(socket)),
+                export_name: export_name.to_string(),
+            });
+        }
+
+        let rest = uri.strip_prefix("nbd://").ok_or_else(invalid)?;
+        let (authority, export_name) = rest.split_once('/').unwrap_or((rest, ""));
+        if authority.is_empty() || authority.starts_with(':') {
+            return Err(invalid());
+        }
+        // The host may be an IPv6 address between brackets, which holds colons.
+        let has_port = match authority.rfind(']') {
+            Some(end) => authority[end..].contains(':'),
+            None => authority.contains(':'),
+        };
+        let address = match has_port {
+            true => authority.to_string(),
+            false => format!("{}:{}", authority, NBD_DEFAULT_PORT),
+        };
+        Ok(NbdUri {
+            address: NbdAddress::Tcp(address),
+            export_name: export_name.to_string(),
+        })
+    }
+}
+
+enum NbdStream {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl NbdStream {
+    fn connect(address: &NbdAddress) -> Result<NbdStream, Error> {
+        match address {
+            NbdAddress::Unix(path) => UnixStream::connect(path).map(NbdStream::Unix),
+            NbdAddress::Tcp(address) => TcpStream::connect(address.as_str()).and_then(|stream| {
+                // Requests are sent as soon as the virtio queue was processed.
+                stream.set_nodelay(true)?;
+                Ok(NbdStream::Tcp(stream))
+            }),
+        }
+        .map_err(Error::Connect)
+    }
+
+    fn set_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            NbdStream::Unix(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+            NbdStream::Tcp(stream) => {
+                stream.set_read_timeout(timeout)?;
+                stream.set_write_timeout(timeout)
+            }
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            NbdStream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            NbdStream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl Read for NbdStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            NbdStream::Unix(stream) => stream.read(buf),
+            NbdStream::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for NbdStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            NbdStream::Unix(stream) => stream.write(buf),
+            NbdStream::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            NbdStream::Unix(stream) => stream.flush(),
+            NbdStream::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsRawFd for NbdStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            NbdStream::Unix(stream) => stream.as_raw_fd(),
+            NbdStream::Tcp(stream) => stream.as_raw_fd(),
+        }
+    }
+}
+
+fn read_array<const N: usize>(stream: &mut impl Read) -> Result<[u8; N], Error> {
+    let mut bytes = [0u8; N];
+    stream.read_exact(&mut bytes).map_err(Error::Socket)?;
+    Ok(bytes)
+}
+
+fn request_header(command: u16, flags: u16, handle: u64, offset: u64, len: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(REQUEST_HEADER_SIZE);
+    header.extend_from_slice(&NBD_REQUEST_MAGIC.to_be_bytes());
+    header.extend_from_slice(&flags.to_be_bytes());
+    header.extend_from_slice(&command.to_be_bytes());
+    header.extend_from_slice(&handle.to_be_bytes());
+    header.extend_from_slice(&offset.to_be_bytes());
+    header.extend_from_slice(&len.to_be_bytes());
+    header
+}
+
+// Size and transmission flags of an export.
+struct ExportInfo {
+    size: u64,
+    flags: u16,
+}
+
+fn write_option(stream: &mut impl Write, option: u32, data: &[u8]) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(16 + data.len());
+    buf.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&option.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    stream.write_all(&buf).map_err(Error::Socket)
+}
+
+// Selects the export with `NBD_OPT_GO`. Returns `None` if the server does not support it.
+fn opt_go(stream: &mut NbdStream, export_name: &str) -> Result<Option<ExportInfo>, Error> {
+    let mut data = Vec::with_capacity(6 + export_name.len());
+    data.extend_from_slice(&(export_name.len() as u32).to_be_bytes());
+    data.extend_from_slice(export_name.as_bytes());
+    // No information is requested: the server describes the export anyway.
+    data.extend_from_slice(&0u16.to_be_bytes());
+    write_option(stream, NBD_OPT_GO, &data)?;
+
+    let mut info = None;
+    loop {
+        let magic = u64::from_be_bytes(read_array(stream)?);
+        if magic != NBD_REP_MAGIC {
+            return Err(Error::InvalidMagic(magic));
+        }
+        let _option: [u8; 4] = read_array(stream)?;
+        let reply_type = u32::from_be_bytes(read_array(stream)?);
+        let len = u32::from_be_bytes(read_array(stream)?);
+        if len > MAX_OPTION_REPLY_LEN {
+            return Err(Error::InvalidOptionReply);
+        }
+        let mut data = vec![0u8; len as usize];
+        stream.read_exact(&mut data).map_err(Error::Socket)?;
+
+        match reply_type {
+            NBD_REP_ACK => return info.ok_or(Error::MissingExportInfo).map(Some),
+            NBD_REP_INFO if data.len() >= 2 && data[0..2] == NBD_INFO_EXPORT.to_be_bytes() => {
+                if data.len() != 12 {
+                    return Err(Error::InvalidOptionReply);
+                }
+                info = Some(ExportInfo {
+                    size: u64::from_be_bytes(data[2..10].try_into().unwrap()),
+                    flags: u16::from_be_bytes(data[10..12].try_into().unwrap()),
+                });
+            }
+            // Other information about the export is not used.
+            NBD_REP_INFO => (),
+            NBD_REP_ERR_UNSUP => return Ok(None),
+            reply_type => return Err(Error::ExportRejected(reply_type)),
+        }
+    }
+}
+
+fn opt_export_name(
+    stream: &mut NbdStream,
+    export_name: &str,
+    no_zeroes: bool,
+) -> Result<ExportInfo, Error> {
+    write_option(stream, NBD_OPT_EXPORT_NAME, export_name.as_bytes())?;
+    // The server closes the connection if the export does not exist.
+    let size = u64::from_be_bytes(read_array(stream)?);
+    let flags = u16::from_be_bytes(read_array(stream)?);
+    if !no_zeroes {
+        let _zeroes: [u8; 124] = read_array(stream)?;
+    }
+    Ok(ExportInfo { size, flags })
+}
+
+fn handshake(stream: &mut NbdStream, export_name: &str) -> Result<ExportInfo, Error> {
+    let magic = u64::from_be_bytes(read_array(stream)?);
+    if magic != NBD_MAGIC {
+        return Err(Error::InvalidMagic(magic));
+    }
+    // Oldstyle servers send the size of their single export instead.
+    let magic = u64::from_be_bytes(read_array(stream)?);
+    if magic != NBD_OPTS_MAGIC {
+        return Err(Error::UnsupportedHandshake);
+    }
+    let handshake_flags = u16::from_be_bytes(read_array(stream)?);
+    if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+        return Err(Error::UnsupportedHandshake);
+    }
+    let no_zeroes = handshake_flags & NBD_FLAG_NO_ZEROES != 0;
+    let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+    if no_zeroes {
+        client_flags |= NBD_FLAG_C_NO_ZEROES;
+    }
+    stream
+        .write_all(&client_flags.to_be_bytes())
+        .map_err(Error::Socket)?;
+
+    match opt_go(stream, export_name)? {
+        Some(info) => Ok(info),
+        None => opt_export_name(stream, export_name, no_zeroes),
+    }
+}
+
+// A request sent to the server, waiting for its reply.
+struct InFlightRequest<T> {
+    // Guest buffer receiving the data of a read request.
+    read_addr: Option<GuestAddress>,
+    count: u32,
+    // `None` for the requests issued by the engine itself.
+    user_data: Option<T>,
+}
+
+// A completed request, holding the data it read until it is copied to guest memory.
+struct Completion<T> {
+    user_data: T,
+    read: Option<(GuestAddress, Vec<u8>)>,
+    result: Result<u32, Error>,
+}
+
+/// Engine sending the requests of a virtio queue to an NBD server, through its own connection.
+pub struct NbdFileEngine<T> {
+    stream: NbdStream,
+    epoll: Epoll,
+    size: u64,
+    flags: u16,
+    next_handle: u64,
+    in_flight: HashMap<u64, InFlightRequest<T>>,
+    // Requests not written to the socket yet.
+    tx_buf: Vec<u8>,
+    // Data read from the socket, which does not hold a whole reply yet.
+    rx_buf: Vec<u8>,
+    completions: VecDeque<Completion<T>>,
+    // Result of the last request issued by the engine itself.
+    internal_result: Option<Result<u32, Error>>,
+    // Whether the socket is polled for writability.
+    polling_out: bool,
+    disconnected: bool,
+}
+
+impl<T> NbdFileEngine<T> {
+    /// Connects to the export described by `uri`, which must be writable unless `read_only`.
+    pub fn connect(uri: &NbdUri, read_only: bool) -> Result<NbdFileEngine<T>, Error> {
+        Self::connect_with_timeout(uri, read_only, HANDSHAKE_TIMEOUT)
+    }
+
+    // The handshake blocks the VMM thread, so a server which stops answering must not hang it.
+    fn connect_with_timeout(
+        uri: &NbdUri,
+        read_only: bool,
+        timeout: Duration,
+    ) -> Result<NbdFileEngine<T>, Error> {
+        let mut stream = NbdStream::connect(&uri.address)?;
+        stream.set_timeout(Some(timeout)).map_err(Error::Socket)?;
+        let info = handshake(&mut stream, &uri.export_name)?;
+        if !read_only && info.flags & NBD_FLAG_READ_ONLY != 0 {
+            return Err(Error::ReadOnlyExport);
+        }
+
+        // The socket is then only accessed once epoll reports it as ready.
+        stream.set_timeout(None).map_err(Error::Socket)?;
+        stream.set_nonblocking(true).map_err(Error::Socket)?;
+        let epoll = Epoll::new().map_err(Error::Epoll)?;
+        epoll
+            .ctl(
+                ControlOperation::Add,
+                stream.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, 0),
+            )
+            .map_err(Error::Epoll)?;
+
+        Ok(NbdFileEngine {
+            stream,
+            epoll,
+            size: info.size,
+            flags: info.flags,
+            next_handle: 0,
+            in_flight: HashMap::new(),
+            tx_buf: Vec::new(),
+            rx_buf: Vec::new(),
+            completions: VecDeque::new(),
+            internal_result: None,
+            polling_out: false,
+            disconnected: false,
+        })
+    }
+
+    /// Size of the export.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Whether the export can be accessed through several connections.
+    pub fn supports_multi_conn(&self) -> bool {
+        self.flags & NBD_FLAG_CAN_MULTI_CONN != 0
+    }
+
+    /// Whether the server has a write cache to flush.
+    pub fn supports_flush(&self) -> bool {
+        self.flags & NBD_FLAG_SEND_FLUSH != 0
+    }
+
+    /// Whether the server can write zeroes, which also serves discard requests when the server
+    /// cannot trim.
+    pub fn supports_write_zeroes(&self) -> bool {
+        self.flags & NBD_FLAG_SEND_WRITE_ZEROES != 0
+    }
+
+    /// Whether the server can trim, i.e. discard data without having to zero it.
+    pub fn supports_trim(&self) -> bool {
+        self.flags & NBD_FLAG_SEND_TRIM != 0
+    }
+
+    /// Epoll FD which is readable when the socket must be written or read.
+    pub fn epoll(&self) -> &Epoll {
+        &self.epoll
+    }
+
+    // Queues a request, and returns its handle.
+    fn queue_request(
+        &mut self,
+        command: u16,
+        flags: u16,
+        offset: u64,
+        len: u32,
+        request: InFlightRequest<T>,
+    ) -> u64 {
+        let handle = self.next_handle;
+        self.next_handle = self.next_handle.wrapping_add(1);
+        self.tx_buf
+            .extend_from_slice(&request_header(command, flags, handle, offset, len));
+        self.in_flight.insert(handle, request);
+        handle
+    }
+
+    fn check_connected(&self, user_data: T) -> Result<T, UserDataError<T, Error>> {
+        match self.disconnected {
+            true => Err(UserDataError {
+                user_data,
+                error: Error::Disconnected,
+            }),
+            false => Ok(user_data),
+        }
+    }
+
+    pub fn push_read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        let user_data = self.check_connected(user_data)?;
+        // The guest buffer is checked before the data is requested.
+        if !mem.check_range(addr, count as usize) {
+            return Err(UserDataError {
+                user_data,
+                error: Error::Transfer(GuestMemoryError::InvalidGuestAddress(addr)),
+            });
+        }
+
+        let request = InFlightRequest {
+            read_addr: Some(addr),
+            count,
+            user_data: Some(user_data),
+        };
+        self.queue_request(NBD_CMD_READ, 0, offset, count, request);
+        Ok(())
+    }
+
+    pub fn push_write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        let user_data = self.check_connected(user_data)?;
+        let mut data = vec![0u8; count as usize];
+        if let Err(err) = mem.read_slice(&mut data, addr) {
+            return Err(UserDataError {
+                user_data,
+                error: Error::Transfer(err),
+            });
+        }
+
+        let request = InFlightRequest {
+            read_addr: None,
+            count,
+            user_data: Some(user_data),
+        };
+        self.queue_request(NBD_CMD_WRITE, 0, offset, count, request);
+        self.tx_buf.extend_from_slice(&data);
+        Ok(())
+    }
+
+    /// Writes zeroes to `len` bytes at `offset`, which the server may deallocate if
+    /// `may_punch_hole`.
+    pub fn push_write_zeroes(
+        &mut self,
+        offset: u64,
+        len: u32,
+        may_punch_hole: bool,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        let user_data = self.check_connected(user_data)?;
+        if !self.supports_write_zeroes() {
+            return Err(UserDataError {
+                user_data,
+                error: Error::UnsupportedRequest,
+            });
+        }
+
+        let flags = match may_punch_hole {
+            true => 0,
+            false => NBD_CMD_FLAG_NO_HOLE,
+        };
+        let request = InFlightRequest {
+            read_addr: None,
+            count: 0,
+            user_data: Some(user_data),
+        };
+        self.queue_request(NBD_CMD_WRITE_ZEROES, flags, offset, len, request);
+        Ok(())
+    }
+
+    /// Discards `len` bytes at `offset`, whose contents are undefined afterwards.
+    pub fn push_trim(
+        &mut self,
+        offset: u64,
+        len: u32,
+        user_data: T,
+    ) -> Result<(), UserDataError<T, Error>> {
+        let user_data = self.check_connected(user_data)?;
+        if !self.supports_trim() {
+            return Err(UserDataError {
+                user_data,
+                error: Error::UnsupportedRequest,
+            });
+        }
+
+        let request = InFlightRequest {
+            read_addr: None,
+            count: 0,
+            user_data: Some(user_data),
+        };
+        self.queue_request(NBD_CMD_TRIM, 0, offset, len, request);
+        Ok(())
+    }
+
+    pub fn push_flush(&mut self, user_data: T) -> Result<(), UserDataError<T, Error>> {
+        let user_data = self.check_connected(user_data)?;
+        if !self.supports_flush() {
+            return Err(UserDataError {
+                user_data,
+                error: Error::UnsupportedRequest,
+            });
+        }
+
+        let request = InFlightRequest {
+            read_addr: None,
+            count: 0,
+            user_data: Some(user_data),
+        };
+        self.queue_request(NBD_CMD_FLUSH, 0, 0, 0, request);
+        Ok(())
+    }
+
+    /// Writes the queued requests to the socket, as long as it does not block.
+    pub fn kick_submission_queue(&mut self) -> Result<(), Error> {
+        if self.disconnected {
+            return Ok(());
+        }
+        let res = self.send();
+        self.check_connection(res)
+    }
+
+    /// Writes the queued requests and reads the replies, once the epoll FD is readable.
+    pub fn process_socket_events(&mut self) -> Result<(), Error> {
+        if self.disconnected {
+            return Ok(());
+        }
+        let res = self.send().and_then(|_| self.receive());
+        self.check_connection(res)
+    }
+
+    fn send(&mut self) -> Result<(), Error> {
+        while !self.tx_buf.is_empty() {
+            match self.stream.write(&self.tx_buf) {
+                Ok(0) => return Err(Error::Disconnected),
+                Ok(count) => {
+                    self.tx_buf.drain(..count);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(Error::Socket(err)),
+            }
+        }
+
+        // The socket is only polled for writability while requests are left to write.
+        let poll_out = !self.tx_buf.is_empty();
+        if poll_out != self.polling_out {
+            let event_set = match poll_out {
+                true => EventSet::IN | EventSet::OUT,
+                false => EventSet::IN,
+            };
+            self.epoll
+                .ctl(
+                    ControlOperation::Modify,
+                    self.stream.as_raw_fd(),
+                    EpollEvent::new(event_set, 0),
+                )
+                .map_err(Error::Epoll)?;
+            self.polling_out = poll_out;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> Result<(), Error> {
+        loop {
+            let len = self.rx_buf.len();
+            self.rx_buf.resize(len + RX_CHUNK_SIZE, 0);
+            let res = self.stream.read(&mut self.rx_buf[len..]);
+            self.rx_buf.truncate(len + *res.as_ref().unwrap_or(&0));
+            match res {
+                Ok(0) => return Err(Error::Disconnected),
+                Ok(_) => self.parse_replies()?,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(err) => return Err(Error::Socket(err)),
+            }
+        }
+    }
+
+    // Completes the requests whose whole reply was received.
+    fn parse_replies(&mut self) -> Result<(), Error> {
+        let mut consumed = 0;
+        while self.rx_buf.len() - consumed >= REPLY_HEADER_SIZE {
+            let header = &self.rx_buf[consumed..consumed + REPLY_HEADER_SIZE];
+            let magic = u32::from_be_bytes(header[0..4].try_into().unwrap());
+            if magic != NBD_SIMPLE_REPLY_MAGIC {
+                return Err(Error::InvalidMagic(u64::from(magic)));
+            }
+            let error = u32::from_be_bytes(header[4..8].try_into().unwrap());
+            let handle = u64::from_be_bytes(header[8..16].try_into().unwrap());
+
+            let (read_addr, count) = match self.in_flight.get(&handle) {
+                Some(request) => (request.read_addr, request.count),
+                None => return Err(Error::UnknownHandle(handle)),
+            };
+            // Only successful reads are followed by data.
+            let data_len = match (error, read_addr) {
+                (0, Some(_)) => count as usize,
+                _ => 0,
+            };
+            if self.rx_buf.len() - consumed < REPLY_HEADER_SIZE + data_len {
+                break;
+            }
+
+            let data_start = consumed + REPLY_HEADER_SIZE;
+            let read = read_addr.filter(|_| data_len > 0).map(|addr| {
+                (
+                    addr,
+                    self.rx_buf[data_start..data_start + data_len].to_vec(),
+                )
+            });
+            let result = match error {
+                0 => Ok(count),
+                error => Err(Error::Server(error)),
+            };
+            if let Some(request) = self.in_flight.remove(&handle) {
+                self.complete(request.user_data, read, result);
+            }
+            consumed = data_start + data_len;
+        }
+
+        self.rx_buf.drain(..consumed);
+        Ok(())
+    }
+
+    fn complete(
+        &mut self,
+        user_data: Option<T>,
+        read: Option<(GuestAddress, Vec<u8>)>,
+        result: Result<u32, Error>,
+    ) {
+        match user_data {
+            Some(user_data) => self.completions.push_back(Completion {
+                user_data,
+                read,
+                result,
+            }),
+            None => self.internal_result = Some(result),
+        }
+    }
+
+    // Fails the requests waiting for a reply once the connection is broken.
+    fn check_connection(&mut self, res: Result<(), Error>) -> Result<(), Error> {
+        if res.is_err() {
+            self.disconnected = true;
+            // The socket would otherwise be reported as hung up forever.
+            if let Err(err) = self.epoll.ctl(
+                ControlOperation::Delete,
+                self.stream.as_raw_fd(),
+                EpollEvent::default(),
+            ) {
+                error!("Failed to stop polling the NBD socket: {}", err);
+            }
+            self.tx_buf.clear();
+            self.rx_buf.clear();
+            let requests: Vec<_> = self.in_flight.drain().map(|(_, request)| request).collect();
+            for request in requests {
+                self.complete(request.user_data, None, Err(Error::Disconnected));
+            }
+        }
+        res
+    }
+
+    /// Returns the next completed request, after copying the data it read to guest memory.
+    pub fn pop(&mut self, mem: &GuestMemoryMmap) -> Option<(T, Result<u32, Error>)> {
+        let completion = self.completions.pop_front()?;
+        let result = match completion.read {
+            Some((addr, data)) => mem
+                .write_slice(&data, addr)
+                .map_err(Error::Transfer)
+                .and(completion.result),
+            None => completion.result,
+        };
+        Some((completion.user_data, result))
+    }
+
+    /// Waits for the replies to all the requests sent to the server. The connection is considered
+    /// lost if the server does not reply in time.
+    pub fn drain(&mut self, discard: bool) -> Result<(), Error> {
+        self.drain_with_timeout(discard, DRAIN_TIMEOUT)
+    }
+
+    fn drain_with_timeout(&mut self, discard: bool, timeout: Duration) -> Result<(), Error> {
+        let deadline = Instant::now() + timeout;
+        let mut events = vec![EpollEvent::default(); 1];
+        loop {
+            self.process_socket_events()?;
+            if self.in_flight.is_empty() || self.disconnected {
+                break;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            // Round up, so that the last wait doesn't turn into a busy loop.
+            let timeout_ms =
+                i32::try_from((remaining.as_micros() + 999) / 1000).unwrap_or(i32::MAX);
+            match self.epoll.wait(timeout_ms, &mut events) {
+                Ok(0) => {
+                    error!("The NBD server did not reply within {:?}.", timeout);
+                    // The connection is given up, since late replies would not match any request.
+                    return self.check_connection(Err(Error::Disconnected));
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                res => {
+                    res.map_err(Error::Epoll)?;
+                }
+            }
+        }
+
+        if discard {
+            self.completions.clear();
+        }
+        Ok(())
+    }
+
+    /// Waits for the replies to all the requests, then has the server flush its write cache.
+    pub fn drain_and_flush(&mut self, discard: bool) -> Result<(), Error> {
+        self.drain(discard)?;
+        if !self.supports_flush() {
+            return Ok(());
+        }
+        if self.disconnected {
+            return Err(Error::Disconnected);
+        }
+
+        let request = InFlightRequest {
+            read_addr: None,
+            count: 0,
+            user_data: None,
+        };
+        self.queue_request(NBD_CMD_FLUSH, 0, 0, 0, request);
+        self.drain(discard)?;
+        self.internal_result
+            .take()
+            .unwrap_or(Err(Error::Disconnected))
+            .map(|_| ())
+    }
+}
+
+impl<T> Drop for NbdFileEngine<T> {
+    fn drop(&mut self) {
+        // The disconnect request is only sent if it cannot be interleaved with another request,
+        // since the server also handles the connection being closed.
+        if !self.disconnected && self.tx_buf.is_empty() {
+            let _ = self.stream.write(&request_header(NBD_CMD_DISC, 0, 0, 0, 0));
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::net::TcpListener;
+    use std::os::unix::net::UnixListener;
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+
+    use utils::tempdir::TempDir;
+    use vm_memory::test_utils::create_anon_guest_memory;
+
+    use super::*;
+
+    pub const EXPORT_NAME: &str = "disk";
+    const NBD_REP_ERR_UNKNOWN: u32 = (1 << 31) | 6;
+    const EINVAL: u32 = 22;
+
+    /// Transmission flags of a writable export supporting all the requests.
+    pub const ALL_FLAGS: u16 =
+        NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_WRITE_ZEROES | NBD_FLAG_SEND_TRIM;
+
+    fn write_option_reply(
+        stream: &mut impl Write,
+        option: u32,
+        reply_type: u32,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let mut buf = NBD_REP_MAGIC.to_be_bytes().to_vec();
+        buf.extend_from_slice(&option.to_be_bytes());
+        buf.extend_from_slice(&reply_type.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        stream.write_all(&buf).map_err(Error::Socket)
+    }
+
+    // Server side of the handshake, which only knows `NBD_OPT_EXPORT_NAME` unless `opt_go`.
+    fn serve_handshake<S: Read + Write>(
+        stream: &mut S,
+        size: u64,
+        flags: u16,
+        opt_go: bool,
+    ) -> Result<(), Error> {
+        let mut greeting = NBD_MAGIC.to_be_bytes().to_vec();
+        greeting.extend_from_slice(&NBD_OPTS_MAGIC.to_be_bytes());
+        greeting.extend_from_slice(&(NBD_FLAG_FIXED_NEWSTYLE | NBD_FLAG_NO_ZEROES).to_be_bytes());
+        stream.write_all(&greeting).map_err(Error::Socket)?;
+        let client_flags = u32::from_be_bytes(read_array(stream)?);
+        assert_eq!(
+            client_flags,
+            NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES
+        );
+
+        loop {
+            assert_eq!(u64::from_be_bytes(read_array(stream)?), NBD_OPTS_MAGIC);
+            let option = u32::from_be_bytes(read_array(stream)?);
+            let len = u32::from_be_bytes(read_array(stream)?);
+            let mut data = vec![0u8; len as usize];
+            stream.read_exact(&mut data).map_err(Error::Socket)?;
+
+            match option {
+                NBD_OPT_GO if opt_go => {
+                    let name_len = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+                    if &data[4..4 + name_len] != EXPORT_NAME.as_bytes() {
+                        write_option_reply(stream, option, NBD_REP_ERR_UNKNOWN, &[])?;
+                        continue;
+                    }
+                    let mut info = NBD_INFO_EXPORT.to_be_bytes().to_vec();
+                    info.extend_from_slice(&size.to_be_bytes());
+                    info.extend_from_slice(&flags.to_be_bytes());
+                    write_option_reply(stream, option, NBD_REP_INFO, &info)?;
+                    return write_option_reply(stream, option, NBD_REP_ACK, &[]);
+                }
+                NBD_OPT_EXPORT_NAME => {
+                    if data != EXPORT_NAME.as_bytes() {
+                        return Err(Error::ExportRejected(NBD_REP_ERR_UNKNOWN));
+                    }
+                    let mut info = size.to_be_bytes().to_vec();
+                    info.extend_from_slice(&flags.to_be_bytes());
+                    return stream.write_all(&info).map_err(Error::Socket);
+                }
+                option => write_option_reply(stream, option, NBD_REP_ERR_UNSUP, &[])?,
+            }
+        }
+    }
+
+    // Serves the requests of a client on an in-memory disk, until it disconnects.
+    fn serve_requests<S: Read + Write>(stream: &mut S, disk: &Mutex<Vec<u8>>) -> Result<(), Error> {
+        loop {
+            let header: [u8; REQUEST_HEADER_SIZE] = read_array(stream)?;
+            assert_eq!(
+                u32::from_be_bytes(header[0..4].try_into().unwrap()),
+                NBD_REQUEST_MAGIC
+            );
+            let command = u16::from_be_bytes(header[6..8].try_into().unwrap());
+            let offset = u64::from_be_bytes(header[16..24].try_into().unwrap()) as usize;
+            let len = u32::from_be_bytes(header[24..28].try_into().unwrap()) as usize;
+
+            let mut disk = disk.lock().unwrap();
+            let range = offset..offset + len;
+            let in_range = range.end <= disk.len();
+            let mut data = Vec::new();
+            let error = match command {
+                NBD_CMD_READ if in_range => {
+                    data.extend_from_slice(&disk[range]);
+                    0
+                }
+                NBD_CMD_WRITE => {
+                    let mut buf = vec![0u8; len];
+                    stream.read_exact(&mut buf).map_err(Error::Socket)?;
+                    if in_range {
+                        disk[range].copy_from_slice(&buf);
+                        0
+                    } else {
+                        EINVAL
+                    }
+                }
+                NBD_CMD_WRITE_ZEROES if in_range => {
+                    disk[range].fill(0);
+                    0
+                }
+                // Trimmed data is left as is, which tells trims apart from write zeroes.
+                NBD_CMD_TRIM if in_range => 0,
+                NBD_CMD_FLUSH => 0,
+                NBD_CMD_DISC => return Ok(()),
+                _ => EINVAL,
+            };
+
+            let mut reply = NBD_SIMPLE_REPLY_MAGIC.to_be_bytes().to_vec();
+            reply.extend_from_slice(&error.to_be_bytes());
+            reply.extend_from_slice(&header[8..16]);
+            reply.extend_from_slice(&data);
+            stream.write_all(&reply).map_err(Error::Socket)?;
+        }
+    }
+
+    /// NBD server exporting an in-memory disk over a Unix domain socket, standing in for
+    /// `nbdkit` in tests.
+    pub struct TestServer {
+        pub disk: Arc<Mutex<Vec<u8>>>,
+        pub uri: String,
+        _dir: TempDir,
+    }
+
+    impl TestServer {
+        pub fn new(size: usize, flags: u16) -> TestServer {
+            Self::with_options(size, flags, true)
+        }
+
+        pub fn with_options(size: usize, flags: u16, opt_go: bool) -> TestServer {
+            let dir = TempDir::new().unwrap();
+            let path = dir.as_path().join("nbd.sock");
+            let listener = UnixListener::bind(&path).unwrap();
+            let disk = Arc::new(Mutex::new(vec![0u8; size]));
+
+            let server_disk = disk.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    let disk = server_disk.clone();
+                    thread::spawn(move || {
+                        let size = disk.lock().unwrap().len() as u64;
+                        serve_handshake(&mut stream, size, flags, opt_go)?;
+                        serve_requests(&mut stream, &disk)
+                    });
+                }
+            });
+
+            TestServer {
+                disk,
+                uri: format!(
+                    "nbd+unix:///{}?socket={}",
+                    EXPORT_NAME,
+                    path.to_str().unwrap()
+                ),
+                _dir: dir,
+            }
+        }
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(7).wrapping_add(seed))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_uri() {
+        assert_eq!(
+            NbdUri::parse("nbd+unix:///disk?socket=/run/nbd.sock").unwrap(),
+            NbdUri {
+                address: NbdAddress::Unix(PathBuf::from("/run/nbd.sock")),
+                export_name: "disk".to_string(),
+            }
+        );
+        assert_eq!(
+            NbdUri::parse("nbd+unix://?socket=nbd.sock").unwrap(),
+            NbdUri {
+                address: NbdAddress::Unix(PathBuf::from("nbd.sock")),
+                export_name: String::new(),
+            }
+        );
+        assert_eq!(
+            NbdUri::parse("nbd://127.0.0.1:10810/disk").unwrap(),
+            NbdUri {
+                address: NbdAddress::Tcp("127.0.0.1:10810".to_string()),
+                export_name: "disk".to_string(),
+            }
+        );
+        assert_eq!(
+            NbdUri::parse("nbd://localhost").unwrap().address,
+            NbdAddress::Tcp("localhost:10809".to_string())
+        );
+        assert_eq!(
+            NbdUri::parse("nbd://[::1]/disk").unwrap().address,
+            NbdAddress::Tcp("[::1]:10809".to_string())
+        );
+        assert_eq!(
+            NbdUri::parse("nbd://[::1]:10810/").unwrap().address,
+            NbdAddress::Tcp("[::1]:10810".to_string())
+        );
+
+        for uri in [
+            "/path/to/disk",
+            "nbd://",
+            "nbd://:10809/disk",
+            "nbd+unix:///disk",
+            "nbd+unix:///disk?socket=",
+            "nbd+unix://host/disk?socket=nbd.sock",
+        ] {
+            assert!(
+                matches!(NbdUri::parse(uri), Err(Error::InvalidUri(_))),
+                "{}",
+                uri
+            );
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let connect = |uri: &str, read_only| {
+            NbdFileEngine::<()>::connect(&NbdUri::parse(uri).unwrap(), read_only)
+        };
+
+        // Both ways of selecting the export are supported.
+        for opt_go in [true, false] {
+            let server = TestServer::with_options(0x2000, ALL_FLAGS, opt_go);
+            let engine = connect(&server.uri, false).unwrap();
+            assert_eq!(engine.size(), 0x2000);
+            assert!(engine.supports_flush());
+            assert!(engine.supports_write_zeroes());
+            assert!(!engine.supports_multi_conn());
+
+            let uri = server.uri.replace(EXPORT_NAME, "missing");
+            match opt_go {
+                true => assert!(matches!(
+                    connect(&uri, false),
+                    Err(Error::ExportRejected(NBD_REP_ERR_UNKNOWN))
+                )),
+                false => assert!(matches!(connect(&uri, false), Err(Error::Socket(_)))),
+            }
+        }
+
+        // Read-only exports can only back read-only drives.
+        let server = TestServer::new(0x2000, NBD_FLAG_READ_ONLY | NBD_FLAG_CAN_MULTI_CONN);
+        assert!(matches!(
+            connect(&server.uri, false),
+            Err(Error::ReadOnlyExport)
+        ));
+        let engine = connect(&server.uri, true).unwrap();
+        assert!(!engine.supports_flush());
+        assert!(!engine.supports_write_zeroes());
+        assert!(engine.supports_multi_conn());
+
+        // Servers must send the NBD magic value.
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("nbd.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"NOTMAGIC").unwrap();
+        });
+        let uri = format!("nbd+unix:///?socket={}", path.to_str().unwrap());
+        assert!(matches!(
+            connect(&uri, false),
+            Err(Error::InvalidMagic(0x4e4f_544d_4147_4943))
+        ));
+    }
+
+    #[test]
+    fn test_requests() {
+        let server = TestServer::new(0x4000, ALL_FLAGS);
+        let mut engine =
+            NbdFileEngine::<u32>::connect(&NbdUri::parse(&server.uri).unwrap(), false).unwrap();
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x1_0000)], false).unwrap();
+
+        // Several requests are pipelined before their replies are read.
+        let data = pattern(0x2000, 3);
+        mem.write_slice(&data, GuestAddress(0x1000)).unwrap();
+        engine
+            .push_write(0x1000, &mem, GuestAddress(0x1000), 0x1000, 1)
+            .unwrap();
+        engine
+            .push_write(0x2000, &mem, GuestAddress(0x2000), 0x1000, 2)
+            .unwrap();
+        engine.push_flush(3).unwrap();
+        engine.push_write_zeroes(0x2800, 0x200, false, 4).unwrap();
+        engine.push_trim(0x1000, 0x200, 5).unwrap();
+        engine.kick_submission_queue().unwrap();
+        engine.drain(false).unwrap();
+        for user_data in 1..=5 {
+            let (popped, res) = engine.pop(&mem).unwrap();
+            assert_eq!(popped, user_data);
+            assert_eq!(
+                res.unwrap(),
+                [0x1000, 0x1000, 0, 0, 0][user_data as usize - 1]
+            );
+        }
+        assert!(engine.pop(&mem).is_none());
+
+        let mut expected = vec![0u8; 0x4000];
+        expected[0x1000..0x3000].copy_from_slice(&data);
+        expected[0x2800..0x2a00].fill(0);
+        assert_eq!(*server.disk.lock().unwrap(), expected);
+
+        // Read the data back, and check errors reported by the server.
+        engine
+            .push_read(0x800, &mem, GuestAddress(0x8000), 0x3000, 5)
+            .unwrap();
+        engine
+            .push_read(0x3e00, &mem, GuestAddress(0x8000), 0x400, 6)
+            .unwrap();
+        engine.kick_submission_queue().unwrap();
+        engine.drain(false).unwrap();
+        let (popped, res) = engine.pop(&mem).unwrap();
+        assert_eq!((popped, res.unwrap()), (5, 0x3000));
+        let mut buf = vec![0u8; 0x3000];
+        mem.read_slice(&mut buf, GuestAddress(0x8000)).unwrap();
+        assert_eq!(buf, expected[0x800..0x3800]);
+        let (popped, res) = engine.pop(&mem).unwrap();
+        assert_eq!(popped, 6);
+        assert!(matches!(res, Err(Error::Server(EINVAL))));
+
+        // Guest memory beyond its end cannot be accessed.
+        assert!(matches!(
+            engine.push_read(0, &mem, GuestAddress(0xff00), 0x200, 7),
+            Err(UserDataError {
+                user_data: 7,
+                error: Error::Transfer(_),
+            })
+        ));
+        assert!(matches!(
+            engine.push_write(0, &mem, GuestAddress(0xff00), 0x200, 8),
+            Err(UserDataError {
+                user_data: 8,
+                error: Error::Transfer(_),
+            })
+        ));
+
+        engine.drain_and_flush(false).unwrap();
+    }
+
+    #[test]
+    fn test_unsupported_requests() {
+        let server = TestServer::new(0x1000, 0);
+        let mut engine =
+            NbdFileEngine::<u32>::connect(&NbdUri::parse(&server.uri).unwrap(), false).unwrap();
+        assert!(matches!(
+            engine.push_flush(1),
+            Err(UserDataError {
+                user_data: 1,
+                error: Error::UnsupportedRequest,
+            })
+        ));
+        assert!(matches!(
+            engine.push_write_zeroes(0, 0x200, true, 2),
+            Err(UserDataError {
+                user_data: 2,
+                error: Error::UnsupportedRequest,
+            })
+        ));
+        assert!(matches!(
+            engine.push_trim(0, 0x200, 3),
+            Err(UserDataError {
+                user_data: 3,
+                error: Error::UnsupportedRequest,
+            })
+        ));
+        // There is no write cache to flush.
+        engine.drain_and_flush(false).unwrap();
+    }
+
+    #[test]
+    fn test_disconnect() {
+        // The server closes the connection right after the handshake.
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("nbd.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            serve_handshake(&mut stream, 0x1000, ALL_FLAGS, true).unwrap();
+            let _header: [u8; REQUEST_HEADER_SIZE] = read_array(&mut stream).unwrap();
+        });
+        let uri = format!(
+            "nbd+unix:///{}?socket={}",
+            EXPORT_NAME,
+            path.to_str().unwrap()
+        );
+        let mut engine =
+            NbdFileEngine::<u32>::connect(&NbdUri::parse(&uri).unwrap(), false).unwrap();
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x1000)], false).unwrap();
+
+        // The pending requests fail once the connection is lost, and so do the next ones.
+        engine
+            .push_read(0, &mem, GuestAddress(0), 0x200, 1)
+            .unwrap();
+        engine.kick_submission_queue().unwrap();
+        assert!(matches!(engine.drain(false), Err(Error::Disconnected)));
+        let (popped, res) = engine.pop(&mem).unwrap();
+        assert_eq!(popped, 1);
+        assert!(matches!(res, Err(Error::Disconnected)));
+        assert!(matches!(
+            engine.push_read(0, &mem, GuestAddress(0), 0x200, 2),
+            Err(UserDataError {
+                user_data: 2,
+                error: Error::Disconnected,
+            })
+        ));
+        assert!(matches!(
+            engine.drain_and_flush(false),
+            Err(Error::Disconnected)
+        ));
+    }
+
+    #[test]
+    fn test_drain_timeout() {
+        // The server stops replying after the handshake.
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("nbd.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            serve_handshake(&mut stream, 0x1000, ALL_FLAGS, true).unwrap();
+            let _header: [u8; REQUEST_HEADER_SIZE] = read_array(&mut stream).unwrap();
+            let _ = done_rx.recv();
+        });
+        let uri = format!(
+            "nbd+unix:///{}?socket={}",
+            EXPORT_NAME,
+            path.to_str().unwrap()
+        );
+        let mut engine =
+            NbdFileEngine::<u32>::connect(&NbdUri::parse(&uri).unwrap(), false).unwrap();
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x1000)], false).unwrap();
+
+        // The pending request fails once the timeout expires, and the connection is given up.
+        engine
+            .push_read(0, &mem, GuestAddress(0), 0x200, 1)
+            .unwrap();
+        engine.kick_submission_queue().unwrap();
+        assert!(matches!(
+            engine.drain_with_timeout(false, Duration::from_millis(100)),
+            Err(Error::Disconnected)
+        ));
+        let (popped, res) = engine.pop(&mem).unwrap();
+        assert_eq!(popped, 1);
+        assert!(matches!(res, Err(Error::Disconnected)));
+        assert!(matches!(
+            engine.push_read(0, &mem, GuestAddress(0), 0x200, 2),
+            Err(UserDataError {
+                user_data: 2,
+                error: Error::Disconnected,
+            })
+        ));
+        done_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        // The server accepts the connection, but never sends its greeting.
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("nbd.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let (_stream, _) = listener.accept().unwrap();
+            let _ = done_rx.recv();
+        });
+        let uri = format!(
+            "nbd+unix:///{}?socket={}",
+            EXPORT_NAME,
+            path.to_str().unwrap()
+        );
+        assert!(matches!(
+            NbdFileEngine::<u32>::connect_with_timeout(
+                &NbdUri::parse(&uri).unwrap(),
+                false,
+                Duration::from_millis(100)
+            ),
+            Err(Error::Socket(err))
+                if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut
+        ));
+        done_tx.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let disk = Mutex::new(vec![0u8; 0x1000]);
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            serve_handshake(&mut stream, 0x1000, ALL_FLAGS, true).unwrap();
+            serve_requests(&mut stream, &disk).unwrap();
+            disk.into_inner().unwrap()
+        });
+
+        let uri = format!("nbd://127.0.0.1:{}/{}", port, EXPORT_NAME);
+        let mut engine =
+            NbdFileEngine::<()>::connect(&NbdUri::parse(&uri).unwrap(), false).unwrap();
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x1000)], false).unwrap();
+        let data = pattern(0x400, 5);
+        mem.write_slice(&data, GuestAddress(0)).unwrap();
+        engine
+            .push_write(0x200, &mem, GuestAddress(0), 0x400, ())
+            .unwrap();
+        engine.kick_submission_queue().unwrap();
+        engine.drain(false).unwrap();
+        assert_eq!(engine.pop(&mem).unwrap().1.unwrap(), 0x400);
+
+        // Dropping the engine disconnects from the server.
+        drop(engine);
+        let disk = server.join().unwrap();
+        assert_eq!(disk[0x200..0x600], data);
+    }
+}
@@ -0,0 +1,507 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Redirects the writes to a read-only base disk image to a copy-on-write overlay file.
+//!
+//! The disk is split in blocks of `OVERLAY_BLOCK_SIZE` bytes. The first write to a block copies
+//! it from the base image to the overlay file and sets its bit in the allocation bitmap; the
+//! block is then only accessed in the overlay file. The overlay file starts with a header
+//! describing the disk, followed by the bitmap and by the data area, which holds each block at
+//! its offset in the disk. The data area is sparse, so the overlay file only takes the space of
+//! the blocks written by the guest. The header records the size and the modification time of the
+//! base image, so that an overlay is not attached to a base image which changed since.
+//!
+//! An empty overlay file is initialized for the base image when it is opened. A block is only
+//! marked as allocated once its data was synced to the overlay file, so that a crash cannot
+//! leave the bitmap pointing to blocks which never reached the disk.
+
+use std::fs::File;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::sync::{Arc, Mutex};
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+/// Magic value found at the start of overlay files.
+pub const OVERLAY_MAGIC: [u8; 8] = *b"FCOVRLY1";
+/// Granularity at which the blocks of the base image are copied to the overlay file.
+pub const OVERLAY_BLOCK_SIZE: u64 = 4096;
+
+// Magic value, block size, reserved word, disk size, modification time of the base image in
+// seconds and nanoseconds and reserved word, padded to a block.
+const HEADER_SIZE: u64 = OVERLAY_BLOCK_SIZE;
+const HEADER_FIELDS_SIZE: usize = 40;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to access the base image or the overlay file.
+    Io(std::io::Error),
+    /// The overlay file does not start with the overlay magic value.
+    InvalidMagic,
+    /// The overlay file uses a block size which is not supported.
+    InvalidBlockSize(u32),
+    /// The overlay file was created for a base image of a different size.
+    SizeMismatch(u64),
+    /// The base image was modified since the overlay file was created.
+    BaseImageChanged,
+    /// The overlay file was opened read-only.
+    ReadOnly,
+    /// The access is beyond the size of the disk.
+    OutOfBounds,
+    /// Discard and write zeroes requests are not supported.
+    UnsupportedRequest,
+    /// Failed to transfer data between the disk and guest memory.
+    Transfer(GuestMemoryError),
+}
+
+/// A read-only base image whose written blocks are stored in an overlay file.
+pub struct OverlayImage {
+    base: File,
+    overlay: File,
+    read_only: bool,
+    size: u64,
+    // One bit per block, set when the block is stored in the overlay file.
+    bitmap: Vec<u8>,
+    data_offset: u64,
+}
+
+impl OverlayImage {
+    /// Opens the overlay file of the `base` image, initializing it if it is empty.
+    pub fn from_files(base: File, overlay: File, read_only: bool) -> Result<Self, Error> {
+        let base_metadata = base.metadata().map_err(Error::Io)?;
+        let size = base_metadata.len();
+        let mtime = base_metadata.mtime();
+        let mtime_nsec = base_metadata.mtime_nsec() as u32;
+        let num_blocks = (size + OVERLAY_BLOCK_SIZE - 1) / OVERLAY_BLOCK_SIZE;
+        let bitmap_len = (num_blocks + 7) / 8;
+        let data_offset = (HEADER_SIZE + bitmap_len + OVERLAY_BLOCK_SIZE - 1) / OVERLAY_BLOCK_SIZE
+            * OVERLAY_BLOCK_SIZE;
+
+        let overlay_len = overlay.metadata().map_err(Error::Io)?.len();
+        if overlay_len == 0 && !read_only {
+            let mut header = [0u8; HEADER_FIELDS_SIZE];
+            header[0..8].copy_from_slice(&OVERLAY_MAGIC);
+            header[8..12].copy_from_slice(&(OVERLAY_BLOCK_SIZE as u32).to_le_bytes());
+            header[16..24].copy_from_slice(&size.to_le_bytes());
+            header[24..32].copy_from_slice(&mtime.to_le_bytes());
+            header[32..36].copy_from_slice(&mtime_nsec.to_le_bytes());
+            overlay.write_all_at(&header, 0).map_err(Error::Io)?;
+            // The bitmap and the data area are left as holes, which read as zeros.
+            overlay.set_len(data_offset).map_err(Error::Io)?;
+        } else {
+            let mut header = [0u8; HEADER_FIELDS_SIZE];
+            overlay.read_exact_at(&mut header, 0).map_err(Error::Io)?;
+            if header[0..8] != OVERLAY_MAGIC {
+                return Err(Error::InvalidMagic);
+            }
+            let mut word = [0u8; 4];
+            word.copy_from_slice(&header[8..12]);
+            let block_size = u32::from_le_bytes(word);
+            if u64::from(block_size) != OVERLAY_BLOCK_SIZE {
+                return Err(Error::InvalidBlockSize(block_size));
+            }
+            let mut dword = [0u8; 8];
+            dword.copy_from_slice(&header[16..24]);
+            let disk_size = u64::from_le_bytes(dword);
+            if disk_size != size {
+                return Err(Error::SizeMismatch(disk_size));
+            }
+            // The blocks which were not copied to the overlay file must be the ones the guest
+            // read from the base image.
+            dword.copy_from_slice(&header[24..32]);
+            word.copy_from_slice(&header[32..36]);
+            if i64::from_le_bytes(dword) != mtime || u32::from_le_bytes(word) != mtime_nsec {
+                return Err(Error::BaseImageChanged);
+            }
+        }
+
+        let mut bitmap = vec![0u8; bitmap_len as usize];
+        overlay
+            .read_exact_at(&mut bitmap, HEADER_SIZE)
+            .map_err(Error::Io)?;
+
+        Ok(OverlayImage {
+            base,
+            overlay,
+            read_only,
+            size,
+            bitmap,
+            data_offset,
+        })
+    }
+
+    /// Size of the disk, which is the size of the base image.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn is_allocated(&self, block: u64) -> bool {
+        self.bitmap[(block / 8) as usize] & (1 << (block % 8)) != 0
+    }
+
+    fn check_bounds(&self, len: usize, offset: u64) -> Result<(), Error> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+
+    /// Reads `buf.len()` bytes of the disk at `offset`.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        self.check_bounds(buf.len(), offset)?;
+
+        let mut done = 0;
+        while done < buf.len() {
+            let offset = offset + done as u64;
+            let len =
+                (buf.len() - done).min((OVERLAY_BLOCK_SIZE - offset % OVERLAY_BLOCK_SIZE) as usize);
+            let chunk = &mut buf[done..done + len];
+            if self.is_allocated(offset / OVERLAY_BLOCK_SIZE) {
+                self.overlay.read_exact_at(chunk, self.data_offset + offset)
+            } else {
+                self.base.read_exact_at(chunk, offset)
+            }
+            .map_err(Error::Io)?;
+            done += len;
+        }
+        Ok(())
+    }
+
+    /// Writes `buf` to the disk at `offset`, copying the blocks it partially covers from the base
+    /// image first.
+    pub fn write_at(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.check_bounds(buf.len(), offset)?;
+
+        let mut new_blocks = Vec::new();
+        let mut done = 0;
+        while done < buf.len() {
+            let offset = offset + done as u64;
+            let block = offset / OVERLAY_BLOCK_SIZE;
+            let offset_in_block = (offset % OVERLAY_BLOCK_SIZE) as usize;
+            let len = (buf.len() - done).min(OVERLAY_BLOCK_SIZE as usize - offset_in_block);
+            let chunk = &buf[done..done + len];
+            if self.is_allocated(block) {
+                self.overlay
+                    .write_all_at(chunk, self.data_offset + offset)
+                    .map_err(Error::Io)?;
+            } else {
+                self.copy_block(block, offset_in_block, chunk)?;
+                new_blocks.push(block);
+            }
+            done += len;
+        }
+        self.allocate_blocks(&new_blocks)
+    }
+
+    // Writes `chunk` at `offset_in_block` in the overlay copy of `block`, which is not allocated
+    // yet.
+    fn copy_block(
+        &mut self,
+        block: u64,
+        offset_in_block: usize,
+        chunk: &[u8],
+    ) -> Result<(), Error> {
+        let block_offset = block * OVERLAY_BLOCK_SIZE;
+        // The last block is shorter when the disk size is not a multiple of the block size.
+        let block_len = (self.size - block_offset).min(OVERLAY_BLOCK_SIZE) as usize;
+        if chunk.len() == block_len {
+            self.overlay
+                .write_all_at(chunk, self.data_offset + block_offset)
+                .map_err(Error::Io)?;
+        } else {
+            let mut data = vec![0u8; block_len];
+            self.base
+                .read_exact_at(&mut data, block_offset)
+                .map_err(Error::Io)?;
+            data[offset_in_block..offset_in_block + chunk.len()].copy_from_slice(chunk);
+            self.overlay
+                .write_all_at(&data, self.data_offset + block_offset)
+                .map_err(Error::Io)?;
+        }
+        Ok(())
+    }
+
+    // Marks the `blocks` copied to the overlay file as allocated, once their data is on the disk.
+    // The blocks are sorted, as they come from a single write.
+    fn allocate_blocks(&mut self, blocks: &[u64]) -> Result<(), Error> {
+        let (first, last) = match (blocks.first(), blocks.last()) {
+            (Some(first), Some(last)) => ((first / 8) as usize, (last / 8) as usize),
+            _ => return Ok(()),
+        };
+        // The bitmap may reach the disk at any time after it is written.
+        self.overlay.sync_data().map_err(Error::Io)?;
+        for block in blocks {
+            self.bitmap[(block / 8) as usize] |= 1 << (block % 8);
+        }
+        self.overlay
+            .write_all_at(&self.bitmap[first..=last], HEADER_SIZE + first as u64)
+            .map_err(Error::Io)
+    }
+
+    /// Writes the blocks and the bitmap to the disk.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.overlay.sync_all().map_err(Error::Io)
+    }
+}
+
+/// IO engine accessing a disk through an overlay image, which may be shared by the engines of
+/// the queues of the device.
+pub struct OverlayFileEngine {
+    image: Arc<Mutex<OverlayImage>>,
+}
+
+impl OverlayFileEngine {
+    pub fn new(image: Arc<Mutex<OverlayImage>>) -> OverlayFileEngine {
+        OverlayFileEngine { image }
+    }
+
+    pub fn read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, Error> {
+        let mut buf = vec![0u8; count as usize];
+        self.image
+            .lock()
+            .expect("Poisoned lock")
+            .read_at(&mut buf, offset)?;
+        mem.write_slice(&buf, addr).map_err(Error::Transfer)?;
+        Ok(count)
+    }
+
+    pub fn write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, Error> {
+        let mut buf = vec![0u8; count as usize];
+        mem.read_slice(&mut buf, addr).map_err(Error::Transfer)?;
+        self.image
+            .lock()
+            .expect("Poisoned lock")
+            .write_at(&buf, offset)?;
+        Ok(count)
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.image.lock().expect("Poisoned lock").flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::os::unix::io::AsRawFd;
+
+    use utils::tempfile::TempFile;
+    use vm_memory::test_utils::create_anon_guest_memory;
+
+    use super::*;
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect()
+    }
+
+    // Returns a base image of `size` bytes holding a pattern, and an empty overlay file.
+    fn create_files(size: usize) -> (File, File) {
+        let mut base = TempFile::new().unwrap().into_file();
+        base.write_all(&pattern(size, 0x5a)).unwrap();
+        let overlay = TempFile::new().unwrap().into_file();
+        (base, overlay)
+    }
+
+    #[test]
+    fn test_read_write() {
+        let size = 10 * OVERLAY_BLOCK_SIZE as usize + 512;
+        let (base, overlay) = create_files(size);
+        let base_clone = base.try_clone().unwrap();
+        let overlay_clone = overlay.try_clone().unwrap();
+        let mut image = OverlayImage::from_files(base, overlay, false).unwrap();
+        assert_eq!(image.size(), size as u64);
+
+        // Reads are served by the base image until the blocks are written.
+        let mut expected = pattern(size, 0x5a);
+        let mut buf = vec![0u8; size];
+        image.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, expected);
+
+        // A partial write of a block, a write spanning blocks, a full block and the last block.
+        for (offset, len, seed) in [
+            (100, 200, 1u8),
+            (3 * 4096 - 10, 4096 + 20, 2),
+            (6 * 4096, 4096, 3),
+            (size - 300, 300, 4),
+        ] {
+            let data = pattern(len, seed);
+            image.write_at(&data, offset as u64).unwrap();
+            expected[offset..offset + len].copy_from_slice(&data);
+        }
+        image.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, expected);
+        for block in 0..11 {
+            assert_eq!(
+                image.is_allocated(block),
+                [0, 2, 3, 4, 6, 10].contains(&block)
+            );
+        }
+
+        // The base image is never written.
+        let mut base_data = vec![0u8; size];
+        base_clone.read_exact_at(&mut base_data, 0).unwrap();
+        assert_eq!(base_data, pattern(size, 0x5a));
+
+        // Accesses beyond the disk fail.
+        assert!(matches!(
+            image.read_at(&mut [0u8; 2], size as u64 - 1),
+            Err(Error::OutOfBounds)
+        ));
+        assert!(matches!(
+            image.write_at(&[0u8; 1], size as u64),
+            Err(Error::OutOfBounds)
+        ));
+
+        // The blocks are found in the overlay file when it is opened again.
+        image.flush().unwrap();
+        drop(image);
+        let image =
+            OverlayImage::from_files(base_clone.try_clone().unwrap(), overlay_clone, true).unwrap();
+        image.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_open_invalid() {
+        let (base, overlay) = create_files(8192);
+        let base_clone = base.try_clone().unwrap();
+        let overlay_clone = overlay.try_clone().unwrap();
+
+        // An empty overlay file cannot be initialized read-only.
+        assert!(matches!(
+            OverlayImage::from_files(base, overlay, true),
+            Err(Error::Io(_))
+        ));
+
+        OverlayImage::from_files(
+            base_clone.try_clone().unwrap(),
+            overlay_clone.try_clone().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        // The overlay file must match the size and the modification time of the base image.
+        let (other_base, _) = create_files(4096);
+        assert!(matches!(
+            OverlayImage::from_files(other_base, overlay_clone.try_clone().unwrap(), false),
+            Err(Error::SizeMismatch(8192))
+        ));
+        let times = [
+            libc::timespec {
+                tv_sec: 0,
+                tv_nsec: libc::UTIME_OMIT,
+            },
+            libc::timespec {
+                tv_sec: 1,
+                tv_nsec: 0,
+            },
+        ];
+        // SAFETY: The file descriptor is valid and `times` holds two timespec structures.
+        assert_eq!(
+            unsafe { libc::futimens(base_clone.as_raw_fd(), times.as_ptr()) },
+            0
+        );
+        assert!(matches!(
+            OverlayImage::from_files(
+                base_clone.try_clone().unwrap(),
+                overlay_clone.try_clone().unwrap(),
+                false
+            ),
+            Err(Error::BaseImageChanged)
+        ));
+        // A new overlay is needed for the modified base image.
+        overlay_clone.set_len(0).unwrap();
+        OverlayImage::from_files(
+            base_clone.try_clone().unwrap(),
+            overlay_clone.try_clone().unwrap(),
+            false,
+        )
+        .unwrap();
+
+        overlay_clone
+            .write_all_at(&8192u32.to_le_bytes(), 8)
+            .unwrap();
+        assert!(matches!(
+            OverlayImage::from_files(
+                base_clone.try_clone().unwrap(),
+                overlay_clone.try_clone().unwrap(),
+                false
+            ),
+            Err(Error::InvalidBlockSize(8192))
+        ));
+
+        overlay_clone.write_all_at(b"QFI\xfb", 0).unwrap();
+        assert!(matches!(
+            OverlayImage::from_files(
+                base_clone.try_clone().unwrap(),
+                overlay_clone.try_clone().unwrap(),
+                false
+            ),
+            Err(Error::InvalidMagic)
+        ));
+
+        // A read-only overlay cannot be written.
+        let (base, overlay) = create_files(8192);
+        let overlay_clone = overlay.try_clone().unwrap();
+        drop(OverlayImage::from_files(base.try_clone().unwrap(), overlay, false).unwrap());
+        let mut image = OverlayImage::from_files(base, overlay_clone, true).unwrap();
+        assert!(matches!(
+            image.write_at(&[0u8; 512], 0),
+            Err(Error::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn test_file_engine() {
+        let (base, overlay) = create_files(0x4000);
+        let image = Arc::new(Mutex::new(
+            OverlayImage::from_files(base, overlay, false).unwrap(),
+        ));
+        let mut engine = OverlayFileEngine::new(image.clone());
+        let mem = create_anon_guest_memory(&[(GuestAddress(0), 0x1_0000)], false).unwrap();
+
+        let data = pattern(0x1800, 9);
+        mem.write_slice(&data, GuestAddress(0x100)).unwrap();
+        assert_eq!(
+            engine
+                .write(0x800, &mem, GuestAddress(0x100), 0x1800)
+                .unwrap(),
+            0x1800
+        );
+        engine.flush().unwrap();
+        assert_eq!(
+            engine
+                .read(0x800, &mem, GuestAddress(0x8000), 0x1800)
+                .unwrap(),
+            0x1800
+        );
+        let mut buf = vec![0u8; 0x1800];
+        mem.read_slice(&mut buf, GuestAddress(0x8000)).unwrap();
+        assert_eq!(buf, data);
+
+        // The engines of the other queues share the image.
+        let mut buf = vec![0u8; 0x1800];
+        image.lock().unwrap().read_at(&mut buf, 0x800).unwrap();
+        assert_eq!(buf, data);
+
+        // Guest memory beyond its end cannot be accessed.
+        assert!(matches!(
+            engine.read(0, &mem, GuestAddress(0xff00), 0x200),
+            Err(Error::Transfer(_))
+        ));
+    }
+}
@@ -0,0 +1,1148 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Reads and writes disk images in the qcow2 format.
+//!
+//! The guest clusters of a qcow2 image are mapped to host clusters through two levels of tables:
+//! the L1 table, loaded when the image is opened, points to L2 tables, which point to the host
+//! clusters. Clusters which are not allocated are read from the backing file of the image, if
+//! any, or as zeros. Writing to such a cluster allocates a new host cluster at the end of the
+//! image file, filled with the previous contents of the guest cluster, then updates the tables
+//! and the reference counts of the allocated clusters.
+//!
+//! Compressed clusters, encryption and the incompatible features of version 3 are not
+//! supported. Images with internal snapshots can only be opened read-only, since their clusters
+//! may be shared.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+/// Magic value found at the start of qcow2 images.
+pub const QCOW2_MAGIC: [u8; 4] = *b"QFI\xfb";
+
+// Size of the header fields of version 2.
+const V2_HEADER_SIZE: usize = 72;
+// Size of the header fields of version 3, up to `header_length`.
+const V3_HEADER_SIZE: usize = 104;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+// Only 16 bit reference counts are supported, which is the only width of version 2.
+const REFCOUNT_ORDER: u32 = 4;
+// Same limits as QEMU.
+const MAX_L1_TABLE_SIZE: u64 = 32 << 20;
+const MAX_REFCOUNT_TABLE_SIZE: u64 = 8 << 20;
+const MAX_BACKING_FILE_NAME_SIZE: u32 = 1023;
+const MAX_BACKING_CHAIN_DEPTH: usize = 16;
+
+// Header extension types.
+const HEADER_EXTENSION_END: u32 = 0;
+const HEADER_EXTENSION_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+// Offset of the autoclear features in the header of version 3.
+const AUTOCLEAR_FEATURES_OFFSET: u64 = 88;
+
+// Host offset held in bits 9-55 of L1 and L2 entries.
+const ENTRY_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+// Host offset held in bits 9-63 of refcount table entries.
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+// The cluster is referenced once, so it can be written in place.
+const COPIED_FLAG: u64 = 1 << 63;
+const COMPRESSED_FLAG: u64 = 1 << 62;
+// Version 3 only: the cluster reads as zeros.
+const ZERO_FLAG: u64 = 1;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to access an image file.
+    Io(std::io::Error),
+    /// The image does not start with the qcow2 magic value.
+    InvalidMagic,
+    /// Only versions 2 and 3 of the format are supported.
+    UnsupportedVersion(u32),
+    /// The header of the image is invalid.
+    InvalidHeader,
+    /// The cluster size is out of the supported range.
+    InvalidClusterBits(u32),
+    /// Encrypted images are not supported.
+    Encrypted,
+    /// The image uses incompatible features which are not supported.
+    IncompatibleFeatures(u64),
+    /// Only 16 bit reference counts are supported.
+    UnsupportedRefcountOrder(u32),
+    /// A table of the image is too large or does not cover the image.
+    InvalidTable,
+    /// The backing file name is invalid, absolute or leaves the directory of the image.
+    InvalidBackingFile,
+    /// The format of the backing file is not supported.
+    UnsupportedBackingFormat(String),
+    /// The backing chain holds too many images.
+    BackingChainTooDeep,
+    /// Images with internal snapshots can only be opened read-only.
+    WritableSnapshots,
+    /// Compressed clusters are not supported.
+    CompressedCluster,
+    /// Clusters shared with internal snapshots cannot be written.
+    SharedCluster,
+    /// The image was opened read-only.
+    ReadOnly,
+    /// The access is beyond the virtual size of the image.
+    OutOfBounds,
+    /// The refcount table is full, and growing it is not supported.
+    RefcountTableFull,
+    /// Discard and write zeroes requests are not supported.
+    UnsupportedRequest,
+    /// Failed to transfer data between the image and guest memory.
+    Transfer(GuestMemoryError),
+}
+
+fn read_be_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_be_bytes(bytes)
+}
+
+fn read_be_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+/// Image a qcow2 image reads its unallocated clusters from.
+enum BackingFile {
+    Raw { file: File, size: u64 },
+    Qcow2(Box<Qcow2Image>),
+}
+
+impl BackingFile {
+    fn open(path: &Path, format: Option<&str>, depth: usize) -> Result<Self, Error> {
+        if depth >= MAX_BACKING_CHAIN_DEPTH {
+            return Err(Error::BackingChainTooDeep);
+        }
+        let file = File::open(path).map_err(Error::Io)?;
+        let is_qcow2 = match format {
+            Some("qcow2") => true,
+            Some("raw") => false,
+            Some(format) => return Err(Error::UnsupportedBackingFormat(format.to_string())),
+            // Without a backing format in the header, the format is probed.
+            None => {
+                let mut magic = [0u8; 4];
+                file.read_exact_at(&mut magic, 0).is_ok() && magic == QCOW2_MAGIC
+            }
+        };
+
+        if is_qcow2 {
+            // Backing files are never written.
+            let image = Qcow2Image::open(file, path, true, depth)?;
+            Ok(BackingFile::Qcow2(Box::new(image)))
+        } else {
+            let size = file.metadata().map_err(Error::Io)?.len();
+            Ok(BackingFile::Raw { file, size })
+        }
+    }
+
+    /// Reads `buf` at `offset`. The backing file reads as zeros past its end.
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        let size = match self {
+            BackingFile::Raw { size, .. } => *size,
+            BackingFile::Qcow2(image) => image.size(),
+        };
+        let len = size.saturating_sub(offset).min(buf.len() as u64) as usize;
+        let (data, zeros) = buf.split_at_mut(len);
+        zeros.fill(0);
+        if data.is_empty() {
+            return Ok(());
+        }
+        match self {
+            BackingFile::Raw { file, .. } => file.read_exact_at(data, offset).map_err(Error::Io),
+            BackingFile::Qcow2(image) => image.read_at(data, offset),
+        }
+    }
+}
+
+/// A qcow2 disk image and its backing chain.
+pub struct Qcow2Image {
+    file: File,
+    read_only: bool,
+    version: u32,
+    cluster_bits: u32,
+    // Virtual size of the image.
+    size: u64,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    // Only loaded for writable images.
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    // The last L2 table accessed, and its offset, or 0 if none.
+    l2_cache_offset: u64,
+    l2_cache: Vec<u64>,
+    backing_file: Option<BackingFile>,
+    // Clusters are allocated at the end of the image file.
+    next_cluster_offset: u64,
+}
+
+impl Qcow2Image {
+    /// Opens the qcow2 image in `file`, found at `path`, and its backing chain.
+    ///
+    /// Backing file names are resolved from the directory of `path`, and cannot leave it. The
+    /// backing files are always opened read-only.
+    pub fn from_file(file: File, path: &Path, read_only: bool) -> Result<Self, Error> {
+        Self::open(file, path, read_only, 0)
+    }
+
+    fn open(file: File, path: &Path, read_only: bool, depth: usize) -> Result<Self, Error> {
+        let mut header = [0u8; V3_HEADER_SIZE];
+        file.read_exact_at(&mut header[..V2_HEADER_SIZE], 0)
+            .map_err(Error::Io)?;
+        if header[..4] != QCOW2_MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+
+        let version = read_be_u32(&header, 4);
+        let mut header_length = V2_HEADER_SIZE as u64;
+        match version {
+            2 => (),
+            3 => {
+                file.read_exact_at(&mut header[V2_HEADER_SIZE..], V2_HEADER_SIZE as u64)
+                    .map_err(Error::Io)?;
+                let incompatible_features = read_be_u64(&header, 72);
+                if incompatible_features != 0 {
+                    return Err(Error::IncompatibleFeatures(incompatible_features));
+                }
+                let refcount_order = read_be_u32(&header, 96);
+                if refcount_order != REFCOUNT_ORDER {
+                    return Err(Error::UnsupportedRefcountOrder(refcount_order));
+                }
+                header_length = u64::from(read_be_u32(&header, 100));
+                if header_length < V3_HEADER_SIZE as u64 {
+                    return Err(Error::InvalidHeader);
+                }
+            }
+            _ => return Err(Error::UnsupportedVersion(version)),
+        }
+
+        let cluster_bits = read_be_u32(&header, 20);
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(Error::InvalidClusterBits(cluster_bits));
+        }
+        let cluster_size = 1u64 << cluster_bits;
+        if header_length > cluster_size {
+            return Err(Error::InvalidHeader);
+        }
+        if read_be_u32(&header, 32) != 0 {
+            return Err(Error::Encrypted);
+        }
+        if !read_only && read_be_u32(&header, 60) != 0 {
+            return Err(Error::WritableSnapshots);
+        }
+
+        let size = read_be_u64(&header, 24);
+        let l1_size = u64::from(read_be_u32(&header, 36));
+        let l1_table_offset = read_be_u64(&header, 40);
+        // Each L1 entry covers the guest clusters of a whole L2 table.
+        let l1_entry_coverage = cluster_size * (cluster_size / 8);
+        let min_l1_size = size
+            .checked_add(l1_entry_coverage - 1)
+            .ok_or(Error::InvalidTable)?
+            / l1_entry_coverage;
+        if l1_size * 8 > MAX_L1_TABLE_SIZE
+            || l1_size < min_l1_size
+            || l1_table_offset % cluster_size != 0
+        {
+            return Err(Error::InvalidTable);
+        }
+        let l1_table = read_table(&file, l1_table_offset, l1_size as usize)?;
+
+        let refcount_table_offset = read_be_u64(&header, 48);
+        let refcount_table_size = u64::from(read_be_u32(&header, 56)) * cluster_size;
+        if refcount_table_size > MAX_REFCOUNT_TABLE_SIZE
+            || refcount_table_offset % cluster_size != 0
+        {
+            return Err(Error::InvalidTable);
+        }
+        let refcount_table = if read_only {
+            Vec::new()
+        } else {
+            read_table(
+                &file,
+                refcount_table_offset,
+                (refcount_table_size / 8) as usize,
+            )?
+        };
+
+        // Writers must clear the autoclear features they do not support, which are all of them.
+        if !read_only && version >= 3 && read_be_u64(&header, 88) != 0 {
+            file.write_all_at(&0u64.to_be_bytes(), AUTOCLEAR_FEATURES_OFFSET)
+                .map_err(Error::Io)?;
+        }
+
+        let backing_file_format = read_backing_file_format(&file, header_length, cluster_size)?;
+        let backing_file = match read_backing_file_name(&header, &file)? {
+            Some(name) => {
+                // Names are relative to the directory of the image.
+                let backing_path = path
+                    .parent()
+                    .unwrap_or_else(|| Path::new(""))
+                    .join(PathBuf::from(name));
+                Some(BackingFile::open(
+                    &backing_path,
+                    backing_file_format.as_deref(),
+                    depth + 1,
+                )?)
+            }
+            None => None,
+        };
+
+        let file_size = file.metadata().map_err(Error::Io)?.len();
+        let next_cluster_offset = (file_size + cluster_size - 1) & !(cluster_size - 1);
+
+        Ok(Qcow2Image {
+            file,
+            read_only,
+            version,
+            cluster_bits,
+            size,
+            l1_table_offset,
+            l1_table,
+            refcount_table_offset,
+            refcount_table,
+            l2_cache_offset: 0,
+            l2_cache: Vec::new(),
+            backing_file,
+            next_cluster_offset,
+        })
+    }
+
+    /// Returns the virtual size of the image.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    fn check_bounds(&self, offset: u64, len: usize) -> Result<(), Error> {
+        match offset.checked_add(len as u64) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+
+    // Length of the part of an access of `len` bytes at `offset` within its first cluster.
+    fn chunk_len(&self, offset: u64, len: usize) -> usize {
+        let cluster_end = (offset | (self.cluster_size() - 1)) + 1;
+        (cluster_end - offset).min(len as u64) as usize
+    }
+
+    fn l1_index(&self, offset: u64) -> usize {
+        (offset >> self.cluster_bits) as usize / self.l2_entries() as usize
+    }
+
+    // Returns the L1 entry of the guest cluster at `offset`. The L1 table covers the virtual
+    // size, so this only fails for offsets out of bounds.
+    fn l1_entry(&self, offset: u64) -> Result<u64, Error> {
+        self.l1_table
+            .get(self.l1_index(offset))
+            .copied()
+            .ok_or(Error::InvalidTable)
+    }
+
+    fn l2_index(&self, offset: u64) -> usize {
+        (offset >> self.cluster_bits) as usize % self.l2_entries() as usize
+    }
+
+    fn load_l2_table(&mut self, l2_offset: u64) -> Result<(), Error> {
+        if self.l2_cache_offset != l2_offset {
+            self.l2_cache = read_table(&self.file, l2_offset, self.l2_entries() as usize)?;
+            self.l2_cache_offset = l2_offset;
+        }
+        Ok(())
+    }
+
+    // Returns the L2 entry of the guest cluster at `offset`, or 0 if it has no L2 table.
+    fn l2_entry(&mut self, offset: u64) -> Result<u64, Error> {
+        let l2_offset = self.l1_entry(offset)? & ENTRY_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        self.load_l2_table(l2_offset)?;
+        Ok(self.l2_cache[self.l2_index(offset)])
+    }
+
+    fn is_zero_entry(&self, entry: u64) -> bool {
+        self.version >= 3 && entry & ZERO_FLAG != 0
+    }
+
+    /// Reads `buf` from the guest offset `offset`.
+    pub fn read_at(&mut self, mut buf: &mut [u8], mut offset: u64) -> Result<(), Error> {
+        self.check_bounds(offset, buf.len())?;
+        while !buf.is_empty() {
+            let len = self.chunk_len(offset, buf.len());
+            let (chunk, rest) = buf.split_at_mut(len);
+
+            let entry = self.l2_entry(offset)?;
+            let host_offset = entry & ENTRY_OFFSET_MASK;
+            if entry & COMPRESSED_FLAG != 0 {
+                return Err(Error::CompressedCluster);
+            } else if self.is_zero_entry(entry) {
+                chunk.fill(0);
+            } else if host_offset != 0 {
+                let offset_in_cluster = offset & (self.cluster_size() - 1);
+                self.file
+                    .read_exact_at(chunk, host_offset + offset_in_cluster)
+                    .map_err(Error::Io)?;
+            } else {
+                self.read_unallocated(chunk, offset)?;
+            }
+
+            buf = rest;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    fn read_unallocated(&mut self, buf: &mut [u8], offset: u64) -> Result<(), Error> {
+        match self.backing_file.as_mut() {
+            Some(backing_file) => backing_file.read_at(buf, offset),
+            None => {
+                buf.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes `buf` at the guest offset `offset`, allocating clusters as needed.
+    pub fn write_at(&mut self, mut buf: &[u8], mut offset: u64) -> Result<(), Error> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+        self.check_bounds(offset, buf.len())?;
+        while !buf.is_empty() {
+            let len = self.chunk_len(offset, buf.len());
+            let (chunk, rest) = buf.split_at(len);
+            self.write_cluster(chunk, offset)?;
+            buf = rest;
+            offset += len as u64;
+        }
+        Ok(())
+    }
+
+    // Writes `buf`, which does not cross a cluster boundary, at the guest offset `offset`.
+    fn write_cluster(&mut self, buf: &[u8], offset: u64) -> Result<(), Error> {
+        let offset_in_cluster = offset & (self.cluster_size() - 1);
+        let entry = self.l2_entry(offset)?;
+        let host_offset = entry & ENTRY_OFFSET_MASK;
+        let is_zero = self.is_zero_entry(entry);
+        if entry & COMPRESSED_FLAG != 0 {
+            return Err(Error::CompressedCluster);
+        }
+        if host_offset != 0 && entry & COPIED_FLAG == 0 {
+            return Err(Error::SharedCluster);
+        }
+        if host_offset != 0 && !is_zero {
+            return self
+                .file
+                .write_all_at(buf, host_offset + offset_in_cluster)
+                .map_err(Error::Io);
+        }
+
+        // The rest of the cluster keeps its previous contents.
+        let cluster_offset = offset - offset_in_cluster;
+        let mut cluster = vec![0u8; self.cluster_size() as usize];
+        if buf.len() != cluster.len() && !is_zero {
+            self.read_unallocated(&mut cluster, cluster_offset)?;
+        }
+        cluster[offset_in_cluster as usize..offset_in_cluster as usize + buf.len()]
+            .copy_from_slice(buf);
+
+        // Zero clusters may already have a host cluster.
+        let host_offset = match host_offset {
+            0 => self.allocate_cluster()?,
+            host_offset => host_offset,
+        };
+        self.file
+            .write_all_at(&cluster, host_offset)
+            .map_err(Error::Io)?;
+        self.set_l2_entry(offset, host_offset | COPIED_FLAG)
+    }
+
+    fn set_l2_entry(&mut self, offset: u64, entry: u64) -> Result<(), Error> {
+        let l1_index = self.l1_index(offset);
+        let l1_entry = self.l1_entry(offset)?;
+        let mut l2_offset = l1_entry & ENTRY_OFFSET_MASK;
+        if l2_offset == 0 {
+            l2_offset = self.allocate_cluster()?;
+            self.file
+                .write_all_at(&vec![0u8; self.cluster_size() as usize], l2_offset)
+                .map_err(Error::Io)?;
+            self.l2_cache = vec![0; self.l2_entries() as usize];
+            self.l2_cache_offset = l2_offset;
+
+            let l1_entry = l2_offset | COPIED_FLAG;
+            self.file
+                .write_all_at(
+                    &l1_entry.to_be_bytes(),
+                    self.l1_table_offset + l1_index as u64 * 8,
+                )
+                .map_err(Error::Io)?;
+            self.l1_table[l1_index] = l1_entry;
+        } else if l1_entry & COPIED_FLAG == 0 {
+            return Err(Error::SharedCluster);
+        }
+
+        self.load_l2_table(l2_offset)?;
+        let l2_index = self.l2_index(offset);
+        self.file
+            .write_all_at(&entry.to_be_bytes(), l2_offset + l2_index as u64 * 8)
+            .map_err(Error::Io)?;
+        self.l2_cache[l2_index] = entry;
+        Ok(())
+    }
+
+    // Allocates a cluster at the end of the image file, with a reference count of 1.
+    fn allocate_cluster(&mut self) -> Result<u64, Error> {
+        let cluster_offset = self.next_cluster_offset;
+        self.next_cluster_offset += self.cluster_size();
+        self.set_refcount(cluster_offset, 1)?;
+        Ok(cluster_offset)
+    }
+
+    fn set_refcount(&mut self, cluster_offset: u64, refcount: u16) -> Result<(), Error> {
+        // Each refcount block holds a 16 bit reference count per cluster.
+        let refcounts_per_block = self.cluster_size() / 2;
+        let cluster_index = cluster_offset >> self.cluster_bits;
+        let table_index = (cluster_index / refcounts_per_block) as usize;
+        if table_index >= self.refcount_table.len() {
+            return Err(Error::RefcountTableFull);
+        }
+
+        let mut block_offset = self.refcount_table[table_index] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset == 0 {
+            block_offset = self.next_cluster_offset;
+            self.next_cluster_offset += self.cluster_size();
+            self.file
+                .write_all_at(&vec![0u8; self.cluster_size() as usize], block_offset)
+                .map_err(Error::Io)?;
+            self.file
+                .write_all_at(
+                    &block_offset.to_be_bytes(),
+                    self.refcount_table_offset + table_index as u64 * 8,
+                )
+                .map_err(Error::Io)?;
+            self.refcount_table[table_index] = block_offset;
+            // The new refcount block is either counted by itself, or by another block which may
+            // have to be allocated in turn.
+            self.set_refcount(block_offset, 1)?;
+        }
+
+        let block_index = cluster_index % refcounts_per_block;
+        self.file
+            .write_all_at(&refcount.to_be_bytes(), block_offset + block_index * 2)
+            .map_err(Error::Io)
+    }
+
+    /// Flushes the image file to the host disk.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.file.sync_all().map_err(Error::Io)
+    }
+}
+
+fn read_table(file: &File, offset: u64, entries: usize) -> Result<Vec<u64>, Error> {
+    let mut buf = vec![0u8; entries * 8];
+    file.read_exact_at(&mut buf, offset).map_err(Error::Io)?;
+    Ok(buf
+        .chunks_exact(8)
+        .map(|entry| read_be_u64(entry, 0))
+        .collect())
+}
+
+fn read_backing_file_name(header: &[u8], file: &File) -> Result<Option<String>, Error> {
+    let name_offset = read_be_u64(header, 8);
+    let name_size = read_be_u32(header, 16);
+    if name_offset == 0 {
+        return Ok(None);
+    }
+    if name_size == 0 || name_size > MAX_BACKING_FILE_NAME_SIZE {
+        return Err(Error::InvalidBackingFile);
+    }
+    let mut name = vec![0u8; name_size as usize];
+    file.read_exact_at(&mut name, name_offset)
+        .map_err(Error::Io)?;
+    let name = String::from_utf8(name).map_err(|_| Error::InvalidBackingFile)?;
+    // Images can come from the guest or from untrusted sources, so their backing files are
+    // confined to the directory of the image.
+    if !Path::new(&name)
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(Error::InvalidBackingFile);
+    }
+    Ok(Some(name))
+}
+
+// Looks for the backing file format in the header extensions, which follow the header in the
+// first cluster of the image.
+fn read_backing_file_format(
+    file: &File,
+    header_length: u64,
+    cluster_size: u64,
+) -> Result<Option<String>, Error> {
+    let mut offset = header_length;
+    while offset + 8 <= cluster_size {
+        let mut extension = [0u8; 8];
+        file.read_exact_at(&mut extension, offset)
+            .map_err(Error::Io)?;
+        let extension_type = read_be_u32(&extension, 0);
+        let extension_len = u64::from(read_be_u32(&extension, 4));
+        if extension_type == HEADER_EXTENSION_END {
+            break;
+        }
+        if offset + 8 + extension_len > cluster_size {
+            return Err(Error::InvalidHeader);
+        }
+        if extension_type == HEADER_EXTENSION_BACKING_FORMAT {
+            let mut format = vec![0u8; extension_len as usize];
+            file.read_exact_at(&mut format, offset + 8)
+                .map_err(Error::Io)?;
+            return String::from_utf8(format)
+                .map(Some)
+                .map_err(|_| Error::InvalidHeader);
+        }
+        // Extension data is padded to 8 bytes.
+        offset += 8 + ((extension_len + 7) & !7);
+    }
+    Ok(None)
+}
+
+/// Engine executing the requests of a virtio queue on a qcow2 image, with blocking system
+/// calls. The image is shared by the engines of all the queues of a device.
+pub struct Qcow2FileEngine {
+    image: Arc<Mutex<Qcow2Image>>,
+}
+
+impl Qcow2FileEngine {
+    pub fn new(image: Arc<Mutex<Qcow2Image>>) -> Qcow2FileEngine {
+        Qcow2FileEngine { image }
+    }
+
+    pub fn read(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, Error> {
+        let mut buf = vec![0u8; count as usize];
+        self.image
+            .lock()
+            .expect("Poisoned lock")
+            .read_at(&mut buf, offset)?;
+        mem.write_slice(&buf, addr).map_err(Error::Transfer)?;
+        Ok(count)
+    }
+
+    pub fn write(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+    ) -> Result<u32, Error> {
+        let mut buf = vec![0u8; count as usize];
+        mem.read_slice(&mut buf, addr).map_err(Error::Transfer)?;
+        self.image
+            .lock()
+            .expect("Poisoned lock")
+            .write_at(&buf, offset)?;
+        Ok(count)
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.image.lock().expect("Poisoned lock").flush()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::fs::OpenOptions;
+
+    use utils::tempdir::TempDir;
+    use utils::tempfile::TempFile;
+
+    use super::*;
+
+    /// Writes an empty version 3 image of `size` bytes to `file`, with an optional backing file
+    /// and backing file format. The header, the refcount table, its first refcount block and the
+    /// L1 table are stored in that order in the first clusters of the image.
+    pub(crate) fn create_image(
+        file: &File,
+        size: u64,
+        cluster_bits: u32,
+        backing_file: Option<&str>,
+        backing_file_format: Option<&str>,
+    ) {
+        let cluster_size = 1u64 << cluster_bits;
+        let l1_size =
+            (size + cluster_size * (cluster_size / 8) - 1) / (cluster_size * (cluster_size / 8));
+        let l1_clusters = ((l1_size * 8 + cluster_size - 1) / cluster_size).max(1);
+        let num_clusters = 3 + l1_clusters;
+
+        let mut image = vec![0u8; (num_clusters * cluster_size) as usize];
+        let mut write = |offset: usize, bytes: &[u8]| {
+            image[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        write(0, &QCOW2_MAGIC);
+        write(4, &3u32.to_be_bytes());
+        write(20, &cluster_bits.to_be_bytes());
+        write(24, &size.to_be_bytes());
+        write(36, &(l1_size as u32).to_be_bytes());
+        write(40, &(3 * cluster_size).to_be_bytes());
+        write(48, &cluster_size.to_be_bytes());
+        write(56, &1u32.to_be_bytes());
+        write(96, &REFCOUNT_ORDER.to_be_bytes());
+        write(100, &(V3_HEADER_SIZE as u32).to_be_bytes());
+
+        let mut offset = V3_HEADER_SIZE;
+        if let Some(format) = backing_file_format {
+            write(offset, &HEADER_EXTENSION_BACKING_FORMAT.to_be_bytes());
+            write(offset + 4, &(format.len() as u32).to_be_bytes());
+            write(offset + 8, format.as_bytes());
+            offset += 8 + ((format.len() + 7) & !7);
+        }
+        // End of the header extensions.
+        offset += 8;
+        if let Some(name) = backing_file {
+            write(8, &(offset as u64).to_be_bytes());
+            write(16, &(name.len() as u32).to_be_bytes());
+            write(offset, name.as_bytes());
+        }
+
+        // Refcount table and block.
+        write(cluster_size as usize, &(2 * cluster_size).to_be_bytes());
+        for cluster in 0..num_clusters as usize {
+            write(2 * cluster_size as usize + cluster * 2, &1u16.to_be_bytes());
+        }
+
+        file.write_all_at(&image, 0).unwrap();
+        file.set_len(image.len() as u64).unwrap();
+    }
+
+    fn open_image(path: &Path, read_only: bool) -> Result<Qcow2Image, Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(path)
+            .unwrap();
+        Qcow2Image::from_file(file, path, read_only)
+    }
+
+    fn create_image_file(size: u64, cluster_bits: u32) -> TempFile {
+        let file = TempFile::new().unwrap();
+        create_image(file.as_file(), size, cluster_bits, None, None);
+        file
+    }
+
+    fn open(file: &TempFile, read_only: bool) -> Result<Qcow2Image, Error> {
+        open_image(file.as_path(), read_only)
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(7).wrapping_add(seed))
+            .collect()
+    }
+
+    // Checks that the reference count of every cluster of the image matches the number of
+    // references to it, which must be 0 or 1 without internal snapshots.
+    fn check_refcounts(image: &Qcow2Image) {
+        let cluster_size = image.cluster_size();
+        let mut references: HashMap<u64, u16> = HashMap::new();
+        let mut reference = |offset: u64| *references.entry(offset).or_default() += 1;
+
+        reference(0);
+        let refcount_table_size =
+            (image.refcount_table.len() as u64 * 8 + cluster_size - 1) / cluster_size;
+        for cluster in 0..refcount_table_size {
+            reference(image.refcount_table_offset + cluster * cluster_size);
+        }
+        for block in image.refcount_table.iter().filter(|&&block| block != 0) {
+            reference(*block);
+        }
+        let l1_clusters = (image.l1_table.len() as u64 * 8 + cluster_size - 1) / cluster_size;
+        for cluster in 0..l1_clusters.max(1) {
+            reference(image.l1_table_offset + cluster * cluster_size);
+        }
+        for l1_entry in image.l1_table.iter().filter(|&&entry| entry != 0) {
+            let l2_offset = l1_entry & ENTRY_OFFSET_MASK;
+            assert_ne!(l1_entry & COPIED_FLAG, 0);
+            reference(l2_offset);
+            let l2_table = read_table(&image.file, l2_offset, image.l2_entries() as usize).unwrap();
+            for l2_entry in l2_table.iter().filter(|&&entry| entry != 0) {
+                assert_ne!(l2_entry & COPIED_FLAG, 0);
+                reference(l2_entry & ENTRY_OFFSET_MASK);
+            }
+        }
+
+        let file_size = image.file.metadata().unwrap().len();
+        assert!(file_size <= image.next_cluster_offset);
+        for cluster_offset in (0..image.next_cluster_offset).step_by(cluster_size as usize) {
+            let cluster_index = cluster_offset / cluster_size;
+            let refcounts_per_block = cluster_size / 2;
+            let block = image.refcount_table[(cluster_index / refcounts_per_block) as usize];
+            let mut refcount = [0u8; 2];
+            if block != 0 {
+                image
+                    .file
+                    .read_exact_at(
+                        &mut refcount,
+                        block + (cluster_index % refcounts_per_block) * 2,
+                    )
+                    .unwrap();
+            }
+            assert_eq!(
+                u16::from_be_bytes(refcount),
+                references.get(&cluster_offset).copied().unwrap_or(0),
+                "cluster at {:#x}",
+                cluster_offset
+            );
+        }
+    }
+
+    #[test]
+    fn test_open_invalid() {
+        let file = create_image_file(0x10_0000, 12);
+        let write_header = |offset: u64, bytes: &[u8]| {
+            file.as_file().write_all_at(bytes, offset).unwrap();
+        };
+        assert_eq!(open(&file, false).unwrap().size(), 0x10_0000);
+
+        write_header(0, b"QFI\x00");
+        assert!(matches!(open(&file, false), Err(Error::InvalidMagic)));
+        write_header(0, &QCOW2_MAGIC);
+
+        write_header(4, &1u32.to_be_bytes());
+        assert!(matches!(
+            open(&file, false),
+            Err(Error::UnsupportedVersion(1))
+        ));
+        write_header(4, &3u32.to_be_bytes());
+
+        write_header(20, &22u32.to_be_bytes());
+        assert!(matches!(
+            open(&file, false),
+            Err(Error::InvalidClusterBits(22))
+        ));
+        write_header(20, &12u32.to_be_bytes());
+
+        write_header(32, &1u32.to_be_bytes());
+        assert!(matches!(open(&file, false), Err(Error::Encrypted)));
+        write_header(32, &0u32.to_be_bytes());
+
+        // The dirty bit.
+        write_header(72, &1u64.to_be_bytes());
+        assert!(matches!(
+            open(&file, false),
+            Err(Error::IncompatibleFeatures(1))
+        ));
+        write_header(72, &0u64.to_be_bytes());
+
+        write_header(96, &5u32.to_be_bytes());
+        assert!(matches!(
+            open(&file, false),
+            Err(Error::UnsupportedRefcountOrder(5))
+        ));
+        write_header(96, &REFCOUNT_ORDER.to_be_bytes());
+
+        // The L1 table must cover the virtual size.
+        write_header(24, &0x20_0001u64.to_be_bytes());
+        assert!(matches!(open(&file, false), Err(Error::InvalidTable)));
+        write_header(24, &u64::MAX.to_be_bytes());
+        assert!(matches!(open(&file, false), Err(Error::InvalidTable)));
+        write_header(24, &0x10_0000u64.to_be_bytes());
+
+        // Images with internal snapshots are only opened read-only.
+        write_header(60, &1u32.to_be_bytes());
+        assert!(matches!(open(&file, false), Err(Error::WritableSnapshots)));
+        assert!(open(&file, true).is_ok());
+        write_header(60, &0u32.to_be_bytes());
+
+        // Unsupported autoclear features are cleared when the image is opened for writing.
+        write_header(AUTOCLEAR_FEATURES_OFFSET, &1u64.to_be_bytes());
+        open(&file, false).unwrap();
+        let mut features = [0u8; 8];
+        file.as_file()
+            .read_exact_at(&mut features, AUTOCLEAR_FEATURES_OFFSET)
+            .unwrap();
+        assert_eq!(u64::from_be_bytes(features), 0);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let file = create_image_file(0x40_0000, 12);
+        let mut image = open(&file, false).unwrap();
+
+        // The image reads as zeros until written.
+        let mut buf = vec![0xffu8; 0x3000];
+        image.read_at(&mut buf, 0x1800).unwrap();
+        assert!(buf.iter().all(|&byte| byte == 0));
+
+        // Unaligned writes across cluster and L2 table boundaries.
+        let data = pattern(0x2345, 1);
+        image.write_at(&data, 0x1f_f123).unwrap();
+        image.read_at(&mut buf[..data.len()], 0x1f_f123).unwrap();
+        assert_eq!(&buf[..data.len()], &data[..]);
+        let mut before = vec![0xffu8; 0x123];
+        image.read_at(&mut before, 0x1f_f000).unwrap();
+        assert!(before.iter().all(|&byte| byte == 0));
+
+        // Overwrite part of an allocated cluster in place.
+        let file_size = image.file.metadata().unwrap().len();
+        image.write_at(&[0xaa; 16], 0x20_0000).unwrap();
+        assert_eq!(image.file.metadata().unwrap().len(), file_size);
+        check_refcounts(&image);
+
+        // Accesses beyond the virtual size fail.
+        assert!(matches!(
+            image.read_at(&mut buf, 0x40_0000 - 0x10),
+            Err(Error::OutOfBounds)
+        ));
+        assert!(matches!(
+            image.write_at(&buf, u64::MAX),
+            Err(Error::OutOfBounds)
+        ));
+
+        // The data is found when the image is opened again.
+        drop(image);
+        let mut image = open(&file, true).unwrap();
+        let mut expected = data;
+        expected[0xedd..0xedd + 16].fill(0xaa);
+        image
+            .read_at(&mut buf[..expected.len()], 0x1f_f123)
+            .unwrap();
+        assert_eq!(&buf[..expected.len()], &expected[..]);
+
+        assert!(matches!(image.write_at(&[0], 0), Err(Error::ReadOnly)));
+    }
+
+    #[test]
+    fn test_refcount_blocks() {
+        // With 512 byte clusters, refcount blocks count 256 clusters, so filling the image
+        // allocates several of them.
+        let size = 0x10_0000;
+        let file = create_image_file(size, 9);
+        let mut image = open(&file, false).unwrap();
+        for offset in (0..size).step_by(0x1000) {
+            image
+                .write_at(&pattern(0x1000, (offset >> 12) as u8), offset)
+                .unwrap();
+        }
+        assert!(
+            image
+                .refcount_table
+                .iter()
+                .filter(|&&block| block != 0)
+                .count()
+                > 8
+        );
+        check_refcounts(&image);
+
+        let mut buf = vec![0u8; 0x1000];
+        for offset in (0..size).step_by(0x1000) {
+            image.read_at(&mut buf, offset).unwrap();
+            assert_eq!(buf, pattern(0x1000, (offset >> 12) as u8));
+        }
+
+        // The refcount table cannot grow.
+        image.refcount_table.truncate(1);
+        image.next_cluster_offset = 256 << 9;
+        assert!(matches!(
+            image.allocate_cluster(),
+            Err(Error::RefcountTableFull)
+        ));
+    }
+
+    #[test]
+    fn test_backing_chain() {
+        let temp_dir = TempDir::new().unwrap();
+        let dir = temp_dir.as_path();
+        let base_data = pattern(0x1_0800, 3);
+        std::fs::write(dir.join("base.raw"), &base_data).unwrap();
+
+        // A qcow2 image over the raw base, with a probed format.
+        let middle = File::create(dir.join("middle.qcow2")).unwrap();
+        create_image(&middle, 0x2_0000, 12, Some("base.raw"), None);
+        let mut middle = open_image(&dir.join("middle.qcow2"), false).unwrap();
+        middle.write_at(&[0x55; 0x100], 0x2f00).unwrap();
+        drop(middle);
+
+        // A writable qcow2 image over the middle one, with a backing format extension.
+        let top = File::create(dir.join("top.qcow2")).unwrap();
+        create_image(&top, 0x2_0000, 12, Some("middle.qcow2"), Some("qcow2"));
+        let mut top = open_image(&dir.join("top.qcow2"), false).unwrap();
+
+        let mut expected = base_data.clone();
+        expected.resize(0x2_0000, 0);
+        expected[0x2f00..0x3000].fill(0x55);
+        let mut buf = vec![0u8; 0x2_0000];
+        top.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, expected);
+
+        // Partial writes copy the rest of the cluster from the backing chain.
+        top.write_at(&[0x66; 0x10], 0x2010).unwrap();
+        top.write_at(&[0x77; 0x10], 0x1_07f8).unwrap();
+        expected[0x2010..0x2020].fill(0x66);
+        expected[0x1_07f8..0x1_0808].fill(0x77);
+        top.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, expected);
+        check_refcounts(&top);
+
+        // The backing files are left untouched.
+        assert_eq!(std::fs::read(dir.join("base.raw")).unwrap(), base_data);
+        let mut middle = open_image(&dir.join("middle.qcow2"), true).unwrap();
+        middle.read_at(&mut buf[..0x10], 0x2010).unwrap();
+        assert_eq!(&buf[..0x10], &base_data[0x2010..0x2020]);
+
+        // Unknown backing formats and endless chains are rejected.
+        let other = File::create(dir.join("other.qcow2")).unwrap();
+        create_image(&other, 0x2_0000, 12, Some("base.raw"), Some("vmdk"));
+        assert!(matches!(
+            open_image(&dir.join("other.qcow2"), true),
+            Err(Error::UnsupportedBackingFormat(format)) if format == "vmdk"
+        ));
+        let looping = dir.join("loop.qcow2");
+        create_image(
+            &File::create(&looping).unwrap(),
+            0x2_0000,
+            12,
+            Some("loop.qcow2"),
+            None,
+        );
+        assert!(matches!(
+            open_image(&looping, true),
+            Err(Error::BackingChainTooDeep)
+        ));
+
+        // Backing files cannot leave the directory of the image.
+        let nested = dir.join("nested");
+        std::fs::create_dir(&nested).unwrap();
+        let escaping = nested.join("escaping.qcow2");
+        let absolute = dir.join("base.raw");
+        for name in ["../base.raw", "./../base.raw", absolute.to_str().unwrap()] {
+            create_image(
+                &File::create(&escaping).unwrap(),
+                0x2_0000,
+                12,
+                Some(name),
+                None,
+            );
+            assert!(matches!(
+                open_image(&escaping, true),
+                Err(Error::InvalidBackingFile)
+            ));
+        }
+        std::fs::copy(dir.join("base.raw"), nested.join("base.raw")).unwrap();
+        create_image(
+            &File::create(&escaping).unwrap(),
+            0x2_0000,
+            12,
+            Some("./base.raw"),
+            None,
+        );
+        open_image(&escaping, true).unwrap();
+    }
+
+    #[test]
+    fn test_special_clusters() {
+        let file = create_image_file(0x2_0000, 12);
+        let mut image = open(&file, false).unwrap();
+        image.write_at(&[0x11; 0x3000], 0).unwrap();
+        let l2_offset = image.l1_table[0] & ENTRY_OFFSET_MASK;
+        let set_l2_entry = |index: u64, entry: u64| {
+            file.as_file()
+                .write_all_at(&entry.to_be_bytes(), l2_offset + index * 8)
+                .unwrap();
+        };
+
+        // The zero flag, with and without a host cluster.
+        let mut entry = [0u8; 8];
+        file.as_file().read_exact_at(&mut entry, l2_offset).unwrap();
+        set_l2_entry(0, u64::from_be_bytes(entry) | ZERO_FLAG);
+        set_l2_entry(1, ZERO_FLAG);
+        // A compressed cluster.
+        set_l2_entry(2, COMPRESSED_FLAG | 0x1000);
+
+        let mut image = open(&file, false).unwrap();
+        let mut buf = vec![0xffu8; 0x2000];
+        image.read_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&byte| byte == 0));
+        assert!(matches!(
+            image.read_at(&mut buf[..1], 0x2000),
+            Err(Error::CompressedCluster)
+        ));
+        assert!(matches!(
+            image.write_at(&buf[..1], 0x2000),
+            Err(Error::CompressedCluster)
+        ));
+
+        // Writing to zero clusters zeroes the rest of the cluster, and reuses the host cluster.
+        let file_size = image.file.metadata().unwrap().len();
+        image.write_at(&[0x22; 0x10], 0x10).unwrap();
+        assert_eq!(image.file.metadata().unwrap().len(), file_size);
+        image.write_at(&[0x33; 0x10], 0x1010).unwrap();
+        image.read_at(&mut buf, 0).unwrap();
+        let mut expected = vec![0u8; 0x2000];
+        expected[0x10..0x20].fill(0x22);
+        expected[0x1010..0x1020].fill(0x33);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_file_engine() {
+        let file = create_image_file(0x1_0000, 12);
+        let image = Arc::new(Mutex::new(open(&file, false).unwrap()));
+        let mut engine = Qcow2FileEngine::new(image.clone());
+        let mem =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x1_0000)], false)
+                .unwrap();
+
+        let data = pattern(0x1800, 9);
+        mem.write_slice(&data, GuestAddress(0x1000)).unwrap();
+        assert_eq!(
+            engine
+                .write(0x800, &mem, GuestAddress(0x1000), 0x1800)
+                .unwrap(),
+            0x1800
+        );
+        engine.flush().unwrap();
+
+        // Engines share the image.
+        let mut other_engine = Qcow2FileEngine::new(image);
+        assert_eq!(
+            other_engine
+                .read(0x800, &mem, GuestAddress(0x4000), 0x1800)
+                .unwrap(),
+            0x1800
+        );
+        let mut buf = vec![0u8; 0x1800];
+        mem.read_slice(&mut buf, GuestAddress(0x4000)).unwrap();
+        assert_eq!(buf, data);
+
+        assert!(matches!(
+            engine.read(0xf000, &mem, GuestAddress(0), 0x2000),
+            Err(Error::OutOfBounds)
+        ));
+        assert!(matches!(
+            engine.read(0, &mem, GuestAddress(u64::MAX - 0x10), 0x1000),
+            Err(Error::Transfer(_))
+        ));
+    }
+}
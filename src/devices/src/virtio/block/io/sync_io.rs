@@ -3,35 +3,50 @@
 
 use std::fs::File;
 use std::io::{Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
 use std::result::Result;
 
-use vm_memory::{Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+use vm_memory::{mark_dirty_mem, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
+
+use super::direct_io::{aligned_host_address, BounceBuffer};
 
 #[derive(Debug)]
 pub enum Error {
+    Fallocate(std::io::Error),
     Flush(std::io::Error),
+    Read(std::io::Error),
     Seek(std::io::Error),
     SyncAll(std::io::Error),
     Transfer(GuestMemoryError),
+    Write(std::io::Error),
 }
 
 pub struct SyncFileEngine {
     file: File,
+    // The alignment of the transfers, if the file was opened with `O_DIRECT`.
+    direct_io_alignment: Option<u32>,
 }
 
 // SAFETY: `File` is send and ultimately a POD.
 unsafe impl Send for SyncFileEngine {}
 
 impl SyncFileEngine {
-    pub fn from_file(file: File) -> SyncFileEngine {
-        SyncFileEngine { file }
+    pub fn from_file(file: File, direct_io_alignment: Option<u32>) -> SyncFileEngine {
+        SyncFileEngine {
+            file,
+            direct_io_alignment,
+        }
     }
 
-    #[cfg(test)]
     pub fn file(&self) -> &File {
         &self.file
     }
 
+    pub fn direct_io_alignment(&self) -> Option<u32> {
+        self.direct_io_alignment
+    }
+
     pub fn read(
         &mut self,
         offset: u64,
@@ -39,6 +54,9 @@ impl SyncFileEngine {
         addr: GuestAddress,
         count: u32,
     ) -> Result<u32, Error> {
+        if let Some(alignment) = self.direct_io_alignment {
+            return self.read_direct(offset, mem, addr, count, alignment);
+        }
         self.file
             .seek(SeekFrom::Start(offset))
             .map_err(Error::Seek)?;
@@ -54,6 +72,9 @@ impl SyncFileEngine {
         addr: GuestAddress,
         count: u32,
     ) -> Result<u32, Error> {
+        if let Some(alignment) = self.direct_io_alignment {
+            return self.write_direct(offset, mem, addr, count, alignment);
+        }
         self.file
             .seek(SeekFrom::Start(offset))
             .map_err(Error::Seek)?;
@@ -62,6 +83,90 @@ impl SyncFileEngine {
             .map_err(Error::Transfer)
     }
 
+    // Reads into guest memory directly when its buffer is aligned, or through a bounce buffer.
+    fn read_direct(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        alignment: u32,
+    ) -> Result<u32, Error> {
+        match aligned_host_address(mem, addr, count, alignment) {
+            Some(ptr) => {
+                // SAFETY: The `count` bytes at `ptr` are mapped guest memory, which may be
+                // modified by the guest concurrently, like with the other IO paths.
+                let buf = unsafe { std::slice::from_raw_parts_mut(ptr, count as usize) };
+                let read = Self::read_at_most(&self.file, buf, offset)?;
+                // The guest memory was not written through the `Bytes` interface.
+                mark_dirty_mem(mem, addr, read);
+                Ok(read as u32)
+            }
+            None => {
+                let mut bounce_buffer = BounceBuffer::new(count as usize, alignment);
+                let read = Self::read_at_most(&self.file, bounce_buffer.as_mut_slice(), offset)?;
+                mem.write_slice(&bounce_buffer.as_slice()[..read], addr)
+                    .map_err(Error::Transfer)?;
+                Ok(read as u32)
+            }
+        }
+    }
+
+    // Reads up to `buf.len()` bytes. Short direct reads only happen at the end of the file, where
+    // the next offset would not be aligned anymore, so they are not retried.
+    fn read_at_most(file: &File, buf: &mut [u8], offset: u64) -> Result<usize, Error> {
+        loop {
+            match file.read_at(buf, offset) {
+                Ok(count) => return Ok(count),
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(Error::Read(err)),
+            }
+        }
+    }
+
+    // Writes from guest memory directly when its buffer is aligned, or through a bounce buffer.
+    fn write_direct(
+        &mut self,
+        offset: u64,
+        mem: &GuestMemoryMmap,
+        addr: GuestAddress,
+        count: u32,
+        alignment: u32,
+    ) -> Result<u32, Error> {
+        match aligned_host_address(mem, addr, count, alignment) {
+            Some(ptr) => {
+                // SAFETY: The `count` bytes at `ptr` are mapped guest memory.
+                let buf = unsafe { std::slice::from_raw_parts(ptr, count as usize) };
+                self.file.write_all_at(buf, offset).map_err(Error::Write)?;
+            }
+            None => {
+                let mut bounce_buffer = BounceBuffer::new(count as usize, alignment);
+                mem.read_slice(bounce_buffer.as_mut_slice(), addr)
+                    .map_err(Error::Transfer)?;
+                self.file
+                    .write_all_at(bounce_buffer.as_slice(), offset)
+                    .map_err(Error::Write)?;
+            }
+        }
+        Ok(count)
+    }
+
+    pub fn fallocate(&mut self, offset: u64, len: u64, mode: u32) -> Result<(), Error> {
+        // SAFETY: Safe because the file descriptor is valid and we check the return value.
+        let ret = unsafe {
+            libc::fallocate64(
+                self.file.as_raw_fd(),
+                mode as libc::c_int,
+                offset as libc::off64_t,
+                len as libc::off64_t,
+            )
+        };
+        if ret != 0 {
+            return Err(Error::Fallocate(std::io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+
     pub fn flush(&mut self) -> Result<(), Error> {
         // flush() first to force any cached data out of rust buffers.
         self.file.flush().map_err(Error::Flush)?;
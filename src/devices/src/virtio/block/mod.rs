@@ -10,18 +10,25 @@ pub mod test_utils;
 
 use vm_memory::GuestMemoryError;
 
-pub use self::device::{Block, CacheType};
+pub use self::device::{Block, CacheType, ImageFormat};
 pub use self::event_handler::*;
+pub use self::io::nbd::is_nbd_uri;
 pub use self::request::*;
 
-pub const CONFIG_SPACE_SIZE: usize = 8;
+// Up to and including the discard and write zeroes limits of `struct virtio_blk_config`.
+pub const CONFIG_SPACE_SIZE: usize = 60;
 pub const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01_u64) << SECTOR_SHIFT;
+// Largest range a discard or write zeroes request may cover, so that its size in bytes fits
+// in an u32.
+pub const MAX_DISCARD_SECTORS: u32 = u32::MAX >> SECTOR_SHIFT;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 1;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE];
+pub const DEFAULT_NUM_QUEUES: usize = 1;
+// Each queue has its own IO engine, so the number of queues is bounded to limit the number of
+// io_uring instances per device.
+pub const MAX_NUM_QUEUES: usize = 32;
 // The virtio queue can hold up to 256 descriptors, but 1 request spreads across 2-3 descriptors.
-// So we can use 128 IO_URING entries without ever triggering a FullSq Error.
+// So we can use 128 IO_URING entries per queue without ever triggering a FullSq Error.
 pub const IO_URING_NUM_ENTRIES: u16 = 128;
 
 #[derive(Debug)]
@@ -36,8 +43,20 @@ pub enum Error {
     GuestMemory(GuestMemoryError),
     /// The data length is invalid.
     InvalidDataLength,
+    /// The discard or write zeroes request has unsupported flags set.
+    InvalidFlags(u32),
+    /// The number of queues is not between 1 and `MAX_NUM_QUEUES`.
+    InvalidNumQueues(usize),
     /// The requested operation would cause a seek beyond disk end.
     InvalidOffset,
+    /// Direct IO is only supported for raw disk images without overlay.
+    UnsupportedDirectIo,
+    /// Copy-on-write overlays are only supported for raw disk images.
+    UnsupportedOverlayFormat,
+    /// NBD exports are only supported as raw disks, without overlay nor direct IO.
+    UnsupportedNbdDrive,
+    /// Resizing is only supported for raw disk image files without overlay.
+    UnsupportedResize,
     /// Guest gave us a read only descriptor that protocol says to write to.
     UnexpectedReadOnlyDescriptor,
     /// Guest gave us a write only descriptor that protocol says to read from.
@@ -9,6 +9,7 @@ use std::sync::Arc;
 use logger::warn;
 use rate_limiter::persist::RateLimiterState;
 use rate_limiter::RateLimiter;
+use serde::Serialize;
 use snapshot::Persist;
 use utils::kernel_version::min_kernel_version_for_io_uring;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
@@ -17,15 +18,27 @@ use virtio_gen::virtio_blk::VIRTIO_BLK_F_RO;
 use vm_memory::GuestMemoryMmap;
 
 use super::*;
-use crate::virtio::block::device::FileEngineType;
+use crate::virtio::block::device::{FileEngineType, ImageFormat};
 use crate::virtio::persist::VirtioDeviceState;
 use crate::virtio::{DeviceState, TYPE_BLOCK};
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Versionize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub enum CacheTypeState {
     Unsafe,
     Writeback,
+    #[version(start = 2, default_fn = "default_cache_type_direct")]
+    Direct,
+}
+
+impl CacheTypeState {
+    fn default_cache_type_direct(&self, _target_version: u16) -> VersionizeResult<Self> {
+        warn!(
+            "Target version does not implement the direct cache type. Defaulting to \"writeback\" \
+             mode."
+        );
+        Ok(CacheTypeState::Writeback)
+    }
 }
 
 impl From<CacheType> for CacheTypeState {
@@ -33,6 +46,7 @@ impl From<CacheType> for CacheTypeState {
         match cache_type {
             CacheType::Unsafe => CacheTypeState::Unsafe,
             CacheType::Writeback => CacheTypeState::Writeback,
+            CacheType::Direct => CacheTypeState::Direct,
         }
     }
 }
@@ -42,11 +56,12 @@ impl From<CacheTypeState> for CacheType {
         match cache_type_state {
             CacheTypeState::Unsafe => CacheType::Unsafe,
             CacheTypeState::Writeback => CacheType::Writeback,
+            CacheTypeState::Direct => CacheType::Direct,
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Versionize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub enum FileEngineTypeState {
     Sync,
@@ -79,7 +94,32 @@ impl Default for FileEngineTypeState {
     }
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub enum ImageFormatState {
+    Raw,
+    Qcow2,
+}
+
+impl From<ImageFormat> for ImageFormatState {
+    fn from(image_format: ImageFormat) -> Self {
+        match image_format {
+            ImageFormat::Raw => ImageFormatState::Raw,
+            ImageFormat::Qcow2 => ImageFormatState::Qcow2,
+        }
+    }
+}
+
+impl From<ImageFormatState> for ImageFormat {
+    fn from(image_format_state: ImageFormatState) -> Self {
+        match image_format_state {
+            ImageFormatState::Raw => ImageFormat::Raw,
+            ImageFormatState::Qcow2 => ImageFormat::Qcow2,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct BlockState {
     id: String,
@@ -99,9 +139,22 @@ pub struct BlockState {
     // v1.0 are incompatible with older FC versions (due to incompatible notification suppression
     // feature).
     file_engine_type: FileEngineTypeState,
+    #[version(
+        start = 4,
+        ser_fn = "block_image_format_ser",
+        default_fn = "default_image_format"
+    )]
+    image_format: ImageFormatState,
+    #[version(start = 5, ser_fn = "block_overlay_path_ser")]
+    overlay_path: Option<String>,
 }
 
 impl BlockState {
+    /// Replaces the host path of the backing file the device is restored with.
+    pub fn set_disk_path(&mut self, disk_path: String) {
+        self.disk_path = disk_path;
+    }
+
     fn block_cache_type_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
         if target_version < 3 && self.cache_type != CacheTypeState::Unsafe {
             warn!(
@@ -116,6 +169,39 @@ impl BlockState {
     fn default_cache_type_flush(_source_version: u16) -> CacheTypeState {
         CacheTypeState::Unsafe
     }
+
+    fn block_image_format_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Restoring a qcow2 image as a raw disk would expose its metadata to the guest.
+        if target_version < 4 && self.image_format != ImageFormatState::Raw {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the qcow2 image format.".to_owned(),
+            ));
+        }
+        // Multi-queue devices came along with the image format, and older versions would only
+        // restore the first queue.
+        if target_version < 4 && self.virtio_state.queues.len() > 1 {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement multi-queue block devices.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn default_image_format(_source_version: u16) -> ImageFormatState {
+        ImageFormatState::Raw
+    }
+
+    fn block_overlay_path_ser(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Restoring without the overlay would expose the base image to the guest.
+        if target_version < 5 && self.overlay_path.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement copy-on-write overlays.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub struct BlockConstructorArgs {
@@ -138,6 +224,8 @@ impl Persist<'_> for Block {
             virtio_state: VirtioDeviceState::from_device(self),
             rate_limiter_state: self.rate_limiter.save(),
             file_engine_type: FileEngineTypeState::from(self.file_engine_type()),
+            image_format: ImageFormatState::from(self.image_format()),
+            overlay_path: self.overlay_path().cloned(),
         }
     }
 
@@ -146,6 +234,9 @@ impl Persist<'_> for Block {
         state: &Self::State,
     ) -> Result<Self, Self::Error> {
         let is_disk_read_only = state.virtio_state.avail_features & (1u64 << VIRTIO_BLK_F_RO) != 0;
+        // The device is restored with as many queues as were saved, each with its own IO engine.
+        // Snapshots of devices with a single queue are compatible with older versions.
+        let num_queues = state.virtio_state.queues.len();
         let rate_limiter =
             RateLimiter::restore((), &state.rate_limiter_state).map_err(Error::RateLimiter)?;
 
@@ -158,6 +249,9 @@ impl Persist<'_> for Block {
             state.root_device,
             rate_limiter,
             state.file_engine_type.into(),
+            num_queues,
+            state.image_format.into(),
+            state.overlay_path.clone(),
         )
         .or_else(|err| match err {
             Error::FileEngine(io::Error::UnsupportedEngine(FileEngineType::Async)) => {
@@ -179,6 +273,9 @@ impl Persist<'_> for Block {
                     state.root_device,
                     rate_limiter,
                     FileEngineType::Sync,
+                    num_queues,
+                    state.image_format.into(),
+                    state.overlay_path.clone(),
                 )
             }
             other_err => Err(other_err),
@@ -186,7 +283,7 @@ impl Persist<'_> for Block {
 
         block.queues = state
             .virtio_state
-            .build_queues_checked(&constructor_args.mem, TYPE_BLOCK, NUM_QUEUES, QUEUE_SIZE)
+            .build_queues_checked(&constructor_args.mem, TYPE_BLOCK, num_queues, QUEUE_SIZE)
             .map_err(Error::Persist)?;
         block.irq_trigger.irq_status =
             Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
@@ -208,6 +305,7 @@ mod tests {
     use utils::tempfile::TempFile;
 
     use super::*;
+    use crate::virtio::block::io::qcow2::tests::create_image;
     use crate::virtio::device::VirtioDevice;
     use crate::virtio::test_utils::default_mem;
 
@@ -221,12 +319,43 @@ mod tests {
             CacheTypeState::Writeback,
             CacheTypeState::from(CacheType::Writeback)
         );
+        assert_eq!(
+            CacheTypeState::Direct,
+            CacheTypeState::from(CacheType::Direct)
+        );
     }
 
     #[test]
     fn test_cache_type_state_into() {
         assert_eq!(CacheType::Unsafe, CacheTypeState::Unsafe.into());
         assert_eq!(CacheType::Writeback, CacheTypeState::Writeback.into());
+        assert_eq!(CacheType::Direct, CacheTypeState::Direct.into());
+    }
+
+    #[test]
+    fn test_cache_type_state_direct() {
+        let mut mem = vec![0; 16];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(CacheTypeState::type_id(), 2);
+
+        // Versions which do not know the direct cache type get the writeback one.
+        CacheTypeState::Direct
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .unwrap();
+        assert_eq!(
+            CacheTypeState::deserialize(&mut mem.as_slice(), &version_map, 1).unwrap(),
+            CacheTypeState::Writeback
+        );
+
+        CacheTypeState::Direct
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        assert_eq!(
+            CacheTypeState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+            CacheTypeState::Direct
+        );
     }
 
     #[test]
@@ -257,6 +386,9 @@ mod tests {
             false,
             RateLimiter::default(),
             FileEngineType::default(),
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Raw,
+            None,
         )
         .unwrap();
 
@@ -311,6 +443,9 @@ mod tests {
                 // Need to use Sync because it will otherwise return an error.
                 // We'll overwrite the state instead.
                 FileEngineType::Sync,
+                DEFAULT_NUM_QUEUES,
+                ImageFormat::Raw,
+                None,
             )
             .unwrap();
 
@@ -354,6 +489,9 @@ mod tests {
             false,
             RateLimiter::default(),
             FileEngineType::default(),
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Raw,
+            None,
         )
         .unwrap();
         let guest_mem = default_mem();
@@ -387,4 +525,164 @@ mod tests {
         // Test that block specific fields are the same.
         assert_eq!(restored_block.disk.file_path(), block.disk.file_path());
     }
+
+    #[test]
+    fn test_persistence_multi_queue() {
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(0x1000).unwrap();
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::default(),
+            4,
+            ImageFormat::Raw,
+            None,
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 3)
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+
+        // Versions which do not know multi-queue devices would only restore the first queue.
+        for app_version in 1..=2 {
+            assert!(matches!(
+                <Block as Persist>::save(&block).serialize(
+                    &mut mem.as_mut_slice(),
+                    &version_map,
+                    app_version
+                ),
+                Err(VersionizeError::Semantic(_))
+            ));
+        }
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 3)
+            .unwrap();
+
+        // The extra queues are restored, each with its own queue event and IO engine.
+        let restored_block = Block::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 3).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.num_queues(), 4);
+        assert_eq!(restored_block.queues(), block.queues());
+        assert_eq!(restored_block.queue_events().len(), 4);
+        assert_eq!(restored_block.disk.file_engines().len(), 4);
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+
+        // A state without queues is rejected.
+        let mut block_state = <Block as Persist>::save(&block);
+        block_state.virtio_state.queues.clear();
+        assert!(matches!(
+            Block::restore(BlockConstructorArgs { mem: default_mem() }, &block_state),
+            Err(Error::InvalidNumQueues(0))
+        ));
+    }
+
+    #[test]
+    fn test_persistence_qcow2() {
+        let f = TempFile::new().unwrap();
+        create_image(f.as_file(), 0x10_0000, 12, None, None);
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            f.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Sync,
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Qcow2,
+            None,
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 4);
+
+        // Versions which do not know the image format cannot describe qcow2 images.
+        assert!(matches!(
+            <Block as Persist>::save(&block).serialize(&mut mem.as_mut_slice(), &version_map, 1),
+            Err(VersionizeError::Semantic(_))
+        ));
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.image_format(), ImageFormat::Qcow2);
+        assert_eq!(restored_block.disk.nsectors(), block.disk.nsectors());
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+
+        // Older states are restored as raw images.
+        assert_eq!(BlockState::default_image_format(3), ImageFormatState::Raw);
+    }
+
+    #[test]
+    fn test_persistence_overlay() {
+        let base = TempFile::new().unwrap();
+        base.as_file().set_len(0x10_0000).unwrap();
+        let overlay = TempFile::new().unwrap();
+        let overlay_path = overlay.as_path().to_str().unwrap().to_string();
+
+        let block = Block::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            base.as_path().to_str().unwrap().to_string(),
+            false,
+            false,
+            RateLimiter::default(),
+            FileEngineType::Sync,
+            DEFAULT_NUM_QUEUES,
+            ImageFormat::Raw,
+            Some(overlay_path.clone()),
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(BlockState::type_id(), 5);
+
+        // Versions which do not know overlays would restore the device on the base image.
+        assert!(matches!(
+            <Block as Persist>::save(&block).serialize(&mut mem.as_mut_slice(), &version_map, 1),
+            Err(VersionizeError::Semantic(_))
+        ));
+
+        <Block as Persist>::save(&block)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let restored_block = Block::restore(
+            BlockConstructorArgs { mem: default_mem() },
+            &BlockState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_block.overlay_path(), Some(&overlay_path));
+        assert_eq!(restored_block.disk.nsectors(), block.disk.nsectors());
+        assert_eq!(restored_block.avail_features(), block.avail_features());
+    }
 }
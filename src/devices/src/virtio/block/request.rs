@@ -7,17 +7,19 @@
 
 use std::convert::From;
 use std::result;
+use std::sync::Arc;
 
-use logger::{error, IncMetric, METRICS};
+use logger::{error, BlockDriveMetrics, IncMetric, METRICS};
 use rate_limiter::{RateLimiter, TokenType};
 pub use virtio_gen::virtio_blk::{
     VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
-    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
+    VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
+    VIRTIO_BLK_T_OUT, VIRTIO_BLK_T_WRITE_ZEROES, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
 };
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
 use super::super::DescriptorChain;
-use super::{io as block_io, Error, SECTOR_SHIFT};
+use super::{io as block_io, Error, MAX_DISCARD_SECTORS, SECTOR_SHIFT};
 use crate::virtio::block::device::DiskProperties;
 use crate::virtio::SECTOR_SIZE;
 
@@ -34,6 +36,8 @@ pub enum RequestType {
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -44,6 +48,8 @@ impl From<u32> for RequestType {
             VIRTIO_BLK_T_OUT => RequestType::Out,
             VIRTIO_BLK_T_FLUSH => RequestType::Flush,
             VIRTIO_BLK_T_GET_ID => RequestType::GetDeviceID,
+            VIRTIO_BLK_T_DISCARD => RequestType::Discard,
+            VIRTIO_BLK_T_WRITE_ZEROES => RequestType::WriteZeroes,
             t => RequestType::Unsupported(t),
         }
     }
@@ -91,6 +97,9 @@ pub struct PendingRequest {
     data_len: u32,
     status_addr: GuestAddress,
     desc_idx: u16,
+    // The metrics of the drive, along with the time the request started to be processed at.
+    metrics: Arc<BlockDriveMetrics>,
+    start_time_us: u64,
 }
 
 impl PendingRequest {
@@ -134,25 +143,40 @@ impl PendingRequest {
     }
 
     pub fn finish(self, mem: &GuestMemoryMmap, res: Result<u32, IoErr>) -> FinishedRequest {
+        let latency_us = match self.r#type {
+            RequestType::In => Some(&self.metrics.read_latency_us),
+            RequestType::Out => Some(&self.metrics.write_latency_us),
+            RequestType::Flush => Some(&self.metrics.flush_latency_us),
+            _ => None,
+        };
+        if let Some(latency_us) = latency_us {
+            latency_us.record_elapsed_time(self.start_time_us);
+        }
+
         let status = match (res, self.r#type) {
             (Ok(transferred_data_len), RequestType::In) => {
                 let status = Status::from_data(self.data_len, transferred_data_len, true);
                 METRICS.block.read_bytes.add(transferred_data_len as usize);
+                self.metrics.read_bytes.add(transferred_data_len as usize);
                 if let Status::Ok { .. } = status {
                     METRICS.block.read_count.inc();
+                    self.metrics.read_count.inc();
                 }
                 status
             }
             (Ok(transferred_data_len), RequestType::Out) => {
                 let status = Status::from_data(self.data_len, transferred_data_len, false);
                 METRICS.block.write_bytes.add(transferred_data_len as usize);
+                self.metrics.write_bytes.add(transferred_data_len as usize);
                 if let Status::Ok { .. } = status {
                     METRICS.block.write_count.inc();
+                    self.metrics.write_count.inc();
                 }
                 status
             }
             (Ok(_), RequestType::Flush) => {
                 METRICS.block.flush_count.inc();
+                self.metrics.flush_count.inc();
                 Status::Ok {
                     num_bytes_to_mem: 0,
                 }
@@ -160,6 +184,20 @@ impl PendingRequest {
             (Ok(transferred_data_len), RequestType::GetDeviceID) => {
                 Status::from_data(self.data_len, transferred_data_len, true)
             }
+            (Ok(_), RequestType::Discard) => {
+                METRICS.block.discard_bytes.add(self.data_len as usize);
+                METRICS.block.discard_count.inc();
+                Status::Ok {
+                    num_bytes_to_mem: 0,
+                }
+            }
+            (Ok(_), RequestType::WriteZeroes) => {
+                METRICS.block.write_zeroes_bytes.add(self.data_len as usize);
+                METRICS.block.write_zeroes_count.inc();
+                Status::Ok {
+                    num_bytes_to_mem: 0,
+                }
+            }
             (_, RequestType::Unsupported(op)) => Status::Unsupported { op },
             (Err(err), _) => Status::IoErr {
                 num_bytes_to_mem: 0,
@@ -171,6 +209,13 @@ impl PendingRequest {
     }
 }
 
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        // Requests leave the queue once finished, or when their IO engine discards them.
+        self.metrics.queue_depth.end();
+    }
+}
+
 /// The request header represents the mandatory fields of each block device request.
 ///
 /// A request header contains the following fields:
@@ -213,6 +258,33 @@ impl RequestHeader {
     }
 }
 
+/// The segment describing the range of a discard or write zeroes request.
+///
+/// A segment contains the following fields:
+///   * sector: an u64 value representing the first sector of the range.
+///   * num_sectors: an u32 value representing the number of sectors in the range.
+///   * flags: an u32 value; only the unmap flag of write zeroes requests is defined.
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct DiscardWriteZeroesSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+// SAFETY: Safe because DiscardWriteZeroesSegment only contains plain data.
+unsafe impl ByteValued for DiscardWriteZeroesSegment {}
+
+impl DiscardWriteZeroesSegment {
+    pub fn new(sector: u64, num_sectors: u32, flags: u32) -> DiscardWriteZeroesSegment {
+        DiscardWriteZeroesSegment {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+}
+
 #[cfg_attr(test, derive(Debug, PartialEq, Eq))]
 pub struct Request {
     pub r#type: RequestType,
@@ -220,6 +292,8 @@ pub struct Request {
     pub status_addr: GuestAddress,
     sector: u64,
     data_addr: GuestAddress,
+    // Whether a write zeroes request allows deallocating the range.
+    unmap: bool,
 }
 
 impl Request {
@@ -240,6 +314,7 @@ impl Request {
             data_addr: GuestAddress(0),
             data_len: 0,
             status_addr: GuestAddress(0),
+            unmap: false,
         };
 
         let data_desc;
@@ -260,7 +335,12 @@ impl Request {
                 .next_descriptor()
                 .ok_or(Error::DescriptorChainTooShort)?;
 
-            if data_desc.is_write_only() && req.r#type == RequestType::Out {
+            if data_desc.is_write_only()
+                && matches!(
+                    req.r#type,
+                    RequestType::Out | RequestType::Discard | RequestType::WriteZeroes
+                )
+            {
                 return Err(Error::UnexpectedWriteOnlyDescriptor);
             }
             if !data_desc.is_write_only() && req.r#type == RequestType::In {
@@ -295,6 +375,36 @@ impl Request {
                     return Err(Error::InvalidDataLength);
                 }
             }
+            RequestType::Discard | RequestType::WriteZeroes => {
+                // Only a single segment per request is supported, as advertised in the
+                // config space.
+                if req.data_len as usize != std::mem::size_of::<DiscardWriteZeroesSegment>() {
+                    return Err(Error::InvalidDataLength);
+                }
+                let segment: DiscardWriteZeroesSegment =
+                    mem.read_obj(req.data_addr).map_err(Error::GuestMemory)?;
+                if segment.num_sectors == 0 || segment.num_sectors > MAX_DISCARD_SECTORS {
+                    return Err(Error::InvalidDataLength);
+                }
+                let top_sector = segment
+                    .sector
+                    .checked_add(u64::from(segment.num_sectors))
+                    .ok_or(Error::InvalidOffset)?;
+                if top_sector > num_disk_sectors {
+                    return Err(Error::InvalidOffset);
+                }
+                let valid_flags = match req.r#type {
+                    RequestType::WriteZeroes => VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+                    _ => 0,
+                };
+                if segment.flags & !valid_flags != 0 {
+                    return Err(Error::InvalidFlags(segment.flags));
+                }
+
+                req.sector = segment.sector;
+                req.data_len = segment.num_sectors << SECTOR_SHIFT;
+                req.unmap = segment.flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+            }
             _ => {}
         }
 
@@ -318,8 +428,12 @@ impl Request {
         if !rate_limiter.consume(1, TokenType::Ops) {
             return true;
         }
-        // Exercise the rate limiter only if this request is of data transfer type.
-        if self.r#type == RequestType::In || self.r#type == RequestType::Out {
+        // Exercise the rate limiter only if this request transfers data or, for discard and
+        // write zeroes requests, changes a range of the disk.
+        if matches!(
+            self.r#type,
+            RequestType::In | RequestType::Out | RequestType::Discard | RequestType::WriteZeroes
+        ) {
             // If limiter.consume() fails it means there is no more TokenType::Bytes
             // budget and rate limiting is in effect.
             if !rate_limiter.consume(u64::from(self.data_len), TokenType::Bytes) {
@@ -336,38 +450,66 @@ impl Request {
         self.sector << SECTOR_SHIFT
     }
 
-    fn to_pending_request(&self, desc_idx: u16) -> PendingRequest {
+    fn to_pending_request(
+        &self,
+        desc_idx: u16,
+        metrics: &Arc<BlockDriveMetrics>,
+    ) -> PendingRequest {
+        metrics.queue_depth.begin();
         PendingRequest {
             r#type: self.r#type,
             data_len: self.data_len,
             status_addr: self.status_addr,
             desc_idx,
+            metrics: metrics.clone(),
+            start_time_us: utils::time::get_time_us(utils::time::ClockType::Monotonic),
         }
     }
 
     pub(crate) fn process(
         self,
         disk: &mut DiskProperties,
+        queue_index: usize,
         desc_idx: u16,
         mem: &GuestMemoryMmap,
+        metrics: &Arc<BlockDriveMetrics>,
     ) -> ProcessingResult {
-        let pending = self.to_pending_request(desc_idx);
+        let pending = self.to_pending_request(desc_idx, metrics);
         let res = match self.r#type {
-            RequestType::In => disk.file_engine_mut().read(
+            RequestType::In => disk.file_engine_mut(queue_index).read(
                 self.offset(),
                 mem,
                 self.data_addr,
                 self.data_len,
                 pending,
             ),
-            RequestType::Out => disk.file_engine_mut().write(
+            RequestType::Out => disk.file_engine_mut(queue_index).write(
                 self.offset(),
                 mem,
                 self.data_addr,
                 self.data_len,
                 pending,
             ),
-            RequestType::Flush => disk.file_engine_mut().flush(pending),
+            RequestType::Flush => disk.file_engine_mut(queue_index).flush(pending),
+            RequestType::Discard => disk.file_engine_mut(queue_index).discard(
+                self.offset(),
+                u64::from(self.data_len),
+                pending,
+            ),
+            RequestType::WriteZeroes => {
+                // When the driver allows deallocating the range, punch a hole instead of
+                // allocating zeroed blocks.
+                let mode = match self.unmap {
+                    true => libc::FALLOC_FL_PUNCH_HOLE,
+                    false => libc::FALLOC_FL_ZERO_RANGE,
+                };
+                disk.file_engine_mut(queue_index).fallocate(
+                    self.offset(),
+                    u64::from(self.data_len),
+                    (mode | libc::FALLOC_FL_KEEP_SIZE) as u32,
+                    pending,
+                )
+            }
             RequestType::GetDeviceID => {
                 let res = mem
                     .write_slice(disk.image_id(), self.data_addr)
@@ -423,6 +565,8 @@ mod tests {
             VIRTIO_BLK_T_OUT,
             VIRTIO_BLK_T_FLUSH,
             VIRTIO_BLK_T_GET_ID,
+            VIRTIO_BLK_T_DISCARD,
+            VIRTIO_BLK_T_WRITE_ZEROES,
         ];
 
         for request_type in supported_request_types {
@@ -449,6 +593,14 @@ mod tests {
             RequestType::from(VIRTIO_BLK_T_GET_ID),
             RequestType::GetDeviceID
         );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_DISCARD),
+            RequestType::Discard
+        );
+        assert_eq!(
+            RequestType::from(VIRTIO_BLK_T_WRITE_ZEROES),
+            RequestType::WriteZeroes
+        );
         assert_eq!(RequestType::from(42), RequestType::Unsupported(42));
     }
 
@@ -696,6 +848,127 @@ mod tests {
         queue.check_parse(true);
     }
 
+    fn check_parse_segment(
+        request_type: u32,
+        flags: u32,
+        check: impl Fn(&RequestVirtQueue, &GuestMemoryMmap),
+    ) {
+        let mem = &create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        let mut queue = RequestVirtQueue::new(GuestAddress(0), mem);
+        let segment_len = std::mem::size_of::<DiscardWriteZeroesSegment>() as u32;
+
+        // The header sector is ignored, the range is described by the segment.
+        let request_header = RequestHeader::new(request_type, 0);
+        queue.set_hdr_desc(0x1000, 0x1000, VIRTQ_DESC_F_NEXT, request_header);
+        queue.set_status_desc(0x3000, 0x1000, VIRTQ_DESC_F_WRITE);
+
+        // Write only data descriptor.
+        queue.set_data_desc(0x2000, segment_len, VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+        queue.check_parse_err(Error::UnexpectedWriteOnlyDescriptor);
+
+        // Several segments.
+        queue.set_data_desc(0x2000, 2 * segment_len, VIRTQ_DESC_F_NEXT);
+        queue.check_parse_err(Error::InvalidDataLength);
+
+        // Empty range.
+        queue.mut_data_desc().len.set(segment_len);
+        mem.write_obj(
+            DiscardWriteZeroesSegment::new(10, 0, flags),
+            GuestAddress(0x2000),
+        )
+        .unwrap();
+        queue.check_parse_err(Error::InvalidDataLength);
+
+        // Range beyond the end of the disk.
+        mem.write_obj(
+            DiscardWriteZeroesSegment::new(NUM_DISK_SECTORS - 1, 2, flags),
+            GuestAddress(0x2000),
+        )
+        .unwrap();
+        queue.check_parse_err(Error::InvalidOffset);
+
+        // Unknown flags.
+        mem.write_obj(
+            DiscardWriteZeroesSegment::new(10, 2, flags | 2),
+            GuestAddress(0x2000),
+        )
+        .unwrap();
+        queue.check_parse_err(Error::InvalidFlags(flags | 2));
+
+        mem.write_obj(
+            DiscardWriteZeroesSegment::new(NUM_DISK_SECTORS - 2, 2, flags),
+            GuestAddress(0x2000),
+        )
+        .unwrap();
+        check(&queue, mem);
+    }
+
+    #[test]
+    fn test_parse_discard() {
+        check_parse_segment(VIRTIO_BLK_T_DISCARD, 0, |queue, mem| {
+            let mut q = queue.vq.create_queue();
+            let request = Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS).unwrap();
+            assert_eq!(request.r#type, RequestType::Discard);
+            assert_eq!(request.sector, NUM_DISK_SECTORS - 2);
+            assert_eq!(request.data_len, 1024);
+            assert!(!request.unmap);
+        });
+
+        // The unmap flag is only defined for write zeroes requests.
+        check_parse_segment(
+            VIRTIO_BLK_T_DISCARD,
+            VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+            |queue, _| {
+                queue.check_parse_err(Error::InvalidFlags(VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP));
+            },
+        );
+    }
+
+    #[test]
+    fn test_parse_write_zeroes() {
+        for flags in [0, VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP] {
+            check_parse_segment(VIRTIO_BLK_T_WRITE_ZEROES, flags, |queue, mem| {
+                let mut q = queue.vq.create_queue();
+                let request = Request::parse(&q.pop(mem).unwrap(), mem, NUM_DISK_SECTORS).unwrap();
+                assert_eq!(request.r#type, RequestType::WriteZeroes);
+                assert_eq!(request.sector, NUM_DISK_SECTORS - 2);
+                assert_eq!(request.data_len, 1024);
+                assert_eq!(request.unmap, flags != 0);
+            });
+        }
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let request = |r#type, data_len| Request {
+            r#type,
+            data_len,
+            status_addr: GuestAddress(0),
+            sector: 0,
+            data_addr: GuestAddress(0),
+            unmap: false,
+        };
+
+        // The requests changing a range of the disk consume its length in bytes.
+        for r#type in [
+            RequestType::In,
+            RequestType::Out,
+            RequestType::Discard,
+            RequestType::WriteZeroes,
+        ] {
+            let mut rate_limiter = RateLimiter::new(1024, 0, 1000, 0, 0, 0).unwrap();
+            assert!(!request(r#type, 1024).rate_limit(&mut rate_limiter));
+            assert!(request(r#type, 512).rate_limit(&mut rate_limiter));
+        }
+
+        // The other requests do not consume any byte.
+        for r#type in [RequestType::Flush, RequestType::GetDeviceID] {
+            let mut rate_limiter = RateLimiter::new(1, 0, 1000, 0, 0, 0).unwrap();
+            assert!(!request(RequestType::Out, 1).rate_limit(&mut rate_limiter));
+            assert!(!request(r#type, 1024).rate_limit(&mut rate_limiter));
+        }
+    }
+
     use std::convert::TryInto;
 
     /// -------------------------------------
@@ -738,9 +1011,9 @@ mod tests {
                     1u32,
                     std::sync::Arc::new(Strategy::prop_map(any::<u32>(), |id| {
                         // Random unsupported requests for our implementation start at
-                        // VIRTIO_BLK_T_GET_ID + 1 = 9.
-                        // This can be further refined to include unsupported requests ids < 9.
-                        RequestType::Unsupported(id.checked_add(9).unwrap_or(9))
+                        // VIRTIO_BLK_T_WRITE_ZEROES + 1 = 14.
+                        // This can be further refined to include unsupported requests ids < 14.
+                        RequestType::Unsupported(id.checked_add(14).unwrap_or(14))
                     })),
                 ),
             ))
@@ -754,6 +1027,8 @@ mod tests {
                 RequestType::Out => VIRTIO_BLK_T_OUT,
                 RequestType::Flush => VIRTIO_BLK_T_FLUSH,
                 RequestType::GetDeviceID => VIRTIO_BLK_T_GET_ID,
+                RequestType::Discard => VIRTIO_BLK_T_DISCARD,
+                RequestType::WriteZeroes => VIRTIO_BLK_T_WRITE_ZEROES,
                 RequestType::Unsupported(id) => id,
             }
         }
@@ -766,6 +1041,7 @@ mod tests {
             RequestType::Out => VIRTQ_DESC_F_NEXT,
             RequestType::Flush => VIRTQ_DESC_F_NEXT,
             RequestType::GetDeviceID => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            RequestType::Discard | RequestType::WriteZeroes => VIRTQ_DESC_F_NEXT,
             RequestType::Unsupported(_) => VIRTQ_DESC_F_NEXT,
         }
     }
@@ -859,6 +1135,7 @@ mod tests {
             status_addr,
             sector: sector & (NUM_DISK_SECTORS - sectors_len),
             data_addr,
+            unmap: false,
         };
         let request_header = RequestHeader::new(virtio_request_id, request.sector);
 
@@ -12,9 +12,10 @@ use rate_limiter::RateLimiter;
 use utils::kernel_version::{min_kernel_version_for_io_uring, KernelVersion};
 use utils::tempfile::TempFile;
 
-use crate::virtio::block::device::FileEngineType;
+use crate::virtio::block::device::{FileEngineType, ImageFormat};
 #[cfg(test)]
 use crate::virtio::block::io::FileEngine;
+use crate::virtio::block::DEFAULT_NUM_QUEUES;
 #[cfg(test)]
 use crate::virtio::IrqType;
 use crate::virtio::{Block, CacheType, Queue};
@@ -28,6 +29,18 @@ pub fn default_block(file_engine_type: FileEngineType) -> Block {
     default_block_with_path(f.as_path().to_str().unwrap().to_string(), file_engine_type)
 }
 
+/// Create a default Block instance with the specified drive id to be used in tests.
+pub fn default_block_with_id(id: String, file_engine_type: FileEngineType) -> Block {
+    let f = TempFile::new().unwrap();
+    f.as_file().set_len(0x1000).unwrap();
+
+    new_block(
+        id,
+        f.as_path().to_str().unwrap().to_string(),
+        file_engine_type,
+    )
+}
+
 /// Return the Async FileEngineType if supported by the host, otherwise default to Sync.
 pub fn default_engine_type_for_kv() -> FileEngineType {
     if KernelVersion::get().unwrap() >= min_kernel_version_for_io_uring() {
@@ -39,10 +52,13 @@ pub fn default_engine_type_for_kv() -> FileEngineType {
 
 /// Create a default Block instance using file at the specified path to be used in tests.
 pub fn default_block_with_path(path: String, file_engine_type: FileEngineType) -> Block {
+    new_block("test".to_string(), path, file_engine_type)
+}
+
+fn new_block(id: String, path: String, file_engine_type: FileEngineType) -> Block {
     // Rate limiting is enabled but with a high operation rate (10 million ops/s).
     let rate_limiter = RateLimiter::new(0, 0, 0, 100_000, 0, 10).unwrap();
 
-    let id = "test".to_string();
     // The default block device is read-write and non-root.
     Block::new(
         id,
@@ -53,6 +69,9 @@ pub fn default_block_with_path(path: String, file_engine_type: FileEngineType) -
         false,
         rate_limiter,
         file_engine_type,
+        DEFAULT_NUM_QUEUES,
+        ImageFormat::Raw,
+        None,
     )
     .unwrap()
 }
@@ -74,7 +93,7 @@ pub fn simulate_queue_event(b: &mut Block, maybe_expected_irq: Option<bool>) {
     // Trigger the queue event.
     b.queue_evts[0].write(1).unwrap();
     // Handle event.
-    b.process_queue_event();
+    b.process_queue_event(0);
     // Validate the queue operation finished successfully.
     if let Some(expected_irq) = maybe_expected_irq {
         assert_eq!(b.irq_trigger.has_pending_irq(IrqType::Vring), expected_irq);
@@ -83,13 +102,17 @@ pub fn simulate_queue_event(b: &mut Block, maybe_expected_irq: Option<bool>) {
 
 #[cfg(test)]
 pub fn simulate_async_completion_event(b: &mut Block, expected_irq: bool) {
-    if let FileEngine::Async(engine) = b.disk.file_engine_mut() {
+    if let FileEngine::Async(engine) = b.disk.file_engine_mut(0) {
         // Wait for all the async operations to complete.
         engine.drain(false).unwrap();
         // Wait for the async completion event to be sent.
         thread::sleep(Duration::from_millis(150));
         // Handle event.
-        b.process_async_completion_event();
+        b.process_async_completion_event(0);
+    } else if let FileEngine::Nbd(engine) = b.disk.file_engine_mut(0) {
+        // Wait for the replies to all the requests.
+        engine.drain(false).unwrap();
+        b.process_async_completion_event(0);
     }
 
     // Validate if there are pending IRQs.
@@ -98,12 +121,12 @@ pub fn simulate_async_completion_event(b: &mut Block, expected_irq: bool) {
 
 #[cfg(test)]
 pub fn simulate_queue_and_async_completion_events(b: &mut Block, expected_irq: bool) {
-    match b.disk.file_engine_mut() {
-        FileEngine::Async(_) => {
+    match b.disk.file_engine_mut(0) {
+        FileEngine::Async(_) | FileEngine::Nbd(_) => {
             simulate_queue_event(b, None);
             simulate_async_completion_event(b, expected_irq);
         }
-        FileEngine::Sync(_) => {
+        FileEngine::Sync(_) | FileEngine::Qcow2(_) | FileEngine::Overlay(_) => {
             simulate_queue_event(b, Some(expected_irq));
         }
     }
@@ -8,7 +8,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, MutexGuard};
 
-use logger::warn;
+use logger::{error, warn};
 use utils::byte_order;
 use vm_memory::{GuestAddress, GuestMemoryMmap};
 
@@ -83,6 +83,12 @@ impl MmioTransport {
         self.device.clone()
     }
 
+    /// Whether a guest driver is bound to the device, i.e. the driver started initializing the
+    /// device and has neither reset it nor given up on it.
+    pub fn is_driver_active(&self) -> bool {
+        self.device_status != device_status::INIT && self.device_status & device_status::FAILED == 0
+    }
+
     fn check_device_status(&self, set: u32, clr: u32) -> bool {
         self.device_status & (set | clr) == set
     }
@@ -178,9 +184,13 @@ impl MmioTransport {
                 self.device_status = status;
                 let device_activated = self.locked_device().is_activated();
                 if !device_activated && self.are_queues_valid() {
-                    self.locked_device()
-                        .activate(self.mem.clone())
-                        .expect("Failed to activate device");
+                    let activate_result = self.locked_device().activate(self.mem.clone());
+                    // The device is unusable until the driver resets it, e.g. when its
+                    // backend is gone, but the rest of the microVM keeps running.
+                    if let Err(err) = activate_result {
+                        error!("Failed to activate virtio device: {:?}", err);
+                        self.device_status |= DEVICE_NEEDS_RESET;
+                    }
                 }
             }
             _ if (status & FAILED) != 0 => {
@@ -835,13 +845,16 @@ pub(crate) mod tests {
         assert!(!d.are_queues_valid());
         assert!(!d.locked_device().is_activated());
         assert_eq!(d.device_status, 0);
+        assert!(!d.is_driver_active());
         activate_device(&mut d);
+        assert!(d.is_driver_active());
 
         // Marking device as FAILED should not affect device_activated state
         write_le_u32(&mut buf[..], 0x8f);
         d.write(0x70, &buf[..]);
         assert_eq!(d.device_status, 0x8f);
         assert!(d.locked_device().is_activated());
+        assert!(!d.is_driver_active());
 
         // Nothing happens when backend driver doesn't support reset
         write_le_u32(&mut buf[..], 0x0);
@@ -17,6 +17,7 @@ pub mod net;
 pub mod persist;
 mod queue;
 pub mod test_utils;
+pub mod vhost_user_blk;
 pub mod vsock;
 
 pub use self::balloon::*;
@@ -26,6 +27,7 @@ pub use self::mmio::*;
 pub use self::net::*;
 pub use self::persist::*;
 pub use self::queue::*;
+pub use self::vhost_user_blk::VhostUserBlock;
 pub use self::vsock::*;
 
 /// When the driver initializes the device, it lets the device know about the
@@ -43,6 +45,7 @@ mod device_status {
     pub const FAILED: u32 = 128;
     pub const FEATURES_OK: u32 = 8;
     pub const DRIVER_OK: u32 = 4;
+    pub const DEVICE_NEEDS_RESET: u32 = 64;
 }
 
 /// Types taken from linux/virtio_ids.h.
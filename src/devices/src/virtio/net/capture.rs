@@ -0,0 +1,149 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Writes the frames going through a net device to a file, in the pcap format.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use utils::time::{get_time_us, ClockType};
+
+// The magic number of pcap files whose timestamps have a microsecond resolution.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+// Frames are never truncated, since the largest frame of a net device fits in this length.
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+const PCAP_HEADER_LEN: u64 = 24;
+const PCAP_RECORD_HEADER_LEN: u64 = 16;
+
+/// A packet capture of the Ethernet frames received and transmitted by a net device.
+///
+/// The capture ends once the file would grow past `max_bytes`, or once `max_packets` frames
+/// were written to it. The frames are buffered, and only reach the file when the capture is
+/// flushed or dropped.
+pub struct PacketCapture {
+    writer: BufWriter<File>,
+    max_bytes: Option<u64>,
+    max_packets: Option<u64>,
+    bytes: u64,
+    packets: u64,
+}
+
+impl PacketCapture {
+    /// Creates the capture file at `path`, replacing any existing file, and writes the pcap
+    /// header to it.
+    pub fn new(path: &Path, max_bytes: Option<u64>, max_packets: Option<u64>) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut writer = BufWriter::new(file);
+
+        writer.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        writer.write_all(&PCAP_VERSION_MAJOR.to_le_bytes())?;
+        writer.write_all(&PCAP_VERSION_MINOR.to_le_bytes())?;
+        // The timestamps are in UTC, and their accuracy is not known.
+        writer.write_all(&0i32.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        writer.flush()?;
+
+        Ok(PacketCapture {
+            writer,
+            max_bytes,
+            max_packets,
+            bytes: PCAP_HEADER_LEN,
+            packets: 0,
+        })
+    }
+
+    /// Appends a frame to the capture file.
+    ///
+    /// Returns `false`, without writing the frame, when it would exceed one of the limits of
+    /// the capture.
+    pub fn write_frame(&mut self, frame: &[u8]) -> io::Result<bool> {
+        let record_len = PCAP_RECORD_HEADER_LEN + frame.len() as u64;
+        if self.max_packets.map_or(false, |max| self.packets >= max)
+            || self
+                .max_bytes
+                .map_or(false, |max| self.bytes + record_len > max)
+        {
+            return Ok(false);
+        }
+
+        let timestamp_us = get_time_us(ClockType::Real);
+        self.writer
+            .write_all(&((timestamp_us / 1_000_000) as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&((timestamp_us % 1_000_000) as u32).to_le_bytes())?;
+        // Both the captured and the original lengths.
+        self.writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.writer.write_all(frame)?;
+
+        self.bytes += record_len;
+        self.packets += 1;
+        Ok(true)
+    }
+
+    /// Writes the buffered frames to the capture file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Provides the number of frames written to the capture file.
+    pub fn packets(&self) -> u64 {
+        self.packets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+
+    #[test]
+    fn test_packet_capture() {
+        let file = TempFile::new().unwrap();
+        let frame = [0xaau8; 60];
+        let record_len = PCAP_RECORD_HEADER_LEN as usize + frame.len();
+
+        let mut capture = PacketCapture::new(file.as_path(), None, Some(2)).unwrap();
+        assert!(capture.write_frame(&frame).unwrap());
+        assert!(capture.write_frame(&frame[..42]).unwrap());
+        // The packet limit was reached.
+        assert!(!capture.write_frame(&frame).unwrap());
+        assert_eq!(capture.packets(), 2);
+
+        // The frames are buffered until the capture is flushed.
+        assert_eq!(fs::metadata(file.as_path()).unwrap().len(), PCAP_HEADER_LEN);
+        capture.flush().unwrap();
+        let data = fs::read(file.as_path()).unwrap();
+        assert_eq!(data.len(), PCAP_HEADER_LEN as usize + 2 * record_len - 18);
+        assert_eq!(&data[0..4], &PCAP_MAGIC.to_le_bytes());
+        assert_eq!(&data[20..24], &LINKTYPE_ETHERNET.to_le_bytes());
+        // The captured and original lengths of the first frame, followed by the frame.
+        let record = &data[PCAP_HEADER_LEN as usize..];
+        assert_eq!(&record[8..12], &60u32.to_le_bytes());
+        assert_eq!(&record[12..16], &60u32.to_le_bytes());
+        assert_eq!(&record[16..record_len], &frame[..]);
+
+        // The file can't grow past the byte limit.
+        let max_bytes = PCAP_HEADER_LEN + record_len as u64;
+        let mut capture = PacketCapture::new(file.as_path(), Some(max_bytes), None).unwrap();
+        assert!(capture.write_frame(&frame).unwrap());
+        assert!(!capture.write_frame(&frame[..1]).unwrap());
+        // Dropping the capture flushes it.
+        drop(capture);
+        assert_eq!(fs::metadata(file.as_path()).unwrap().len(), max_bytes);
+    }
+}
@@ -5,33 +5,42 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::net::Ipv4Addr;
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{cmp, mem, result};
 
 use dumbo::pdu::ethernet::EthernetFrame;
 use libc::EAGAIN;
-use logger::{error, warn, IncMetric, METRICS};
+use logger::{error, info, warn, IncMetric, METRICS};
 use mmds::data_store::Mmds;
 use mmds::ns::MmdsNetworkStack;
 use rate_limiter::{BucketUpdate, RateLimiter, TokenType};
+use snapshot::Persist;
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
 use utils::eventfd::EventFd;
 use utils::net::mac::MacAddr;
 use virtio_gen::virtio_net::{
-    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
-    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MAC,
+    virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MQ, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
+    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_ERR, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_CTRL_VQ,
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
+    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ,
+    VIRTIO_NET_F_MTU, VIRTIO_NET_F_STATUS, VIRTIO_NET_OK, VIRTIO_NET_S_LINK_UP,
 };
 use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{ByteValued, Bytes, GuestAddress, GuestMemoryError, GuestMemoryMmap};
 
+use crate::virtio::net::capture::PacketCapture;
+use crate::virtio::net::filter::AntiSpoofingFilter;
 use crate::virtio::net::tap::Tap;
 #[cfg(test)]
 use crate::virtio::net::test_utils::Mocks;
+use crate::virtio::net::user::{UserNetworkConfig, UserNetworkStack};
 use crate::virtio::net::{
-    Error, NetQueue, Result, MAX_BUFFER_SIZE, QUEUE_SIZE, QUEUE_SIZES, RX_INDEX, TX_INDEX,
+    Error, NetQueue, Result, MAX_BUFFER_SIZE, MAX_NUM_QUEUE_PAIRS, MIN_MTU, QUEUE_SIZE, RX_INDEX,
+    TX_INDEX,
 };
 use crate::virtio::{
     ActivateResult, DescriptorChain, DeviceState, IrqTrigger, IrqType, Queue, VirtioDevice,
@@ -47,6 +56,13 @@ enum FrontendError {
     ReadOnlyDescriptor,
 }
 
+// How often the frames buffered by the packet capture are written to its file.
+const CAPTURE_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// The longest control command handled by the device: the class and command bytes, followed by
+// the number of queue pairs.
+const CTRL_COMMAND_MAX_LEN: usize = 4;
+
 pub(crate) fn vnet_hdr_len() -> usize {
     mem::size_of::<virtio_net_hdr_v1>()
 }
@@ -75,9 +91,67 @@ fn init_vnet_hdr(buf: &mut [u8]) {
     buf[0..vnet_hdr_len()].fill(0);
 }
 
+// Writes the buffered frames of the packet capture, if any, to its file. The capture ends if the
+// file can't be written.
+fn flush_capture(capture: &mut Option<PacketCapture>) {
+    if let Some(Err(err)) = capture.as_mut().map(PacketCapture::flush) {
+        error!("Failed to write to the packet capture file: {:?}", err);
+        METRICS.net.capture_fails.inc();
+        *capture = None;
+    }
+}
+
+// Writes a frame, preceded by its VNET header, to the packet capture file, if any. The capture
+// ends once one of its limits is reached, or if the file can't be written.
+fn capture_frame(capture: &mut Option<PacketCapture>, buf: &[u8]) {
+    let pcap = match capture.as_mut() {
+        Some(pcap) => pcap,
+        None => return,
+    };
+    let frame = match frame_bytes_from_buf(buf) {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+
+    match pcap.write_frame(frame) {
+        Ok(true) => METRICS.net.capture_packets_count.inc(),
+        Ok(false) => {
+            info!(
+                "Packet capture limit reached after {} frames, stopping the capture.",
+                pcap.packets()
+            );
+            flush_capture(capture);
+            *capture = None;
+        }
+        Err(err) => {
+            error!("Failed to write to the packet capture file: {:?}", err);
+            METRICS.net.capture_fails.inc();
+            *capture = None;
+        }
+    }
+}
+
+// Where the frames transmitted by the guest, MMDS traffic excluded, are sent.
+pub(crate) enum Backend<'a> {
+    Tap(&'a mut Tap),
+    UserNs(&'a mut UserNetworkStack),
+}
+
+// Returns the index of a queue of the given queue pair from the queues/queue_evts vector.
+pub(crate) fn queue_index(pair: usize, queue_type: NetQueue) -> usize {
+    match queue_type {
+        NetQueue::Rx => 2 * pair + RX_INDEX,
+        NetQueue::Tx => 2 * pair + TX_INDEX,
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
 pub struct ConfigSpace {
     pub guest_mac: MacAddr,
+    pub status: u16,
+    pub max_virtqueue_pairs: u16,
+    pub mtu: u16,
 }
 
 // SAFETY: `ConfigSpace` contains only PODs.
@@ -86,7 +160,12 @@ unsafe impl ByteValued for ConfigSpace {}
 pub struct Net {
     pub(crate) id: String,
 
-    pub tap: Tap,
+    // The tap queues, one for each queue pair. There are none when the device is backed by
+    // `user_ns` instead.
+    pub(crate) taps: Vec<Tap>,
+    pub(crate) user_ns: Option<UserNetworkStack>,
+    // The number of queue pairs in use by the driver, whose tap queues are attached.
+    pub(crate) active_queue_pairs: usize,
 
     pub(crate) avail_features: u64,
     pub(crate) acked_features: u64,
@@ -94,13 +173,15 @@ pub struct Net {
     pub(crate) queues: Vec<Queue>,
     pub(crate) queue_evts: Vec<EventFd>,
 
-    pub(crate) rx_rate_limiter: RateLimiter,
-    pub(crate) tx_rate_limiter: RateLimiter,
+    // The rate limiters of each queue pair, which share the configuration of the device but
+    // account for the traffic of their own pair.
+    pub(crate) rx_rate_limiters: Vec<RateLimiter>,
+    pub(crate) tx_rate_limiters: Vec<RateLimiter>,
 
-    pub(crate) rx_deferred_frame: bool,
+    pub(crate) rx_deferred_frame: Vec<bool>,
 
-    rx_bytes_read: usize,
-    rx_frame_buf: [u8; MAX_BUFFER_SIZE],
+    rx_bytes_read: Vec<usize>,
+    rx_frame_buf: Vec<[u8; MAX_BUFFER_SIZE]>,
 
     tx_iovec: Vec<(GuestAddress, usize)>,
     tx_frame_buf: [u8; MAX_BUFFER_SIZE],
@@ -109,47 +190,171 @@ pub struct Net {
 
     pub(crate) config_space: ConfigSpace,
     pub(crate) guest_mac: Option<MacAddr>,
+    pub(crate) mtu: Option<u16>,
 
     pub(crate) device_state: DeviceState,
     pub(crate) activate_evt: EventFd,
 
     pub mmds_ns: Option<MmdsNetworkStack>,
 
+    // The frames received and transmitted by the device, MMDS traffic included, are written to
+    // this capture.
+    pub(crate) capture: Option<PacketCapture>,
+    // Periodically writes the frames buffered by `capture` to its file.
+    pub(crate) capture_timer: TimerFd,
+
+    // The frames transmitted by the guest, MMDS traffic excluded, must pass this filter to reach
+    // the tap. It keeps the MAC the device was configured with, regardless of `guest_mac`.
+    pub(crate) anti_spoofing_filter: Option<AntiSpoofingFilter>,
+
     #[cfg(test)]
     pub(crate) mocks: Mocks,
 }
 
 impl Net {
-    /// Create a new virtio network device with the given TAP interface.
+    /// Create a new virtio network device with the given TAP interface, and
+    /// `num_queue_pairs` pairs of receive and transmit queues.
+    ///
+    /// Each queue pair is backed by its own queue of the TAP interface, which
+    /// must be a multi-queue interface when there are several pairs, and is
+    /// rate limited on its own with the buckets of the given rate limiters.
+    /// The optional `mtu` is advertised to the driver as the largest MTU to use.
     pub fn new_with_tap(
         id: String,
         tap_if_name: String,
         guest_mac: Option<MacAddr>,
         rx_rate_limiter: RateLimiter,
         tx_rate_limiter: RateLimiter,
+        num_queue_pairs: usize,
+        mtu: Option<u16>,
     ) -> Result<Self> {
-        let tap = Tap::open_named(&tap_if_name).map_err(Error::TapOpen)?;
+        if num_queue_pairs == 0 || num_queue_pairs > MAX_NUM_QUEUE_PAIRS {
+            return Err(Error::InvalidNumQueuePairs(num_queue_pairs));
+        }
+        Self::validate_mtu(mtu)?;
 
-        // Set offload flags to match the virtio features below.
-        tap.set_offload(
-            net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6,
-        )
-        .map_err(Error::TapSetOffload)?;
+        let taps = Tap::open_named_queues(&tap_if_name, num_queue_pairs).map_err(Error::TapOpen)?;
 
         let vnet_hdr_size = vnet_hdr_len() as i32;
-        tap.set_vnet_hdr_size(vnet_hdr_size)
-            .map_err(Error::TapSetVnetHdrSize)?;
+        for tap in &taps {
+            // Set offload flags to match the virtio features below.
+            tap.set_offload(
+                net_gen::TUN_F_CSUM
+                    | net_gen::TUN_F_UFO
+                    | net_gen::TUN_F_TSO4
+                    | net_gen::TUN_F_TSO6,
+            )
+            .map_err(Error::TapSetOffload)?;
+
+            tap.set_vnet_hdr_size(vnet_hdr_size)
+                .map_err(Error::TapSetVnetHdrSize)?;
+        }
 
-        let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
+        let avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_CSUM
             | 1 << VIRTIO_NET_F_GUEST_TSO4
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_STATUS
             | 1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_RING_F_EVENT_IDX;
 
-        let mut config_space = ConfigSpace::default();
+        Self::new_with_backend(
+            id,
+            taps,
+            None,
+            guest_mac,
+            rx_rate_limiter,
+            tx_rate_limiter,
+            mtu,
+            avail_features,
+        )
+    }
+
+    /// Create a new virtio network device with a single pair of receive and
+    /// transmit queues, whose traffic is handled by a userspace network stack
+    /// instead of a TAP interface.
+    ///
+    /// The guest is attached to a NATed network, which only lets it open
+    /// outbound TCP connections and UDP flows to the destinations `user_config`
+    /// lets it reach. The optional `mtu` is advertised to the driver as the
+    /// largest MTU to use.
+    pub fn new_with_user_ns(
+        id: String,
+        guest_mac: Option<MacAddr>,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+        mtu: Option<u16>,
+        user_config: UserNetworkConfig,
+    ) -> Result<Self> {
+        Self::validate_mtu(mtu)?;
+
+        let user_ns = UserNetworkStack::new(user_config).map_err(Error::UserNetworkStack)?;
+
+        // The stack handles neither partially checksummed nor segmentation offloaded frames, so
+        // the driver must transmit complete frames.
+        let avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
+            | 1 << VIRTIO_NET_F_GUEST_TSO4
+            | 1 << VIRTIO_NET_F_GUEST_UFO
+            | 1 << VIRTIO_NET_F_STATUS
+            | 1 << VIRTIO_F_VERSION_1
+            | 1 << VIRTIO_RING_F_EVENT_IDX;
+
+        Self::new_with_backend(
+            id,
+            Vec::new(),
+            Some(user_ns),
+            guest_mac,
+            rx_rate_limiter,
+            tx_rate_limiter,
+            mtu,
+            avail_features,
+        )
+    }
+
+    // Returns `rate_limiter` for the first queue pair, followed by rate limiters with the same
+    // buckets for the other pairs.
+    fn rate_limiter_per_queue_pair(
+        rate_limiter: RateLimiter,
+        num_queue_pairs: usize,
+    ) -> Result<Vec<RateLimiter>> {
+        let state = rate_limiter.save();
+        let mut rate_limiters = Vec::with_capacity(num_queue_pairs);
+        rate_limiters.push(rate_limiter);
+        for _ in 1..num_queue_pairs {
+            rate_limiters.push(RateLimiter::restore((), &state).map_err(Error::RateLimiter)?);
+        }
+        Ok(rate_limiters)
+    }
+
+    fn validate_mtu(mtu: Option<u16>) -> Result<()> {
+        match mtu.filter(|&mtu| mtu < MIN_MTU) {
+            Some(mtu) => Err(Error::InvalidMtu(mtu)),
+            None => Ok(()),
+        }
+    }
+
+    // Creates the device around its backend, which is either the tap queues or the userspace
+    // network stack. There is a queue pair for each tap queue, or a single one for the stack.
+    #[allow(clippy::too_many_arguments)]
+    fn new_with_backend(
+        id: String,
+        taps: Vec<Tap>,
+        user_ns: Option<UserNetworkStack>,
+        guest_mac: Option<MacAddr>,
+        rx_rate_limiter: RateLimiter,
+        tx_rate_limiter: RateLimiter,
+        mtu: Option<u16>,
+        mut avail_features: u64,
+    ) -> Result<Self> {
+        let num_queue_pairs = if user_ns.is_some() { 1 } else { taps.len() };
+
+        // The link is up until the user sets it down.
+        let mut config_space = ConfigSpace {
+            status: VIRTIO_NET_S_LINK_UP as u16,
+            ..Default::default()
+        };
         if let Some(mac) = guest_mac {
             config_space.guest_mac = mac;
             // Enabling feature for MAC address configuration
@@ -157,33 +362,54 @@ impl Net {
             avail_features |= 1 << VIRTIO_NET_F_MAC;
         }
 
-        let mut queue_evts = Vec::new();
-        let mut queues = Vec::new();
-        for &size in QUEUE_SIZES {
+        if let Some(mtu) = mtu {
+            config_space.mtu = mtu;
+            avail_features |= 1 << VIRTIO_NET_F_MTU;
+        }
+
+        let mut num_queues = 2 * num_queue_pairs;
+        if num_queue_pairs > 1 {
+            // The driver selects the number of queue pairs it uses through the control queue,
+            // which follows the queue pairs.
+            avail_features |= 1 << VIRTIO_NET_F_MQ | 1 << VIRTIO_NET_F_CTRL_VQ;
+            config_space.max_virtqueue_pairs = num_queue_pairs as u16;
+            num_queues += 1;
+        }
+
+        let mut queue_evts = Vec::with_capacity(num_queues);
+        for _ in 0..num_queues {
             queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
-            queues.push(Queue::new(size));
         }
 
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
+
         Ok(Net {
             id,
-            tap,
+            taps,
+            user_ns,
+            active_queue_pairs: num_queue_pairs,
             avail_features,
             acked_features: 0u64,
             queues,
             queue_evts,
-            rx_rate_limiter,
-            tx_rate_limiter,
-            rx_deferred_frame: false,
-            rx_bytes_read: 0,
-            rx_frame_buf: [0u8; MAX_BUFFER_SIZE],
+            rx_rate_limiters: Self::rate_limiter_per_queue_pair(rx_rate_limiter, num_queue_pairs)?,
+            tx_rate_limiters: Self::rate_limiter_per_queue_pair(tx_rate_limiter, num_queue_pairs)?,
+            rx_deferred_frame: vec![false; num_queue_pairs],
+            rx_bytes_read: vec![0; num_queue_pairs],
+            rx_frame_buf: vec![[0u8; MAX_BUFFER_SIZE]; num_queue_pairs],
             tx_frame_buf: [0u8; MAX_BUFFER_SIZE],
             tx_iovec: Vec::with_capacity(QUEUE_SIZE as usize),
             irq_trigger: IrqTrigger::new().map_err(Error::EventFd)?,
             config_space,
             guest_mac,
+            mtu,
             device_state: DeviceState::Inactive,
             activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
             mmds_ns: None,
+            capture: None,
+            capture_timer: TimerFd::new_custom(ClockId::Monotonic, true, true)
+                .map_err(Error::Timer)?,
+            anti_spoofing_filter: None,
 
             #[cfg(test)]
             mocks: Mocks::default(),
@@ -200,9 +426,86 @@ impl Net {
         self.guest_mac.as_ref()
     }
 
-    /// Provides the host IFACE name of this net device.
+    /// Provides the host IFACE name of this net device, which is empty when the device is
+    /// backed by the userspace network stack.
     pub fn iface_name(&self) -> String {
-        self.tap.if_name_as_str().to_string()
+        self.taps
+            .first()
+            .map_or_else(String::new, |tap| tap.if_name_as_str().to_string())
+    }
+
+    /// Provides the MTU advertised to the driver of this net device, if any.
+    pub fn mtu(&self) -> Option<u16> {
+        self.mtu
+    }
+
+    /// Tells whether the link of this net device is reported as up to the driver.
+    pub fn link_up(&self) -> bool {
+        self.config_space.status & VIRTIO_NET_S_LINK_UP as u16 != 0
+    }
+
+    /// Sets the link state reported to the driver, and notifies an activated driver of the
+    /// change.
+    pub fn set_link_up(&mut self, link_up: bool) {
+        if link_up == self.link_up() {
+            return;
+        }
+
+        self.config_space.status ^= VIRTIO_NET_S_LINK_UP as u16;
+        if self.is_activated() {
+            // Kick the driver to pick up the new link state.
+            self.irq_trigger.trigger_irq(IrqType::Config).unwrap();
+        }
+    }
+
+    /// Provides the number of queue pairs of this net device.
+    pub fn num_queue_pairs(&self) -> usize {
+        // The control queue, if any, follows the queue pairs.
+        self.queues.len() / 2
+    }
+
+    // The index of the control queue, which only exists with several queue pairs.
+    pub(crate) fn ctrl_queue_index(&self) -> usize {
+        2 * self.num_queue_pairs()
+    }
+
+    /// Starts writing the frames going through this net device to `capture`, replacing any
+    /// ongoing capture, or stops capturing them.
+    ///
+    /// The frames of the replaced capture are written to its file, and those of `capture` are
+    /// written to its file periodically.
+    pub fn set_capture(&mut self, capture: Option<PacketCapture>) {
+        flush_capture(&mut self.capture);
+        let timer_state = match capture {
+            Some(_) => TimerState::Periodic {
+                current: CAPTURE_FLUSH_INTERVAL,
+                interval: CAPTURE_FLUSH_INTERVAL,
+            },
+            None => TimerState::Disarmed,
+        };
+        self.capture_timer
+            .set_state(timer_state, SetTimeFlags::Default);
+        self.capture = capture;
+    }
+
+    /// Tells whether the frames going through this net device are being captured.
+    pub fn is_capturing(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    /// Sets the filter the frames transmitted by the guest must pass, or removes it.
+    pub fn set_anti_spoofing_filter(&mut self, filter: Option<AntiSpoofingFilter>) {
+        self.anti_spoofing_filter = filter;
+    }
+
+    /// Provides the filter the frames transmitted by the guest must pass, if any.
+    pub fn anti_spoofing_filter(&self) -> Option<&AntiSpoofingFilter> {
+        self.anti_spoofing_filter.as_ref()
+    }
+
+    /// Provides the userspace network stack backing this net device, if any.
+    pub fn user_ns(&self) -> Option<&UserNetworkStack> {
+        self.user_ns.as_ref()
     }
 
     /// Provides the MmdsNetworkStack of this net device.
@@ -225,25 +528,50 @@ impl Net {
         self.mmds_ns = None
     }
 
-    /// Provides a reference to the configured RX rate limiter.
+    /// Provides a reference to the RX rate limiter of the first queue pair, whose buckets are
+    /// configured like the ones of the other pairs.
     pub fn rx_rate_limiter(&self) -> &RateLimiter {
-        &self.rx_rate_limiter
+        &self.rx_rate_limiters[0]
     }
 
-    /// Provides a reference to the configured TX rate limiter.
+    /// Provides a reference to the TX rate limiter of the first queue pair, whose buckets are
+    /// configured like the ones of the other pairs.
     pub fn tx_rate_limiter(&self) -> &RateLimiter {
-        &self.tx_rate_limiter
+        &self.tx_rate_limiters[0]
     }
 
-    fn signal_used_queue(&mut self, queue_type: NetQueue) -> result::Result<(), DeviceError> {
+    /// Attaches the tap queues of the first `active_queue_pairs` queue pairs and detaches
+    /// the others, so that received frames are only steered to the queues the driver uses.
+    pub(crate) fn set_active_queue_pairs(&mut self, active_queue_pairs: usize) -> Result<()> {
+        if active_queue_pairs == 0 || active_queue_pairs > self.num_queue_pairs() {
+            return Err(Error::InvalidNumQueuePairs(active_queue_pairs));
+        }
+
+        // The tap queues of a single queue pair device can't be detached.
+        if self.num_queue_pairs() > 1 {
+            for pair in 0..self.num_queue_pairs() {
+                let is_active = pair < active_queue_pairs;
+                if is_active != (pair < self.active_queue_pairs) {
+                    self.taps[pair]
+                        .set_queue_enabled(is_active)
+                        .map_err(Error::TapSetQueue)?;
+                }
+                if !is_active {
+                    // The driver stopped using the queue, so a deferred frame can't be received.
+                    self.rx_deferred_frame[pair] = false;
+                }
+            }
+        }
+        self.active_queue_pairs = active_queue_pairs;
+
+        Ok(())
+    }
+
+    fn signal_used_queue(&mut self, queue_index: usize) -> result::Result<(), DeviceError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
-        let queue = match queue_type {
-            NetQueue::Rx => &mut self.queues[RX_INDEX],
-            NetQueue::Tx => &mut self.queues[TX_INDEX],
-        };
-
+        let queue = &mut self.queues[queue_index];
         if queue.prepare_kick(mem) {
             self.irq_trigger
                 .trigger_irq(IrqType::Vring)
@@ -256,38 +584,35 @@ impl Net {
         Ok(())
     }
 
-    // Attempts to copy a single frame into the guest if there is enough
-    // rate limiting budget.
+    // Attempts to copy a single frame into the RX queue of the given queue pair if there is
+    // enough rate limiting budget.
     // Returns true on successful frame delivery.
-    fn rate_limited_rx_single_frame(&mut self) -> bool {
+    fn rate_limited_rx_single_frame(&mut self, pair: usize) -> bool {
         // If limiter.consume() fails it means there is no more TokenType::Ops
         // budget and rate limiting is in effect.
-        if !self.rx_rate_limiter.consume(1, TokenType::Ops) {
+        if !self.rx_rate_limiters[pair].consume(1, TokenType::Ops) {
             METRICS.net.rx_rate_limiter_throttled.inc();
             return false;
         }
         // If limiter.consume() fails it means there is no more TokenType::Bytes
         // budget and rate limiting is in effect.
-        if !self
-            .rx_rate_limiter
-            .consume(self.rx_bytes_read as u64, TokenType::Bytes)
-        {
+        if !self.rx_rate_limiters[pair].consume(self.rx_bytes_read[pair] as u64, TokenType::Bytes) {
             // revert the OPS consume()
-            self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
+            self.rx_rate_limiters[pair].manual_replenish(1, TokenType::Ops);
             METRICS.net.rx_rate_limiter_throttled.inc();
             return false;
         }
 
         // Attempt frame delivery.
-        let success = self.write_frame_to_guest();
+        let success = self.write_frame_to_guest(pair);
 
         // Undo the tokens consumption if guest delivery failed.
         if !success {
             // revert the OPS consume()
-            self.rx_rate_limiter.manual_replenish(1, TokenType::Ops);
+            self.rx_rate_limiters[pair].manual_replenish(1, TokenType::Ops);
             // revert the BYTES consume()
-            self.rx_rate_limiter
-                .manual_replenish(self.rx_bytes_read as u64, TokenType::Bytes);
+            self.rx_rate_limiters[pair]
+                .manual_replenish(self.rx_bytes_read[pair] as u64, TokenType::Bytes);
         }
         success
     }
@@ -340,12 +665,12 @@ impl Net {
         Err(FrontendError::DescriptorChainTooSmall)
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest.
-    fn do_write_frame_to_guest(&mut self) -> std::result::Result<(), FrontendError> {
+    // Copies a single frame from the `self.rx_frame_buf` of the given queue pair into the guest.
+    fn do_write_frame_to_guest(&mut self, pair: usize) -> std::result::Result<(), FrontendError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
-        let queue = &mut self.queues[RX_INDEX];
+        let queue = &mut self.queues[queue_index(pair, NetQueue::Rx)];
         let head_descriptor = queue.pop_or_enable_notification(mem).ok_or_else(|| {
             METRICS.net.no_rx_avail_buffer.inc();
             FrontendError::EmptyQueue
@@ -354,7 +679,7 @@ impl Net {
 
         let result = Self::write_to_descriptor_chain(
             mem,
-            &self.rx_frame_buf[..self.rx_bytes_read],
+            &self.rx_frame_buf[pair][..self.rx_bytes_read[pair]],
             head_descriptor,
         );
         // Mark the descriptor chain as used. If an error occurred, skip the descriptor chain.
@@ -362,7 +687,7 @@ impl Net {
             METRICS.net.rx_fails.inc();
             0
         } else {
-            self.rx_bytes_read as u32
+            self.rx_bytes_read[pair] as u32
         };
         queue.add_used(mem, head_index, used_len).map_err(|err| {
             error!("Failed to add available descriptor {}: {}", head_index, err);
@@ -372,12 +697,13 @@ impl Net {
         result
     }
 
-    // Copies a single frame from `self.rx_frame_buf` into the guest. In case of an error retries
-    // the operation if possible. Returns true if the operation was successfull.
-    fn write_frame_to_guest(&mut self) -> bool {
-        let max_iterations = self.queues[RX_INDEX].actual_size();
+    // Copies a single frame from the `self.rx_frame_buf` of the given queue pair into the guest.
+    // In case of an error retries the operation if possible. Returns true if the operation was
+    // successfull.
+    fn write_frame_to_guest(&mut self, pair: usize) -> bool {
+        let max_iterations = self.queues[queue_index(pair, NetQueue::Rx)].actual_size();
         for _ in 0..max_iterations {
-            match self.do_write_frame_to_guest() {
+            match self.do_write_frame_to_guest(pair) {
                 Ok(()) => return true,
                 Err(FrontendError::EmptyQueue) | Err(FrontendError::AddUsed) => {
                     return false;
@@ -392,16 +718,19 @@ impl Net {
         false
     }
 
-    // Tries to detour the frame to MMDS and if MMDS doesn't accept it, sends it on the host TAP.
+    // Tries to detour the frame to MMDS and if MMDS doesn't accept it, sends it to the backend,
+    // either the host TAP or the userspace network stack.
     //
     // `frame_buf` should contain the frame bytes in a slice of exact length.
-    // Returns whether MMDS consumed the frame.
+    // Returns whether MMDS or the userspace network stack consumed the frame, in which case
+    // there may be frames to receive in response.
     fn write_to_mmds_or_tap(
         mmds_ns: Option<&mut MmdsNetworkStack>,
         rate_limiter: &mut RateLimiter,
         frame_buf: &[u8],
-        tap: &mut Tap,
+        backend: Backend,
         guest_mac: Option<MacAddr>,
+        anti_spoofing_filter: Option<&AntiSpoofingFilter>,
     ) -> Result<bool> {
         let checked_frame = |frame_buf| {
             frame_bytes_from_buf(frame_buf).map_err(|err| {
@@ -434,6 +763,22 @@ impl Net {
             });
         }
 
+        if let Some(filter) = anti_spoofing_filter {
+            if !filter.allows(checked_frame(frame_buf)?) {
+                return Ok(false);
+            }
+        }
+
+        let tap = match backend {
+            Backend::Tap(tap) => tap,
+            Backend::UserNs(user_ns) => {
+                user_ns.receive_frame(checked_frame(frame_buf)?);
+                METRICS.net.tx_bytes_count.add(frame_buf.len());
+                METRICS.net.tx_packets_count.inc();
+                METRICS.net.tx_count.inc();
+                return Ok(true);
+            }
+        };
         match tap.write(frame_buf) {
             Ok(_) => {
                 METRICS.net.tx_bytes_count.add(frame_buf.len());
@@ -448,32 +793,53 @@ impl Net {
         Ok(false)
     }
 
-    // We currently prioritize packets from the MMDS over regular network packets.
-    fn read_from_mmds_or_tap(&mut self) -> Result<usize> {
+    // We currently prioritize packets from the MMDS over regular network packets, which come
+    // from either the tap or the userspace network stack.
+    fn read_from_mmds_or_tap(&mut self, pair: usize) -> Result<usize> {
         if let Some(ns) = self.mmds_ns.as_mut() {
             if let Some(len) =
-                ns.write_next_frame(frame_bytes_from_buf_mut(&mut self.rx_frame_buf)?)
+                ns.write_next_frame(frame_bytes_from_buf_mut(&mut self.rx_frame_buf[pair])?)
             {
                 let len = len.get();
                 METRICS.mmds.tx_frames.inc();
                 METRICS.mmds.tx_bytes.add(len);
-                init_vnet_hdr(&mut self.rx_frame_buf);
-                return Ok(vnet_hdr_len() + len);
+                init_vnet_hdr(&mut self.rx_frame_buf[pair]);
+                let len = vnet_hdr_len() + len;
+                capture_frame(&mut self.capture, &self.rx_frame_buf[pair][..len]);
+                return Ok(len);
             }
         }
 
-        self.read_tap().map_err(Error::IO)
+        if let Some(user_ns) = self.user_ns.as_mut() {
+            // Like a non-blocking tap, the stack reports EAGAIN once it has no frame to send.
+            let len = user_ns
+                .write_next_frame(frame_bytes_from_buf_mut(&mut self.rx_frame_buf[pair])?)
+                .ok_or_else(|| Error::IO(io::Error::from_raw_os_error(EAGAIN)))?;
+            init_vnet_hdr(&mut self.rx_frame_buf[pair]);
+            let len = vnet_hdr_len() + len.get();
+            capture_frame(&mut self.capture, &self.rx_frame_buf[pair][..len]);
+            return Ok(len);
+        }
+
+        let len = self.read_tap(pair).map_err(Error::IO)?;
+        capture_frame(&mut self.capture, &self.rx_frame_buf[pair][..len]);
+        Ok(len)
     }
 
-    fn process_rx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_rx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
         // Read as many frames as possible.
         loop {
-            match self.read_from_mmds_or_tap() {
+            match self.read_from_mmds_or_tap(pair) {
                 Ok(count) => {
-                    self.rx_bytes_read = count;
                     METRICS.net.rx_count.inc();
-                    if !self.rate_limited_rx_single_frame() {
-                        self.rx_deferred_frame = true;
+                    // A down link doesn't deliver frames to the guest.
+                    if !self.link_up() {
+                        METRICS.net.rx_link_down_dropped.inc();
+                        continue;
+                    }
+                    self.rx_bytes_read[pair] = count;
+                    if !self.rate_limited_rx_single_frame(pair) {
+                        self.rx_deferred_frame[pair] = true;
                         break;
                     }
                 }
@@ -498,45 +864,47 @@ impl Net {
 
         // At this point we processed as many Rx frames as possible.
         // We have to wake the guest if at least one descriptor chain has been used.
-        self.signal_used_queue(NetQueue::Rx)
+        self.signal_used_queue(queue_index(pair, NetQueue::Rx))
     }
 
     // Process the deferred frame first, then continue reading from tap.
-    fn handle_deferred_frame(&mut self) -> result::Result<(), DeviceError> {
-        if self.rate_limited_rx_single_frame() {
-            self.rx_deferred_frame = false;
+    fn handle_deferred_frame(&mut self, pair: usize) -> result::Result<(), DeviceError> {
+        if self.rate_limited_rx_single_frame(pair) {
+            self.rx_deferred_frame[pair] = false;
             // process_rx() was interrupted possibly before consuming all
             // packets in the tap; try continuing now.
-            return self.process_rx();
+            return self.process_rx(pair);
         }
 
-        self.signal_used_queue(NetQueue::Rx)
+        self.signal_used_queue(queue_index(pair, NetQueue::Rx))
     }
 
-    fn resume_rx(&mut self) -> result::Result<(), DeviceError> {
-        if self.rx_deferred_frame {
-            self.handle_deferred_frame()
+    fn resume_rx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
+        if self.rx_deferred_frame[pair] {
+            self.handle_deferred_frame(pair)
         } else {
             Ok(())
         }
     }
 
-    fn process_tx(&mut self) -> result::Result<(), DeviceError> {
+    fn process_tx(&mut self, pair: usize) -> result::Result<(), DeviceError> {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
 
         // The MMDS network stack works like a state machine, based on synchronous calls, and
         // without being added to any event loop. If any frame is accepted by the MMDS, we also
         // trigger a process_rx() which checks if there are any new frames to be sent, starting
-        // with the MMDS network stack.
-        let mut process_rx_for_mmds = false;
+        // with the MMDS network stack. The same goes for the userspace network stack, whose
+        // answers to the frames of the guest don't wait for the host sockets.
+        let mut process_rx_for_ns = false;
         let mut used_any = false;
-        let tx_queue = &mut self.queues[TX_INDEX];
+        let link_up = self.link_up();
+        let tx_queue = &mut self.queues[queue_index(pair, NetQueue::Tx)];
 
         while let Some(head) = tx_queue.pop_or_enable_notification(mem) {
             // If limiter.consume() fails it means there is no more TokenType::Ops
             // budget and rate limiting is in effect.
-            if !self.tx_rate_limiter.consume(1, TokenType::Ops) {
+            if !self.tx_rate_limiters[pair].consume(1, TokenType::Ops) {
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
                 tx_queue.undo_pop();
@@ -561,12 +929,9 @@ impl Net {
 
             // If limiter.consume() fails it means there is no more TokenType::Bytes
             // budget and rate limiting is in effect.
-            if !self
-                .tx_rate_limiter
-                .consume(read_count as u64, TokenType::Bytes)
-            {
+            if !self.tx_rate_limiters[pair].consume(read_count as u64, TokenType::Bytes) {
                 // revert the OPS consume()
-                self.tx_rate_limiter.manual_replenish(1, TokenType::Ops);
+                self.tx_rate_limiters[pair].manual_replenish(1, TokenType::Ops);
                 // Stop processing the queue and return this descriptor chain to the
                 // avail ring, for later processing.
                 tx_queue.undo_pop();
@@ -603,17 +968,34 @@ impl Net {
                 }
             }
 
-            let frame_consumed_by_mmds = Self::write_to_mmds_or_tap(
+            // Frames for the MMDS are captured as well.
+            capture_frame(&mut self.capture, &self.tx_frame_buf[..read_count]);
+            // A down link doesn't deliver the frames of the guest either.
+            if !link_up {
+                METRICS.net.tx_link_down_dropped.inc();
+                tx_queue
+                    .add_used(mem, head_index, 0)
+                    .map_err(DeviceError::QueueError)?;
+                used_any = true;
+                continue;
+            }
+            let backend = match self.user_ns.as_mut() {
+                Some(user_ns) => Backend::UserNs(user_ns),
+                None => Backend::Tap(&mut self.taps[pair]),
+            };
+            let frame_consumed_by_ns = Self::write_to_mmds_or_tap(
                 self.mmds_ns.as_mut(),
-                &mut self.tx_rate_limiter,
+                &mut self.tx_rate_limiters[pair],
                 &self.tx_frame_buf[..read_count],
-                &mut self.tap,
+                backend,
                 self.guest_mac,
+                self.anti_spoofing_filter.as_ref(),
             )
             .unwrap_or(false);
-            if frame_consumed_by_mmds && !self.rx_deferred_frame {
-                // MMDS consumed this frame/request, let's also try to process the response.
-                process_rx_for_mmds = true;
+            if frame_consumed_by_ns && !self.rx_deferred_frame[pair] {
+                // A network stack consumed this frame/request, let's also try to process the
+                // response.
+                process_rx_for_ns = true;
             }
 
             tx_queue
@@ -626,17 +1008,108 @@ impl Net {
             METRICS.net.no_tx_avail_buffer.inc();
         }
 
-        self.signal_used_queue(NetQueue::Tx)?;
+        self.signal_used_queue(queue_index(pair, NetQueue::Tx))?;
 
-        // An incoming frame for the MMDS may trigger the transmission of a new message.
-        if process_rx_for_mmds {
-            self.process_rx()
+        // An incoming frame for a network stack may trigger the transmission of a new message.
+        if process_rx_for_ns {
+            self.process_rx(pair)
         } else {
             Ok(())
         }
     }
 
-    /// Updates the parameters for the rate limiters
+    // Reads the command from the device readable descriptors of a control queue descriptor
+    // chain. Returns it along with the address of the device writable ack.
+    fn read_ctrl_command(
+        mem: &GuestMemoryMmap,
+        head: DescriptorChain,
+    ) -> std::result::Result<(Vec<u8>, GuestAddress), FrontendError> {
+        let mut command = Vec::with_capacity(CTRL_COMMAND_MAX_LEN);
+        let mut next_descriptor = Some(head);
+
+        while let Some(descriptor) = next_descriptor {
+            if descriptor.is_write_only() {
+                if descriptor.len == 0 {
+                    break;
+                }
+                return Ok((command, descriptor.addr));
+            }
+
+            // Longer commands are not supported, so their extra bytes are not read.
+            let start = command.len();
+            let len = cmp::min(descriptor.len as usize, CTRL_COMMAND_MAX_LEN - start);
+            command.resize(start + len, 0);
+            mem.read_slice(&mut command[start..], descriptor.addr)
+                .map_err(FrontendError::GuestMemory)?;
+
+            next_descriptor = descriptor.next_descriptor();
+        }
+
+        Err(FrontendError::DescriptorChainTooSmall)
+    }
+
+    // Executes a control command. Returns whether it succeeded.
+    fn handle_ctrl_command(&mut self, command: &[u8]) -> bool {
+        match *command {
+            [class, cmd, pairs_lo, pairs_hi]
+                if u32::from(class) == VIRTIO_NET_CTRL_MQ
+                    && u32::from(cmd) == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET =>
+            {
+                let pairs = u16::from_le_bytes([pairs_lo, pairs_hi]);
+                if u32::from(pairs) < VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN {
+                    error!("Net: invalid number of queue pairs: {}", pairs);
+                    return false;
+                }
+                self.set_active_queue_pairs(usize::from(pairs))
+                    .map_err(|err| error!("Failed to set the number of queue pairs: {:?}", err))
+                    .is_ok()
+            }
+            _ => {
+                warn!("Net: unsupported control command: {:?}", command);
+                false
+            }
+        }
+    }
+
+    fn process_ctrl(&mut self) -> result::Result<(), DeviceError> {
+        let ctrl_index = self.ctrl_queue_index();
+
+        // This is safe since we checked in the event handler that the device is activated.
+        while let Some(head) = self.queues[ctrl_index].pop(self.device_state.mem().unwrap()) {
+            let head_index = head.index;
+            let command = Self::read_ctrl_command(self.device_state.mem().unwrap(), head);
+
+            let used_len = match command {
+                Ok((command, ack_addr)) => {
+                    let ack = if self.handle_ctrl_command(&command) {
+                        VIRTIO_NET_OK
+                    } else {
+                        VIRTIO_NET_ERR
+                    };
+                    let mem = self.device_state.mem().unwrap();
+                    match mem.write_obj(ack as u8, ack_addr) {
+                        Ok(()) => 1,
+                        Err(err) => {
+                            error!("Failed to write control command ack: {:?}", err);
+                            0
+                        }
+                    }
+                }
+                Err(_) => {
+                    error!("Net: malformed control queue descriptor chain");
+                    0
+                }
+            };
+
+            self.queues[ctrl_index]
+                .add_used(self.device_state.mem().unwrap(), head_index, used_len)
+                .map_err(DeviceError::QueueError)?;
+        }
+
+        self.signal_used_queue(ctrl_index)
+    }
+
+    /// Updates the parameters for the rate limiters of all the queue pairs
     pub fn patch_rate_limiters(
         &mut self,
         rx_bytes: BucketUpdate,
@@ -644,31 +1117,35 @@ impl Net {
         tx_bytes: BucketUpdate,
         tx_ops: BucketUpdate,
     ) {
-        self.rx_rate_limiter.update_buckets(rx_bytes, rx_ops);
-        self.tx_rate_limiter.update_buckets(tx_bytes, tx_ops);
+        for rx_rate_limiter in self.rx_rate_limiters.iter_mut() {
+            rx_rate_limiter.update_buckets(rx_bytes.clone(), rx_ops.clone());
+        }
+        for tx_rate_limiter in self.tx_rate_limiters.iter_mut() {
+            tx_rate_limiter.update_buckets(tx_bytes.clone(), tx_ops.clone());
+        }
     }
 
     #[cfg(not(test))]
-    fn read_tap(&mut self) -> std::io::Result<usize> {
-        self.tap.read(&mut self.rx_frame_buf)
+    fn read_tap(&mut self, pair: usize) -> std::io::Result<usize> {
+        self.taps[pair].read(&mut self.rx_frame_buf[pair])
     }
 
-    pub fn process_rx_queue_event(&mut self) {
+    pub fn process_rx_queue_event(&mut self, pair: usize) {
         METRICS.net.rx_queue_event_count.inc();
 
-        if let Err(err) = self.queue_evts[RX_INDEX].read() {
+        if let Err(err) = self.queue_evts[queue_index(pair, NetQueue::Rx)].read() {
             // rate limiters present but with _very high_ allowed rate
             error!("Failed to get rx queue event: {:?}", err);
             METRICS.net.event_fails.inc();
-        } else if self.rx_rate_limiter.is_blocked() {
+        } else if self.rx_rate_limiters[pair].is_blocked() {
             METRICS.net.rx_rate_limiter_throttled.inc();
         } else {
             // If the limiter is not blocked, resume the receiving of bytes.
-            self.resume_rx().unwrap_or_else(report_net_event_fail);
+            self.resume_rx(pair).unwrap_or_else(report_net_event_fail);
         }
     }
 
-    pub fn process_tap_rx_event(&mut self) {
+    pub fn process_tap_rx_event(&mut self, pair: usize) {
         // This is safe since we checked in the event handler that the device is activated.
         let mem = self.device_state.mem().unwrap();
         METRICS.net.rx_tap_event_count.inc();
@@ -677,51 +1154,83 @@ impl Net {
         // don't process any more incoming. Otherwise start processing a frame. In the
         // process the deferred_frame flag will be set in order to avoid freezing the
         // RX queue.
-        if self.queues[RX_INDEX].is_empty(mem) && self.rx_deferred_frame {
+        if self.queues[queue_index(pair, NetQueue::Rx)].is_empty(mem)
+            && self.rx_deferred_frame[pair]
+        {
             METRICS.net.no_rx_avail_buffer.inc();
             return;
         }
 
         // While limiter is blocked, don't process any more incoming.
-        if self.rx_rate_limiter.is_blocked() {
+        if self.rx_rate_limiters[pair].is_blocked() {
             METRICS.net.rx_rate_limiter_throttled.inc();
             return;
         }
 
-        if self.rx_deferred_frame
+        if self.rx_deferred_frame[pair]
         // Process a deferred frame first if available. Don't read from tap again
         // until we manage to receive this deferred frame.
         {
-            self.handle_deferred_frame()
+            self.handle_deferred_frame(pair)
                 .unwrap_or_else(report_net_event_fail);
         } else {
-            self.process_rx().unwrap_or_else(report_net_event_fail);
+            self.process_rx(pair).unwrap_or_else(report_net_event_fail);
+        }
+    }
+
+    /// Handles the host sockets of the userspace network stack, and receives the frames they
+    /// produced.
+    pub fn process_user_ns_event(&mut self) {
+        if let Some(user_ns) = self.user_ns.as_mut() {
+            user_ns.process_host_events();
         }
+        self.process_tap_rx_event(0);
     }
 
-    pub fn process_tx_queue_event(&mut self) {
+    pub fn process_tx_queue_event(&mut self, pair: usize) {
         METRICS.net.tx_queue_event_count.inc();
-        if let Err(err) = self.queue_evts[TX_INDEX].read() {
+        if let Err(err) = self.queue_evts[queue_index(pair, NetQueue::Tx)].read() {
             error!("Failed to get tx queue event: {:?}", err);
             METRICS.net.event_fails.inc();
-        } else if !self.tx_rate_limiter.is_blocked()
+        } else if !self.tx_rate_limiters[pair].is_blocked()
         // If the limiter is not blocked, continue transmitting bytes.
         {
-            self.process_tx().unwrap_or_else(report_net_event_fail);
+            self.process_tx(pair).unwrap_or_else(report_net_event_fail);
         } else {
             METRICS.net.tx_rate_limiter_throttled.inc();
         }
     }
 
-    pub fn process_rx_rate_limiter_event(&mut self) {
+    /// Writes the frames buffered by the packet capture to its file.
+    pub fn process_capture_timer_event(&mut self) {
+        // Reading the timer acknowledges its expirations.
+        self.capture_timer.read();
+        flush_capture(&mut self.capture);
+        // The capture may have ended since the last expiration.
+        if self.capture.is_none() {
+            self.capture_timer
+                .set_state(TimerState::Disarmed, SetTimeFlags::Default);
+        }
+    }
+
+    pub fn process_ctrl_queue_event(&mut self) {
+        if let Err(err) = self.queue_evts[self.ctrl_queue_index()].read() {
+            error!("Failed to get ctrl queue event: {:?}", err);
+            METRICS.net.event_fails.inc();
+        } else {
+            self.process_ctrl().unwrap_or_else(report_net_event_fail);
+        }
+    }
+
+    pub fn process_rx_rate_limiter_event(&mut self, pair: usize) {
         METRICS.net.rx_event_rate_limiter_count.inc();
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
 
-        match self.rx_rate_limiter.event_handler() {
+        match self.rx_rate_limiters[pair].event_handler() {
             Ok(_) => {
-                // There might be enough budget now to receive the frame.
-                self.resume_rx().unwrap_or_else(report_net_event_fail);
+                // There might be enough budget now to receive the deferred frame.
+                self.resume_rx(pair).unwrap_or_else(report_net_event_fail);
             }
             Err(err) => {
                 error!("Failed to get rx rate-limiter event: {:?}", err);
@@ -730,14 +1239,14 @@ impl Net {
         }
     }
 
-    pub fn process_tx_rate_limiter_event(&mut self) {
+    pub fn process_tx_rate_limiter_event(&mut self, pair: usize) {
         METRICS.net.tx_rate_limiter_event_count.inc();
         // Upon rate limiter event, call the rate limiter handler
         // and restart processing the queue.
-        match self.tx_rate_limiter.event_handler() {
+        match self.tx_rate_limiters[pair].event_handler() {
             Ok(_) => {
-                // There might be enough budget now to send the frame.
-                self.process_tx().unwrap_or_else(report_net_event_fail);
+                // There might be enough budget now to send the frames.
+                self.process_tx(pair).unwrap_or_else(report_net_event_fail);
             }
             Err(err) => {
                 error!("Failed to get tx rate-limiter event: {:?}", err);
@@ -748,8 +1257,10 @@ impl Net {
 
     /// Process device virtio queue(s).
     pub fn process_virtio_queues(&mut self) {
-        let _ = self.resume_rx();
-        let _ = self.process_tx();
+        for pair in 0..self.active_queue_pairs {
+            let _ = self.resume_rx(pair);
+            let _ = self.process_tx(pair);
+        }
     }
 }
 
@@ -810,7 +1321,8 @@ impl VirtioDevice for Net {
     fn write_config(&mut self, offset: u64, data: &[u8]) {
         let data_len = data.len() as u64;
         let config_space_bytes = self.config_space.as_mut_slice();
-        let config_len = config_space_bytes.len() as u64;
+        // Only the MAC address is writable by the driver.
+        let config_len = mem::size_of::<MacAddr>() as u64;
         if offset + data_len > config_len {
             error!("Failed to write config space");
             METRICS.net.cfg_fails.inc();
@@ -834,6 +1346,13 @@ impl VirtioDevice for Net {
             }
         }
 
+        // The driver only uses the first queue pair until it selects more through the control
+        // queue.
+        if let Err(err) = self.set_active_queue_pairs(1) {
+            error!("Net: Cannot detach the tap queues: {:?}", err);
+            return Err(super::super::ActivateError::BadActivate);
+        }
+
         if self.activate_evt.write(1).is_err() {
             error!("Net: Cannot write to activate_evt");
             return Err(super::super::ActivateError::BadActivate);
@@ -847,6 +1366,8 @@ impl VirtioDevice for Net {
 #[macro_use]
 pub mod tests {
     use std::net::Ipv4Addr;
+    use std::os::unix::io::AsRawFd;
+    use std::sync::atomic::Ordering;
     use std::time::Duration;
     use std::{io, mem, thread};
 
@@ -855,10 +1376,11 @@ pub mod tests {
     use logger::{IncMetric, METRICS};
     use rate_limiter::{RateLimiter, TokenBucket, TokenType};
     use utils::net::mac::MAC_ADDR_LEN;
+    use utils::tempfile::TempFile;
     use virtio_gen::virtio_net::{
         virtio_net_hdr_v1, VIRTIO_F_VERSION_1, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_GUEST_CSUM,
         VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4,
-        VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC,
+        VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MTU, VIRTIO_NET_F_STATUS,
     };
     use vm_memory::{Address, GuestMemory};
 
@@ -869,26 +1391,28 @@ pub mod tests {
     };
     use crate::virtio::net::test_utils::test::TestHelper;
     use crate::virtio::net::test_utils::{
-        default_net, if_index, inject_tap_tx_frame, set_mac, NetEvent, NetQueue, ReadTapMock,
-        TapTrafficSimulator,
+        default_guest_memory, default_net, default_net_with_queue_pairs, if_index,
+        inject_tap_tx_frame, set_mac, NetEvent, NetQueue, ReadTapMock, TapTrafficSimulator,
     };
-    use crate::virtio::net::QUEUE_SIZES;
+    use crate::virtio::net::user::GATEWAY_ADDR;
+    use crate::virtio::test_utils::VirtQueue;
     use crate::virtio::{
-        Net, VirtioDevice, MAX_BUFFER_SIZE, RX_INDEX, TX_INDEX, TYPE_NET, VIRTQ_DESC_F_WRITE,
+        Net, VirtioDevice, MAX_BUFFER_SIZE, RX_INDEX, TX_INDEX, TYPE_NET, VIRTQ_DESC_F_NEXT,
+        VIRTQ_DESC_F_WRITE,
     };
 
     impl Net {
-        pub fn read_tap(&mut self) -> io::Result<usize> {
+        pub fn read_tap(&mut self, pair: usize) -> io::Result<usize> {
             match &self.mocks.read_tap {
                 ReadTapMock::MockFrame(frame) => {
-                    self.rx_frame_buf[..frame.len()].copy_from_slice(frame);
+                    self.rx_frame_buf[pair][..frame.len()].copy_from_slice(frame);
                     Ok(frame.len())
                 }
                 ReadTapMock::Failure => Err(io::Error::new(
                     io::ErrorKind::Other,
                     "Read tap synthetically failed.",
                 )),
-                ReadTapMock::TapFrame => self.tap.read(&mut self.rx_frame_buf),
+                ReadTapMock::TapFrame => self.taps[pair].read(&mut self.rx_frame_buf[pair]),
             }
         }
     }
@@ -944,6 +1468,7 @@ pub mod tests {
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_STATUS
             | 1 << VIRTIO_F_VERSION_1
             | 1 << VIRTIO_RING_F_EVENT_IDX;
 
@@ -973,7 +1498,7 @@ pub mod tests {
 
         // Invalid read.
         config_mac = [0u8; MAC_ADDR_LEN];
-        net.read_config(MAC_ADDR_LEN as u64, &mut config_mac);
+        net.read_config(mem::size_of::<ConfigSpace>() as u64, &mut config_mac);
         assert_eq!(config_mac, [0u8, 0u8, 0u8, 0u8, 0u8, 0u8]);
     }
 
@@ -1009,6 +1534,222 @@ pub mod tests {
         assert_eq!(new_config, new_config_read);
     }
 
+    #[test]
+    fn test_multi_queue_config() {
+        let net = default_net_with_queue_pairs(2);
+
+        // The control queue follows the two queue pairs.
+        assert_eq!(net.num_queue_pairs(), 2);
+        assert_eq!(net.queues().len(), 5);
+        assert_eq!(net.queue_events().len(), 5);
+        assert_eq!(net.ctrl_queue_index(), 4);
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+
+        // The maximum number of queue pairs follows the MAC and the status.
+        let mut max_virtqueue_pairs = [0u8; 2];
+        net.read_config(8, &mut max_virtqueue_pairs);
+        assert_eq!(u16::from_le_bytes(max_virtqueue_pairs), 2);
+
+        // A single queue pair doesn't need a control queue.
+        let net = default_net();
+        assert_eq!(net.queues().len(), 2);
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_MQ), 0);
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_CTRL_VQ), 0);
+
+        for num_queue_pairs in [0, MAX_NUM_QUEUE_PAIRS + 1] {
+            assert!(matches!(
+                Net::new_with_tap(
+                    "net-device".to_string(),
+                    "net-device".to_string(),
+                    None,
+                    RateLimiter::default(),
+                    RateLimiter::default(),
+                    num_queue_pairs,
+                    None,
+                ),
+                Err(Error::InvalidNumQueuePairs(n)) if n == num_queue_pairs
+            ));
+        }
+    }
+
+    #[test]
+    fn test_queue_pair_rate_limiters() {
+        let mut net = default_net_with_queue_pairs(2);
+        assert_eq!(net.rx_rate_limiters.len(), 2);
+        assert_eq!(net.tx_rate_limiters.len(), 2);
+        assert_ne!(
+            net.rx_rate_limiters[0].as_raw_fd(),
+            net.rx_rate_limiters[1].as_raw_fd()
+        );
+
+        // The rate limiters of all the queue pairs are updated.
+        net.patch_rate_limiters(
+            BucketUpdate::Update(TokenBucket::new(1000, 0, 1000).unwrap()),
+            BucketUpdate::None,
+            BucketUpdate::None,
+            BucketUpdate::Update(TokenBucket::new(1, 0, 1000).unwrap()),
+        );
+        for pair in 0..2 {
+            assert_eq!(
+                net.rx_rate_limiters[pair].bandwidth().unwrap().capacity(),
+                1000
+            );
+            assert_eq!(net.tx_rate_limiters[pair].ops().unwrap().capacity(), 1);
+        }
+
+        // The traffic of each queue pair is accounted on its own.
+        assert!(net.tx_rate_limiters[0].consume(1, TokenType::Ops));
+        assert!(!net.tx_rate_limiters[0].consume(1, TokenType::Ops));
+        assert!(net.tx_rate_limiters[1].consume(1, TokenType::Ops));
+    }
+
+    #[test]
+    fn test_link_state_and_mtu() {
+        let mut th = TestHelper::default();
+
+        // The link is reported as up, and no MTU is advertised by default.
+        let mut status = [0u8; 2];
+        th.net().read_config(MAC_ADDR_LEN as u64, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP as u16);
+        assert!(th.net().link_up());
+        assert_eq!(th.net().mtu(), None);
+        assert_eq!(th.net().avail_features() & (1 << VIRTIO_NET_F_MTU), 0);
+
+        // The driver is not notified before activation.
+        th.net().set_link_up(false);
+        assert!(!th.net().link_up());
+        assert!(!th.net().irq_trigger.has_pending_irq(IrqType::Config));
+
+        th.activate_net();
+        th.net().set_link_up(true);
+        th.net().read_config(MAC_ADDR_LEN as u64, &mut status);
+        assert_eq!(u16::from_le_bytes(status), VIRTIO_NET_S_LINK_UP as u16);
+        assert!(th.net().irq_trigger.has_pending_irq(IrqType::Config));
+
+        // Setting the same link state doesn't notify the driver again.
+        th.net().irq_trigger.irq_status.store(0, Ordering::SeqCst);
+        th.net().set_link_up(true);
+        assert!(!th.net().irq_trigger.has_pending_irq(IrqType::Config));
+
+        // The MTU is advertised in the config space, after the maximum number of queue pairs.
+        let net = Net::new_with_tap(
+            "net-device".to_string(),
+            "net-device".to_string(),
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+            1,
+            Some(9000),
+        )
+        .unwrap();
+        let mut mtu = [0u8; 2];
+        net.read_config(10, &mut mtu);
+        assert_eq!(u16::from_le_bytes(mtu), 9000);
+        assert_eq!(net.mtu(), Some(9000));
+        assert_ne!(net.avail_features() & (1 << VIRTIO_NET_F_MTU), 0);
+
+        assert!(matches!(
+            Net::new_with_tap(
+                "net-device".to_string(),
+                "net-device".to_string(),
+                None,
+                RateLimiter::default(),
+                RateLimiter::default(),
+                1,
+                Some(MIN_MTU - 1),
+            ),
+            Err(Error::InvalidMtu(mtu)) if mtu == MIN_MTU - 1
+        ));
+    }
+
+    #[test]
+    fn test_link_down() {
+        let mut th = TestHelper::default();
+        th.activate_net();
+        th.net().set_link_up(false);
+        th.net().mocks.set_read_tap(ReadTapMock::TapFrame);
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().taps[0]));
+
+        // The frames received from the tap are dropped.
+        th.add_desc_chain(NetQueue::Rx, 0, &[(0, 1000, VIRTQ_DESC_F_WRITE)]);
+        inject_tap_tx_frame(&th.net(), 200);
+        check_metric_after_block!(
+            METRICS.net.rx_link_down_dropped,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        assert_eq!(th.rxq.used.idx.get(), 0);
+
+        // The frames of the guest are consumed without reaching the tap.
+        let desc_list = [(0, 100, 0)];
+        th.add_desc_chain(NetQueue::Tx, 1000, &desc_list);
+        th.write_tx_frame(&desc_list, 100);
+        check_metric_after_block!(
+            METRICS.net.tx_link_down_dropped,
+            1,
+            th.event_manager.run_with_timeout(100).unwrap()
+        );
+        assert_eq!(th.txq.used.idx.get(), 1);
+        th.txq.check_used_elem(0, 0, 0);
+        assert!(!tap_traffic_simulator.pop_rx_packet(&mut [0; 1000]));
+    }
+
+    #[test]
+    fn test_ctrl_queue() {
+        let mem = default_guest_memory();
+        let ctrlq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let command_addr = ctrlq.end().raw_value();
+        let ack_addr = command_addr + 0x100;
+
+        let mut net = default_net_with_queue_pairs(2);
+        let ctrl_index = net.ctrl_queue_index();
+        net.queues[ctrl_index] = ctrlq.create_queue();
+        net.activate(mem.clone()).unwrap();
+        // The driver starts with a single queue pair.
+        assert_eq!(net.active_queue_pairs, 1);
+
+        let send_command = |net: &mut Net, command: &[u8]| {
+            let used_idx = ctrlq.used.idx.get();
+            mem.write_slice(command, GuestAddress(command_addr))
+                .unwrap();
+            ctrlq.dtable[0].set(command_addr, command.len() as u32, VIRTQ_DESC_F_NEXT, 1);
+            ctrlq.dtable[1].set(ack_addr, 1, VIRTQ_DESC_F_WRITE, 0);
+            ctrlq.avail.ring[used_idx as usize].set(0);
+            ctrlq.avail.idx.set(used_idx + 1);
+
+            net.queue_evts[ctrl_index].write(1).unwrap();
+            net.process_ctrl_queue_event();
+            ctrlq.check_used_elem(used_idx, 0, 1);
+            u32::from(mem.read_obj::<u8>(GuestAddress(ack_addr)).unwrap())
+        };
+        let set_pairs = |pairs: u16| {
+            let [pairs_lo, pairs_hi] = pairs.to_le_bytes();
+            [
+                VIRTIO_NET_CTRL_MQ as u8,
+                VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8,
+                pairs_lo,
+                pairs_hi,
+            ]
+        };
+
+        assert_eq!(send_command(&mut net, &set_pairs(2)), VIRTIO_NET_OK);
+        assert_eq!(net.active_queue_pairs, 2);
+
+        // The number of queue pairs must be between 1 and the maximum.
+        assert_eq!(send_command(&mut net, &set_pairs(3)), VIRTIO_NET_ERR);
+        assert_eq!(send_command(&mut net, &set_pairs(0)), VIRTIO_NET_ERR);
+        assert_eq!(net.active_queue_pairs, 2);
+
+        // Other control commands are not supported.
+        assert_eq!(send_command(&mut net, &[0, 0, 1]), VIRTIO_NET_ERR);
+
+        // Going back to a single queue pair detaches the tap queue of the second one.
+        assert_eq!(send_command(&mut net, &set_pairs(1)), VIRTIO_NET_OK);
+        assert_eq!(net.active_queue_pairs, 1);
+        assert!(net.taps[1].set_queue_enabled(false).is_err());
+    }
+
     #[test]
     fn test_rx_missing_queue_signal() {
         let mut th = TestHelper::default();
@@ -1125,7 +1866,7 @@ pub mod tests {
         th.rxq.check_used_elem(1, 3, 0);
         th.rxq.check_used_elem(2, 4, 0);
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().rx_deferred_frame[0]);
         // Check that the frame has been written successfully to the valid Rx descriptor chain.
         th.rxq.check_used_elem(3, 5, frame.len() as u32);
         th.rxq.dtable[5].check_data(&frame);
@@ -1158,7 +1899,7 @@ pub mod tests {
         );
 
         // Check that the frame wasn't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().rx_deferred_frame[0]);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 1);
         assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1197,7 +1938,7 @@ pub mod tests {
         );
 
         // Check that the frames weren't deferred.
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().rx_deferred_frame[0]);
         // Check that the used queue has advanced.
         assert_eq!(th.rxq.used.idx.get(), 2);
         assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
@@ -1215,7 +1956,7 @@ pub mod tests {
     fn test_tx_missing_queue_signal() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().taps[0]));
 
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 4096, 0)]);
         th.net().queue_evts[TX_INDEX].read().unwrap();
@@ -1235,7 +1976,7 @@ pub mod tests {
     fn test_tx_writeable_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().taps[0]));
 
         let desc_list = [(0, 100, 0), (1, 100, VIRTQ_DESC_F_WRITE), (2, 500, 0)];
         th.add_desc_chain(NetQueue::Tx, 0, &desc_list);
@@ -1254,7 +1995,7 @@ pub mod tests {
     fn test_tx_short_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().taps[0]));
 
         // Send an invalid frame (too small, VNET header missing).
         th.add_desc_chain(NetQueue::Tx, 0, &[(0, 1, 0)]);
@@ -1276,7 +2017,7 @@ pub mod tests {
     fn test_tx_partial_read() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().taps[0]));
 
         // The descriptor chain is created so that the last descriptor doesn't fit in the
         // guest memory.
@@ -1304,7 +2045,7 @@ pub mod tests {
     fn test_tx_retry() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().taps[0]));
 
         // Add invalid descriptor chain - writeable descriptor.
         th.add_desc_chain(
@@ -1344,7 +2085,7 @@ pub mod tests {
     fn test_tx_complex_descriptor() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().taps[0]));
 
         // Add gaps between the descriptor ids in order to ensure that we follow
         // the `next` field.
@@ -1372,7 +2113,7 @@ pub mod tests {
     fn test_tx_multiple_frame() {
         let mut th = TestHelper::default();
         th.activate_net();
-        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().tap));
+        let tap_traffic_simulator = TapTrafficSimulator::new(if_index(&th.net().taps[0]));
 
         // Write the first frame to the Tx queue
         let desc_list = [(0, 50, 0), (1, 100, 0), (2, 150, 0)];
@@ -1454,10 +2195,11 @@ pub mod tests {
             1,
             assert!(Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
+                &mut net.tx_rate_limiters[0],
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                Backend::Tap(&mut net.taps[0]),
                 Some(src_mac),
+                None,
             )
             .unwrap())
         );
@@ -1466,10 +2208,80 @@ pub mod tests {
         check_metric_after_block!(
             &METRICS.mmds.tx_frames,
             1,
-            net.read_from_mmds_or_tap().unwrap()
+            net.read_from_mmds_or_tap(0).unwrap()
         );
     }
 
+    #[test]
+    fn test_packet_capture() {
+        let file = TempFile::new().unwrap();
+        let mut net = default_net();
+        net.set_capture(Some(
+            PacketCapture::new(file.as_path(), None, Some(2)).unwrap(),
+        ));
+        assert!(net.is_capturing());
+        assert!(matches!(
+            net.capture_timer.get_state(),
+            TimerState::Periodic { .. }
+        ));
+
+        let src_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
+        let src_ip = Ipv4Addr::new(10, 1, 2, 3);
+        let dst_mac = MacAddr::parse_str("22:22:22:22:22:22").unwrap();
+        let dst_ip = Ipv4Addr::new(169, 254, 169, 254);
+        let (frame_buf, frame_len) = create_arp_request(src_mac, src_ip, dst_mac, dst_ip);
+        Net::write_to_mmds_or_tap(
+            net.mmds_ns.as_mut(),
+            &mut net.tx_rate_limiters[0],
+            &frame_buf[..frame_len],
+            Backend::Tap(&mut net.taps[0]),
+            Some(src_mac),
+            None,
+        )
+        .unwrap();
+
+        // The frames answered by the MMDS are captured.
+        let captured = METRICS.net.capture_packets_count.count();
+        let len = net.read_from_mmds_or_tap(0).unwrap();
+        assert_eq!(METRICS.net.capture_packets_count.count(), captured + 1);
+
+        // The captured frames reach the file when the capture timer expires.
+        let file_len = || std::fs::metadata(file.as_path()).unwrap().len() as usize;
+        assert_eq!(file_len(), 24);
+        net.process_capture_timer_event();
+        assert_eq!(file_len(), 24 + 16 + len - vnet_hdr_len());
+
+        // So are the frames read from the tap, until the capture limit is reached.
+        net.mocks
+            .set_read_tap(ReadTapMock::MockFrame(frame_buf[..frame_len].to_vec()));
+        check_metric_after_block!(
+            &METRICS.net.capture_packets_count,
+            1,
+            net.read_from_mmds_or_tap(0).unwrap()
+        );
+        assert!(net.is_capturing());
+        check_metric_after_block!(
+            &METRICS.net.capture_packets_count,
+            0,
+            net.read_from_mmds_or_tap(0).unwrap()
+        );
+        assert!(!net.is_capturing());
+
+        // The pcap header, followed by a record header and the frame without its VNET header,
+        // for each of the two frames.
+        assert_eq!(
+            file_len(),
+            24 + 2 * 16 + (len - vnet_hdr_len()) + (frame_len - vnet_hdr_len())
+        );
+
+        // The timer stops once the capture ended.
+        net.process_capture_timer_event();
+        assert!(matches!(
+            net.capture_timer.get_state(),
+            TimerState::Disarmed
+        ));
+    }
+
     #[test]
     fn test_mac_spoofing_detection() {
         let mut net = default_net();
@@ -1488,10 +2300,11 @@ pub mod tests {
             0,
             Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
+                &mut net.tx_rate_limiters[0],
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                Backend::Tap(&mut net.taps[0]),
                 Some(guest_mac),
+                None,
             )
         );
 
@@ -1501,12 +2314,130 @@ pub mod tests {
             1,
             Net::write_to_mmds_or_tap(
                 net.mmds_ns.as_mut(),
-                &mut net.tx_rate_limiter,
+                &mut net.tx_rate_limiters[0],
+                &frame_buf[..frame_len],
+                Backend::Tap(&mut net.taps[0]),
+                Some(not_guest_mac),
+                None,
+            )
+        );
+    }
+
+    #[test]
+    fn test_anti_spoofing_filter() {
+        let mut net = default_net();
+        assert!(net.anti_spoofing_filter().is_none());
+
+        let guest_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
+        let not_guest_mac = MacAddr::parse_str("33:33:33:33:33:33").unwrap();
+        let guest_ip = Ipv4Addr::new(10, 1, 2, 3);
+        let not_guest_ip = Ipv4Addr::new(10, 1, 2, 4);
+        let dst_mac = MacAddr::parse_str("22:22:22:22:22:22").unwrap();
+        let dst_ip = Ipv4Addr::new(10, 1, 1, 1);
+        net.set_anti_spoofing_filter(Some(AntiSpoofingFilter::new(guest_mac, vec![guest_ip])));
+        assert_eq!(
+            net.anti_spoofing_filter().unwrap().ipv4_allowlist(),
+            &[guest_ip]
+        );
+
+        // Frames from the guest addresses are not dropped.
+        let (frame_buf, frame_len) = create_arp_request(guest_mac, guest_ip, dst_mac, dst_ip);
+        check_metric_after_block!(
+            &METRICS.net.tx_spoofed_ip_dropped,
+            0,
+            Net::write_to_mmds_or_tap(
+                net.mmds_ns.as_mut(),
+                &mut net.tx_rate_limiters[0],
+                &frame_buf[..frame_len],
+                Backend::Tap(&mut net.taps[0]),
+                Some(guest_mac),
+                net.anti_spoofing_filter.as_ref(),
+            )
+        );
+
+        // Frames with a spoofed source MAC are dropped, even after the guest changed its MAC.
+        let (frame_buf, frame_len) = create_arp_request(not_guest_mac, guest_ip, dst_mac, dst_ip);
+        check_metric_after_block!(
+            &METRICS.net.tx_spoofed_mac_dropped,
+            1,
+            Net::write_to_mmds_or_tap(
+                net.mmds_ns.as_mut(),
+                &mut net.tx_rate_limiters[0],
                 &frame_buf[..frame_len],
-                &mut net.tap,
+                Backend::Tap(&mut net.taps[0]),
                 Some(not_guest_mac),
+                net.anti_spoofing_filter.as_ref(),
             )
         );
+
+        // Frames with a spoofed sender IPv4 address are dropped.
+        let (frame_buf, frame_len) = create_arp_request(guest_mac, not_guest_ip, dst_mac, dst_ip);
+        check_metric_after_block!(
+            &METRICS.net.tx_spoofed_ip_dropped,
+            1,
+            Net::write_to_mmds_or_tap(
+                net.mmds_ns.as_mut(),
+                &mut net.tx_rate_limiters[0],
+                &frame_buf[..frame_len],
+                Backend::Tap(&mut net.taps[0]),
+                Some(guest_mac),
+                net.anti_spoofing_filter.as_ref(),
+            )
+        );
+    }
+
+    #[test]
+    fn test_user_ns_backend() {
+        let guest_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
+        let mut net = Net::new_with_user_ns(
+            "user0".to_string(),
+            Some(guest_mac),
+            RateLimiter::default(),
+            RateLimiter::default(),
+            None,
+            UserNetworkConfig::default(),
+        )
+        .unwrap();
+        assert!(net.user_ns().is_some());
+        assert_eq!(net.iface_name(), "");
+        assert_eq!(net.num_queue_pairs(), 1);
+        // The stack doesn't take partially checksummed nor segmentation offloaded frames.
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_CSUM), 0);
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_HOST_TSO4), 0);
+        assert_eq!(net.avail_features() & (1 << VIRTIO_NET_F_HOST_UFO), 0);
+
+        // The frames of the guest are handed to the stack, which answers the ARP requests for
+        // the gateway.
+        let guest_ip = Ipv4Addr::new(10, 0, 2, 15);
+        let broadcast_mac = MacAddr::parse_str("ff:ff:ff:ff:ff:ff").unwrap();
+        let (frame_buf, frame_len) =
+            create_arp_request(guest_mac, guest_ip, broadcast_mac, GATEWAY_ADDR);
+        assert!(Net::write_to_mmds_or_tap(
+            None,
+            &mut net.tx_rate_limiters[0],
+            &frame_buf[..frame_len],
+            Backend::UserNs(net.user_ns.as_mut().unwrap()),
+            Some(guest_mac),
+            None,
+        )
+        .unwrap());
+
+        let len = net.read_from_mmds_or_tap(0).unwrap();
+        let reply = EthIPv4ArpFrame::from_bytes(
+            EthernetFrame::from_bytes(&net.rx_frame_buf[0][vnet_hdr_len()..len])
+                .unwrap()
+                .payload(),
+        )
+        .unwrap();
+        assert_eq!(reply.spa(), GATEWAY_ADDR);
+        assert_eq!(reply.tha(), guest_mac);
+        assert_eq!(reply.tpa(), guest_ip);
+
+        // Once the stack has nothing more to send, it reports EAGAIN like a tap does.
+        match net.read_from_mmds_or_tap(0) {
+            Err(Error::IO(err)) => assert_eq!(err.raw_os_error(), Some(EAGAIN)),
+            _ => panic!("Expected EAGAIN."),
+        }
     }
 
     #[test]
@@ -1541,7 +2472,7 @@ pub mod tests {
         th.net().mocks.set_read_tap(ReadTapMock::Failure);
 
         // The RX queue is empty and rx_deffered_frame is set.
-        th.net().rx_deferred_frame = true;
+        th.net().rx_deferred_frame[0] = true;
         check_metric_after_block!(
             &METRICS.net.no_rx_avail_buffer,
             1,
@@ -1551,7 +2482,7 @@ pub mod tests {
         // We need to set this here to false, otherwise the device will try to
         // handle a deferred frame, it will fail and will never try to read from
         // the tap.
-        th.net().rx_deferred_frame = false;
+        th.net().rx_deferred_frame[0] = false;
 
         // Fake an avail buffer; this time, tap reading should error out.
         th.rxq.avail.idx.set(1);
@@ -1579,7 +2510,7 @@ pub mod tests {
         );
         // The frame we read from the tap should be deferred now and
         // no frames should have been transmitted
-        assert!(th.net().rx_deferred_frame);
+        assert!(th.net().rx_deferred_frame[0]);
         assert_eq!(METRICS.net.rx_packets_count.count(), rx_packets_count);
 
         // Let's add a second frame, which should really have the same
@@ -1596,7 +2527,7 @@ pub mod tests {
             th.simulate_event(NetEvent::Tap)
         );
         // We should still have a deferred frame
-        assert!(th.net().rx_deferred_frame);
+        assert!(th.net().rx_deferred_frame[0]);
         // However, we should have delivered the first frame
         assert_eq!(METRICS.net.rx_packets_count.count(), rx_packets_count + 1);
 
@@ -1609,7 +2540,7 @@ pub mod tests {
         );
 
         // We should be done with any deferred frame
-        assert!(!th.net().rx_deferred_frame);
+        assert!(!th.net().rx_deferred_frame[0]);
     }
 
     #[test]
@@ -1617,7 +2548,7 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().rx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
+        th.net().rx_rate_limiters[0] = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
         // There is no actual event on the rate limiter's timerfd.
         check_metric_after_block!(
             &METRICS.net.event_fails,
@@ -1631,7 +2562,7 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().tx_rate_limiter = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
+        th.net().tx_rate_limiters[0] = RateLimiter::new(0, 0, 0, 0, 0, 0).unwrap();
         th.simulate_event(NetEvent::TxRateLimiter);
         // There is no actual event on the rate limiter's timerfd.
         check_metric_after_block!(
@@ -1654,7 +2585,7 @@ pub mod tests {
             assert!(rl.consume(0x1000, TokenType::Bytes));
 
             // set this tx rate limiter to be used
-            th.net().tx_rate_limiter = rl;
+            th.net().tx_rate_limiters[0] = rl;
 
             // try doing TX
             // following TX procedure should fail because of bandwidth rate limiting
@@ -1664,7 +2595,7 @@ pub mod tests {
                 th.simulate_event(NetEvent::TxQueue);
 
                 // assert that limiter is blocked
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().tx_rate_limiters[0].is_blocked());
                 assert_eq!(METRICS.net.tx_rate_limiter_throttled.count(), 1);
                 // make sure the data is still queued for processing
                 assert_eq!(th.txq.used.idx.get(), 0);
@@ -1693,7 +2624,7 @@ pub mod tests {
                 );
                 // This should be still blocked. We managed to send the first frame, but
                 // not enough budget for the second
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().tx_rate_limiters[0].is_blocked());
                 // make sure the data queue advanced
                 assert_eq!(th.txq.used.idx.get(), 1);
             }
@@ -1709,7 +2640,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::TxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().tx_rate_limiter.is_blocked());
+                assert!(!th.net().tx_rate_limiters[0].is_blocked());
                 // make sure the data queue advance one more place
                 assert_eq!(th.txq.used.idx.get(), 2);
             }
@@ -1723,10 +2654,10 @@ pub mod tests {
             assert!(rl.consume(0x1000, TokenType::Bytes));
 
             // set this rx rate limiter to be used
-            th.net().rx_rate_limiter = rl;
+            th.net().rx_rate_limiters[0] = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().rx_deferred_frame[0]);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of bandwidth rate limiting
@@ -1735,9 +2666,9 @@ pub mod tests {
                 th.simulate_event(NetEvent::Tap);
 
                 // assert that limiter is blocked
-                assert!(th.net().rx_rate_limiter.is_blocked());
+                assert!(th.net().rx_rate_limiters[0].is_blocked());
                 assert_eq!(METRICS.net.rx_rate_limiter_throttled.count(), 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().rx_deferred_frame[0]);
                 // assert that no operation actually completed (limiter blocked it)
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data is still queued for processing
@@ -1766,7 +2697,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::RxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().rx_rate_limiter.is_blocked());
+                assert!(!th.net().rx_rate_limiters[0].is_blocked());
                 // make sure the virtio queue operation completed this time
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data queue advanced
@@ -1790,7 +2721,7 @@ pub mod tests {
             assert!(rl.consume(1, TokenType::Ops));
 
             // set this tx rate limiter to be used
-            th.net().tx_rate_limiter = rl;
+            th.net().tx_rate_limiters[0] = rl;
 
             // try doing TX
             // following TX procedure should fail because of ops rate limiting
@@ -1804,7 +2735,7 @@ pub mod tests {
                 );
 
                 // assert that limiter is blocked
-                assert!(th.net().tx_rate_limiter.is_blocked());
+                assert!(th.net().tx_rate_limiters[0].is_blocked());
                 // make sure the data is still queued for processing
                 assert_eq!(th.txq.used.idx.get(), 0);
             }
@@ -1822,7 +2753,7 @@ pub mod tests {
                     th.simulate_event(NetEvent::TxRateLimiter)
                 );
                 // validate the rate_limiter is no longer blocked
-                assert!(!th.net().tx_rate_limiter.is_blocked());
+                assert!(!th.net().tx_rate_limiters[0].is_blocked());
                 // make sure the data queue advanced
                 assert_eq!(th.txq.used.idx.get(), 1);
             }
@@ -1836,10 +2767,10 @@ pub mod tests {
             assert!(rl.consume(1, TokenType::Ops));
 
             // set this rx rate limiter to be used
-            th.net().rx_rate_limiter = rl;
+            th.net().rx_rate_limiters[0] = rl;
 
             // set up RX
-            assert!(!th.net().rx_deferred_frame);
+            assert!(!th.net().rx_deferred_frame[0]);
             th.add_desc_chain(NetQueue::Rx, 0, &[(0, 4096, VIRTQ_DESC_F_WRITE)]);
 
             // following RX procedure should fail because of ops rate limiting
@@ -1852,9 +2783,9 @@ pub mod tests {
                 );
 
                 // assert that limiter is blocked
-                assert!(th.net().rx_rate_limiter.is_blocked());
+                assert!(th.net().rx_rate_limiters[0].is_blocked());
                 assert!(METRICS.net.rx_rate_limiter_throttled.count() >= 1);
-                assert!(th.net().rx_deferred_frame);
+                assert!(th.net().rx_deferred_frame[0]);
                 // assert that no operation actually completed (limiter blocked it)
                 assert!(&th.net().irq_trigger.has_pending_irq(IrqType::Vring));
                 // make sure the data is still queued for processing
@@ -1891,8 +2822,8 @@ pub mod tests {
         let mut th = TestHelper::default();
         th.activate_net();
 
-        th.net().rx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
-        th.net().tx_rate_limiter = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
+        th.net().rx_rate_limiters[0] = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
+        th.net().tx_rate_limiters[0] = RateLimiter::new(10, 0, 10, 2, 0, 2).unwrap();
 
         let rx_bytes = TokenBucket::new(1000, 1001, 1002).unwrap();
         let rx_ops = TokenBucket::new(1003, 1004, 1005).unwrap();
@@ -1910,10 +2841,10 @@ pub mod tests {
             assert_eq!(a.one_time_burst(), b.one_time_burst());
             assert_eq!(a.refill_time_ms(), b.refill_time_ms());
         };
-        compare_buckets(th.net().rx_rate_limiter.bandwidth().unwrap(), &rx_bytes);
-        compare_buckets(th.net().rx_rate_limiter.ops().unwrap(), &rx_ops);
-        compare_buckets(th.net().tx_rate_limiter.bandwidth().unwrap(), &tx_bytes);
-        compare_buckets(th.net().tx_rate_limiter.ops().unwrap(), &tx_ops);
+        compare_buckets(th.net().rx_rate_limiters[0].bandwidth().unwrap(), &rx_bytes);
+        compare_buckets(th.net().rx_rate_limiters[0].ops().unwrap(), &rx_ops);
+        compare_buckets(th.net().tx_rate_limiters[0].bandwidth().unwrap(), &tx_bytes);
+        compare_buckets(th.net().tx_rate_limiters[0].ops().unwrap(), &tx_ops);
 
         th.net().patch_rate_limiters(
             BucketUpdate::Disabled,
@@ -1921,10 +2852,10 @@ pub mod tests {
             BucketUpdate::Disabled,
             BucketUpdate::Disabled,
         );
-        assert!(th.net().rx_rate_limiter.bandwidth().is_none());
-        assert!(th.net().rx_rate_limiter.ops().is_none());
-        assert!(th.net().tx_rate_limiter.bandwidth().is_none());
-        assert!(th.net().tx_rate_limiter.ops().is_none());
+        assert!(th.net().rx_rate_limiters[0].bandwidth().is_none());
+        assert!(th.net().rx_rate_limiters[0].ops().is_none());
+        assert!(th.net().tx_rate_limiters[0].bandwidth().is_none());
+        assert!(th.net().tx_rate_limiters[0].ops().is_none());
     }
 
     #[test]
@@ -1935,12 +2866,12 @@ pub mod tests {
 
         // Test queues count (TX and RX).
         let queues = net.queues();
-        assert_eq!(queues.len(), QUEUE_SIZES.len());
+        assert_eq!(queues.len(), 2);
         assert_eq!(queues[RX_INDEX].size, th.rxq.size());
         assert_eq!(queues[TX_INDEX].size, th.txq.size());
 
         // Test corresponding queues events.
-        assert_eq!(net.queue_events().len(), QUEUE_SIZES.len());
+        assert_eq!(net.queue_events().len(), 2);
 
         // Test interrupts.
         assert!(!&net.irq_trigger.has_pending_irq(IrqType::Vring));
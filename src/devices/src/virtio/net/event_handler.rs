@@ -1,34 +1,73 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use event_manager::{EventOps, Events, MutEventSubscriber};
 use logger::{debug, error, warn, IncMetric, METRICS};
+use rate_limiter::RateLimiter;
 use utils::epoll::EventSet;
 
 use crate::virtio::net::device::Net;
-use crate::virtio::{VirtioDevice, RX_INDEX, TX_INDEX};
+use crate::virtio::{VirtioDevice, RX_INDEX};
 
 impl Net {
     fn register_runtime_events(&self, ops: &mut EventOps) {
-        if let Err(err) = ops.add(Events::new(&self.queue_evts[RX_INDEX], EventSet::IN)) {
-            error!("Failed to register rx queue event: {}", err);
+        for queue_evt in self.queue_evts.iter() {
+            if let Err(err) = ops.add(Events::new(queue_evt, EventSet::IN)) {
+                error!("Failed to register queue event: {}", err);
+            }
+        }
+        for rx_rate_limiter in self.rx_rate_limiters.iter() {
+            if let Err(err) = ops.add(Events::new(rx_rate_limiter, EventSet::IN)) {
+                error!("Failed to register rx queue event: {}", err);
+            }
+        }
+        for tx_rate_limiter in self.tx_rate_limiters.iter() {
+            if let Err(err) = ops.add(Events::new(tx_rate_limiter, EventSet::IN)) {
+                error!("Failed to register tx queue event: {}", err);
+            }
         }
-        if let Err(err) = ops.add(Events::new(&self.queue_evts[TX_INDEX], EventSet::IN)) {
-            error!("Failed to register tx queue event: {}", err);
+        for tap in self.taps.iter() {
+            if let Err(err) = ops.add(Events::new(tap, EventSet::IN | EventSet::EDGE_TRIGGERED)) {
+                error!("Failed to register tap event: {}", err);
+            }
         }
-        if let Err(err) = ops.add(Events::new(&self.rx_rate_limiter, EventSet::IN)) {
-            error!("Failed to register rx queue event: {}", err);
+        if let Some(user_ns) = self.user_ns.as_ref() {
+            if let Err(err) = ops.add(Events::new(user_ns, EventSet::IN)) {
+                error!("Failed to register user network stack event: {}", err);
+            }
         }
-        if let Err(err) = ops.add(Events::new(&self.tx_rate_limiter, EventSet::IN)) {
-            error!("Failed to register tx queue event: {}", err);
+        if let Err(err) = ops.add(Events::new(&self.capture_timer, EventSet::IN)) {
+            error!("Failed to register packet capture timer event: {}", err);
         }
-        if let Err(err) = ops.add(Events::new(
-            &self.tap,
-            EventSet::IN | EventSet::EDGE_TRIGGERED,
-        )) {
-            error!("Failed to register tap event: {}", err);
+    }
+
+    // Returns the index of the queue whose queue event is `source`.
+    fn queue_evt_index(&self, source: RawFd) -> Option<usize> {
+        self.queue_evts
+            .iter()
+            .position(|queue_evt| queue_evt.as_raw_fd() == source)
+    }
+
+    // Returns the index of the queue pair whose tap queue is `source`.
+    fn tap_index(&self, source: RawFd) -> Option<usize> {
+        self.taps.iter().position(|tap| tap.as_raw_fd() == source)
+    }
+
+    // Returns the index of the queue pair whose rate limiter is `source`.
+    fn rate_limiter_index(rate_limiters: &[RateLimiter], source: RawFd) -> Option<usize> {
+        rate_limiters
+            .iter()
+            .position(|rate_limiter| rate_limiter.as_raw_fd() == source)
+    }
+
+    fn process_queue_event(&mut self, queue_index: usize) {
+        let pair = queue_index / 2;
+        match queue_index {
+            _ if queue_index == self.ctrl_queue_index() => self.process_ctrl_queue_event(),
+            _ if queue_index % 2 == RX_INDEX => self.process_rx_queue_event(pair),
+            _ => self.process_tx_queue_event(pair),
         }
     }
 
@@ -67,24 +106,30 @@ impl MutEventSubscriber for Net {
         }
 
         if self.is_activated() {
-            let virtq_rx_ev_fd = self.queue_evts[RX_INDEX].as_raw_fd();
-            let virtq_tx_ev_fd = self.queue_evts[TX_INDEX].as_raw_fd();
-            let rx_rate_limiter_fd = self.rx_rate_limiter.as_raw_fd();
-            let tx_rate_limiter_fd = self.tx_rate_limiter.as_raw_fd();
-            let tap_fd = self.tap.as_raw_fd();
             let activate_fd = self.activate_evt.as_raw_fd();
+            let user_ns_fd = self.user_ns.as_ref().map(AsRawFd::as_raw_fd);
+            let capture_timer_fd = self.capture_timer.as_raw_fd();
 
             // Looks better than C style if/else if/else.
             match source {
-                _ if source == virtq_rx_ev_fd => self.process_rx_queue_event(),
-                _ if source == tap_fd => self.process_tap_rx_event(),
-                _ if source == virtq_tx_ev_fd => self.process_tx_queue_event(),
-                _ if source == rx_rate_limiter_fd => self.process_rx_rate_limiter_event(),
-                _ if source == tx_rate_limiter_fd => self.process_tx_rate_limiter_event(),
                 _ if activate_fd == source => self.process_activate_event(ops),
+                _ if user_ns_fd == Some(source) => self.process_user_ns_event(),
+                _ if capture_timer_fd == source => self.process_capture_timer_event(),
                 _ => {
-                    warn!("Net: Spurious event received: {:?}", source);
-                    METRICS.net.event_fails.inc();
+                    if let Some(pair) = Self::rate_limiter_index(&self.rx_rate_limiters, source) {
+                        self.process_rx_rate_limiter_event(pair);
+                    } else if let Some(pair) =
+                        Self::rate_limiter_index(&self.tx_rate_limiters, source)
+                    {
+                        self.process_tx_rate_limiter_event(pair);
+                    } else if let Some(pair) = self.tap_index(source) {
+                        self.process_tap_rx_event(pair);
+                    } else if let Some(queue_index) = self.queue_evt_index(source) {
+                        self.process_queue_event(queue_index);
+                    } else {
+                        warn!("Net: Spurious event received: {:?}", source);
+                        METRICS.net.event_fails.inc();
+                    }
                 }
             }
         } else {
@@ -0,0 +1,222 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Drops the frames transmitted by a guest whose source addresses it does not own.
+
+use std::net::Ipv4Addr;
+
+use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use dumbo::pdu::ipv4::{self, IPv4Packet};
+use logger::{IncMetric, METRICS};
+use utils::net::mac::MacAddr;
+
+/// The reasons for which a frame is dropped by the filter.
+#[derive(Debug, PartialEq)]
+enum DropReason {
+    /// The frame, or the packet it carries, can't be parsed.
+    Malformed,
+    /// The source MAC of the frame, or the sender MAC of the ARP frame, is not the guest MAC.
+    SpoofedMac,
+    /// The sender IPv4 address is not in the allowlist.
+    SpoofedIp,
+    /// The frame carries neither an IPv4 packet nor an ARP frame.
+    UnsupportedEthertype,
+}
+
+/// An anti-spoofing filter for the TX path of a net device.
+///
+/// A frame passes the filter only if its source MAC is the guest MAC and it carries either an
+/// IPv4 packet or an ARP frame, whose sender address is in the IPv4 allowlist. Frames of any
+/// other EtherType, IPv6 and VLAN tagged frames included, are dropped.
+///
+/// The guest MAC is the one the filter was created with, so that the guest can't get around the
+/// filter by changing the MAC in the configuration space of the device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AntiSpoofingFilter {
+    guest_mac: MacAddr,
+    ipv4_allowlist: Vec<Ipv4Addr>,
+}
+
+impl AntiSpoofingFilter {
+    /// Creates a filter which only lets through the frames sent from `guest_mac` and the IPv4
+    /// addresses of `ipv4_allowlist`.
+    pub fn new(guest_mac: MacAddr, ipv4_allowlist: Vec<Ipv4Addr>) -> Self {
+        AntiSpoofingFilter {
+            guest_mac,
+            ipv4_allowlist,
+        }
+    }
+
+    /// Provides the MAC address the guest is allowed to send from.
+    pub fn guest_mac(&self) -> MacAddr {
+        self.guest_mac
+    }
+
+    /// Provides the IPv4 addresses the guest is allowed to send from.
+    pub fn ipv4_allowlist(&self) -> &[Ipv4Addr] {
+        &self.ipv4_allowlist
+    }
+
+    /// Tells whether a frame, stripped of its VNET header, can be sent by the guest. Dropped
+    /// frames are accounted for in the net metrics.
+    pub fn allows(&self, frame: &[u8]) -> bool {
+        match self.check(frame) {
+            Ok(()) => true,
+            Err(reason) => {
+                match reason {
+                    DropReason::Malformed => &METRICS.net.tx_malformed_frames,
+                    DropReason::SpoofedMac => &METRICS.net.tx_spoofed_mac_dropped,
+                    DropReason::SpoofedIp => &METRICS.net.tx_spoofed_ip_dropped,
+                    DropReason::UnsupportedEthertype => {
+                        &METRICS.net.tx_unsupported_ethertype_dropped
+                    }
+                }
+                .inc();
+                false
+            }
+        }
+    }
+
+    fn check(&self, frame: &[u8]) -> Result<(), DropReason> {
+        let eth_frame = EthernetFrame::from_bytes(frame).map_err(|_| DropReason::Malformed)?;
+        if eth_frame.src_mac() != self.guest_mac {
+            return Err(DropReason::SpoofedMac);
+        }
+
+        let sender_ip = match eth_frame.ethertype() {
+            ETHERTYPE_IPV4 => ipv4_packet(eth_frame.payload())
+                .ok_or(DropReason::Malformed)?
+                .source_address(),
+            ETHERTYPE_ARP => {
+                // Short frames are padded up to the minimum Ethernet frame length.
+                let arp_frame = eth_frame
+                    .payload()
+                    .get(..ETH_IPV4_FRAME_LEN)
+                    .and_then(|bytes| EthIPv4ArpFrame::from_bytes(bytes).ok())
+                    .ok_or(DropReason::Malformed)?;
+                if arp_frame.sha() != self.guest_mac {
+                    return Err(DropReason::SpoofedMac);
+                }
+                arp_frame.spa()
+            }
+            _ => return Err(DropReason::UnsupportedEthertype),
+        };
+
+        if !self.ipv4_allowlist.contains(&sender_ip) {
+            return Err(DropReason::SpoofedIp);
+        }
+        Ok(())
+    }
+}
+
+/// Parses the IPv4 packet carried by the payload of an Ethernet frame, without verifying its
+/// checksum.
+pub(crate) fn ipv4_packet(bytes: &[u8]) -> Option<IPv4Packet<&[u8]>> {
+    match IPv4Packet::from_bytes(bytes, false) {
+        Ok(packet) => Some(packet),
+        // Short frames are padded up to the minimum Ethernet frame length, so the packet may
+        // end before the payload of the frame does.
+        Err(ipv4::Error::SliceExactLen) => {
+            let total_len = IPv4Packet::from_bytes_unchecked(bytes).total_len() as usize;
+            IPv4Packet::from_bytes(bytes.get(..total_len)?, false).ok()
+        }
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dumbo::pdu::ipv4::PROTOCOL_UDP;
+
+    use super::*;
+
+    const FRAME_LEN: usize = 60;
+    // The length of the IPv4 packets carried by the test frames, followed by padding.
+    const IPV4_PACKET_LEN: usize = 28;
+
+    fn eth_frame(buf: &mut [u8], src_mac: MacAddr, ethertype: u16) -> EthernetFrame<'_, &mut [u8]> {
+        let dst_mac = MacAddr::parse_str("22:22:22:22:22:22").unwrap();
+        EthernetFrame::write_incomplete(buf, dst_mac, src_mac, ethertype)
+            .unwrap()
+            .with_payload_len_unchecked(FRAME_LEN - 14)
+    }
+
+    fn ipv4_frame(src_mac: MacAddr, src_ip: Ipv4Addr) -> [u8; FRAME_LEN] {
+        let mut buf = [0u8; FRAME_LEN];
+        let mut frame = eth_frame(&mut buf, src_mac, ETHERTYPE_IPV4);
+        IPv4Packet::write_header(
+            frame.payload_mut(),
+            PROTOCOL_UDP,
+            src_ip,
+            Ipv4Addr::new(10, 1, 1, 1),
+        )
+        .unwrap()
+        .with_header_and_payload_len_unchecked(20, IPV4_PACKET_LEN - 20, true);
+        buf
+    }
+
+    fn arp_frame(src_mac: MacAddr, sha: MacAddr, spa: Ipv4Addr) -> [u8; FRAME_LEN] {
+        let mut buf = [0u8; FRAME_LEN];
+        let mut frame = eth_frame(&mut buf, src_mac, ETHERTYPE_ARP);
+        EthIPv4ArpFrame::write_reply(
+            &mut frame.payload_mut()[..ETH_IPV4_FRAME_LEN],
+            sha,
+            spa,
+            MacAddr::parse_str("22:22:22:22:22:22").unwrap(),
+            Ipv4Addr::new(10, 1, 1, 1),
+        )
+        .unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_anti_spoofing_filter() {
+        let guest_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
+        let spoofed_mac = MacAddr::parse_str("33:33:33:33:33:33").unwrap();
+        let guest_ip = Ipv4Addr::new(10, 1, 2, 3);
+        let spoofed_ip = Ipv4Addr::new(10, 1, 2, 4);
+        let filter = AntiSpoofingFilter::new(guest_mac, vec![guest_ip]);
+        assert_eq!(filter.guest_mac(), guest_mac);
+        assert_eq!(filter.ipv4_allowlist(), &[guest_ip]);
+
+        // Padded IPv4 frames and ARP frames from the guest addresses go through.
+        let frame = ipv4_frame(guest_mac, guest_ip);
+        assert_eq!(filter.check(&frame), Ok(()));
+        assert_eq!(filter.check(&frame[..14 + IPV4_PACKET_LEN]), Ok(()));
+        let frame = arp_frame(guest_mac, guest_mac, guest_ip);
+        assert_eq!(filter.check(&frame), Ok(()));
+        assert!(filter.allows(&frame));
+
+        // Spoofed source MACs.
+        let frame = ipv4_frame(spoofed_mac, guest_ip);
+        assert_eq!(filter.check(&frame), Err(DropReason::SpoofedMac));
+        let frame = arp_frame(guest_mac, spoofed_mac, guest_ip);
+        assert_eq!(filter.check(&frame), Err(DropReason::SpoofedMac));
+
+        // Spoofed sender IPv4 addresses.
+        let frame = ipv4_frame(guest_mac, spoofed_ip);
+        assert_eq!(filter.check(&frame), Err(DropReason::SpoofedIp));
+        let frame = arp_frame(guest_mac, guest_mac, spoofed_ip);
+        assert_eq!(filter.check(&frame), Err(DropReason::SpoofedIp));
+
+        // Other EtherTypes are dropped.
+        let mut frame = ipv4_frame(guest_mac, guest_ip);
+        eth_frame(&mut frame, guest_mac, 0x86dd);
+        assert_eq!(filter.check(&frame), Err(DropReason::UnsupportedEthertype));
+
+        // Malformed frames.
+        let frame = ipv4_frame(guest_mac, guest_ip);
+        assert_eq!(filter.check(&frame[..10]), Err(DropReason::Malformed));
+        // The IPv4 packet is truncated.
+        assert_eq!(
+            filter.check(&frame[..14 + IPV4_PACKET_LEN - 1]),
+            Err(DropReason::Malformed)
+        );
+        let frame = arp_frame(guest_mac, guest_mac, guest_ip);
+        assert_eq!(
+            filter.check(&frame[..14 + ETH_IPV4_FRAME_LEN - 1]),
+            Err(DropReason::Malformed)
+        );
+    }
+}
@@ -5,23 +5,33 @@ use std::{io, result};
 
 pub const MAX_BUFFER_SIZE: usize = 65562;
 pub const QUEUE_SIZE: u16 = 256;
-pub const NUM_QUEUES: usize = 2;
-pub const QUEUE_SIZES: &[u16] = &[QUEUE_SIZE; NUM_QUEUES];
-// The index of the rx queue from Net device queues/queues_evts vector.
+pub const DEFAULT_NUM_QUEUE_PAIRS: usize = 1;
+// Each queue pair has its own tap queue, so the number of pairs is bounded to limit the number
+// of file descriptors per device.
+pub const MAX_NUM_QUEUE_PAIRS: usize = 16;
+// The smallest MTU a driver is required to support, as defined by the virtio specification.
+pub const MIN_MTU: u16 = 68;
+// The index of the rx queue of the first queue pair from Net device queues/queues_evts vector.
 pub const RX_INDEX: usize = 0;
-// The index of the tx queue from Net device queues/queues_evts vector.
+// The index of the tx queue of the first queue pair from Net device queues/queues_evts vector.
 pub const TX_INDEX: usize = 1;
 
+pub mod capture;
 pub mod device;
 pub mod event_handler;
+pub mod filter;
 pub mod persist;
 mod tap;
 pub mod test_utils;
+pub mod user;
 
 pub use tap::Error as TapError;
 
+pub use self::capture::PacketCapture;
 pub use self::device::Net;
 pub use self::event_handler::*;
+pub use self::filter::AntiSpoofingFilter;
+pub use self::user::{UserNetworkConfig, UserNetworkStack};
 
 /// Enum representing the Net device queue types
 pub enum NetQueue {
@@ -41,12 +51,24 @@ pub enum Error {
     TapSetVnetHdrSize(TapError),
     /// Enabling tap interface failed.
     TapEnable(TapError),
+    /// Attaching or detaching a tap queue failed.
+    TapSetQueue(TapError),
+    /// The number of queue pairs is not between 1 and `MAX_NUM_QUEUE_PAIRS`.
+    InvalidNumQueuePairs(usize),
+    /// The MTU is smaller than `MIN_MTU`.
+    InvalidMtu(u16),
     /// EventFd error.
     EventFd(io::Error),
+    /// Creating the rate limiter of a queue pair failed.
+    RateLimiter(io::Error),
+    /// Creating the timer of the packet capture failed.
+    Timer(io::Error),
     /// IO error.
     IO(io::Error),
     /// The VNET header is missing from the frame.
     VnetHeaderMissing,
+    /// Creating the userspace network stack failed.
+    UserNetworkStack(user::Error),
 }
 
 pub type Result<T> = result::Result<T, Error>;
@@ -4,6 +4,7 @@
 //! Defines the structures needed for saving/restoring net devices.
 
 use std::io;
+use std::net::Ipv4Addr;
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, Mutex};
 
@@ -13,24 +14,32 @@ use mmds::ns::MmdsNetworkStack;
 use mmds::persist::MmdsNetworkStackState;
 use rate_limiter::persist::RateLimiterState;
 use rate_limiter::RateLimiter;
+use serde::Serialize;
 use snapshot::Persist;
+use utils::net::ipv4addr::Ipv4Network;
 use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
-use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::GuestMemoryMmap;
 
 use super::device::Net;
-use super::{NUM_QUEUES, QUEUE_SIZE};
+use super::filter::AntiSpoofingFilter;
+use super::user::UserNetworkConfig;
+use super::QUEUE_SIZE;
 use crate::virtio::persist::{Error as VirtioStateError, VirtioDeviceState};
 use crate::virtio::{DeviceState, TYPE_NET};
 
-#[derive(Debug, Default, Clone, Versionize)]
+#[derive(Debug, Default, Clone, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct NetConfigSpaceState {
     #[version(end = 2, default_fn = "def_guest_mac_old")]
     guest_mac: [u8; MAC_ADDR_LEN],
     #[version(start = 2, de_fn = "de_guest_mac_v2", ser_fn = "ser_guest_mac_v2")]
     guest_mac_v2: Option<MacAddr>,
+    #[version(start = 3, ser_fn = "ser_link_up", default_fn = "def_link_up")]
+    link_up: bool,
+    #[version(start = 3)]
+    mtu: Option<u16>,
 }
 
 impl NetConfigSpaceState {
@@ -57,9 +66,86 @@ impl NetConfigSpaceState {
         // v1.2 and newer don't use this field anyway
         Default::default()
     }
+
+    fn ser_link_up(&mut self, _target_version: u16) -> VersionizeResult<()> {
+        // v1.2 and older versions always restore the link as up.
+        if !self.link_up {
+            warn!("Saving to older snapshot version, the link state will not be saved.");
+        }
+        Ok(())
+    }
+
+    fn def_link_up(_: u16) -> bool {
+        true
+    }
+}
+
+#[derive(Clone, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct AntiSpoofingFilterState {
+    guest_mac: MacAddr,
+    ipv4_allowlist: Vec<u32>,
+}
+
+impl From<&AntiSpoofingFilter> for AntiSpoofingFilterState {
+    fn from(filter: &AntiSpoofingFilter) -> Self {
+        AntiSpoofingFilterState {
+            guest_mac: filter.guest_mac(),
+            ipv4_allowlist: filter
+                .ipv4_allowlist()
+                .iter()
+                .map(|&addr| u32::from(addr))
+                .collect(),
+        }
+    }
+}
+
+impl From<&AntiSpoofingFilterState> for AntiSpoofingFilter {
+    fn from(state: &AntiSpoofingFilterState) -> Self {
+        AntiSpoofingFilter::new(
+            state.guest_mac,
+            state
+                .ipv4_allowlist
+                .iter()
+                .map(|&addr| Ipv4Addr::from(addr))
+                .collect(),
+        )
+    }
+}
+
+#[derive(Clone, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct UserNetworkConfigState {
+    host_loopback: bool,
+    allowed_networks: Option<Vec<Ipv4Network>>,
+}
+
+impl From<&UserNetworkConfig> for UserNetworkConfigState {
+    fn from(config: &UserNetworkConfig) -> Self {
+        UserNetworkConfigState {
+            host_loopback: config.host_loopback,
+            allowed_networks: config.allowed_networks.clone(),
+        }
+    }
+}
+
+impl From<&UserNetworkConfigState> for UserNetworkConfig {
+    fn from(state: &UserNetworkConfigState) -> Self {
+        UserNetworkConfig {
+            host_loopback: state.host_loopback,
+            allowed_networks: state.allowed_networks.clone(),
+        }
+    }
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
+// NOTICE: Any changes to this structure require a snapshot version bump.
+pub struct QueuePairRateLimiterState {
+    rx_rate_limiter_state: RateLimiterState,
+    tx_rate_limiter_state: RateLimiterState,
+}
+
+#[derive(Clone, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct NetState {
     id: String,
@@ -69,6 +155,84 @@ pub struct NetState {
     pub mmds_ns: Option<MmdsNetworkStackState>,
     config_space: NetConfigSpaceState,
     virtio_state: VirtioDeviceState,
+    #[version(
+        start = 2,
+        ser_fn = "ser_active_queue_pairs",
+        default_fn = "def_active_queue_pairs"
+    )]
+    active_queue_pairs: u16,
+    #[version(start = 2, ser_fn = "ser_anti_spoofing_filter")]
+    anti_spoofing_filter: Option<AntiSpoofingFilterState>,
+    // The configuration of the userspace network stack backing the device instead of a tap.
+    #[version(start = 2, ser_fn = "ser_user_ns")]
+    user_ns: Option<UserNetworkConfigState>,
+    // The rate limiters of the queue pairs following the first one, whose rate limiters are
+    // saved above.
+    #[version(start = 2)]
+    queue_pair_rate_limiter_states: Vec<QueuePairRateLimiterState>,
+}
+
+impl NetState {
+    /// Replaces the name of the host tap device the device is restored with.
+    pub fn set_tap_if_name(&mut self, tap_if_name: String) {
+        self.tap_if_name = tap_if_name;
+    }
+
+    /// Tells whether the device is backed by the userspace network stack rather than a tap.
+    pub fn has_user_ns(&self) -> bool {
+        self.user_ns.is_some()
+    }
+
+    /// Replaces the MAC address exposed to the guest in the device configuration space, and the
+    /// one the anti-spoofing filter lets through.
+    pub fn set_guest_mac(&mut self, guest_mac: MacAddr) {
+        self.config_space.guest_mac_v2 = Some(guest_mac);
+        if let Some(filter) = self.anti_spoofing_filter.as_mut() {
+            filter.guest_mac = guest_mac;
+        }
+    }
+
+    // The queue pairs are followed by a control queue when there are several of them.
+    fn num_queue_pairs(&self) -> usize {
+        self.virtio_state.queues.len() / 2
+    }
+
+    fn ser_active_queue_pairs(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would restore the device with a single queue pair.
+        if target_version < 2 && self.num_queue_pairs() > 1 {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement multi-queue net devices.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn def_active_queue_pairs(_source_version: u16) -> u16 {
+        1
+    }
+
+    fn ser_anti_spoofing_filter(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would restore the device without filtering the guest traffic.
+        if target_version < 2 && self.anti_spoofing_filter.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the net anti-spoofing filter.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn ser_user_ns(&mut self, target_version: u16) -> VersionizeResult<()> {
+        // Older versions would restore the device with a tap.
+        if target_version < 2 && self.user_ns.is_some() {
+            return Err(VersionizeError::Semantic(
+                "Target version does not implement the userspace network backend.".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub struct NetConstructorArgs {
@@ -93,14 +257,33 @@ impl Persist<'_> for Net {
         NetState {
             id: self.id().clone(),
             tap_if_name: self.iface_name(),
-            rx_rate_limiter_state: self.rx_rate_limiter.save(),
-            tx_rate_limiter_state: self.tx_rate_limiter.save(),
+            rx_rate_limiter_state: self.rx_rate_limiters[0].save(),
+            tx_rate_limiter_state: self.tx_rate_limiters[0].save(),
             mmds_ns: self.mmds_ns.as_ref().map(|mmds| mmds.save()),
             config_space: NetConfigSpaceState {
                 guest_mac_v2: self.guest_mac,
                 guest_mac: Default::default(),
+                link_up: self.link_up(),
+                mtu: self.mtu(),
             },
             virtio_state: VirtioDeviceState::from_device(self),
+            active_queue_pairs: self.active_queue_pairs as u16,
+            anti_spoofing_filter: self
+                .anti_spoofing_filter()
+                .map(AntiSpoofingFilterState::from),
+            user_ns: self
+                .user_ns()
+                .map(|user_ns| UserNetworkConfigState::from(user_ns.config())),
+            queue_pair_rate_limiter_states: self
+                .rx_rate_limiters
+                .iter()
+                .zip(self.tx_rate_limiters.iter())
+                .skip(1)
+                .map(|(rx, tx)| QueuePairRateLimiterState {
+                    rx_rate_limiter_state: rx.save(),
+                    tx_rate_limiter_state: tx.save(),
+                })
+                .collect(),
         }
     }
 
@@ -111,13 +294,40 @@ impl Persist<'_> for Net {
         // RateLimiter::restore() can fail at creating a timerfd.
         let rx_rate_limiter = RateLimiter::restore((), &state.rx_rate_limiter_state)?;
         let tx_rate_limiter = RateLimiter::restore((), &state.tx_rate_limiter_state)?;
-        let mut net = Net::new_with_tap(
-            state.id.clone(),
-            state.tap_if_name.clone(),
-            state.config_space.guest_mac_v2,
-            rx_rate_limiter,
-            tx_rate_limiter,
-        )?;
+        // The connections and flows of the userspace network stack are not saved, so the guest
+        // gets a fresh stack, and resets for the TCP connections it had open.
+        let mut net = match &state.user_ns {
+            Some(user_config) => Net::new_with_user_ns(
+                state.id.clone(),
+                state.config_space.guest_mac_v2,
+                rx_rate_limiter,
+                tx_rate_limiter,
+                state.config_space.mtu,
+                UserNetworkConfig::from(user_config),
+            )?,
+            None => Net::new_with_tap(
+                state.id.clone(),
+                state.tap_if_name.clone(),
+                state.config_space.guest_mac_v2,
+                rx_rate_limiter,
+                tx_rate_limiter,
+                state.num_queue_pairs(),
+                state.config_space.mtu,
+            )?,
+        };
+
+        // The other queue pairs start with the rate limiters of the first one, unless they were
+        // saved on their own.
+        for ((rx, tx), rate_limiter_states) in net
+            .rx_rate_limiters
+            .iter_mut()
+            .zip(net.tx_rate_limiters.iter_mut())
+            .skip(1)
+            .zip(state.queue_pair_rate_limiter_states.iter())
+        {
+            *rx = RateLimiter::restore((), &rate_limiter_states.rx_rate_limiter_state)?;
+            *tx = RateLimiter::restore((), &rate_limiter_states.tx_rate_limiter_state)?;
+        }
 
         // We trust the MMIODeviceManager::restore to pass us an MMDS data store reference if
         // there is at least one net device having the MMDS NS present and/or the mmds version was
@@ -136,18 +346,28 @@ impl Persist<'_> for Net {
             );
         }
 
+        // All the queue pairs and the control queue are restored.
         net.queues = state.virtio_state.build_queues_checked(
             &constructor_args.mem,
             TYPE_NET,
-            NUM_QUEUES,
+            net.queues.len(),
             QUEUE_SIZE,
         )?;
         net.irq_trigger.irq_status =
             Arc::new(AtomicUsize::new(state.virtio_state.interrupt_status));
         net.avail_features = state.virtio_state.avail_features;
         net.acked_features = state.virtio_state.acked_features;
+        net.set_link_up(state.config_space.link_up);
+        net.set_anti_spoofing_filter(
+            state
+                .anti_spoofing_filter
+                .as_ref()
+                .map(AntiSpoofingFilter::from),
+        );
 
         if state.virtio_state.activated {
+            // Only the tap queues of the queue pairs in use by the driver stay attached.
+            net.set_active_queue_pairs(usize::from(state.active_queue_pairs))?;
             net.device_state = DeviceState::Activated(constructor_args.mem);
         }
 
@@ -159,9 +379,15 @@ impl Persist<'_> for Net {
 mod tests {
     use std::sync::atomic::Ordering;
 
+    use rate_limiter::{BucketUpdate, TokenBucket, TokenType};
+    use vm_memory::{Address, GuestAddress};
+
     use super::*;
     use crate::virtio::device::VirtioDevice;
-    use crate::virtio::net::test_utils::{default_guest_memory, default_net, default_net_no_mmds};
+    use crate::virtio::net::test_utils::{
+        default_guest_memory, default_net, default_net_no_mmds, default_net_with_queue_pairs,
+    };
+    use crate::virtio::test_utils::{VirtQueue, VirtqDesc};
 
     fn validate_save_and_restore(net: Net, mmds_ds: Option<Arc<Mutex<Mmds>>>) {
         let guest_mem = default_guest_memory();
@@ -215,8 +441,8 @@ mod tests {
                     assert_eq!(&restored_net.id, &id);
                     assert_eq!(&restored_net.iface_name(), &tap_if_name);
                     assert_eq!(restored_net.mmds_ns.is_some(), allow_mmds_requests);
-                    assert_eq!(restored_net.rx_rate_limiter, RateLimiter::default());
-                    assert_eq!(restored_net.tx_rate_limiter, RateLimiter::default());
+                    assert_eq!(restored_net.rx_rate_limiters, [RateLimiter::default()]);
+                    assert_eq!(restored_net.tx_rate_limiters, [RateLimiter::default()]);
                 }
                 Err(Error::NoMmdsDataStore) => assert!(has_mmds_ns && !allow_mmds_requests),
                 _ => unreachable!(),
@@ -239,4 +465,199 @@ mod tests {
         // data store. This will return an error.
         validate_save_and_restore(default_net(), None);
     }
+
+    #[test]
+    fn test_persistence_multi_queue() {
+        let guest_mem = default_guest_memory();
+        let mut net = default_net_with_queue_pairs(2);
+
+        // Two queue pairs and the control queue, which must be valid for an activated device.
+        let mut addr = GuestAddress(0);
+        let vqs: Vec<VirtQueue> = (0..5)
+            .map(|_| {
+                let vq = VirtQueue::new(addr, &guest_mem, 16);
+                addr = vq.end().unchecked_align_up(VirtqDesc::ALIGNMENT);
+                vq
+            })
+            .collect();
+        for (queue, vq) in net.queues.iter_mut().zip(vqs.iter()) {
+            *queue = vq.create_queue();
+            queue.max_size = QUEUE_SIZE;
+        }
+        net.activate(guest_mem.clone()).unwrap();
+        assert_eq!(net.active_queue_pairs, 1);
+        // Each queue pair has its own rate limiting budget.
+        net.patch_rate_limiters(
+            BucketUpdate::None,
+            BucketUpdate::None,
+            BucketUpdate::None,
+            BucketUpdate::Update(TokenBucket::new(10, 0, 1_000_000).unwrap()),
+        );
+        assert!(net.tx_rate_limiters[1].consume(4, TokenType::Ops));
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+
+        // Older versions can't restore more than a queue pair.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let queues = net.queues().to_vec();
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: guest_mem,
+                mmds: None,
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.num_queue_pairs(), 2);
+        assert_eq!(restored_net.queues(), queues.as_slice());
+        assert_eq!(restored_net.queue_events().len(), 5);
+        for (pair, budget) in [(0, 10), (1, 6)] {
+            let ops = restored_net.tx_rate_limiters[pair].ops().unwrap();
+            assert_eq!(ops.capacity(), 10);
+            assert_eq!(ops.budget(), budget);
+        }
+
+        // The tap queue of the unused queue pair is detached again.
+        assert_eq!(restored_net.active_queue_pairs, 1);
+        assert!(restored_net.taps[1].set_queue_enabled(false).is_err());
+    }
+
+    #[test]
+    fn test_persistence_link_state_and_mtu() {
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetConfigSpaceState::type_id(), 3);
+
+        for (version, link_up, mtu) in [(1, true, None), (2, false, Some(1400))] {
+            let mut net = Net::new_with_tap(
+                "net-persist".to_string(),
+                "net-persist".to_string(),
+                None,
+                RateLimiter::default(),
+                RateLimiter::default(),
+                1,
+                Some(1400),
+            )
+            .unwrap();
+            net.set_link_up(false);
+            <Net as Persist>::save(&net)
+                .serialize(&mut mem.as_mut_slice(), &version_map, version)
+                .unwrap();
+            drop(net);
+
+            // Older versions restore the link as up, without MTU.
+            let restored_net = Net::restore(
+                NetConstructorArgs {
+                    mem: default_guest_memory(),
+                    mmds: None,
+                },
+                &NetState::deserialize(&mut mem.as_slice(), &version_map, version).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(restored_net.link_up(), link_up);
+            assert_eq!(restored_net.mtu(), mtu);
+        }
+    }
+
+    #[test]
+    fn test_persistence_anti_spoofing_filter() {
+        let mut net = default_net_no_mmds();
+        let guest_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
+        let allowlist = vec![Ipv4Addr::new(10, 1, 2, 3), Ipv4Addr::new(0, 0, 0, 0)];
+        let filter = AntiSpoofingFilter::new(guest_mac, allowlist);
+        net.set_anti_spoofing_filter(Some(filter.clone()));
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+
+        // Older versions would restore the device without the filter.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        drop(net);
+
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+            },
+            &NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(restored_net.anti_spoofing_filter(), Some(&filter));
+
+        // The filter follows the MAC address the device is restored with.
+        let other_mac = MacAddr::parse_str("22:22:22:22:22:22").unwrap();
+        let mut state = NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+        state.set_guest_mac(other_mac);
+        assert_eq!(
+            AntiSpoofingFilter::from(state.anti_spoofing_filter.as_ref().unwrap()).guest_mac(),
+            other_mac
+        );
+    }
+
+    #[test]
+    fn test_persistence_user_ns() {
+        let user_config = UserNetworkConfig {
+            host_loopback: true,
+            allowed_networks: Some(vec![Ipv4Network::parse_str("192.0.2.0/24").unwrap()]),
+        };
+        let net = Net::new_with_user_ns(
+            "net-persist".to_string(),
+            None,
+            RateLimiter::default(),
+            RateLimiter::default(),
+            None,
+            user_config.clone(),
+        )
+        .unwrap();
+
+        let mut mem = vec![0; 4096];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(NetState::type_id(), 2);
+
+        // Older versions would restore the device with a tap.
+        assert!(<Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 1)
+            .is_err());
+        <Net as Persist>::save(&net)
+            .serialize(&mut mem.as_mut_slice(), &version_map, 2)
+            .unwrap();
+
+        let state = NetState::deserialize(&mut mem.as_slice(), &version_map, 2).unwrap();
+        assert!(state.has_user_ns());
+        let restored_net = Net::restore(
+            NetConstructorArgs {
+                mem: default_guest_memory(),
+                mmds: None,
+            },
+            &state,
+        )
+        .unwrap();
+        assert_eq!(restored_net.user_ns().unwrap().config(), &user_config);
+        assert!(restored_net.taps.is_empty());
+        assert_eq!(restored_net.iface_name(), "");
+        assert_eq!(restored_net.avail_features(), net.avail_features());
+    }
 }
@@ -7,21 +7,15 @@
 
 use std::fs::{File, OpenOptions};
 use std::io::{Error as IoError, Read, Result as IoResult, Write};
-use std::os::{
-    raw::*,
-    unix::{
-        fs::{FileTypeExt, OpenOptionsExt},
-        io::{AsRawFd, FromRawFd, RawFd},
-    },
-};
+use std::os::raw::*;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::path::Path;
 
 use net_gen::ifreq;
-use utils::{
-    ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val},
-    ioctl_expr, ioctl_ioc_nr, ioctl_iow_nr,
-    net::macvtap::MacVTap,
-};
+use utils::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
+use utils::net::macvtap::MacVTap;
+use utils::{ioctl_expr, ioctl_ioc_nr, ioctl_iow_nr};
 
 // As defined in the Linux UAPI:
 // https://elixir.bootlin.com/linux/v4.17/source/include/uapi/linux/if.h#L33
@@ -52,6 +46,7 @@ const TUNTAP: ::std::os::raw::c_uint = 84;
 ioctl_iow_nr!(TUNSETIFF, TUNTAP, 202, ::std::os::raw::c_int);
 ioctl_iow_nr!(TUNSETOFFLOAD, TUNTAP, 208, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETVNETHDRSZ, TUNTAP, 216, ::std::os::raw::c_int);
+ioctl_iow_nr!(TUNSETQUEUE, TUNTAP, 217, ::std::os::raw::c_int);
 
 /// Handle for a network tap interface.
 ///
@@ -128,10 +123,32 @@ impl Tap {
         if let Ok(path) = MacVTap::get_device_node(if_name) {
             Self::macvtap_open_named(if_name, &path)
         } else {
-            Self::tap_open_named(if_name)
+            Self::tap_open_named(if_name, false)
         }
     }
 
+    /// Open `num_queues` queues of a TUN/TAP device given the tap or macvtap interface name.
+    /// A single queue is opened like with `open_named`, while more queues require a
+    /// multi-queue interface.
+    /// # Arguments
+    ///
+    /// * `if_name` - the name of the interface.
+    /// * `num_queues` - the number of queues to open.
+    pub fn open_named_queues(if_name: &str, num_queues: usize) -> Result<Vec<Tap>> {
+        if num_queues <= 1 {
+            return Ok(vec![Self::open_named(if_name)?]);
+        }
+
+        // Each open of a macvtap device node attaches a new queue to the interface.
+        let macvtap_path = MacVTap::get_device_node(if_name).ok();
+        (0..num_queues)
+            .map(|_| match &macvtap_path {
+                Some(path) => Self::macvtap_open_named(if_name, path),
+                None => Self::tap_open_named(if_name, true),
+            })
+            .collect()
+    }
+
     /// Create a TUN/TAP device given the macvtap interface name and device node.
     /// # Arguments
     ///
@@ -173,7 +190,8 @@ impl Tap {
     /// # Arguments
     ///
     /// * `if_name` - the name of the interface.
-    fn tap_open_named(if_name: &str) -> Result<Tap> {
+    /// * `multi_queue` - whether the interface has multiple queues.
+    fn tap_open_named(if_name: &str, multi_queue: bool) -> Result<Tap> {
         let terminated_if_name = build_terminated_if_name(if_name)?;
 
         // SAFETY: Open calls are safe because we give a constant null-terminated
@@ -190,9 +208,13 @@ impl Tap {
         // SAFETY: We just checked that the fd is valid.
         let tuntap = unsafe { File::from_raw_fd(fd) };
 
+        let mut flags = net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR;
+        if multi_queue {
+            flags |= net_gen::IFF_MULTI_QUEUE;
+        }
         let ifreq = IfReqBuilder::new()
             .if_name(&terminated_if_name)
-            .flags((net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR) as i16)
+            .flags(flags as i16)
             .execute(&tuntap, TUNSETIFF())?;
 
         Ok(Tap {
@@ -232,6 +254,21 @@ impl Tap {
 
         Ok(())
     }
+
+    /// Attach this queue to its multi-queue interface, or detach it. The interface only
+    /// steers received frames to its attached queues.
+    pub fn set_queue_enabled(&self, enabled: bool) -> Result<()> {
+        let flags = if enabled {
+            net_gen::IFF_ATTACH_QUEUE
+        } else {
+            net_gen::IFF_DETACH_QUEUE
+        };
+        IfReqBuilder::new()
+            .flags(flags as i16)
+            .execute(&self.tap_file, TUNSETQUEUE())?;
+
+        Ok(())
+    }
 }
 
 impl Read for Tap {
@@ -306,6 +343,30 @@ pub mod tests {
         Tap::open_named("exclusivetap").unwrap_err();
     }
 
+    #[test]
+    fn test_tap_multi_queue() {
+        // A single queue doesn't need a multi-queue interface.
+        let taps = Tap::open_named_queues("singlequeuetap", 1).unwrap();
+        assert_eq!(taps.len(), 1);
+        // Detaching a queue is only possible on a multi-queue interface.
+        assert!(taps[0].set_queue_enabled(false).is_err());
+        drop(taps);
+
+        let taps = Tap::open_named_queues("multiqueuetap", 2).unwrap();
+        assert_eq!(taps.len(), 2);
+        assert_ne!(taps[0].as_raw_fd(), taps[1].as_raw_fd());
+        assert_eq!(taps[0].if_name, taps[1].if_name);
+
+        // The queues start attached, and can only be detached once.
+        taps[1].set_queue_enabled(false).unwrap();
+        assert!(taps[1].set_queue_enabled(false).is_err());
+        taps[1].set_queue_enabled(true).unwrap();
+        assert!(taps[1].set_queue_enabled(true).is_err());
+
+        // A multi-queue interface can't be opened as a single queue one.
+        Tap::open_named("multiqueuetap").unwrap_err();
+    }
+
     #[test]
     fn test_set_options() {
         // This line will fail to provide an initialized FD if the test is not run as root.
@@ -0,0 +1,250 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Answers the DHCP requests of the guest, which is always leased the same address.
+
+use std::net::Ipv4Addr;
+
+use super::{DNS_ADDR, GATEWAY_ADDR, GUEST_ADDR, NETWORK_MASK};
+
+/// The port DHCP servers listen on.
+pub const SERVER_PORT: u16 = 67;
+/// The port DHCP clients listen on.
+pub const CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+// Offsets of the fields of a BOOTP message.
+const OP_OFFSET: usize = 0;
+const HTYPE_OFFSET: usize = 1;
+const HLEN_OFFSET: usize = 2;
+const XID_OFFSET: usize = 4;
+const FLAGS_OFFSET: usize = 10;
+const CIADDR_OFFSET: usize = 12;
+const YIADDR_OFFSET: usize = 16;
+const GIADDR_OFFSET: usize = 24;
+const CHADDR_OFFSET: usize = 28;
+const CHADDR_LEN: usize = 16;
+const MAGIC_COOKIE_OFFSET: usize = 236;
+const OPTIONS_OFFSET: usize = 240;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDR: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+const LEASE_TIME_SECS: u32 = 86400;
+// Some clients discard the BOOTP messages shorter than this.
+const MIN_MESSAGE_LEN: usize = 300;
+
+/// Returns the reply to a DHCP message of the guest, or `None` if the message is malformed or
+/// doesn't call for a reply.
+///
+/// A `DHCPDISCOVER` is answered with a `DHCPOFFER` of `GUEST_ADDR`, and a `DHCPREQUEST` with a
+/// `DHCPACK`, unless it asks for another address, in which case it gets a `DHCPNAK`.
+pub fn reply(request: &[u8]) -> Option<Vec<u8>> {
+    if request.len() < OPTIONS_OFFSET
+        || request[OP_OFFSET] != BOOTREQUEST
+        || request[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET] != MAGIC_COOKIE
+    {
+        return None;
+    }
+
+    let mut message_type = None;
+    let mut requested_addr = None;
+    for (code, value) in options(&request[OPTIONS_OFFSET..]) {
+        match code {
+            OPTION_MESSAGE_TYPE => message_type = value.first().copied(),
+            OPTION_REQUESTED_ADDR => {
+                requested_addr = <[u8; 4]>::try_from(value).ok().map(Ipv4Addr::from)
+            }
+            _ => (),
+        }
+    }
+    // A client renewing its lease puts its address in `ciaddr` instead.
+    let ciaddr = ipv4_addr_at(request, CIADDR_OFFSET);
+    let requested_addr = requested_addr.or_else(|| Some(ciaddr).filter(|a| !a.is_unspecified()));
+
+    let reply_type = match message_type? {
+        DHCPDISCOVER => DHCPOFFER,
+        DHCPREQUEST if requested_addr.map_or(true, |addr| addr == GUEST_ADDR) => DHCPACK,
+        DHCPREQUEST => DHCPNAK,
+        _ => return None,
+    };
+
+    let mut reply = vec![0u8; OPTIONS_OFFSET];
+    reply[OP_OFFSET] = BOOTREPLY;
+    reply[HTYPE_OFFSET] = request[HTYPE_OFFSET];
+    reply[HLEN_OFFSET] = request[HLEN_OFFSET];
+    // The transaction ID, the flags, the relay agent and the hardware address of the client are
+    // echoed back.
+    reply[XID_OFFSET..XID_OFFSET + 4].copy_from_slice(&request[XID_OFFSET..XID_OFFSET + 4]);
+    reply[FLAGS_OFFSET..FLAGS_OFFSET + 2].copy_from_slice(&request[FLAGS_OFFSET..FLAGS_OFFSET + 2]);
+    reply[GIADDR_OFFSET..CHADDR_OFFSET + CHADDR_LEN]
+        .copy_from_slice(&request[GIADDR_OFFSET..CHADDR_OFFSET + CHADDR_LEN]);
+    reply[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+
+    push_option(&mut reply, OPTION_MESSAGE_TYPE, &[reply_type]);
+    push_option(&mut reply, OPTION_SERVER_ID, &GATEWAY_ADDR.octets());
+    if reply_type != DHCPNAK {
+        reply[YIADDR_OFFSET..YIADDR_OFFSET + 4].copy_from_slice(&GUEST_ADDR.octets());
+        push_option(
+            &mut reply,
+            OPTION_LEASE_TIME,
+            &LEASE_TIME_SECS.to_be_bytes(),
+        );
+        push_option(&mut reply, OPTION_SUBNET_MASK, &NETWORK_MASK.octets());
+        push_option(&mut reply, OPTION_ROUTER, &GATEWAY_ADDR.octets());
+        push_option(&mut reply, OPTION_DNS, &DNS_ADDR.octets());
+    }
+    reply.push(OPTION_END);
+    if reply.len() < MIN_MESSAGE_LEN {
+        reply.resize(MIN_MESSAGE_LEN, OPTION_PAD);
+    }
+
+    Some(reply)
+}
+
+// Iterates over the code and the value of the options of a message, until the end option or the
+// first malformed option.
+fn options(mut bytes: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || loop {
+        let (&code, rest) = bytes.split_first()?;
+        match code {
+            OPTION_PAD => bytes = rest,
+            OPTION_END => return None,
+            _ => {
+                let (&len, rest) = rest.split_first()?;
+                let value = rest.get(..usize::from(len))?;
+                bytes = &rest[usize::from(len)..];
+                return Some((code, value));
+            }
+        }
+    })
+}
+
+fn push_option(message: &mut Vec<u8>, code: u8, value: &[u8]) {
+    message.push(code);
+    message.push(value.len() as u8);
+    message.extend_from_slice(value);
+}
+
+fn ipv4_addr_at(message: &[u8], offset: usize) -> Ipv4Addr {
+    let mut octets = [0u8; 4];
+    octets.copy_from_slice(&message[offset..offset + 4]);
+    Ipv4Addr::from(octets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHADDR: [u8; 6] = [0x06, 0x00, 0xac, 0x10, 0x00, 0x02];
+
+    fn request(message_type: u8, requested_addr: Option<Ipv4Addr>) -> Vec<u8> {
+        let mut request = vec![0u8; OPTIONS_OFFSET];
+        request[OP_OFFSET] = BOOTREQUEST;
+        request[HTYPE_OFFSET] = 1;
+        request[HLEN_OFFSET] = 6;
+        request[XID_OFFSET..XID_OFFSET + 4].copy_from_slice(&[1, 2, 3, 4]);
+        request[FLAGS_OFFSET] = 0x80;
+        request[CHADDR_OFFSET..CHADDR_OFFSET + 6].copy_from_slice(&CHADDR);
+        request[MAGIC_COOKIE_OFFSET..OPTIONS_OFFSET].copy_from_slice(&MAGIC_COOKIE);
+        push_option(&mut request, OPTION_MESSAGE_TYPE, &[message_type]);
+        // Padding between the options is skipped.
+        request.push(OPTION_PAD);
+        if let Some(addr) = requested_addr {
+            push_option(&mut request, OPTION_REQUESTED_ADDR, &addr.octets());
+        }
+        request.push(OPTION_END);
+        request
+    }
+
+    fn reply_options(reply: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        options(&reply[OPTIONS_OFFSET..])
+            .map(|(code, value)| (code, value.to_vec()))
+            .collect()
+    }
+
+    #[test]
+    fn test_options() {
+        let bytes = [OPTION_PAD, 53, 1, 3, 50, 4, 10, 0, 2, 15, OPTION_END, 1, 1];
+        let parsed: Vec<_> = options(&bytes).collect();
+        assert_eq!(parsed, vec![(53, &[3u8][..]), (50, &[10u8, 0, 2, 15][..])]);
+
+        // The value of the last option is truncated.
+        let bytes = [53, 1, 3, 50, 4, 10, 0];
+        assert_eq!(options(&bytes).count(), 1);
+    }
+
+    #[test]
+    fn test_reply() {
+        let message = reply(&request(DHCPDISCOVER, None)).unwrap();
+        assert_eq!(message.len(), MIN_MESSAGE_LEN);
+        assert_eq!(message[OP_OFFSET], BOOTREPLY);
+        assert_eq!(&message[XID_OFFSET..XID_OFFSET + 4], &[1, 2, 3, 4]);
+        assert_eq!(message[FLAGS_OFFSET], 0x80);
+        assert_eq!(&message[CHADDR_OFFSET..CHADDR_OFFSET + 6], &CHADDR);
+        assert_eq!(ipv4_addr_at(&message, YIADDR_OFFSET), GUEST_ADDR);
+        assert_eq!(
+            reply_options(&message),
+            vec![
+                (OPTION_MESSAGE_TYPE, vec![DHCPOFFER]),
+                (OPTION_SERVER_ID, GATEWAY_ADDR.octets().to_vec()),
+                (OPTION_LEASE_TIME, LEASE_TIME_SECS.to_be_bytes().to_vec()),
+                (OPTION_SUBNET_MASK, NETWORK_MASK.octets().to_vec()),
+                (OPTION_ROUTER, GATEWAY_ADDR.octets().to_vec()),
+                (OPTION_DNS, DNS_ADDR.octets().to_vec()),
+            ]
+        );
+
+        let message = reply(&request(DHCPREQUEST, Some(GUEST_ADDR))).unwrap();
+        assert_eq!(
+            reply_options(&message)[0],
+            (OPTION_MESSAGE_TYPE, vec![DHCPACK])
+        );
+
+        // A renewal names the leased address in `ciaddr`.
+        let mut renewal = request(DHCPREQUEST, None);
+        renewal[CIADDR_OFFSET..CIADDR_OFFSET + 4].copy_from_slice(&GUEST_ADDR.octets());
+        let message = reply(&renewal).unwrap();
+        assert_eq!(
+            reply_options(&message)[0],
+            (OPTION_MESSAGE_TYPE, vec![DHCPACK])
+        );
+
+        // Other addresses are refused.
+        let message = reply(&request(DHCPREQUEST, Some(Ipv4Addr::new(10, 0, 2, 16)))).unwrap();
+        assert_eq!(ipv4_addr_at(&message, YIADDR_OFFSET), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(
+            reply_options(&message),
+            vec![
+                (OPTION_MESSAGE_TYPE, vec![DHCPNAK]),
+                (OPTION_SERVER_ID, GATEWAY_ADDR.octets().to_vec()),
+            ]
+        );
+
+        // Messages which don't call for a reply.
+        assert!(reply(&request(7, None)).is_none());
+        let mut bootreply = request(DHCPDISCOVER, None);
+        bootreply[OP_OFFSET] = BOOTREPLY;
+        assert!(reply(&bootreply).is_none());
+        let mut no_cookie = request(DHCPDISCOVER, None);
+        no_cookie[MAGIC_COOKIE_OFFSET] = 0;
+        assert!(reply(&no_cookie).is_none());
+        assert!(reply(&request(DHCPDISCOVER, None)[..OPTIONS_OFFSET - 1]).is_none());
+    }
+}
@@ -0,0 +1,1078 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A userspace network stack, which lets a net device reach the network without a tap.
+//!
+//! The guest sees a NATed network in the style of slirp: the guest is leased `GUEST_ADDR` over
+//! DHCP, and the gateway at `GATEWAY_ADDR` can also stand for the loopback interface of the host.
+//! The DNS queries sent to `DNS_ADDR` are forwarded to the first IPv4 name server of the host.
+//! The destinations the guest can reach are restricted by a `UserNetworkConfig`.
+//!
+//! The TCP connections of the guest are terminated by dumbo and proxied to host sockets, while
+//! the UDP datagrams of each flow of the guest are relayed through a host socket of their own.
+//! The host sockets, along with a timer driving the retransmissions and the cleanup of the idle
+//! flows, are polled through a nested epoll FD, which the net device registers to the event
+//! manager in place of a tap.
+
+mod dhcp;
+mod tcp;
+mod udp;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::num::NonZeroUsize;
+use std::ops::Bound;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+use std::{fs, io};
+
+use dumbo::pdu::arp::{Error as ArpFrameError, EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
+use dumbo::pdu::ethernet::{
+    Error as EthernetFrameError, EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4,
+};
+use dumbo::pdu::ipv4::{Error as IPv4PacketError, IPv4Packet, PROTOCOL_TCP, PROTOCOL_UDP};
+use dumbo::pdu::tcp::{Error as TcpSegmentError, Flags as TcpFlags, TcpSegment};
+use dumbo::pdu::udp::{Error as UdpDatagramError, UdpDatagram, UDP_HEADER_SIZE};
+use dumbo::tcp::connection::WriteNextError;
+use logger::{error, warn, IncMetric, METRICS};
+use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
+use utils::epoll::{ControlOperation, Epoll, EpollEvent, EventSet};
+use utils::net::ipv4addr::Ipv4Network;
+use utils::net::mac::MacAddr;
+use utils::time::{get_time_us, ClockType};
+
+use self::tcp::TcpProxy;
+use self::udp::UdpFlow;
+use crate::virtio::net::filter::ipv4_packet;
+
+/// The network the guest is attached to.
+pub const NETWORK_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 0);
+/// The mask of the network the guest is attached to.
+pub const NETWORK_MASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+/// The address of the gateway, which can also stand for the loopback interface of the host.
+pub const GATEWAY_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+/// The address of the DNS forwarder.
+pub const DNS_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+/// The address leased to the guest.
+pub const GUEST_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+
+// The MAC address the gateway and the DNS forwarder answer ARP requests with.
+const GATEWAY_MAC: [u8; 6] = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+const DNS_PORT: u16 = 53;
+// The "more fragments" flag of an IPv4 packet.
+const IPV4_FLAG_MORE_FRAGMENTS: u8 = 0b001;
+
+const MAX_TCP_CONNECTIONS: usize = 256;
+const MAX_UDP_FLOWS: usize = 256;
+// The UDP flows which stay idle for this long are closed.
+const UDP_FLOW_TIMEOUT_US: u64 = 60_000_000;
+// The ARP, DHCP and RST frames awaiting to be sent to the guest.
+const MAX_PENDING_FRAMES: usize = 64;
+const MAX_PENDING_FRAME_LEN: usize = 512;
+// The largest payload of a UDP datagram carried by an IPv4 packet.
+const MAX_UDP_PAYLOAD_LEN: usize = 65507;
+// The period of the timer, which ticks while there are flows.
+const TIMER_PERIOD: Duration = Duration::from_millis(200);
+const MAX_EVENTS: usize = 32;
+
+/// The destinations the guest can reach through a userspace network stack.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserNetworkConfig {
+    /// Whether the flows of the guest to `GATEWAY_ADDR` go to the loopback interface of the host.
+    pub host_loopback: bool,
+    /// The only networks the guest can reach. Without them, the guest can reach any address but
+    /// the link-local ones.
+    ///
+    /// The addresses of the host itself, such as the loopback ones, and the broadcast and
+    /// multicast addresses are never reachable.
+    pub allowed_networks: Option<Vec<Ipv4Network>>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to create the epoll FD polling the host sockets.
+    EpollCreate(io::Error),
+    /// Failed to add the timer to the epoll FD.
+    EpollAdd(io::Error),
+    /// Failed to create the timer.
+    Timer(io::Error),
+}
+
+#[derive(derive_more::From)]
+enum WriteFrameError {
+    Arp(ArpFrameError),
+    BufferTooSmall,
+    Ethernet(EthernetFrameError),
+    IPv4Packet(IPv4PacketError),
+    TcpSegment(TcpSegmentError),
+    TcpWriteNext(WriteNextError),
+    UdpDatagram(UdpDatagramError),
+}
+
+// A flow of the guest, identified by the address of the guest and by the remote address, as the
+// guest sees it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct FlowKey {
+    guest: SocketAddrV4,
+    remote: SocketAddrV4,
+}
+
+// The flow a host socket belongs to.
+#[derive(Clone, Copy, Debug)]
+enum Flow {
+    Tcp(FlowKey),
+    Udp(FlowKey),
+}
+
+/// A userspace network stack, which NATs the TCP and UDP traffic of the guest to host sockets,
+/// and answers its ARP and DHCP requests.
+pub struct UserNetworkStack {
+    // The MAC address of the gateway, which is the source of the frames sent to the guest.
+    mac_addr: MacAddr,
+    // The MAC address the guest sends from, which is the destination of the frames sent to the
+    // guest. It is the broadcast address until the guest sends a frame.
+    guest_mac: MacAddr,
+    // The host name server the DNS queries of the guest are forwarded to.
+    dns_server: Option<Ipv4Addr>,
+    config: UserNetworkConfig,
+    // The frames awaiting to be sent to the guest, ahead of the TCP and UDP traffic.
+    pending_frames: VecDeque<Vec<u8>>,
+    tcp_proxies: BTreeMap<FlowKey, TcpProxy>,
+    udp_flows: BTreeMap<FlowKey, UdpFlow>,
+    // The flows which last sent a frame to the guest. The next frames are taken from the flows
+    // which follow, so that no flow starves the others.
+    last_tcp_flow: Option<FlowKey>,
+    last_udp_flow: Option<FlowKey>,
+    // The flows the host sockets polled by `epoll` belong to.
+    host_sockets: HashMap<RawFd, Flow>,
+    epoll: Epoll,
+    timer: TimerFd,
+    timer_armed: bool,
+    // Holds a datagram received from the host until it is written to a frame.
+    udp_buf: Vec<u8>,
+}
+
+impl UserNetworkStack {
+    /// Creates a network stack, which forwards the DNS queries of the guest to the name server
+    /// found in the `/etc/resolv.conf` file of the host, and its other flows to the destinations
+    /// `config` lets it reach.
+    pub fn new(config: UserNetworkConfig) -> Result<Self, Error> {
+        let epoll = Epoll::new().map_err(Error::EpollCreate)?;
+        let timer = TimerFd::new_custom(ClockId::Monotonic, true, true).map_err(Error::Timer)?;
+        epoll
+            .ctl(
+                ControlOperation::Add,
+                timer.as_raw_fd(),
+                EpollEvent::new(EventSet::IN, timer.as_raw_fd() as u64),
+            )
+            .map_err(Error::EpollAdd)?;
+
+        let dns_server = match fs::read_to_string(RESOLV_CONF_PATH) {
+            Ok(resolv_conf) => parse_nameserver(&resolv_conf),
+            Err(err) => {
+                warn!("Failed to read {}: {}", RESOLV_CONF_PATH, err);
+                None
+            }
+        };
+        if dns_server.is_none() {
+            warn!("No IPv4 name server found, the DNS queries of the guest are dropped.");
+        }
+
+        Ok(UserNetworkStack {
+            mac_addr: MacAddr::from(GATEWAY_MAC),
+            guest_mac: MacAddr::from([0xff; 6]),
+            dns_server,
+            config,
+            pending_frames: VecDeque::new(),
+            tcp_proxies: BTreeMap::new(),
+            udp_flows: BTreeMap::new(),
+            last_tcp_flow: None,
+            last_udp_flow: None,
+            host_sockets: HashMap::new(),
+            epoll,
+            timer,
+            timer_armed: false,
+            udp_buf: vec![0u8; MAX_UDP_PAYLOAD_LEN],
+        })
+    }
+
+    /// Provides the destinations the guest can reach through this stack.
+    pub fn config(&self) -> &UserNetworkConfig {
+        &self.config
+    }
+
+    /// Handles a frame sent by the guest, stripped of its VNET header.
+    pub fn receive_frame(&mut self, frame: &[u8]) {
+        if !self.handle_frame(frame) {
+            METRICS.user_net.rx_frames_dropped.inc();
+        }
+    }
+
+    /// Writes the next frame for the guest to `buf`, if there is one, and returns its length.
+    ///
+    /// The ARP, DHCP and RST frames go first, followed by the TCP segments and then by the UDP
+    /// datagrams, each flow taking its turn.
+    pub fn write_next_frame(&mut self, buf: &mut [u8]) -> Option<NonZeroUsize> {
+        if let Some(frame) = self.pending_frames.pop_front() {
+            match buf.get_mut(..frame.len()) {
+                Some(dst) => {
+                    dst.copy_from_slice(&frame);
+                    return NonZeroUsize::new(frame.len());
+                }
+                None => METRICS.user_net.tx_errors.inc(),
+            }
+        }
+
+        let now_us = get_time_us(ClockType::Monotonic);
+        let (mac_addr, guest_mac) = (self.mac_addr, self.guest_mac);
+
+        let len = round_robin(
+            &mut self.tcp_proxies,
+            &mut self.last_tcp_flow,
+            |key, proxy| {
+                let result = write_ipv4_frame(
+                    buf,
+                    (mac_addr, guest_mac),
+                    PROTOCOL_TCP,
+                    (*key.remote.ip(), *key.guest.ip()),
+                    |payload| Ok(proxy.write_next_segment(payload, key.remote, key.guest, now_us)?),
+                );
+                result.unwrap_or_else(|_| {
+                    METRICS.user_net.tx_errors.inc();
+                    None
+                })
+            },
+        );
+        if len.is_some() {
+            // The connection may be over once its last segment was sent.
+            if let Some(key) = self.last_tcp_flow {
+                if self.tcp_proxies.get(&key).map_or(false, TcpProxy::is_done) {
+                    self.remove_tcp_proxy(&key);
+                }
+            }
+            return len;
+        }
+
+        let udp_buf = &mut self.udp_buf;
+        round_robin(&mut self.udp_flows, &mut self.last_udp_flow, |key, flow| {
+            let payload_len = flow.recv(udp_buf, now_us)?;
+            let result = write_ipv4_frame(
+                buf,
+                (mac_addr, guest_mac),
+                PROTOCOL_UDP,
+                (*key.remote.ip(), *key.guest.ip()),
+                |payload| {
+                    write_udp_datagram(payload, &udp_buf[..payload_len], key.remote, key.guest)
+                },
+            );
+            result.unwrap_or_else(|_| {
+                METRICS.user_net.tx_errors.inc();
+                None
+            })
+        })
+    }
+
+    /// Handles the events of the host sockets and of the timer, once the epoll FD is readable.
+    pub fn process_host_events(&mut self) {
+        let mut events = vec![EpollEvent::new(EventSet::empty(), 0); MAX_EVENTS];
+        let count = match self.epoll.wait(0, &mut events) {
+            Ok(count) => count,
+            Err(err) => {
+                error!("Failed to poll the host sockets: {}", err);
+                METRICS.user_net.event_fails.inc();
+                return;
+            }
+        };
+
+        for event in &events[..count] {
+            let fd = event.fd();
+            let event_set = EventSet::from_bits_truncate(event.events);
+            if fd == self.timer.as_raw_fd() {
+                self.process_timer_event();
+                continue;
+            }
+
+            match self.host_sockets.get(&fd) {
+                Some(Flow::Tcp(key)) => {
+                    if let Some(proxy) = self.tcp_proxies.get_mut(key) {
+                        proxy.process_host_event(event_set);
+                    }
+                }
+                Some(Flow::Udp(key)) => {
+                    if let Some(flow) = self.udp_flows.get_mut(key) {
+                        flow.set_readable();
+                    }
+                }
+                None => {
+                    warn!("Spurious event received for host socket {}", fd);
+                    METRICS.user_net.event_fails.inc();
+                }
+            }
+        }
+    }
+
+    fn handle_frame(&mut self, frame: &[u8]) -> bool {
+        let eth = match EthernetFrame::from_bytes(frame) {
+            Ok(eth) => eth,
+            Err(_) => return false,
+        };
+        self.guest_mac = eth.src_mac();
+
+        match eth.ethertype() {
+            ETHERTYPE_ARP => self.handle_arp(eth.payload()),
+            ETHERTYPE_IPV4 => match ipv4_packet(eth.payload()) {
+                Some(packet) => self.handle_ipv4(&packet),
+                None => false,
+            },
+            _ => false,
+        }
+    }
+
+    // Answers the ARP requests for the gateway and the DNS forwarder.
+    fn handle_arp(&mut self, bytes: &[u8]) -> bool {
+        // Short frames are padded up to the minimum Ethernet frame length.
+        let request = match bytes
+            .get(..ETH_IPV4_FRAME_LEN)
+            .and_then(|bytes| EthIPv4ArpFrame::request_from_bytes(bytes).ok())
+        {
+            Some(request) => request,
+            None => return false,
+        };
+        let tpa = request.tpa();
+        if tpa != GATEWAY_ADDR && tpa != DNS_ADDR {
+            return false;
+        }
+
+        let (mac_addr, sha, spa) = (self.mac_addr, request.sha(), request.spa());
+        self.queue_frame(|buf| {
+            let mut eth = EthernetFrame::write_incomplete(buf, sha, mac_addr, ETHERTYPE_ARP)?;
+            let arp_len = EthIPv4ArpFrame::write_reply(
+                eth.inner_mut()
+                    .payload_mut()
+                    .split_at_mut(ETH_IPV4_FRAME_LEN)
+                    .0,
+                mac_addr,
+                tpa,
+                sha,
+                spa,
+            )?
+            .len();
+            Ok(NonZeroUsize::new(
+                eth.with_payload_len_unchecked(arp_len).len(),
+            ))
+        })
+    }
+
+    fn handle_ipv4(&mut self, packet: &IPv4Packet<&[u8]>) -> bool {
+        // Fragmented packets are not reassembled.
+        let (flags, fragment_offset) = packet.flags_and_fragment_offset();
+        if flags & IPV4_FLAG_MORE_FRAGMENTS != 0 || fragment_offset != 0 {
+            return false;
+        }
+
+        match packet.protocol() {
+            PROTOCOL_TCP => self.handle_tcp(packet),
+            PROTOCOL_UDP => self.handle_udp(packet),
+            _ => false,
+        }
+    }
+
+    fn handle_udp(&mut self, packet: &IPv4Packet<&[u8]>) -> bool {
+        let datagram = match UdpDatagram::from_bytes(packet.payload(), None) {
+            Ok(datagram) => datagram,
+            Err(_) => return false,
+        };
+        let key = FlowKey {
+            guest: SocketAddrV4::new(packet.source_address(), datagram.source_port()),
+            remote: SocketAddrV4::new(packet.destination_address(), datagram.destination_port()),
+        };
+
+        if key.remote.port() == dhcp::SERVER_PORT
+            && (key.remote.ip().is_broadcast() || *key.remote.ip() == GATEWAY_ADDR)
+        {
+            return self.handle_dhcp(datagram.payload());
+        }
+
+        let now_us = get_time_us(ClockType::Monotonic);
+        if let Some(flow) = self.udp_flows.get_mut(&key) {
+            flow.send(datagram.payload(), now_us);
+            return true;
+        }
+
+        let host_addr = match self.host_addr(key.remote) {
+            Some(host_addr) => host_addr,
+            None => return false,
+        };
+        if self.udp_flows.len() >= MAX_UDP_FLOWS {
+            // The least recently active flow makes room for the new one.
+            let idle_key = self
+                .udp_flows
+                .iter()
+                .min_by_key(|(_, flow)| flow.last_active_us())
+                .map(|(key, _)| *key);
+            if let Some(idle_key) = idle_key {
+                self.remove_udp_flow(&idle_key);
+            }
+        }
+        let mut flow = match UdpFlow::new(host_addr, now_us) {
+            Ok(flow) => flow,
+            Err(err) => {
+                error!("Failed to open a UDP socket to {}: {}", host_addr, err);
+                METRICS.user_net.host_socket_fails.inc();
+                return false;
+            }
+        };
+        if !self.add_host_socket(flow.as_raw_fd(), EventSet::IN, Flow::Udp(key)) {
+            return false;
+        }
+        flow.send(datagram.payload(), now_us);
+        self.udp_flows.insert(key, flow);
+        METRICS.user_net.udp_flows_created.inc();
+        self.update_timer();
+        true
+    }
+
+    fn handle_dhcp(&mut self, request: &[u8]) -> bool {
+        let reply = match dhcp::reply(request) {
+            Some(reply) => reply,
+            None => return false,
+        };
+        METRICS.user_net.dhcp_replies.inc();
+
+        // The guest may not have an address yet, so the reply is broadcast.
+        let (mac_addr, guest_mac) = (self.mac_addr, self.guest_mac);
+        let src_addr = SocketAddrV4::new(GATEWAY_ADDR, dhcp::SERVER_PORT);
+        let dst_addr = SocketAddrV4::new(Ipv4Addr::BROADCAST, dhcp::CLIENT_PORT);
+        self.queue_frame(|buf| {
+            write_ipv4_frame(
+                buf,
+                (mac_addr, guest_mac),
+                PROTOCOL_UDP,
+                (*src_addr.ip(), *dst_addr.ip()),
+                |payload| write_udp_datagram(payload, &reply, src_addr, dst_addr),
+            )
+        })
+    }
+
+    fn handle_tcp(&mut self, packet: &IPv4Packet<&[u8]>) -> bool {
+        let segment = match TcpSegment::from_bytes(packet.payload(), None) {
+            Ok(segment) => segment,
+            Err(_) => return false,
+        };
+        let key = FlowKey {
+            guest: SocketAddrV4::new(packet.source_address(), segment.source_port()),
+            remote: SocketAddrV4::new(packet.destination_address(), segment.destination_port()),
+        };
+
+        if let Some(proxy) = self.tcp_proxies.get_mut(&key) {
+            proxy.receive_segment(&segment, get_time_us(ClockType::Monotonic));
+            return true;
+        }
+
+        let flags = segment.flags_after_ns();
+        if flags.intersects(TcpFlags::RST) {
+            // There's no connection to reset.
+            return true;
+        }
+        // Only a SYN opens a connection, and the guest can't have more than
+        // `MAX_TCP_CONNECTIONS` of them.
+        if flags & (TcpFlags::SYN | TcpFlags::ACK) != TcpFlags::SYN
+            || self.tcp_proxies.len() >= MAX_TCP_CONNECTIONS
+        {
+            return self.queue_tcp_reset(key, &segment);
+        }
+        let host_addr = match self.host_addr(key.remote) {
+            Some(host_addr) => host_addr,
+            None => return self.queue_tcp_reset(key, &segment),
+        };
+
+        let proxy = match TcpProxy::new(&segment, host_addr) {
+            Ok(proxy) => proxy,
+            Err(tcp::Error::InvalidSyn) => return self.queue_tcp_reset(key, &segment),
+            Err(tcp::Error::Connect(err)) => {
+                error!("Failed to connect to {}: {}", host_addr, err);
+                METRICS.user_net.tcp_connect_fails.inc();
+                return self.queue_tcp_reset(key, &segment);
+            }
+        };
+        let event_set = EventSet::IN | EventSet::OUT;
+        if !self.add_host_socket(proxy.as_raw_fd(), event_set, Flow::Tcp(key)) {
+            return self.queue_tcp_reset(key, &segment);
+        }
+        self.tcp_proxies.insert(key, proxy);
+        METRICS.user_net.tcp_connections_created.inc();
+        self.update_timer();
+        true
+    }
+
+    // Answers a segment which doesn't belong to any connection with a RST.
+    fn queue_tcp_reset(&mut self, key: FlowKey, segment: &TcpSegment<&[u8]>) -> bool {
+        let flags = segment.flags_after_ns();
+        let (seq, ack, rst_flags) = if flags.intersects(TcpFlags::ACK) {
+            (segment.ack_number(), 0, TcpFlags::RST)
+        } else {
+            // The SYN and the FIN take up a sequence number each.
+            let len = segment.payload_len()
+                + usize::from(flags.intersects(TcpFlags::SYN))
+                + usize::from(flags.intersects(TcpFlags::FIN));
+            let ack = segment.sequence_number().wrapping_add(len as u32);
+            (0, ack, TcpFlags::RST | TcpFlags::ACK)
+        };
+
+        let (mac_addr, guest_mac) = (self.mac_addr, self.guest_mac);
+        self.queue_frame(|buf| {
+            write_ipv4_frame(
+                buf,
+                (mac_addr, guest_mac),
+                PROTOCOL_TCP,
+                (*key.remote.ip(), *key.guest.ip()),
+                |payload| {
+                    let segment = TcpSegment::write_segment::<[u8]>(
+                        payload,
+                        key.remote.port(),
+                        key.guest.port(),
+                        seq,
+                        ack,
+                        rst_flags,
+                        0,
+                        None,
+                        0,
+                        None,
+                        Some((*key.remote.ip(), *key.guest.ip())),
+                    )?;
+                    Ok(Some(segment.len()))
+                },
+            )
+        })
+    }
+
+    // Maps a remote address, as the guest sees it, to the host address the flow goes to.
+    fn host_addr(&self, remote: SocketAddrV4) -> Option<SocketAddrV4> {
+        let ip = *remote.ip();
+        let host_addr = if ip == GATEWAY_ADDR {
+            Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, remote.port()))
+                .filter(|_| self.config.host_loopback)
+        } else if ip == DNS_ADDR {
+            // The name server is picked by the host, so the configuration doesn't apply to it.
+            self.dns_server
+                .filter(|_| remote.port() == DNS_PORT)
+                .map(|dns_server| SocketAddrV4::new(dns_server, DNS_PORT))
+        } else {
+            Some(remote).filter(|_| self.is_reachable(ip))
+        };
+
+        if host_addr.is_none() {
+            METRICS.user_net.unreachable_destinations.inc();
+        }
+        host_addr
+    }
+
+    // Tells whether the guest can reach an address outside of the network it is attached to.
+    fn is_reachable(&self, ip: Ipv4Addr) -> bool {
+        // The other addresses of the network, the addresses of the host itself, and the broadcast
+        // and multicast addresses are never reachable.
+        if is_in_network(ip)
+            || ip.is_broadcast()
+            || ip.is_multicast()
+            || ip.is_loopback()
+            || ip.octets()[0] == 0
+        {
+            return false;
+        }
+
+        match self.config.allowed_networks.as_ref() {
+            Some(networks) => networks.iter().any(|network| network.contains(ip)),
+            // The link-local addresses are where cloud providers usually serve the metadata of
+            // the host.
+            None => !ip.is_link_local(),
+        }
+    }
+
+    // Queues a frame for the guest, written by `write_frame`. Returns whether the frame was
+    // queued.
+    fn queue_frame<F>(&mut self, write_frame: F) -> bool
+    where
+        F: FnOnce(&mut [u8]) -> Result<Option<NonZeroUsize>, WriteFrameError>,
+    {
+        if self.pending_frames.len() >= MAX_PENDING_FRAMES {
+            return false;
+        }
+
+        let mut frame = vec![0u8; MAX_PENDING_FRAME_LEN];
+        match write_frame(&mut frame) {
+            Ok(Some(len)) => {
+                frame.truncate(len.get());
+                self.pending_frames.push_back(frame);
+                true
+            }
+            Ok(None) => false,
+            Err(_) => {
+                METRICS.user_net.tx_errors.inc();
+                false
+            }
+        }
+    }
+
+    // Polls a host socket in edge triggered mode.
+    fn add_host_socket(&mut self, fd: RawFd, event_set: EventSet, flow: Flow) -> bool {
+        let event = EpollEvent::new(event_set | EventSet::EDGE_TRIGGERED, fd as u64);
+        if let Err(err) = self.epoll.ctl(ControlOperation::Add, fd, event) {
+            error!("Failed to poll host socket {}: {}", fd, err);
+            METRICS.user_net.event_fails.inc();
+            return false;
+        }
+        self.host_sockets.insert(fd, flow);
+        true
+    }
+
+    fn remove_host_socket(&mut self, fd: RawFd) {
+        self.host_sockets.remove(&fd);
+        if let Err(err) = self
+            .epoll
+            .ctl(ControlOperation::Delete, fd, EpollEvent::default())
+        {
+            error!("Failed to stop polling host socket {}: {}", fd, err);
+            METRICS.user_net.event_fails.inc();
+        }
+    }
+
+    fn remove_tcp_proxy(&mut self, key: &FlowKey) {
+        if let Some(proxy) = self.tcp_proxies.remove(key) {
+            self.remove_host_socket(proxy.as_raw_fd());
+            METRICS.user_net.tcp_connections_destroyed.inc();
+        }
+    }
+
+    fn remove_udp_flow(&mut self, key: &FlowKey) {
+        if let Some(flow) = self.udp_flows.remove(key) {
+            self.remove_host_socket(flow.as_raw_fd());
+            METRICS.user_net.udp_flows_destroyed.inc();
+        }
+    }
+
+    fn process_timer_event(&mut self) {
+        self.timer.read();
+        self.remove_stale_flows(get_time_us(ClockType::Monotonic));
+    }
+
+    // Closes the idle UDP flows and the TCP connections which are over.
+    fn remove_stale_flows(&mut self, now_us: u64) {
+        let idle_udp_flows: Vec<FlowKey> = self
+            .udp_flows
+            .iter()
+            .filter(|(_, flow)| now_us.saturating_sub(flow.last_active_us()) >= UDP_FLOW_TIMEOUT_US)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in idle_udp_flows {
+            self.remove_udp_flow(&key);
+        }
+
+        let done_tcp_proxies: Vec<FlowKey> = self
+            .tcp_proxies
+            .iter()
+            .filter(|(_, proxy)| proxy.is_done())
+            .map(|(key, _)| *key)
+            .collect();
+        for key in done_tcp_proxies {
+            self.remove_tcp_proxy(&key);
+        }
+
+        self.update_timer();
+    }
+
+    // The timer only ticks while there are flows, since the flows are what it drives.
+    fn update_timer(&mut self) {
+        let armed = !self.tcp_proxies.is_empty() || !self.udp_flows.is_empty();
+        if armed == self.timer_armed {
+            return;
+        }
+
+        let timer_state = if armed {
+            TimerState::Periodic {
+                current: TIMER_PERIOD,
+                interval: TIMER_PERIOD,
+            }
+        } else {
+            TimerState::Disarmed
+        };
+        self.timer.set_state(timer_state, SetTimeFlags::Default);
+        self.timer_armed = armed;
+    }
+}
+
+impl AsRawFd for UserNetworkStack {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+fn is_in_network(addr: Ipv4Addr) -> bool {
+    u32::from(addr) & u32::from(NETWORK_MASK) == u32::from(NETWORK_ADDR)
+}
+
+// Returns the first IPv4 name server of a resolv.conf file.
+fn parse_nameserver(resolv_conf: &str) -> Option<Ipv4Addr> {
+    resolv_conf.lines().find_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("nameserver"), Some(addr)) => addr.parse().ok(),
+            _ => None,
+        }
+    })
+}
+
+// Calls `f` on the entries of `map`, starting after `last` and wrapping around, until it returns
+// a value. `last` then becomes the key of the entry which returned it.
+fn round_robin<V, T, F>(
+    map: &mut BTreeMap<FlowKey, V>,
+    last: &mut Option<FlowKey>,
+    mut f: F,
+) -> Option<T>
+where
+    F: FnMut(&FlowKey, &mut V) -> Option<T>,
+{
+    let start = *last;
+    let first_pass = match start {
+        Some(key) => map.range_mut((Bound::Excluded(key), Bound::Unbounded)),
+        None => map.range_mut(..),
+    };
+    for (key, value) in first_pass {
+        if let Some(ret) = f(key, value) {
+            *last = Some(*key);
+            return Some(ret);
+        }
+    }
+
+    if let Some(start) = start {
+        for (key, value) in map.range_mut(..=start) {
+            if let Some(ret) = f(key, value) {
+                *last = Some(*key);
+                return Some(ret);
+            }
+        }
+    }
+    None
+}
+
+// Writes an IPv4 packet for the guest to `buf`, whose payload is written by `write_payload`, and
+// returns the length of the frame. Nothing is written if there is no payload.
+fn write_ipv4_frame<F>(
+    buf: &mut [u8],
+    (src_mac, dst_mac): (MacAddr, MacAddr),
+    protocol: u8,
+    (src_addr, dst_addr): (Ipv4Addr, Ipv4Addr),
+    write_payload: F,
+) -> Result<Option<NonZeroUsize>, WriteFrameError>
+where
+    F: FnOnce(&mut [u8]) -> Result<Option<usize>, WriteFrameError>,
+{
+    let mut eth = EthernetFrame::write_incomplete(buf, dst_mac, src_mac, ETHERTYPE_IPV4)?;
+    let mut packet =
+        IPv4Packet::write_header(eth.inner_mut().payload_mut(), protocol, src_addr, dst_addr)?;
+    let payload_len = match write_payload(packet.inner_mut().payload_mut())? {
+        Some(payload_len) => payload_len,
+        None => return Ok(None),
+    };
+    let packet_len = packet.with_payload_len_unchecked(payload_len, true).len();
+    Ok(NonZeroUsize::new(
+        eth.with_payload_len_unchecked(packet_len).len(),
+    ))
+}
+
+fn write_udp_datagram(
+    buf: &mut [u8],
+    payload: &[u8],
+    src_addr: SocketAddrV4,
+    dst_addr: SocketAddrV4,
+) -> Result<Option<usize>, WriteFrameError> {
+    if buf.len() < UDP_HEADER_SIZE + payload.len() {
+        return Err(WriteFrameError::BufferTooSmall);
+    }
+    let datagram = UdpDatagram::write_incomplete_datagram(buf, payload)?.finalize(
+        src_addr.port(),
+        dst_addr.port(),
+        Some((*src_addr.ip(), *dst_addr.ip())),
+    );
+    Ok(Some(usize::from(datagram.len())))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use super::*;
+
+    const GUEST_MAC: [u8; 6] = [0x06, 0x00, 0x0a, 0x00, 0x02, 0x0f];
+    const FRAME_LEN: usize = 1514;
+
+    fn ipv4_frame<F>(protocol: u8, src_addr: Ipv4Addr, dst_addr: Ipv4Addr, write: F) -> Vec<u8>
+    where
+        F: FnOnce(&mut [u8]) -> Result<Option<usize>, WriteFrameError>,
+    {
+        let mut buf = vec![0u8; FRAME_LEN];
+        let len = write_ipv4_frame(
+            &mut buf,
+            (MacAddr::from(GUEST_MAC), MacAddr::from(GATEWAY_MAC)),
+            protocol,
+            (src_addr, dst_addr),
+            write,
+        )
+        .ok()
+        .flatten()
+        .unwrap();
+        buf.truncate(len.get());
+        buf
+    }
+
+    fn udp_frame(src_addr: SocketAddrV4, dst_addr: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+        ipv4_frame(PROTOCOL_UDP, *src_addr.ip(), *dst_addr.ip(), |buf| {
+            write_udp_datagram(buf, payload, src_addr, dst_addr)
+        })
+    }
+
+    fn next_frame(stack: &mut UserNetworkStack) -> Option<Vec<u8>> {
+        let mut buf = vec![0u8; FRAME_LEN];
+        let len = stack.write_next_frame(&mut buf)?;
+        buf.truncate(len.get());
+        Some(buf)
+    }
+
+    #[test]
+    fn test_parse_nameserver() {
+        let resolv_conf = "# Generated\nsearch example.com\nnameserver ::1\nnameserver  10.1.2.3 \
+                           \nnameserver 10.1.2.4\n";
+        assert_eq!(
+            parse_nameserver(resolv_conf),
+            Some(Ipv4Addr::new(10, 1, 2, 3))
+        );
+        assert_eq!(parse_nameserver("search example.com\nnameserver\n"), None);
+    }
+
+    #[test]
+    fn test_host_addr() {
+        let mut stack = UserNetworkStack::new(UserNetworkConfig::default()).unwrap();
+        stack.dns_server = Some(Ipv4Addr::new(10, 1, 2, 3));
+
+        // The loopback interface of the host is only reachable once allowed.
+        let remote = SocketAddrV4::new(GATEWAY_ADDR, 8080);
+        assert_eq!(stack.host_addr(remote), None);
+        stack.config.host_loopback = true;
+        assert_eq!(
+            stack.host_addr(remote),
+            Some(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 8080))
+        );
+        let remote = SocketAddrV4::new(DNS_ADDR, DNS_PORT);
+        assert_eq!(
+            stack.host_addr(remote),
+            Some(SocketAddrV4::new(Ipv4Addr::new(10, 1, 2, 3), DNS_PORT))
+        );
+        let remote = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 443);
+        assert_eq!(stack.host_addr(remote), Some(remote));
+
+        for addr in [
+            SocketAddrV4::new(DNS_ADDR, 80),
+            SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 100), 80),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, 80),
+            SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 1), 80),
+            SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80),
+            SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 1), 80),
+            SocketAddrV4::new(Ipv4Addr::new(169, 254, 169, 254), 80),
+        ] {
+            assert_eq!(stack.host_addr(addr), None);
+        }
+
+        // Only the allowed networks are reachable, and the host itself never is.
+        let link_local = SocketAddrV4::new(Ipv4Addr::new(169, 254, 169, 254), 80);
+        stack.config.allowed_networks = Some(vec![
+            Ipv4Network::parse_str("169.254.169.254/32").unwrap(),
+            Ipv4Network::parse_str("127.0.0.0/8").unwrap(),
+        ]);
+        assert_eq!(stack.host_addr(link_local), Some(link_local));
+        let remote = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 443);
+        assert_eq!(stack.host_addr(remote), None);
+        assert_eq!(
+            stack.host_addr(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80)),
+            None
+        );
+        // The name server is reachable regardless of the allowed networks.
+        let remote = SocketAddrV4::new(DNS_ADDR, DNS_PORT);
+        assert_eq!(
+            stack.host_addr(remote),
+            Some(SocketAddrV4::new(Ipv4Addr::new(10, 1, 2, 3), DNS_PORT))
+        );
+
+        stack.dns_server = None;
+        assert_eq!(stack.host_addr(SocketAddrV4::new(DNS_ADDR, DNS_PORT)), None);
+    }
+
+    #[test]
+    fn test_arp() {
+        let mut stack = UserNetworkStack::new(UserNetworkConfig::default()).unwrap();
+        let guest_mac = MacAddr::from(GUEST_MAC);
+        let mut buf = [0u8; 60];
+
+        let mut eth =
+            EthernetFrame::write_incomplete(&mut buf[..], stack.mac_addr, guest_mac, ETHERTYPE_ARP)
+                .unwrap();
+        EthIPv4ArpFrame::write_request(
+            &mut eth.inner_mut().payload_mut()[..ETH_IPV4_FRAME_LEN],
+            guest_mac,
+            GUEST_ADDR,
+            MacAddr::from([0u8; 6]),
+            GATEWAY_ADDR,
+        )
+        .unwrap();
+        stack.receive_frame(&buf);
+
+        let frame = next_frame(&mut stack).unwrap();
+        let eth = EthernetFrame::from_bytes(frame.as_slice()).unwrap();
+        assert_eq!(eth.dst_mac(), guest_mac);
+        assert_eq!(eth.ethertype(), ETHERTYPE_ARP);
+        let arp = EthIPv4ArpFrame::from_bytes(eth.payload()).unwrap();
+        assert_eq!(arp.sha(), MacAddr::from(GATEWAY_MAC));
+        assert_eq!(arp.spa(), GATEWAY_ADDR);
+        assert_eq!(arp.tha(), guest_mac);
+        assert_eq!(arp.tpa(), GUEST_ADDR);
+        assert!(next_frame(&mut stack).is_none());
+
+        // The other addresses of the network are not answered for.
+        EthIPv4ArpFrame::write_request(
+            &mut buf[14..14 + ETH_IPV4_FRAME_LEN],
+            guest_mac,
+            GUEST_ADDR,
+            MacAddr::from([0u8; 6]),
+            Ipv4Addr::new(10, 0, 2, 100),
+        )
+        .unwrap();
+        stack.receive_frame(&buf);
+        assert!(next_frame(&mut stack).is_none());
+    }
+
+    #[test]
+    fn test_dhcp() {
+        let mut stack = UserNetworkStack::new(UserNetworkConfig::default()).unwrap();
+        let mut request = vec![0u8; 240];
+        request[0] = 1;
+        request[236..240].copy_from_slice(&[99, 130, 83, 99]);
+        request.extend_from_slice(&[53, 1, 1, 255]);
+        let frame = udp_frame(
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, dhcp::CLIENT_PORT),
+            SocketAddrV4::new(Ipv4Addr::BROADCAST, dhcp::SERVER_PORT),
+            &request,
+        );
+        stack.receive_frame(&frame);
+
+        let frame = next_frame(&mut stack).unwrap();
+        let eth = EthernetFrame::from_bytes(frame.as_slice()).unwrap();
+        assert_eq!(eth.src_mac(), MacAddr::from(GATEWAY_MAC));
+        // The guest MAC is learned from the request.
+        assert_eq!(eth.dst_mac(), MacAddr::from(GUEST_MAC));
+        let packet = IPv4Packet::from_bytes(eth.payload(), true).unwrap();
+        assert_eq!(packet.source_address(), GATEWAY_ADDR);
+        assert_eq!(packet.destination_address(), Ipv4Addr::BROADCAST);
+        let datagram =
+            UdpDatagram::from_bytes(packet.payload(), Some((GATEWAY_ADDR, Ipv4Addr::BROADCAST)))
+                .unwrap();
+        assert_eq!(datagram.source_port(), dhcp::SERVER_PORT);
+        assert_eq!(datagram.destination_port(), dhcp::CLIENT_PORT);
+        assert_eq!(
+            datagram.payload(),
+            dhcp::reply(&request).unwrap().as_slice()
+        );
+        assert!(next_frame(&mut stack).is_none());
+    }
+
+    #[test]
+    fn test_tcp_reset() {
+        let mut stack = UserNetworkStack::new(UserNetworkConfig::default()).unwrap();
+        let guest_addr = SocketAddrV4::new(GUEST_ADDR, 40000);
+        // The other addresses of the network are not reachable.
+        let remote_addr = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 100), 80);
+
+        let frame = ipv4_frame(PROTOCOL_TCP, *guest_addr.ip(), *remote_addr.ip(), |buf| {
+            let segment = TcpSegment::write_segment::<[u8]>(
+                buf,
+                guest_addr.port(),
+                remote_addr.port(),
+                1000,
+                0,
+                TcpFlags::SYN,
+                10000,
+                None,
+                0,
+                None,
+                Some((*guest_addr.ip(), *remote_addr.ip())),
+            )?;
+            Ok(Some(segment.len()))
+        });
+        stack.receive_frame(&frame);
+
+        let frame = next_frame(&mut stack).unwrap();
+        let eth = EthernetFrame::from_bytes(frame.as_slice()).unwrap();
+        let packet = IPv4Packet::from_bytes(eth.payload(), true).unwrap();
+        assert_eq!(packet.source_address(), *remote_addr.ip());
+        assert_eq!(packet.destination_address(), *guest_addr.ip());
+        let segment = TcpSegment::from_bytes(
+            packet.payload(),
+            Some((*remote_addr.ip(), *guest_addr.ip())),
+        )
+        .unwrap();
+        assert_eq!(segment.source_port(), remote_addr.port());
+        assert_eq!(segment.destination_port(), guest_addr.port());
+        assert_eq!(segment.flags_after_ns(), TcpFlags::RST | TcpFlags::ACK);
+        // The SYN takes up a sequence number.
+        assert_eq!(segment.ack_number(), 1001);
+        assert!(stack.tcp_proxies.is_empty());
+    }
+
+    #[test]
+    fn test_udp() {
+        let config = UserNetworkConfig {
+            host_loopback: true,
+            allowed_networks: None,
+        };
+        let mut stack = UserNetworkStack::new(config).unwrap();
+        let host = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let guest_addr = SocketAddrV4::new(GUEST_ADDR, 40000);
+        // The gateway stands for the loopback interface of the host, once allowed.
+        let remote_addr = SocketAddrV4::new(GATEWAY_ADDR, host.local_addr().unwrap().port());
+
+        stack.receive_frame(&udp_frame(guest_addr, remote_addr, b"ping"));
+        assert_eq!(stack.udp_flows.len(), 1);
+        assert!(stack.timer_armed);
+        let mut buf = [0u8; 16];
+        let (len, flow_addr) = host.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+
+        // The reply makes its way to the guest once the host socket is polled.
+        host.send_to(b"pong", flow_addr).unwrap();
+        let frame = loop {
+            stack.process_host_events();
+            if let Some(frame) = next_frame(&mut stack) {
+                break frame;
+            }
+        };
+        let eth = EthernetFrame::from_bytes(frame.as_slice()).unwrap();
+        let packet = IPv4Packet::from_bytes(eth.payload(), true).unwrap();
+        assert_eq!(packet.source_address(), *remote_addr.ip());
+        assert_eq!(packet.destination_address(), *guest_addr.ip());
+        let datagram = UdpDatagram::from_bytes(
+            packet.payload(),
+            Some((*remote_addr.ip(), *guest_addr.ip())),
+        )
+        .unwrap();
+        assert_eq!(datagram.source_port(), remote_addr.port());
+        assert_eq!(datagram.destination_port(), guest_addr.port());
+        assert_eq!(datagram.payload(), b"pong");
+
+        // Idle flows are closed once the timer ticks.
+        let now_us = get_time_us(ClockType::Monotonic);
+        stack.remove_stale_flows(now_us);
+        assert_eq!(stack.udp_flows.len(), 1);
+        stack.remove_stale_flows(now_us + UDP_FLOW_TIMEOUT_US);
+        assert!(stack.udp_flows.is_empty());
+        assert!(stack.host_sockets.is_empty());
+        assert!(!stack.timer_armed);
+    }
+}
@@ -0,0 +1,313 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Proxies a TCP connection of the guest to a host socket.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddrV4, TcpStream};
+use std::num::{NonZeroU16, NonZeroU64, Wrapping};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::{cmp, mem, ptr};
+
+use dumbo::pdu::tcp::TcpSegment;
+use dumbo::tcp::connection::{Connection, WriteNextError};
+use dumbo::tcp::seq_after;
+use logger::{IncMetric, METRICS};
+use utils::epoll::EventSet;
+
+// The guest data which the host socket didn't take yet is held in a buffer of this size, which
+// is also the receive window advertised to the guest.
+const RECV_BUF_SIZE: usize = 65535;
+// At most this much host data is held until the guest acknowledges it.
+const SEND_BUF_SIZE: usize = 65536;
+// The retransmission timeout, in microseconds, and how many retransmissions reset the
+// connection.
+const RTO_PERIOD_US: u64 = 1_000_000;
+const RTO_COUNT_MAX: u16 = 15;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The segment opening the connection is not a valid SYN.
+    InvalidSyn,
+    /// Failed to start connecting to the host.
+    Connect(io::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HostState {
+    // The host socket is connecting. The guest doesn't get a SYN-ACK until it is connected.
+    Connecting,
+    Connected,
+    // The host socket failed, and the connection of the guest is reset.
+    Failed,
+}
+
+/// A TCP connection opened by the guest, whose data is relayed to a host socket.
+///
+/// The connection of the guest is terminated by a dumbo `Connection`, which is only accepted
+/// once the host socket connected. The data of the host is read as the guest can take it, and
+/// the end of stream of either side is passed along to the other one.
+pub struct TcpProxy {
+    connection: Connection,
+    stream: TcpStream,
+    host_state: HostState,
+    // Whether the host socket may have data to read. The socket is polled in edge triggered mode,
+    // so this holds until a read would block.
+    host_readable: bool,
+    // Whether the host closed its side of the connection.
+    host_eof: bool,
+    // Whether the write side of the host socket was shut down, after the FIN of the guest.
+    host_write_shutdown: bool,
+    // The host data the guest didn't acknowledge yet, starting at sequence number
+    // `send_buf_seq`.
+    send_buf: Vec<u8>,
+    send_buf_seq: Wrapping<u32>,
+    // The guest data the host socket didn't take yet.
+    recv_buf: Box<[u8]>,
+    recv_len: usize,
+}
+
+impl TcpProxy {
+    /// Accepts the connection opened by `syn`, and starts connecting to `host_addr`.
+    pub fn new(syn: &TcpSegment<&[u8]>, host_addr: SocketAddrV4) -> Result<Self, Error> {
+        let connection = Connection::passive_open(
+            syn,
+            RECV_BUF_SIZE as u32,
+            // The unwraps are safe because the values are not 0.
+            NonZeroU64::new(RTO_PERIOD_US).unwrap(),
+            NonZeroU16::new(RTO_COUNT_MAX).unwrap(),
+        )
+        .map_err(|_| Error::InvalidSyn)?;
+        let stream = connect_nonblocking(host_addr).map_err(Error::Connect)?;
+        let send_buf_seq = connection.first_not_sent();
+
+        Ok(TcpProxy {
+            connection,
+            stream,
+            host_state: HostState::Connecting,
+            host_readable: false,
+            host_eof: false,
+            host_write_shutdown: false,
+            send_buf: Vec::new(),
+            send_buf_seq,
+            recv_buf: vec![0u8; RECV_BUF_SIZE].into_boxed_slice(),
+            recv_len: 0,
+        })
+    }
+
+    /// Tells whether the proxy is no longer needed, once the connection was reset or closed by
+    /// both sides.
+    pub fn is_done(&self) -> bool {
+        self.connection.is_reset()
+            || (self.connection.is_done()
+                && self.connection.highest_ack_received() == self.connection.first_not_sent())
+    }
+
+    /// Handles an event of the host socket.
+    pub fn process_host_event(&mut self, event_set: EventSet) {
+        if self.host_state == HostState::Connecting
+            && event_set.intersects(EventSet::OUT | EventSet::ERROR | EventSet::HANG_UP)
+        {
+            match self.stream.take_error() {
+                Ok(None) => self.host_state = HostState::Connected,
+                _ => {
+                    METRICS.user_net.tcp_connect_fails.inc();
+                    self.fail();
+                }
+            }
+        }
+
+        if self.host_state == HostState::Connected {
+            if event_set.intersects(EventSet::IN | EventSet::HANG_UP | EventSet::ERROR) {
+                self.host_readable = true;
+            }
+            if event_set.contains(EventSet::OUT) {
+                self.flush_to_host();
+            }
+        }
+    }
+
+    /// Handles a segment the guest sent on this connection.
+    pub fn receive_segment(&mut self, segment: &TcpSegment<&[u8]>, now_us: u64) {
+        // Segments which don't fit in the receive window are dropped, and so are the segments
+        // which arrive once the connection was reset.
+        if let Ok((Some(len), _)) =
+            self.connection
+                .receive_segment(segment, &mut self.recv_buf[self.recv_len..], now_us)
+        {
+            self.recv_len += len.get();
+        }
+
+        // The host data acknowledged by the guest is no longer needed.
+        let ack = self.connection.highest_ack_received();
+        if seq_after(ack, self.send_buf_seq) {
+            // The FIN takes up a sequence number past the data.
+            let acked = cmp::min((ack - self.send_buf_seq).0 as usize, self.send_buf.len());
+            self.send_buf.drain(..acked);
+            self.send_buf_seq += Wrapping(acked as u32);
+        }
+
+        if self.host_state == HostState::Connected {
+            self.flush_to_host();
+        }
+    }
+
+    /// Writes the next segment for the guest to `buf`, if there is one, and returns its length.
+    pub fn write_next_segment(
+        &mut self,
+        buf: &mut [u8],
+        src_addr: SocketAddrV4,
+        dst_addr: SocketAddrV4,
+        now_us: u64,
+    ) -> Result<Option<usize>, WriteNextError> {
+        if self.host_state == HostState::Connecting || self.connection.is_reset() {
+            return Ok(None);
+        }
+
+        if self.host_state == HostState::Connected {
+            self.read_from_host();
+        }
+        // The FIN is only sent after the data of the host, since no data can follow it.
+        let send_buf_end = self.send_buf_seq + Wrapping(self.send_buf.len() as u32);
+        if self.host_eof && send_buf_end == self.connection.first_not_sent() {
+            self.connection.close();
+        }
+
+        let payload_src = if self.send_buf.is_empty() {
+            None
+        } else {
+            Some((self.send_buf.as_slice(), self.send_buf_seq))
+        };
+        match self
+            .connection
+            .write_next_segment(buf, 0, payload_src, now_us)
+        {
+            Ok(Some(segment)) => Ok(Some(
+                segment
+                    .finalize(
+                        src_addr.port(),
+                        dst_addr.port(),
+                        Some((*src_addr.ip(), *dst_addr.ip())),
+                    )
+                    .len(),
+            )),
+            Ok(None) => Ok(None),
+            Err(err) => {
+                // The guest gets a RST instead.
+                self.connection.reset();
+                Err(err)
+            }
+        }
+    }
+
+    // Resets the connection of the guest, once the host socket failed.
+    fn fail(&mut self) {
+        self.host_state = HostState::Failed;
+        self.connection.reset();
+    }
+
+    // Reads the host data until the socket is drained or the send buffer is full.
+    fn read_from_host(&mut self) {
+        while self.host_readable && !self.host_eof && self.send_buf.len() < SEND_BUF_SIZE {
+            let len = self.send_buf.len();
+            self.send_buf.resize(SEND_BUF_SIZE, 0);
+            let result = self.stream.read(&mut self.send_buf[len..]);
+            self.send_buf
+                .truncate(len + result.as_ref().map_or(0, |&read| read));
+            match result {
+                Ok(0) => self.host_eof = true,
+                Ok(_) => (),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => self.host_readable = false,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(_) => {
+                    METRICS.user_net.host_socket_fails.inc();
+                    self.fail();
+                    return;
+                }
+            }
+        }
+    }
+
+    // Writes the guest data to the host socket, as much as it takes, and passes the FIN of the
+    // guest along once the data was all written.
+    fn flush_to_host(&mut self) {
+        let mut written = 0;
+        while written < self.recv_len {
+            match self.stream.write(&self.recv_buf[written..self.recv_len]) {
+                Ok(len) => written += len,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(_) => {
+                    METRICS.user_net.host_socket_fails.inc();
+                    self.fail();
+                    return;
+                }
+            }
+        }
+
+        if written > 0 {
+            self.recv_buf.copy_within(written..self.recv_len, 0);
+            self.recv_len -= written;
+            // The guest may send as much data as the host socket took.
+            self.connection.advance_local_rwnd_edge(written as u32);
+        }
+
+        if self.connection.fin_received() && self.recv_len == 0 && !self.host_write_shutdown {
+            self.host_write_shutdown = true;
+            if self.stream.shutdown(Shutdown::Write).is_err() {
+                METRICS.user_net.host_socket_fails.inc();
+                self.fail();
+            }
+        }
+    }
+}
+
+impl AsRawFd for TcpProxy {
+    fn as_raw_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+}
+
+// Starts connecting a non-blocking TCP socket to `addr`, so that the connection completes while
+// the VMM goes on. The socket becomes writable once it is connected.
+fn connect_nonblocking(addr: SocketAddrV4) -> io::Result<TcpStream> {
+    // SAFETY: Safe because the arguments are valid, and the return value is checked.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_INET,
+            libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: Safe because the file descriptor was just created, and nothing else owns it.
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+
+    let sockaddr = libc::sockaddr_in {
+        sin_family: libc::AF_INET as libc::sa_family_t,
+        sin_port: addr.port().to_be(),
+        sin_addr: libc::in_addr {
+            s_addr: u32::from(*addr.ip()).to_be(),
+        },
+        sin_zero: [0; 8],
+    };
+    // SAFETY: Safe because `sockaddr` is a valid IPv4 socket address, whose size is passed along,
+    // and the return value is checked.
+    let ret = unsafe {
+        libc::connect(
+            fd,
+            ptr::addr_of!(sockaddr).cast::<libc::sockaddr>(),
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EINPROGRESS) {
+            return Err(err);
+        }
+    }
+
+    Ok(stream)
+}
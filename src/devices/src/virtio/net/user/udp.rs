@@ -0,0 +1,131 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Relays the UDP datagrams of a flow of the guest through a host socket.
+
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use logger::{IncMetric, METRICS};
+
+/// The datagrams exchanged by a port of the guest with a remote endpoint.
+///
+/// They go through a host socket connected to the endpoint, so that the replies of the endpoint
+/// are the only datagrams the socket receives.
+pub struct UdpFlow {
+    socket: UdpSocket,
+    // Whether the socket may have datagrams to receive. The socket is polled in edge triggered
+    // mode, so this holds until a receive would block.
+    readable: bool,
+    // When a datagram last went through the flow, in microseconds.
+    last_active_us: u64,
+}
+
+impl UdpFlow {
+    /// Creates a flow to `host_addr`, through a non-blocking socket bound to an ephemeral port.
+    pub fn new(host_addr: SocketAddrV4, now_us: u64) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.connect(host_addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(UdpFlow {
+            socket,
+            readable: false,
+            last_active_us: now_us,
+        })
+    }
+
+    /// Provides the last time a datagram went through the flow, in microseconds.
+    pub fn last_active_us(&self) -> u64 {
+        self.last_active_us
+    }
+
+    /// Records that the socket has datagrams to receive.
+    pub fn set_readable(&mut self) {
+        self.readable = true;
+    }
+
+    /// Sends the payload of a datagram of the guest to the remote endpoint.
+    pub fn send(&mut self, payload: &[u8], now_us: u64) {
+        self.last_active_us = now_us;
+        if let Err(err) = self.socket.send(payload) {
+            // The datagram is lost, as it would be on the network.
+            if err.kind() != ErrorKind::WouldBlock {
+                METRICS.user_net.host_socket_fails.inc();
+            }
+        }
+    }
+
+    /// Receives the payload of the next datagram of the remote endpoint into `buf`, if there is
+    /// one, and returns its length.
+    pub fn recv(&mut self, buf: &mut [u8], now_us: u64) -> Option<usize> {
+        let mut retried = false;
+        while self.readable {
+            match self.socket.recv(buf) {
+                Ok(len) => {
+                    self.last_active_us = now_us;
+                    return Some(len);
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => self.readable = false,
+                Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                // An error of the socket, such as the one caused by an ICMP port unreachable, is
+                // cleared once it is reported, so the socket is read once more.
+                Err(_) if !retried => {
+                    METRICS.user_net.host_socket_fails.inc();
+                    retried = true;
+                }
+                Err(_) => {
+                    METRICS.user_net.host_socket_fails.inc();
+                    self.readable = false;
+                }
+            }
+        }
+        None
+    }
+}
+
+impl AsRawFd for UdpFlow {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_flow() {
+        let remote = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let remote_addr = match remote.local_addr().unwrap() {
+            std::net::SocketAddr::V4(addr) => addr,
+            _ => unreachable!(),
+        };
+        let mut flow = UdpFlow::new(remote_addr, 1).unwrap();
+        let mut buf = [0u8; 16];
+
+        flow.send(b"ping", 2);
+        assert_eq!(flow.last_active_us(), 2);
+        let (len, flow_addr) = remote.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"ping");
+
+        // Nothing is received until the socket is known to be readable.
+        remote.send_to(b"pong", flow_addr).unwrap();
+        assert_eq!(flow.recv(&mut buf, 3), None);
+        flow.set_readable();
+        // The datagram may take a while to be delivered over the loopback interface.
+        let len = loop {
+            if let Some(len) = flow.recv(&mut buf, 3) {
+                break len;
+            }
+            flow.set_readable();
+        };
+        assert_eq!(&buf[..len], b"pong");
+        assert_eq!(flow.last_active_us(), 3);
+
+        // The socket is drained.
+        assert_eq!(flow.recv(&mut buf, 4), None);
+        assert!(!flow.readable);
+        assert_eq!(flow.last_active_us(), 3);
+    }
+}
@@ -0,0 +1,499 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::cmp;
+use std::io::Write;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+use logger::{error, IncMetric, METRICS};
+use utils::eventfd::EventFd;
+use virtio_gen::virtio_blk::{
+    VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_GEOMETRY,
+    VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_SIZE_MAX,
+    VIRTIO_BLK_F_TOPOLOGY, VIRTIO_BLK_F_WRITE_ZEROES, VIRTIO_F_VERSION_1,
+};
+use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
+use vm_memory::GuestMemoryMmap;
+
+use super::vhost_user::{VhostUserFrontend, VHOST_USER_PROTOCOL_F_MQ};
+use super::{Error, QUEUE_SIZE, VIRTIO_RING_F_INDIRECT_DESC};
+use crate::virtio::block::{CONFIG_SPACE_SIZE, MAX_NUM_QUEUES};
+use crate::virtio::{
+    ActivateError, ActivateResult, CacheType, DeviceState, IrqTrigger, IrqType, Queue,
+    VirtioDevice, TYPE_BLOCK,
+};
+
+// Offset of the `num_queues` field in `struct virtio_blk_config`.
+const CONFIG_NUM_QUEUES_OFFSET: usize = 34;
+
+/// Virtio block device whose virtqueues are processed by a vhost-user backend.
+pub struct VhostUserBlock {
+    // Host resources.
+    id: String,
+    partuuid: Option<String>,
+    root_device: bool,
+    read_only: bool,
+    cache_type: CacheType,
+    socket_path: String,
+    frontend: VhostUserFrontend,
+
+    // Virtio fields.
+    pub(crate) avail_features: u64,
+    pub(crate) acked_features: u64,
+    config_space: Vec<u8>,
+
+    // Transport related fields.
+    pub(crate) queues: Vec<Queue>,
+    // The backend gets the queue events as kick event fds, which are the ioeventfds the driver
+    // notifies the queues through.
+    pub(crate) queue_evts: Vec<EventFd>,
+    // The backend writes to the call event fds once it used descriptors of the matching queues.
+    pub(crate) call_evts: Vec<EventFd>,
+    pub(crate) irq_trigger: IrqTrigger,
+    pub(crate) device_state: DeviceState,
+
+    // Implementation specific fields.
+    pub(crate) activate_evt: EventFd,
+}
+
+impl VhostUserBlock {
+    /// Create a new vhost-user block device connected to the backend listening on
+    /// `socket_path`.
+    pub fn new(
+        id: String,
+        partuuid: Option<String>,
+        cache_type: CacheType,
+        socket_path: String,
+        is_disk_read_only: bool,
+        is_disk_root: bool,
+        num_queues: usize,
+    ) -> Result<VhostUserBlock, Error> {
+        if num_queues == 0 || num_queues > MAX_NUM_QUEUES {
+            return Err(Error::InvalidNumQueues(num_queues));
+        }
+
+        let mut frontend = VhostUserFrontend::connect(&socket_path)?;
+        let backend_features = frontend.features();
+
+        // The backend processes the requests, so it has to enforce read-only drives.
+        if is_disk_read_only && backend_features & (1u64 << VIRTIO_BLK_F_RO) == 0 {
+            return Err(Error::MissingFeature("VIRTIO_BLK_F_RO"));
+        }
+        if backend_features & (1u64 << VIRTIO_F_VERSION_1) == 0 {
+            return Err(Error::MissingFeature("VIRTIO_F_VERSION_1"));
+        }
+        if num_queues > 1 {
+            if backend_features & (1u64 << VIRTIO_BLK_F_MQ) == 0
+                || !frontend.has_protocol_feature(VHOST_USER_PROTOCOL_F_MQ)
+            {
+                return Err(Error::MissingFeature("VIRTIO_BLK_F_MQ"));
+            }
+            let backend_num_queues = frontend.get_queue_num()?;
+            if backend_num_queues < num_queues as u64 {
+                return Err(Error::TooManyQueues(num_queues, backend_num_queues));
+            }
+        }
+
+        let mut supported_features = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+            | (1u64 << VIRTIO_RING_F_INDIRECT_DESC)
+            | (1u64 << VIRTIO_BLK_F_SIZE_MAX)
+            | (1u64 << VIRTIO_BLK_F_SEG_MAX)
+            | (1u64 << VIRTIO_BLK_F_GEOMETRY)
+            | (1u64 << VIRTIO_BLK_F_RO)
+            | (1u64 << VIRTIO_BLK_F_BLK_SIZE)
+            | (1u64 << VIRTIO_BLK_F_TOPOLOGY);
+        // As for the other block devices, the flush requests only reach the backend with the
+        // `Writeback` cache type.
+        if cache_type == CacheType::Writeback {
+            supported_features |= 1u64 << VIRTIO_BLK_F_FLUSH;
+        }
+        if !is_disk_read_only {
+            supported_features |=
+                (1u64 << VIRTIO_BLK_F_DISCARD) | (1u64 << VIRTIO_BLK_F_WRITE_ZEROES);
+        }
+        if num_queues > 1 {
+            supported_features |= 1u64 << VIRTIO_BLK_F_MQ;
+        }
+        let avail_features = backend_features & supported_features;
+
+        let mut config_space = frontend.get_config(CONFIG_SPACE_SIZE as u32)?;
+        // The driver only uses as many queues as the device has, which can be less than the
+        // backend supports.
+        config_space[CONFIG_NUM_QUEUES_OFFSET..CONFIG_NUM_QUEUES_OFFSET + 2]
+            .copy_from_slice(&(num_queues as u16).to_le_bytes());
+
+        let mut queue_evts = Vec::with_capacity(num_queues);
+        let mut call_evts = Vec::with_capacity(num_queues);
+        for _ in 0..num_queues {
+            queue_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
+            call_evts.push(EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?);
+        }
+
+        let queues = (0..num_queues).map(|_| Queue::new(QUEUE_SIZE)).collect();
+
+        Ok(VhostUserBlock {
+            id,
+            partuuid,
+            root_device: is_disk_root,
+            read_only: is_disk_read_only,
+            cache_type,
+            socket_path,
+            frontend,
+            avail_features,
+            acked_features: 0u64,
+            config_space,
+            queues,
+            queue_evts,
+            call_evts,
+            irq_trigger: IrqTrigger::new().map_err(Error::EventFd)?,
+            device_state: DeviceState::Inactive,
+            activate_evt: EventFd::new(libc::EFD_NONBLOCK).map_err(Error::EventFd)?,
+        })
+    }
+
+    /// Provides the ID of this block device.
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    /// Provides the PARTUUID of this block device.
+    pub fn partuuid(&self) -> Option<&String> {
+        self.partuuid.as_ref()
+    }
+
+    /// Specifies if this block device is read only.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Specifies if this block device is the root device.
+    pub fn is_root_device(&self) -> bool {
+        self.root_device
+    }
+
+    /// Specifies block device cache type.
+    pub fn cache_type(&self) -> CacheType {
+        self.cache_type
+    }
+
+    /// Provides the path of the Unix domain socket of the backend.
+    pub fn socket_path(&self) -> &String {
+        &self.socket_path
+    }
+
+    /// Provides the number of virtio queues of this block device.
+    pub fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+
+    pub(crate) fn process_call_event(&self, queue_index: usize) {
+        if let Err(err) = self.call_evts[queue_index].read() {
+            error!("Failed to get vhost-user call event: {:?}", err);
+            METRICS.block.event_fails.inc();
+            return;
+        }
+        if let Err(err) = self.irq_trigger.trigger_irq(IrqType::Vring) {
+            error!("Failed to signal used queue: {:?}", err);
+            METRICS.block.event_fails.inc();
+        }
+    }
+
+    // Hands the guest memory and the ready queues to the backend.
+    fn setup_backend(&mut self, mem: &GuestMemoryMmap) -> Result<(), Error> {
+        self.frontend.set_features(self.acked_features)?;
+        self.frontend.set_mem_table(mem)?;
+        for (index, queue) in self.queues.iter().enumerate() {
+            if !queue.ready {
+                continue;
+            }
+            self.frontend.setup_vring(
+                index,
+                queue,
+                mem,
+                &self.queue_evts[index],
+                &self.call_evts[index],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl VirtioDevice for VhostUserBlock {
+    fn device_type(&self) -> u32 {
+        TYPE_BLOCK
+    }
+
+    fn queues(&self) -> &[Queue] {
+        &self.queues
+    }
+
+    fn queues_mut(&mut self) -> &mut [Queue] {
+        &mut self.queues
+    }
+
+    fn queue_events(&self) -> &[EventFd] {
+        &self.queue_evts
+    }
+
+    fn interrupt_evt(&self) -> &EventFd {
+        &self.irq_trigger.irq_evt
+    }
+
+    /// Returns the current device interrupt status.
+    fn interrupt_status(&self) -> Arc<AtomicUsize> {
+        self.irq_trigger.irq_status.clone()
+    }
+
+    fn avail_features(&self) -> u64 {
+        self.avail_features
+    }
+
+    fn acked_features(&self) -> u64 {
+        self.acked_features
+    }
+
+    fn set_acked_features(&mut self, acked_features: u64) {
+        self.acked_features = acked_features;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_len = self.config_space.len() as u64;
+        if offset >= config_len {
+            error!("Failed to read config space");
+            METRICS.block.cfg_fails.inc();
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write_all(&self.config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) {
+        // None of the writable fields of the configuration space is negotiated.
+        error!("Failed to write config space");
+        METRICS.block.cfg_fails.inc();
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_state.is_activated()
+    }
+
+    fn activate(&mut self, mem: GuestMemoryMmap) -> ActivateResult {
+        if let Err(err) = self.setup_backend(&mem) {
+            error!(
+                "Failed to hand the queues of {} to the vhost-user backend: {:?}",
+                self.id, err
+            );
+            METRICS.block.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+
+        if self.activate_evt.write(1).is_err() {
+            error!("Block: Cannot write to activate_evt");
+            METRICS.block.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+        self.device_state = DeviceState::Activated(mem);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::io::Read;
+
+    use vm_memory::{GuestAddress, GuestMemory};
+
+    use super::*;
+    use crate::virtio::test_utils::VirtQueue;
+    use crate::virtio::vhost_user_blk::vhost_user::tests::{TestBackend, PROTOCOL_FEATURES};
+    use crate::virtio::vhost_user_blk::vhost_user::*;
+
+    const BACKEND_FEATURES: u64 = PROTOCOL_FEATURES
+        | (1u64 << VIRTIO_F_VERSION_1)
+        | (1u64 << VIRTIO_RING_F_EVENT_IDX)
+        | (1u64 << VIRTIO_BLK_F_FLUSH)
+        | (1u64 << VIRTIO_BLK_F_MQ)
+        | (1u64 << VIRTIO_BLK_F_DISCARD);
+    const BACKEND_PROTOCOL_FEATURES: u64 = (1 << VHOST_USER_PROTOCOL_F_MQ)
+        | (1 << VHOST_USER_PROTOCOL_F_REPLY_ACK)
+        | (1 << VHOST_USER_PROTOCOL_F_CONFIG);
+
+    fn backend_config() -> Vec<u8> {
+        // 8 sectors, and 1 queue.
+        let mut config = vec![0u8; CONFIG_SPACE_SIZE];
+        config[0..8].copy_from_slice(&8u64.to_le_bytes());
+        config[CONFIG_NUM_QUEUES_OFFSET..CONFIG_NUM_QUEUES_OFFSET + 2]
+            .copy_from_slice(&1u16.to_le_bytes());
+        config
+    }
+
+    fn default_vhost_user_block(backend: &TestBackend, num_queues: usize) -> VhostUserBlock {
+        VhostUserBlock::new(
+            "test".to_string(),
+            None,
+            CacheType::Writeback,
+            backend.socket_path.clone(),
+            false,
+            false,
+            num_queues,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_new() {
+        let backend = TestBackend::new(
+            BACKEND_FEATURES,
+            BACKEND_PROTOCOL_FEATURES,
+            backend_config(),
+        );
+        assert!(matches!(
+            VhostUserBlock::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                backend.socket_path.clone(),
+                false,
+                false,
+                0,
+            ),
+            Err(Error::InvalidNumQueues(0))
+        ));
+
+        // The backend does not support read-only drives.
+        assert!(matches!(
+            VhostUserBlock::new(
+                "test".to_string(),
+                None,
+                CacheType::Unsafe,
+                backend.socket_path.clone(),
+                true,
+                false,
+                1,
+            ),
+            Err(Error::MissingFeature("VIRTIO_BLK_F_RO"))
+        ));
+    }
+
+    #[test]
+    fn test_features_and_config() {
+        let backend = TestBackend::new(
+            BACKEND_FEATURES,
+            BACKEND_PROTOCOL_FEATURES,
+            backend_config(),
+        );
+        let block = default_vhost_user_block(&backend, 2);
+        assert_eq!(block.device_type(), TYPE_BLOCK);
+        assert_eq!(block.num_queues(), 2);
+        // The vhost-user specific features are not offered to the driver.
+        assert_eq!(
+            block.avail_features(),
+            BACKEND_FEATURES & !PROTOCOL_FEATURES
+        );
+
+        let mut capacity = [0u8; 8];
+        block.read_config(0, &mut capacity);
+        assert_eq!(u64::from_le_bytes(capacity), 8);
+        // The number of queues is the one of the device.
+        let mut num_queues = [0u8; 2];
+        block.read_config(CONFIG_NUM_QUEUES_OFFSET as u64, &mut num_queues);
+        assert_eq!(u16::from_le_bytes(num_queues), 2);
+
+        // Single queue devices do not offer multi-queue, nor the flush command without the
+        // `Writeback` cache type.
+        let backend = TestBackend::new(
+            BACKEND_FEATURES,
+            BACKEND_PROTOCOL_FEATURES,
+            backend_config(),
+        );
+        let block = VhostUserBlock::new(
+            "test".to_string(),
+            None,
+            CacheType::Unsafe,
+            backend.socket_path.clone(),
+            false,
+            false,
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            block.avail_features(),
+            BACKEND_FEATURES
+                & !PROTOCOL_FEATURES
+                & !(1u64 << VIRTIO_BLK_F_MQ)
+                & !(1u64 << VIRTIO_BLK_F_FLUSH)
+        );
+        assert!(backend.payloads(VHOST_USER_GET_QUEUE_NUM).is_empty());
+    }
+
+    #[test]
+    fn test_activate() {
+        let backend = TestBackend::new(
+            BACKEND_FEATURES,
+            BACKEND_PROTOCOL_FEATURES,
+            backend_config(),
+        );
+        let mut block = default_vhost_user_block(&backend, 2);
+
+        // The backend cannot access private guest memory.
+        let private_mem =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false)
+                .unwrap();
+        assert!(block.activate(private_mem).is_err());
+        assert!(!block.is_activated());
+
+        let mem =
+            vm_memory::create_shared_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        block.queues[0] = vq.create_queue();
+        block.set_acked_features(block.avail_features());
+        block.activate(mem.clone()).unwrap();
+        assert!(block.is_activated());
+
+        let features = u64::from_le_bytes(
+            backend.payloads(VHOST_USER_SET_FEATURES).last().unwrap()[..]
+                .try_into()
+                .unwrap(),
+        );
+        assert_eq!(features, BACKEND_FEATURES);
+        assert_eq!(backend.payloads(VHOST_USER_SET_MEM_TABLE).len(), 1);
+        // Only the ready queue is handed to the backend.
+        assert_eq!(
+            backend.payloads(VHOST_USER_SET_VRING_NUM),
+            vec![[0u32.to_le_bytes(), 16u32.to_le_bytes()].concat()]
+        );
+        let addresses = &backend.payloads(VHOST_USER_SET_VRING_ADDR)[0];
+        let desc_addr = u64::from_le_bytes(addresses[8..16].try_into().unwrap());
+        assert_eq!(
+            desc_addr,
+            mem.get_host_address(vq.dtable_start()).unwrap() as u64
+        );
+        assert_eq!(
+            backend.payloads(VHOST_USER_SET_VRING_ENABLE),
+            vec![[0u32.to_le_bytes(), 1u32.to_le_bytes()].concat()]
+        );
+
+        // The backend notifies the guest through the call event fd.
+        {
+            let received = backend.received.lock().unwrap();
+            let mut call_fd = &received.fds[&VHOST_USER_SET_VRING_CALL][0];
+            call_fd.write_all(&1u64.to_ne_bytes()).unwrap();
+        }
+        block.process_call_event(0);
+        assert!(block.irq_trigger.has_pending_irq(IrqType::Vring));
+
+        // The backend reads the notifications of the driver from the kick event fd.
+        block.queue_evts[0].write(1).unwrap();
+        let received = backend.received.lock().unwrap();
+        let mut kick_fd = &received.fds[&VHOST_USER_SET_VRING_KICK][0];
+        let mut value = [0u8; 8];
+        kick_fd.read_exact(&mut value).unwrap();
+        assert_eq!(u64::from_ne_bytes(value), 1);
+    }
+}
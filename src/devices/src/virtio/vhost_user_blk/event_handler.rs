@@ -0,0 +1,88 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::os::unix::io::AsRawFd;
+
+use event_manager::{EventOps, Events, MutEventSubscriber};
+use logger::{debug, error, warn};
+use utils::epoll::EventSet;
+
+use crate::virtio::vhost_user_blk::device::VhostUserBlock;
+use crate::virtio::VirtioDevice;
+
+impl VhostUserBlock {
+    // The backend processes the queue events itself, so only its call events are monitored.
+    fn register_runtime_events(&self, ops: &mut EventOps) {
+        for evt in &self.call_evts {
+            if let Err(err) = ops.add(Events::new(evt, EventSet::IN)) {
+                error!("Failed to register vhost-user call event: {}", err);
+            }
+        }
+    }
+
+    fn register_activate_event(&self, ops: &mut EventOps) {
+        if let Err(err) = ops.add(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to register activate event: {}", err);
+        }
+    }
+
+    fn process_activate_event(&self, ops: &mut EventOps) {
+        debug!("vhost-user block: activate event");
+        if let Err(err) = self.activate_evt.read() {
+            error!(
+                "Failed to consume vhost-user block activate event: {:?}",
+                err
+            );
+        }
+        self.register_runtime_events(ops);
+        if let Err(err) = ops.remove(Events::new(&self.activate_evt, EventSet::IN)) {
+            error!("Failed to un-register activate event: {}", err);
+        }
+    }
+}
+
+impl MutEventSubscriber for VhostUserBlock {
+    fn process(&mut self, event: Events, ops: &mut EventOps) {
+        let source = event.fd();
+        let event_set = event.event_set();
+        let supported_events = EventSet::IN;
+
+        if !supported_events.contains(event_set) {
+            warn!(
+                "Received unknown event: {:?} from source: {:?}",
+                event_set, source
+            );
+            return;
+        }
+
+        if self.is_activated() {
+            if source == self.activate_evt.as_raw_fd() {
+                self.process_activate_event(ops);
+            } else if let Some(queue_index) = self
+                .call_evts
+                .iter()
+                .position(|evt| evt.as_raw_fd() == source)
+            {
+                self.process_call_event(queue_index);
+            } else {
+                warn!("Vhost-user block: Spurious event received: {:?}", source);
+            }
+        } else {
+            warn!(
+                "Vhost-user block: The device is not yet activated. Spurious event received: {:?}",
+                source
+            );
+        }
+    }
+
+    fn init(&mut self, ops: &mut EventOps) {
+        // This function can be called during different points in the device lifetime:
+        //  - shortly after device creation,
+        //  - on device activation (is-activated already true at this point).
+        if self.is_activated() {
+            self.register_runtime_events(ops);
+        } else {
+            self.register_activate_event(ops);
+        }
+    }
+}
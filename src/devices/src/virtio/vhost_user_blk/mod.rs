@@ -0,0 +1,45 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Implements a vhost-user-blk frontend, which hands the virtqueues of a block device to a
+//! backend running in another process, such as an SPDK target.
+
+pub mod device;
+pub mod event_handler;
+pub mod vhost_user;
+
+pub use self::device::VhostUserBlock;
+pub use self::event_handler::*;
+
+// The virtio ring feature allowing the driver to use indirect descriptors.
+pub(crate) const VIRTIO_RING_F_INDIRECT_DESC: u32 = 28;
+// Queues of the vhost-user-blk backends are sized like the ones of the other block devices.
+pub const QUEUE_SIZE: u16 = 256;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Cannot connect to the Unix domain socket of the backend.
+    Connect(std::io::Error),
+    /// Cannot create an event fd.
+    EventFd(std::io::Error),
+    /// The guest memory is not shared, so the backend cannot access it.
+    GuestMemoryNotShared,
+    /// The address of a virtqueue is not valid guest memory.
+    InvalidQueueAddress,
+    /// The number of queues is not between 1 and `MAX_NUM_QUEUES`.
+    InvalidNumQueues(usize),
+    /// The backend replied with an unexpected message, or one of an unexpected size.
+    InvalidReply(u32),
+    /// The backend does not support a feature the device needs.
+    MissingFeature(&'static str),
+    /// Cannot receive a reply from the backend.
+    ReceiveReply(std::io::Error),
+    /// The backend failed to process a message.
+    RequestFailed(u32),
+    /// Cannot send a message to the backend.
+    SendMessage(std::io::Error),
+    /// The backend does not support as many queues as the device has.
+    TooManyQueues(usize, u64),
+    /// The guest memory has more regions than the protocol allows.
+    TooManyMemoryRegions(usize),
+}
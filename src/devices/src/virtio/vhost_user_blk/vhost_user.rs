@@ -0,0 +1,530 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Frontend side of the vhost-user protocol, limited to the messages a block device needs.
+//!
+//! Every message starts with a header made of the request type, flags and the size of the
+//! payload following it. File descriptors, i.e. the ones of the guest memory regions and of the
+//! virtqueue notifiers, are sent along with the message as `SCM_RIGHTS` ancillary data.
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+
+use utils::eventfd::EventFd;
+use utils::sock_ctrl_msg::ScmSocket;
+use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+use super::Error;
+use crate::virtio::Queue;
+
+pub(crate) const VHOST_USER_GET_FEATURES: u32 = 1;
+pub(crate) const VHOST_USER_SET_FEATURES: u32 = 2;
+pub(crate) const VHOST_USER_SET_OWNER: u32 = 3;
+pub(crate) const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+pub(crate) const VHOST_USER_SET_VRING_NUM: u32 = 8;
+pub(crate) const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+pub(crate) const VHOST_USER_SET_VRING_BASE: u32 = 10;
+pub(crate) const VHOST_USER_SET_VRING_KICK: u32 = 12;
+pub(crate) const VHOST_USER_SET_VRING_CALL: u32 = 13;
+pub(crate) const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+pub(crate) const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+pub(crate) const VHOST_USER_GET_QUEUE_NUM: u32 = 17;
+pub(crate) const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+pub(crate) const VHOST_USER_GET_CONFIG: u32 = 24;
+
+pub(crate) const VHOST_USER_VERSION: u32 = 0x1;
+pub(crate) const VHOST_USER_REPLY_MASK: u32 = 0x4;
+pub(crate) const VHOST_USER_NEED_REPLY_MASK: u32 = 0x8;
+pub(crate) const HEADER_SIZE: usize = 12;
+// Offset, size and flags preceding the configuration space in `VHOST_USER_GET_CONFIG` messages.
+pub(crate) const CONFIG_HEADER_SIZE: usize = 12;
+// The largest reply the frontend expects is the one to `VHOST_USER_GET_CONFIG`.
+const MAX_REPLY_SIZE: usize = 256;
+
+/// Feature bit telling that the backend supports the negotiation of protocol features.
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u32 = 30;
+/// Protocol feature bit telling that the backend supports several queues.
+pub const VHOST_USER_PROTOCOL_F_MQ: u32 = 0;
+/// Protocol feature bit telling that the backend acknowledges the messages on request.
+pub const VHOST_USER_PROTOCOL_F_REPLY_ACK: u32 = 3;
+/// Protocol feature bit telling that the backend exposes the device configuration space.
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u32 = 9;
+const SUPPORTED_PROTOCOL_FEATURES: u64 = (1 << VHOST_USER_PROTOCOL_F_MQ)
+    | (1 << VHOST_USER_PROTOCOL_F_REPLY_ACK)
+    | (1 << VHOST_USER_PROTOCOL_F_CONFIG);
+/// Maximum number of guest memory regions in a `VHOST_USER_SET_MEM_TABLE` message.
+pub const MAX_MEMORY_REGIONS: usize = 8;
+
+// Payload of the messages about the state of a virtqueue, e.g. its size or whether it is
+// enabled.
+fn vring_state(index: u32, num: u32) -> Vec<u8> {
+    let mut payload = index.to_le_bytes().to_vec();
+    payload.extend_from_slice(&num.to_le_bytes());
+    payload
+}
+
+/// Connection of the frontend to a vhost-user backend.
+#[derive(Debug)]
+pub struct VhostUserFrontend {
+    socket: UnixStream,
+    // Features of the backend, including the vhost-user specific ones.
+    features: u64,
+    // Protocol features negotiated with the backend.
+    protocol_features: u64,
+}
+
+impl VhostUserFrontend {
+    /// Connects to the backend listening on `socket_path`, and negotiates the protocol
+    /// features with it. The backend needs to expose the device configuration space.
+    pub fn connect<P: AsRef<Path>>(socket_path: P) -> Result<Self, Error> {
+        let socket = UnixStream::connect(socket_path).map_err(Error::Connect)?;
+        let mut frontend = VhostUserFrontend {
+            socket,
+            features: 0,
+            protocol_features: 0,
+        };
+
+        frontend.features = frontend.get_u64(VHOST_USER_GET_FEATURES)?;
+        if frontend.features & (1 << VHOST_USER_F_PROTOCOL_FEATURES) == 0 {
+            return Err(Error::MissingFeature("VHOST_USER_F_PROTOCOL_FEATURES"));
+        }
+        let protocol_features =
+            frontend.get_u64(VHOST_USER_GET_PROTOCOL_FEATURES)? & SUPPORTED_PROTOCOL_FEATURES;
+        if protocol_features & (1 << VHOST_USER_PROTOCOL_F_CONFIG) == 0 {
+            return Err(Error::MissingFeature("VHOST_USER_PROTOCOL_F_CONFIG"));
+        }
+        frontend.send_request(
+            VHOST_USER_SET_PROTOCOL_FEATURES,
+            &protocol_features.to_le_bytes(),
+            &[],
+        )?;
+        // The next messages can be acknowledged, if the backend supports it.
+        frontend.protocol_features = protocol_features;
+        frontend.send_request(VHOST_USER_SET_OWNER, &[], &[])?;
+        Ok(frontend)
+    }
+
+    /// Returns the features of the backend, including the vhost-user specific ones.
+    pub fn features(&self) -> u64 {
+        self.features
+    }
+
+    /// Whether the protocol feature `feature` was negotiated with the backend.
+    pub fn has_protocol_feature(&self, feature: u32) -> bool {
+        self.protocol_features & (1 << feature) != 0
+    }
+
+    /// Returns the number of queues the backend supports.
+    pub fn get_queue_num(&mut self) -> Result<u64, Error> {
+        if !self.has_protocol_feature(VHOST_USER_PROTOCOL_F_MQ) {
+            return Err(Error::MissingFeature("VHOST_USER_PROTOCOL_F_MQ"));
+        }
+        self.get_u64(VHOST_USER_GET_QUEUE_NUM)
+    }
+
+    /// Reads the first `len` bytes of the device configuration space from the backend.
+    pub fn get_config(&mut self, len: u32) -> Result<Vec<u8>, Error> {
+        // Offset, size and flags, followed by room for the configuration space.
+        let mut payload = 0u32.to_le_bytes().to_vec();
+        payload.extend_from_slice(&len.to_le_bytes());
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        payload.resize(CONFIG_HEADER_SIZE + len as usize, 0);
+
+        self.send_message(VHOST_USER_GET_CONFIG, 0, &payload, &[])?;
+        let reply = self.recv_reply(VHOST_USER_GET_CONFIG)?;
+        if reply.len() != payload.len() {
+            return Err(Error::InvalidReply(VHOST_USER_GET_CONFIG));
+        }
+        Ok(reply[CONFIG_HEADER_SIZE..].to_vec())
+    }
+
+    /// Sets the features the driver acknowledged.
+    pub fn set_features(&mut self, features: u64) -> Result<(), Error> {
+        // The protocol features stay enabled only if the frontend acknowledges them too.
+        let features = features | (1 << VHOST_USER_F_PROTOCOL_FEATURES);
+        self.send_request(VHOST_USER_SET_FEATURES, &features.to_le_bytes(), &[])
+    }
+
+    /// Shares the guest memory with the backend, which maps all its regions.
+    pub fn set_mem_table(&mut self, mem: &GuestMemoryMmap) -> Result<(), Error> {
+        // The backend can only access the guest memory the frontend writes to if both map
+        // the same pages.
+        if !vm_memory::is_shared_guest_memory(mem) {
+            return Err(Error::GuestMemoryNotShared);
+        }
+        let num_regions = mem.num_regions();
+        if num_regions > MAX_MEMORY_REGIONS {
+            return Err(Error::TooManyMemoryRegions(num_regions));
+        }
+
+        // Number of regions and padding, followed by the regions.
+        let mut payload = (num_regions as u32).to_le_bytes().to_vec();
+        payload.extend_from_slice(&0u32.to_le_bytes());
+        let mut fds = Vec::with_capacity(num_regions);
+        for region in mem.iter() {
+            let file_offset = region.file_offset().ok_or(Error::GuestMemoryNotShared)?;
+            payload.extend_from_slice(&region.start_addr().raw_value().to_le_bytes());
+            payload.extend_from_slice(&region.len().to_le_bytes());
+            payload.extend_from_slice(&(region.as_ptr() as u64).to_le_bytes());
+            payload.extend_from_slice(&file_offset.start().to_le_bytes());
+            fds.push(file_offset.file().as_raw_fd());
+        }
+        self.send_request(VHOST_USER_SET_MEM_TABLE, &payload, &fds)
+    }
+
+    /// Hands the virtqueue `index` to the backend, which then processes the requests the
+    /// driver notifies through `kick_evt`, and notifies the used descriptors through
+    /// `call_evt`.
+    pub fn setup_vring(
+        &mut self,
+        index: usize,
+        queue: &Queue,
+        mem: &GuestMemoryMmap,
+        kick_evt: &EventFd,
+        call_evt: &EventFd,
+    ) -> Result<(), Error> {
+        let index = index as u32;
+        // The backend translates the addresses of the frontend using the memory table.
+        let host_address = |addr| {
+            mem.get_host_address(addr)
+                .map(|ptr| ptr as u64)
+                .map_err(|_| Error::InvalidQueueAddress)
+        };
+
+        self.send_request(
+            VHOST_USER_SET_VRING_NUM,
+            &vring_state(index, u32::from(queue.actual_size())),
+            &[],
+        )?;
+
+        // Index and flags, followed by the descriptor table, used ring, available ring and
+        // dirty log addresses.
+        let mut addresses = index.to_le_bytes().to_vec();
+        addresses.extend_from_slice(&0u32.to_le_bytes());
+        addresses.extend_from_slice(&host_address(queue.desc_table)?.to_le_bytes());
+        addresses.extend_from_slice(&host_address(queue.used_ring)?.to_le_bytes());
+        addresses.extend_from_slice(&host_address(queue.avail_ring)?.to_le_bytes());
+        addresses.extend_from_slice(&0u64.to_le_bytes());
+        self.send_request(VHOST_USER_SET_VRING_ADDR, &addresses, &[])?;
+
+        self.send_request(
+            VHOST_USER_SET_VRING_BASE,
+            &vring_state(index, u32::from(queue.next_avail.0)),
+            &[],
+        )?;
+        self.send_request(
+            VHOST_USER_SET_VRING_CALL,
+            &u64::from(index).to_le_bytes(),
+            &[call_evt.as_raw_fd()],
+        )?;
+        self.send_request(
+            VHOST_USER_SET_VRING_KICK,
+            &u64::from(index).to_le_bytes(),
+            &[kick_evt.as_raw_fd()],
+        )?;
+        // With the protocol features negotiated, the rings start disabled.
+        self.send_request(VHOST_USER_SET_VRING_ENABLE, &vring_state(index, 1), &[])
+    }
+
+    // Sends a message the backend does not reply to, then waits for the backend to acknowledge
+    // it if `VHOST_USER_PROTOCOL_F_REPLY_ACK` was negotiated.
+    fn send_request(&mut self, request: u32, payload: &[u8], fds: &[RawFd]) -> Result<(), Error> {
+        let need_reply = self.has_protocol_feature(VHOST_USER_PROTOCOL_F_REPLY_ACK);
+        let flags = if need_reply {
+            VHOST_USER_NEED_REPLY_MASK
+        } else {
+            0
+        };
+        self.send_message(request, flags, payload, fds)?;
+
+        if need_reply && self.get_reply_u64(request)? != 0 {
+            return Err(Error::RequestFailed(request));
+        }
+        Ok(())
+    }
+
+    fn send_message(
+        &mut self,
+        request: u32,
+        flags: u32,
+        payload: &[u8],
+        fds: &[RawFd],
+    ) -> Result<(), Error> {
+        let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+        message.extend_from_slice(&request.to_le_bytes());
+        message.extend_from_slice(&(VHOST_USER_VERSION | flags).to_le_bytes());
+        message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        message.extend_from_slice(payload);
+
+        if fds.is_empty() {
+            return self.socket.write_all(&message).map_err(Error::SendMessage);
+        }
+        // The messages carrying file descriptors are small enough to be sent at once.
+        let sent = self
+            .socket
+            .send_with_fds(&[&message[..]], fds)
+            .map_err(|err| Error::SendMessage(io::Error::from_raw_os_error(err.errno())))?;
+        if sent != message.len() {
+            return Err(Error::SendMessage(io::Error::from(
+                io::ErrorKind::WriteZero,
+            )));
+        }
+        Ok(())
+    }
+
+    // Sends a message without payload, to which the backend replies with an u64.
+    fn get_u64(&mut self, request: u32) -> Result<u64, Error> {
+        self.send_message(request, 0, &[], &[])?;
+        self.get_reply_u64(request)
+    }
+
+    fn get_reply_u64(&mut self, request: u32) -> Result<u64, Error> {
+        let reply = self.recv_reply(request)?;
+        reply
+            .as_slice()
+            .try_into()
+            .map(u64::from_le_bytes)
+            .map_err(|_| Error::InvalidReply(request))
+    }
+
+    // Receives the payload of the reply to `request`.
+    fn recv_reply(&mut self, request: u32) -> Result<Vec<u8>, Error> {
+        let mut header = [0u8; HEADER_SIZE];
+        self.socket
+            .read_exact(&mut header)
+            .map_err(Error::ReceiveReply)?;
+        let reply_request = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let flags = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let size = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        if reply_request != request || flags & VHOST_USER_REPLY_MASK == 0 || size > MAX_REPLY_SIZE {
+            return Err(Error::InvalidReply(request));
+        }
+
+        let mut payload = vec![0u8; size];
+        self.socket
+            .read_exact(&mut payload)
+            .map_err(Error::ReceiveReply)?;
+        Ok(payload)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::os::unix::fs::FileExt;
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use utils::tempdir::TempDir;
+    use vm_memory::{Bytes, GuestAddress};
+
+    use super::*;
+
+    /// Messages received by a `TestBackend`, with the file descriptors sent along.
+    #[derive(Default)]
+    pub(crate) struct ReceivedMessages {
+        pub(crate) requests: Vec<(u32, Vec<u8>)>,
+        pub(crate) fds: HashMap<u32, Vec<File>>,
+    }
+
+    /// In-process vhost-user backend, which records the messages it receives.
+    pub(crate) struct TestBackend {
+        pub(crate) socket_path: String,
+        pub(crate) received: Arc<Mutex<ReceivedMessages>>,
+        _dir: TempDir,
+    }
+
+    impl TestBackend {
+        /// Starts a backend with the given device features and protocol features, exposing
+        /// `config` as device configuration space.
+        pub(crate) fn new(features: u64, protocol_features: u64, config: Vec<u8>) -> Self {
+            let dir = TempDir::new().unwrap();
+            let socket_path = dir.as_path().join("vhost-user.sock");
+            let listener = UnixListener::bind(&socket_path).unwrap();
+            let received = Arc::new(Mutex::new(ReceivedMessages::default()));
+
+            let thread_received = received.clone();
+            thread::spawn(move || {
+                let (stream, _) = listener.accept().unwrap();
+                // The backend stops when the frontend disconnects.
+                let _ = serve(
+                    stream,
+                    features,
+                    protocol_features,
+                    &config,
+                    &thread_received,
+                );
+            });
+
+            TestBackend {
+                socket_path: socket_path.to_str().unwrap().to_string(),
+                received,
+                _dir: dir,
+            }
+        }
+
+        /// Returns the payloads of the received `request` messages.
+        pub(crate) fn payloads(&self, request: u32) -> Vec<Vec<u8>> {
+            let received = self.received.lock().unwrap();
+            received
+                .requests
+                .iter()
+                .filter(|(r, _)| *r == request)
+                .map(|(_, payload)| payload.clone())
+                .collect()
+        }
+    }
+
+    fn reply(stream: &mut UnixStream, request: u32, payload: &[u8]) -> io::Result<()> {
+        let mut message = request.to_le_bytes().to_vec();
+        message.extend_from_slice(&(VHOST_USER_VERSION | VHOST_USER_REPLY_MASK).to_le_bytes());
+        message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        message.extend_from_slice(payload);
+        stream.write_all(&message)
+    }
+
+    fn serve(
+        mut stream: UnixStream,
+        features: u64,
+        protocol_features: u64,
+        config: &[u8],
+        received: &Mutex<ReceivedMessages>,
+    ) -> io::Result<()> {
+        loop {
+            // The file descriptors come along with the first bytes of the message.
+            let mut header = [0u8; HEADER_SIZE];
+            let (len, file) = stream
+                .recv_with_fd(&mut header)
+                .map_err(|err| io::Error::from_raw_os_error(err.errno()))?;
+            if len == 0 {
+                return Ok(());
+            }
+            stream.read_exact(&mut header[len..])?;
+            let request = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let flags = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            let size = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+            let mut payload = vec![0u8; size];
+            stream.read_exact(&mut payload)?;
+
+            {
+                let mut received = received.lock().unwrap();
+                received.requests.push((request, payload.clone()));
+                if let Some(file) = file {
+                    received.fds.entry(request).or_default().push(file);
+                }
+            }
+
+            match request {
+                VHOST_USER_GET_FEATURES => reply(&mut stream, request, &features.to_le_bytes())?,
+                VHOST_USER_GET_PROTOCOL_FEATURES => {
+                    reply(&mut stream, request, &protocol_features.to_le_bytes())?
+                }
+                VHOST_USER_GET_QUEUE_NUM => reply(&mut stream, request, &4u64.to_le_bytes())?,
+                VHOST_USER_GET_CONFIG => {
+                    let len = u32::from_le_bytes(payload[4..8].try_into().unwrap()) as usize;
+                    payload[CONFIG_HEADER_SIZE..].copy_from_slice(&config[..len]);
+                    reply(&mut stream, request, &payload)?;
+                }
+                _ if flags & VHOST_USER_NEED_REPLY_MASK != 0 => {
+                    reply(&mut stream, request, &0u64.to_le_bytes())?
+                }
+                _ => (),
+            }
+        }
+    }
+
+    /// Device features of a backend supporting the negotiation of protocol features.
+    pub(crate) const PROTOCOL_FEATURES: u64 = 1 << VHOST_USER_F_PROTOCOL_FEATURES;
+
+    #[test]
+    fn test_connect() {
+        let backend = TestBackend::new(0, u64::MAX, vec![0; 60]);
+        assert!(matches!(
+            VhostUserFrontend::connect(&backend.socket_path),
+            Err(Error::MissingFeature("VHOST_USER_F_PROTOCOL_FEATURES"))
+        ));
+
+        let backend = TestBackend::new(
+            PROTOCOL_FEATURES,
+            1 << VHOST_USER_PROTOCOL_F_MQ,
+            vec![0; 60],
+        );
+        assert!(matches!(
+            VhostUserFrontend::connect(&backend.socket_path),
+            Err(Error::MissingFeature("VHOST_USER_PROTOCOL_F_CONFIG"))
+        ));
+
+        let backend = TestBackend::new(
+            PROTOCOL_FEATURES | (1 << 32),
+            u64::MAX,
+            (0..60).map(|byte| byte as u8).collect(),
+        );
+        let mut frontend = VhostUserFrontend::connect(&backend.socket_path).unwrap();
+        assert_eq!(
+            frontend.features(),
+            (1 << 32) | (1 << VHOST_USER_F_PROTOCOL_FEATURES)
+        );
+        // Unknown protocol features are not negotiated.
+        assert_eq!(
+            backend.payloads(VHOST_USER_SET_PROTOCOL_FEATURES),
+            vec![SUPPORTED_PROTOCOL_FEATURES.to_le_bytes().to_vec()]
+        );
+        assert!(frontend.has_protocol_feature(VHOST_USER_PROTOCOL_F_REPLY_ACK));
+        assert_eq!(backend.payloads(VHOST_USER_SET_OWNER).len(), 1);
+        assert_eq!(frontend.get_queue_num().unwrap(), 4);
+        assert_eq!(
+            frontend.get_config(8).unwrap(),
+            vec![0, 1, 2, 3, 4, 5, 6, 7]
+        );
+    }
+
+    #[test]
+    fn test_set_mem_table() {
+        let backend = TestBackend::new(
+            PROTOCOL_FEATURES,
+            1 << VHOST_USER_PROTOCOL_F_CONFIG,
+            vec![0; 60],
+        );
+        let mut frontend = VhostUserFrontend::connect(&backend.socket_path).unwrap();
+
+        let private_mem =
+            vm_memory::test_utils::create_anon_guest_memory(&[(GuestAddress(0), 0x10000)], false)
+                .unwrap();
+        assert!(matches!(
+            frontend.set_mem_table(&private_mem),
+            Err(Error::GuestMemoryNotShared)
+        ));
+
+        let mem =
+            vm_memory::create_shared_guest_memory(&[(GuestAddress(0), 0x10000)], false).unwrap();
+        mem.write_obj(0xdead_beef_u32, GuestAddress(0x1000))
+            .unwrap();
+        frontend.set_mem_table(&mem).unwrap();
+        // Without `VHOST_USER_PROTOCOL_F_REPLY_ACK`, wait for a reply to a later message.
+        frontend.get_u64(VHOST_USER_GET_FEATURES).unwrap();
+
+        let payload = &backend.payloads(VHOST_USER_SET_MEM_TABLE)[0];
+        assert_eq!(payload.len(), 8 + 32);
+        assert_eq!(u32::from_le_bytes(payload[0..4].try_into().unwrap()), 1);
+        assert_eq!(u64::from_le_bytes(payload[8..16].try_into().unwrap()), 0);
+        assert_eq!(
+            u64::from_le_bytes(payload[16..24].try_into().unwrap()),
+            0x10000
+        );
+        let mmap_offset = u64::from_le_bytes(payload[32..40].try_into().unwrap());
+
+        // The backend sees the writes of the frontend.
+        let received = backend.received.lock().unwrap();
+        let memfd = &received.fds[&VHOST_USER_SET_MEM_TABLE][0];
+        let mut value = [0u8; 4];
+        memfd
+            .read_exact_at(&mut value, mmap_offset + 0x1000)
+            .unwrap();
+        assert_eq!(u32::from_le_bytes(value), 0xdead_beef);
+    }
+}
@@ -7,6 +7,7 @@
 //! [`Connection`]: struct.Connection.html
 
 use std::num::{NonZeroU16, NonZeroU64, NonZeroUsize, Wrapping};
+use std::ops::Index;
 
 use bitflags::bitflags;
 use utils::rand::xor_pseudo_rng_u32;
@@ -78,6 +79,31 @@ bitflags! {
 // R should have the trait bound R: ByteBuffer, but bounds are ignored on type aliases.
 pub type PayloadSource<'a, R> = Option<(&'a R, Wrapping<u32>)>;
 
+// A view of a payload source which starts at `offset`, so that the payload of a data segment
+// begins with the byte associated with the sequence number of the segment.
+struct PayloadView<'a, R: ?Sized> {
+    buf: &'a R,
+    offset: usize,
+}
+
+impl<'a, R: ByteBuffer + ?Sized> Index<usize> for PayloadView<'a, R> {
+    type Output = u8;
+
+    fn index(&self, index: usize) -> &u8 {
+        &self.buf[self.offset + index]
+    }
+}
+
+impl<'a, R: ByteBuffer + ?Sized> ByteBuffer for PayloadView<'a, R> {
+    fn len(&self) -> usize {
+        self.buf.len() - self.offset
+    }
+
+    fn read_to_slice(&self, offset: usize, buf: &mut [u8]) {
+        self.buf.read_to_slice(self.offset + offset, buf)
+    }
+}
+
 /// Describes errors which may occur during a passive open.
 #[cfg_attr(test, derive(Debug, PartialEq, Eq))]
 pub enum PassiveOpenError {
@@ -291,10 +317,6 @@ impl Connection {
         self.flags_intersect(ConnStatusFlags::SYNACK_SENT)
     }
 
-    fn is_reset(&self) -> bool {
-        self.flags_intersect(ConnStatusFlags::RESET)
-    }
-
     fn fin_sent(&self) -> bool {
         self.flags_intersect(ConnStatusFlags::FIN_SENT)
     }
@@ -400,6 +422,13 @@ impl Connection {
         self.flags_intersect(ConnStatusFlags::ESTABLISHED)
     }
 
+    /// Returns `true` if the connection has been reset, either by the remote endpoint or
+    /// locally.
+    #[inline]
+    pub fn is_reset(&self) -> bool {
+        self.flags_intersect(ConnStatusFlags::RESET)
+    }
+
     /// Returns `true` if a `FIN` has been received.
     #[inline]
     pub fn fin_received(&self) -> bool {
@@ -935,6 +964,12 @@ impl Connection {
             // delimit a valid sequence number interval.
             if seq_after(actual_end, seq_to_send) {
                 let max_payload_len = (actual_end - seq_to_send).0 as usize;
+                // The payload source may begin before the sequence number we are sending, for
+                // example when it also holds the bytes which were sent but not acked yet.
+                let payload = PayloadView {
+                    buf: read_buf,
+                    offset: (seq_to_send - payload_seq).0 as usize,
+                };
 
                 // We always set the ACK flag for data segments.
                 let tcp_flags = TcpFlags::ACK;
@@ -946,7 +981,7 @@ impl Connection {
                     seq_to_send,
                     ack_to_send,
                     tcp_flags,
-                    Some((read_buf, max_payload_len)),
+                    Some((&payload, max_payload_len)),
                 )?;
 
                 // If self.dup_ack was Some(_), we've just written the retransmission segment,
@@ -1778,4 +1813,36 @@ pub(crate) mod tests {
         // and we don't wait for our FIN to be ACKed.
         assert!(c.is_done());
     }
+
+    #[test]
+    fn test_payload_src_offset() {
+        let mut buf1 = [0u8; 100];
+        let mut buf2 = [0u8; 100];
+        let send_buf: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+
+        let mut t = ConnectionTester::new();
+        let syn = t.write_syn(buf1.as_mut());
+        let mut ctrl = t.write_ctrl(buf2.as_mut());
+
+        let mut c = t.passive_open(&syn).unwrap();
+        t.check_synack_is_next(&mut c);
+        let conn_isn = c.first_not_sent.0.wrapping_sub(1);
+        ctrl.set_flags_after_ns(TcpFlags::ACK)
+            .set_ack_number(conn_isn.wrapping_add(1));
+        t.receive_segment(&mut c, &ctrl).unwrap();
+        check_established(&c);
+
+        // The payload source starts at the first byte which is not acked yet, so every segment
+        // after the first one carries data from somewhere in the middle of the buffer.
+        let payload_src = Some((send_buf.as_ref(), c.highest_ack_received));
+        let mss = usize::from(t.mss);
+        for i in 0..3 {
+            let s = t.write_next_segment(&mut c, payload_src).unwrap().unwrap();
+            assert_eq!(
+                s.sequence_number(),
+                conn_isn.wrapping_add(1 + (i * mss) as u32)
+            );
+            assert_eq!(s.payload(), &send_buf[i * mss..(i + 1) * mss]);
+        }
+    }
 }
@@ -49,9 +49,12 @@ impl ApiServerAdapter {
             event_manager
                 .run()
                 .expect("EventManager events driver fatal error");
-            if let Some(exit_code) = vmm.lock().unwrap().shutdown_exit_code() {
+            let mut locked_vmm = vmm.lock().unwrap();
+            if let Some(exit_code) = locked_vmm.shutdown_exit_code() {
                 return exit_code;
             }
+            // Hook the devices plugged or unplugged by the API requests to the event manager.
+            locked_vmm.update_event_subscribers(event_manager);
         }
     }
 
@@ -7,9 +7,10 @@
 
 mod api_server_adapter;
 mod metrics;
+mod snapshot_info;
 
 use std::fs::{self, File};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::{io, panic, process};
 
@@ -221,6 +222,21 @@ fn main_exitable() -> FcExitCode {
                 .takes_value(true)
                 .help("Print the data format version of the provided snapshot state file."),
         )
+        .arg(
+            Argument::new("inspect-snapshot")
+                .takes_value(true)
+                .help("Print the microVM state saved in the provided snapshot state file as JSON."),
+        )
+        .arg(
+            Argument::new("compare-snapshot")
+                .takes_value(true)
+                .requires("inspect-snapshot")
+                .help(
+                    "Print the differences between the microVM state saved in the snapshot state \
+                     file provided to `--inspect-snapshot` and the one saved in this snapshot \
+                     state file as JSON.",
+                ),
+        )
         .arg(
             Argument::new("http-api-max-payload-size")
                 .takes_value(true)
@@ -259,6 +275,13 @@ fn main_exitable() -> FcExitCode {
                 return vmm::FcExitCode::Ok;
             }
 
+            if let Some(snapshot_path) = arg_parser.arguments().single_value("inspect-snapshot") {
+                return inspect_snapshot(
+                    snapshot_path,
+                    arg_parser.arguments().single_value("compare-snapshot"),
+                );
+            }
+
             arg_parser.arguments()
         }
     };
@@ -480,6 +503,37 @@ fn print_snapshot_data_format(snapshot_path: &str) {
     println!("v{}", key);
 }
 
+// Print the microVM state saved in a snapshot state file as JSON, or its differences
+// with the one saved in another snapshot state file.
+fn inspect_snapshot(snapshot_path: &str, other_snapshot_path: Option<&String>) -> FcExitCode {
+    let describe = |path: &str| {
+        snapshot_info::describe_snapshot(Path::new(path)).map_err(|err| {
+            generic_error_exit(&format!(
+                "Unable to inspect snapshot state file {}: {:?}",
+                path, err
+            ))
+        })
+    };
+
+    let description = match describe(snapshot_path) {
+        Ok(description) => description,
+        Err(exit_code) => return exit_code,
+    };
+    let output = match other_snapshot_path {
+        Some(other_snapshot_path) => match describe(other_snapshot_path) {
+            Ok(other_description) => {
+                serde_json::Value::from(snapshot_info::diff(&description, &other_description))
+            }
+            Err(exit_code) => return exit_code,
+        },
+        None => description,
+    };
+
+    // Serializing a `serde_json::Value` cannot fail.
+    println!("{}", serde_json::to_string_pretty(&output).unwrap());
+    vmm::FcExitCode::Ok
+}
+
 // Configure and start a microVM as described by the command-line JSON.
 fn build_microvm_from_json(
     seccomp_filters: &BpfThreadMap,
@@ -0,0 +1,136 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Describes the microVM state saved in snapshot files as JSON, and compares them.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use serde_json::{json, Value};
+use snapshot::Snapshot;
+use vmm::persist::{snapshot_state_from_file, SnapshotStateFromFileError};
+use vmm::version_map::{FC_VERSION_TO_SNAP_VERSION, VERSION_MAP};
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// Failed to open the snapshot file.
+    Open(io::Error),
+    /// Failed to read the data format version of the snapshot file.
+    DataVersion(snapshot::Error),
+    /// Failed to load the microVM state.
+    Load(SnapshotStateFromFileError),
+    /// Failed to describe the microVM state.
+    Describe(serde_json::Error),
+}
+
+/// Describes the snapshot file at `snapshot_path`: its data format version and the
+/// microVM state it holds.
+pub(crate) fn describe_snapshot(snapshot_path: &Path) -> Result<Value, Error> {
+    let mut snapshot_reader = File::open(snapshot_path).map_err(Error::Open)?;
+    let data_version = Snapshot::get_data_version(&mut snapshot_reader, &VERSION_MAP)
+        .map_err(Error::DataVersion)?;
+    let version = FC_VERSION_TO_SNAP_VERSION
+        .iter()
+        .find(|(_, &val)| val == data_version)
+        .map(|(key, _)| key.clone());
+
+    let microvm_state =
+        snapshot_state_from_file(snapshot_path, VERSION_MAP.clone()).map_err(Error::Load)?;
+    let state = serde_json::to_value(&microvm_state).map_err(Error::Describe)?;
+
+    Ok(json!({
+        "version": version,
+        "data_version": data_version,
+        "state": state,
+    }))
+}
+
+/// Lists the differences between two JSON descriptions, as objects holding the path of
+/// the differing value and its value on each side. Missing values are described as `null`.
+pub(crate) fn diff(left: &Value, right: &Value) -> Vec<Value> {
+    let mut differences = Vec::new();
+    diff_values(String::new(), Some(left), Some(right), &mut differences);
+    differences
+}
+
+fn diff_values(
+    path: String,
+    left: Option<&Value>,
+    right: Option<&Value>,
+    differences: &mut Vec<Value>,
+) {
+    match (left, right) {
+        (Some(Value::Object(left)), Some(Value::Object(right))) => {
+            let right_only = right.keys().filter(|key| !left.contains_key(*key));
+            for key in left.keys().chain(right_only) {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+                diff_values(path, left.get(key), right.get(key), differences);
+            }
+        }
+        (Some(Value::Array(left)), Some(Value::Array(right))) => {
+            for index in 0..left.len().max(right.len()) {
+                diff_values(
+                    format!("{}[{}]", path, index),
+                    left.get(index),
+                    right.get(index),
+                    differences,
+                );
+            }
+        }
+        _ if left != right => differences.push(json!({
+            "path": path,
+            "left": left,
+            "right": right,
+        })),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let left = json!({
+            "version": "1.2.0",
+            "state": {
+                "vcpu_states": [{ "regs": { "rip": 1, "rsp": 2 } }],
+                "device_states": { "vsock_device": null },
+            },
+        });
+        assert!(diff(&left, &left).is_empty());
+
+        let right = json!({
+            "version": "1.1.0",
+            "state": {
+                "vcpu_states": [{ "regs": { "rip": 3, "rsp": 2 } }, { "regs": {} }],
+                "device_states": { "vsock_device": null, "balloon_device": { "id": "b" } },
+            },
+        });
+        assert_eq!(
+            diff(&left, &right),
+            vec![
+                json!({
+                    "path": "state.device_states.balloon_device",
+                    "left": null,
+                    "right": { "id": "b" },
+                }),
+                json!({ "path": "state.vcpu_states[0].regs.rip", "left": 1, "right": 3 }),
+                json!({ "path": "state.vcpu_states[1]", "left": null, "right": { "regs": {} } }),
+                json!({ "path": "version", "left": "1.2.0", "right": "1.1.0" }),
+            ]
+        );
+
+        // Values of different types are reported as a whole.
+        assert_eq!(
+            diff(&json!({ "a": [1] }), &json!({ "a": { "b": 1 } })),
+            vec![json!({ "path": "a", "left": [1], "right": { "b": 1 } })]
+        );
+    }
+}
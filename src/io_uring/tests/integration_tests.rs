@@ -363,3 +363,37 @@ fn test_read() {
     // Verify the result.
     assert_eq!(buf, &init_contents[..]);
 }
+
+#[test]
+fn test_fallocate() {
+    skip_if_io_uring_unsupported!();
+
+    // Test that punching a hole in a file zeroes the range.
+
+    const NUM_BYTES: usize = 8192;
+    // Setup.
+    let file = TempFile::new().unwrap().into_file();
+    let mut ring = IoUring::new(NUM_ENTRIES, vec![&file], vec![], None).unwrap();
+    file.write_all_at(&[0xaa; NUM_BYTES], 0).unwrap();
+
+    // Perform the IO.
+    unsafe {
+        ring.push(Operation::fallocate(
+            0,
+            4096,
+            (libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE) as u32,
+            4096,
+            71,
+        ))
+        .unwrap()
+    };
+    assert_eq!(ring.submit_and_wait_all().unwrap(), 1);
+    assert!(unsafe { ring.pop::<u8>().unwrap().unwrap().result().is_ok() });
+
+    // Verify the result.
+    let mut buf = [0u8; NUM_BYTES];
+    file.read_exact_at(&mut buf, 0).unwrap();
+    assert_eq!(buf[..4096], [0xaa; 4096]);
+    assert_eq!(buf[4096..], [0; 4096]);
+    assert_eq!(file.metadata().unwrap().len(), NUM_BYTES as u64);
+}
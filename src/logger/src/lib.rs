@@ -21,8 +21,9 @@ pub use crate::logger::{LoggerError, LOGGER};
 #[cfg(target_arch = "aarch64")]
 pub use crate::metrics::RTCDeviceMetrics;
 pub use crate::metrics::{
-    IncMetric, MetricsError, ProcessTimeReporter, SerialDeviceMetrics, SharedIncMetric,
-    SharedStoreMetric, StoreMetric, METRICS,
+    BlockDriveMetrics, IncMetric, MetricsError, ProcessTimeReporter, SerialDeviceMetrics,
+    SharedHistogramMetric, SharedIncMetric, SharedInflightMetric, SharedStoreMetric, StoreMetric,
+    METRICS,
 };
 
 /// Prefix to be used in log lines for functions/modules in Firecracker
@@ -44,12 +44,16 @@
 //! * Since all metrics start at 0, we implement the `Default` trait via derive for all of them, to
 //!   avoid having to initialize everything by hand.
 //!
-//! The system implements 2 types of metrics:
+//! The system implements 4 types of metrics:
 //! * Shared Incremental Metrics (SharedIncMetrics) - dedicated for the metrics which need a counter
 //! (i.e the number of times an API request failed). These metrics are reset upon flush.
 //! * Shared Store Metrics (SharedStoreMetrics) - are targeted at keeping a persistent value, it is
 //!   not
 //! intended to act as a counter (i.e for measure the process start up time for example).
+//! * Shared Histogram Metrics (SharedHistogramMetrics) - count the recorded values, such as the
+//!   latencies of the block requests, in buckets. These metrics are reset upon flush.
+//! * Shared Inflight Metrics (SharedInflightMetrics) - keep the number of operations in progress
+//!   and the highest number reached since the last flush.
 //!
 //! The current approach for the `SharedIncMetrics` type is to store two values (current and
 //! previous) and compute the delta between them each time we do a flush (i.e by serialization).
@@ -61,13 +65,15 @@
 //! If if turns out this approach is not really what we want, it's pretty easy to resort to
 //! something else, while working behind the same interface.
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::io::Write;
 use std::ops::Deref;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
 use lazy_static::lazy_static;
+use serde::ser::SerializeMap;
 use serde::{Serialize, Serializer};
 #[cfg(target_arch = "aarch64")]
 use vm_superio::rtc_pl031::RtcEvents;
@@ -300,6 +306,110 @@ impl Serialize for SharedStoreMetric {
     }
 }
 
+/// Upper bounds, in microseconds, of the buckets of the latency histograms. The values above
+/// the last bound are counted in an extra bucket.
+pub const LATENCY_BUCKETS_US: [u64; 12] = [
+    10, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 50_000, 100_000, 1_000_000,
+];
+
+/// Representation of a histogram of values, such as latencies in microseconds, which can be
+/// recorded from more than one thread.
+// Each bucket is a `SharedIncMetric`, so only the values recorded since the last flush are
+// serialized.
+#[derive(Default)]
+pub struct SharedHistogramMetric {
+    count: SharedIncMetric,
+    sum: SharedIncMetric,
+    buckets: [SharedIncMetric; LATENCY_BUCKETS_US.len() + 1],
+}
+
+impl SharedHistogramMetric {
+    /// Records `value` in the bucket with the lowest upper bound above or equal to it.
+    pub fn record(&self, value: u64) {
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[bucket].inc();
+        self.count.inc();
+        self.sum.add(value as usize);
+    }
+
+    /// Returns the number of recorded values.
+    pub fn count(&self) -> usize {
+        self.count.count()
+    }
+
+    /// Records the time elapsed since `start_time_us`, a monotonic timestamp in microseconds.
+    pub fn record_elapsed_time(&self, start_time_us: u64) {
+        let now_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+        self.record(now_us.saturating_sub(start_time_us));
+    }
+}
+
+// Serializes the buckets of a histogram as a map from their upper bound to their count.
+struct HistogramBuckets<'a>(&'a [SharedIncMetric]);
+
+impl Serialize for HistogramBuckets<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (bound, bucket) in LATENCY_BUCKETS_US.iter().zip(self.0) {
+            map.serialize_entry(&bound.to_string(), bucket)?;
+        }
+        map.serialize_entry("inf", &self.0[LATENCY_BUCKETS_US.len()])?;
+        map.end()
+    }
+}
+
+impl Serialize for SharedHistogramMetric {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(3))?;
+        map.serialize_entry("count", &self.count)?;
+        map.serialize_entry("sum", &self.sum)?;
+        map.serialize_entry("buckets", &HistogramBuckets(&self.buckets))?;
+        map.end()
+    }
+}
+
+/// Representation of a metric keeping the number of operations in progress, such as the
+/// requests submitted to a block device backend, along with the highest number reached since
+/// the last flush.
+#[derive(Default)]
+pub struct SharedInflightMetric {
+    current: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl SharedInflightMetric {
+    /// Accounts for an operation that started.
+    pub fn begin(&self) {
+        let current = self.current.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max.fetch_max(current, Ordering::Relaxed);
+    }
+
+    /// Accounts for an operation that completed.
+    pub fn end(&self) {
+        self.current.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Returns the number of operations in progress.
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+}
+
+impl Serialize for SharedInflightMetric {
+    /// Resets the highest number of operations in progress to the current one.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let current = self.current.load(Ordering::Relaxed);
+        let max = self.max.swap(current, Ordering::Relaxed).max(current);
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("current", &(current as u64))?;
+        map.serialize_entry("max", &(max as u64))?;
+        map.end()
+    }
+}
+
 /// Reporter object which computes the process wall time and
 /// process CPU time and populates the metric with the results.
 pub struct ProcessTimeReporter {
@@ -501,6 +611,14 @@ pub struct BlockDeviceMetrics {
     pub read_count: SharedIncMetric,
     /// Number of successful write operations.
     pub write_count: SharedIncMetric,
+    /// Number of bytes discarded by this block device.
+    pub discard_bytes: SharedIncMetric,
+    /// Number of successful discard operations.
+    pub discard_count: SharedIncMetric,
+    /// Number of bytes zeroed by this block device.
+    pub write_zeroes_bytes: SharedIncMetric,
+    /// Number of successful write zeroes operations.
+    pub write_zeroes_count: SharedIncMetric,
     /// Number of rate limiter throttling events.
     pub rate_limiter_throttled_events: SharedIncMetric,
     /// Number of virtio events throttled because of the IO engine.
@@ -508,6 +626,82 @@ pub struct BlockDeviceMetrics {
     pub io_engine_throttled_events: SharedIncMetric,
 }
 
+/// Metrics of a single block device, reported under its drive id.
+#[derive(Default, Serialize)]
+pub struct BlockDriveMetrics {
+    /// Number of bytes read by this drive.
+    pub read_bytes: SharedIncMetric,
+    /// Number of bytes written by this drive.
+    pub write_bytes: SharedIncMetric,
+    /// Number of successful read operations.
+    pub read_count: SharedIncMetric,
+    /// Number of successful write operations.
+    pub write_count: SharedIncMetric,
+    /// Number of successful flush operations.
+    pub flush_count: SharedIncMetric,
+    /// Latency of the read requests, from their processing to their completion, in
+    /// microseconds.
+    pub read_latency_us: SharedHistogramMetric,
+    /// Latency of the write requests, from their processing to their completion, in
+    /// microseconds.
+    pub write_latency_us: SharedHistogramMetric,
+    /// Latency of the flush requests, from their processing to their completion, in
+    /// microseconds.
+    pub flush_latency_us: SharedHistogramMetric,
+    /// Number of requests submitted to the IO engines and not completed yet.
+    pub queue_depth: SharedInflightMetric,
+    /// Time during which the queues were blocked by the rate limiter, in microseconds.
+    pub rate_limiter_throttled_time_us: SharedIncMetric,
+    /// Time during which the queues were blocked by full IO engines, in microseconds.
+    pub io_engine_throttled_time_us: SharedIncMetric,
+}
+
+// Metrics of a drive, with the number of block devices of that drive which report them.
+#[derive(Default)]
+struct BlockDriveMetricsEntry {
+    metrics: Arc<BlockDriveMetrics>,
+    devices: usize,
+}
+
+/// Metrics of the block devices, by drive id.
+#[derive(Default)]
+pub struct BlockDriveMetricsMap(RwLock<BTreeMap<String, BlockDriveMetricsEntry>>);
+
+impl BlockDriveMetricsMap {
+    /// Returns the metrics of the drive `drive_id`, which are created if the drive has none.
+    /// Every call is paired with a call to `release` once the block device is dropped.
+    pub fn get(&self, drive_id: &str) -> Arc<BlockDriveMetrics> {
+        let mut map = extract_guard(self.0.write());
+        let entry = map.entry(drive_id.to_string()).or_default();
+        entry.devices += 1;
+        entry.metrics.clone()
+    }
+
+    /// Stops reporting the metrics of the drive `drive_id` once the last of its block devices
+    /// is dropped. Drives can be replaced by drives with the same id, which keep the same
+    /// metrics. The metrics can still be updated afterwards by the requests left in flight.
+    pub fn release(&self, drive_id: &str) {
+        let mut map = extract_guard(self.0.write());
+        if let Some(entry) = map.get_mut(drive_id) {
+            entry.devices -= 1;
+            if entry.devices == 0 {
+                map.remove(drive_id);
+            }
+        }
+    }
+}
+
+impl Serialize for BlockDriveMetricsMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let map = extract_guard(self.0.read());
+        let mut serialized = serializer.serialize_map(Some(map.len()))?;
+        for (drive_id, entry) in map.iter() {
+            serialized.serialize_entry(drive_id, entry.metrics.as_ref())?;
+        }
+        serialized.end()
+    }
+}
+
 /// Metrics specific to the i8042 device.
 #[derive(Default, Serialize)]
 pub struct I8042DeviceMetrics {
@@ -538,6 +732,20 @@ pub struct LoggerSystemMetrics {
     pub log_fails: SharedIncMetric,
 }
 
+/// Metrics related to the live migration of the microVM.
+#[derive(Default, Serialize)]
+pub struct MigrationMetrics {
+    /// Number of guest memory copy rounds performed while the vCPUs were running,
+    /// during the last migration.
+    pub precopy_rounds: SharedStoreMetric,
+    /// Number of dirty pages sent while the vCPUs were paused, during the last migration.
+    pub final_dirty_pages: SharedStoreMetric,
+    /// Number of failures in sending the microVM to another process.
+    pub send_fails: SharedIncMetric,
+    /// Number of failures in receiving the microVM from another process.
+    pub receive_fails: SharedIncMetric,
+}
+
 /// Metrics for the MMDS functionality.
 #[derive(Default, Serialize)]
 pub struct MmdsMetrics {
@@ -570,6 +778,10 @@ pub struct MmdsMetrics {
 pub struct NetDeviceMetrics {
     /// Number of times when activate failed on a network device.
     pub activate_fails: SharedIncMetric,
+    /// Number of frames written to the packet capture file.
+    pub capture_packets_count: SharedIncMetric,
+    /// Number of times writing to the packet capture file failed.
+    pub capture_fails: SharedIncMetric,
     /// Number of times when interacting with the space config of a network device failed.
     pub cfg_fails: SharedIncMetric,
     //// Number of times the mac address was updated through the config space.
@@ -588,6 +800,8 @@ pub struct NetDeviceMetrics {
     pub rx_partial_writes: SharedIncMetric,
     /// Number of RX rate limiter throttling events.
     pub rx_rate_limiter_throttled: SharedIncMetric,
+    /// Number of RX frames dropped because the link is down.
+    pub rx_link_down_dropped: SharedIncMetric,
     /// Number of events received on the associated tap.
     pub rx_tap_event_count: SharedIncMetric,
     /// Number of bytes received.
@@ -620,8 +834,43 @@ pub struct NetDeviceMetrics {
     pub tx_rate_limiter_event_count: SharedIncMetric,
     /// Number of RX rate limiter throttling events.
     pub tx_rate_limiter_throttled: SharedIncMetric,
+    /// Number of TX frames dropped because the link is down.
+    pub tx_link_down_dropped: SharedIncMetric,
     /// Number of packets with a spoofed mac, sent by the guest.
     pub tx_spoofed_mac_count: SharedIncMetric,
+    /// Number of TX frames dropped by the anti-spoofing filter due to their source MAC.
+    pub tx_spoofed_mac_dropped: SharedIncMetric,
+    /// Number of TX frames dropped by the anti-spoofing filter due to their sender IPv4 address.
+    pub tx_spoofed_ip_dropped: SharedIncMetric,
+    /// Number of TX frames dropped by the anti-spoofing filter due to their EtherType.
+    pub tx_unsupported_ethertype_dropped: SharedIncMetric,
+}
+
+/// Metrics of the userspace network stack of the net devices which have no tap.
+#[derive(Default, Serialize)]
+pub struct UserNetworkMetrics {
+    /// Number of frames sent by the guest which the stack dropped.
+    pub rx_frames_dropped: SharedIncMetric,
+    /// Number of errors while writing a frame for the guest.
+    pub tx_errors: SharedIncMetric,
+    /// Number of replies to the DHCP requests of the guest.
+    pub dhcp_replies: SharedIncMetric,
+    /// Number of TCP connections opened by the guest.
+    pub tcp_connections_created: SharedIncMetric,
+    /// Number of TCP connections cleaned up by the stack.
+    pub tcp_connections_destroyed: SharedIncMetric,
+    /// Number of TCP connections which couldn't be proxied to the host.
+    pub tcp_connect_fails: SharedIncMetric,
+    /// Number of TCP connections and UDP flows to destinations the guest can't reach.
+    pub unreachable_destinations: SharedIncMetric,
+    /// Number of UDP flows opened by the guest.
+    pub udp_flows_created: SharedIncMetric,
+    /// Number of UDP flows cleaned up by the stack.
+    pub udp_flows_destroyed: SharedIncMetric,
+    /// Number of failed operations on host sockets.
+    pub host_socket_fails: SharedIncMetric,
+    /// Number of times when handling the events of the host sockets failed.
+    pub event_fails: SharedIncMetric,
 }
 
 /// Performance metrics related for the moment only to snapshots.
@@ -638,18 +887,40 @@ pub struct PerformanceMetrics {
     pub full_create_snapshot: SharedStoreMetric,
     /// Measures the snapshot diff create time, at the API (user) level, in microseconds.
     pub diff_create_snapshot: SharedStoreMetric,
+    /// Measures the snapshot live create time, at the API (user) level, in microseconds.
+    pub live_create_snapshot: SharedStoreMetric,
     /// Measures the snapshot load time, at the API (user) level, in microseconds.
     pub load_snapshot: SharedStoreMetric,
     /// Measures the microVM pausing duration, at the API (user) level, in microseconds.
     pub pause_vm: SharedStoreMetric,
     /// Measures the microVM resuming duration, at the API (user) level, in microseconds.
     pub resume_vm: SharedStoreMetric,
+    /// Measures the time needed to send the microVM to another process, at the API (user)
+    /// level, in microseconds.
+    pub send_migration: SharedStoreMetric,
+    /// Measures the time needed to receive the microVM from another process, at the API
+    /// (user) level, in microseconds.
+    pub receive_migration: SharedStoreMetric,
     /// Measures the snapshot full create time, at the VMM level, in microseconds.
     pub vmm_full_create_snapshot: SharedStoreMetric,
     /// Measures the snapshot diff create time, at the VMM level, in microseconds.
     pub vmm_diff_create_snapshot: SharedStoreMetric,
+    /// Measures the snapshot live create time, at the VMM level, in microseconds.
+    pub vmm_live_create_snapshot: SharedStoreMetric,
+    /// Measures for how long the vCPUs were paused while creating a live snapshot,
+    /// in microseconds.
+    pub vmm_live_snapshot_downtime: SharedStoreMetric,
     /// Measures the snapshot load time, at the VMM level, in microseconds.
     pub vmm_load_snapshot: SharedStoreMetric,
+    /// Measures the time needed to send the microVM to another process, at the VMM level,
+    /// in microseconds.
+    pub vmm_send_migration: SharedStoreMetric,
+    /// Measures the time needed to receive the microVM from another process, at the VMM
+    /// level, in microseconds.
+    pub vmm_receive_migration: SharedStoreMetric,
+    /// Measures for how long the vCPUs were paused while migrating the microVM, including
+    /// the restore on the destination, in microseconds.
+    pub vmm_migration_downtime: SharedStoreMetric,
     /// Measures the microVM pausing duration, at the VMM level, in microseconds.
     pub vmm_pause_vm: SharedStoreMetric,
     /// Measures the microVM resuming duration, at the VMM level, in microseconds.
@@ -690,6 +961,17 @@ pub struct SeccompMetrics {
     pub num_faults: SharedStoreMetric,
 }
 
+/// Metrics related to the snapshot creation process.
+#[derive(Default, Serialize)]
+pub struct SnapshotMetrics {
+    /// Number of guest memory copy rounds performed while the vCPUs were running,
+    /// during the last live snapshot creation.
+    pub live_precopy_rounds: SharedStoreMetric,
+    /// Number of dirty pages copied while the vCPUs were paused, during the last
+    /// live snapshot creation.
+    pub live_final_dirty_pages: SharedStoreMetric,
+}
+
 /// Metrics specific to the UART device.
 #[derive(Default, Serialize)]
 pub struct SerialDeviceMetrics {
@@ -822,6 +1104,8 @@ pub struct FirecrackerMetrics {
     pub balloon: BalloonDeviceMetrics,
     /// A block device's related metrics.
     pub block: BlockDeviceMetrics,
+    /// Metrics of each block device, by drive id.
+    pub block_drives: BlockDriveMetricsMap,
     /// Metrics related to deprecated API calls.
     pub deprecated_api: DeprecatedApiMetrics,
     /// Metrics related to API GET requests.
@@ -832,6 +1116,8 @@ pub struct FirecrackerMetrics {
     pub latencies_us: PerformanceMetrics,
     /// Logging related metrics.
     pub logger: LoggerSystemMetrics,
+    /// Metrics related to live migration.
+    pub migration: MigrationMetrics,
     /// Metrics specific to MMDS functionality.
     pub mmds: MmdsMetrics,
     /// A network device's related metrics.
@@ -845,6 +1131,10 @@ pub struct FirecrackerMetrics {
     pub rtc: Arc<RTCDeviceMetrics>,
     /// Metrics related to seccomp filtering.
     pub seccomp: SeccompMetrics,
+    /// Metrics related to snapshot creation.
+    pub snapshot: SnapshotMetrics,
+    /// Metrics of the userspace network stack.
+    pub user_net: UserNetworkMetrics,
     /// Metrics related to a vcpu's functioning.
     pub vcpu: VcpuMetrics,
     /// Metrics related to the virtual machine manager.
@@ -929,6 +1219,74 @@ mod tests {
         assert_eq!(1, m1.fetch());
     }
 
+    #[test]
+    fn test_shared_histogram_metric() {
+        let histogram = SharedHistogramMetric::default();
+        histogram.record(0);
+        histogram.record(10);
+        histogram.record(11);
+        histogram.record(2_000_000);
+        assert_eq!(histogram.count(), 4);
+
+        let value = serde_json::to_value(&histogram).unwrap();
+        assert_eq!(value["count"], 4);
+        assert_eq!(value["sum"], 2_000_021);
+        assert_eq!(value["buckets"]["10"], 2);
+        assert_eq!(value["buckets"]["50"], 1);
+        assert_eq!(value["buckets"]["100"], 0);
+        assert_eq!(value["buckets"]["inf"], 1);
+
+        // Only the values recorded since the last flush are reported.
+        histogram.record(60);
+        let value = serde_json::to_value(&histogram).unwrap();
+        assert_eq!(value["count"], 1);
+        assert_eq!(value["buckets"]["10"], 0);
+        assert_eq!(value["buckets"]["100"], 1);
+    }
+
+    #[test]
+    fn test_shared_inflight_metric() {
+        let inflight = SharedInflightMetric::default();
+        inflight.begin();
+        inflight.begin();
+        inflight.end();
+        assert_eq!(inflight.current(), 1);
+
+        let value = serde_json::to_value(&inflight).unwrap();
+        assert_eq!(value["current"], 1);
+        assert_eq!(value["max"], 2);
+
+        // The maximum is reset to the current value on flush.
+        let value = serde_json::to_value(&inflight).unwrap();
+        assert_eq!(value["max"], 1);
+    }
+
+    #[test]
+    fn test_block_drive_metrics_map() {
+        let map = BlockDriveMetricsMap::default();
+        let metrics = map.get("rootfs");
+        metrics.read_count.add(5);
+        // A drive replacing another one with the same id keeps its metrics.
+        let new_metrics = map.get("rootfs");
+        assert!(Arc::ptr_eq(&metrics, &new_metrics));
+        map.release("rootfs");
+        drop(metrics);
+
+        let value = serde_json::to_value(&map).unwrap();
+        assert_eq!(value["rootfs"]["read_count"], 5);
+
+        // The metrics stop being reported once the last drive is released, even if requests
+        // left in flight still hold them.
+        map.release("rootfs");
+        new_metrics.read_count.inc();
+        assert_eq!(serde_json::to_string(&map).unwrap(), "{}");
+        drop(new_metrics);
+
+        // Releasing an unknown drive is a no-op.
+        map.release("rootfs");
+        assert_eq!(serde_json::to_string(&map).unwrap(), "{}");
+    }
+
     #[test]
     fn test_serialize() {
         let s = serde_json::to_string(&FirecrackerMetrics::default());
@@ -147,6 +147,15 @@ impl Mmds {
             .and_then(|ta| ta.generate_token_secret(ttl_seconds))
     }
 
+    /// Generate a new encryption key for the session tokens when MMDS version 2
+    /// is enabled, invalidating the tokens generated so far.
+    pub fn regenerate_token_key(&mut self) -> Result<(), TokenError> {
+        match self.token_authority.as_mut() {
+            Some(ta) => ta.regenerate_key(),
+            None => Ok(()),
+        }
+    }
+
     pub fn set_data_store_limit(&mut self, data_store_limit: usize) {
         self.data_store_limit = data_store_limit;
     }
@@ -612,4 +621,17 @@ mod tests {
             TokenError::InvalidState.to_string()
         );
     }
+
+    #[test]
+    fn test_regenerate_token_key() {
+        let mut mmds = Mmds::default();
+        // Nothing to regenerate with MMDS V1.
+        mmds.regenerate_token_key().unwrap();
+
+        mmds.set_version(MmdsVersion::V2).unwrap();
+        let token = mmds.generate_token(60).unwrap();
+        assert!(mmds.is_valid_token(&token).unwrap());
+        mmds.regenerate_token_key().unwrap();
+        assert!(!mmds.is_valid_token(&token).unwrap());
+    }
 }
@@ -114,6 +114,15 @@ impl TokenAuthority {
         self.aad = format!("microvmid={}", instance_id);
     }
 
+    /// Reinitialize the cipher entity under a new random key.
+    /// As a result, all valid tokens created under the previous key are invalidated.
+    pub fn regenerate_key(&mut self) -> Result<(), Error> {
+        self.cipher = TokenAuthority::create_cipher(&mut self.entropy_pool)?;
+        // Reset encrypted tokens count.
+        self.num_encrypted_tokens = 0;
+        Ok(())
+    }
+
     /// Generate encoded token string using the token time to live provided.
     pub fn generate_token_secret(&mut self, ttl_seconds: u32) -> Result<String, Error> {
         // Check number of tokens encrypted under the current key. We need to
@@ -248,9 +257,7 @@ impl TokenAuthority {
             // healthy interactions with MMDS. However, if it happens, we expect the
             // customer code to have a retry mechanism in place and regenerate the
             // session token if the previous ones become invalid.
-            self.cipher = TokenAuthority::create_cipher(&mut self.entropy_pool)?;
-            // Reset encrypted tokens count.
-            self.num_encrypted_tokens = 0;
+            self.regenerate_key()?;
             warn!(
                 "The limit of tokens generated under current MMDS token authority
                 has been reached. MMDS's token authority entity has been reseeded
@@ -530,6 +537,20 @@ mod tests {
         assert!(!token_authority.is_valid(&token1));
     }
 
+    #[test]
+    fn test_regenerate_key() {
+        let mut token_authority = TokenAuthority::new().unwrap();
+        let token0 = token_authority.generate_token_secret(60).unwrap();
+        assert!(token_authority.is_valid(&token0));
+
+        // Tokens created under the previous key are invalidated.
+        token_authority.regenerate_key().unwrap();
+        assert_eq!(token_authority.num_encrypted_tokens, 0);
+        assert!(!token_authority.is_valid(&token0));
+        let token1 = token_authority.generate_token_secret(60).unwrap();
+        assert!(token_authority.is_valid(&token1));
+    }
+
     #[test]
     fn test_error_display() {
         assert_eq!(
@@ -6,29 +6,58 @@
 #![warn(clippy::cast_lossless)]
 
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom};
-use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::{env, process};
 
+use snapshot::diff_chain::{copy_range, ChainError, DiffChain, MemoryLayer};
+use snapshot::diff_manifest::{DiffManifest, ManifestError, RegionDirtyPages};
 use utils::arg_parser::{ArgParser, Argument, Arguments};
-use utils::seek_hole::SeekHole;
+use vmm::memory_snapshot::GuestMemoryState;
+use vmm::persist::{snapshot_state_from_file, SnapshotStateFromFileError};
+use vmm::version_map::VERSION_MAP;
 
 const REBASE_SNAP_VERSION: &str = env!("FIRECRACKER_VERSION");
 const EXIT_CODE_SUCCESS: i32 = 0;
 const BASE_FILE: &str = "base-file";
 const DIFF_FILE: &str = "diff-file";
+const DIFF_MANIFEST: &str = "diff-manifest";
+const VMSTATE_FILE: &str = "vmstate-file";
+const INSPECT: &str = "inspect";
+const OUTPUT_FILE: &str = "output-file";
+const OUTPUT_MANIFEST: &str = "output-manifest";
 
 #[derive(Debug)]
 enum Error {
     InvalidBaseFile(std::io::Error),
     InvalidDiffFile(std::io::Error),
-    SeekData(std::io::Error),
-    SeekHole(std::io::Error),
-    Seek(std::io::Error),
+    InvalidManifestFile(std::io::Error),
+    InvalidOutputFile(std::io::Error),
+    InvalidVmstateFile(SnapshotStateFromFileError),
+    LoadManifest(snapshot::Error),
+    InvalidManifest(ManifestError),
+    ManifestCount(usize, usize),
+    MissingManifest,
+    SaveManifest(snapshot::Error),
+    BaseFileSize(u64, u64),
+    ManifestSize(u64, u64),
+    DiffFileTooLong(u64, u64),
+    DiffFileTooShort(u64),
+    Chain(ChainError),
     Sendfile(std::io::Error),
     Metadata(std::io::Error),
 }
 
+/// What to do with the chain of diff files.
+#[derive(Debug)]
+enum Action {
+    /// Merge the diff files onto the base file.
+    Rebase,
+    /// Print which parts of the memory each diff file provides.
+    Inspect,
+    /// Flatten the diff files into a single diff file, with an optional manifest.
+    Flatten(File, Option<PathBuf>),
+}
+
 fn build_arg_parser<'a>() -> ArgParser<'a> {
     let arg_parser = ArgParser::new()
         .arg(
@@ -40,8 +69,43 @@ fn build_arg_parser<'a>() -> ArgParser<'a> {
         .arg(
             Argument::new(DIFF_FILE)
                 .required(true)
+                .allow_multiple(true)
+                .help(
+                    "File path of a diff mem snapshot. Can be repeated to process a chain of diff \
+                     snapshots, from the oldest to the newest.",
+                ),
+        )
+        .arg(Argument::new(DIFF_MANIFEST).allow_multiple(true).help(
+            "File path of the manifest of a diff mem snapshot. When present, only the pages \
+             listed in the manifest are copied, instead of the non-sparse sections of the diff \
+             file. Must be repeated once per diff file, in the same order.",
+        ))
+        .arg(Argument::new(VMSTATE_FILE).takes_value(true).help(
+            "File path of the microVM state snapshot created along with the mem snapshots. When \
+             present, the base and diff files are validated against the guest memory regions it \
+             describes, instead of the size of the base file.",
+        ))
+        .arg(
+            Argument::new(INSPECT)
+                .takes_value(false)
+                .forbids(vec![OUTPUT_FILE])
+                .help(
+                    "Print the memory regions provided by each diff file instead of merging them \
+                     onto the base file.",
+                ),
+        )
+        .arg(Argument::new(OUTPUT_FILE).takes_value(true).help(
+            "File path of a new diff mem snapshot holding all the diff files flattened together. \
+             The base file is left untouched.",
+        ))
+        .arg(
+            Argument::new(OUTPUT_MANIFEST)
                 .takes_value(true)
-                .help("File path of the diff mem snapshot."),
+                .requires(OUTPUT_FILE)
+                .help(
+                    "File path of the manifest of the flattened diff mem snapshot. Requires a \
+                     manifest for every diff file.",
+                ),
         );
 
     arg_parser
@@ -58,7 +122,8 @@ fn extract_args<'a>(arg_parser: &'a mut ArgParser<'a>) -> &'a Arguments<'a> {
     if arg_parser.arguments().flag_present("help") {
         println!("Rebase_snap v{}", REBASE_SNAP_VERSION);
         println!(
-            "Tool that copies all the non-sparse sections from a diff file onto a base file\n"
+            "Tool that copies all the non-sparse sections from a chain of diff files onto a base \
+             file\n"
         );
         println!("{}", arg_parser.formatted_help());
         process::exit(EXIT_CODE_SUCCESS);
@@ -71,50 +136,95 @@ fn extract_args<'a>(arg_parser: &'a mut ArgParser<'a>) -> &'a Arguments<'a> {
     arg_parser.arguments()
 }
 
-fn parse_args(args: &Arguments) -> Result<(File, File), Error> {
+fn load_manifest(manifest_file_path: &str) -> Result<DiffManifest, Error> {
+    let mut manifest_file = File::open(manifest_file_path).map_err(Error::InvalidManifestFile)?;
+    let manifest_len = manifest_file.metadata().map_err(Error::Metadata)?.len();
+    let manifest = DiffManifest::load(&mut manifest_file, manifest_len as usize)
+        .map_err(Error::LoadManifest)?;
+    manifest.validate().map_err(Error::InvalidManifest)?;
+    Ok(manifest)
+}
+
+fn load_memory_state(vmstate_file_path: &str) -> Result<GuestMemoryState, Error> {
+    let microvm_state = snapshot_state_from_file(Path::new(vmstate_file_path), VERSION_MAP.clone())
+        .map_err(Error::InvalidVmstateFile)?;
+    Ok(microvm_state.memory_state)
+}
+
+fn parse_args(
+    args: &Arguments,
+) -> Result<(File, DiffChain, Option<GuestMemoryState>, Action), Error> {
+    let action = if args.flag_present(INSPECT) {
+        Action::Inspect
+    } else if let Some(output_file_path) = args.single_value(OUTPUT_FILE) {
+        let output_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output_file_path)
+            .map_err(Error::InvalidOutputFile)?;
+        Action::Flatten(
+            output_file,
+            args.single_value(OUTPUT_MANIFEST).map(PathBuf::from),
+        )
+    } else {
+        Action::Rebase
+    };
+
     // Safe to unwrap since the required arguments are checked as part of
     // `arg_parser.parse_from_cmdline()`
     let base_file_path = args.single_value(BASE_FILE).unwrap();
+    // The base file is only written to when the diff files are merged onto it.
     let base_file = OpenOptions::new()
-        .write(true)
+        .read(true)
+        .write(matches!(action, Action::Rebase))
         .open(base_file_path)
         .map_err(Error::InvalidBaseFile)?;
+
     // Safe to unwrap since the required arguments are checked as part of
     // `arg_parser.parse_from_cmdline()`
-    let diff_file_path = args.single_value(DIFF_FILE).unwrap();
-    let diff_file = OpenOptions::new()
-        .read(true)
-        .open(diff_file_path)
-        .map_err(Error::InvalidDiffFile)?;
-
-    Ok((base_file, diff_file))
-}
+    let diff_file_paths = args.multiple_values(DIFF_FILE).unwrap();
+    let manifest_file_paths = args.multiple_values(DIFF_MANIFEST).unwrap_or_default();
+    if !manifest_file_paths.is_empty() && manifest_file_paths.len() != diff_file_paths.len() {
+        return Err(Error::ManifestCount(
+            manifest_file_paths.len(),
+            diff_file_paths.len(),
+        ));
+    }
 
-fn rebase(base_file: &mut File, diff_file: &mut File) -> Result<(), Error> {
-    let mut cursor: u64 = 0;
-    while let Some(block_start) = diff_file.seek_data(cursor).map_err(Error::SeekData)? {
-        cursor = block_start;
-        let block_end = match diff_file.seek_hole(block_start).map_err(Error::SeekHole)? {
-            Some(hole_start) => hole_start,
-            None => diff_file.metadata().map_err(Error::Metadata)?.len(),
+    let mut layers = Vec::with_capacity(diff_file_paths.len());
+    for (index, diff_file_path) in diff_file_paths.iter().enumerate() {
+        let diff_file = File::open(diff_file_path).map_err(Error::InvalidDiffFile)?;
+        let diff_manifest = match manifest_file_paths.get(index) {
+            Some(manifest_file_path) => Some(load_manifest(manifest_file_path)?),
+            None => None,
         };
+        layers.push(MemoryLayer::new(
+            PathBuf::from(diff_file_path),
+            diff_file,
+            diff_manifest,
+        ));
+    }
+
+    let mem_state = match args.single_value(VMSTATE_FILE) {
+        Some(vmstate_file_path) => Some(load_memory_state(vmstate_file_path)?),
+        None => None,
+    };
+
+    Ok((base_file, DiffChain::new(layers), mem_state, action))
+}
 
-        while cursor < block_end {
-            base_file
-                .seek(SeekFrom::Start(cursor))
-                .map_err(Error::Seek)?;
-
-            // SAFETY: Safe because the parameters are valid.
-            let num_transferred_bytes = unsafe {
-                libc::sendfile64(
-                    base_file.as_raw_fd(),
-                    diff_file.as_raw_fd(),
-                    (&mut cursor as *mut u64).cast::<i64>(),
-                    block_end.saturating_sub(cursor) as usize,
-                )
-            };
-            if num_transferred_bytes < 0 {
-                return Err(Error::Sendfile(std::io::Error::last_os_error()));
+fn rebase(base_file: &File, chain: &mut DiffChain) -> Result<(), Error> {
+    // The layers are merged from the oldest to the newest, so that the pages of each layer
+    // overwrite the ones of the older layers.
+    for layer in chain.layers_mut().iter_mut() {
+        match layer.manifest() {
+            Some(diff_manifest) => rebase_with_manifest(base_file, layer.file(), diff_manifest)?,
+            None => {
+                for (offset, len) in layer.data_ranges().map_err(Error::Chain)? {
+                    copy_range(base_file, layer.file(), offset, offset + len)
+                        .map_err(Error::Sendfile)?;
+                }
             }
         }
     }
@@ -122,14 +232,197 @@ fn rebase(base_file: &mut File, diff_file: &mut File) -> Result<(), Error> {
     Ok(())
 }
 
+fn rebase_with_manifest(
+    base_file: &File,
+    diff_file: &File,
+    diff_manifest: &DiffManifest,
+) -> Result<(), Error> {
+    // The manifest must describe the memory held by the base file, and the diff file cannot hold
+    // more than that memory.
+    let mem_size = diff_manifest.mem_size();
+    let base_file_len = base_file.metadata().map_err(Error::Metadata)?.len();
+    if mem_size != base_file_len {
+        return Err(Error::ManifestSize(mem_size, base_file_len));
+    }
+    let diff_file_len = diff_file.metadata().map_err(Error::Metadata)?.len();
+    if diff_file_len > mem_size {
+        return Err(Error::DiffFileTooLong(diff_file_len, mem_size));
+    }
+
+    let dirty_ranges = diff_manifest.dirty_ranges();
+    // Only the pages listed in the manifest need to be present in the diff file.
+    if let Some((offset, len)) = dirty_ranges.last() {
+        if offset + len > diff_file_len {
+            return Err(Error::DiffFileTooShort(diff_file_len));
+        }
+    }
+
+    for (offset, len) in dirty_ranges {
+        copy_range(base_file, diff_file, offset, offset + len).map_err(Error::Sendfile)?;
+    }
+
+    Ok(())
+}
+
+fn flatten(
+    chain: &mut DiffChain,
+    mem_size: u64,
+    output_file: &File,
+    output_manifest_path: Option<PathBuf>,
+) -> Result<(), Error> {
+    // Check that a manifest can be produced before writing anything.
+    let merged_manifest = match output_manifest_path {
+        Some(path) => Some((path, chain.merged_manifest().ok_or(Error::MissingManifest)?)),
+        None => None,
+    };
+
+    // The pages not provided by the chain are left as holes.
+    output_file
+        .set_len(mem_size)
+        .map_err(|err| Error::Chain(ChainError::Output(err)))?;
+    chain.copy_to(output_file).map_err(Error::Chain)?;
+
+    if let Some((path, manifest)) = merged_manifest {
+        let mut manifest_file = File::create(path).map_err(Error::InvalidOutputFile)?;
+        manifest
+            .save(&mut manifest_file)
+            .map_err(Error::SaveManifest)?;
+    }
+
+    Ok(())
+}
+
+// Returns the number of bytes of each region covered by `ranges`.
+fn region_usage(regions: &[RegionDirtyPages], ranges: &[(u64, u64)]) -> Vec<u64> {
+    regions
+        .iter()
+        .map(|region| {
+            let region_end = region.offset + region.size;
+            ranges
+                .iter()
+                .map(|&(offset, len)| {
+                    (offset + len)
+                        .min(region_end)
+                        .saturating_sub(offset.max(region.offset))
+                })
+                .sum()
+        })
+        .collect()
+}
+
+fn inspect(chain: &mut DiffChain, mem_size: u64) -> Result<String, Error> {
+    let mut report = format!("Base memory file: {} bytes\n", mem_size);
+
+    // The chain is validated, so all the manifests describe the same regions.
+    let regions = chain
+        .layers()
+        .iter()
+        .find_map(|layer| layer.manifest())
+        .map(|manifest| manifest.regions.clone())
+        .unwrap_or_default();
+
+    for (index, layer) in chain.layers_mut().iter_mut().enumerate() {
+        let ranges = layer.data_ranges().map_err(Error::Chain)?;
+        report.push_str(&format!(
+            "Layer {}: {:?} ({})\n  {} bytes in {} ranges\n",
+            index + 1,
+            layer.path(),
+            if layer.manifest().is_some() {
+                "manifest"
+            } else {
+                "no manifest"
+            },
+            ranges.iter().map(|(_, len)| len).sum::<u64>(),
+            ranges.len()
+        ));
+        for (region, used) in regions.iter().zip(region_usage(&regions, &ranges)) {
+            if used > 0 {
+                report.push_str(&format!(
+                    "  region {:#x}: {} of {} bytes\n",
+                    region.base_address, used, region.size
+                ));
+            }
+        }
+    }
+
+    let extents = chain.resolve().map_err(Error::Chain)?;
+    report.push_str(&format!(
+        "Flattened chain: {} bytes in {} ranges\n",
+        extents.iter().map(|extent| extent.len).sum::<u64>(),
+        extents.len()
+    ));
+    for index in 0..chain.layers().len() {
+        report.push_str(&format!(
+            "  layer {}: {} bytes\n",
+            index + 1,
+            extents
+                .iter()
+                .filter(|extent| extent.layer == index)
+                .map(|extent| extent.len)
+                .sum::<u64>()
+        ));
+    }
+
+    Ok(report)
+}
+
+// Checks that the base file and every layer of the chain describe the whole guest memory, and
+// returns its size. Without the guest memory state, the size of the base file is used instead.
+fn validate(
+    base_file: &File,
+    chain: &DiffChain,
+    mem_state: Option<&GuestMemoryState>,
+) -> Result<u64, Error> {
+    let base_file_len = base_file.metadata().map_err(Error::Metadata)?.len();
+    let mem_state = match mem_state {
+        Some(mem_state) => mem_state,
+        None => {
+            chain.validate(base_file_len).map_err(Error::Chain)?;
+            return Ok(base_file_len);
+        }
+    };
+
+    let regions: Vec<_> = mem_state
+        .regions
+        .iter()
+        .map(|region| (region.base_address, region.offset, region.size as u64))
+        .collect();
+    let mem_size = regions.iter().map(|(_, _, size)| size).sum();
+    if base_file_len != mem_size {
+        return Err(Error::BaseFileSize(base_file_len, mem_size));
+    }
+    chain.validate_regions(&regions).map_err(Error::Chain)?;
+    Ok(mem_size)
+}
+
+fn run(
+    base_file: &File,
+    chain: &mut DiffChain,
+    mem_state: Option<&GuestMemoryState>,
+    action: Action,
+) -> Result<(), Error> {
+    let mem_size = validate(base_file, chain, mem_state)?;
+
+    match action {
+        Action::Rebase => rebase(base_file, chain),
+        Action::Inspect => {
+            print!("{}", inspect(chain, mem_size)?);
+            Ok(())
+        }
+        Action::Flatten(output_file, output_manifest_path) => {
+            flatten(chain, mem_size, &output_file, output_manifest_path)
+        }
+    }
+}
+
 fn main() {
     let mut arg_parser = build_arg_parser();
     let args = extract_args(&mut arg_parser);
-    let (mut base_file, mut diff_file) =
+    let (base_file, mut chain, mem_state, action) =
         parse_args(args).unwrap_or_else(|err| panic!("Error parsing the cmd line args: {:?}", err));
 
-    rebase(&mut base_file, &mut diff_file)
-        .unwrap_or_else(|err| panic!("Error merging the files: {:?}", err));
+    run(&base_file, &mut chain, mem_state.as_ref(), action)
+        .unwrap_or_else(|err| panic!("Error processing the files: {:?}", err));
 }
 
 #[cfg(test)]
@@ -138,6 +431,7 @@ mod tests {
     use std::os::unix::fs::FileExt;
 
     use utils::{rand, tempfile};
+    use vmm::memory_snapshot::GuestMemoryRegionState;
 
     use super::*;
 
@@ -153,6 +447,14 @@ mod tests {
         }
     }
 
+    fn chain(diff_file: &File, diff_manifest: Option<DiffManifest>) -> DiffChain {
+        DiffChain::new(vec![MemoryLayer::new(
+            PathBuf::from("diff_file"),
+            diff_file.try_clone().unwrap(),
+            diff_manifest,
+        )])
+    }
+
     #[test]
     fn test_parse_args() {
         let base_file = tempfile::TempFile::new().unwrap();
@@ -213,7 +515,134 @@ mod tests {
                 .as_ref(),
             )
             .unwrap();
-        assert!(parse_args(arguments).is_ok());
+        assert!(matches!(
+            parse_args(arguments),
+            Ok((_, _, None, Action::Rebase))
+        ));
+
+        let arguments = &mut arg_parser.arguments().clone();
+        arguments
+            .parse(
+                vec![
+                    "rebase_snap",
+                    "--base-file",
+                    &base_file_path,
+                    "--diff-file",
+                    &diff_file_path,
+                    "--diff-manifest",
+                    "diff_manifest",
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .as_ref(),
+            )
+            .unwrap();
+        assert_err!(parse_args(arguments), Error::InvalidManifestFile(_));
+
+        // The manifest is saved with an invalid page size.
+        let manifest_file = tempfile::TempFile::new().unwrap();
+        let manifest_file_path = manifest_file.as_path().to_str().unwrap().to_string();
+        DiffManifest {
+            page_size: 1000,
+            regions: vec![],
+        }
+        .save(&mut manifest_file.as_file())
+        .unwrap();
+        let arguments = &mut arg_parser.arguments().clone();
+        arguments
+            .parse(
+                vec![
+                    "rebase_snap",
+                    "--base-file",
+                    &base_file_path,
+                    "--diff-file",
+                    &diff_file_path,
+                    "--diff-manifest",
+                    &manifest_file_path,
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .as_ref(),
+            )
+            .unwrap();
+        assert_err!(
+            parse_args(arguments),
+            Error::InvalidManifest(ManifestError::InvalidPageSize(1000))
+        );
+
+        // A manifest is needed for each diff file.
+        let arguments = &mut arg_parser.arguments().clone();
+        arguments
+            .parse(
+                vec![
+                    "rebase_snap",
+                    "--base-file",
+                    &base_file_path,
+                    "--diff-file",
+                    &diff_file_path,
+                    "--diff-file",
+                    &diff_file_path,
+                    "--diff-manifest",
+                    &manifest_file_path,
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .as_ref(),
+            )
+            .unwrap();
+        assert_err!(parse_args(arguments), Error::ManifestCount(1, 2));
+
+        let output_file = tempfile::TempFile::new().unwrap();
+        let output_file_path = output_file.as_path().to_str().unwrap().to_string();
+        let arguments = &mut arg_parser.arguments().clone();
+        arguments
+            .parse(
+                vec![
+                    "rebase_snap",
+                    "--base-file",
+                    &base_file_path,
+                    "--diff-file",
+                    &diff_file_path,
+                    "--diff-file",
+                    &diff_file_path,
+                    "--output-file",
+                    &output_file_path,
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .as_ref(),
+            )
+            .unwrap();
+        match parse_args(arguments) {
+            Ok((_, chain, None, Action::Flatten(_, None))) => assert_eq!(chain.layers().len(), 2),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let arguments = &mut arg_parser.arguments().clone();
+        arguments
+            .parse(
+                vec![
+                    "rebase_snap",
+                    "--base-file",
+                    &base_file_path,
+                    "--diff-file",
+                    &diff_file_path,
+                    "--inspect",
+                ]
+                .into_iter()
+                .map(String::from)
+                .collect::<Vec<String>>()
+                .as_ref(),
+            )
+            .unwrap();
+        assert!(matches!(
+            parse_args(arguments),
+            Ok((_, _, None, Action::Inspect))
+        ));
     }
 
     fn check_file_content(file: &mut File, expected_content: &[u8]) {
@@ -228,7 +657,7 @@ mod tests {
         let mut diff_file = tempfile::TempFile::new().unwrap().into_file();
 
         // 1. Empty files
-        rebase(&mut base_file, &mut diff_file).unwrap();
+        rebase(&base_file, &mut chain(&diff_file, None)).unwrap();
         assert_eq!(base_file.metadata().unwrap().len(), 0);
 
         let initial_base_file_content = rand::rand_alphanumerics(50000).into_string().unwrap();
@@ -240,13 +669,13 @@ mod tests {
         diff_file
             .set_len(initial_base_file_content.len() as u64)
             .unwrap();
-        rebase(&mut base_file, &mut diff_file).unwrap();
+        rebase(&base_file, &mut chain(&diff_file, None)).unwrap();
         check_file_content(&mut base_file, initial_base_file_content.as_bytes());
 
         // 3. Diff file that has only data
         let diff_data = rand::rand_alphanumerics(50000).into_string().unwrap();
         diff_file.write_all(diff_data.as_bytes()).unwrap();
-        rebase(&mut base_file, &mut diff_file).unwrap();
+        rebase(&base_file, &mut chain(&diff_file, None)).unwrap();
         check_file_content(&mut base_file, diff_data.as_bytes());
     }
 
@@ -283,7 +712,7 @@ mod tests {
             expected_result.append(&mut diff_block);
 
             // Rebase and check the result
-            rebase(&mut base_file, &mut diff_file).unwrap();
+            rebase(&base_file, &mut chain(&diff_file, None)).unwrap();
             check_file_content(&mut base_file, &expected_result);
 
             // 4. The diff file is bigger
@@ -291,7 +720,7 @@ mod tests {
             diff_file.write_all(diff_block.as_bytes()).unwrap();
             expected_result.append(&mut diff_block.into_bytes());
             // Rebase and check the result
-            rebase(&mut base_file, &mut diff_file).unwrap();
+            rebase(&base_file, &mut chain(&diff_file, None)).unwrap();
             check_file_content(&mut base_file, &expected_result);
 
             // 5. The base file is bigger
@@ -299,8 +728,242 @@ mod tests {
             base_file.write_all(base_block.as_bytes()).unwrap();
             expected_result.append(&mut base_block.into_bytes());
             // Rebase and check the result
-            rebase(&mut base_file, &mut diff_file).unwrap();
+            rebase(&base_file, &mut chain(&diff_file, None)).unwrap();
             check_file_content(&mut base_file, &expected_result);
         }
     }
+
+    #[test]
+    fn test_rebase_with_manifest() {
+        let page_size = 4096;
+        let mut base_file = tempfile::TempFile::new().unwrap().into_file();
+        let mut diff_file = tempfile::TempFile::new().unwrap().into_file();
+
+        let base_content = rand::rand_alphanumerics(page_size * 4)
+            .into_string()
+            .unwrap();
+        base_file.write_all(base_content.as_bytes()).unwrap();
+        // The diff file has no holes, as if it had been copied without preserving them.
+        let diff_content = rand::rand_alphanumerics(page_size * 4)
+            .into_string()
+            .unwrap();
+        diff_file.write_all(diff_content.as_bytes()).unwrap();
+
+        // Only the second and the last pages are part of the diff snapshot.
+        let manifest = DiffManifest {
+            page_size: page_size as u64,
+            regions: vec![RegionDirtyPages {
+                base_address: 0,
+                offset: 0,
+                size: page_size as u64 * 4,
+                bitmap: vec![0b1010],
+            }],
+        };
+        rebase_with_manifest(&base_file, &diff_file, &manifest).unwrap();
+        let expected_result = [
+            &base_content.as_bytes()[..page_size],
+            &diff_content.as_bytes()[page_size..page_size * 2],
+            &base_content.as_bytes()[page_size * 2..page_size * 3],
+            &diff_content.as_bytes()[page_size * 3..],
+        ]
+        .concat();
+        check_file_content(&mut base_file, &expected_result);
+
+        // The last page listed in the manifest is missing from the diff file.
+        diff_file.set_len(page_size as u64 * 3).unwrap();
+        assert_err!(
+            rebase_with_manifest(&base_file, &diff_file, &manifest),
+            Error::DiffFileTooShort(_)
+        );
+        assert_err!(
+            run(
+                &base_file,
+                &mut chain(&diff_file, Some(manifest.clone())),
+                None,
+                Action::Rebase
+            ),
+            Error::Chain(ChainError::TruncatedLayer(_, _))
+        );
+
+        // The diff file is larger than the memory described by the manifest.
+        diff_file.set_len(page_size as u64 * 5).unwrap();
+        assert_err!(
+            rebase_with_manifest(&base_file, &diff_file, &manifest),
+            Error::DiffFileTooLong(20480, 16384)
+        );
+
+        // The manifest describes more memory than the base file holds.
+        diff_file.set_len(page_size as u64 * 4).unwrap();
+        base_file.set_len(page_size as u64 * 2).unwrap();
+        assert_err!(
+            rebase_with_manifest(&base_file, &diff_file, &manifest),
+            Error::ManifestSize(16384, 8192)
+        );
+        check_file_content(&mut base_file, &expected_result[..page_size * 2]);
+    }
+
+    fn manifest(num_pages: u64, bitmap: u64) -> DiffManifest {
+        DiffManifest {
+            page_size: 4096,
+            regions: vec![RegionDirtyPages {
+                base_address: 0x1000_0000,
+                offset: 0,
+                size: num_pages * 4096,
+                bitmap: vec![bitmap],
+            }],
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        let page_size = 4096;
+        let mut base_file = tempfile::TempFile::new().unwrap().into_file();
+        base_file.write_all_at(&[1u8; 4096 * 4], 0).unwrap();
+
+        // The first layer provides pages 0 and 1, the second one pages 1 and 3.
+        let layers = [(0b0011, 2u8), (0b1010, 3u8)];
+        let mut diff_files = Vec::new();
+        for (bitmap, value) in layers.iter() {
+            let diff_file = tempfile::TempFile::new().unwrap().into_file();
+            diff_file.set_len(page_size * 4).unwrap();
+            for page in (0..4).filter(|page| bitmap & (1 << page) != 0) {
+                diff_file
+                    .write_all_at(&[*value; 4096], page * page_size)
+                    .unwrap();
+            }
+            diff_files.push(diff_file);
+        }
+        let new_chain = |with_manifests: bool| {
+            DiffChain::new(
+                diff_files
+                    .iter()
+                    .zip(layers.iter())
+                    .map(|(diff_file, (bitmap, _))| {
+                        MemoryLayer::new(
+                            PathBuf::from("diff_file"),
+                            diff_file.try_clone().unwrap(),
+                            Some(manifest(4, *bitmap)).filter(|_| with_manifests),
+                        )
+                    })
+                    .collect(),
+            )
+        };
+        let expected_content: Vec<u8> = [2u8, 3, 0, 3]
+            .iter()
+            .flat_map(|&value| [value; 4096])
+            .collect();
+
+        // Inspect the chain.
+        let report = inspect(&mut new_chain(true), page_size * 4).unwrap();
+        assert_eq!(
+            report,
+            "Base memory file: 16384 bytes\nLayer 1: \"diff_file\" (manifest)\n  8192 bytes in 1 \
+             ranges\n\x20 region 0x10000000: 8192 of 16384 bytes\nLayer 2: \"diff_file\" \
+             (manifest)\n  8192 bytes in 2 ranges\n\x20 region 0x10000000: 8192 of 16384 \
+             bytes\nFlattened chain: 12288 bytes in 3 ranges\n\x20 layer 1: 4096 bytes\n  layer \
+             2: 8192 bytes\n"
+        );
+
+        // Flatten the chain into a new diff file, with its manifest.
+        let mut output_file = tempfile::TempFile::new().unwrap().into_file();
+        let output_manifest = tempfile::TempFile::new().unwrap();
+        let output_manifest_path = output_manifest.as_path().to_path_buf();
+        run(
+            &base_file,
+            &mut new_chain(true),
+            None,
+            Action::Flatten(
+                output_file.try_clone().unwrap(),
+                Some(output_manifest_path.clone()),
+            ),
+        )
+        .unwrap();
+        check_file_content(&mut output_file, &expected_content);
+        assert_eq!(
+            load_manifest(output_manifest_path.to_str().unwrap()).unwrap(),
+            manifest(4, 0b1011)
+        );
+
+        // The flattened manifest requires a manifest for every diff file.
+        assert_err!(
+            run(
+                &base_file,
+                &mut new_chain(false),
+                None,
+                Action::Flatten(output_file, Some(output_manifest_path))
+            ),
+            Error::MissingManifest
+        );
+
+        // Merge the chain onto the base file.
+        run(&base_file, &mut new_chain(false), None, Action::Rebase).unwrap();
+        let expected_content: Vec<u8> = [2u8, 3, 1, 3]
+            .iter()
+            .flat_map(|&value| [value; 4096])
+            .collect();
+        check_file_content(&mut base_file, &expected_content);
+
+        // The diff files must describe the whole base file.
+        base_file.set_len(page_size * 8).unwrap();
+        assert_err!(
+            run(&base_file, &mut new_chain(false), None, Action::Rebase),
+            Error::Chain(ChainError::SizeMismatch(_, _, _))
+        );
+        assert_err!(
+            run(&base_file, &mut new_chain(true), None, Action::Inspect),
+            Error::Chain(ChainError::ManifestSizeMismatch(_, _, _))
+        );
+    }
+
+    #[test]
+    fn test_validate_memory_state() {
+        let base_file = tempfile::TempFile::new().unwrap().into_file();
+        base_file.set_len(4096 * 4).unwrap();
+        let diff_file = tempfile::TempFile::new().unwrap().into_file();
+        diff_file.set_len(4096 * 4).unwrap();
+        let mem_state = |regions: &[(u64, u64, usize)]| GuestMemoryState {
+            regions: regions
+                .iter()
+                .map(|&(base_address, offset, size)| GuestMemoryRegionState {
+                    base_address,
+                    size,
+                    offset,
+                })
+                .collect(),
+        };
+
+        let valid_state = mem_state(&[(0x1000_0000, 0, 4096 * 4)]);
+        assert_eq!(
+            validate(
+                &base_file,
+                &chain(&diff_file, Some(manifest(4, 0b1))),
+                Some(&valid_state)
+            )
+            .unwrap(),
+            4096 * 4
+        );
+
+        // The base file does not hold the whole guest memory.
+        let larger_state = mem_state(&[(0x1000_0000, 0, 4096 * 8)]);
+        assert_err!(
+            validate(&base_file, &chain(&diff_file, None), Some(&larger_state)),
+            Error::BaseFileSize(16384, 32768)
+        );
+
+        // The manifest describes other regions than the microVM state.
+        let split_state = mem_state(&[(0, 0, 4096 * 2), (0x1000_0000, 4096 * 2, 4096 * 2)]);
+        assert_err!(
+            validate(
+                &base_file,
+                &chain(&diff_file, Some(manifest(4, 0b1))),
+                Some(&split_state)
+            ),
+            Error::Chain(ChainError::RegionMismatch(_))
+        );
+
+        assert_err!(
+            load_memory_state("invalid_vmstate_file"),
+            Error::InvalidVmstateFile(SnapshotStateFromFileError::Open(_))
+        );
+    }
 }
@@ -0,0 +1,550 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines chains of diff snapshot memory files.
+//!
+//! A chain is an ordered list of diff memory files, from the oldest to the newest, meant to be
+//! applied on top of a base memory file. Every page of guest memory is provided by the newest
+//! layer holding it, or by the base if no layer does. Layers are described either by their
+//! manifest or, when they do not have one, by the non-sparse sections of their memory file.
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use utils::seek_hole::SeekHole;
+
+use crate::diff_manifest::{DiffManifest, ManifestError};
+
+/// Errors related to diff snapshot chains.
+#[derive(Debug, thiserror::Error)]
+pub enum ChainError {
+    /// Failed to access a memory file.
+    #[error("Cannot access the memory file {0:?}: {1}")]
+    MemoryFile(PathBuf, io::Error),
+    /// The manifest of a memory file is invalid.
+    #[error("Invalid manifest for the memory file {0:?}: {1}")]
+    InvalidManifest(PathBuf, ManifestError),
+    /// A memory file size does not match the guest memory size.
+    #[error("The memory file {0:?} is {1} bytes long instead of {2}")]
+    SizeMismatch(PathBuf, u64, u64),
+    /// The manifest of a memory file does not match the guest memory size.
+    #[error("The manifest of the memory file {0:?} describes {1} bytes of memory instead of {2}")]
+    ManifestSizeMismatch(PathBuf, u64, u64),
+    /// The manifests of the chain describe different memory layouts.
+    #[error(
+        "The manifest of the memory file {0:?} describes a different memory layout than the \
+         previous layers"
+    )]
+    LayoutMismatch(PathBuf),
+    /// The manifest of a memory file does not describe the guest memory regions.
+    #[error("The manifest of the memory file {0:?} does not describe the guest memory regions")]
+    RegionMismatch(PathBuf),
+    /// A memory file is shorter than the data listed in its manifest.
+    #[error(
+        "The memory file {0:?} is {1} bytes long, which is missing data listed in its manifest"
+    )]
+    TruncatedLayer(PathBuf, u64),
+    /// Failed to copy data from a memory file to the output memory file.
+    #[error("Cannot copy the memory file {0:?} to the output memory file: {1}")]
+    Copy(PathBuf, io::Error),
+    /// Failed to write to the output memory file.
+    #[error("Cannot write to the output memory file: {0}")]
+    Output(io::Error),
+}
+
+/// Returns the ranges of `file` holding data, as `(offset, length)` pairs, skipping its holes.
+pub fn file_data_ranges(file: &mut File) -> io::Result<Vec<(u64, u64)>> {
+    let mut ranges = Vec::new();
+    let mut cursor = 0;
+    while let Some(data_start) = file.seek_data(cursor)? {
+        let data_end = match file.seek_hole(data_start)? {
+            Some(hole_start) => hole_start,
+            None => file.metadata()?.len(),
+        };
+        ranges.push((data_start, data_end - data_start));
+        cursor = data_end;
+    }
+    Ok(ranges)
+}
+
+/// Copies the bytes of `input` between `offset` and `end` to the same offsets of `output`.
+pub fn copy_range(mut output: &File, input: &File, mut offset: u64, end: u64) -> io::Result<()> {
+    while offset < end {
+        output.seek(SeekFrom::Start(offset))?;
+
+        // SAFETY: Safe because the parameters are valid.
+        let num_transferred_bytes = unsafe {
+            libc::sendfile64(
+                output.as_raw_fd(),
+                input.as_raw_fd(),
+                (&mut offset as *mut u64).cast::<i64>(),
+                (end - offset) as usize,
+            )
+        };
+        if num_transferred_bytes < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if num_transferred_bytes == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+    }
+    Ok(())
+}
+
+/// A diff memory file of a chain, along with its optional manifest.
+#[derive(Debug)]
+pub struct MemoryLayer {
+    path: PathBuf,
+    file: File,
+    manifest: Option<DiffManifest>,
+}
+
+impl MemoryLayer {
+    /// Creates a layer from an open memory file and its optional manifest.
+    pub fn new(path: PathBuf, file: File, manifest: Option<DiffManifest>) -> Self {
+        MemoryLayer {
+            path,
+            file,
+            manifest,
+        }
+    }
+
+    /// Returns the path of the memory file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the memory file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Returns the manifest of the memory file, if there is one.
+    pub fn manifest(&self) -> Option<&DiffManifest> {
+        self.manifest.as_ref()
+    }
+
+    /// Returns the size of the memory file.
+    pub fn file_len(&self) -> Result<u64, ChainError> {
+        self.file
+            .metadata()
+            .map(|metadata| metadata.len())
+            .map_err(|err| ChainError::MemoryFile(self.path.clone(), err))
+    }
+
+    /// Returns the ranges of the memory file holding data, as `(offset, length)` pairs: the
+    /// pages listed in the manifest if there is one, the non-sparse sections of the file
+    /// otherwise.
+    pub fn data_ranges(&mut self) -> Result<Vec<(u64, u64)>, ChainError> {
+        match self.manifest.as_ref() {
+            Some(manifest) => Ok(manifest.dirty_ranges()),
+            None => file_data_ranges(&mut self.file)
+                .map_err(|err| ChainError::MemoryFile(self.path.clone(), err)),
+        }
+    }
+}
+
+/// A range of the memory files provided by a layer of a chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChainExtent {
+    /// Offset of the range in the memory files.
+    pub offset: u64,
+    /// Length of the range.
+    pub len: u64,
+    /// Index of the layer providing the range.
+    pub layer: usize,
+}
+
+/// An ordered list of diff memory files, from the oldest to the newest.
+#[derive(Debug, Default)]
+pub struct DiffChain {
+    layers: Vec<MemoryLayer>,
+}
+
+impl DiffChain {
+    /// Creates a chain from its layers, ordered from the oldest to the newest.
+    pub fn new(layers: Vec<MemoryLayer>) -> Self {
+        DiffChain { layers }
+    }
+
+    /// Returns the layers of the chain.
+    pub fn layers(&self) -> &[MemoryLayer] {
+        &self.layers
+    }
+
+    /// Returns the layers of the chain, so that their data ranges can be fetched.
+    pub fn layers_mut(&mut self) -> &mut [MemoryLayer] {
+        &mut self.layers
+    }
+
+    /// Checks that every layer describes `mem_size` bytes of guest memory, that the manifests
+    /// are valid and describe the same memory layout and that the memory files hold all the
+    /// pages listed in their manifest.
+    pub fn validate(&self, mem_size: u64) -> Result<(), ChainError> {
+        let mut layout = None;
+        for layer in self.layers.iter() {
+            let file_len = layer.file_len()?;
+            let manifest = match layer.manifest.as_ref() {
+                Some(manifest) => manifest,
+                None if file_len == mem_size => continue,
+                None => {
+                    return Err(ChainError::SizeMismatch(
+                        layer.path.clone(),
+                        file_len,
+                        mem_size,
+                    ))
+                }
+            };
+
+            manifest
+                .validate()
+                .map_err(|err| ChainError::InvalidManifest(layer.path.clone(), err))?;
+            if manifest.mem_size() != mem_size {
+                return Err(ChainError::ManifestSizeMismatch(
+                    layer.path.clone(),
+                    manifest.mem_size(),
+                    mem_size,
+                ));
+            }
+
+            let manifest_layout: Vec<_> = manifest
+                .regions
+                .iter()
+                .map(|region| (region.base_address, region.offset, region.size))
+                .collect();
+            match layout.as_ref() {
+                None => layout = Some((manifest.page_size, manifest_layout)),
+                Some((page_size, regions))
+                    if *page_size != manifest.page_size || *regions != manifest_layout =>
+                {
+                    return Err(ChainError::LayoutMismatch(layer.path.clone()))
+                }
+                Some(_) => (),
+            }
+
+            if let Some((offset, len)) = manifest.dirty_ranges().last() {
+                if offset + len > file_len {
+                    return Err(ChainError::TruncatedLayer(layer.path.clone(), file_len));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the chain as [`DiffChain::validate`] does, for the guest memory regions described
+    /// by `regions` as `(base_address, offset, size)` tuples. The manifests must describe these
+    /// same regions.
+    pub fn validate_regions(&self, regions: &[(u64, u64, u64)]) -> Result<(), ChainError> {
+        self.validate(regions.iter().map(|(_, _, size)| size).sum())?;
+        for layer in self.layers.iter() {
+            if let Some(manifest) = layer.manifest.as_ref() {
+                let describes_regions = manifest
+                    .regions
+                    .iter()
+                    .map(|region| (region.base_address, region.offset, region.size))
+                    .eq(regions.iter().copied());
+                if !describes_regions {
+                    return Err(ChainError::RegionMismatch(layer.path.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the ranges of the memory files provided by the layers, sorted by offset. Each
+    /// range is taken from the newest layer holding it. The ranges missing from the result are
+    /// provided by the base memory file.
+    pub fn resolve(&mut self) -> Result<Vec<ChainExtent>, ChainError> {
+        // Maps the start of each range to its end and to the layer providing it.
+        let mut ranges: BTreeMap<u64, (u64, usize)> = BTreeMap::new();
+        for (index, layer) in self.layers.iter_mut().enumerate() {
+            for (start, len) in layer.data_ranges()? {
+                if len == 0 {
+                    continue;
+                }
+                let end = start + len;
+
+                // Trim the ranges of the older layers overlapping the new one.
+                let overlapping: Vec<u64> = ranges
+                    .range(..end)
+                    .rev()
+                    .take_while(|(_, (range_end, _))| *range_end > start)
+                    .map(|(range_start, _)| *range_start)
+                    .collect();
+                for range_start in overlapping {
+                    let (range_end, range_layer) = ranges.remove(&range_start).unwrap();
+                    if range_start < start {
+                        ranges.insert(range_start, (start, range_layer));
+                    }
+                    if range_end > end {
+                        ranges.insert(end, (range_end, range_layer));
+                    }
+                }
+                ranges.insert(start, (end, index));
+            }
+        }
+
+        let mut extents: Vec<ChainExtent> = Vec::new();
+        for (start, (end, layer)) in ranges {
+            match extents.last_mut() {
+                Some(last) if last.layer == layer && last.offset + last.len == start => {
+                    last.len += end - start
+                }
+                _ => extents.push(ChainExtent {
+                    offset: start,
+                    len: end - start,
+                    layer,
+                }),
+            }
+        }
+        Ok(extents)
+    }
+
+    /// Copies the data provided by the layers to `output`, at the same offsets. Copying to the
+    /// base memory file merges the whole chain onto it.
+    pub fn copy_to(&mut self, output: &File) -> Result<(), ChainError> {
+        for extent in self.resolve()? {
+            let layer = &self.layers[extent.layer];
+            copy_range(
+                output,
+                &layer.file,
+                extent.offset,
+                extent.offset + extent.len,
+            )
+            .map_err(|err| ChainError::Copy(layer.path.clone(), err))?;
+        }
+        Ok(())
+    }
+
+    /// Returns the manifest of the pages provided by the layers, if all of them have a
+    /// manifest. The chain is expected to be validated.
+    pub fn merged_manifest(&self) -> Option<DiffManifest> {
+        let mut manifests = self.layers.iter().map(|layer| layer.manifest.as_ref());
+        let mut merged = manifests.next()??.clone();
+        for manifest in manifests {
+            for (merged_region, region) in merged.regions.iter_mut().zip(manifest?.regions.iter()) {
+                for (merged_word, word) in merged_region.bitmap.iter_mut().zip(region.bitmap.iter())
+                {
+                    *merged_word |= word;
+                }
+            }
+        }
+        Some(merged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::FileExt;
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::diff_manifest::RegionDirtyPages;
+
+    const PAGE_SIZE: usize = 4096;
+
+    // Creates a layer of `num_pages` pages, where the pages in `data_pages` are filled with
+    // `value` and the others are holes.
+    fn layer(
+        num_pages: usize,
+        data_pages: &[usize],
+        value: u8,
+        with_manifest: bool,
+    ) -> MemoryLayer {
+        let temp_file = TempFile::new().unwrap();
+        let path = temp_file.as_path().to_path_buf();
+        let file = temp_file.into_file();
+        file.set_len((num_pages * PAGE_SIZE) as u64).unwrap();
+
+        let mut bitmap = vec![0u64; (num_pages + 63) / 64];
+        for &page in data_pages {
+            file.write_all_at(&[value; PAGE_SIZE], (page * PAGE_SIZE) as u64)
+                .unwrap();
+            bitmap[page / 64] |= 1 << (page % 64);
+        }
+
+        let manifest = DiffManifest {
+            page_size: PAGE_SIZE as u64,
+            regions: vec![RegionDirtyPages {
+                base_address: 0,
+                offset: 0,
+                size: (num_pages * PAGE_SIZE) as u64,
+                bitmap,
+            }],
+        };
+        MemoryLayer::new(path, file, Some(manifest).filter(|_| with_manifest))
+    }
+
+    #[test]
+    fn test_file_data_ranges() {
+        let mut layer = layer(8, &[1, 2, 5], 1, false);
+        assert_eq!(
+            file_data_ranges(&mut layer.file).unwrap(),
+            vec![
+                (PAGE_SIZE as u64, PAGE_SIZE as u64 * 2),
+                (PAGE_SIZE as u64 * 5, PAGE_SIZE as u64)
+            ]
+        );
+        assert_eq!(
+            layer.data_ranges().unwrap(),
+            file_data_ranges(&mut layer.file).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let page = PAGE_SIZE as u64;
+        let mut chain = DiffChain::new(vec![
+            layer(8, &[0, 1, 2, 3, 4], 1, false),
+            layer(8, &[2, 7], 2, true),
+            layer(8, &[1, 2], 3, false),
+        ]);
+        chain.validate(page * 8).unwrap();
+        assert_eq!(
+            chain.resolve().unwrap(),
+            vec![
+                ChainExtent {
+                    offset: 0,
+                    len: page,
+                    layer: 0
+                },
+                ChainExtent {
+                    offset: page,
+                    len: page * 2,
+                    layer: 2
+                },
+                ChainExtent {
+                    offset: page * 3,
+                    len: page * 2,
+                    layer: 0
+                },
+                ChainExtent {
+                    offset: page * 7,
+                    len: page,
+                    layer: 1
+                },
+            ]
+        );
+
+        // Copy the chain on top of a base.
+        let base = TempFile::new().unwrap().into_file();
+        base.write_all_at(&[9u8; PAGE_SIZE * 8], 0).unwrap();
+        chain.copy_to(&base).unwrap();
+        let mut content = vec![0u8; PAGE_SIZE * 8];
+        base.read_exact_at(&mut content, 0).unwrap();
+        let expected: Vec<u8> = [1, 3, 3, 1, 1, 9, 9, 2]
+            .iter()
+            .flat_map(|&value| [value; PAGE_SIZE])
+            .collect();
+        assert_eq!(content, expected);
+
+        // An empty chain provides nothing.
+        assert!(DiffChain::default().resolve().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_validate() {
+        let page = PAGE_SIZE as u64;
+
+        // Layer without manifest and with the wrong size.
+        let chain = DiffChain::new(vec![layer(4, &[0], 1, true), layer(8, &[0], 1, false)]);
+        assert!(matches!(
+            chain.validate(page * 4),
+            Err(ChainError::SizeMismatch(_, len, mem_size)) if len == page * 8 && mem_size == page * 4
+        ));
+
+        // Manifest describing the wrong memory size.
+        let chain = DiffChain::new(vec![layer(8, &[0], 1, true)]);
+        assert!(matches!(
+            chain.validate(page * 4),
+            Err(ChainError::ManifestSizeMismatch(_, _, _))
+        ));
+
+        // Manifests with different layouts.
+        let mut other = layer(8, &[0], 1, true);
+        other.manifest.as_mut().unwrap().regions[0].base_address = 0x1000_0000;
+        let chain = DiffChain::new(vec![layer(8, &[0], 1, true), other]);
+        assert!(matches!(
+            chain.validate(page * 8),
+            Err(ChainError::LayoutMismatch(_))
+        ));
+
+        // Memory file missing pages listed in its manifest.
+        let truncated = layer(8, &[7], 1, true);
+        truncated.file.set_len(page * 4).unwrap();
+        let chain = DiffChain::new(vec![truncated]);
+        assert!(matches!(
+            chain.validate(page * 8),
+            Err(ChainError::TruncatedLayer(_, len)) if len == page * 4
+        ));
+
+        // Invalid manifest.
+        let mut invalid = layer(8, &[0], 1, true);
+        invalid.manifest.as_mut().unwrap().page_size = 1000;
+        let chain = DiffChain::new(vec![invalid]);
+        assert!(matches!(
+            chain.validate(page * 8),
+            Err(ChainError::InvalidManifest(
+                _,
+                ManifestError::InvalidPageSize(1000)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_validate_regions() {
+        let page = PAGE_SIZE as u64;
+        let chain = DiffChain::new(vec![layer(8, &[0], 1, true), layer(8, &[1], 2, false)]);
+        chain.validate_regions(&[(0, 0, page * 8)]).unwrap();
+
+        // The regions have the same size, but a different layout.
+        assert!(matches!(
+            chain.validate_regions(&[(0, 0, page * 4), (0x1000_0000, page * 4, page * 4)]),
+            Err(ChainError::RegionMismatch(_))
+        ));
+        // The regions are smaller than the memory files.
+        assert!(matches!(
+            chain.validate_regions(&[(0, 0, page * 4)]),
+            Err(ChainError::ManifestSizeMismatch(_, _, _))
+        ));
+    }
+
+    #[test]
+    fn test_copy_range() {
+        let input = TempFile::new().unwrap().into_file();
+        input.write_all_at(&[1u8; PAGE_SIZE * 2], 0).unwrap();
+        let output = TempFile::new().unwrap().into_file();
+
+        copy_range(&output, &input, PAGE_SIZE as u64, PAGE_SIZE as u64 * 2).unwrap();
+        let mut content = vec![0u8; PAGE_SIZE * 2];
+        output.read_exact_at(&mut content, 0).unwrap();
+        assert_eq!(&content[..PAGE_SIZE], &[0u8; PAGE_SIZE]);
+        assert_eq!(&content[PAGE_SIZE..], &[1u8; PAGE_SIZE]);
+
+        // The input is shorter than the range.
+        assert_eq!(
+            copy_range(&output, &input, 0, PAGE_SIZE as u64 * 3)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn test_merged_manifest() {
+        let chain = DiffChain::new(vec![
+            layer(70, &[0, 65], 1, true),
+            layer(70, &[1, 65, 69], 2, true),
+        ]);
+        let merged = chain.merged_manifest().unwrap();
+        assert_eq!(merged.regions[0].bitmap, vec![0b11, 0b100010]);
+
+        // All the layers need a manifest.
+        let chain = DiffChain::new(vec![layer(8, &[0], 1, true), layer(8, &[1], 2, false)]);
+        assert!(chain.merged_manifest().is_none());
+        assert!(DiffChain::default().merged_manifest().is_none());
+    }
+}
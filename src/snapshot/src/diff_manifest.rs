@@ -0,0 +1,242 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Defines the manifest of a diff snapshot memory file.
+//!
+//! A diff snapshot memory file only holds the guest pages dirtied since the previous snapshot,
+//! the other pages being left as holes. The manifest explicitly records which pages are present
+//! in the file, so that diff memory files can be transported, validated and merged without
+//! relying on the file system preserving holes.
+//!
+//! The manifest is stored using the `Snapshot` format, with its own version map, so it does not
+//! depend on the version of the microVM state it accompanies.
+use std::io::{Read, Write};
+
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
+use crate::{Error, Snapshot};
+
+/// Current data version of the manifest.
+pub const DIFF_MANIFEST_VERSION: u16 = 1;
+
+/// Errors related to validating a diff snapshot manifest.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ManifestError {
+    /// The page size is not a power of two.
+    #[error("Invalid page size: {0}")]
+    InvalidPageSize(u64),
+    /// The region does not start where the previous one ends in the memory file.
+    #[error("The region at offset {0:#x} does not follow the previous region")]
+    NonContiguousRegion(u64),
+    /// The region size is not a multiple of the page size.
+    #[error("The size of the region at offset {0:#x} is not a multiple of the page size")]
+    UnalignedRegion(u64),
+    /// The region bitmap does not match the number of pages in the region.
+    #[error("The dirty page bitmap of the region at offset {0:#x} does not match its size")]
+    InvalidBitmap(u64),
+}
+
+/// Pages of a guest memory region present in a diff snapshot memory file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a manifest version bump.
+pub struct RegionDirtyPages {
+    /// Base guest physical address of the region.
+    pub base_address: u64,
+    /// Offset of the region in the memory file.
+    pub offset: u64,
+    /// Region size, in bytes.
+    pub size: u64,
+    /// One bit per page, set if the page is present in the memory file.
+    pub bitmap: Vec<u64>,
+}
+
+impl RegionDirtyPages {
+    /// Returns whether the page with index `page` in the region is present in the memory file.
+    pub fn is_dirty(&self, page: usize) -> bool {
+        self.bitmap
+            .get(page / 64)
+            .map_or(false, |word| (word >> (page % 64)) & 1 != 0)
+    }
+
+    /// Returns the number of pages of the region present in the memory file.
+    pub fn dirty_pages(&self) -> usize {
+        self.bitmap
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+}
+
+/// Describes the pages present in a diff snapshot memory file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Versionize)]
+// NOTICE: Any changes to this structure require a manifest version bump.
+pub struct DiffManifest {
+    /// Page size used by the region bitmaps, in bytes.
+    pub page_size: u64,
+    /// Guest memory regions, in memory file order.
+    pub regions: Vec<RegionDirtyPages>,
+}
+
+impl DiffManifest {
+    /// Loads a manifest of `len` bytes from `reader` and validates its checksum.
+    pub fn load<T: Read>(reader: &mut T, len: usize) -> Result<Self, Error> {
+        Snapshot::load(reader, len, VersionMap::new())
+    }
+
+    /// Saves the manifest to `writer`, followed by its checksum.
+    pub fn save<T: Write>(&self, writer: &mut T) -> Result<(), Error> {
+        Snapshot::new(VersionMap::new(), DIFF_MANIFEST_VERSION).save(writer, self)
+    }
+
+    /// Checks that the regions are laid out contiguously in the memory file and that
+    /// their bitmaps cover exactly their pages.
+    pub fn validate(&self) -> Result<(), ManifestError> {
+        if !self.page_size.is_power_of_two() {
+            return Err(ManifestError::InvalidPageSize(self.page_size));
+        }
+
+        let mut expected_offset = 0;
+        for region in self.regions.iter() {
+            if region.offset != expected_offset {
+                return Err(ManifestError::NonContiguousRegion(region.offset));
+            }
+            if region.size % self.page_size != 0 {
+                return Err(ManifestError::UnalignedRegion(region.offset));
+            }
+
+            let num_pages = region.size / self.page_size;
+            if region.bitmap.len() as u64 != (num_pages + 63) / 64
+                || region.bitmap.last().map_or(false, |word| {
+                    num_pages % 64 != 0 && word >> (num_pages % 64) != 0
+                })
+            {
+                return Err(ManifestError::InvalidBitmap(region.offset));
+            }
+            expected_offset += region.size;
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the guest memory described by the manifest.
+    pub fn mem_size(&self) -> u64 {
+        self.regions.iter().map(|region| region.size).sum()
+    }
+
+    /// Returns the number of pages present in the memory file.
+    pub fn dirty_pages(&self) -> usize {
+        self.regions.iter().map(|region| region.dirty_pages()).sum()
+    }
+
+    /// Returns the ranges of the memory file holding data, as `(offset, length)` pairs.
+    /// Adjacent dirty pages are merged into a single range.
+    pub fn dirty_ranges(&self) -> Vec<(u64, u64)> {
+        let mut ranges: Vec<(u64, u64)> = Vec::new();
+        for region in self.regions.iter() {
+            let num_pages = (region.size / self.page_size) as usize;
+            for page in (0..num_pages).filter(|&page| region.is_dirty(page)) {
+                let offset = region.offset + page as u64 * self.page_size;
+                match ranges.last_mut() {
+                    Some((start, len)) if *start + *len == offset => *len += self.page_size,
+                    _ => ranges.push((offset, self.page_size)),
+                }
+            }
+        }
+        ranges
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> DiffManifest {
+        DiffManifest {
+            page_size: 4096,
+            regions: vec![
+                RegionDirtyPages {
+                    base_address: 0,
+                    offset: 0,
+                    size: 4096 * 66,
+                    bitmap: vec![0b1011, 0b11],
+                },
+                RegionDirtyPages {
+                    base_address: 0x1_0000_0000,
+                    offset: 4096 * 66,
+                    size: 4096 * 2,
+                    bitmap: vec![0b01],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_dirty_pages() {
+        let manifest = manifest();
+        manifest.validate().unwrap();
+        assert_eq!(manifest.mem_size(), 4096 * 68);
+        assert_eq!(manifest.dirty_pages(), 6);
+        assert!(manifest.regions[0].is_dirty(64));
+        assert!(!manifest.regions[0].is_dirty(2));
+        assert!(!manifest.regions[0].is_dirty(1000));
+
+        // The last page of the first region and the first page of the second one are merged.
+        assert_eq!(
+            manifest.dirty_ranges(),
+            vec![(0, 4096 * 2), (4096 * 3, 4096), (4096 * 64, 4096 * 3)]
+        );
+    }
+
+    #[test]
+    fn test_save_load() {
+        let manifest = manifest();
+        let mut buf = Vec::new();
+        manifest.save(&mut buf).unwrap();
+        let restored = DiffManifest::load(&mut buf.as_slice(), buf.len()).unwrap();
+        assert_eq!(manifest, restored);
+
+        // Corrupt the bitmap.
+        let len = buf.len();
+        buf[len - 12] ^= 1;
+        assert!(matches!(
+            DiffManifest::load(&mut buf.as_slice(), len),
+            Err(Error::Crc64(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut invalid = manifest();
+        invalid.page_size = 4095;
+        assert_eq!(
+            invalid.validate(),
+            Err(ManifestError::InvalidPageSize(4095))
+        );
+
+        let mut invalid = manifest();
+        invalid.regions[1].offset += 4096;
+        assert_eq!(
+            invalid.validate(),
+            Err(ManifestError::NonContiguousRegion(4096 * 67))
+        );
+
+        let mut invalid = manifest();
+        invalid.regions[1].size += 1;
+        assert_eq!(
+            invalid.validate(),
+            Err(ManifestError::UnalignedRegion(4096 * 66))
+        );
+
+        let mut invalid = manifest();
+        invalid.regions[0].bitmap.pop();
+        assert_eq!(invalid.validate(), Err(ManifestError::InvalidBitmap(0)));
+
+        // A page past the end of the region is marked as dirty.
+        let mut invalid = manifest();
+        invalid.regions[1].bitmap[0] |= 0b100;
+        assert_eq!(
+            invalid.validate(),
+            Err(ManifestError::InvalidBitmap(4096 * 66))
+        );
+    }
+}
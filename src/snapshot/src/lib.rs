@@ -27,6 +27,8 @@
 //! primitives types (currently we use versionize that uses serde bincode as a backend). The current
 //! implementation does not have any logic dependent on it.
 //!  - **the data version** which refers to the state.
+pub mod diff_chain;
+pub mod diff_manifest;
 mod persist;
 use std::io::{Read, Write};
 
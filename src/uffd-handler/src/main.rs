@@ -0,0 +1,616 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+#![warn(clippy::ptr_as_ptr)]
+#![warn(clippy::undocumented_unsafe_blocks)]
+#![warn(clippy::cast_lossless)]
+
+//! Userfaultfd page fault handler serving the guest memory of a restored microVM from a
+//! memory file.
+//!
+//! Pages are populated one at a time, when the guest first accesses them. The order in which
+//! the pages are faulted can be recorded in a working set file. When restoring from the same
+//! snapshot again, the recorded working set is prefetched in the background, ahead of the
+//! guest accesses, while page faults keep being served first.
+
+mod memory;
+mod working_set;
+
+use std::fs::{File, OpenOptions};
+use std::io::ErrorKind;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixListener;
+use std::{env, process, ptr};
+
+use userfaultfd::{Event, Uffd};
+use utils::arg_parser::{ArgParser, Argument, Arguments};
+use utils::get_page_size;
+use utils::sock_ctrl_msg::ScmSocket;
+
+use crate::memory::{GuestMemory, GuestRegionUffdMapping, PageIndex, PageState};
+use crate::working_set::Recorder;
+
+const UFFD_HANDLER_VERSION: &str = env!("FIRECRACKER_VERSION");
+const EXIT_CODE_SUCCESS: i32 = 0;
+const SOCKET: &str = "socket";
+const MEM_FILE: &str = "mem-file";
+const WORKING_SET: &str = "working-set";
+
+// Maximum size of the memory mappings message sent by Firecracker.
+const MAPPINGS_MESSAGE_MAX_SIZE: usize = 4096;
+// Number of working set pages prefetched between two checks for page faults.
+const PREFETCH_BATCH_SIZE: usize = 64;
+// Magic starting a compressed memory file, which cannot be mapped.
+// This is the same with the one used in src/vmm.
+const COMPRESSED_MEM_FILE_MAGIC: [u8; 8] = *b"FCMEMLZ4";
+
+#[derive(Debug)]
+enum Error {
+    InvalidMemFile(std::io::Error),
+    CompressedMemFile,
+    MemFileTooShort(u64, u64),
+    MmapMemFile(std::io::Error),
+    InvalidWorkingSetFile(std::io::Error),
+    LoadWorkingSet(working_set::Error),
+    RecordWorkingSet(working_set::Error),
+    Bind(std::io::Error),
+    Accept(std::io::Error),
+    ReceiveMappings(utils::errno::Error),
+    MissingUffd,
+    InvalidMappings(serde_json::Error),
+    Poll(std::io::Error),
+    ReadEvent(userfaultfd::Error),
+    UnexpectedEvent(Event),
+    UnknownAddress(u64),
+    Copy(u64, userfaultfd::Error),
+    Zeropage(u64, userfaultfd::Error),
+    Wake(u64, userfaultfd::Error),
+}
+
+/// What to do with the working set file.
+enum WorkingSet {
+    /// Prefetch the recorded page offsets.
+    Prefetch(Vec<u64>),
+    /// Record the faulted pages.
+    Record(File),
+}
+
+fn build_arg_parser<'a>() -> ArgParser<'a> {
+    let arg_parser = ArgParser::new()
+        .arg(Argument::new(SOCKET).required(true).takes_value(true).help(
+            "Path of the Unix domain socket on which Firecracker sends the userfaultfd. Must \
+             match the `backend_path` of the `Uffd` memory backend.",
+        ))
+        .arg(
+            Argument::new(MEM_FILE)
+                .required(true)
+                .takes_value(true)
+                .help("File path of the mem snapshot serving the guest memory."),
+        )
+        .arg(Argument::new(WORKING_SET).takes_value(true).help(
+            "File path of the working set of the mem snapshot. If the file does not exist, the \
+             order in which pages are faulted is recorded into it. Otherwise, the recorded pages \
+             are prefetched ahead of the guest accesses.",
+        ));
+
+    arg_parser
+}
+
+fn extract_args<'a>(arg_parser: &'a mut ArgParser<'a>) -> &'a Arguments<'a> {
+    arg_parser.parse_from_cmdline().unwrap_or_else(|err| {
+        panic!(
+            "Arguments parsing error: {} \n\nFor more information try --help.",
+            err
+        );
+    });
+
+    if arg_parser.arguments().flag_present("help") {
+        println!("Uffd_handler v{}", UFFD_HANDLER_VERSION);
+        println!(
+            "Userfaultfd page fault handler serving guest memory from a mem snapshot, with \
+             working set prefetching\n"
+        );
+        println!("{}", arg_parser.formatted_help());
+        process::exit(EXIT_CODE_SUCCESS);
+    }
+    if arg_parser.arguments().flag_present("version") {
+        println!("Uffd_handler v{}\n", UFFD_HANDLER_VERSION);
+        process::exit(EXIT_CODE_SUCCESS);
+    }
+
+    arg_parser.arguments()
+}
+
+fn parse_args(args: &Arguments, page_size: usize) -> Result<(File, Option<WorkingSet>), Error> {
+    // Safe to unwrap since the required arguments are checked as part of
+    // `arg_parser.parse_from_cmdline()`
+    let mem_file_path = args.single_value(MEM_FILE).unwrap();
+    let mem_file = File::open(mem_file_path).map_err(Error::InvalidMemFile)?;
+    if is_compressed(&mem_file).map_err(Error::InvalidMemFile)? {
+        return Err(Error::CompressedMemFile);
+    }
+
+    let working_set = match args.single_value(WORKING_SET) {
+        Some(working_set_path) => match File::open(working_set_path) {
+            Ok(mut file) => Some(WorkingSet::Prefetch(
+                working_set::load(&mut file, page_size).map_err(Error::LoadWorkingSet)?,
+            )),
+            Err(err) if err.kind() == ErrorKind::NotFound => Some(WorkingSet::Record(
+                OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(working_set_path)
+                    .map_err(Error::InvalidWorkingSetFile)?,
+            )),
+            Err(err) => return Err(Error::InvalidWorkingSetFile(err)),
+        },
+        None => None,
+    };
+
+    Ok((mem_file, working_set))
+}
+
+/// Checks whether the memory file was written in the compressed format, whose pages cannot be
+/// served from a mapping of the file.
+fn is_compressed(mem_file: &File) -> std::io::Result<bool> {
+    let mut magic = [0u8; COMPRESSED_MEM_FILE_MAGIC.len()];
+    match mem_file.read_exact_at(&mut magic, 0) {
+        Ok(()) => Ok(magic == COMPRESSED_MEM_FILE_MAGIC),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Serves the guest memory page faults from a mapping of the memory file.
+struct PageServer {
+    uffd: Uffd,
+    memory: GuestMemory,
+    mem_file_ptr: *const u8,
+    recorder: Option<Recorder>,
+}
+
+impl PageServer {
+    /// Populates a page from the memory file.
+    fn copy_page(&mut self, page: PageIndex) -> Result<(), Error> {
+        let addr = self.memory.host_addr(page);
+        let src = self.mem_file_ptr as u64 + self.memory.file_offset(page);
+        // SAFETY: Safe because the source lies within the mapping of the memory file, as checked
+        // when the server was created, and the destination is a page of a region registered with
+        // the userfaultfd.
+        let result = unsafe {
+            self.uffd.copy(
+                src as *const _,
+                addr as *mut _,
+                self.memory.page_size(),
+                true,
+            )
+        };
+        match result {
+            Ok(_) => (),
+            // The page was already populated, in which case the faulting thread is woken up.
+            Err(userfaultfd::Error::CopyFailed(errno)) if errno as i32 == libc::EEXIST => (),
+            Err(err) => return Err(Error::Copy(addr, err)),
+        }
+        self.memory.set_state(page, PageState::FromFile);
+        Ok(())
+    }
+
+    /// Serves a page fault at the host virtual address `addr`.
+    fn serve_fault(&mut self, addr: u64) -> Result<(), Error> {
+        let page = self
+            .memory
+            .page_at_addr(addr)
+            .ok_or(Error::UnknownAddress(addr))?;
+        match self.memory.state(page) {
+            PageState::Uninitialized => {
+                self.copy_page(page)?;
+                if let Some(recorder) = self.recorder.as_mut() {
+                    recorder
+                        .record(self.memory.file_offset(page))
+                        .map_err(Error::RecordWorkingSet)?;
+                }
+            }
+            // Pages removed through the balloon device are handed back zeroed.
+            PageState::Removed | PageState::Anonymous => {
+                let page_addr = self.memory.host_addr(page);
+                // SAFETY: Safe because the destination is a page of a region registered with the
+                // userfaultfd.
+                unsafe {
+                    self.uffd
+                        .zeropage(page_addr as *mut _, self.memory.page_size(), true)
+                        .map_err(|err| Error::Zeropage(page_addr, err))?;
+                }
+                self.memory.set_state(page, PageState::Anonymous);
+            }
+            // The fault was raised before the page got prefetched.
+            PageState::FromFile => {
+                let page_addr = self.memory.host_addr(page);
+                self.uffd
+                    .wake(page_addr as *mut _, self.memory.page_size())
+                    .map_err(|err| Error::Wake(page_addr, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Prefetches the page stored at `offset` in the memory file, unless the guest already
+    /// accessed it.
+    fn prefetch(&mut self, offset: u64) -> Result<(), Error> {
+        match self.memory.page_at_offset(offset) {
+            Some(page) if self.memory.state(page) == PageState::Uninitialized => {
+                self.copy_page(page)
+            }
+            // The working set may come from a microVM with a different memory layout.
+            _ => Ok(()),
+        }
+    }
+
+    /// Handles the pending userfaultfd events.
+    fn handle_events(&mut self) -> Result<(), Error> {
+        while let Some(event) = self.uffd.read_event().map_err(Error::ReadEvent)? {
+            match event {
+                Event::Pagefault { addr, .. } => self.serve_fault(addr as u64)?,
+                Event::Remove { start, end } => self.memory.remove(start as u64, end as u64),
+                event => return Err(Error::UnexpectedEvent(event)),
+            }
+        }
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.flush().map_err(Error::RecordWorkingSet)?;
+        }
+        Ok(())
+    }
+
+    /// Waits for `timeout` milliseconds, or forever if negative, for userfaultfd events and
+    /// returns the received poll events.
+    fn poll(&self, timeout: i32) -> Result<libc::c_short, Error> {
+        let mut pollfd = libc::pollfd {
+            fd: self.uffd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: Safe because the parameters are valid.
+        if unsafe { libc::poll(&mut pollfd, 1, timeout) } == -1 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() != ErrorKind::Interrupted {
+                return Err(Error::Poll(err));
+            }
+        }
+        Ok(pollfd.revents)
+    }
+
+    /// Serves page faults until Firecracker exits, prefetching `working_set` while there are
+    /// none.
+    fn run(&mut self, working_set: Vec<u64>) -> Result<(), Error> {
+        let mut prefetch = working_set.into_iter().peekable();
+        loop {
+            // Page faults stall the guest, so they are always served before prefetching.
+            let timeout = if prefetch.peek().is_some() { 0 } else { -1 };
+            let revents = self.poll(timeout)?;
+            if revents & libc::POLLIN != 0 {
+                self.handle_events()?;
+                continue;
+            }
+            // The userfaultfd is closed when Firecracker exits.
+            if revents & (libc::POLLHUP | libc::POLLERR) != 0 {
+                return Ok(());
+            }
+            for offset in prefetch.by_ref().take(PREFETCH_BATCH_SIZE) {
+                self.prefetch(offset)?;
+            }
+        }
+    }
+}
+
+fn run(
+    mem_file: File,
+    working_set: Option<WorkingSet>,
+    args: &Arguments,
+    page_size: usize,
+) -> Result<(), Error> {
+    // Safe to unwrap since the required arguments are checked as part of
+    // `arg_parser.parse_from_cmdline()`
+    let socket_path = args.single_value(SOCKET).unwrap();
+    let listener = UnixListener::bind(socket_path).map_err(Error::Bind)?;
+    let (stream, _) = listener.accept().map_err(Error::Accept)?;
+
+    let mut message = vec![0u8; MAPPINGS_MESSAGE_MAX_SIZE];
+    let (message_len, uffd_file) = stream
+        .recv_with_fd(&mut message)
+        .map_err(Error::ReceiveMappings)?;
+    let uffd_file = uffd_file.ok_or(Error::MissingUffd)?;
+    let mappings: Vec<GuestRegionUffdMapping> =
+        serde_json::from_slice(&message[..message_len]).map_err(Error::InvalidMappings)?;
+    let memory = GuestMemory::new(mappings, page_size);
+
+    let mem_file_len = mem_file.metadata().map_err(Error::InvalidMemFile)?.len();
+    if memory.mem_file_end() > mem_file_len {
+        return Err(Error::MemFileTooShort(mem_file_len, memory.mem_file_end()));
+    }
+    // SAFETY: Safe because the parameters are valid and the mapping is checked below.
+    let mem_file_ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            mem_file_len as usize,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            mem_file.as_raw_fd(),
+            0,
+        )
+    };
+    if mem_file_ptr == libc::MAP_FAILED {
+        return Err(Error::MmapMemFile(std::io::Error::last_os_error()));
+    }
+
+    let (working_set, recorder) = match working_set {
+        Some(WorkingSet::Prefetch(offsets)) => (offsets, None),
+        Some(WorkingSet::Record(file)) => (
+            Vec::new(),
+            Some(Recorder::new(file, page_size).map_err(Error::RecordWorkingSet)?),
+        ),
+        None => (Vec::new(), None),
+    };
+
+    let mut page_server = PageServer {
+        // SAFETY: Safe because the file descriptor was sent by Firecracker as its userfaultfd.
+        uffd: unsafe { Uffd::from_raw_fd(uffd_file.into_raw_fd()) },
+        memory,
+        mem_file_ptr: mem_file_ptr.cast::<u8>(),
+        recorder,
+    };
+    page_server.run(working_set)
+}
+
+fn main() {
+    let mut arg_parser = build_arg_parser();
+    let args = extract_args(&mut arg_parser);
+    let page_size =
+        get_page_size().unwrap_or_else(|err| panic!("Error getting the page size: {}", err));
+    let (mem_file, working_set) = parse_args(args, page_size)
+        .unwrap_or_else(|err| panic!("Error parsing the cmd line args: {:?}", err));
+
+    run(mem_file, working_set, args, page_size)
+        .unwrap_or_else(|err| panic!("Error serving the guest memory: {:?}", err));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::thread;
+
+    use userfaultfd::UffdBuilder;
+    use utils::tempfile::TempFile;
+
+    use super::*;
+
+    macro_rules! assert_err {
+        ($expression:expr, $($pattern:tt)+) => {
+            match $expression {
+                Err($($pattern)+) => (),
+                ref err =>  {
+                    println!(
+                        "expected `{}` but got `{:?}`",
+                        stringify!($($pattern)+),
+                        err.as_ref().err()
+                    );
+                    assert!(false)
+                }
+            }
+        }
+    }
+
+    fn parse(arg_parser: &ArgParser, args: Vec<&str>) -> Result<(File, Option<WorkingSet>), Error> {
+        let arguments = &mut arg_parser.arguments().clone();
+        arguments
+            .parse(
+                args.into_iter()
+                    .map(String::from)
+                    .collect::<Vec<String>>()
+                    .as_ref(),
+            )
+            .unwrap();
+        parse_args(arguments, 4096)
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let mem_file = TempFile::new().unwrap();
+        let mem_file_path = mem_file.as_path().to_str().unwrap().to_string();
+        let working_set_dir = utils::tempdir::TempDir::new().unwrap();
+        let working_set_path = working_set_dir
+            .as_path()
+            .join("working_set")
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let arg_parser = build_arg_parser();
+        assert_err!(
+            parse(
+                &arg_parser,
+                vec!["uffd_handler", "--socket", "sock", "--mem-file", "mem_file"]
+            ),
+            Error::InvalidMemFile(_)
+        );
+        assert!(matches!(
+            parse(
+                &arg_parser,
+                vec![
+                    "uffd_handler",
+                    "--socket",
+                    "sock",
+                    "--mem-file",
+                    &mem_file_path
+                ]
+            ),
+            Ok((_, None))
+        ));
+
+        // The working set does not exist yet, so it gets recorded.
+        let args = vec![
+            "uffd_handler",
+            "--socket",
+            "sock",
+            "--mem-file",
+            &mem_file_path,
+            "--working-set",
+            &working_set_path,
+        ];
+        match parse(&arg_parser, args.clone()) {
+            Ok((_, Some(WorkingSet::Record(file)))) => {
+                let mut recorder = Recorder::new(file, 4096).unwrap();
+                recorder.record(0x2000).unwrap();
+                recorder.flush().unwrap();
+            }
+            _ => panic!("The working set should be recorded"),
+        }
+
+        // The recorded working set gets prefetched.
+        match parse(&arg_parser, args) {
+            Ok((_, Some(WorkingSet::Prefetch(offsets)))) => assert_eq!(offsets, vec![0x2000]),
+            _ => panic!("The working set should be prefetched"),
+        }
+
+        // The working set is not a valid working set file.
+        assert_err!(
+            parse(
+                &arg_parser,
+                vec![
+                    "uffd_handler",
+                    "--socket",
+                    "sock",
+                    "--mem-file",
+                    &mem_file_path,
+                    "--working-set",
+                    &mem_file_path,
+                ]
+            ),
+            Error::LoadWorkingSet(working_set::Error::InvalidMagic)
+        );
+
+        // Compressed memory files are rejected.
+        mem_file
+            .as_file()
+            .write_all_at(&COMPRESSED_MEM_FILE_MAGIC, 0)
+            .unwrap();
+        assert_err!(
+            parse(
+                &arg_parser,
+                vec![
+                    "uffd_handler",
+                    "--socket",
+                    "sock",
+                    "--mem-file",
+                    &mem_file_path
+                ]
+            ),
+            Error::CompressedMemFile
+        );
+    }
+
+    // Creates a page server for `num_pages` anonymous pages registered with a new userfaultfd,
+    // serving them from `mem_file`.
+    fn new_page_server(
+        mem_file: &File,
+        num_pages: usize,
+        recorder: Option<Recorder>,
+    ) -> PageServer {
+        let page_size = get_page_size().unwrap();
+        let len = page_size * num_pages;
+        // SAFETY: Safe because the parameters are valid.
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let uffd = UffdBuilder::new()
+            .close_on_exec(true)
+            .non_blocking(true)
+            .create()
+            .unwrap();
+        uffd.register(addr, len).unwrap();
+
+        // SAFETY: Safe because the parameters are valid.
+        let mem_file_ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                mem_file.as_raw_fd(),
+                0,
+            )
+        };
+        assert_ne!(mem_file_ptr, libc::MAP_FAILED);
+
+        let mappings = vec![GuestRegionUffdMapping {
+            base_host_virt_addr: addr as u64,
+            size: len,
+            offset: 0,
+        }];
+        PageServer {
+            uffd,
+            memory: GuestMemory::new(mappings, page_size),
+            mem_file_ptr: mem_file_ptr.cast::<u8>(),
+            recorder,
+        }
+    }
+
+    // Reads the first byte of the page at `addr` from another thread, which blocks until the
+    // page fault is served.
+    fn fault(page_server: &mut PageServer, addr: u64) -> u8 {
+        // SAFETY: Safe because the address lies within a mapping registered with the userfaultfd.
+        let reader = thread::spawn(move || unsafe { ptr::read_volatile(addr as *const u8) });
+        assert_ne!(page_server.poll(5000).unwrap() & libc::POLLIN, 0);
+        page_server.handle_events().unwrap();
+        reader.join().unwrap()
+    }
+
+    #[test]
+    fn test_serve_working_set() {
+        let page_size = get_page_size().unwrap();
+        // Every page of the memory file is filled with its index plus one.
+        let mut mem_file = TempFile::new().unwrap().into_file();
+        for page in 0..4u8 {
+            mem_file.write_all(&vec![page + 1; page_size]).unwrap();
+        }
+        let working_set_file = TempFile::new().unwrap();
+
+        // The first restore records the faulted pages.
+        let recorder =
+            Recorder::new(working_set_file.as_file().try_clone().unwrap(), page_size).unwrap();
+        let mut page_server = new_page_server(&mem_file, 4, Some(recorder));
+        let base_addr = page_server.memory.host_addr((0, 0));
+        assert_eq!(fault(&mut page_server, base_addr + page_size as u64 * 2), 3);
+        let working_set = working_set::load(
+            &mut File::open(working_set_file.as_path()).unwrap(),
+            page_size,
+        )
+        .unwrap();
+        assert_eq!(working_set, vec![page_size as u64 * 2]);
+
+        // The next restore prefetches the working set, then serves the faults of the other pages.
+        let mut page_server = new_page_server(&mem_file, 4, None);
+        for offset in working_set {
+            page_server.prefetch(offset).unwrap();
+        }
+        let prefetched_page = page_server.memory.page_at_offset(page_size as u64 * 2);
+        assert_eq!(
+            page_server.memory.state(prefetched_page.unwrap()),
+            PageState::FromFile
+        );
+        let base_addr = page_server.memory.host_addr((0, 0));
+        // SAFETY: Safe because the page was populated from the memory file.
+        let prefetched_value =
+            unsafe { ptr::read_volatile((base_addr + page_size as u64 * 2) as *const u8) };
+        assert_eq!(prefetched_value, 3);
+        assert_eq!(fault(&mut page_server, base_addr), 1);
+        assert_eq!(page_server.memory.state((0, 0)), PageState::FromFile);
+    }
+}
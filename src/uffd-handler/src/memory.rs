@@ -0,0 +1,192 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Tracks the guest memory regions served by the handler and the state of their pages.
+
+use serde::Deserialize;
+
+// This is the same with the one used in src/vmm.
+/// This describes the mapping between Firecracker base virtual address and offset in the
+/// memory file for a guest memory region.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+pub struct GuestRegionUffdMapping {
+    /// Base host virtual address where the guest memory contents for this region
+    /// should be copied/populated.
+    pub base_host_virt_addr: u64,
+    /// Region size.
+    pub size: usize,
+    /// Offset in the memory file where the region contents are.
+    pub offset: u64,
+}
+
+/// State of a guest memory page.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageState {
+    /// The page was never touched.
+    Uninitialized,
+    /// The page was populated from the memory file.
+    FromFile,
+    /// The page was removed by the guest, through the balloon device.
+    Removed,
+    /// The page was zeroed after having been removed.
+    Anonymous,
+}
+
+/// Location of a guest memory page: the index of its region and its index in the region.
+pub type PageIndex = (usize, usize);
+
+struct Region {
+    mapping: GuestRegionUffdMapping,
+    pages: Vec<PageState>,
+}
+
+/// Guest memory regions registered with the userfaultfd.
+pub struct GuestMemory {
+    regions: Vec<Region>,
+    page_size: usize,
+}
+
+impl GuestMemory {
+    /// Creates the guest memory from the mappings sent by Firecracker, with all the pages
+    /// uninitialized.
+    pub fn new(mappings: Vec<GuestRegionUffdMapping>, page_size: usize) -> Self {
+        let regions = mappings
+            .into_iter()
+            .map(|mapping| Region {
+                pages: vec![PageState::Uninitialized; mapping.size / page_size],
+                mapping,
+            })
+            .collect();
+        GuestMemory { regions, page_size }
+    }
+
+    /// Returns the page size.
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Returns the end of the memory file section used by the regions.
+    pub fn mem_file_end(&self) -> u64 {
+        self.regions
+            .iter()
+            .map(|region| region.mapping.offset + region.mapping.size as u64)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Returns the page holding the host virtual address `addr`.
+    pub fn page_at_addr(&self, addr: u64) -> Option<PageIndex> {
+        self.regions.iter().enumerate().find_map(|(index, region)| {
+            let start = region.mapping.base_host_virt_addr;
+            (start..start + region.mapping.size as u64)
+                .contains(&addr)
+                .then(|| (index, ((addr - start) / self.page_size as u64) as usize))
+        })
+    }
+
+    /// Returns the page stored at `offset` in the memory file.
+    pub fn page_at_offset(&self, offset: u64) -> Option<PageIndex> {
+        self.regions.iter().enumerate().find_map(|(index, region)| {
+            let start = region.mapping.offset;
+            (start..start + region.mapping.size as u64)
+                .contains(&offset)
+                .then(|| (index, ((offset - start) / self.page_size as u64) as usize))
+        })
+    }
+
+    /// Returns the host virtual address of a page.
+    pub fn host_addr(&self, (region, page): PageIndex) -> u64 {
+        self.regions[region].mapping.base_host_virt_addr + (page * self.page_size) as u64
+    }
+
+    /// Returns the offset of a page in the memory file.
+    pub fn file_offset(&self, (region, page): PageIndex) -> u64 {
+        self.regions[region].mapping.offset + (page * self.page_size) as u64
+    }
+
+    /// Returns the state of a page.
+    pub fn state(&self, (region, page): PageIndex) -> PageState {
+        self.regions[region].pages[page]
+    }
+
+    /// Sets the state of a page.
+    pub fn set_state(&mut self, (region, page): PageIndex, state: PageState) {
+        self.regions[region].pages[page] = state;
+    }
+
+    /// Marks the pages between the host virtual addresses `start` and `end` as removed.
+    pub fn remove(&mut self, start: u64, end: u64) {
+        let page_size = self.page_size as u64;
+        for region in self.regions.iter_mut() {
+            let region_start = region.mapping.base_host_virt_addr;
+            let first_page = start.saturating_sub(region_start) / page_size;
+            let last_page = end.saturating_sub(region_start) / page_size;
+            let range = first_page as usize..(last_page as usize).min(region.pages.len());
+            if let Some(pages) = region.pages.get_mut(range) {
+                pages.fill(PageState::Removed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guest_memory() -> GuestMemory {
+        GuestMemory::new(
+            vec![
+                GuestRegionUffdMapping {
+                    base_host_virt_addr: 0x10_0000,
+                    size: 0x4000,
+                    offset: 0,
+                },
+                GuestRegionUffdMapping {
+                    base_host_virt_addr: 0x20_0000,
+                    size: 0x2000,
+                    offset: 0x4000,
+                },
+            ],
+            0x1000,
+        )
+    }
+
+    #[test]
+    fn test_page_lookup() {
+        let memory = guest_memory();
+        assert_eq!(memory.mem_file_end(), 0x6000);
+
+        assert_eq!(memory.page_at_addr(0x10_0000), Some((0, 0)));
+        assert_eq!(memory.page_at_addr(0x10_3fff), Some((0, 3)));
+        assert_eq!(memory.page_at_addr(0x20_1234), Some((1, 1)));
+        assert_eq!(memory.page_at_addr(0x10_4000), None);
+        assert_eq!(memory.page_at_addr(0xf_ffff), None);
+
+        assert_eq!(memory.page_at_offset(0x3000), Some((0, 3)));
+        assert_eq!(memory.page_at_offset(0x5000), Some((1, 1)));
+        assert_eq!(memory.page_at_offset(0x6000), None);
+
+        assert_eq!(memory.host_addr((1, 1)), 0x20_1000);
+        assert_eq!(memory.file_offset((1, 1)), 0x5000);
+    }
+
+    #[test]
+    fn test_page_states() {
+        let mut memory = guest_memory();
+        assert_eq!(memory.state((0, 1)), PageState::Uninitialized);
+        memory.set_state((0, 1), PageState::FromFile);
+        assert_eq!(memory.state((0, 1)), PageState::FromFile);
+
+        // Remove the last two pages of the first region.
+        memory.remove(0x10_2000, 0x10_4000);
+        assert_eq!(memory.state((0, 1)), PageState::FromFile);
+        assert_eq!(memory.state((0, 2)), PageState::Removed);
+        assert_eq!(memory.state((0, 3)), PageState::Removed);
+        assert_eq!(memory.state((1, 0)), PageState::Uninitialized);
+
+        // Ranges spanning several regions.
+        memory.remove(0, u64::MAX);
+        assert_eq!(memory.state((0, 0)), PageState::Removed);
+        assert_eq!(memory.state((1, 1)), PageState::Removed);
+    }
+}
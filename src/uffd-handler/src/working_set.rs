@@ -0,0 +1,129 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Records and loads the working set of a restored microVM.
+//!
+//! A working set file lists the offsets, in the memory file, of the pages faulted by the guest,
+//! in the order in which they were first accessed. It starts with a magic value and the page
+//! size, followed by one little endian `u64` per page. Records are appended while the guest
+//! runs, so a trailing incomplete record, left by an interrupted handler, is ignored.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+
+/// Magic value found at the start of a working set file.
+pub const WORKING_SET_MAGIC: [u8; 8] = *b"FCWSET01";
+
+// Magic value and page size.
+const HEADER_SIZE: usize = 16;
+// Page offset.
+const RECORD_SIZE: usize = 8;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to access the working set file.
+    Io(io::Error),
+    /// The working set file does not start with the expected magic value.
+    InvalidMagic,
+    /// The working set was recorded with a different page size.
+    PageSize(u64),
+}
+
+/// Loads the page offsets listed in a working set file recorded with `page_size`.
+pub fn load<T: Read>(reader: &mut T, page_size: usize) -> Result<Vec<u64>, Error> {
+    let mut content = Vec::new();
+    reader.read_to_end(&mut content).map_err(Error::Io)?;
+    if content.len() < HEADER_SIZE || content[..8] != WORKING_SET_MAGIC {
+        return Err(Error::InvalidMagic);
+    }
+    let recorded_page_size = read_u64(&content[8..HEADER_SIZE]);
+    if recorded_page_size != page_size as u64 {
+        return Err(Error::PageSize(recorded_page_size));
+    }
+
+    Ok(content[HEADER_SIZE..]
+        .chunks_exact(RECORD_SIZE)
+        .map(read_u64)
+        .collect())
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(buf);
+    u64::from_le_bytes(word)
+}
+
+/// Appends the pages faulted by the guest to a working set file.
+pub struct Recorder {
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    /// Starts a working set file with pages of `page_size` bytes.
+    pub fn new(file: File, page_size: usize) -> Result<Self, Error> {
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&WORKING_SET_MAGIC).map_err(Error::Io)?;
+        writer
+            .write_all(&(page_size as u64).to_le_bytes())
+            .map_err(Error::Io)?;
+        Ok(Recorder { writer })
+    }
+
+    /// Records a page faulted by the guest.
+    pub fn record(&mut self, offset: u64) -> Result<(), Error> {
+        self.writer
+            .write_all(&offset.to_le_bytes())
+            .map_err(Error::Io)
+    }
+
+    /// Writes the recorded pages to the file.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush().map_err(Error::Io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom};
+
+    use utils::tempfile::TempFile;
+
+    use super::*;
+
+    #[test]
+    fn test_record_load() {
+        let mut file = TempFile::new().unwrap().into_file();
+        let mut recorder = Recorder::new(file.try_clone().unwrap(), 4096).unwrap();
+        for offset in [0x3000, 0, 0x1_0000_0000] {
+            recorder.record(offset).unwrap();
+        }
+        recorder.flush().unwrap();
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(
+            load(&mut file, 4096).unwrap(),
+            vec![0x3000, 0, 0x1_0000_0000]
+        );
+
+        // The page size must match.
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert!(matches!(load(&mut file, 16384), Err(Error::PageSize(4096))));
+
+        // A trailing incomplete record is ignored.
+        file.write_all(&[0xff; 3]).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        assert_eq!(load(&mut file, 4096).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_load_invalid() {
+        assert!(matches!(
+            load(&mut &b"FCWSET01"[..], 4096),
+            Err(Error::InvalidMagic)
+        ));
+        assert!(matches!(
+            load(&mut &[0u8; 32][..], 4096),
+            Err(Error::InvalidMagic)
+        ));
+    }
+}
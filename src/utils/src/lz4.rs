@@ -0,0 +1,451 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Minimal implementation of the LZ4 block format.
+//!
+//! Only the raw block format is supported (no frame headers, no dictionaries). The compressor
+//! is a simple greedy single-pass matcher which trades compression ratio for speed, which is
+//! what we want when compressing guest memory. The decompressor validates every sequence, so
+//! corrupted or malicious input results in an error rather than out of bounds accesses.
+use std::fmt;
+
+// Minimum length of a match.
+const MIN_MATCH: usize = 4;
+// The last 5 bytes of a block are always literals.
+const LAST_LITERALS: usize = 5;
+// The last match must start at least 12 bytes before the end of the block.
+const MF_LIMIT: usize = 12;
+// Maximum back-reference distance.
+const MAX_DISTANCE: usize = 65535;
+// Number of bits used to index the match finder hash table.
+const HASH_LOG: u32 = 12;
+// Skip-ahead speed factor used when no matches are found.
+const SKIP_TRIGGER: usize = 6;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The compressed block ends in the middle of a sequence.
+    Truncated,
+    /// A match references data before the start of the output.
+    InvalidOffset(usize),
+    /// The decompressed data does not fit in the output buffer.
+    OutputOverflow,
+    /// The decompressed data is shorter than the output buffer.
+    SizeMismatch(usize, usize), // (expected, actual)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Truncated => write!(f, "truncated LZ4 block"),
+            Error::InvalidOffset(offset) => write!(f, "invalid LZ4 match offset {}", offset),
+            Error::OutputOverflow => write!(f, "LZ4 block decompresses past the output buffer"),
+            Error::SizeMismatch(expected, actual) => write!(
+                f,
+                "LZ4 block decompressed to {} bytes, expected {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
+#[inline]
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+#[inline]
+fn hash(seq: u32) -> usize {
+    (seq.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+fn write_length(output: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        output.push(255);
+        len -= 255;
+    }
+    output.push(len as u8);
+}
+
+fn write_literals(output: &mut Vec<u8>, token: u8, literals: &[u8]) {
+    let lit_len = literals.len();
+    output.push(token | ((lit_len.min(15) as u8) << 4));
+    if lit_len >= 15 {
+        write_length(output, lit_len - 15);
+    }
+    output.extend_from_slice(literals);
+}
+
+/// Compresses `input` as a single LZ4 block, appending the result to `output`.
+///
+/// Returns the number of bytes appended.
+pub fn compress(input: &[u8], output: &mut Vec<u8>) -> usize {
+    let start_len = output.len();
+    let len = input.len();
+    let mut anchor = 0;
+
+    if len > MF_LIMIT {
+        let mut table = vec![0u32; 1 << HASH_LOG];
+        let mut pos = 0;
+
+        while pos + MF_LIMIT <= len {
+            let seq = read_u32(input, pos);
+            let h = hash(seq);
+            let candidate = table[h] as usize;
+            table[h] = pos as u32;
+
+            // Stale or colliding table entries are filtered out by comparing the actual bytes.
+            if candidate < pos
+                && pos - candidate <= MAX_DISTANCE
+                && read_u32(input, candidate) == seq
+            {
+                let max_len = len - LAST_LITERALS - pos;
+                let mut match_len = MIN_MATCH;
+                while match_len < max_len && input[candidate + match_len] == input[pos + match_len]
+                {
+                    match_len += 1;
+                }
+
+                let ml = match_len - MIN_MATCH;
+                write_literals(output, ml.min(15) as u8, &input[anchor..pos]);
+                output.extend_from_slice(&((pos - candidate) as u16).to_le_bytes());
+                if ml >= 15 {
+                    write_length(output, ml - 15);
+                }
+
+                pos += match_len;
+                anchor = pos;
+            } else {
+                // Move faster through incompressible data.
+                pos += 1 + ((pos - anchor) >> SKIP_TRIGGER);
+            }
+        }
+    }
+
+    write_literals(output, 0, &input[anchor..]);
+    output.len() - start_len
+}
+
+fn read_length(input: &[u8], ip: &mut usize) -> Result<usize, Error> {
+    let mut len = 0usize;
+    loop {
+        let byte = *input.get(*ip).ok_or(Error::Truncated)?;
+        *ip += 1;
+        len = len
+            .checked_add(byte as usize)
+            .ok_or(Error::OutputOverflow)?;
+        if byte != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+/// Decompresses a single LZ4 block from `input` into `output`.
+///
+/// The block must decompress to exactly `output.len()` bytes.
+pub fn decompress(input: &[u8], output: &mut [u8]) -> Result<(), Error> {
+    let mut ip = 0;
+    let mut op = 0;
+
+    loop {
+        let token = *input.get(ip).ok_or(Error::Truncated)?;
+        ip += 1;
+
+        let mut lit_len = (token >> 4) as usize;
+        if lit_len == 15 {
+            lit_len += read_length(input, &mut ip)?;
+        }
+        let literals = input
+            .get(ip..ip.checked_add(lit_len).ok_or(Error::Truncated)?)
+            .ok_or(Error::Truncated)?;
+        output
+            .get_mut(op..op + lit_len)
+            .ok_or(Error::OutputOverflow)?
+            .copy_from_slice(literals);
+        ip += lit_len;
+        op += lit_len;
+
+        // The last sequence only contains literals.
+        if ip == input.len() {
+            break;
+        }
+
+        let offset_bytes = input.get(ip..ip + 2).ok_or(Error::Truncated)?;
+        let offset = u16::from_le_bytes([offset_bytes[0], offset_bytes[1]]) as usize;
+        ip += 2;
+        if offset == 0 || offset > op {
+            return Err(Error::InvalidOffset(offset));
+        }
+
+        let mut match_len = (token & 0x0f) as usize;
+        if match_len == 15 {
+            match_len += read_length(input, &mut ip)?;
+        }
+        match_len += MIN_MATCH;
+        if match_len > output.len() - op {
+            return Err(Error::OutputOverflow);
+        }
+
+        // Matches may overlap the bytes they produce, so copy one byte at a time.
+        for i in op..op + match_len {
+            output[i] = output[i - offset];
+        }
+        op += match_len;
+    }
+
+    if op != output.len() {
+        return Err(Error::SizeMismatch(output.len(), op));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(data: &[u8]) -> usize {
+        let mut compressed = Vec::new();
+        let len = compress(data, &mut compressed);
+        assert_eq!(len, compressed.len());
+        check_end_of_block(&compressed, data.len());
+
+        let mut decompressed = vec![0u8; data.len()];
+        decompress(&compressed, &mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+        len
+    }
+
+    // Checks the end of block conditions of the LZ4 block format, which `decompress` does not
+    // enforce but the reference decoder relies on: the last match starts at least `MF_LIMIT`
+    // bytes before the end of the block and the last `LAST_LITERALS` bytes are literals.
+    fn check_end_of_block(block: &[u8], decompressed_len: usize) {
+        let mut ip = 0;
+        let mut op = 0;
+        loop {
+            let token = block[ip];
+            ip += 1;
+            let mut lit_len = (token >> 4) as usize;
+            if lit_len == 15 {
+                lit_len += read_length(block, &mut ip).unwrap();
+            }
+            ip += lit_len;
+            op += lit_len;
+            if ip == block.len() {
+                break;
+            }
+
+            assert!(op + MF_LIMIT <= decompressed_len);
+            ip += 2;
+            let mut match_len = (token & 0x0f) as usize;
+            if match_len == 15 {
+                match_len += read_length(block, &mut ip).unwrap();
+            }
+            op += match_len + MIN_MATCH;
+            assert!(op + LAST_LITERALS <= decompressed_len);
+        }
+        assert_eq!(op, decompressed_len);
+    }
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    // Linear congruential generator, in which repeated 4 byte sequences are rare.
+    fn pseudo_random(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        // Empty and tiny inputs are stored as literals only.
+        assert_eq!(roundtrip(&[]), 1);
+        assert_eq!(roundtrip(b"firecracker"), 12);
+
+        // Highly compressible input.
+        let zeroes = vec![0u8; 4096];
+        assert!(roundtrip(&zeroes) < 64);
+
+        // Repeating pattern with long literal runs in between.
+        let mut data = Vec::new();
+        for i in 0..2000u32 {
+            data.extend_from_slice(b"snapshot memory ");
+            data.extend_from_slice(&i.wrapping_mul(2_654_435_761).to_le_bytes());
+        }
+        assert!(roundtrip(&data) < data.len());
+
+        // Incompressible input only grows by the literal length encoding.
+        let mut state = 0x1234_5678u32;
+        let random: Vec<u8> = (0..65536)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        assert!(roundtrip(&random) <= random.len() + random.len() / 255 + 16);
+    }
+
+    #[test]
+    fn test_reference_blocks() {
+        // Blocks produced by the reference implementation (the `lz4` 1.9 command line tool,
+        // at the default and at the highest compression levels) for known inputs.
+        let repeated = b"abcdefgh".repeat(100);
+        let block = from_hex("8f61626364656667680800ffffff03506465666768");
+        let mut output = vec![0u8; repeated.len()];
+        decompress(&block, &mut output).unwrap();
+        assert_eq!(output, repeated);
+
+        // Overlapping match with an offset of 1.
+        let run = vec![b'a'; 1000];
+        let block = from_hex("1f610100ffffffd2506161616161");
+        let mut output = vec![0u8; run.len()];
+        decompress(&block, &mut output).unwrap();
+        assert_eq!(output, run);
+
+        // Literal and match lengths spanning several bytes.
+        let random = pseudo_random(300);
+        let random_twice = random.repeat(2);
+        let block = [
+            &[0xff, 0xff, 0x1e][..],
+            &random,
+            &[0x2c, 0x01, 0xff, 0x15, 0x50],
+            &random[295..],
+        ]
+        .concat();
+        let mut output = vec![0u8; random_twice.len()];
+        decompress(&block, &mut output).unwrap();
+        assert_eq!(output, random_twice);
+
+        // Matches at several distances.
+        let mixed = [b"firecracker ".repeat(3), pseudo_random(20)]
+            .concat()
+            .repeat(40);
+        for hex in [
+            concat!(
+                "cf66697265637261636b6572200c0005ff05c67e816b4bfbe2fb54f6bddf7c1ce18701bf31de",
+                "2c00050818000f3800ffffffffffffffff54508701bf31de"
+            ),
+            concat!(
+                "cf66697265637261636b6572200c0005ff05c67e816b4bfbe2fb54f6bddf7c1ce18701bf31de",
+                "3800ffffffffffffffff78508701bf31de"
+            ),
+        ] {
+            let mut output = vec![0u8; mixed.len()];
+            decompress(&from_hex(hex), &mut output).unwrap();
+            assert_eq!(output, mixed);
+        }
+
+        // The compressor follows the end of block conditions for the same inputs.
+        for data in [&repeated, &run, &random_twice, &mixed] {
+            roundtrip(data);
+        }
+    }
+
+    #[test]
+    fn test_random_roundtrip() {
+        // Inputs made of literal runs, byte runs and copies at random distances, sized
+        // around the end of block limits.
+        let random = pseudo_random(1 << 16);
+        let mut state = 0x9e37_79b9u32;
+        let mut next = |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as usize % bound
+        };
+        for _ in 0..500 {
+            let len = match next(3) {
+                0 => next(2 * MF_LIMIT),
+                1 => next(1024),
+                _ => next(1 << 16),
+            };
+            let mut data = Vec::with_capacity(len);
+            while data.len() < len {
+                let run_len = 1 + next(300);
+                match next(3) {
+                    0 => {
+                        let start = next(random.len() - run_len);
+                        data.extend_from_slice(&random[start..start + run_len]);
+                    }
+                    1 if !data.is_empty() => {
+                        let distance = 1 + next(data.len().min(MAX_DISTANCE));
+                        for _ in 0..run_len {
+                            data.push(data[data.len() - distance]);
+                        }
+                    }
+                    _ => data.resize(data.len() + run_len, next(256) as u8),
+                }
+            }
+            data.truncate(len);
+            roundtrip(&data);
+        }
+    }
+
+    #[test]
+    fn test_decompress_corrupted() {
+        let data = [b"firecracker ".repeat(3), pseudo_random(20)]
+            .concat()
+            .repeat(40);
+        let mut compressed = Vec::new();
+        compress(&data, &mut compressed);
+
+        // Corrupted blocks either fail to decompress or decompress to the wrong data,
+        // but never access memory out of bounds.
+        let mut output = vec![0u8; data.len()];
+        for pos in 0..compressed.len() {
+            for value in [0x00, 0x0f, 0xf0, 0xff, compressed[pos] ^ 0x01] {
+                let mut corrupted = compressed.clone();
+                corrupted[pos] = value;
+                let _ = decompress(&corrupted, &mut output);
+            }
+            let _ = decompress(&compressed[..pos], &mut output);
+        }
+    }
+
+    #[test]
+    fn test_decompress_errors() {
+        let mut compressed = Vec::new();
+        compress(&[0xAAu8; 1024], &mut compressed);
+
+        // Wrong output size.
+        let mut small = vec![0u8; 512];
+        assert_eq!(
+            decompress(&compressed, &mut small),
+            Err(Error::OutputOverflow)
+        );
+        let mut large = vec![0u8; 2048];
+        assert_eq!(
+            decompress(&compressed, &mut large),
+            Err(Error::SizeMismatch(2048, 1024))
+        );
+
+        // Truncated input.
+        let mut output = vec![0u8; 1024];
+        assert_eq!(
+            decompress(&compressed[..compressed.len() - 3], &mut output),
+            Err(Error::Truncated)
+        );
+        assert_eq!(decompress(&[], &mut output), Err(Error::Truncated));
+
+        // Back-reference before the start of the output.
+        assert_eq!(
+            decompress(&[0x10, 0xAA, 0x02, 0x00, 0x00], &mut output),
+            Err(Error::InvalidOffset(2))
+        );
+
+        assert_eq!(
+            format!("{}", Error::SizeMismatch(2, 1)),
+            "LZ4 block decompressed to 1 bytes, expected 2"
+        );
+    }
+}
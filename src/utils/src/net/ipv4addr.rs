@@ -1,8 +1,14 @@
 // Copyright 2020 Amazon.com, Inc. or its affiliates. All Rights Reserved.
 // SPDX-License-Identifier: Apache-2.0
 
+use std::fmt;
 use std::net::Ipv4Addr;
 
+use serde::de::{Deserialize, Deserializer, Error};
+use serde::ser::{Serialize, Serializer};
+use versionize::{VersionMap, Versionize, VersionizeResult};
+use versionize_derive::Versionize;
+
 /// Checks if an IPv4 address is RFC 3927 compliant.
 /// # Examples
 ///
@@ -20,11 +26,85 @@ pub fn is_link_local_valid(ipv4_addr: Ipv4Addr) -> bool {
     }
 }
 
+/// An IPv4 network, made of the addresses sharing their first `prefix_len` bits with `addr`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Versionize)]
+pub struct Ipv4Network {
+    addr: u32,
+    prefix_len: u8,
+}
+
+impl Ipv4Network {
+    /// Creates the network of the addresses sharing their first `prefix_len` bits with `addr`.
+    ///
+    /// Returns `None` when `prefix_len` is greater than 32, or when `addr` has bits set past
+    /// the prefix.
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Option<Self> {
+        let addr = u32::from(addr);
+        if prefix_len > 32 || addr & !Self::mask(prefix_len) != 0 {
+            return None;
+        }
+        Some(Ipv4Network { addr, prefix_len })
+    }
+
+    /// Parses a network in the CIDR notation, such as `169.254.0.0/16`.
+    /// # Examples
+    ///
+    /// ```
+    /// use std::net::Ipv4Addr;
+    ///
+    /// use utils::net::ipv4addr::Ipv4Network;
+    ///
+    /// let network = Ipv4Network::parse_str("169.254.0.0/16").unwrap();
+    /// assert!(network.contains(Ipv4Addr::new(169, 254, 169, 254)));
+    /// ```
+    pub fn parse_str(s: &str) -> Option<Self> {
+        let (addr, prefix_len) = s.split_once('/')?;
+        Self::new(addr.parse().ok()?, prefix_len.parse().ok()?)
+    }
+
+    /// Tells whether `addr` belongs to this network.
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & Self::mask(self.prefix_len) == self.addr
+    }
+
+    fn mask(prefix_len: u8) -> u32 {
+        u32::MAX
+            .checked_shl(32 - u32::from(prefix_len))
+            .unwrap_or(0)
+    }
+}
+
+impl fmt::Display for Ipv4Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", Ipv4Addr::from(self.addr), self.prefix_len)
+    }
+}
+
+impl Serialize for Ipv4Network {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        Serialize::serialize(&self.to_string(), serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ipv4Network {
+    fn deserialize<D>(deserializer: D) -> Result<Ipv4Network, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = <std::string::String as Deserialize>::deserialize(deserializer)?;
+        Ipv4Network::parse_str(&s)
+            .ok_or_else(|| D::Error::custom("The provided IPv4 network is invalid."))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::net::Ipv4Addr;
 
-    use crate::net::ipv4addr::is_link_local_valid;
+    use crate::net::ipv4addr::{is_link_local_valid, Ipv4Network};
 
     #[test]
     fn test_is_link_local_valid() {
@@ -60,4 +140,44 @@ mod tests {
         ipv4_addr = Ipv4Addr::new(169, 254, 170, 2);
         assert!(is_link_local_valid(ipv4_addr));
     }
+
+    #[test]
+    fn test_ipv4_network() {
+        let network = Ipv4Network::parse_str("10.1.0.0/16").unwrap();
+        assert!(network.contains(Ipv4Addr::new(10, 1, 0, 0)));
+        assert!(network.contains(Ipv4Addr::new(10, 1, 255, 255)));
+        assert!(!network.contains(Ipv4Addr::new(10, 2, 0, 0)));
+        assert_eq!(network.to_string(), "10.1.0.0/16");
+
+        // The edge prefix lengths.
+        let network = Ipv4Network::parse_str("0.0.0.0/0").unwrap();
+        assert!(network.contains(Ipv4Addr::BROADCAST));
+        let network = Ipv4Network::parse_str("192.0.2.1/32").unwrap();
+        assert!(network.contains(Ipv4Addr::new(192, 0, 2, 1)));
+        assert!(!network.contains(Ipv4Addr::new(192, 0, 2, 2)));
+
+        for s in [
+            "10.1.0.0",
+            "10.1.0.0/33",
+            "10.1.0.1/16",
+            "10.1.0/16",
+            "10.1.0.0/a",
+        ] {
+            assert_eq!(Ipv4Network::parse_str(s), None);
+        }
+    }
+
+    #[test]
+    fn test_ipv4_network_serialization_and_deserialization() {
+        let network: Ipv4Network = serde_json::from_str("\"169.254.0.0/16\"").unwrap();
+        assert_eq!(
+            network,
+            Ipv4Network::new(Ipv4Addr::new(169, 254, 0, 0), 16).unwrap()
+        );
+        assert_eq!(
+            serde_json::to_string(&network).unwrap(),
+            "\"169.254.0.0/16\""
+        );
+        assert!(serde_json::from_str::<Ipv4Network>("\"169.254.0.1/16\"").is_err());
+    }
 }
@@ -6,8 +6,10 @@
 // found in the THIRD-PARTY file.
 #![warn(clippy::undocumented_unsafe_blocks)]
 
+use std::fs::File;
 use std::io::Error as IoError;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
 
 use vm_memory_upstream::bitmap::AtomicBitmap;
 pub use vm_memory_upstream::bitmap::Bitmap;
@@ -134,6 +136,55 @@ pub fn create_guest_memory(
     GuestMemoryMmap::from_regions(mmap_regions)
 }
 
+/// Helper for creating the guest memory in a single memfd mapped as shared, so that other
+/// processes given the file descriptor, e.g. vhost-user backends, can access it.
+pub fn create_shared_guest_memory(
+    regions: &[(GuestAddress, usize)],
+    track_dirty_pages: bool,
+) -> std::result::Result<GuestMemoryMmap, Error> {
+    let mem_size = regions.iter().map(|region| region.1 as u64).sum();
+    let memfd = Arc::new(
+        create_memfd(mem_size).map_err(|err| Error::MmapRegion(MmapRegionError::Mmap(err)))?,
+    );
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    let flags = libc::MAP_NORESERVE | libc::MAP_SHARED;
+    let mut mmap_regions = Vec::with_capacity(regions.len());
+
+    // The regions are laid out one after the other in the memfd.
+    let mut offset = 0;
+    for region in regions {
+        let file_offset = FileOffset::from_arc(memfd.clone(), offset);
+        let mmap_region =
+            build_guarded_region(Some(file_offset), region.1, prot, flags, track_dirty_pages)
+                .map_err(Error::MmapRegion)?;
+
+        mmap_regions.push(GuestRegionMmap::new(mmap_region, region.0)?);
+        offset += region.1 as u64;
+    }
+
+    GuestMemoryMmap::from_regions(mmap_regions)
+}
+
+fn create_memfd(size: u64) -> std::result::Result<File, IoError> {
+    // SAFETY: Safe because the name is a valid C string, and the return value is checked.
+    let fd = unsafe { libc::memfd_create(b"guest_mem\0".as_ptr().cast(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(IoError::last_os_error());
+    }
+    // SAFETY: Safe because the file descriptor was just created, and is not owned elsewhere.
+    let memfd = unsafe { File::from_raw_fd(fd) };
+    memfd.set_len(size)?;
+    Ok(memfd)
+}
+
+/// Checks whether all the regions of the guest memory are shared mappings of files, which other
+/// processes can map as well.
+pub fn is_shared_guest_memory(mem: &GuestMemoryMmap) -> bool {
+    mem.iter().all(|region| {
+        region.file_offset().is_some() && region.flags() & libc::MAP_SHARED == libc::MAP_SHARED
+    })
+}
+
 pub fn mark_dirty_mem(mem: &GuestMemoryMmap, addr: GuestAddress, len: usize) {
     let _ = mem.try_access(len, addr, |_total, count, caddr, region| {
         if let Some(bitmap) = region.bitmap() {
@@ -396,6 +447,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_create_shared_guest_memory() {
+        let region_size = 0x10000;
+        let regions = vec![
+            (GuestAddress(0x0), region_size),
+            (GuestAddress(0x20000), region_size),
+        ];
+
+        let guest_memory = create_shared_guest_memory(&regions, true).unwrap();
+        assert!(is_shared_guest_memory(&guest_memory));
+        guest_memory.iter().for_each(|region| {
+            assert!(region.bitmap().is_some());
+            validate_guard_region(region);
+        });
+
+        // The regions follow each other in the same file.
+        guest_memory
+            .write_obj(0xdead_beef_u32, GuestAddress(0x20000))
+            .unwrap();
+        let region = guest_memory.iter().nth(1).unwrap();
+        let file_offset = region.file_offset().unwrap();
+        assert_eq!(file_offset.start(), region_size as u64);
+        let mut value = [0u8; 4];
+        std::os::unix::fs::FileExt::read_exact_at(
+            file_offset.file(),
+            &mut value,
+            file_offset.start(),
+        )
+        .unwrap();
+        assert_eq!(u32::from_le_bytes(value), 0xdead_beef);
+
+        let private_memory =
+            test_utils::create_anon_guest_memory(&[(GuestAddress(0), region_size)], false).unwrap();
+        assert!(!is_shared_guest_memory(&private_memory));
+    }
+
     #[test]
     fn test_mark_dirty_mem() {
         let page_size = utils::get_page_size().unwrap();
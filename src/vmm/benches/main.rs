@@ -20,7 +20,7 @@ use vmm::persist::{MicrovmState, VmInfo};
 use vmm::utilities::mock_resources::NOISY_KERNEL_IMAGE;
 use vmm::utilities::test_utils::create_vmm;
 use vmm::version_map::VERSION_MAP;
-use vmm::vmm_config::snapshot::{CreateSnapshotParams, SnapshotType};
+use vmm::vmm_config::snapshot::{CreateSnapshotParams, MemFileFormat, SnapshotType};
 use vmm::{persist, FcExitCode};
 
 #[inline]
@@ -76,6 +76,8 @@ fn create_microvm_state(is_diff: bool) -> MicrovmState {
         snapshot_type,
         snapshot_path: snapshot_file.as_path().to_path_buf(),
         mem_file_path: memory_file.as_path().to_path_buf(),
+        mem_file_format: MemFileFormat::Raw,
+        diff_manifest_path: None,
         version: None,
     };
     let vm_info = VmInfo {
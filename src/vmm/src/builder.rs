@@ -3,6 +3,7 @@
 
 //! Enables pre-boot setup, instantiation and booting of a Firecracker VMM.
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::fmt::{Display, Formatter};
 use std::io::{self, Read, Seek, SeekFrom};
@@ -17,7 +18,9 @@ use devices::legacy::RTCDevice;
 use devices::legacy::{
     EventFdTrigger, ReadableFd, SerialDevice, SerialEventsWrapper, SerialWrapper,
 };
-use devices::virtio::{Balloon, Block, MmioTransport, Net, VirtioDevice, Vsock, VsockUnixBackend};
+use devices::virtio::{
+    Balloon, Block, MmioTransport, Net, VhostUserBlock, VirtioDevice, Vsock, VsockUnixBackend,
+};
 use event_manager::{MutEventSubscriber, SubscriberOps};
 use libc::EFD_NONBLOCK;
 use linux_loader::cmdline::Cmdline as LoaderKernelCmdline;
@@ -28,7 +31,6 @@ use linux_loader::loader::pe::PE as Loader;
 use linux_loader::loader::KernelLoader;
 use logger::{error, warn, METRICS};
 use seccompiler::BpfThreadMap;
-use snapshot::Persist;
 use userfaultfd::Uffd;
 use utils::eventfd::EventFd;
 use utils::terminal::Terminal;
@@ -304,6 +306,8 @@ fn create_vmm_and_vcpus(
         mmio_device_manager,
         #[cfg(target_arch = "x86_64")]
         pio_device_manager,
+        subscriber_updates: Vec::new(),
+        hotplug_subscribers: HashMap::new(),
     };
 
     Ok((vmm, vcpus))
@@ -332,8 +336,12 @@ pub fn build_microvm_for_boot(
         .ok_or(MissingKernelConfig)?;
 
     let track_dirty_pages = vm_resources.track_dirty_pages();
-    let guest_memory =
-        create_guest_memory(vm_resources.vm_config().mem_size_mib, track_dirty_pages)?;
+    // The vhost-user backends map the guest memory themselves.
+    let guest_memory = create_guest_memory(
+        vm_resources.vm_config().mem_size_mib,
+        track_dirty_pages,
+        vm_resources.block.has_vhost_user_devices(),
+    )?;
     let vcpu_config = vm_resources.vcpu_config();
     let entry_addr = load_kernel(boot_config, &guest_memory)?;
     let initrd = load_initrd_from_config(boot_config, &guest_memory)?;
@@ -361,12 +369,27 @@ pub fn build_microvm_for_boot(
         attach_balloon_device(&mut vmm, &mut boot_cmdline, balloon, event_manager)?;
     }
 
+    // The root device is attached first, whatever its backend.
+    let vhost_user_blocks = &vm_resources.block.vhost_user_list;
+    let num_vhost_user_roots = usize::from(vm_resources.block.has_vhost_user_root_device());
+    attach_vhost_user_block_devices(
+        &mut vmm,
+        &mut boot_cmdline,
+        vhost_user_blocks.iter().take(num_vhost_user_roots),
+        event_manager,
+    )?;
     attach_block_devices(
         &mut vmm,
         &mut boot_cmdline,
         vm_resources.block.list.iter(),
         event_manager,
     )?;
+    attach_vhost_user_block_devices(
+        &mut vmm,
+        &mut boot_cmdline,
+        vhost_user_blocks.iter().skip(num_vhost_user_roots),
+        event_manager,
+    )?;
     attach_net_devices(
         &mut vmm,
         &mut boot_cmdline,
@@ -376,6 +399,9 @@ pub fn build_microvm_for_boot(
     if let Some(unix_vsock) = vm_resources.vsock.get() {
         attach_unixsock_vsock_device(&mut vmm, &mut boot_cmdline, unix_vsock, event_manager)?;
     }
+    vmm.mmio_device_manager
+        .reserve_hotplug_slots(vm_resources.vm_config().hotplug_slots, &mut boot_cmdline)
+        .map_err(RegisterMmioDevice)?;
 
     #[cfg(target_arch = "aarch64")]
     attach_legacy_devices_aarch64(event_manager, &mut vmm, &mut boot_cmdline).map_err(Internal)?;
@@ -546,6 +572,7 @@ pub fn build_microvm_from_snapshot(
         smt: Some(microvm_state.vm_info.smt),
         cpu_template: Some(microvm_state.vm_info.cpu_template),
         track_dirty_pages: Some(track_dirty_pages),
+        hotplug_slots: None,
     })?;
 
     // Restore the boot source config paths.
@@ -559,6 +586,7 @@ pub fn build_microvm_from_snapshot(
         for_each_restored_device: VmResources::update_from_restored_device,
         vm_resources,
         instance_id: &instance_info.id,
+        hotplug_subscribers: &mut vmm.hotplug_subscribers,
     };
 
     vmm.mmio_device_manager =
@@ -592,14 +620,20 @@ pub fn build_microvm_from_snapshot(
     Ok(vmm)
 }
 
-/// Creates GuestMemory of `mem_size_mib` MiB in size.
+/// Creates GuestMemory of `mem_size_mib` MiB in size, which other processes can map if
+/// `shared`.
 pub fn create_guest_memory(
     mem_size_mib: usize,
     track_dirty_pages: bool,
+    shared: bool,
 ) -> std::result::Result<GuestMemoryMmap, StartMicrovmError> {
     let mem_size = mem_size_mib << 20;
     let arch_mem_regions = arch::arch_memory_regions(mem_size);
 
+    if shared {
+        return vm_memory::create_shared_guest_memory(&arch_mem_regions, track_dirty_pages)
+            .map_err(StartMicrovmError::GuestMemoryMmap);
+    }
     vm_memory::create_guest_memory(
         &arch_mem_regions
             .iter()
@@ -892,7 +926,7 @@ pub fn configure_system_for_boot(
             &vmm.guest_memory,
             cmdline,
             vcpu_mpidr,
-            vmm.mmio_device_manager.get_device_info(),
+            &vmm.mmio_device_manager.get_fdt_device_info(),
             vmm.vm.get_irqchip(),
             initrd,
         )
@@ -936,6 +970,23 @@ pub(crate) fn attach_boot_timer_device(
     Ok(())
 }
 
+fn insert_root_device_cmdline(
+    cmdline: &mut LoaderKernelCmdline,
+    partuuid: Option<&String>,
+    is_read_only: bool,
+) -> std::result::Result<(), StartMicrovmError> {
+    cmdline.insert_str(if let Some(partuuid) = partuuid {
+        format!("root=PARTUUID={}", partuuid)
+    } else {
+        // If no PARTUUID was specified for the root device, try with the /dev/vda.
+        "root=/dev/vda".to_string()
+    })?;
+
+    let flags = if is_read_only { "ro" } else { "rw" };
+    cmdline.insert_str(flags)?;
+    Ok(())
+}
+
 fn attach_block_devices<'a>(
     vmm: &mut Vmm,
     cmdline: &mut LoaderKernelCmdline,
@@ -946,15 +997,27 @@ fn attach_block_devices<'a>(
         let id = {
             let locked = block.lock().expect("Poisoned lock");
             if locked.is_root_device() {
-                cmdline.insert_str(if let Some(partuuid) = locked.partuuid() {
-                    format!("root=PARTUUID={}", partuuid)
-                } else {
-                    // If no PARTUUID was specified for the root device, try with the /dev/vda.
-                    "root=/dev/vda".to_string()
-                })?;
-
-                let flags = if locked.is_read_only() { "ro" } else { "rw" };
-                cmdline.insert_str(flags)?;
+                insert_root_device_cmdline(cmdline, locked.partuuid(), locked.is_read_only())?;
+            }
+            locked.id().clone()
+        };
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        attach_virtio_device(event_manager, vmm, id, block.clone(), cmdline)?;
+    }
+    Ok(())
+}
+
+fn attach_vhost_user_block_devices<'a>(
+    vmm: &mut Vmm,
+    cmdline: &mut LoaderKernelCmdline,
+    blocks: impl Iterator<Item = &'a Arc<Mutex<VhostUserBlock>>>,
+    event_manager: &mut EventManager,
+) -> std::result::Result<(), StartMicrovmError> {
+    for block in blocks {
+        let id = {
+            let locked = block.lock().expect("Poisoned lock");
+            if locked.is_root_device() {
+                insert_root_device_cmdline(cmdline, locked.partuuid(), locked.is_read_only())?;
             }
             locked.id().clone()
         };
@@ -1030,8 +1093,10 @@ pub mod tests {
     use super::*;
     use crate::vmm_config::balloon::{BalloonBuilder, BalloonDeviceConfig, BALLOON_DEV_ID};
     use crate::vmm_config::boot_source::DEFAULT_KERNEL_CMDLINE;
-    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, CacheType, FileEngineType};
-    use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
+    use crate::vmm_config::drive::{
+        BlockBackendType, BlockBuilder, BlockDeviceConfig, CacheType, FileEngineType, ImageFormat,
+    };
+    use crate::vmm_config::net::{NetBuilder, NetworkBackendType, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::vsock::{VsockBuilder, VsockDeviceConfig};
 
@@ -1114,7 +1179,7 @@ pub mod tests {
     }
 
     pub(crate) fn default_vmm() -> Vmm {
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, false, false).unwrap();
 
         let vcpus_exit_evt = EventFd::new(libc::EFD_NONBLOCK)
             .map_err(Error::EventFd)
@@ -1148,6 +1213,8 @@ pub mod tests {
             mmio_device_manager,
             #[cfg(target_arch = "x86_64")]
             pio_device_manager,
+            subscriber_updates: Vec::new(),
+            hotplug_subscribers: HashMap::new(),
         }
     }
 
@@ -1176,6 +1243,10 @@ pub mod tests {
                 cache_type: custom_block_cfg.cache_type,
                 rate_limiter: None,
                 file_engine_type: FileEngineType::default(),
+                num_queues: 1,
+                image_format: ImageFormat::default(),
+                overlay_path_on_host: None,
+                backend_type: BlockBackendType::default(),
             };
             block_dev_configs.insert(block_device_config).unwrap();
         }
@@ -1329,13 +1400,13 @@ pub mod tests {
 
         // Case 1: create guest memory without dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, false).unwrap();
+            let guest_memory = create_guest_memory(mem_size, false, false).unwrap();
             assert!(!is_dirty_tracking_enabled(&guest_memory));
         }
 
         // Case 2: create guest memory with dirty page tracking
         {
-            let guest_memory = create_guest_memory(mem_size, true).unwrap();
+            let guest_memory = create_guest_memory(mem_size, true, false).unwrap();
             assert!(is_dirty_tracking_enabled(&guest_memory));
         }
     }
@@ -1343,7 +1414,7 @@ pub mod tests {
     #[test]
     fn test_create_vcpus() {
         let vcpu_count = 2;
-        let guest_memory = create_guest_memory(128, false).unwrap();
+        let guest_memory = create_guest_memory(128, false, false).unwrap();
 
         #[allow(unused_mut)]
         let mut vm = setup_kvm_vm(&guest_memory, false).unwrap();
@@ -1367,6 +1438,11 @@ pub mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            mtu: None,
+            anti_spoofing: None,
+            backend_type: NetworkBackendType::default(),
+            user_backend: None,
         };
 
         let mut cmdline = default_kernel_cmdline();
@@ -26,6 +26,7 @@ use devices::BusDevice;
 use kvm_ioctls::{IoEventAddress, VmFd};
 use linux_loader::cmdline as kernel_cmdline;
 use logger::info;
+use serde::Serialize;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_allocator::{AddressAllocator, AllocPolicy, IdAllocator};
@@ -53,6 +54,16 @@ pub enum Error {
     RegisterIoEvent(kvm_ioctls::Error),
     /// Registering an IRQ FD failed.
     RegisterIrqFd(kvm_ioctls::Error),
+    /// Unregistering an IO Event failed.
+    UnregisterIoEvent(kvm_ioctls::Error),
+    /// Unregistering an IRQ FD failed.
+    UnregisterIrqFd(kvm_ioctls::Error),
+    /// All the hot-plug slots are in use.
+    NoFreeHotplugSlot,
+    /// The device was attached at boot time and cannot be unplugged.
+    NotHotplugged,
+    /// The guest driver is still bound to the device.
+    DeviceInUse,
     /// Failed to update the mmio device.
     UpdateFailed,
     /// Allocation logic error.
@@ -72,6 +83,11 @@ impl fmt::Display for Error {
             Error::InvalidInput => write!(f, "invalid configuration"),
             Error::RegisterIoEvent(e) => write!(f, "failed to register IO event: {}", e),
             Error::RegisterIrqFd(e) => write!(f, "failed to register irqfd: {}", e),
+            Error::UnregisterIoEvent(e) => write!(f, "failed to unregister IO event: {}", e),
+            Error::UnregisterIrqFd(e) => write!(f, "failed to unregister irqfd: {}", e),
+            Error::NoFreeHotplugSlot => write!(f, "no free hot-plug slot"),
+            Error::NotHotplugged => write!(f, "the device was not hot-plugged"),
+            Error::DeviceInUse => write!(f, "the device is still in use by the guest driver"),
             Error::DeviceNotFound => write!(f, "the device couldn't be found"),
             Error::UpdateFailed => write!(f, "failed to update the mmio device"),
             Error::AllocatorError(e) => write!(f, "failed to allocate requested resource: {}", e),
@@ -88,7 +104,7 @@ type Result<T> = ::std::result::Result<T, Error>;
 pub const MMIO_LEN: u64 = 0x1000;
 
 /// Stores the address range and irq allocated to this device.
-#[derive(Clone, Debug, PartialEq, Eq, Versionize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct MMIODeviceInfo {
     /// Mmio address at which the device is registered.
@@ -99,12 +115,41 @@ pub struct MMIODeviceInfo {
     pub irqs: Vec<u32>,
 }
 
+/// MMIO range reserved at boot time for a virtio device plugged later on.
+///
+/// The vCPUs keep their own copy of the bus, so the devices plugged after boot are reached
+/// through the slot registered on the bus at boot time. An empty slot reads as zeros, which the
+/// guest virtio-mmio driver rejects as an invalid magic value.
+#[derive(Default)]
+pub struct MmioHotplugSlot {
+    device: Option<Arc<Mutex<dyn BusDevice>>>,
+}
+
+impl BusDevice for MmioHotplugSlot {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        match &self.device {
+            Some(device) => device.lock().expect("Poisoned lock").read(offset, data),
+            None => data.fill(0),
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if let Some(device) = &self.device {
+            device.lock().expect("Poisoned lock").write(offset, data);
+        }
+    }
+}
+
 /// Manages the complexities of registering a MMIO device.
 pub struct MMIODeviceManager {
     pub(crate) bus: devices::Bus,
     pub(crate) irq_allocator: IdAllocator,
     pub(crate) address_allocator: AddressAllocator,
     pub(crate) id_to_dev_info: HashMap<(DeviceType, String), MMIODeviceInfo>,
+    // Slots reserved at boot time for hot-plugging virtio devices.
+    pub(crate) hotplug_slots: Vec<(MMIODeviceInfo, Arc<Mutex<MmioHotplugSlot>>)>,
+    // Virtio devices plugged in a hot-plug slot, which are not on the bus themselves.
+    pub(crate) hotplugged_devices: HashMap<(DeviceType, String), Arc<Mutex<dyn BusDevice>>>,
 }
 
 impl MMIODeviceManager {
@@ -120,6 +165,8 @@ impl MMIODeviceManager {
                 .map_err(Error::AllocatorError)?,
             bus: devices::Bus::new(),
             id_to_dev_info: HashMap::new(),
+            hotplug_slots: Vec::new(),
+            hotplugged_devices: HashMap::new(),
         })
     }
 
@@ -163,31 +210,55 @@ impl MMIODeviceManager {
         mmio_device: MmioTransport,
         device_info: &MMIODeviceInfo,
     ) -> Result<()> {
+        let identifier = Self::register_virtio_events(vm, device_id, &mmio_device, device_info)?;
+        self.register_mmio_device(
+            identifier,
+            device_info.clone(),
+            Arc::new(Mutex::new(mmio_device)),
+        )
+    }
+
+    // Routes the queue notifications and the interrupt of a virtio device through KVM.
+    fn register_virtio_events(
+        vm: &VmFd,
+        device_id: String,
+        mmio_device: &MmioTransport,
+        device_info: &MMIODeviceInfo,
+    ) -> Result<(DeviceType, String)> {
         // Our virtio devices are currently hardcoded to use a single IRQ.
         // Validate that requirement.
         if device_info.irqs.len() != 1 {
             return Err(Error::InvalidInput);
         }
-        let identifier;
-        {
-            let locked_device = mmio_device.locked_device();
-            identifier = (DeviceType::Virtio(locked_device.device_type()), device_id);
-            for (i, queue_evt) in locked_device.queue_events().iter().enumerate() {
-                let io_addr = IoEventAddress::Mmio(
-                    device_info.addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET),
-                );
-                vm.register_ioevent(queue_evt, &io_addr, i as u32)
-                    .map_err(Error::RegisterIoEvent)?;
-            }
-            vm.register_irqfd(locked_device.interrupt_evt(), device_info.irqs[0])
-                .map_err(Error::RegisterIrqFd)?;
+        let locked_device = mmio_device.locked_device();
+        for (i, queue_evt) in locked_device.queue_events().iter().enumerate() {
+            let io_addr = IoEventAddress::Mmio(
+                device_info.addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET),
+            );
+            vm.register_ioevent(queue_evt, &io_addr, i as u32)
+                .map_err(Error::RegisterIoEvent)?;
         }
+        vm.register_irqfd(locked_device.interrupt_evt(), device_info.irqs[0])
+            .map_err(Error::RegisterIrqFd)?;
+        Ok((DeviceType::Virtio(locked_device.device_type()), device_id))
+    }
 
-        self.register_mmio_device(
-            identifier,
-            device_info.clone(),
-            Arc::new(Mutex::new(mmio_device)),
-        )
+    // Undoes `register_virtio_events()`.
+    fn unregister_virtio_events(
+        vm: &VmFd,
+        mmio_device: &MmioTransport,
+        device_info: &MMIODeviceInfo,
+    ) -> Result<()> {
+        let locked_device = mmio_device.locked_device();
+        for (i, queue_evt) in locked_device.queue_events().iter().enumerate() {
+            let io_addr = IoEventAddress::Mmio(
+                device_info.addr + u64::from(devices::virtio::NOTIFY_REG_OFFSET),
+            );
+            vm.unregister_ioevent(queue_evt, &io_addr, i as u32)
+                .map_err(Error::UnregisterIoEvent)?;
+        }
+        vm.unregister_irqfd(locked_device.interrupt_evt(), device_info.irqs[0])
+            .map_err(Error::UnregisterIrqFd)
     }
 
     /// Append a registered virtio-over-MMIO device to the kernel cmdline.
@@ -227,6 +298,117 @@ impl MMIODeviceManager {
         Ok(device_info)
     }
 
+    /// Reserve `count` slots for the virtio devices plugged after boot. The slots are added to the
+    /// boot cmdline, so that the guest knows about them from the start.
+    pub fn reserve_hotplug_slots(
+        &mut self,
+        count: u8,
+        _cmdline: &mut kernel_cmdline::Cmdline,
+    ) -> Result<()> {
+        for _ in 0..count {
+            let device_info = self.allocate_mmio_resources(1)?;
+            #[cfg(target_arch = "x86_64")]
+            Self::add_virtio_device_to_cmdline(_cmdline, &device_info)?;
+            self.add_hotplug_slot(device_info)?;
+        }
+        Ok(())
+    }
+
+    /// Add an empty hot-plug slot in the MMIO range described by `device_info`, which is already
+    /// allocated.
+    pub fn add_hotplug_slot(&mut self, device_info: MMIODeviceInfo) -> Result<()> {
+        let slot = Arc::new(Mutex::new(MmioHotplugSlot::default()));
+        self.bus
+            .insert(slot.clone(), device_info.addr, device_info.len)
+            .map_err(Error::Bus)?;
+        self.hotplug_slots.push((device_info, slot));
+        Ok(())
+    }
+
+    /// Plug an already created virtio-over-MMIO device in a free hot-plug slot.
+    pub fn hotplug_mmio_virtio(
+        &mut self,
+        vm: &VmFd,
+        device_id: String,
+        mmio_device: MmioTransport,
+    ) -> Result<MMIODeviceInfo> {
+        let device_info = self
+            .hotplug_slots
+            .iter()
+            .find(|(_, slot)| slot.lock().expect("Poisoned lock").device.is_none())
+            .map(|(slot_info, _)| slot_info.clone())
+            .ok_or(Error::NoFreeHotplugSlot)?;
+        self.plug_mmio_virtio(vm, device_id, mmio_device, &device_info)?;
+        Ok(device_info)
+    }
+
+    /// Plug an already created virtio-over-MMIO device in the free hot-plug slot described by
+    /// `device_info`, such as the one it was plugged in before a snapshot was taken.
+    pub fn plug_mmio_virtio(
+        &mut self,
+        vm: &VmFd,
+        device_id: String,
+        mmio_device: MmioTransport,
+        device_info: &MMIODeviceInfo,
+    ) -> Result<()> {
+        let slot = self
+            .hotplug_slots
+            .iter()
+            .find(|(slot_info, slot)| {
+                slot_info == device_info && slot.lock().expect("Poisoned lock").device.is_none()
+            })
+            .map(|(_, slot)| slot.clone())
+            .ok_or(Error::NoFreeHotplugSlot)?;
+        let identifier = Self::register_virtio_events(vm, device_id, &mmio_device, device_info)?;
+
+        let device: Arc<Mutex<dyn BusDevice>> = Arc::new(Mutex::new(mmio_device));
+        slot.lock().expect("Poisoned lock").device = Some(device.clone());
+        self.hotplugged_devices.insert(identifier.clone(), device);
+        self.id_to_dev_info.insert(identifier, device_info.clone());
+        Ok(())
+    }
+
+    /// Unplug a hot-plugged virtio device, once the guest driver released it.
+    pub fn unplug_mmio_virtio(
+        &mut self,
+        vm: &VmFd,
+        virtio_type: u32,
+        device_id: &str,
+    ) -> Result<()> {
+        let identifier = (DeviceType::Virtio(virtio_type), device_id.to_string());
+        let device = match self.hotplugged_devices.get(&identifier) {
+            Some(device) => device.clone(),
+            None if self.id_to_dev_info.contains_key(&identifier) => {
+                return Err(Error::NotHotplugged)
+            }
+            None => return Err(Error::DeviceNotFound),
+        };
+        let device_info = self.id_to_dev_info[&identifier].clone();
+        {
+            let locked_device = device.lock().expect("Poisoned lock");
+            let mmio_device = locked_device
+                .as_any()
+                .downcast_ref::<MmioTransport>()
+                .expect("Unexpected BusDevice type");
+            if mmio_device.is_driver_active() {
+                return Err(Error::DeviceInUse);
+            }
+            Self::unregister_virtio_events(vm, mmio_device, &device_info)?;
+        }
+
+        // The vCPUs lock the slot before the device, so the device must be unlocked by now.
+        if let Some((_, slot)) = self
+            .hotplug_slots
+            .iter()
+            .find(|(slot_info, _)| slot_info.addr == device_info.addr)
+        {
+            slot.lock().expect("Poisoned lock").device = None;
+        }
+        self.hotplugged_devices.remove(&identifier);
+        self.id_to_dev_info.remove(&identifier);
+        Ok(())
+    }
+
     #[cfg(target_arch = "aarch64")]
     /// Register an early console at the specified MMIO configuration if given as parameter,
     /// otherwise allocate a new MMIO resources for it.
@@ -303,6 +485,20 @@ impl MMIODeviceManager {
         &self.id_to_dev_info
     }
 
+    #[cfg(target_arch = "aarch64")]
+    /// Gets the information of the registered devices and of the hot-plug slots, which are
+    /// described to the guest as virtio devices.
+    pub fn get_fdt_device_info(&self) -> HashMap<(DeviceType, String), MMIODeviceInfo> {
+        let mut device_info = self.id_to_dev_info.clone();
+        for (index, (slot_info, _)) in self.hotplug_slots.iter().enumerate() {
+            device_info.insert(
+                (DeviceType::Virtio(0), format!("hotplug_slot_{}", index)),
+                slot_info.clone(),
+            );
+        }
+        device_info
+    }
+
     #[cfg(target_arch = "x86_64")]
     /// Gets the number of interrupts used by the devices registered.
     pub fn used_irqs_count(&self) -> usize {
@@ -319,6 +515,12 @@ impl MMIODeviceManager {
         device_type: DeviceType,
         device_id: &str,
     ) -> Option<&Mutex<dyn BusDevice>> {
+        if let Some(device) = self
+            .hotplugged_devices
+            .get(&(device_type, device_id.to_string()))
+        {
+            return Some(device);
+        }
         if let Some(device_info) = self
             .id_to_dev_info
             .get(&(device_type, device_id.to_string()))
@@ -421,7 +623,11 @@ impl MMIODeviceManager {
                     }
                 }
                 TYPE_BLOCK => {
-                    let block = virtio.as_mut_any().downcast_mut::<Block>().unwrap();
+                    // Vhost-user block devices are processed by their backend.
+                    let block = match virtio.as_mut_any().downcast_mut::<Block>() {
+                        Some(block) => block,
+                        None => return Ok(()),
+                    };
                     // If device is activated, kick the block queue(s) to make up for any
                     // pending or in-flight epoll events we may have not captured in snapshot.
                     // No need to kick Ratelimiters because they are restored 'unblocked' so
@@ -680,6 +886,11 @@ mod tests {
                 Error::InvalidInput => format!("{}{:?}", err, err),
                 Error::RegisterIoEvent(_) => format!("{}{:?}", err, err),
                 Error::RegisterIrqFd(_) => format!("{}{:?}", err, err),
+                Error::UnregisterIoEvent(_) => format!("{}{:?}", err, err),
+                Error::UnregisterIrqFd(_) => format!("{}{:?}", err, err),
+                Error::NoFreeHotplugSlot => format!("{}{:?}", err, err),
+                Error::NotHotplugged => format!("{}{:?}", err, err),
+                Error::DeviceInUse => format!("{}{:?}", err, err),
                 Error::UpdateFailed => format!("{}{:?}", err, err),
                 Error::AllocatorError(_) => format!("{}{:?}", err, err),
             };
@@ -695,6 +906,11 @@ mod tests {
         check_fmt_err(Error::AllocatorError(vm_allocator::Error::Overflow));
         check_fmt_err(Error::RegisterIoEvent(errno::Error::new(0)));
         check_fmt_err(Error::RegisterIrqFd(errno::Error::new(0)));
+        check_fmt_err(Error::UnregisterIoEvent(errno::Error::new(0)));
+        check_fmt_err(Error::UnregisterIrqFd(errno::Error::new(0)));
+        check_fmt_err(Error::NoFreeHotplugSlot);
+        check_fmt_err(Error::NotHotplugged);
+        check_fmt_err(Error::DeviceInUse);
         check_fmt_err(Error::UpdateFailed);
     }
 
@@ -768,6 +984,103 @@ mod tests {
         assert_eq!(device_manager.used_irqs_count(), 2);
     }
 
+    #[test]
+    fn test_hotplug_virtio_device() {
+        let start_addr1 = GuestAddress(0x0);
+        let start_addr2 = GuestAddress(0x1000);
+        let guest_mem = vm_memory::test_utils::create_anon_guest_memory(
+            &[(start_addr1, 0x1000), (start_addr2, 0x1000)],
+            false,
+        )
+        .unwrap();
+        let mut vm = builder::setup_kvm_vm(&guest_mem, false).unwrap();
+        #[cfg(target_arch = "x86_64")]
+        assert!(builder::setup_interrupt_controller(&mut vm).is_ok());
+        #[cfg(target_arch = "aarch64")]
+        assert!(builder::setup_interrupt_controller(&mut vm, 1).is_ok());
+
+        let mut device_manager = MMIODeviceManager::new(
+            0xd000_0000,
+            arch::MMIO_MEM_SIZE,
+            (arch::IRQ_BASE, arch::IRQ_MAX),
+        )
+        .unwrap();
+        let mut cmdline = kernel_cmdline::Cmdline::new(4096).unwrap();
+        device_manager
+            .register_virtio_test_device(
+                vm.fd(),
+                guest_mem.clone(),
+                Arc::new(Mutex::new(DummyDevice::new())),
+                &mut cmdline,
+                "boot",
+            )
+            .unwrap();
+        device_manager
+            .reserve_hotplug_slots(1, &mut cmdline)
+            .unwrap();
+        #[cfg(target_arch = "x86_64")]
+        assert_eq!(device_manager.used_irqs_count(), 1);
+        let slot_addr = device_manager.hotplug_slots[0].0.addr;
+
+        // Empty slots read as zeros.
+        let mut magic = [0xff; 4];
+        assert!(device_manager.bus.read(slot_addr, &mut magic));
+        assert_eq!(magic, [0; 4]);
+
+        let dummy = Arc::new(Mutex::new(DummyDevice::new()));
+        let type_id = dummy.lock().unwrap().device_type();
+        let device_info = device_manager
+            .hotplug_mmio_virtio(
+                vm.fd(),
+                "plugged".to_string(),
+                MmioTransport::new(guest_mem.clone(), dummy),
+            )
+            .unwrap();
+        assert_eq!(device_info.addr, slot_addr);
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(type_id), "plugged")
+            .is_some());
+        assert!(device_manager.bus.read(slot_addr, &mut magic));
+        assert_eq!(u32::from_le_bytes(magic), 0x7472_6976);
+
+        assert!(matches!(
+            device_manager.hotplug_mmio_virtio(
+                vm.fd(),
+                "other".to_string(),
+                MmioTransport::new(guest_mem, Arc::new(Mutex::new(DummyDevice::new()))),
+            ),
+            Err(Error::NoFreeHotplugSlot)
+        ));
+        assert!(matches!(
+            device_manager.unplug_mmio_virtio(vm.fd(), type_id, "boot"),
+            Err(Error::NotHotplugged)
+        ));
+        assert!(matches!(
+            device_manager.unplug_mmio_virtio(vm.fd(), type_id, "other"),
+            Err(Error::DeviceNotFound)
+        ));
+
+        // The device cannot be unplugged while the guest driver uses it.
+        assert!(device_manager
+            .bus
+            .write(slot_addr + 0x70, &1u32.to_le_bytes()));
+        assert!(matches!(
+            device_manager.unplug_mmio_virtio(vm.fd(), type_id, "plugged"),
+            Err(Error::DeviceInUse)
+        ));
+        assert!(device_manager
+            .bus
+            .write(slot_addr + 0x70, &0u32.to_le_bytes()));
+        device_manager
+            .unplug_mmio_virtio(vm.fd(), type_id, "plugged")
+            .unwrap();
+        assert!(device_manager
+            .get_device(DeviceType::Virtio(type_id), "plugged")
+            .is_none());
+        assert!(device_manager.bus.read(slot_addr, &mut magic));
+        assert_eq!(magic, [0; 4]);
+    }
+
     #[test]
     fn test_slot_irq_allocation() {
         let mut device_manager = MMIODeviceManager::new(
@@ -3,6 +3,8 @@
 
 //! Provides functionality for saving/restoring the MMIO device manager and its devices.
 
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Read};
 use std::result::Result;
 use std::sync::{Arc, Mutex};
 
@@ -18,13 +20,15 @@ use devices::virtio::persist::{MmioTransportConstructorArgs, MmioTransportState}
 use devices::virtio::vsock::persist::{VsockConstructorArgs, VsockState, VsockUdsConstructorArgs};
 use devices::virtio::vsock::{Vsock, VsockError, VsockUnixBackend, VsockUnixBackendError};
 use devices::virtio::{
-    MmioTransport, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET, TYPE_VSOCK,
+    MmioTransport, VhostUserBlock, VirtioDevice, TYPE_BALLOON, TYPE_BLOCK, TYPE_NET, TYPE_VSOCK,
 };
-use event_manager::{MutEventSubscriber, SubscriberOps};
+use event_manager::{MutEventSubscriber, SubscriberId, SubscriberOps};
 use kvm_ioctls::VmFd;
 use logger::{error, warn};
 use mmds::data_store::MmdsVersion;
+use serde::Serialize;
 use snapshot::Persist;
+use utils::net::mac::{MacAddr, MAC_ADDR_LEN};
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_allocator::AllocPolicy;
@@ -33,6 +37,7 @@ use vm_memory::GuestMemoryMmap;
 use super::mmio::*;
 use crate::resources::VmResources;
 use crate::vmm_config::mmds::MmdsConfigError;
+use crate::vmm_config::snapshot::{CloneIdentity, DeviceOverrides};
 use crate::EventManager;
 
 /// Errors for (de)serialization of the MMIO device manager.
@@ -45,14 +50,67 @@ pub enum Error {
     #[cfg(target_arch = "aarch64")]
     Legacy(crate::Error),
     Net(NetError),
+    #[from(ignore)]
+    VhostUserBlock(String),
     Vsock(VsockError),
     VsockUnixBackend(VsockUnixBackendError),
     MmdsConfig(MmdsConfigError),
 }
 
+/// Errors related to overriding the host resources of the restored devices.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum DeviceOverrideError {
+    /// The overridden drive is not part of the snapshot.
+    #[error("Cannot override the backing file of unknown drive: {0}")]
+    UnknownDrive(String),
+    /// The overridden network interface is not part of the snapshot.
+    #[error("Cannot override the host device of unknown network interface: {0}")]
+    UnknownNetworkInterface(String),
+    /// The overridden network interface is backed by the userspace network stack.
+    #[error("Cannot override the host device of network interface without a tap: {0}")]
+    NetworkInterfaceWithoutTap(String),
+    /// The snapshot does not have a vsock device.
+    #[error("Cannot override the Unix domain socket of a missing vsock device")]
+    MissingVsock,
+}
+
+/// Errors related to assigning a new guest identity to the restored devices.
+#[derive(Debug, thiserror::Error)]
+pub enum CloneIdentityError {
+    /// The network interface is not part of the snapshot.
+    #[error("Cannot assign a guest MAC address to unknown network interface: {0}")]
+    UnknownNetworkInterface(String),
+    /// The snapshot does not have a vsock device.
+    #[error("Cannot assign a guest CID to a missing vsock device")]
+    MissingVsock,
+    /// The vsock guest CID is reserved.
+    #[error("Invalid vsock guest CID: {0}")]
+    InvalidVsockCid(u32),
+    /// The snapshot does not have an MMDS data store.
+    #[error("Cannot publish the guest identity without MMDS")]
+    MissingMmds,
+    /// Failed to generate the random parts of the identity.
+    #[error("Failed to read from the entropy pool: {0}")]
+    Entropy(io::Error),
+}
+
+/// Guest identity assigned to the restored devices, as published to the guest.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct GuestIdentity {
+    /// Random 128-bit identifier, unique to every restore of the snapshot.
+    pub generation_id: String,
+    /// Random 256-bit value, in hexadecimal, for the guest to mix into its entropy pool.
+    pub entropy_seed: String,
+    /// Guest MAC addresses of the network interfaces, by interface ID.
+    pub guest_macs: BTreeMap<String, MacAddr>,
+    /// Guest CID of the vsock device.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vsock_guest_cid: Option<u32>,
+}
+
 /// Holds the state of a balloon device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 pub struct ConnectedBalloonState {
     /// Device identifier.
     pub device_id: String,
@@ -66,7 +124,7 @@ pub struct ConnectedBalloonState {
 
 /// Holds the state of a block device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 pub struct ConnectedBlockState {
     /// Device identifier.
     pub device_id: String,
@@ -80,7 +138,7 @@ pub struct ConnectedBlockState {
 
 /// Holds the state of a net device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 pub struct ConnectedNetState {
     /// Device identifier.
     pub device_id: String,
@@ -94,7 +152,7 @@ pub struct ConnectedNetState {
 
 /// Holds the state of a vsock device connected to the MMIO space.
 // NOTICE: Any changes to this structure require a snapshot version bump.
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 pub struct ConnectedVsockState {
     /// Device identifier.
     pub device_id: String,
@@ -108,7 +166,7 @@ pub struct ConnectedVsockState {
 
 /// Holds the state of a legacy device connected to the MMIO space.
 #[cfg(target_arch = "aarch64")]
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 pub struct ConnectedLegacyState {
     /// Device identifier.
     pub type_: DeviceType,
@@ -118,7 +176,7 @@ pub struct ConnectedLegacyState {
 
 /// Holds the MMDS data store version.
 // NOTICE: Any changes to this structure require a snapshot version bump.
-#[derive(Debug, Clone, PartialEq, Eq, Versionize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Versionize)]
 pub enum MmdsVersionState {
     V1,
     V2,
@@ -144,7 +202,7 @@ impl From<MmdsVersion> for MmdsVersionState {
 
 /// Holds the device states.
 // NOTICE: Any changes to this structure require a snapshot version bump.
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 pub struct DeviceStates {
     #[cfg(target_arch = "aarch64")]
     // State of legacy devices in MMIO space.
@@ -161,6 +219,9 @@ pub struct DeviceStates {
     /// Mmds version.
     #[version(start = 3, ser_fn = "mmds_version_serialize")]
     pub mmds_version: Option<MmdsVersionState>,
+    /// Hot-plug slots, free or holding one of the devices above.
+    #[version(start = 4, ser_fn = "hotplug_slots_serialize")]
+    pub hotplug_slots: Vec<MMIODeviceInfo>,
 }
 
 /// A type used to extract the concrete Arc<Mutex<T>> for each of the device types when restoring
@@ -173,6 +234,149 @@ pub enum SharedDeviceType {
 }
 
 impl DeviceStates {
+    /// Replaces the host resources saved for the devices with the ones in `overrides`,
+    /// before the devices are restored.
+    pub fn apply_overrides(
+        &mut self,
+        overrides: &DeviceOverrides,
+    ) -> std::result::Result<(), DeviceOverrideError> {
+        // Check all the overrides first, so that a typo in one of them does not
+        // leave the state partially updated.
+        if let Some(drive_id) = overrides.drives.keys().find(|drive_id| {
+            !self
+                .block_devices
+                .iter()
+                .any(|block| block.device_id == **drive_id)
+        }) {
+            return Err(DeviceOverrideError::UnknownDrive(drive_id.clone()));
+        }
+        if let Some(iface_id) = overrides.network_interfaces.keys().find(|iface_id| {
+            !self
+                .net_devices
+                .iter()
+                .any(|net| net.device_id == **iface_id)
+        }) {
+            return Err(DeviceOverrideError::UnknownNetworkInterface(
+                iface_id.clone(),
+            ));
+        }
+        if let Some(net) = self.net_devices.iter().find(|net| {
+            net.device_state.has_user_ns()
+                && overrides.network_interfaces.contains_key(&net.device_id)
+        }) {
+            return Err(DeviceOverrideError::NetworkInterfaceWithoutTap(
+                net.device_id.clone(),
+            ));
+        }
+        if overrides.vsock_uds_path.is_some() && self.vsock_device.is_none() {
+            return Err(DeviceOverrideError::MissingVsock);
+        }
+
+        for block in self.block_devices.iter_mut() {
+            if let Some(path) = overrides.drives.get(&block.device_id) {
+                block.device_state.set_disk_path(path.clone());
+            }
+        }
+        for net in self.net_devices.iter_mut() {
+            if let Some(tap_if_name) = overrides.network_interfaces.get(&net.device_id) {
+                net.device_state.set_tap_if_name(tap_if_name.clone());
+            }
+        }
+        if let (Some(uds_path), Some(vsock)) =
+            (&overrides.vsock_uds_path, self.vsock_device.as_mut())
+        {
+            vsock.device_state.backend.set_uds_path(uds_path.clone());
+        }
+        Ok(())
+    }
+
+    /// Assigns a new guest identity to the devices, before they are restored, so that
+    /// it is not shared by the microVMs restored from the same snapshot. The values not
+    /// given in `identity` are generated from `entropy`.
+    pub fn assign_identity<R: Read>(
+        &mut self,
+        identity: &CloneIdentity,
+        entropy: &mut R,
+    ) -> std::result::Result<GuestIdentity, CloneIdentityError> {
+        if let Some(iface_id) = identity.guest_macs.keys().find(|iface_id| {
+            !self
+                .net_devices
+                .iter()
+                .any(|net| net.device_id == **iface_id)
+        }) {
+            return Err(CloneIdentityError::UnknownNetworkInterface(
+                iface_id.clone(),
+            ));
+        }
+        if let Some(cid) = identity.vsock_guest_cid {
+            if self.vsock_device.is_none() {
+                return Err(CloneIdentityError::MissingVsock);
+            }
+            // CIDs 0 to 2 are reserved, and u32::MAX stands for any CID.
+            if cid < 3 || cid == u32::MAX {
+                return Err(CloneIdentityError::InvalidVsockCid(cid));
+            }
+        }
+        let has_mmds = self.mmds_version.is_some()
+            || self
+                .net_devices
+                .iter()
+                .any(|net| net.device_state.mmds_ns.is_some());
+        if identity.mmds_key.is_some() && !has_mmds {
+            return Err(CloneIdentityError::MissingMmds);
+        }
+
+        let mut read_random =
+            |buf: &mut [u8]| entropy.read_exact(buf).map_err(CloneIdentityError::Entropy);
+        let to_hex =
+            |bytes: &[u8]| -> String { bytes.iter().map(|byte| format!("{:02x}", byte)).collect() };
+
+        let mut generation_id = [0u8; 16];
+        read_random(&mut generation_id)?;
+        // The guest entropy pool is part of the snapshot, so every clone would otherwise
+        // generate the same random numbers until it gets reseeded.
+        let mut entropy_seed = [0u8; 32];
+        read_random(&mut entropy_seed)?;
+        let mut guest_identity = GuestIdentity {
+            generation_id: to_hex(&generation_id),
+            entropy_seed: to_hex(&entropy_seed),
+            ..Default::default()
+        };
+
+        for net in self.net_devices.iter_mut() {
+            let guest_mac = match identity.guest_macs.get(&net.device_id) {
+                Some(guest_mac) => *guest_mac,
+                None => {
+                    let mut bytes = [0u8; MAC_ADDR_LEN];
+                    read_random(&mut bytes)?;
+                    // Unicast and locally administered.
+                    bytes[0] = (bytes[0] & 0xfc) | 0x02;
+                    MacAddr::from_bytes_unchecked(&bytes)
+                }
+            };
+            net.device_state.set_guest_mac(guest_mac);
+            guest_identity
+                .guest_macs
+                .insert(net.device_id.clone(), guest_mac);
+        }
+
+        if let Some(vsock) = self.vsock_device.as_mut() {
+            let cid = match identity.vsock_guest_cid {
+                Some(cid) => cid,
+                None => {
+                    let mut bytes = [0u8; 4];
+                    read_random(&mut bytes)?;
+                    // Pick a CID between 3 and u32::MAX - 1.
+                    3 + u32::from_le_bytes(bytes) % (u32::MAX - 3)
+                }
+            };
+            vsock.device_state.frontend.cid = u64::from(cid);
+            guest_identity.vsock_guest_cid = Some(cid);
+        }
+
+        Ok(guest_identity)
+    }
+
     fn balloon_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
         if target_version < 2 && self.balloon_device.is_some() {
             return Err(VersionizeError::Semantic(
@@ -193,6 +397,17 @@ impl DeviceStates {
 
         Ok(())
     }
+
+    fn hotplug_slots_serialize(&mut self, target_version: u16) -> VersionizeResult<()> {
+        if target_version < 4 && !self.hotplug_slots.is_empty() {
+            warn!(
+                "Target version does not support persisting the hot-plug slots. The hot-plugged \
+                 devices will be restored as regular devices, without free slots."
+            );
+        }
+
+        Ok(())
+    }
 }
 
 pub struct MMIODevManagerConstructorArgs<'a> {
@@ -202,14 +417,35 @@ pub struct MMIODevManagerConstructorArgs<'a> {
     pub for_each_restored_device: fn(&mut VmResources, SharedDeviceType),
     pub vm_resources: &'a mut VmResources,
     pub instance_id: &'a str,
+    /// Filled with the event subscribers of the devices restored in a hot-plug slot, which are
+    /// removed when the devices are unplugged.
+    pub hotplug_subscribers: &'a mut HashMap<(u32, String), SubscriberId>,
 }
 
-impl<'a> Persist<'a> for MMIODeviceManager {
-    type State = DeviceStates;
-    type ConstructorArgs = MMIODevManagerConstructorArgs<'a>;
-    type Error = Error;
+impl MMIODeviceManager {
+    /// Checks that the state of all the devices can be saved.
+    ///
+    /// The state of the vhost-user devices is kept by their backends, so they cannot be
+    /// saved or restored.
+    pub fn check_persistable(&self) -> Result<(), Error> {
+        self.for_each_virtio_device(|_, devid, _, device| {
+            if device
+                .lock()
+                .expect("Poisoned lock")
+                .as_any()
+                .is::<VhostUserBlock>()
+            {
+                return Err(Error::VhostUserBlock(devid.clone()));
+            }
+            Ok(())
+        })
+    }
 
-    fn save(&self) -> Self::State {
+    /// Returns the state of the devices.
+    ///
+    /// Saving fails when one of the devices cannot be saved, such as a vhost-user device, which
+    /// is why the device manager does not implement `Persist`.
+    pub fn save(&self) -> Result<DeviceStates, Error> {
         let mut states = DeviceStates {
             balloon_device: None,
             block_devices: Vec::new(),
@@ -218,8 +454,13 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             #[cfg(target_arch = "aarch64")]
             legacy_devices: Vec::new(),
             mmds_version: None,
+            hotplug_slots: self
+                .hotplug_slots
+                .iter()
+                .map(|(slot_info, _)| slot_info.clone())
+                .collect(),
         };
-        let _: Result<(), ()> = self.for_each_device(|devtype, devid, device_info, bus_dev| {
+        self.for_each_device(|devtype, devid, device_info, bus_dev| {
             if *devtype == arch::DeviceType::BootTimer {
                 // No need to save BootTimer state.
                 return Ok(());
@@ -261,7 +502,11 @@ impl<'a> Persist<'a> for MMIODeviceManager {
                     });
                 }
                 TYPE_BLOCK => {
-                    let block = locked_device.as_mut_any().downcast_mut::<Block>().unwrap();
+                    // The state of vhost-user block devices is kept by their backend.
+                    let block = match locked_device.as_mut_any().downcast_mut::<Block>() {
+                        Some(block) => block,
+                        None => return Err(Error::VhostUserBlock(devid.clone())),
+                    };
                     block.prepare_save();
                     states.block_devices.push(ConnectedBlockState {
                         device_id: devid.clone(),
@@ -317,20 +562,21 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             };
 
             Ok(())
-        });
-        states
+        })?;
+        Ok(states)
     }
 
-    fn restore(
-        constructor_args: Self::ConstructorArgs,
-        state: &Self::State,
-    ) -> Result<Self, Self::Error> {
+    /// Constructs the device manager and its devices from their saved state.
+    pub fn restore(
+        constructor_args: MMIODevManagerConstructorArgs<'_>,
+        state: &DeviceStates,
+    ) -> Result<Self, Error> {
         let mut dev_manager = MMIODeviceManager::new(
             arch::MMIO_MEM_START,
             arch::MMIO_MEM_SIZE,
             (arch::IRQ_BASE, arch::IRQ_MAX),
         )
-        .map_err(Self::Error::DeviceManager)?;
+        .map_err(Error::DeviceManager)?;
         let mem = &constructor_args.mem;
         let vm = constructor_args.vm;
 
@@ -374,13 +620,24 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             }
         }
 
+        // The slots are restored first, so that the devices plugged in them are found there.
+        for slot_info in &state.hotplug_slots {
+            dev_manager
+                .address_allocator
+                .allocate(MMIO_LEN, MMIO_LEN, AllocPolicy::ExactMatch(slot_info.addr))
+                .map_err(|e| Error::DeviceManager(super::mmio::Error::AllocatorError(e)))?;
+            dev_manager.add_hotplug_slot(slot_info.clone())?;
+        }
+        let hotplug_subscribers = constructor_args.hotplug_subscribers;
+
         let mut restore_helper = |device: Arc<Mutex<dyn VirtioDevice>>,
                                   as_subscriber: Arc<Mutex<dyn MutEventSubscriber>>,
                                   id: &String,
                                   state: &MmioTransportState,
                                   device_info: &MMIODeviceInfo,
                                   event_manager: &mut EventManager|
-         -> Result<(), Self::Error> {
+         -> Result<(), Error> {
+            let device_type = device.lock().expect("Poisoned lock").device_type();
             let restore_args = MmioTransportConstructorArgs {
                 mem: mem.clone(),
                 device,
@@ -388,6 +645,18 @@ impl<'a> Persist<'a> for MMIODeviceManager {
             let mmio_transport =
                 MmioTransport::restore(restore_args, state).map_err(|()| Error::MmioTransport)?;
 
+            // Hot-plugged devices go back in their slot, so that they can be unplugged.
+            if dev_manager
+                .hotplug_slots
+                .iter()
+                .any(|(slot_info, _)| slot_info == device_info)
+            {
+                dev_manager.plug_mmio_virtio(vm, id.clone(), mmio_transport, device_info)?;
+                let subscriber_id = event_manager.add_subscriber(as_subscriber);
+                hotplug_subscribers.insert((device_type, id.clone()), subscriber_id);
+                return Ok(());
+            }
+
             // We do not currently require exact re-allocation of IDs via
             // `dev_manager.irq_allocator.allocate_id()` and currently cannot do
             // this effectively as `IdAllocator` does not implement an exact
@@ -539,9 +808,11 @@ mod tests {
 
     use super::*;
     use crate::builder::tests::*;
+    use crate::hotplug::hotplug_block_device;
+    use crate::hotplug::tests::block_config;
     use crate::resources::VmmConfig;
     use crate::vmm_config::balloon::BalloonDeviceConfig;
-    use crate::vmm_config::net::NetworkInterfaceConfig;
+    use crate::vmm_config::net::{NetworkBackendType, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::VsockDeviceConfig;
 
     impl PartialEq for ConnectedBalloonState {
@@ -707,6 +978,11 @@ mod tests {
                 guest_mac: None,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                num_queue_pairs: 1,
+                mtu: None,
+                anti_spoofing: None,
+                backend_type: NetworkBackendType::default(),
+                user_backend: None,
             };
             insert_net_device_with_mmds(
                 &mut vmm,
@@ -723,11 +999,14 @@ mod tests {
                 uds_path: tmp_sock_file.as_path().to_str().unwrap().to_string(),
             };
             insert_vsock_device(&mut vmm, &mut cmdline, &mut event_manager, vsock_config);
+            vmm.mmio_device_manager.check_persistable().unwrap();
 
             assert_eq!(
-                vmm.mmio_device_manager
-                    .save()
-                    .serialize(&mut buf.as_mut_slice(), &version_map, 1),
+                vmm.mmio_device_manager.save().unwrap().serialize(
+                    &mut buf.as_mut_slice(),
+                    &version_map,
+                    1
+                ),
                 Err(VersionizeError::Semantic(
                     "Target version does not implement the virtio-balloon device.".to_string()
                 ))
@@ -738,6 +1017,7 @@ mod tests {
                 .set_type_version(DeviceStates::type_id(), 2);
             vmm.mmio_device_manager
                 .save()
+                .unwrap()
                 .serialize(&mut buf.as_mut_slice(), &version_map, 2)
                 .unwrap();
 
@@ -751,6 +1031,7 @@ mod tests {
             // there's at least one network device having a MMDS NS.
             vmm.mmio_device_manager
                 .save()
+                .unwrap()
                 .serialize(&mut buf.as_mut_slice(), &version_map, 2)
                 .unwrap();
             let device_states: DeviceStates =
@@ -759,6 +1040,7 @@ mod tests {
 
             vmm.mmio_device_manager
                 .save()
+                .unwrap()
                 .serialize(&mut buf.as_mut_slice(), &version_map, 3)
                 .unwrap();
 
@@ -779,6 +1061,7 @@ mod tests {
             for_each_restored_device: VmResources::update_from_restored_device,
             vm_resources,
             instance_id: "microvm-id",
+            hotplug_subscribers: &mut HashMap::new(),
         };
         let restored_dev_manager =
             MMIODeviceManager::restore(restore_args, &device_states).unwrap();
@@ -857,4 +1140,283 @@ mod tests {
             serde_json::to_string_pretty(&VmmConfig::from(&*vm_resources)).unwrap()
         );
     }
+
+    #[test]
+    fn test_hotplug_slots_persistence() {
+        let backing_file = TempFile::new().unwrap();
+        let mut vmm = default_vmm();
+        let mut vm_resources = VmResources::default();
+        vmm.mmio_device_manager
+            .reserve_hotplug_slots(2, &mut default_kernel_cmdline())
+            .unwrap();
+        hotplug_block_device(
+            &mut vmm,
+            &mut vm_resources,
+            block_config("scratch", &backing_file),
+        )
+        .unwrap();
+        let slots = vmm.mmio_device_manager.save().unwrap().hotplug_slots;
+        assert_eq!(slots.len(), 2);
+
+        // Versions which do not know the slots restore the devices on the bus.
+        let mut buf = vec![0; 16384];
+        let mut version_map = VersionMap::new();
+        version_map
+            .new_version()
+            .set_type_version(DeviceStates::type_id(), 3)
+            .new_version()
+            .set_type_version(DeviceStates::type_id(), 4);
+        vmm.mmio_device_manager
+            .save()
+            .unwrap()
+            .serialize(&mut buf.as_mut_slice(), &version_map, 2)
+            .unwrap();
+        let device_states =
+            DeviceStates::deserialize(&mut buf.as_slice(), &version_map, 2).unwrap();
+        assert!(device_states.hotplug_slots.is_empty());
+
+        vmm.mmio_device_manager
+            .save()
+            .unwrap()
+            .serialize(&mut buf.as_mut_slice(), &version_map, 3)
+            .unwrap();
+        let device_states =
+            DeviceStates::deserialize(&mut buf.as_slice(), &version_map, 3).unwrap();
+        assert_eq!(device_states.hotplug_slots, slots);
+
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let mut vmm = default_vmm();
+        let mut vm_resources = VmResources::default();
+        let mut hotplug_subscribers = HashMap::new();
+        let restore_args = MMIODevManagerConstructorArgs {
+            mem: vmm.guest_memory().clone(),
+            vm: vmm.vm.fd(),
+            event_manager: &mut event_manager,
+            for_each_restored_device: VmResources::update_from_restored_device,
+            vm_resources: &mut vm_resources,
+            instance_id: "microvm-id",
+            hotplug_subscribers: &mut hotplug_subscribers,
+        };
+        vmm.mmio_device_manager = MMIODeviceManager::restore(restore_args, &device_states).unwrap();
+
+        // The device is restored in its slot, and the other slot is still free.
+        assert_eq!(vmm.mmio_device_manager.hotplug_slots.len(), 2);
+        assert!(vmm
+            .mmio_device_manager
+            .hotplugged_devices
+            .contains_key(&(arch::DeviceType::Virtio(TYPE_BLOCK), "scratch".to_string())));
+        assert!(hotplug_subscribers.contains_key(&(TYPE_BLOCK, "scratch".to_string())));
+        vmm.mmio_device_manager
+            .unplug_mmio_virtio(vmm.vm.fd(), TYPE_BLOCK, "scratch")
+            .unwrap();
+        hotplug_block_device(
+            &mut vmm,
+            &mut vm_resources,
+            block_config("scratch2", &backing_file),
+        )
+        .unwrap();
+        hotplug_block_device(
+            &mut vmm,
+            &mut vm_resources,
+            block_config("scratch3", &backing_file),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_apply_overrides() {
+        let tmp_sock_file = TempFile::new().unwrap();
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let mut vmm = default_vmm();
+        let mut cmdline = default_kernel_cmdline();
+
+        let block_configs = vec![CustomBlockConfig::new(
+            String::from("root"),
+            true,
+            None,
+            true,
+            CacheType::Unsafe,
+        )];
+        let _block_files =
+            insert_block_devices(&mut vmm, &mut cmdline, &mut event_manager, block_configs);
+        let network_interface = NetworkInterfaceConfig {
+            iface_id: String::from("netif"),
+            host_dev_name: String::from("hostname"),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            mtu: None,
+            anti_spoofing: None,
+            backend_type: NetworkBackendType::default(),
+            user_backend: None,
+        };
+        insert_net_device(
+            &mut vmm,
+            &mut cmdline,
+            &mut event_manager,
+            network_interface,
+        );
+        let vsock_config = VsockDeviceConfig {
+            vsock_id: Some(String::from("vsock")),
+            guest_cid: 3,
+            uds_path: tmp_sock_file.as_path().to_str().unwrap().to_string(),
+        };
+        insert_vsock_device(&mut vmm, &mut cmdline, &mut event_manager, vsock_config);
+        let mut device_states = vmm.mmio_device_manager.save().unwrap();
+
+        // Overrides of devices missing from the snapshot are rejected.
+        let overrides = DeviceOverrides {
+            drives: [("data".to_string(), "/new/data".to_string())].into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            device_states.clone().apply_overrides(&overrides),
+            Err(DeviceOverrideError::UnknownDrive("data".to_string()))
+        );
+        let overrides = DeviceOverrides {
+            network_interfaces: [("eth1".to_string(), "tap1".to_string())].into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            device_states.clone().apply_overrides(&overrides),
+            Err(DeviceOverrideError::UnknownNetworkInterface(
+                "eth1".to_string()
+            ))
+        );
+
+        let overrides = DeviceOverrides {
+            drives: [("root".to_string(), "/new/root".to_string())].into(),
+            network_interfaces: [("netif".to_string(), "tap1".to_string())].into(),
+            vsock_uds_path: Some("/new/v.sock".to_string()),
+        };
+        device_states.apply_overrides(&overrides).unwrap();
+        let description = serde_json::to_value(&device_states).unwrap();
+        assert_eq!(
+            description["block_devices"][0]["device_state"]["disk_path"],
+            "/new/root"
+        );
+        assert_eq!(
+            description["net_devices"][0]["device_state"]["tap_if_name"],
+            "tap1"
+        );
+        assert_eq!(
+            description["vsock_device"]["device_state"]["backend"]["Uds"]["path"],
+            "/new/v.sock"
+        );
+
+        device_states.vsock_device = None;
+        assert_eq!(
+            device_states.apply_overrides(&overrides),
+            Err(DeviceOverrideError::MissingVsock)
+        );
+    }
+
+    #[test]
+    fn test_assign_identity() {
+        let tmp_sock_file = TempFile::new().unwrap();
+        let mut event_manager = EventManager::new().expect("Unable to create EventManager");
+        let mut vmm = default_vmm();
+        let mut cmdline = default_kernel_cmdline();
+
+        for iface_id in ["eth0", "eth1"] {
+            let network_interface = NetworkInterfaceConfig {
+                iface_id: String::from(iface_id),
+                host_dev_name: format!("host{}", iface_id),
+                guest_mac: Some(MacAddr::parse_str("12:34:56:78:9a:bc").unwrap()),
+                rx_rate_limiter: None,
+                tx_rate_limiter: None,
+                num_queue_pairs: 1,
+                mtu: None,
+                anti_spoofing: None,
+                backend_type: NetworkBackendType::default(),
+                user_backend: None,
+            };
+            insert_net_device(
+                &mut vmm,
+                &mut cmdline,
+                &mut event_manager,
+                network_interface,
+            );
+        }
+        let vsock_config = VsockDeviceConfig {
+            vsock_id: Some(String::from("vsock")),
+            guest_cid: 3,
+            uds_path: tmp_sock_file.as_path().to_str().unwrap().to_string(),
+        };
+        insert_vsock_device(&mut vmm, &mut cmdline, &mut event_manager, vsock_config);
+        let mut device_states = vmm.mmio_device_manager.save().unwrap();
+
+        let identity = CloneIdentity {
+            guest_macs: [("eth2".to_string(), MacAddr::default())].into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            device_states.clone().assign_identity(&identity, &mut io::repeat(0)),
+            Err(CloneIdentityError::UnknownNetworkInterface(iface_id)) if iface_id == "eth2"
+        ));
+        for cid in [2, u32::MAX] {
+            let identity = CloneIdentity {
+                vsock_guest_cid: Some(cid),
+                ..Default::default()
+            };
+            assert!(matches!(
+                device_states
+                    .clone()
+                    .assign_identity(&identity, &mut io::repeat(0)),
+                Err(CloneIdentityError::InvalidVsockCid(_))
+            ));
+        }
+        let identity = CloneIdentity {
+            mmds_key: Some("clone".to_string()),
+            ..Default::default()
+        };
+        assert!(matches!(
+            device_states
+                .clone()
+                .assign_identity(&identity, &mut io::repeat(0)),
+            Err(CloneIdentityError::MissingMmds)
+        ));
+        assert!(matches!(
+            device_states
+                .clone()
+                .assign_identity(&CloneIdentity::default(), &mut io::empty()),
+            Err(CloneIdentityError::Entropy(_))
+        ));
+
+        // The MAC address of `eth0` is given, the other values are random.
+        let guest_mac = MacAddr::parse_str("06:00:ac:10:00:02").unwrap();
+        let identity = CloneIdentity {
+            guest_macs: [("eth0".to_string(), guest_mac)].into(),
+            ..Default::default()
+        };
+        let guest_identity = device_states
+            .assign_identity(&identity, &mut io::repeat(0xff))
+            .unwrap();
+        assert_eq!(
+            guest_identity,
+            GuestIdentity {
+                generation_id: "ff".repeat(16),
+                entropy_seed: "ff".repeat(32),
+                guest_macs: [
+                    ("eth0".to_string(), guest_mac),
+                    (
+                        "eth1".to_string(),
+                        MacAddr::parse_str("fe:ff:ff:ff:ff:ff").unwrap()
+                    ),
+                ]
+                .into(),
+                vsock_guest_cid: Some(6),
+            }
+        );
+        let description = serde_json::to_value(&device_states).unwrap();
+        assert_eq!(
+            description["net_devices"][1]["device_state"]["config_space"]["guest_mac_v2"],
+            "fe:ff:ff:ff:ff:ff"
+        );
+        assert_eq!(
+            description["vsock_device"]["device_state"]["frontend"]["cid"],
+            6
+        );
+    }
 }
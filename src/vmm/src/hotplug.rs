@@ -0,0 +1,291 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hot-plug and hot-unplug of virtio devices on a running microVM.
+//!
+//! The devices are plugged in MMIO slots reserved at boot time, which the guest knows about
+//! from the kernel command line (or from the FDT on aarch64). An empty slot doesn't look like
+//! a virtio device to the guest, so the guest driver gives up on it at boot time, and has to be
+//! bound to the slot again once a device was plugged in. Likewise, a device is only unplugged
+//! after the guest driver released it.
+
+use std::sync::{Arc, Mutex};
+
+use devices::virtio::vsock::VSOCK_DEV_ID;
+use devices::virtio::{TYPE_BLOCK, TYPE_NET, TYPE_VSOCK};
+
+use crate::resources::VmResources;
+use crate::vmm_config::drive::{BlockBackendType, BlockBuilder, BlockDeviceConfig, DriveError};
+use crate::vmm_config::hotplug::UnplugDeviceConfig;
+use crate::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceError};
+use crate::vmm_config::vsock::{VsockConfigError, VsockDeviceConfig};
+use crate::{Error as VmmError, Vmm};
+
+/// Errors associated with plugging devices into, and unplugging them from, a running microVM.
+#[derive(Debug, thiserror::Error)]
+pub enum HotplugError {
+    /// Root block devices cannot be hot-plugged.
+    #[error("Cannot hot-plug a root block device.")]
+    RootBlockDevice,
+    /// A device with the same id is already attached.
+    #[error("A device with id {0} is already attached.")]
+    DeviceAlreadyAttached(String),
+    /// The block device cannot be created.
+    #[error("{0}")]
+    Drive(DriveError),
+    /// Vhost-user backends cannot map the guest memory.
+    #[error("Cannot hot-plug a drive with a vhost-user backend, the guest memory is not shared.")]
+    GuestMemoryNotShared,
+    /// The network device cannot be created.
+    #[error("{0}")]
+    NetworkInterface(NetworkInterfaceError),
+    /// The vsock device cannot be created or removed.
+    #[error("{0}")]
+    Vsock(VsockConfigError),
+    /// The device cannot be plugged in the microVM.
+    #[error("Cannot plug the device: {0}")]
+    Attach(VmmError),
+    /// The device cannot be unplugged from the microVM.
+    #[error("Cannot unplug the device: {0}")]
+    Detach(VmmError),
+}
+
+/// Creates a block device and plugs it in the running microVM.
+pub fn hotplug_block_device(
+    vmm: &mut Vmm,
+    vm_resources: &mut VmResources,
+    config: BlockDeviceConfig,
+) -> Result<(), HotplugError> {
+    // The root device is chosen on the kernel command line.
+    if config.is_root_device {
+        return Err(HotplugError::RootBlockDevice);
+    }
+    if vm_resources.block.has_drive_id(&config.drive_id) {
+        return Err(HotplugError::DeviceAlreadyAttached(config.drive_id));
+    }
+
+    let drive_id = config.drive_id.clone();
+    if config.backend_type == BlockBackendType::VhostUser {
+        // The guest memory can only be shared when the microVM boots.
+        if !vm_memory::is_shared_guest_memory(vmm.guest_memory()) {
+            return Err(HotplugError::GuestMemoryNotShared);
+        }
+        let block = Arc::new(Mutex::new(
+            BlockBuilder::create_vhost_user_block(config).map_err(HotplugError::Drive)?,
+        ));
+        vmm.hotplug_virtio_device(drive_id, block.clone())
+            .map_err(HotplugError::Attach)?;
+        vm_resources.block.add_vhost_user_device(block);
+        return Ok(());
+    }
+
+    let block = Arc::new(Mutex::new(
+        BlockBuilder::create_block(config).map_err(HotplugError::Drive)?,
+    ));
+    vmm.hotplug_virtio_device(drive_id, block.clone())
+        .map_err(HotplugError::Attach)?;
+    vm_resources.block.add_device(block);
+    Ok(())
+}
+
+/// Creates a network device and plugs it in the running microVM.
+pub fn hotplug_net_device(
+    vmm: &mut Vmm,
+    vm_resources: &mut VmResources,
+    config: NetworkInterfaceConfig,
+) -> Result<(), HotplugError> {
+    if vm_resources
+        .net_builder
+        .iter()
+        .any(|net| net.lock().expect("Poisoned lock").id() == &config.iface_id)
+    {
+        return Err(HotplugError::DeviceAlreadyAttached(config.iface_id));
+    }
+
+    let iface_id = config.iface_id.clone();
+    let net = vm_resources
+        .net_builder
+        .build(config)
+        .map_err(HotplugError::NetworkInterface)?;
+    vmm.hotplug_virtio_device(iface_id.clone(), net)
+        .map_err(|err| {
+            vm_resources.net_builder.remove(&iface_id);
+            HotplugError::Attach(err)
+        })
+}
+
+/// Creates the vsock device and plugs it in the running microVM.
+pub fn hotplug_vsock_device(
+    vmm: &mut Vmm,
+    vm_resources: &mut VmResources,
+    config: VsockDeviceConfig,
+) -> Result<(), HotplugError> {
+    if vm_resources.vsock.get().is_some() {
+        return Err(HotplugError::DeviceAlreadyAttached(
+            VSOCK_DEV_ID.to_string(),
+        ));
+    }
+
+    vm_resources
+        .vsock
+        .insert(config)
+        .map_err(HotplugError::Vsock)?;
+    // Safe to unwrap because the device was just inserted.
+    let vsock = vm_resources.vsock.get().unwrap().clone();
+    vmm.hotplug_virtio_device(VSOCK_DEV_ID.to_string(), vsock)
+        .map_err(|err| {
+            // Also removes the socket created along with the device.
+            let _ = vm_resources.vsock.remove();
+            HotplugError::Attach(err)
+        })
+}
+
+/// Unplugs a hot-plugged device from the running microVM.
+pub fn unplug_device(
+    vmm: &mut Vmm,
+    vm_resources: &mut VmResources,
+    config: UnplugDeviceConfig,
+) -> Result<(), HotplugError> {
+    match config {
+        UnplugDeviceConfig::Drive { drive_id } => {
+            vmm.hot_unplug_virtio_device(TYPE_BLOCK, &drive_id)
+                .map_err(HotplugError::Detach)?;
+            vm_resources.block.remove(&drive_id);
+        }
+        UnplugDeviceConfig::NetworkInterface { iface_id } => {
+            vmm.hot_unplug_virtio_device(TYPE_NET, &iface_id)
+                .map_err(HotplugError::Detach)?;
+            vm_resources.net_builder.remove(&iface_id);
+        }
+        UnplugDeviceConfig::Vsock => {
+            vmm.hot_unplug_virtio_device(TYPE_VSOCK, VSOCK_DEV_ID)
+                .map_err(HotplugError::Detach)?;
+            vm_resources.vsock.remove().map_err(HotplugError::Vsock)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use utils::tempfile::TempFile;
+
+    use super::*;
+    use crate::builder::tests::{default_kernel_cmdline, default_vmm};
+    use crate::device_manager::mmio::Error as MmioError;
+    use crate::vmm_config::drive::{BlockBackendType, CacheType, FileEngineType, ImageFormat};
+    use crate::vmm_config::vsock::tests::default_config;
+
+    pub(crate) fn block_config(drive_id: &str, backing_file: &TempFile) -> BlockDeviceConfig {
+        BlockDeviceConfig {
+            drive_id: drive_id.to_string(),
+            path_on_host: backing_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            cache_type: CacheType::Unsafe,
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
+        }
+    }
+
+    #[test]
+    fn test_hotplug_block_device() {
+        let mut vmm = default_vmm();
+        let mut vm_resources = VmResources::default();
+        let backing_file = TempFile::new().unwrap();
+
+        // No hot-plug slot was reserved.
+        assert!(matches!(
+            hotplug_block_device(
+                &mut vmm,
+                &mut vm_resources,
+                block_config("scratch", &backing_file)
+            ),
+            Err(HotplugError::Attach(VmmError::DeviceManager(
+                MmioError::NoFreeHotplugSlot
+            )))
+        ));
+        assert!(vm_resources.block.list.is_empty());
+
+        vmm.mmio_device_manager
+            .reserve_hotplug_slots(1, &mut default_kernel_cmdline())
+            .unwrap();
+        let mut root_config = block_config("root", &backing_file);
+        root_config.is_root_device = true;
+        assert!(matches!(
+            hotplug_block_device(&mut vmm, &mut vm_resources, root_config),
+            Err(HotplugError::RootBlockDevice)
+        ));
+
+        hotplug_block_device(
+            &mut vmm,
+            &mut vm_resources,
+            block_config("scratch", &backing_file),
+        )
+        .unwrap();
+        assert_eq!(vm_resources.block.list.len(), 1);
+        assert!(matches!(
+            hotplug_block_device(
+                &mut vmm,
+                &mut vm_resources,
+                block_config("scratch", &backing_file)
+            ),
+            Err(HotplugError::DeviceAlreadyAttached(_))
+        ));
+
+        let unplug_config = UnplugDeviceConfig::Drive {
+            drive_id: "scratch".to_string(),
+        };
+        unplug_device(&mut vmm, &mut vm_resources, unplug_config.clone()).unwrap();
+        assert!(vm_resources.block.list.is_empty());
+        assert!(matches!(
+            unplug_device(&mut vmm, &mut vm_resources, unplug_config),
+            Err(HotplugError::Detach(VmmError::DeviceManager(
+                MmioError::DeviceNotFound
+            )))
+        ));
+
+        // The slot can be reused.
+        hotplug_block_device(
+            &mut vmm,
+            &mut vm_resources,
+            block_config("scratch2", &backing_file),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_hotplug_vsock_device() {
+        let mut vmm = default_vmm();
+        let mut vm_resources = VmResources::default();
+        let mut tmp_sock_file = TempFile::new().unwrap();
+        tmp_sock_file.remove().unwrap();
+
+        // The socket is removed when the device cannot be plugged.
+        assert!(matches!(
+            hotplug_vsock_device(&mut vmm, &mut vm_resources, default_config(&tmp_sock_file)),
+            Err(HotplugError::Attach(_))
+        ));
+        assert!(vm_resources.vsock.get().is_none());
+        assert!(!tmp_sock_file.as_path().exists());
+
+        vmm.mmio_device_manager
+            .reserve_hotplug_slots(2, &mut default_kernel_cmdline())
+            .unwrap();
+        hotplug_vsock_device(&mut vmm, &mut vm_resources, default_config(&tmp_sock_file)).unwrap();
+        assert!(vm_resources.vsock.get().is_some());
+        assert!(matches!(
+            hotplug_vsock_device(&mut vmm, &mut vm_resources, default_config(&tmp_sock_file)),
+            Err(HotplugError::DeviceAlreadyAttached(_))
+        ));
+
+        unplug_device(&mut vmm, &mut vm_resources, UnplugDeviceConfig::Vsock).unwrap();
+        assert!(vm_resources.vsock.get().is_none());
+        assert!(!tmp_sock_file.as_path().exists());
+    }
+}
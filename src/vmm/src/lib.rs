@@ -14,7 +14,11 @@
 /// Handles setup and initialization a `Vmm` object.
 pub mod builder;
 pub(crate) mod device_manager;
+/// Hot-plug and hot-unplug of virtio devices on a running microVM.
+pub mod hotplug;
 pub mod memory_snapshot;
+/// Live migration utilities.
+pub mod migration;
 /// Save/restore utilities.
 pub mod persist;
 /// Resource store for configured microVM resources.
@@ -43,16 +47,19 @@ use std::{fmt, io};
 use arch::DeviceType;
 use devices::legacy::{IER_RDA_BIT, IER_RDA_OFFSET};
 use devices::virtio::balloon::Error as BalloonError;
+use devices::virtio::net::PacketCapture;
 use devices::virtio::{
-    Balloon, BalloonConfig, BalloonStats, Block, MmioTransport, Net, BALLOON_DEV_ID, TYPE_BALLOON,
-    TYPE_BLOCK, TYPE_NET,
+    Balloon, BalloonConfig, BalloonStats, Block, MmioTransport, Net, VirtioDevice, BALLOON_DEV_ID,
+    TYPE_BALLOON, TYPE_BLOCK, TYPE_NET,
 };
 use devices::BusDevice;
-use event_manager::{EventManager as BaseEventManager, EventOps, Events, MutEventSubscriber};
+use event_manager::{
+    EventManager as BaseEventManager, EventOps, Events, MutEventSubscriber, SubscriberId,
+    SubscriberOps,
+};
 use logger::{error, info, warn, LoggerError, MetricsError, METRICS};
 use rate_limiter::BucketUpdate;
 use seccompiler::BpfProgram;
-use snapshot::Persist;
 use userfaultfd::Uffd;
 use utils::epoll::EventSet;
 use utils::eventfd::EventFd;
@@ -278,6 +285,12 @@ pub enum RestoreVcpusError {
     NotAllowed(String),
 }
 
+// Change of the event subscribers, following a device hot-plug or hot-unplug.
+enum SubscriberUpdate {
+    Add((u32, String), Arc<Mutex<dyn MutEventSubscriber>>),
+    Remove((u32, String)),
+}
+
 /// Contains the state and associated methods required for the Firecracker VMM.
 pub struct Vmm {
     events_observer: Option<Box<dyn VmmEventsObserver>>,
@@ -299,6 +312,11 @@ pub struct Vmm {
     mmio_device_manager: MMIODeviceManager,
     #[cfg(target_arch = "x86_64")]
     pio_device_manager: PortIODeviceManager,
+
+    // Event subscribers of the devices plugged or unplugged since the event manager last ran.
+    subscriber_updates: Vec<SubscriberUpdate>,
+    // Event subscribers of the hot-plugged devices, by device type and id.
+    hotplug_subscribers: HashMap<(u32, String), SubscriberId>,
 }
 
 impl Vmm {
@@ -465,12 +483,20 @@ impl Vmm {
             .map_err(Error::I8042Error)
     }
 
+    /// Checks that the state of all the attached devices can be saved.
+    pub fn check_devices_persistable(&self) -> std::result::Result<(), MicrovmStateError> {
+        self.mmio_device_manager
+            .check_persistable()
+            .map_err(MicrovmStateError::SaveDevices)
+    }
+
     /// Saves the state of a paused Microvm.
     pub fn save_state(
         &mut self,
         vm_info: &VmInfo,
     ) -> std::result::Result<MicrovmState, MicrovmStateError> {
         use self::MicrovmStateError::SaveVmState;
+        self.check_devices_persistable()?;
         let vcpu_states = self.save_vcpu_states()?;
         let vm_state = {
             #[cfg(target_arch = "x86_64")]
@@ -484,7 +510,10 @@ impl Vmm {
                 self.vm.save_state(&mpidrs).map_err(SaveVmState)?
             }
         };
-        let device_states = self.mmio_device_manager.save();
+        let device_states = self
+            .mmio_device_manager
+            .save()
+            .map_err(MicrovmStateError::SaveDevices)?;
 
         let memory_state = self.guest_memory().describe();
 
@@ -599,6 +628,68 @@ impl Vmm {
             .map_err(Error::DeviceManager)
     }
 
+    /// Re-reads the size of the host file backing the emulated block device with id `drive_id`.
+    /// We update the capacity in its virtio configuration and notify the guest.
+    pub fn update_block_device_size(&mut self, drive_id: &str) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_BLOCK, drive_id, |block: &mut Block| {
+                block.update_disk_size().map_err(|err| format!("{:?}", err))
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Plugs a virtio device in a free hot-plug slot of the running microVM. The device gets
+    /// its events once the event manager runs again.
+    pub fn hotplug_virtio_device<T: 'static + VirtioDevice + MutEventSubscriber>(
+        &mut self,
+        id: String,
+        device: Arc<Mutex<T>>,
+    ) -> Result<()> {
+        let device_type = device.lock().expect("Poisoned lock").device_type();
+        // The device mutex mustn't be locked here otherwise it will deadlock.
+        let mmio_device = MmioTransport::new(self.guest_memory.clone(), device.clone());
+        self.mmio_device_manager
+            .hotplug_mmio_virtio(self.vm.fd(), id.clone(), mmio_device)
+            .map_err(Error::DeviceManager)?;
+        self.subscriber_updates
+            .push(SubscriberUpdate::Add((device_type, id), device));
+        Ok(())
+    }
+
+    /// Unplugs the hot-plugged virtio device of type `device_type` with id `id`, once the guest
+    /// driver released it.
+    pub fn hot_unplug_virtio_device(&mut self, device_type: u32, id: &str) -> Result<()> {
+        self.mmio_device_manager
+            .unplug_mmio_virtio(self.vm.fd(), device_type, id)
+            .map_err(Error::DeviceManager)?;
+        self.subscriber_updates
+            .push(SubscriberUpdate::Remove((device_type, id.to_string())));
+        Ok(())
+    }
+
+    /// Registers the hot-plugged devices with the event manager and unregisters the
+    /// hot-unplugged ones.
+    pub fn update_event_subscribers(&mut self, event_manager: &mut EventManager) {
+        for update in self.subscriber_updates.drain(..) {
+            match update {
+                SubscriberUpdate::Add(key, subscriber) => {
+                    let subscriber_id = event_manager.add_subscriber(subscriber);
+                    self.hotplug_subscribers.insert(key, subscriber_id);
+                }
+                SubscriberUpdate::Remove(key) => {
+                    if let Some(subscriber_id) = self.hotplug_subscribers.remove(&key) {
+                        if let Err(err) = event_manager.remove_subscriber(subscriber_id) {
+                            error!(
+                                "Failed to remove the event subscriber of {}: {:?}",
+                                key.1, err
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
     /// Updates the rate limiter parameters for block device with `drive_id` id.
     pub fn update_block_rate_limiter(
         &mut self,
@@ -631,6 +722,37 @@ impl Vmm {
             .map_err(Error::DeviceManager)
     }
 
+    /// Tells whether a net device with `net_id` id is attached to the microVM.
+    pub fn has_net_device(&self, net_id: &str) -> bool {
+        self.get_bus_device(DeviceType::Virtio(TYPE_NET), net_id)
+            .is_some()
+    }
+
+    /// Starts writing the frames going through the net device with `net_id` id to `capture`, or
+    /// stops capturing them.
+    pub fn update_net_capture(
+        &mut self,
+        net_id: &str,
+        capture: Option<PacketCapture>,
+    ) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.set_capture(capture);
+                Ok(())
+            })
+            .map_err(Error::DeviceManager)
+    }
+
+    /// Sets the link state reported to the guest by the net device with `net_id` id.
+    pub fn update_net_link_state(&mut self, net_id: &str, link_up: bool) -> Result<()> {
+        self.mmio_device_manager
+            .with_virtio_device_with_id(TYPE_NET, net_id, |net: &mut Net| {
+                net.set_link_up(link_up);
+                Ok(())
+            })
+            .map_err(Error::DeviceManager)
+    }
+
     /// Returns a reference to the balloon device if present.
     pub fn balloon_config(&self) -> std::result::Result<BalloonConfig, BalloonError> {
         if let Some(busdev) = self.get_bus_device(DeviceType::Virtio(TYPE_BALLOON), BALLOON_DEV_ID)
@@ -4,20 +4,54 @@
 //! Defines functionality for creating guest memory snapshots.
 
 use std::fs::File;
-use std::io::SeekFrom;
+use std::io::{ErrorKind, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
 
-use utils::{errno, get_page_size};
+use serde::Serialize;
+use snapshot::diff_chain::{ChainError, DiffChain};
+use snapshot::diff_manifest::{DiffManifest, RegionDirtyPages};
+use utils::{errno, get_page_size, lz4};
+use versionize::crc::CRC64Writer;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{
     Bitmap, Bytes, FileOffset, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap,
-    GuestMemoryRegion, MemoryRegionAddress,
+    GuestMemoryRegion, GuestRegionMmap, MemoryRegionAddress,
 };
 
 use crate::DirtyBitmap;
 
+/// Magic value found at the start of a compressed guest memory file.
+pub const COMPRESSED_MEM_FILE_MAGIC: [u8; 8] = *b"FCMEMLZ4";
+/// Version of the compressed guest memory file format.
+pub const COMPRESSED_MEM_FILE_VERSION: u32 = 1;
+/// Amount of guest memory covered by one chunk of a compressed guest memory file.
+pub const COMPRESSED_MEM_FILE_CHUNK_SIZE: usize = 64 << 10;
+
+// Largest chunk size accepted when loading a compressed guest memory file.
+const MAX_COMPRESSED_CHUNK_SIZE: usize = 16 << 20;
+// Magic, version, chunk size, memory size and the CRC64 of the previous fields.
+const COMPRESSED_HEADER_SIZE: usize = 32;
+// Chunk kind, stored length and the CRC64 of the uncompressed chunk.
+const CHUNK_HEADER_SIZE: usize = 16;
+
+// The chunk only contains zeroes and has no payload.
+const CHUNK_ZERO: u32 = 0;
+// The chunk is stored uncompressed.
+const CHUNK_RAW: u32 = 1;
+// The chunk is stored as an LZ4 block.
+const CHUNK_LZ4: u32 = 2;
+
+// Largest number of diff memory file ranges mapped over guest memory on restore. Each mapping
+// can split the mapping of the base file in three, so this stays well below the default
+// `vm.max_map_count` of 65530. The smallest ranges beyond this number are copied instead.
+const MAX_LAYER_MAPPINGS: usize = 16 << 10;
+// Size of the chunks in which the ranges of diff memory files are copied to guest memory.
+const LAYER_COPY_CHUNK_SIZE: usize = 1 << 20;
+
 /// State of a guest memory region saved to file/buffer.
-#[derive(Debug, PartialEq, Eq, Versionize)]
+#[derive(Debug, PartialEq, Eq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct GuestMemoryRegionState {
     // This should have been named `base_guest_addr` since it's _guest_ addr, but for
@@ -31,7 +65,7 @@ pub struct GuestMemoryRegionState {
 }
 
 /// Describes guest memory regions and their snapshot file mappings.
-#[derive(Debug, Default, PartialEq, Eq, Versionize)]
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct GuestMemoryState {
     /// List of regions.
@@ -45,6 +79,13 @@ where
 {
     /// Describes GuestMemoryMmap through a GuestMemoryState struct.
     fn describe(&self) -> GuestMemoryState;
+    /// Describes the pages of GuestMemoryMmap that `dump_dirty` writes for `dirty_bitmap`.
+    fn describe_dirty(
+        &self,
+        dirty_bitmap: &DirtyBitmap,
+    ) -> std::result::Result<DiffManifest, Error>;
+    /// Counts the pages of GuestMemoryMmap that `dump_dirty` writes for `dirty_bitmap`.
+    fn count_dirty(&self, dirty_bitmap: &DirtyBitmap) -> std::result::Result<usize, Error>;
     /// Dumps all contents of GuestMemoryMmap to a writer.
     fn dump<T: std::io::Write>(&self, writer: &mut T) -> std::result::Result<(), Error>;
     /// Dumps all pages of GuestMemoryMmap present in `dirty_bitmap` to a writer.
@@ -53,6 +94,11 @@ where
         writer: &mut T,
         dirty_bitmap: &DirtyBitmap,
     ) -> std::result::Result<(), Error>;
+    /// Dumps all contents of GuestMemoryMmap to a writer, in the compressed
+    /// memory file format.
+    fn dump_compressed<T: std::io::Write>(&self, writer: &mut T) -> std::result::Result<(), Error>;
+    /// Marks all pages as clean in the Firecracker dirty page bitmap.
+    fn reset_dirty(&self);
     /// Creates a GuestMemoryMmap given a `file` containing the data
     /// and a `state` containing mapping information.
     fn restore(
@@ -60,6 +106,21 @@ where
         state: &GuestMemoryState,
         track_dirty_pages: bool,
     ) -> std::result::Result<Self, Error>;
+    /// Creates a GuestMemoryMmap backed by anonymous memory and populates it from
+    /// a `reader` holding a compressed memory file.
+    fn restore_compressed<T: std::io::Read>(
+        reader: &mut T,
+        state: &GuestMemoryState,
+        track_dirty_pages: bool,
+    ) -> std::result::Result<Self, Error>;
+    /// Creates a GuestMemoryMmap backed by a `base` file and overlays the pages
+    /// provided by a validated `chain` of diff memory files on top of it.
+    fn restore_layered(
+        base: &File,
+        chain: &mut DiffChain,
+        state: &GuestMemoryState,
+        track_dirty_pages: bool,
+    ) -> std::result::Result<Self, Error>;
 }
 
 /// Errors associated with dumping guest memory to file.
@@ -80,6 +141,92 @@ pub enum Error {
     /// Cannot dump memory.
     #[error("Cannot dump memory: {0:?}")]
     WriteMemory(#[from] GuestMemoryError),
+    /// Invalid compressed memory file header.
+    #[error("Invalid compressed memory file header: {0}")]
+    CompressedHeader(&'static str),
+    /// Invalid chunk header in a compressed memory file.
+    #[error("Invalid chunk header at memory offset {0:#x}")]
+    InvalidChunk(u64),
+    /// Cannot decompress a chunk of a compressed memory file.
+    #[error("Cannot decompress the chunk at memory offset {0:#x}: {1}")]
+    DecompressChunk(u64, lz4::Error),
+    /// Checksum mismatch for a chunk of a compressed memory file.
+    #[error("Checksum mismatch for the chunk at memory offset {0:#x}")]
+    ChunkChecksum(u64),
+    /// Cannot resolve the pages provided by a chain of diff memory files.
+    #[error("Cannot resolve the diff memory files: {0}")]
+    DiffChain(#[from] ChainError),
+    /// Cannot map a diff memory file over guest memory.
+    #[error("Cannot map a diff memory file at memory offset {0:#x}: {1}")]
+    MapLayer(u64, std::io::Error),
+}
+
+/// Returns whether `file` is a compressed memory file, by looking at its magic value.
+pub fn is_compressed_memory_file(file: &File) -> std::io::Result<bool> {
+    let mut magic = [0u8; COMPRESSED_MEM_FILE_MAGIC.len()];
+    match file.read_exact_at(&mut magic, 0) {
+        Ok(()) => Ok(magic == COMPRESSED_MEM_FILE_MAGIC),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+fn crc64(data: &[u8]) -> u64 {
+    let mut writer = CRC64Writer::new(std::io::sink());
+    // Writing to a sink cannot fail.
+    writer.write_all(data).unwrap();
+    writer.checksum()
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Calls `f` with the offset and the length of every run of consecutive pages of `region`
+/// which are dirty in either `kvm_bitmap` or the Firecracker dirty page bitmap of the region.
+pub(crate) fn for_each_dirty_range<F, E>(
+    region: &GuestRegionMmap,
+    kvm_bitmap: &[u64],
+    page_size: usize,
+    mut f: F,
+) -> std::result::Result<(), E>
+where
+    F: FnMut(u64, usize) -> std::result::Result<(), E>,
+{
+    let firecracker_bitmap = region.bitmap();
+    let num_pages = region.len() as usize / page_size;
+    let mut range_start = 0;
+    let mut range_len = 0;
+
+    for page in 0..num_pages {
+        let is_kvm_page_dirty = kvm_bitmap
+            .get(page / 64)
+            .map_or(false, |word| (word >> (page % 64)) & 1 != 0);
+        let page_offset = page * page_size;
+        if is_kvm_page_dirty || firecracker_bitmap.dirty_at(page_offset) {
+            // We are at the start of a new range of dirty pages.
+            if range_len == 0 {
+                range_start = page_offset;
+            }
+            range_len += page_size;
+        } else if range_len > 0 {
+            // We are at the end of a range of dirty pages.
+            f(range_start as u64, range_len)?;
+            range_len = 0;
+        }
+    }
+    if range_len > 0 {
+        f(range_start as u64, range_len)?;
+    }
+    Ok(())
 }
 
 impl SnapshotMemory for GuestMemoryMmap {
@@ -99,6 +246,59 @@ impl SnapshotMemory for GuestMemoryMmap {
         guest_memory_state
     }
 
+    /// Describes the pages of GuestMemoryMmap that `dump_dirty` writes for `dirty_bitmap`,
+    /// which are the ones dirty in either the KVM or the Firecracker dirty page bitmap.
+    fn describe_dirty(
+        &self,
+        dirty_bitmap: &DirtyBitmap,
+    ) -> std::result::Result<DiffManifest, Error> {
+        let page_size = get_page_size()?;
+        let mut offset = 0;
+        let regions = self
+            .iter()
+            .enumerate()
+            .map(|(slot, region)| {
+                let kvm_bitmap = dirty_bitmap.get(&slot).unwrap();
+                let num_pages = region.len() as usize / page_size;
+                let mut bitmap = vec![0u64; (num_pages + 63) / 64];
+                for_each_dirty_range::<_, ()>(region, kvm_bitmap, page_size, |offset, len| {
+                    let first_page = offset as usize / page_size;
+                    for page in first_page..first_page + len / page_size {
+                        bitmap[page / 64] |= 1 << (page % 64);
+                    }
+                    Ok(())
+                })
+                .unwrap();
+
+                let region_dirty_pages = RegionDirtyPages {
+                    base_address: region.start_addr().0,
+                    offset,
+                    size: region.len(),
+                    bitmap,
+                };
+                offset += region.len();
+                region_dirty_pages
+            })
+            .collect();
+
+        Ok(DiffManifest {
+            page_size: page_size as u64,
+            regions,
+        })
+    }
+
+    /// Counts the pages of GuestMemoryMmap that `dump_dirty` writes for `dirty_bitmap`,
+    /// which are the ones dirty in either the KVM or the Firecracker dirty page bitmap.
+    fn count_dirty(&self, dirty_bitmap: &DirtyBitmap) -> std::result::Result<usize, Error> {
+        Ok(self
+            .describe_dirty(dirty_bitmap)?
+            .regions
+            .iter()
+            .flat_map(|region| region.bitmap.iter())
+            .map(|word| word.count_ones() as usize)
+            .sum())
+    }
+
     /// Dumps all contents of GuestMemoryMmap to a writer.
     fn dump<T: std::io::Write>(&self, writer: &mut T) -> std::result::Result<(), Error> {
         self.iter()
@@ -121,46 +321,15 @@ impl SnapshotMemory for GuestMemoryMmap {
             .enumerate()
             .try_for_each(|(slot, region)| {
                 let kvm_bitmap = dirty_bitmap.get(&slot).unwrap();
-                let firecracker_bitmap = region.bitmap();
-                let mut write_size = 0;
-                let mut dirty_batch_start: u64 = 0;
-
-                for (i, v) in kvm_bitmap.iter().enumerate() {
-                    for j in 0..64 {
-                        let is_kvm_page_dirty = ((v >> j) & 1u64) != 0u64;
-                        let page_offset = ((i * 64) + j) * page_size;
-                        let is_firecracker_page_dirty = firecracker_bitmap.dirty_at(page_offset);
-                        if is_kvm_page_dirty || is_firecracker_page_dirty {
-                            // We are at the start of a new batch of dirty pages.
-                            if write_size == 0 {
-                                // Seek forward over the unmodified pages.
-                                writer
-                                    .seek(SeekFrom::Start(writer_offset + page_offset as u64))
-                                    .unwrap();
-                                dirty_batch_start = page_offset as u64;
-                            }
-                            write_size += page_size;
-                        } else if write_size > 0 {
-                            // We are at the end of a batch of dirty pages.
-                            region.write_all_to(
-                                MemoryRegionAddress(dirty_batch_start),
-                                writer,
-                                write_size,
-                            )?;
-                            write_size = 0;
-                        }
-                    }
-                }
-
-                if write_size > 0 {
-                    region.write_all_to(
-                        MemoryRegionAddress(dirty_batch_start),
-                        writer,
-                        write_size,
-                    )?;
-                }
+                for_each_dirty_range(region, kvm_bitmap, page_size, |offset, len| {
+                    // Seek forward over the unmodified pages.
+                    writer
+                        .seek(SeekFrom::Start(writer_offset + offset))
+                        .map_err(GuestMemoryError::IOError)?;
+                    region.write_all_to(MemoryRegionAddress(offset), writer, len)
+                })?;
                 writer_offset += region.len();
-                if let Some(bitmap) = firecracker_bitmap {
+                if let Some(bitmap) = region.bitmap() {
                     bitmap.reset();
                 }
 
@@ -169,6 +338,63 @@ impl SnapshotMemory for GuestMemoryMmap {
             .map_err(Error::WriteMemory)
     }
 
+    /// Dumps all contents of GuestMemoryMmap to a writer, in the compressed
+    /// memory file format.
+    ///
+    /// Guest memory is split in chunks of `COMPRESSED_MEM_FILE_CHUNK_SIZE` bytes, walking
+    /// the regions in order. Chunks only containing zeroes are elided, the others are
+    /// stored as LZ4 blocks, or as is when they do not compress. Every chunk carries the
+    /// CRC64 of its uncompressed contents.
+    fn dump_compressed<T: std::io::Write>(&self, writer: &mut T) -> std::result::Result<(), Error> {
+        let mem_size: u64 = self.iter().map(|region| region.len()).sum();
+        let mut header = Vec::with_capacity(COMPRESSED_HEADER_SIZE);
+        header.extend_from_slice(&COMPRESSED_MEM_FILE_MAGIC);
+        header.extend_from_slice(&COMPRESSED_MEM_FILE_VERSION.to_le_bytes());
+        header.extend_from_slice(&(COMPRESSED_MEM_FILE_CHUNK_SIZE as u32).to_le_bytes());
+        header.extend_from_slice(&mem_size.to_le_bytes());
+        let header_crc = crc64(&header);
+        header.extend_from_slice(&header_crc.to_le_bytes());
+        writer.write_all(&header)?;
+
+        let mut chunk_buf = vec![0u8; COMPRESSED_MEM_FILE_CHUNK_SIZE];
+        let mut compressed = Vec::with_capacity(COMPRESSED_MEM_FILE_CHUNK_SIZE);
+        for region in self.iter() {
+            let region_len = region.len() as usize;
+            for offset in (0..region_len).step_by(COMPRESSED_MEM_FILE_CHUNK_SIZE) {
+                let chunk_len = COMPRESSED_MEM_FILE_CHUNK_SIZE.min(region_len - offset);
+                let chunk = &mut chunk_buf[..chunk_len];
+                region.read_slice(chunk, MemoryRegionAddress(offset as u64))?;
+                let chunk = &*chunk;
+
+                compressed.clear();
+                let (kind, payload, crc) = if chunk.iter().all(|&byte| byte == 0) {
+                    (CHUNK_ZERO, &[][..], 0)
+                } else if lz4::compress(chunk, &mut compressed) < chunk_len {
+                    (CHUNK_LZ4, compressed.as_slice(), crc64(chunk))
+                } else {
+                    (CHUNK_RAW, chunk, crc64(chunk))
+                };
+
+                let mut chunk_header = [0u8; CHUNK_HEADER_SIZE];
+                chunk_header[0..4].copy_from_slice(&kind.to_le_bytes());
+                chunk_header[4..8].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+                chunk_header[8..16].copy_from_slice(&crc.to_le_bytes());
+                writer.write_all(&chunk_header)?;
+                writer.write_all(payload)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks all pages as clean in the Firecracker dirty page bitmap.
+    fn reset_dirty(&self) {
+        self.iter().for_each(|region| {
+            if let Some(bitmap) = region.bitmap() {
+                bitmap.reset();
+            }
+        });
+    }
+
     /// Creates a GuestMemoryMmap backed by a `file` if present, otherwise backed
     /// by anonymous memory. Memory layout and ranges are described in `state` param.
     fn restore(
@@ -188,13 +414,225 @@ impl SnapshotMemory for GuestMemoryMmap {
 
         vm_memory::create_guest_memory(&regions, track_dirty_pages).map_err(Error::CreateMemory)
     }
+
+    /// Creates a GuestMemoryMmap backed by anonymous memory and populates it from
+    /// a `reader` holding a compressed memory file. The checksum of every chunk is
+    /// verified before it is copied to guest memory.
+    fn restore_compressed<T: std::io::Read>(
+        reader: &mut T,
+        state: &GuestMemoryState,
+        track_dirty_pages: bool,
+    ) -> std::result::Result<Self, Error> {
+        let mut header = [0u8; COMPRESSED_HEADER_SIZE];
+        reader.read_exact(&mut header)?;
+        if header[0..8] != COMPRESSED_MEM_FILE_MAGIC {
+            return Err(Error::CompressedHeader("invalid magic value"));
+        }
+        if crc64(&header[..24]) != read_u64(&header, 24) {
+            return Err(Error::CompressedHeader("checksum mismatch"));
+        }
+        if read_u32(&header, 8) != COMPRESSED_MEM_FILE_VERSION {
+            return Err(Error::CompressedHeader("unsupported version"));
+        }
+        let chunk_size = read_u32(&header, 12) as usize;
+        if chunk_size == 0 || chunk_size > MAX_COMPRESSED_CHUNK_SIZE {
+            return Err(Error::CompressedHeader("invalid chunk size"));
+        }
+        let mem_size: u64 = state.regions.iter().map(|region| region.size as u64).sum();
+        if read_u64(&header, 16) != mem_size {
+            return Err(Error::CompressedHeader(
+                "memory size does not match the microVM state",
+            ));
+        }
+
+        let guest_memory = Self::restore(None, state, track_dirty_pages)?;
+        let mut chunk_buf = vec![0u8; chunk_size];
+        let mut compressed = Vec::new();
+        let mut region_start = 0;
+        for region in guest_memory.iter() {
+            let region_len = region.len() as usize;
+            for offset in (0..region_len).step_by(chunk_size) {
+                let chunk = &mut chunk_buf[..chunk_size.min(region_len - offset)];
+                let mem_offset = region_start + offset as u64;
+
+                let mut chunk_header = [0u8; CHUNK_HEADER_SIZE];
+                reader.read_exact(&mut chunk_header)?;
+                let stored_len = read_u32(&chunk_header, 4) as usize;
+                match read_u32(&chunk_header, 0) {
+                    // Anonymous memory is already zeroed.
+                    CHUNK_ZERO if stored_len == 0 => continue,
+                    CHUNK_RAW if stored_len == chunk.len() => reader.read_exact(chunk)?,
+                    CHUNK_LZ4 if stored_len < chunk.len() => {
+                        compressed.resize(stored_len, 0);
+                        reader.read_exact(&mut compressed)?;
+                        lz4::decompress(&compressed, chunk)
+                            .map_err(|err| Error::DecompressChunk(mem_offset, err))?;
+                    }
+                    _ => return Err(Error::InvalidChunk(mem_offset)),
+                }
+
+                if crc64(chunk) != read_u64(&chunk_header, 8) {
+                    return Err(Error::ChunkChecksum(mem_offset));
+                }
+                region.write_slice(chunk, MemoryRegionAddress(offset as u64))?;
+            }
+            region_start += region.len();
+        }
+
+        // Populating the guest memory marked all the written pages as dirty.
+        guest_memory.reset_dirty();
+        Ok(guest_memory)
+    }
+
+    /// Creates a GuestMemoryMmap backed by a private mapping of the `base` file,
+    /// then maps the ranges provided by each layer of `chain` over it, so that
+    /// every page comes from the newest memory file holding it. Ranges which are
+    /// not page aligned, as well as the smallest ranges when there are too many
+    /// of them to be mapped, are copied instead of being mapped.
+    fn restore_layered(
+        base: &File,
+        chain: &mut DiffChain,
+        state: &GuestMemoryState,
+        track_dirty_pages: bool,
+    ) -> std::result::Result<Self, Error> {
+        let guest_memory = Self::restore(Some(base), state, track_dirty_pages)?;
+        overlay_layers(&guest_memory, chain, state, MAX_LAYER_MAPPINGS)?;
+
+        // Copying ranges marked their pages as dirty.
+        guest_memory.reset_dirty();
+        Ok(guest_memory)
+    }
+}
+
+// A range of a diff memory file laid over a guest memory region.
+struct LayerRange {
+    // Index of the guest memory region.
+    region: usize,
+    // Offset of the range in the memory files.
+    offset: u64,
+    len: u64,
+    // Index of the layer providing the range.
+    layer: usize,
+}
+
+// Returns the ranges provided by the layers of `chain`, split at the boundaries of the guest
+// memory regions and sorted by offset.
+fn layer_ranges(
+    chain: &mut DiffChain,
+    state: &GuestMemoryState,
+) -> std::result::Result<Vec<LayerRange>, Error> {
+    // Layers backed by the same memory file provide the same data at the same offsets.
+    let mut file_ids = Vec::with_capacity(chain.layers().len());
+    for layer in chain.layers() {
+        let metadata = layer.file().metadata()?;
+        file_ids.push((metadata.dev(), metadata.ino()));
+    }
+
+    let mut ranges: Vec<LayerRange> = Vec::new();
+    for extent in chain.resolve()? {
+        let extent_end = extent.offset + extent.len;
+        // Adjacent pages of the memory file may belong to different regions.
+        for (region, state_region) in state.regions.iter().enumerate() {
+            let start = extent.offset.max(state_region.offset);
+            let end = extent_end.min(state_region.offset + state_region.size as u64);
+            if start >= end {
+                continue;
+            }
+            match ranges.last_mut() {
+                // Contiguous ranges of the same memory file and region need a single mapping.
+                Some(last)
+                    if last.region == region
+                        && last.offset + last.len == start
+                        && file_ids[last.layer] == file_ids[extent.layer] =>
+                {
+                    last.len += end - start
+                }
+                _ => ranges.push(LayerRange {
+                    region,
+                    offset: start,
+                    len: end - start,
+                    layer: extent.layer,
+                }),
+            }
+        }
+    }
+    Ok(ranges)
+}
+
+// Lays the ranges provided by the layers of `chain` over `guest_memory`, mapping at most
+// `max_mappings` of them and copying the others.
+fn overlay_layers(
+    guest_memory: &GuestMemoryMmap,
+    chain: &mut DiffChain,
+    state: &GuestMemoryState,
+    max_mappings: usize,
+) -> std::result::Result<(), Error> {
+    let page_size = get_page_size()? as u64;
+    let regions: Vec<&GuestRegionMmap> = guest_memory.iter().collect();
+    let ranges = layer_ranges(chain, state)?;
+
+    // The number of mappings is capped before mapping anything, mapping the largest ranges.
+    let (mut mapped, mut copied): (Vec<_>, Vec<_>) = ranges.into_iter().partition(|range| {
+        let region_offset = range.offset - state.regions[range.region].offset;
+        range.offset % page_size == 0
+            && region_offset % page_size == 0
+            && range.len % page_size == 0
+    });
+    if mapped.len() > max_mappings {
+        mapped.sort_unstable_by(|a, b| b.len.cmp(&a.len));
+        copied.extend(mapped.split_off(max_mappings));
+    }
+
+    for range in mapped {
+        let region_offset = range.offset - state.regions[range.region].offset;
+        // SAFETY: Safe because the range lies within the mapping of the region, which is
+        // replaced by a private mapping of the same size, and the file descriptor is valid.
+        let addr = unsafe {
+            libc::mmap(
+                regions[range.region]
+                    .as_ptr()
+                    .add(region_offset as usize)
+                    .cast(),
+                range.len as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_NORESERVE | libc::MAP_PRIVATE | libc::MAP_FIXED,
+                chain.layers()[range.layer].file().as_raw_fd(),
+                range.offset as libc::off_t,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(Error::MapLayer(
+                range.offset,
+                std::io::Error::last_os_error(),
+            ));
+        }
+    }
+
+    let largest_copy = copied.iter().map(|range| range.len).max().unwrap_or(0);
+    let mut buf = vec![0u8; largest_copy.min(LAYER_COPY_CHUNK_SIZE as u64) as usize];
+    for range in copied {
+        let region_offset = range.offset - state.regions[range.region].offset;
+        let file = chain.layers()[range.layer].file();
+        let mut done = 0;
+        while done < range.len {
+            let chunk_len = (buf.len() as u64).min(range.len - done) as usize;
+            let chunk = &mut buf[..chunk_len];
+            file.read_exact_at(chunk, range.offset + done)?;
+            regions[range.region].write_slice(chunk, MemoryRegionAddress(region_offset + done))?;
+            done += chunk.len() as u64;
+        }
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
     use std::io::{Read, Seek};
+    use std::path::PathBuf;
 
+    use snapshot::diff_chain::MemoryLayer;
     use utils::get_page_size;
     use utils::tempfile::TempFile;
     use vm_memory::GuestAddress;
@@ -374,4 +812,386 @@ mod tests {
             assert_eq!(expected_first_region, diff_file_content);
         }
     }
+
+    #[test]
+    fn test_describe_dirty() {
+        let page_size: usize = get_page_size().unwrap();
+
+        // Two regions of two pages each, with a one page gap between them.
+        let mem_regions = [
+            (None, GuestAddress(0), page_size * 2),
+            (None, GuestAddress(page_size as u64 * 3), page_size * 2),
+        ];
+        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true).unwrap();
+
+        // KVM Bitmap
+        // First region pages: [dirty, clean]
+        // Second region pages: [clean, clean]
+        let mut dirty_bitmap: DirtyBitmap = HashMap::new();
+        dirty_bitmap.insert(0, vec![0b01; 1]);
+        dirty_bitmap.insert(1, vec![0b00; 1]);
+
+        // Firecracker Bitmap
+        // First region pages: [dirty, clean]
+        // Second region pages: [clean, dirty]
+        let ones = vec![1u8; page_size];
+        let zeros = vec![0u8; page_size];
+        guest_memory.write(&ones[..], GuestAddress(0)).unwrap();
+        guest_memory
+            .write(&ones[..], GuestAddress(page_size as u64 * 4))
+            .unwrap();
+
+        let manifest = guest_memory.describe_dirty(&dirty_bitmap).unwrap();
+        manifest.validate().unwrap();
+        assert_eq!(guest_memory.count_dirty(&dirty_bitmap).unwrap(), 2);
+        assert_eq!(manifest.page_size, page_size as u64);
+        assert_eq!(
+            manifest.regions,
+            vec![
+                RegionDirtyPages {
+                    base_address: 0,
+                    offset: 0,
+                    size: page_size as u64 * 2,
+                    bitmap: vec![0b01],
+                },
+                RegionDirtyPages {
+                    base_address: page_size as u64 * 3,
+                    offset: page_size as u64 * 2,
+                    size: page_size as u64 * 2,
+                    bitmap: vec![0b10],
+                },
+            ]
+        );
+
+        // The manifest describes the pages written by `dump_dirty`.
+        let file = TempFile::new().unwrap();
+        guest_memory
+            .dump_dirty(&mut file.as_file(), &dirty_bitmap)
+            .unwrap();
+        let mut file_content = Vec::new();
+        file.as_file().read_to_end(&mut file_content).unwrap();
+        let expected_file_content = [
+            ones.as_slice(),
+            zeros.as_slice(),
+            zeros.as_slice(),
+            ones.as_slice(),
+        ]
+        .concat();
+        assert_eq!(expected_file_content, file_content);
+        assert_eq!(
+            manifest.dirty_ranges(),
+            vec![
+                (0, page_size as u64),
+                (page_size as u64 * 3, page_size as u64)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compressed_memory_file() {
+        let page_size: usize = get_page_size().unwrap();
+        let chunk_pages = COMPRESSED_MEM_FILE_CHUNK_SIZE / page_size;
+
+        // The first region spans one and a half chunks, the second one a single page.
+        let mem_regions = [
+            (None, GuestAddress(0), page_size * chunk_pages * 3 / 2),
+            (
+                None,
+                GuestAddress(page_size as u64 * chunk_pages as u64 * 2),
+                page_size,
+            ),
+        ];
+        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], true).unwrap();
+        let memory_state = guest_memory.describe();
+
+        // First chunk: compressible, second chunk: zeroes, last chunk: incompressible.
+        let ones = vec![1u8; page_size];
+        guest_memory.write(&ones[..], GuestAddress(0)).unwrap();
+        let mut state = 0x1234_5678u32;
+        let random: Vec<u8> = (0..page_size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let last_page = GuestAddress(page_size as u64 * chunk_pages as u64 * 2);
+        guest_memory.write(&random[..], last_page).unwrap();
+
+        let mut file_content = Vec::new();
+        guest_memory.dump_compressed(&mut file_content).unwrap();
+        assert!(file_content.len() < page_size * 2);
+
+        let memory_file = TempFile::new().unwrap();
+        memory_file.as_file().write_all(&file_content).unwrap();
+        assert!(is_compressed_memory_file(memory_file.as_file()).unwrap());
+        let raw_file = TempFile::new().unwrap();
+        guest_memory.dump(&mut raw_file.as_file()).unwrap();
+        assert!(!is_compressed_memory_file(raw_file.as_file()).unwrap());
+        assert!(!is_compressed_memory_file(TempFile::new().unwrap().as_file()).unwrap());
+
+        let restored_guest_memory =
+            GuestMemoryMmap::restore_compressed(&mut file_content.as_slice(), &memory_state, true)
+                .unwrap();
+        let mut actual_page = vec![0u8; page_size];
+        restored_guest_memory
+            .read(actual_page.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(ones, actual_page);
+        restored_guest_memory
+            .read(actual_page.as_mut_slice(), last_page)
+            .unwrap();
+        assert_eq!(random, actual_page);
+        restored_guest_memory
+            .read(
+                actual_page.as_mut_slice(),
+                GuestAddress(COMPRESSED_MEM_FILE_CHUNK_SIZE as u64),
+            )
+            .unwrap();
+        assert_eq!(vec![0u8; page_size], actual_page);
+        // Restoring does not leave the pages dirty.
+        restored_guest_memory
+            .iter()
+            .for_each(|region| assert!(!region.bitmap().dirty_at(0)));
+
+        // Memory layout mismatch.
+        let other_memory = vm_memory::create_guest_memory(&mem_regions[..1], false).unwrap();
+        assert!(matches!(
+            GuestMemoryMmap::restore_compressed(
+                &mut file_content.as_slice(),
+                &other_memory.describe(),
+                false
+            ),
+            Err(Error::CompressedHeader(_))
+        ));
+
+        // Corrupted header.
+        let mut corrupted = file_content.clone();
+        corrupted[16] ^= 1;
+        assert!(matches!(
+            GuestMemoryMmap::restore_compressed(&mut corrupted.as_slice(), &memory_state, false),
+            Err(Error::CompressedHeader("checksum mismatch"))
+        ));
+
+        // Corrupted data in the last, uncompressed, chunk.
+        let mut corrupted = file_content.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        assert!(matches!(
+            GuestMemoryMmap::restore_compressed(&mut corrupted.as_slice(), &memory_state, false),
+            Err(Error::ChunkChecksum(offset)) if offset == COMPRESSED_MEM_FILE_CHUNK_SIZE as u64 * 3 / 2
+        ));
+
+        // Invalid chunk header.
+        let mut corrupted = file_content.clone();
+        corrupted[COMPRESSED_HEADER_SIZE] = 0xff;
+        assert!(matches!(
+            GuestMemoryMmap::restore_compressed(&mut corrupted.as_slice(), &memory_state, false),
+            Err(Error::InvalidChunk(0))
+        ));
+
+        // Truncated file.
+        assert!(matches!(
+            GuestMemoryMmap::restore_compressed(
+                &mut &file_content[..file_content.len() - 1],
+                &memory_state,
+                false
+            ),
+            Err(Error::FileHandle(_))
+        ));
+    }
+
+    #[test]
+    fn test_restore_layered() {
+        let page_size: usize = get_page_size().unwrap();
+
+        // Two regions of two pages each, with a one page gap between them.
+        let mem_regions = [
+            (None, GuestAddress(0), page_size * 2),
+            (None, GuestAddress(page_size as u64 * 3), page_size * 2),
+        ];
+        let guest_memory = vm_memory::create_guest_memory(&mem_regions[..], false).unwrap();
+        let memory_state = guest_memory.describe();
+
+        // The base holds 1s in the first region and 2s in the second one.
+        guest_memory
+            .write(&vec![1u8; page_size * 2], GuestAddress(0))
+            .unwrap();
+        guest_memory
+            .write(
+                &vec![2u8; page_size * 2],
+                GuestAddress(page_size as u64 * 3),
+            )
+            .unwrap();
+        let base_file = TempFile::new().unwrap();
+        guest_memory.dump(&mut base_file.as_file()).unwrap();
+
+        // The first layer holds the last page of the first region and the first page of the
+        // second one, found from the holes of the file.
+        let first_layer = TempFile::new().unwrap().into_file();
+        first_layer.set_len(page_size as u64 * 4).unwrap();
+        first_layer
+            .write_all_at(&vec![3u8; page_size * 2], page_size as u64)
+            .unwrap();
+
+        // The second layer has no holes and its manifest only lists the first 512 bytes of the
+        // second region, which are copied rather than mapped.
+        let second_layer = TempFile::new().unwrap().into_file();
+        second_layer
+            .write_all_at(&vec![4u8; page_size * 4], 0)
+            .unwrap();
+        let sub_pages = (page_size / 512) as u64;
+        let manifest = DiffManifest {
+            page_size: 512,
+            regions: vec![
+                RegionDirtyPages {
+                    base_address: 0,
+                    offset: 0,
+                    size: page_size as u64 * 2,
+                    bitmap: vec![0; ((sub_pages * 2 + 63) / 64) as usize],
+                },
+                RegionDirtyPages {
+                    base_address: page_size as u64 * 3,
+                    offset: page_size as u64 * 2,
+                    size: page_size as u64 * 2,
+                    bitmap: {
+                        let mut bitmap = vec![0; ((sub_pages * 2 + 63) / 64) as usize];
+                        bitmap[0] = 1;
+                        bitmap
+                    },
+                },
+            ],
+        };
+
+        let mut chain = DiffChain::new(vec![
+            MemoryLayer::new(PathBuf::from("first_layer"), first_layer, None),
+            MemoryLayer::new(PathBuf::from("second_layer"), second_layer, Some(manifest)),
+        ]);
+        chain.validate(page_size as u64 * 4).unwrap();
+        let restored_guest_memory =
+            GuestMemoryMmap::restore_layered(base_file.as_file(), &mut chain, &memory_state, true)
+                .unwrap();
+
+        let mut actual_region = vec![0u8; page_size * 2];
+        restored_guest_memory
+            .read(actual_region.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(
+            actual_region,
+            [vec![1u8; page_size], vec![3u8; page_size]].concat()
+        );
+        restored_guest_memory
+            .read(
+                actual_region.as_mut_slice(),
+                GuestAddress(page_size as u64 * 3),
+            )
+            .unwrap();
+        assert_eq!(
+            actual_region,
+            [
+                vec![4u8; 512],
+                vec![3u8; page_size - 512],
+                vec![2u8; page_size]
+            ]
+            .concat()
+        );
+
+        // Neither the base nor the layers are modified by guest memory writes.
+        restored_guest_memory
+            .write(&vec![5u8; page_size * 2], GuestAddress(0))
+            .unwrap();
+        let mut base_content = vec![0u8; page_size];
+        base_file
+            .as_file()
+            .read_exact_at(&mut base_content, 0)
+            .unwrap();
+        assert_eq!(base_content, vec![1u8; page_size]);
+        let mut layer_content = vec![0u8; page_size];
+        chain.layers()[0]
+            .file()
+            .read_exact_at(&mut layer_content, page_size as u64)
+            .unwrap();
+        assert_eq!(layer_content, vec![3u8; page_size]);
+
+        // Populating guest memory does not mark any page as dirty.
+        let restored_guest_memory =
+            GuestMemoryMmap::restore_layered(base_file.as_file(), &mut chain, &memory_state, true)
+                .unwrap();
+        restored_guest_memory
+            .iter()
+            .for_each(|region| assert!(!region.bitmap().dirty_at(0)));
+
+        // Past the maximum number of mappings, the smallest ranges are copied instead.
+        let expected_guest_memory =
+            GuestMemoryMmap::restore_layered(base_file.as_file(), &mut chain, &memory_state, false)
+                .unwrap();
+        for max_mappings in 0..3 {
+            let guest_memory =
+                GuestMemoryMmap::restore(Some(base_file.as_file()), &memory_state, false).unwrap();
+            overlay_layers(&guest_memory, &mut chain, &memory_state, max_mappings).unwrap();
+            for (region, expected_region) in guest_memory.iter().zip(expected_guest_memory.iter()) {
+                let mut content = vec![0u8; page_size * 2];
+                let mut expected_content = vec![0u8; page_size * 2];
+                region
+                    .read_slice(&mut content, MemoryRegionAddress(0))
+                    .unwrap();
+                expected_region
+                    .read_slice(&mut expected_content, MemoryRegionAddress(0))
+                    .unwrap();
+                assert_eq!(content, expected_content);
+            }
+        }
+    }
+
+    #[test]
+    fn test_overlay_same_file() {
+        let page_size: usize = get_page_size().unwrap();
+        let guest_memory =
+            vm_memory::create_guest_memory(&[(None, GuestAddress(0), page_size * 4)], false)
+                .unwrap();
+        let memory_state = guest_memory.describe();
+        let base_file = TempFile::new().unwrap();
+        guest_memory.dump(&mut base_file.as_file()).unwrap();
+
+        // Both layers are the same memory file, listed with complementary manifests, so that
+        // their contiguous ranges are laid with a single mapping.
+        let layer_file = TempFile::new().unwrap();
+        layer_file
+            .as_file()
+            .write_all_at(&vec![1u8; page_size * 4], 0)
+            .unwrap();
+        let manifest = |bitmap: u64| DiffManifest {
+            page_size: page_size as u64,
+            regions: vec![RegionDirtyPages {
+                base_address: 0,
+                offset: 0,
+                size: page_size as u64 * 4,
+                bitmap: vec![bitmap],
+            }],
+        };
+        let mut chain = DiffChain::new(vec![
+            MemoryLayer::new(
+                PathBuf::from("layer"),
+                File::open(layer_file.as_path()).unwrap(),
+                Some(manifest(0b0101)),
+            ),
+            MemoryLayer::new(
+                PathBuf::from("layer"),
+                File::open(layer_file.as_path()).unwrap(),
+                Some(manifest(0b1010)),
+            ),
+        ]);
+        assert_eq!(chain.resolve().unwrap().len(), 4);
+        assert_eq!(layer_ranges(&mut chain, &memory_state).unwrap().len(), 1);
+
+        let restored_guest_memory =
+            GuestMemoryMmap::restore(Some(base_file.as_file()), &memory_state, false).unwrap();
+        overlay_layers(&restored_guest_memory, &mut chain, &memory_state, 1).unwrap();
+        let mut content = vec![0u8; page_size * 4];
+        restored_guest_memory
+            .read(content.as_mut_slice(), GuestAddress(0))
+            .unwrap();
+        assert_eq!(content, vec![1u8; page_size * 4]);
+    }
 }
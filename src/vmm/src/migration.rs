@@ -0,0 +1,993 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Live migration of a running microVM to another Firecracker process.
+//!
+//! The source process connects to a Unix domain socket on which the destination
+//! process is waiting, and streams the guest memory while the microVM keeps running.
+//! Guest memory is sent in its entirety first and then, for a bounded number of
+//! rounds, only the pages dirtied in the meantime are sent again. Once the dirty set
+//! is small enough, the source pauses the microVM and sends the last dirty pages and
+//! the vCPU and device states. The destination rebuilds the microVM and acknowledges
+//! the migration, after which the source microVM stays paused and can be stopped.
+//!
+//! All integers on the wire are little endian. The stream starts with a preamble
+//! made of [`MIGRATION_MAGIC`] and [`MIGRATION_PROTOCOL_VERSION`], followed by
+//! messages made of a `u32` kind, a `u64` payload length and the payload itself.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use logger::{info, update_metric_with_elapsed_time, warn, IncMetric, StoreMetric, METRICS};
+use seccompiler::BpfThreadMap;
+use snapshot::Snapshot;
+use utils::get_page_size;
+use versionize::VersionMap;
+use vm_memory::{
+    Bytes, GuestAddress, GuestMemory, GuestMemoryError, GuestMemoryMmap, GuestMemoryRegion,
+    GuestRegionMmap, MemoryRegionAddress,
+};
+
+use crate::builder::{self, BuildMicrovmFromSnapshotError};
+use crate::memory_snapshot::{self, GuestMemoryRegionState, GuestMemoryState, SnapshotMemory};
+use crate::persist::{
+    fetch_dirty_pages, precopy_memory, snapshot_state_sanity_check, MicrovmState,
+    MicrovmStateError, PrecopyError, SnapShotStateSanityCheckError, VmInfo,
+};
+use crate::resources::VmResources;
+use crate::vmm_config::instance_info::{InstanceInfo, VmState as InstanceVmState};
+use crate::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
+use crate::{DirtyBitmap, Error as VmmError, EventManager, Vmm};
+
+/// Magic number identifying a Firecracker migration stream.
+pub const MIGRATION_MAGIC: u64 = 0x0046_434d_4947_5254;
+/// Version of the migration wire protocol.
+pub const MIGRATION_PROTOCOL_VERSION: u16 = 1;
+
+/// Maximum number of guest memory regions accepted in a memory layout message.
+const MAX_MEMORY_REGIONS: u64 = 64;
+/// Maximum size of a serialized microVM state accepted by the destination.
+const MAX_MICROVM_STATE_SIZE: u64 = 64 << 20;
+/// Maximum size of the error message sent back when a migration is aborted.
+const MAX_ABORT_MESSAGE_SIZE: u64 = 4096;
+/// How long the destination waits for the source to connect.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long the destination waits for the source to send more of the stream.
+const STREAM_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Kinds of messages exchanged during a migration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u32)]
+enum MessageKind {
+    /// Source -> destination: number of memory regions, then the guest address
+    /// and size of each of them.
+    MemoryLayout = 1,
+    /// Source -> destination: guest address of a batch of pages, then the pages.
+    MemoryPages = 2,
+    /// Source -> destination: the serialized and checksummed `MicrovmState`.
+    MicrovmState = 3,
+    /// Source -> destination: no more messages follow.
+    Complete = 4,
+    /// Destination -> source: the migrated microVM is up and running.
+    Ack = 5,
+    /// Destination -> source: the migration failed, the payload holds the reason.
+    Abort = 6,
+}
+
+impl MessageKind {
+    fn from_raw(kind: u32) -> Option<Self> {
+        match kind {
+            1 => Some(Self::MemoryLayout),
+            2 => Some(Self::MemoryPages),
+            3 => Some(Self::MicrovmState),
+            4 => Some(Self::Complete),
+            5 => Some(Self::Ack),
+            6 => Some(Self::Abort),
+            _ => None,
+        }
+    }
+}
+
+/// Errors related to the migration wire protocol.
+#[derive(Debug, thiserror::Error)]
+pub enum MigrationStreamError {
+    /// Failed to read from or write to the migration stream.
+    #[error("Migration stream I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// Failed to transfer guest memory.
+    #[error("Cannot transfer guest memory: {0}")]
+    Memory(#[from] GuestMemoryError),
+    /// Cannot fetch system's page size.
+    #[error("Cannot fetch system's page size: {0}")]
+    PageSize(#[from] utils::errno::Error),
+    /// The stream does not start with the migration magic number.
+    #[error("Invalid migration stream magic number: {0:#x}")]
+    InvalidMagic(u64),
+    /// The peer speaks an unsupported version of the protocol.
+    #[error("Unsupported migration protocol version: {0}")]
+    UnsupportedVersion(u16),
+    /// A message was received out of order or has an unknown kind.
+    #[error("Unexpected migration message of kind {0}")]
+    UnexpectedMessage(u32),
+    /// A message payload is larger than allowed.
+    #[error("Migration message of kind {0} is too large: {1} bytes")]
+    MessageTooLarge(u32, u64),
+    /// The memory layout sent by the source is invalid.
+    #[error("Invalid guest memory layout")]
+    InvalidMemoryLayout,
+}
+
+/// Error type for [`send_migration`].
+#[derive(Debug, thiserror::Error)]
+pub enum SendMigrationError {
+    /// Failed to connect to the destination.
+    #[error("Cannot connect to the migration socket: {0}")]
+    Connect(io::Error),
+    /// The inherited socket file descriptor cannot carry the migration stream.
+    #[error("Cannot use the inherited migration socket: {0}")]
+    InheritedSocket(io::Error),
+    /// Not exactly one of the socket path and the socket file descriptor was given.
+    #[error("Exactly one of the migration socket path and file descriptor must be given")]
+    InvalidSocket,
+    /// Failed to copy guest memory while the microVM keeps running.
+    #[error("{0}")]
+    Precopy(#[from] PrecopyError),
+    /// Failed to pause the microVM.
+    #[error("Cannot pause the microVM: {0}")]
+    PauseMicrovm(VmmError),
+    /// Failed to resume the microVM after a failed migration.
+    #[error("Cannot resume the microVM: {0}")]
+    ResumeMicrovm(VmmError),
+    /// Failed to save MicrovmState.
+    #[error("Cannot save the microVM state: {0}")]
+    MicrovmState(MicrovmStateError),
+    /// Failed to serialize MicrovmState.
+    #[error("Cannot serialize the microVM state: {0:?}")]
+    SerializeMicrovmState(snapshot::Error),
+    /// Failed to communicate with the destination.
+    #[error("{0}")]
+    Stream(#[from] MigrationStreamError),
+    /// The destination failed to restore the microVM.
+    #[error("The destination aborted the migration: {0}")]
+    Aborted(String),
+    /// The outcome of the migration is unknown, since the destination did not reply
+    /// after receiving the whole microVM.
+    #[error("Cannot get the migration outcome from the destination: {0}")]
+    MissingAck(MigrationStreamError),
+}
+
+/// Error type for [`receive_migration`].
+#[derive(Debug, thiserror::Error)]
+pub enum ReceiveMigrationError {
+    /// Receiving a migration is not allowed after configuring boot-specific resources.
+    #[error(
+        "Receiving a microVM migration not allowed after configuring boot-specific resources."
+    )]
+    ReceiveMigrationNotAllowed,
+    /// The inherited socket file descriptor cannot carry the migration stream.
+    #[error("Cannot use the inherited migration socket: {0}")]
+    InheritedSocket(io::Error),
+    /// Not exactly one of the socket path and the socket file descriptor was given.
+    #[error("Exactly one of the migration socket path and file descriptor must be given")]
+    InvalidSocket,
+    /// Failed to bind the migration socket.
+    #[error("Cannot bind the migration socket: {0}")]
+    Bind(io::Error),
+    /// Failed to accept the connection from the source.
+    #[error("Cannot accept the migration connection: {0}")]
+    Accept(io::Error),
+    /// The source did not connect in time.
+    #[error("The migration source did not connect within {} seconds", ACCEPT_TIMEOUT.as_secs())]
+    AcceptTimeout,
+    /// Failed to communicate with the source.
+    #[error("{0}")]
+    Stream(#[from] MigrationStreamError),
+    /// Failed to create guest memory.
+    #[error("Cannot create guest memory: {0}")]
+    GuestMemory(#[from] memory_snapshot::Error),
+    /// Failed to deserialize MicrovmState.
+    #[error("Cannot deserialize the microVM state: {0:?}")]
+    DeserializeMicrovmState(snapshot::Error),
+    /// The migrated microVM state is not valid.
+    #[error("Invalid microVM state: {0}")]
+    SanityCheck(#[from] SnapShotStateSanityCheckError),
+    /// The source closed the stream before sending the microVM state.
+    #[error("The migration stream ended before the microVM state was received")]
+    MissingMicrovmState,
+    /// Failed to build the microVM.
+    #[error("Cannot build the microVM: {0}")]
+    Build(#[from] BuildMicrovmFromSnapshotError),
+    /// Failed to resume the microVM.
+    #[error("Cannot resume the microVM: {0}")]
+    ResumeMicrovm(VmmError),
+}
+
+impl ReceiveMigrationError {
+    /// Whether the error happened after the microVM started being built, which
+    /// leaves the process in a state it cannot recover from.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Self::Build(_) | Self::ResumeMicrovm(_))
+    }
+}
+
+/// Sends the microVM to the Firecracker process listening on `params.socket_path`, or
+/// on the other end of the connected socket `params.socket_fd`.
+///
+/// On success, the microVM is left paused and is meant to be stopped. On failure,
+/// the microVM is resumed if it was running before the migration started, unless the
+/// destination may have taken over the microVM already.
+pub fn send_migration(
+    vmm: &mut Vmm,
+    vm_info: &VmInfo,
+    params: &SendMigrationParams,
+    version_map: VersionMap,
+) -> std::result::Result<(), SendMigrationError> {
+    let mut stream = match (&params.socket_path, params.socket_fd) {
+        (Some(socket_path), None) => {
+            UnixStream::connect(socket_path).map_err(SendMigrationError::Connect)?
+        }
+        (None, Some(socket_fd)) => {
+            inherited_stream(socket_fd).map_err(SendMigrationError::InheritedSocket)?
+        }
+        _ => return Err(SendMigrationError::InvalidSocket),
+    };
+    migrate_to(vmm, vm_info, &mut stream, version_map).map_err(|err| {
+        METRICS.migration.send_fails.inc();
+        err
+    })
+}
+
+fn migrate_to<T: Read + Write>(
+    vmm: &mut Vmm,
+    vm_info: &VmInfo,
+    stream: &mut T,
+    version_map: VersionMap,
+) -> std::result::Result<(), SendMigrationError> {
+    use self::SendMigrationError::*;
+    let was_running = vmm.instance_info.state == InstanceVmState::Running;
+
+    // Fail before the destination receives any guest memory.
+    vmm.check_devices_persistable().map_err(MicrovmState)?;
+    write_preamble(stream)?;
+    send_memory_layout(stream, &vmm.guest_memory().describe())?;
+
+    let precopy_rounds = precopy_memory(vmm, |guest_memory, dirty_bitmap| {
+        match dirty_bitmap {
+            Some(dirty_bitmap) => send_dirty_memory(stream, guest_memory, dirty_bitmap),
+            None => send_memory(stream, guest_memory),
+        }
+        .map_err(Stream)
+    })?;
+    METRICS.migration.precopy_rounds.store(precopy_rounds);
+
+    let downtime_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+    if was_running {
+        vmm.pause_vm().map_err(PauseMicrovm)?;
+    }
+    if let Err(err) = migration_final_round(vmm, vm_info, stream, version_map) {
+        // The microVM did not make it to the destination, keep it running here. If
+        // the destination did not reply, it may be running the microVM already, so
+        // leave it paused rather than risk running it twice.
+        if was_running && !matches!(err, MissingAck(_)) {
+            vmm.resume_vm().map_err(ResumeMicrovm)?;
+        }
+        return Err(err);
+    }
+    let downtime_us = update_metric_with_elapsed_time(
+        &METRICS.latencies_us.vmm_migration_downtime,
+        downtime_start_us,
+    );
+    info!(
+        "Migration: {} pre-copy rounds, microVM paused for {} us.",
+        precopy_rounds, downtime_us
+    );
+    Ok(())
+}
+
+/// Sends the last dirty pages and the microVM state, then waits for the destination
+/// to acknowledge the migration. The microVM needs to be paused.
+fn migration_final_round<T: Read + Write>(
+    vmm: &mut Vmm,
+    vm_info: &VmInfo,
+    stream: &mut T,
+    version_map: VersionMap,
+) -> std::result::Result<(), SendMigrationError> {
+    use self::SendMigrationError::*;
+    // Saving the device states drains the in-flight block I/O, which can still
+    // dirty guest memory, so it has to happen before fetching the last dirty set.
+    let microvm_state = vmm.save_state(vm_info).map_err(MicrovmState)?;
+    let (dirty_bitmap, dirty_pages) = fetch_dirty_pages(vmm)?;
+    METRICS.migration.final_dirty_pages.store(dirty_pages);
+    send_dirty_memory(stream, vmm.guest_memory(), &dirty_bitmap)?;
+
+    let mut state_bytes = Vec::new();
+    let data_version = version_map.latest_version();
+    Snapshot::new(version_map, data_version)
+        .save(&mut state_bytes, &microvm_state)
+        .map_err(SerializeMicrovmState)?;
+    write_message(stream, MessageKind::MicrovmState, &state_bytes)?;
+    write_message(stream, MessageKind::Complete, &[])?;
+
+    let (kind, len) = read_message_header(stream).map_err(MissingAck)?;
+    match kind {
+        MessageKind::Ack => Ok(()),
+        MessageKind::Abort => {
+            let reason =
+                read_payload(stream, kind, len, MAX_ABORT_MESSAGE_SIZE).map_err(MissingAck)?;
+            Err(Aborted(String::from_utf8_lossy(&reason).into_owned()))
+        }
+        _ => Err(MissingAck(MigrationStreamError::UnexpectedMessage(
+            kind as u32,
+        ))),
+    }
+}
+
+/// Waits on `params.socket_path`, or on the connected socket `params.socket_fd`, for
+/// a microVM sent by another Firecracker process and builds it.
+pub fn receive_migration(
+    instance_info: &InstanceInfo,
+    event_manager: &mut EventManager,
+    seccomp_filters: &BpfThreadMap,
+    params: &ReceiveMigrationParams,
+    version_map: VersionMap,
+    vm_resources: &mut VmResources,
+) -> std::result::Result<Arc<Mutex<Vmm>>, ReceiveMigrationError> {
+    let mut stream = match (&params.socket_path, params.socket_fd) {
+        (Some(socket_path), None) => accept_on(socket_path)?,
+        (None, Some(socket_fd)) => {
+            inherited_stream(socket_fd).map_err(ReceiveMigrationError::InheritedSocket)?
+        }
+        _ => return Err(ReceiveMigrationError::InvalidSocket),
+    };
+    // A source which stops sending midway must not hold the API thread forever either.
+    stream
+        .set_read_timeout(Some(STREAM_READ_TIMEOUT))
+        .map_err(ReceiveMigrationError::Accept)?;
+
+    let result = migrate_from(
+        instance_info,
+        event_manager,
+        seccomp_filters,
+        params,
+        version_map,
+        vm_resources,
+        &mut stream,
+    );
+    // Let the source know whether it can let go of the microVM. This is best effort
+    // since the source may have gone away already.
+    let reply = match &result {
+        Ok(_) => write_message(&mut stream, MessageKind::Ack, &[]),
+        Err(err) => {
+            METRICS.migration.receive_fails.inc();
+            write_message(&mut stream, MessageKind::Abort, err.to_string().as_bytes())
+        }
+    };
+    if let Err(err) = reply {
+        warn!("Cannot reply to the migration source: {}", err);
+    }
+    result
+}
+
+// Waits for the source to connect to a Unix domain socket bound to `socket_path`.
+fn accept_on(socket_path: &Path) -> std::result::Result<UnixStream, ReceiveMigrationError> {
+    let listener = UnixListener::bind(socket_path).map_err(ReceiveMigrationError::Bind)?;
+    let accepted = accept_with_timeout(&listener, ACCEPT_TIMEOUT);
+    // Only one source is ever accepted, so the socket is not needed anymore.
+    if let Err(err) = fs::remove_file(socket_path) {
+        warn!("Cannot remove the migration socket: {}", err);
+    }
+    accepted
+}
+
+// Takes ownership of the connected stream socket inherited as `fd`. Only the socket
+// level options of the socket are used, so its address family does not matter.
+fn inherited_stream(fd: RawFd) -> io::Result<UnixStream> {
+    // SAFETY: Safe because `libc::stat` only holds integers, for which zero is valid.
+    let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+    // SAFETY: Safe because `stat` is valid for the duration of the call.
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if stat.st_mode & libc::S_IFMT != libc::S_IFSOCK {
+        return Err(io::Error::from_raw_os_error(libc::ENOTSOCK));
+    }
+    // SAFETY: Safe because the file descriptor is handed over to Firecracker for the
+    // migration, and nothing else in the process uses it.
+    Ok(unsafe { UnixStream::from_raw_fd(fd) })
+}
+
+// Waits for the source to connect to `listener`, for at most `timeout`.
+fn accept_with_timeout(
+    listener: &UnixListener,
+    timeout: Duration,
+) -> std::result::Result<UnixStream, ReceiveMigrationError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let mut pollfd = libc::pollfd {
+            fd: listener.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        // Round up, so that the last poll doesn't turn into a busy loop.
+        let timeout_ms = i32::try_from((remaining.as_micros() + 999) / 1000).unwrap_or(i32::MAX);
+        // SAFETY: Safe because `pollfd` is valid for the duration of the call.
+        match unsafe { libc::poll(&mut pollfd, 1, timeout_ms) } {
+            0 => return Err(ReceiveMigrationError::AcceptTimeout),
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(ReceiveMigrationError::Accept(err));
+                }
+            }
+            _ => {
+                return listener
+                    .accept()
+                    .map(|(stream, _)| stream)
+                    .map_err(ReceiveMigrationError::Accept)
+            }
+        }
+    }
+}
+
+fn migrate_from<T: Read>(
+    instance_info: &InstanceInfo,
+    event_manager: &mut EventManager,
+    seccomp_filters: &BpfThreadMap,
+    params: &ReceiveMigrationParams,
+    version_map: VersionMap,
+    vm_resources: &mut VmResources,
+    stream: &mut T,
+) -> std::result::Result<Arc<Mutex<Vmm>>, ReceiveMigrationError> {
+    let track_dirty_pages = params.enable_diff_snapshots;
+    let (guest_memory, microvm_state) = receive_microvm(stream, track_dirty_pages, version_map)?;
+
+    let vmm = builder::build_microvm_from_snapshot(
+        instance_info,
+        event_manager,
+        microvm_state,
+        guest_memory,
+        None,
+        track_dirty_pages,
+        seccomp_filters,
+        vm_resources,
+    )?;
+    if params.resume_vm {
+        vmm.lock()
+            .expect("Poisoned lock")
+            .resume_vm()
+            .map_err(ReceiveMigrationError::ResumeMicrovm)?;
+    }
+    Ok(vmm)
+}
+
+/// Reads the guest memory and the microVM state sent by the source, until the
+/// end of the migration stream.
+fn receive_microvm<T: Read>(
+    stream: &mut T,
+    track_dirty_pages: bool,
+    version_map: VersionMap,
+) -> std::result::Result<(GuestMemoryMmap, MicrovmState), ReceiveMigrationError> {
+    read_preamble(stream)?;
+
+    let memory_state = match read_message_header(stream)? {
+        (MessageKind::MemoryLayout, len) => read_memory_layout(stream, len)?,
+        (kind, _) => return Err(MigrationStreamError::UnexpectedMessage(kind as u32).into()),
+    };
+    let guest_memory = GuestMemoryMmap::restore(None, &memory_state, track_dirty_pages)?;
+
+    let mut microvm_state = None;
+    loop {
+        match read_message_header(stream)? {
+            (MessageKind::MemoryPages, len) => receive_pages(stream, &guest_memory, len)?,
+            (kind @ MessageKind::MicrovmState, len) => {
+                let state_bytes = read_payload(stream, kind, len, MAX_MICROVM_STATE_SIZE)?;
+                let state: MicrovmState = Snapshot::load(
+                    &mut state_bytes.as_slice(),
+                    state_bytes.len(),
+                    version_map.clone(),
+                )
+                .map_err(ReceiveMigrationError::DeserializeMicrovmState)?;
+                microvm_state = Some(state);
+            }
+            (MessageKind::Complete, _) => break,
+            (kind, _) => return Err(MigrationStreamError::UnexpectedMessage(kind as u32).into()),
+        }
+    }
+
+    let microvm_state = microvm_state.ok_or(ReceiveMigrationError::MissingMicrovmState)?;
+    snapshot_state_sanity_check(&microvm_state)?;
+    if microvm_state.memory_state != memory_state {
+        return Err(MigrationStreamError::InvalidMemoryLayout.into());
+    }
+    Ok((guest_memory, microvm_state))
+}
+
+fn write_preamble<T: Write>(stream: &mut T) -> std::result::Result<(), MigrationStreamError> {
+    stream.write_all(&MIGRATION_MAGIC.to_le_bytes())?;
+    stream.write_all(&MIGRATION_PROTOCOL_VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_preamble<T: Read>(stream: &mut T) -> std::result::Result<(), MigrationStreamError> {
+    let magic = read_u64(stream)?;
+    if magic != MIGRATION_MAGIC {
+        return Err(MigrationStreamError::InvalidMagic(magic));
+    }
+    let mut version = [0u8; 2];
+    stream.read_exact(&mut version)?;
+    let version = u16::from_le_bytes(version);
+    if version != MIGRATION_PROTOCOL_VERSION {
+        return Err(MigrationStreamError::UnsupportedVersion(version));
+    }
+    Ok(())
+}
+
+fn write_message_header<T: Write>(
+    stream: &mut T,
+    kind: MessageKind,
+    len: u64,
+) -> std::result::Result<(), MigrationStreamError> {
+    stream.write_all(&(kind as u32).to_le_bytes())?;
+    stream.write_all(&len.to_le_bytes())?;
+    Ok(())
+}
+
+fn write_message<T: Write>(
+    stream: &mut T,
+    kind: MessageKind,
+    payload: &[u8],
+) -> std::result::Result<(), MigrationStreamError> {
+    write_message_header(stream, kind, payload.len() as u64)?;
+    stream.write_all(payload)?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn read_message_header<T: Read>(
+    stream: &mut T,
+) -> std::result::Result<(MessageKind, u64), MigrationStreamError> {
+    let mut kind = [0u8; 4];
+    stream.read_exact(&mut kind)?;
+    let kind = u32::from_le_bytes(kind);
+    let kind = MessageKind::from_raw(kind).ok_or(MigrationStreamError::UnexpectedMessage(kind))?;
+    Ok((kind, read_u64(stream)?))
+}
+
+fn read_payload<T: Read>(
+    stream: &mut T,
+    kind: MessageKind,
+    len: u64,
+    max_len: u64,
+) -> std::result::Result<Vec<u8>, MigrationStreamError> {
+    if len > max_len {
+        return Err(MigrationStreamError::MessageTooLarge(kind as u32, len));
+    }
+    let len = usize::try_from(len)
+        .map_err(|_| MigrationStreamError::MessageTooLarge(kind as u32, len))?;
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+fn read_u64<T: Read>(stream: &mut T) -> std::result::Result<u64, MigrationStreamError> {
+    let mut bytes = [0u8; 8];
+    stream.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn send_memory_layout<T: Write>(
+    stream: &mut T,
+    memory_state: &GuestMemoryState,
+) -> std::result::Result<(), MigrationStreamError> {
+    let mut payload = Vec::with_capacity(8 + 16 * memory_state.regions.len());
+    payload.extend_from_slice(&(memory_state.regions.len() as u64).to_le_bytes());
+    for region in memory_state.regions.iter() {
+        payload.extend_from_slice(&region.base_address.to_le_bytes());
+        payload.extend_from_slice(&(region.size as u64).to_le_bytes());
+    }
+    write_message(stream, MessageKind::MemoryLayout, &payload)
+}
+
+fn read_memory_layout<T: Read>(
+    stream: &mut T,
+    len: u64,
+) -> std::result::Result<GuestMemoryState, MigrationStreamError> {
+    let payload = read_payload(
+        stream,
+        MessageKind::MemoryLayout,
+        len,
+        8 + 16 * MAX_MEMORY_REGIONS,
+    )?;
+    let mut payload = payload.as_slice();
+    let num_regions = read_u64(&mut payload)?;
+    if num_regions == 0 || num_regions > MAX_MEMORY_REGIONS || len != 8 + 16 * num_regions {
+        return Err(MigrationStreamError::InvalidMemoryLayout);
+    }
+
+    // Lay the regions out the same way `SnapshotMemory::describe()` does, so that
+    // the result can be compared against the memory state of the microVM.
+    let mut memory_state = GuestMemoryState::default();
+    let mut offset: u64 = 0;
+    for _ in 0..num_regions {
+        let base_address = read_u64(&mut payload)?;
+        let size = read_u64(&mut payload)?;
+        memory_state.regions.push(GuestMemoryRegionState {
+            base_address,
+            size: usize::try_from(size).map_err(|_| MigrationStreamError::InvalidMemoryLayout)?,
+            offset,
+        });
+        offset = offset
+            .checked_add(size)
+            .ok_or(MigrationStreamError::InvalidMemoryLayout)?;
+    }
+
+    // Only allocate guest memory laid out the way this Firecracker would lay out
+    // guest memory of the same size.
+    let mem_size =
+        usize::try_from(offset).map_err(|_| MigrationStreamError::InvalidMemoryLayout)?;
+    if memory_state != arch_memory_state(mem_size) {
+        return Err(MigrationStreamError::InvalidMemoryLayout);
+    }
+    Ok(memory_state)
+}
+
+// Describes guest memory of `mem_size` bytes as laid out for the host architecture.
+fn arch_memory_state(mem_size: usize) -> GuestMemoryState {
+    let mut memory_state = GuestMemoryState::default();
+    let mut offset = 0;
+    for (base_address, size) in arch::arch_memory_regions(mem_size) {
+        memory_state.regions.push(GuestMemoryRegionState {
+            base_address: base_address.0,
+            size,
+            offset,
+        });
+        offset += size as u64;
+    }
+    memory_state
+}
+
+fn send_pages<T: Write>(
+    stream: &mut T,
+    region: &GuestRegionMmap,
+    offset: u64,
+    len: usize,
+) -> std::result::Result<(), MigrationStreamError> {
+    write_message_header(stream, MessageKind::MemoryPages, 8 + len as u64)?;
+    stream.write_all(&(region.start_addr().0 + offset).to_le_bytes())?;
+    region.write_all_to(MemoryRegionAddress(offset), stream, len)?;
+    Ok(())
+}
+
+fn receive_pages<T: Read>(
+    stream: &mut T,
+    guest_memory: &GuestMemoryMmap,
+    len: u64,
+) -> std::result::Result<(), MigrationStreamError> {
+    let len = len
+        .checked_sub(8)
+        .ok_or(MigrationStreamError::UnexpectedMessage(
+            MessageKind::MemoryPages as u32,
+        ))?;
+    let len = usize::try_from(len)
+        .map_err(|_| MigrationStreamError::MessageTooLarge(MessageKind::MemoryPages as u32, len))?;
+    let guest_address = read_u64(stream)?;
+    guest_memory.read_exact_from(GuestAddress(guest_address), stream, len)?;
+    Ok(())
+}
+
+/// Sends all of guest memory.
+fn send_memory<T: Write>(
+    stream: &mut T,
+    guest_memory: &GuestMemoryMmap,
+) -> std::result::Result<(), MigrationStreamError> {
+    guest_memory
+        .iter()
+        .try_for_each(|region| send_pages(stream, region, 0, region.len() as usize))?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Sends the pages marked as dirty either in `dirty_bitmap` or in the Firecracker
+/// dirty page bitmap, and marks them as clean in the latter.
+fn send_dirty_memory<T: Write>(
+    stream: &mut T,
+    guest_memory: &GuestMemoryMmap,
+    dirty_bitmap: &DirtyBitmap,
+) -> std::result::Result<(), MigrationStreamError> {
+    let page_size = get_page_size()?;
+
+    for (slot, region) in guest_memory.iter().enumerate() {
+        let kvm_bitmap = dirty_bitmap.get(&slot).map(Vec::as_slice).unwrap_or(&[]);
+        memory_snapshot::for_each_dirty_range(region, kvm_bitmap, page_size, |offset, len| {
+            send_pages(stream, region, offset, len)
+        })?;
+        if let Some(bitmap) = region.bitmap() {
+            bitmap.reset();
+        }
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use utils::tempfile::TempFile;
+    use vm_memory::create_guest_memory;
+
+    use super::*;
+
+    fn create_memory(regions: &[(GuestAddress, usize)]) -> GuestMemoryMmap {
+        let regions: Vec<_> = regions
+            .iter()
+            .map(|&(base, size)| (None, base, size))
+            .collect();
+        create_guest_memory(&regions, false).unwrap()
+    }
+
+    fn create_memory_with_pattern(regions: &[(GuestAddress, usize)]) -> GuestMemoryMmap {
+        let guest_memory = create_memory(regions);
+        for (base, size) in regions {
+            let pattern: Vec<u8> = (0..*size).map(|i| (i % 251) as u8).collect();
+            guest_memory.write_slice(&pattern, *base).unwrap();
+        }
+        guest_memory
+    }
+
+    fn read_region(guest_memory: &GuestMemoryMmap, base: GuestAddress, size: usize) -> Vec<u8> {
+        let mut data = vec![0u8; size];
+        guest_memory.read_slice(&mut data, base).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_preamble() {
+        let mut stream = Vec::new();
+        write_preamble(&mut stream).unwrap();
+        read_preamble(&mut stream.as_slice()).unwrap();
+
+        let mut bad_magic = stream.clone();
+        bad_magic[0] ^= 0xff;
+        assert!(matches!(
+            read_preamble(&mut bad_magic.as_slice()),
+            Err(MigrationStreamError::InvalidMagic(_))
+        ));
+
+        let mut bad_version = stream;
+        bad_version[8] = 0xff;
+        assert!(matches!(
+            read_preamble(&mut bad_version.as_slice()),
+            Err(MigrationStreamError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut stream = Vec::new();
+        write_message(&mut stream, MessageKind::Abort, b"failed").unwrap();
+        write_message(&mut stream, MessageKind::Ack, &[]).unwrap();
+        stream.extend_from_slice(&42u32.to_le_bytes());
+        stream.extend_from_slice(&0u64.to_le_bytes());
+
+        let mut reader = stream.as_slice();
+        let (kind, len) = read_message_header(&mut reader).unwrap();
+        assert_eq!(kind, MessageKind::Abort);
+        let mut peek = reader;
+        assert!(matches!(
+            read_payload(&mut peek, kind, len, 2),
+            Err(MigrationStreamError::MessageTooLarge(6, 6))
+        ));
+        assert_eq!(read_payload(&mut reader, kind, len, 6).unwrap(), b"failed");
+        assert_eq!(
+            read_message_header(&mut reader).unwrap(),
+            (MessageKind::Ack, 0)
+        );
+        assert!(matches!(
+            read_message_header(&mut reader),
+            Err(MigrationStreamError::UnexpectedMessage(42))
+        ));
+        assert!(matches!(
+            read_message_header(&mut reader),
+            Err(MigrationStreamError::Io(_))
+        ));
+    }
+
+    fn check_invalid_memory_layout(memory_state: &GuestMemoryState) {
+        let mut stream = Vec::new();
+        send_memory_layout(&mut stream, memory_state).unwrap();
+        let mut reader = stream.as_slice();
+        let (_, len) = read_message_header(&mut reader).unwrap();
+        assert!(matches!(
+            read_memory_layout(&mut reader, len),
+            Err(MigrationStreamError::InvalidMemoryLayout)
+        ));
+    }
+
+    #[test]
+    fn test_memory_layout() {
+        let page_size = get_page_size().unwrap();
+        let regions = arch::arch_memory_regions(page_size * 16);
+        let guest_memory = create_memory(&regions);
+        let memory_state = guest_memory.describe();
+
+        let mut stream = Vec::new();
+        send_memory_layout(&mut stream, &memory_state).unwrap();
+        let mut reader = stream.as_slice();
+        let (kind, len) = read_message_header(&mut reader).unwrap();
+        assert_eq!(kind, MessageKind::MemoryLayout);
+        assert_eq!(read_memory_layout(&mut reader, len).unwrap(), memory_state);
+
+        // A layout without any region is rejected.
+        check_invalid_memory_layout(&GuestMemoryState::default());
+
+        // So is a layout this Firecracker would not give to guest memory of the same size.
+        let mut memory_state = guest_memory.describe();
+        memory_state.regions[0].base_address += page_size as u64;
+        check_invalid_memory_layout(&memory_state);
+
+        // So is a layout whose size overflows.
+        let mut memory_state = guest_memory.describe();
+        memory_state.regions.push(GuestMemoryRegionState {
+            base_address: 1 << 40,
+            size: usize::MAX,
+            offset: 0,
+        });
+        check_invalid_memory_layout(&memory_state);
+    }
+
+    #[test]
+    fn test_send_memory() {
+        let page_size = get_page_size().unwrap();
+        let regions = [
+            (GuestAddress(0), page_size * 4),
+            (GuestAddress(page_size as u64 * 8), page_size * 2),
+        ];
+        let src_memory = create_memory_with_pattern(&regions);
+        let dst_memory = create_memory(&regions);
+
+        let mut stream = Vec::new();
+        send_memory(&mut stream, &src_memory).unwrap();
+        let mut reader = stream.as_slice();
+        for _ in 0..regions.len() {
+            let (kind, len) = read_message_header(&mut reader).unwrap();
+            assert_eq!(kind, MessageKind::MemoryPages);
+            receive_pages(&mut reader, &dst_memory, len).unwrap();
+        }
+        assert!(reader.is_empty());
+
+        for (base, size) in regions {
+            assert_eq!(
+                read_region(&src_memory, base, size),
+                read_region(&dst_memory, base, size)
+            );
+        }
+    }
+
+    #[test]
+    fn test_send_dirty_memory() {
+        let page_size = get_page_size().unwrap();
+        let regions = [
+            (GuestAddress(0), page_size * 4),
+            (GuestAddress(page_size as u64 * 8), page_size * 2),
+        ];
+        let src_memory = create_memory_with_pattern(&regions);
+        let dst_memory = create_memory(&regions);
+
+        // Pages 0, 1 and 3 of the first region and page 1 of the second one are dirty.
+        let mut dirty_bitmap: DirtyBitmap = HashMap::new();
+        dirty_bitmap.insert(0, vec![0b1011]);
+        dirty_bitmap.insert(1, vec![0b10]);
+
+        let mut stream = Vec::new();
+        send_dirty_memory(&mut stream, &src_memory, &dirty_bitmap).unwrap();
+        let mut reader = stream.as_slice();
+        let mut batches = 0;
+        while !reader.is_empty() {
+            let (kind, len) = read_message_header(&mut reader).unwrap();
+            assert_eq!(kind, MessageKind::MemoryPages);
+            receive_pages(&mut reader, &dst_memory, len).unwrap();
+            batches += 1;
+        }
+        assert_eq!(batches, 3);
+
+        let zeroes = vec![0u8; page_size];
+        for (page, dirty) in [
+            (0, true),
+            (1, true),
+            (2, false),
+            (3, true),
+            (8, false),
+            (9, true),
+        ] {
+            let base = GuestAddress((page * page_size) as u64);
+            let dst_page = read_region(&dst_memory, base, page_size);
+            if dirty {
+                assert_eq!(dst_page, read_region(&src_memory, base, page_size));
+            } else {
+                assert_eq!(dst_page, zeroes);
+            }
+        }
+    }
+
+    #[test]
+    fn test_receive_pages_out_of_bounds() {
+        let page_size = get_page_size().unwrap();
+        let dst_memory = create_memory(&[(GuestAddress(0), page_size)]);
+
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&(page_size as u64).to_le_bytes());
+        stream.extend_from_slice(&vec![0u8; page_size]);
+        assert!(matches!(
+            receive_pages(&mut stream.as_slice(), &dst_memory, 8 + page_size as u64),
+            Err(MigrationStreamError::Memory(_))
+        ));
+        assert!(receive_pages(&mut stream.as_slice(), &dst_memory, 4).is_err());
+    }
+
+    #[test]
+    fn test_accept_with_timeout() {
+        let mut socket_file = TempFile::new().unwrap();
+        socket_file.remove().unwrap();
+        let listener = UnixListener::bind(socket_file.as_path()).unwrap();
+
+        // Nobody connects.
+        assert!(matches!(
+            accept_with_timeout(&listener, Duration::from_millis(10)),
+            Err(ReceiveMigrationError::AcceptTimeout)
+        ));
+
+        // The source connects before the timeout expires.
+        let _source = UnixStream::connect(socket_file.as_path()).unwrap();
+        assert!(accept_with_timeout(&listener, Duration::from_millis(10)).is_ok());
+        fs::remove_file(socket_file.as_path()).unwrap();
+    }
+
+    #[test]
+    fn test_inherited_stream() {
+        use std::os::unix::io::IntoRawFd;
+
+        let (source, destination) = UnixStream::pair().unwrap();
+        let mut source = inherited_stream(source.into_raw_fd()).unwrap();
+        write_preamble(&mut source).unwrap();
+        read_preamble(&mut &destination).unwrap();
+
+        // Only sockets are accepted.
+        let file = TempFile::new().unwrap();
+        assert_eq!(
+            inherited_stream(file.as_file().as_raw_fd())
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOTSOCK)
+        );
+    }
+
+    #[test]
+    fn test_error_display() {
+        use self::SendMigrationError::*;
+
+        let err = Connect(io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+        let err = InvalidSocket;
+        let _ = format!("{}{:?}", err, err);
+        let err = Aborted(String::new());
+        let _ = format!("{}{:?}", err, err);
+        let err = Stream(MigrationStreamError::InvalidMemoryLayout);
+        let _ = format!("{}{:?}", err, err);
+
+        let err = ReceiveMigrationError::ReceiveMigrationNotAllowed;
+        let _ = format!("{}{:?}", err, err);
+        assert!(!err.is_fatal());
+        let err = ReceiveMigrationError::AcceptTimeout;
+        let _ = format!("{}{:?}", err, err);
+        assert!(!err.is_fatal());
+        let err = ReceiveMigrationError::ResumeMicrovm(VmmError::VcpuResume);
+        assert!(err.is_fatal());
+    }
+}
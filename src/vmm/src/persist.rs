@@ -4,10 +4,10 @@
 //! Defines state structures for saving/restoring a Firecracker microVM.
 
 use std::fs::{File, OpenOptions};
-use std::io::{self, Write};
+use std::io::{self, BufReader, BufWriter, Write};
 use std::os::unix::io::AsRawFd;
 use std::os::unix::net::UnixStream;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 #[cfg(target_arch = "aarch64")]
@@ -15,9 +15,11 @@ use arch::regs::{get_manufacturer_id_from_host, get_manufacturer_id_from_state};
 #[cfg(target_arch = "x86_64")]
 use cpuid::common::{get_vendor_id_from_cpuid, get_vendor_id_from_host};
 use devices::virtio::TYPE_NET;
-use logger::{error, info, warn};
+use logger::{error, info, update_metric_with_elapsed_time, warn, StoreMetric, METRICS};
 use seccompiler::BpfThreadMap;
 use serde::Serialize;
+use snapshot::diff_chain::{ChainError, DiffChain, MemoryLayer};
+use snapshot::diff_manifest::DiffManifest;
 use snapshot::Snapshot;
 use userfaultfd::{FeatureFlags, Uffd, UffdBuilder};
 use utils::sock_ctrl_msg::ScmSocket;
@@ -27,25 +29,41 @@ use virtio_gen::virtio_ring::VIRTIO_RING_F_EVENT_IDX;
 use vm_memory::{GuestMemory, GuestMemoryMmap};
 
 use crate::builder::{self, BuildMicrovmFromSnapshotError};
-use crate::device_manager::persist::{DeviceStates, Error as DevicePersistError};
-use crate::memory_snapshot::{GuestMemoryState, SnapshotMemory};
+use crate::device_manager::persist::{
+    CloneIdentityError, DeviceOverrideError, DeviceStates, Error as DevicePersistError,
+    GuestIdentity,
+};
+use crate::memory_snapshot::{is_compressed_memory_file, GuestMemoryState, SnapshotMemory};
 use crate::resources::VmResources;
 #[cfg(target_arch = "x86_64")]
 use crate::version_map::FC_V0_23_SNAP_VERSION;
 use crate::version_map::{FC_V1_0_SNAP_VERSION, FC_V1_1_SNAP_VERSION, FC_VERSION_TO_SNAP_VERSION};
 use crate::vmm_config::boot_source::BootSourceConfig;
-use crate::vmm_config::instance_info::InstanceInfo;
+use crate::vmm_config::instance_info::{InstanceInfo, VmState as InstanceVmState};
 use crate::vmm_config::machine_config::{CpuFeaturesTemplate, MAX_SUPPORTED_VCPUS};
 use crate::vmm_config::snapshot::{
-    CreateSnapshotParams, LoadSnapshotParams, MemBackendType, SnapshotType,
+    CreateSnapshotParams, LoadSnapshotParams, MemBackendType, MemFileFormat, MemLayerConfig,
+    SnapshotType,
 };
 use crate::vstate::vcpu::{VcpuSendEventError, VcpuState};
 use crate::vstate::vm::VmState;
-use crate::{mem_size_mib, memory_snapshot, vstate, Error as VmmError, EventManager, Vmm};
+use crate::{
+    mem_size_mib, memory_snapshot, vstate, DirtyBitmap, Error as VmmError, EventManager, Vmm,
+};
 
 #[cfg(target_arch = "x86_64")]
 const FC_V0_23_MAX_DEVICES: u32 = 11;
 
+/// Source of the random values of the guest identities assigned on restore.
+const RANDOMNESS_POOL: &str = "/dev/urandom";
+
+/// Maximum number of guest memory copy rounds performed while the vCPUs are
+/// running, when creating a live snapshot or migrating the microVM.
+pub(crate) const LIVE_SNAPSHOT_MAX_PRECOPY_ROUNDS: usize = 8;
+/// Number of dirty pages under which the pre-copy phase of a live snapshot or
+/// migration is considered converged and the microVM gets paused for the final round.
+pub(crate) const LIVE_SNAPSHOT_DIRTY_PAGES_THRESHOLD: usize = 256;
+
 /// Holds information related to the VM that is not part of VmState.
 #[derive(Clone, Debug, Default, PartialEq, Eq, Versionize, Serialize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
@@ -103,7 +121,7 @@ impl VmInfo {
 }
 
 /// Contains the necesary state for saving/restoring a microVM.
-#[derive(Versionize)]
+#[derive(Serialize, Versionize)]
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct MicrovmState {
     /// Miscellaneous VM info.
@@ -158,6 +176,9 @@ pub enum MicrovmStateError {
     /// Failed to restore VM state.
     #[error("Cannot restore Vm state: {0:?}")]
     RestoreVmState(vstate::vm::Error),
+    /// Failed to save devices.
+    #[error("Cannot save devices: {0:?}")]
+    SaveDevices(DevicePersistError),
     /// Failed to save Vcpu state.
     #[error("Cannot save Vcpu state: {0:?}")]
     SaveVcpuState(vstate::vcpu::Error),
@@ -172,12 +193,26 @@ pub enum MicrovmStateError {
     UnexpectedVcpuResponse,
 }
 
+/// Errors associated with the pre-copy rounds of a live snapshot or a migration.
+#[derive(Debug, thiserror::Error)]
+pub enum PrecopyError {
+    /// Failed to get dirty bitmap.
+    #[error("Cannot get dirty bitmap: {0}")]
+    DirtyBitmap(VmmError),
+    /// Failed to count the dirty pages.
+    #[error("Cannot count the dirty pages: {0}")]
+    DirtyPages(memory_snapshot::Error),
+}
+
 /// Errors associated with creating a snapshot.
 #[derive(Debug, thiserror::Error)]
 pub enum CreateSnapshotError {
     /// Failed to get dirty bitmap.
     #[error("Cannot get dirty bitmap: {0}")]
     DirtyBitmap(VmmError),
+    /// Failed to copy guest memory while the microVM keeps running.
+    #[error("{0}")]
+    Precopy(#[from] PrecopyError),
     /// The virtio devices uses a features that is incompatible with older versions of Firecracker.
     #[error(
         "The virtio devices use a features that is incompatible with older versions of \
@@ -190,21 +225,39 @@ pub enum CreateSnapshotError {
     /// MicroVM version does not support snapshot.
     #[error("Cannot translate microVM version to snapshot data version")]
     UnsupportedVersion,
+    /// Failed to open the diff manifest file.
+    #[error("Cannot perform {0} on the diff manifest file: {1}")]
+    DiffManifestFile(&'static str, io::Error),
     /// Failed to write memory to snapshot.
     #[error("Cannot write memory file: {0}")]
     Memory(memory_snapshot::Error),
     /// Failed to open memory backing file.
     #[error("Cannot perform {0} on the memory backing file: {1}")]
     MemoryBackingFile(&'static str, io::Error),
+    /// Failed to pause the microVM for the final round of a live snapshot.
+    #[error("Cannot pause the microVM: {0}")]
+    PauseMicrovm(VmmError),
+    /// Failed to resume the microVM after the final round of a live snapshot.
+    #[error("Cannot resume the microVM: {0}")]
+    ResumeMicrovm(VmmError),
     /// Failed to save MicrovmState.
     #[error("Cannot save the microVM state: {0}")]
     MicrovmState(MicrovmStateError),
     /// Failed to serialize microVM state.
     #[error("Cannot serialize the microVM state: {0}")]
     SerializeMicrovmState(snapshot::Error),
+    /// Failed to serialize the diff manifest.
+    #[error("Cannot serialize the diff manifest: {0}")]
+    SerializeDiffManifest(snapshot::Error),
     /// Failed to open the snapshot backing file.
     #[error("Cannot perform {0} on the snapshot backing file: {1}")]
     SnapshotBackingFile(&'static str, io::Error),
+    /// The memory file format is not supported for the requested snapshot type.
+    #[error("Compressed memory files are only supported for full snapshots")]
+    UnsupportedMemFileFormat,
+    /// A diff manifest was requested for a snapshot type other than diff.
+    #[error("Diff manifests can only be created for diff snapshots")]
+    UnsupportedDiffManifest,
     /// Number of devices exceeds the maximum supported devices for the snapshot data version.
     #[cfg(target_arch = "x86_64")]
     #[error(
@@ -224,6 +277,23 @@ pub fn create_snapshot(
     // Fail early from invalid target version.
     let snapshot_data_version = get_snapshot_data_version(&params.version, &version_map, vmm)?;
 
+    // Diff and live snapshots write pages in place, which the compressed format does not allow.
+    if params.mem_file_format == MemFileFormat::Compressed
+        && params.snapshot_type != SnapshotType::Full
+    {
+        return Err(CreateSnapshotError::UnsupportedMemFileFormat);
+    }
+    if params.diff_manifest_path.is_some() && params.snapshot_type != SnapshotType::Diff {
+        return Err(CreateSnapshotError::UnsupportedDiffManifest);
+    }
+    // Fail before the live snapshots start copying the guest memory.
+    vmm.check_devices_persistable()
+        .map_err(CreateSnapshotError::MicrovmState)?;
+
+    if params.snapshot_type == SnapshotType::Live {
+        return create_live_snapshot(vmm, vm_info, params, snapshot_data_version, version_map);
+    }
+
     let microvm_state = vmm
         .save_state(vm_info)
         .map_err(CreateSnapshotError::MicrovmState)?;
@@ -235,7 +305,15 @@ pub fn create_snapshot(
         version_map,
     )?;
 
-    snapshot_memory_to_file(vmm, &params.mem_file_path, &params.snapshot_type)?;
+    match params.mem_file_format {
+        MemFileFormat::Raw => snapshot_memory_to_file(
+            vmm,
+            &params.mem_file_path,
+            &params.snapshot_type,
+            params.diff_manifest_path.as_deref(),
+        )?,
+        MemFileFormat::Compressed => compressed_memory_to_file(vmm, &params.mem_file_path)?,
+    }
 
     Ok(())
 }
@@ -266,13 +344,130 @@ fn snapshot_state_to_file(
         .map_err(|err| SnapshotBackingFile("sync_all", err))
 }
 
-fn snapshot_memory_to_file(
+/// Creates a full snapshot while the microVM keeps running.
+///
+/// Guest memory is first copied in its entirety and then, for a bounded number of
+/// rounds, only the pages dirtied during the previous round are copied again. Once
+/// the dirty set is small enough, the vCPUs are paused, the microVM state is saved
+/// and the remaining dirty pages are copied, after which the microVM is resumed.
+fn create_live_snapshot(
+    vmm: &mut Vmm,
+    vm_info: &VmInfo,
+    params: &CreateSnapshotParams,
+    snapshot_data_version: u16,
+    version_map: VersionMap,
+) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+    let mut file = open_memory_file(vmm, &params.mem_file_path)?;
+    let was_running = vmm.instance_info.state == InstanceVmState::Running;
+
+    let precopy_rounds = precopy_memory(vmm, |guest_memory, dirty_bitmap| {
+        match dirty_bitmap {
+            Some(dirty_bitmap) => guest_memory.dump_dirty(&mut file, dirty_bitmap),
+            None => guest_memory.dump(&mut file),
+        }
+        .map_err(Memory)
+    })?;
+    METRICS.snapshot.live_precopy_rounds.store(precopy_rounds);
+
+    let downtime_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+    if was_running {
+        vmm.pause_vm().map_err(PauseMicrovm)?;
+    }
+    // Always try to resume the microVM, even if the final round failed.
+    let final_round = live_snapshot_final_round(vmm, vm_info, &mut file);
+    if was_running {
+        vmm.resume_vm().map_err(ResumeMicrovm)?;
+    }
+    let microvm_state = final_round?;
+    let downtime_us = update_metric_with_elapsed_time(
+        &METRICS.latencies_us.vmm_live_snapshot_downtime,
+        downtime_start_us,
+    );
+    info!(
+        "Live snapshot: {} pre-copy rounds, microVM paused for {} us.",
+        precopy_rounds, downtime_us
+    );
+
+    snapshot_state_to_file(
+        &microvm_state,
+        &params.snapshot_path,
+        snapshot_data_version,
+        version_map,
+    )?;
+    sync_memory_file(&mut file)
+}
+
+/// Saves the microVM state and copies the pages dirtied since the last pre-copy
+/// round. The microVM needs to be paused.
+fn live_snapshot_final_round(
+    vmm: &mut Vmm,
+    vm_info: &VmInfo,
+    file: &mut File,
+) -> std::result::Result<MicrovmState, CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+    // Saving the device states drains the in-flight block I/O, which can still
+    // dirty guest memory, so it has to happen before fetching the last dirty set.
+    let microvm_state = vmm.save_state(vm_info).map_err(MicrovmState)?;
+    let (dirty_bitmap, dirty_pages) = fetch_dirty_pages(vmm)?;
+    METRICS.snapshot.live_final_dirty_pages.store(dirty_pages);
+    vmm.guest_memory()
+        .dump_dirty(file, &dirty_bitmap)
+        .map_err(Memory)?;
+    Ok(microvm_state)
+}
+
+/// Copies guest memory while the microVM keeps running, for a live snapshot or a
+/// migration.
+///
+/// `copy` is first called without a dirty bitmap, to copy guest memory in its entirety.
+/// Then, for a bounded number of rounds and until the dirty set is small enough, it is
+/// called with the pages dirtied during the previous round. Returns the number of rounds.
+pub(crate) fn precopy_memory<F, E>(vmm: &Vmm, mut copy: F) -> std::result::Result<usize, E>
+where
+    F: FnMut(&GuestMemoryMmap, Option<&DirtyBitmap>) -> std::result::Result<(), E>,
+    E: From<PrecopyError>,
+{
+    let was_running = vmm.instance_info.state == InstanceVmState::Running;
+
+    // Reset the dirty page logs, so that the next rounds only look at the pages
+    // dirtied after the full copy below has started.
+    vmm.get_dirty_bitmap().map_err(PrecopyError::DirtyBitmap)?;
+    vmm.guest_memory().reset_dirty();
+    copy(vmm.guest_memory(), None)?;
+
+    let mut precopy_rounds = 1;
+    // If the microVM is not running, nothing can dirty its memory anymore.
+    while was_running && precopy_rounds < LIVE_SNAPSHOT_MAX_PRECOPY_ROUNDS {
+        let (dirty_bitmap, dirty_pages) = fetch_dirty_pages(vmm)?;
+        copy(vmm.guest_memory(), Some(&dirty_bitmap))?;
+        precopy_rounds += 1;
+        if dirty_pages <= LIVE_SNAPSHOT_DIRTY_PAGES_THRESHOLD {
+            break;
+        }
+    }
+    Ok(precopy_rounds)
+}
+
+/// Fetches the pages dirtied since the previous copy round, along with their number.
+/// Copying the dirty pages marks them as clean, so they need to be counted first.
+pub(crate) fn fetch_dirty_pages(
+    vmm: &Vmm,
+) -> std::result::Result<(DirtyBitmap, usize), PrecopyError> {
+    let dirty_bitmap = vmm.get_dirty_bitmap().map_err(PrecopyError::DirtyBitmap)?;
+    let dirty_pages = vmm
+        .guest_memory()
+        .count_dirty(&dirty_bitmap)
+        .map_err(PrecopyError::DirtyPages)?;
+    Ok((dirty_bitmap, dirty_pages))
+}
+
+fn open_memory_file(
     vmm: &Vmm,
     mem_file_path: &Path,
-    snapshot_type: &SnapshotType,
-) -> std::result::Result<(), CreateSnapshotError> {
+) -> std::result::Result<File, CreateSnapshotError> {
     use self::CreateSnapshotError::*;
-    let mut file = OpenOptions::new()
+    let file = OpenOptions::new()
         .write(true)
         .create(true)
         .truncate(true)
@@ -283,20 +478,93 @@ fn snapshot_memory_to_file(
     let mem_size_mib = mem_size_mib(vmm.guest_memory());
     file.set_len((mem_size_mib * 1024 * 1024) as u64)
         .map_err(|err| MemoryBackingFile("set_length", err))?;
+    Ok(file)
+}
+
+fn sync_memory_file(file: &mut File) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+    file.flush()
+        .map_err(|err| MemoryBackingFile("flush", err))?;
+    file.sync_all()
+        .map_err(|err| MemoryBackingFile("sync_all", err))
+}
+
+fn snapshot_memory_to_file(
+    vmm: &Vmm,
+    mem_file_path: &Path,
+    snapshot_type: &SnapshotType,
+    diff_manifest_path: Option<&Path>,
+) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+    let mut file = open_memory_file(vmm, mem_file_path)?;
 
     match snapshot_type {
         SnapshotType::Diff => {
             let dirty_bitmap = vmm.get_dirty_bitmap().map_err(DirtyBitmap)?;
+            // The manifest has to be built before dumping the dirty pages, since
+            // that resets the Firecracker dirty page bitmap.
+            if let Some(manifest_path) = diff_manifest_path {
+                let manifest = vmm
+                    .guest_memory()
+                    .describe_dirty(&dirty_bitmap)
+                    .map_err(Memory)?;
+                diff_manifest_to_file(&manifest, manifest_path)?;
+            }
             vmm.guest_memory()
                 .dump_dirty(&mut file, &dirty_bitmap)
                 .map_err(Memory)
         }
-        SnapshotType::Full => vmm.guest_memory().dump(&mut file).map_err(Memory),
+        // Live snapshots copy their memory through `create_live_snapshot`.
+        SnapshotType::Full | SnapshotType::Live => {
+            vmm.guest_memory().dump(&mut file).map_err(Memory)
+        }
     }?;
-    file.flush()
-        .map_err(|err| MemoryBackingFile("flush", err))?;
-    file.sync_all()
-        .map_err(|err| MemoryBackingFile("sync_all", err))
+    sync_memory_file(&mut file)
+}
+
+fn diff_manifest_to_file(
+    manifest: &DiffManifest,
+    manifest_path: &Path,
+) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+    let mut manifest_file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(manifest_path)
+        .map_err(|err| DiffManifestFile("open", err))?;
+
+    manifest
+        .save(&mut manifest_file)
+        .map_err(SerializeDiffManifest)?;
+    manifest_file
+        .flush()
+        .map_err(|err| DiffManifestFile("flush", err))?;
+    manifest_file
+        .sync_all()
+        .map_err(|err| DiffManifestFile("sync_all", err))
+}
+
+fn compressed_memory_to_file(
+    vmm: &Vmm,
+    mem_file_path: &Path,
+) -> std::result::Result<(), CreateSnapshotError> {
+    use self::CreateSnapshotError::*;
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(mem_file_path)
+        .map_err(|err| MemoryBackingFile("open", err))?;
+
+    let mut writer = BufWriter::new(file);
+    vmm.guest_memory()
+        .dump_compressed(&mut writer)
+        .map_err(Memory)?;
+    let mut file = writer
+        .into_inner()
+        .map_err(|err| MemoryBackingFile("flush", err.into_error()))?;
+    sync_memory_file(&mut file)
 }
 
 /// Validate the microVM version and translate it to its corresponding snapshot data format.
@@ -482,15 +750,25 @@ pub enum RestoreFromSnapshotError {
     /// Invalid snapshot state.
     #[error("Invalid snapshot state: {0}")]
     Invalid(#[from] SnapShotStateSanityCheckError),
+    /// Invalid device overrides.
+    #[error("Invalid device overrides: {0}")]
+    DeviceOverrides(#[from] DeviceOverrideError),
+    /// Failed to assign a new guest identity.
+    #[error("Failed to assign a new guest identity: {0}")]
+    CloneIdentity(#[from] CloneIdentityError),
     /// Failed to load guest memory
     #[error("Failed to load guest memory: {0}")]
     GuestMemory(#[from] RestoreFromSnapshotGuestMemoryError),
     /// Failed to build microVM from snapshot.
     #[error("Failed to build microVM from snapshot: {0}")]
     Build(#[from] BuildMicrovmFromSnapshotError),
+    /// Failed to publish the new guest identity in MMDS.
+    #[error("Failed to publish the new guest identity in MMDS: {0}")]
+    PublishIdentity(mmds::data_store::Error),
 }
-/// Sub-Error type for [`restore_from_snapshot`] to contain either [`GuestMemoryFromFileError`] or
-/// [`GuestMemoryFromUffdError`] within [`RestoreFromSnapshotError`].
+/// Sub-Error type for [`restore_from_snapshot`] to contain either [`GuestMemoryFromFileError`],
+/// [`GuestMemoryFromUffdError`] or [`GuestMemoryFromLayersError`] within
+/// [`RestoreFromSnapshotError`].
 #[derive(Debug, thiserror::Error)]
 pub enum RestoreFromSnapshotGuestMemoryError {
     /// Error creating guest memory from file.
@@ -499,6 +777,9 @@ pub enum RestoreFromSnapshotGuestMemoryError {
     /// Error creating guest memory from uffd.
     #[error("Error creating guest memory from uffd: {0}")]
     Uffd(#[from] GuestMemoryFromUffdError),
+    /// Error creating guest memory from a chain of diff files.
+    #[error("Error creating guest memory from a chain of diff files: {0}")]
+    Layers(#[from] GuestMemoryFromLayersError),
 }
 
 /// Loads a Microvm snapshot producing a 'paused' Microvm.
@@ -510,11 +791,29 @@ pub fn restore_from_snapshot(
     version_map: VersionMap,
     vm_resources: &mut VmResources,
 ) -> std::result::Result<Arc<Mutex<Vmm>>, RestoreFromSnapshotError> {
-    let microvm_state = snapshot_state_from_file(&params.snapshot_path, version_map)?;
+    let mut microvm_state = snapshot_state_from_file(&params.snapshot_path, version_map)?;
 
     // Some sanity checks before building the microvm.
     snapshot_state_sanity_check(&microvm_state)?;
 
+    // The devices are restored with the host resources given by the user, if any.
+    microvm_state
+        .device_states
+        .apply_overrides(&params.device_overrides)?;
+
+    // Each restore of the snapshot gets its own guest identity, if requested.
+    let guest_identity = match &params.clone_identity {
+        Some(identity) => {
+            let mut entropy = File::open(RANDOMNESS_POOL).map_err(CloneIdentityError::Entropy)?;
+            Some(
+                microvm_state
+                    .device_states
+                    .assign_identity(identity, &mut entropy)?,
+            )
+        }
+        None => None,
+    };
+
     let mem_backend_path = &params.mem_backend.backend_path;
     let mem_state = &microvm_state.memory_state;
     let track_dirty_pages = params.enable_diff_snapshots;
@@ -534,8 +833,18 @@ pub fn restore_from_snapshot(
             microvm_state.device_states.balloon_device.is_some(),
         )
         .map_err(RestoreFromSnapshotGuestMemoryError::Uffd)?,
+        MemBackendType::Layered => (
+            guest_memory_from_layers(
+                mem_backend_path,
+                &params.mem_backend.diff_layers,
+                mem_state,
+                track_dirty_pages,
+            )
+            .map_err(RestoreFromSnapshotGuestMemoryError::Layers)?,
+            None,
+        ),
     };
-    builder::build_microvm_from_snapshot(
+    let vmm = builder::build_microvm_from_snapshot(
         instance_info,
         event_manager,
         microvm_state,
@@ -545,7 +854,36 @@ pub fn restore_from_snapshot(
         seccomp_filters,
         vm_resources,
     )
-    .map_err(RestoreFromSnapshotError::Build)
+    .map_err(RestoreFromSnapshotError::Build)?;
+
+    if let (Some(identity), Some(guest_identity)) = (&params.clone_identity, guest_identity) {
+        publish_guest_identity(vm_resources, identity.mmds_key.as_deref(), &guest_identity)
+            .map_err(RestoreFromSnapshotError::PublishIdentity)?;
+    }
+    Ok(vmm)
+}
+
+/// Invalidates the MMDS session tokens, which are shared by all the restores of
+/// a snapshot, and publishes the new guest identity under `mmds_key`, if given.
+fn publish_guest_identity(
+    vm_resources: &VmResources,
+    mmds_key: Option<&str>,
+    guest_identity: &GuestIdentity,
+) -> std::result::Result<(), mmds::data_store::Error> {
+    let mut mmds = match vm_resources.mmds.as_ref() {
+        Some(mmds) => mmds.lock().expect("Poisoned lock"),
+        None => return Ok(()),
+    };
+    mmds.regenerate_token_key()?;
+
+    if let Some(key) = mmds_key {
+        // The data store is usually empty at this point, so the identity is
+        // patched into it without requiring it to be initialized.
+        let mut data_store = mmds.data_store_value();
+        mmds::json_patch(&mut data_store, &serde_json::json!({ key: guest_identity }));
+        mmds.put_data(data_store)?;
+    }
+    Ok(())
 }
 
 /// Error type for [`snapshot_state_from_file`]
@@ -562,7 +900,8 @@ pub enum SnapshotStateFromFileError {
     Load(#[from] snapshot::Error),
 }
 
-fn snapshot_state_from_file(
+/// Loads the microVM state from the snapshot file at `snapshot_path`.
+pub fn snapshot_state_from_file(
     snapshot_path: &Path,
     version_map: VersionMap,
 ) -> std::result::Result<MicrovmState, SnapshotStateFromFileError> {
@@ -591,10 +930,114 @@ fn guest_memory_from_file(
     track_dirty_pages: bool,
 ) -> std::result::Result<GuestMemoryMmap, GuestMemoryFromFileError> {
     let mem_file = File::open(mem_file_path)?;
-    let guest_mem = GuestMemoryMmap::restore(Some(&mem_file), mem_state, track_dirty_pages)?;
+    let guest_mem = if is_compressed_memory_file(&mem_file)? {
+        // Compressed memory files cannot be mapped, so they are decoded into anonymous memory.
+        GuestMemoryMmap::restore_compressed(
+            &mut BufReader::new(mem_file),
+            mem_state,
+            track_dirty_pages,
+        )?
+    } else {
+        GuestMemoryMmap::restore(Some(&mem_file), mem_state, track_dirty_pages)?
+    };
     Ok(guest_mem)
 }
 
+/// Error type for [`guest_memory_from_layers`].
+#[derive(Debug, thiserror::Error)]
+pub enum GuestMemoryFromLayersError {
+    /// Failed to open a memory file.
+    #[error("Failed to open memory file {0:?}: {1}")]
+    Open(PathBuf, std::io::Error),
+    /// Failed to load the manifest of a diff memory file.
+    #[error("Failed to load diff manifest {0:?}: {1}")]
+    Manifest(PathBuf, snapshot::Error),
+    /// The base memory file cannot be mapped.
+    #[error("The base memory file is compressed and cannot be mapped")]
+    CompressedBase,
+    /// The base memory file does not match the guest memory size.
+    #[error("The base memory file is {0} bytes long instead of {1}")]
+    BaseSize(u64, u64),
+    /// Invalid chain of diff memory files.
+    #[error("Invalid chain of diff memory files: {0}")]
+    Chain(#[from] ChainError),
+    /// Failed to restore guest memory.
+    #[error("Failed to restore guest memory: {0}")]
+    Restore(#[from] crate::memory_snapshot::Error),
+}
+
+fn guest_memory_from_layers(
+    base_file_path: &Path,
+    diff_layers: &[MemLayerConfig],
+    mem_state: &GuestMemoryState,
+    track_dirty_pages: bool,
+) -> std::result::Result<GuestMemoryMmap, GuestMemoryFromLayersError> {
+    let open = |path: &Path| {
+        File::open(path).map_err(|err| GuestMemoryFromLayersError::Open(path.to_path_buf(), err))
+    };
+
+    let base_file = open(base_file_path)?;
+    let base_len = base_file
+        .metadata()
+        .map_err(|err| GuestMemoryFromLayersError::Open(base_file_path.to_path_buf(), err))?
+        .len();
+    if is_compressed_memory_file(&base_file)
+        .map_err(|err| GuestMemoryFromLayersError::Open(base_file_path.to_path_buf(), err))?
+    {
+        return Err(GuestMemoryFromLayersError::CompressedBase);
+    }
+    let mem_size: u64 = mem_state
+        .regions
+        .iter()
+        .map(|region| region.size as u64)
+        .sum();
+    if base_len != mem_size {
+        return Err(GuestMemoryFromLayersError::BaseSize(base_len, mem_size));
+    }
+
+    let mut layers = Vec::with_capacity(diff_layers.len());
+    for layer in diff_layers {
+        let diff_manifest = match layer.diff_manifest_path.as_deref() {
+            Some(manifest_path) => {
+                let mut manifest_file = open(manifest_path)?;
+                let manifest_len = manifest_file
+                    .metadata()
+                    .map_err(|err| {
+                        GuestMemoryFromLayersError::Open(manifest_path.to_path_buf(), err)
+                    })?
+                    .len();
+                Some(
+                    DiffManifest::load(&mut manifest_file, manifest_len as usize).map_err(
+                        |err| {
+                            GuestMemoryFromLayersError::Manifest(manifest_path.to_path_buf(), err)
+                        },
+                    )?,
+                )
+            }
+            None => None,
+        };
+        layers.push(MemoryLayer::new(
+            layer.mem_file_path.clone(),
+            open(&layer.mem_file_path)?,
+            diff_manifest,
+        ));
+    }
+
+    let regions: Vec<_> = mem_state
+        .regions
+        .iter()
+        .map(|region| (region.base_address, region.offset, region.size as u64))
+        .collect();
+    let mut chain = DiffChain::new(layers);
+    chain.validate_regions(&regions)?;
+    Ok(GuestMemoryMmap::restore_layered(
+        &base_file,
+        &mut chain,
+        mem_state,
+        track_dirty_pages,
+    )?)
+}
+
 /// Error type for [`guest_memory_from_uffd`]
 #[derive(Debug, thiserror::Error)]
 pub enum GuestMemoryFromUffdError {
@@ -720,7 +1163,6 @@ fn validate_fc_version_format(version: &str) -> Result<(), CreateSnapshotError>
 
 #[cfg(test)]
 mod tests {
-    use snapshot::Persist;
     use utils::errno;
     use utils::tempfile::TempFile;
 
@@ -735,7 +1177,7 @@ mod tests {
     use crate::version_map::{FC_VERSION_TO_SNAP_VERSION, VERSION_MAP};
     use crate::vmm_config::balloon::BalloonDeviceConfig;
     use crate::vmm_config::drive::CacheType;
-    use crate::vmm_config::net::NetworkInterfaceConfig;
+    use crate::vmm_config::net::{NetworkBackendType, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
     use crate::Vmm;
 
@@ -773,6 +1215,11 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            mtu: None,
+            anti_spoofing: None,
+            backend_type: NetworkBackendType::default(),
+            user_backend: None,
         };
         insert_net_device(
             &mut vmm,
@@ -794,7 +1241,7 @@ mod tests {
     #[test]
     fn test_microvmstate_versionize() {
         let vmm = default_vmm_with_devices();
-        let states = vmm.mmio_device_manager.save();
+        let states = vmm.mmio_device_manager.save().unwrap();
 
         // Only checking that all devices are saved, actual device state
         // is tested by that device's tests.
@@ -894,6 +1341,11 @@ mod tests {
         let err = DirtyBitmap(VmmError::DirtyBitmap(kvm_ioctls::Error::new(20)));
         let _ = format!("{}{:?}", err, err);
 
+        let err = Precopy(PrecopyError::DirtyPages(memory_snapshot::Error::PageSize(
+            errno::Error::new(0),
+        )));
+        let _ = format!("{}{:?}", err, err);
+
         let err = InvalidVersionFormat;
         let _ = format!("{}{:?}", err, err);
 
@@ -908,6 +1360,12 @@ mod tests {
         let err = MemoryBackingFile("open", io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
+        let err = PauseMicrovm(VmmError::VcpuPause);
+        let _ = format!("{}{:?}", err, err);
+
+        let err = ResumeMicrovm(VmmError::VcpuResume);
+        let _ = format!("{}{:?}", err, err);
+
         let err = MicrovmState(MicrovmStateError::UnexpectedVcpuResponse);
         let _ = format!("{}{:?}", err, err);
 
@@ -917,6 +1375,18 @@ mod tests {
         let err = SnapshotBackingFile("open", io::Error::from_raw_os_error(0));
         let _ = format!("{}{:?}", err, err);
 
+        let err = UnsupportedMemFileFormat;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = UnsupportedDiffManifest;
+        let _ = format!("{}{:?}", err, err);
+
+        let err = DiffManifestFile("open", io::Error::from_raw_os_error(0));
+        let _ = format!("{}{:?}", err, err);
+
+        let err = SerializeDiffManifest(snapshot::Error::InvalidMagic(0));
+        let _ = format!("{}{:?}", err, err);
+
         #[cfg(target_arch = "x86_64")]
         {
             let err = TooManyDevices(0);
@@ -943,6 +1413,9 @@ mod tests {
         let err = RestoreVmState(vstate::vm::Error::NotEnoughMemorySlots);
         let _ = format!("{}{:?}", err, err);
 
+        let err = SaveDevices(DevicePersistError::VhostUserBlock(String::from("vhost")));
+        let _ = format!("{}{:?}", err, err);
+
         let err = SaveVcpuState(vstate::vcpu::Error::VcpuTlsNotPresent);
         let _ = format!("{}{:?}", err, err);
 
@@ -300,6 +300,11 @@ impl VmResources {
             self.vm_config.track_dirty_pages = track_dirty_pages;
         }
 
+        // Update the number of hot-plug slots
+        if let Some(hotplug_slots) = machine_config.hotplug_slots {
+            self.vm_config.hotplug_slots = hotplug_slots;
+        }
+
         Ok(())
     }
 
@@ -399,6 +404,15 @@ impl VmResources {
         Ok(())
     }
 
+    /// Starts or stops the packet capture of a network interface to be attached when the VM
+    /// starts.
+    pub fn set_net_capture(
+        &mut self,
+        capture_cfg: &NetworkCaptureConfig,
+    ) -> Result<NetworkInterfaceError> {
+        self.net_builder.set_capture(capture_cfg)
+    }
+
     /// Sets a vsock device to be attached when the VM starts.
     pub fn set_vsock_device(&mut self, config: VsockDeviceConfig) -> Result<VsockConfigError> {
         self.vsock.insert(config)
@@ -508,9 +522,11 @@ mod tests {
     use crate::vmm_config::boot_source::{
         BootConfig, BootSource, BootSourceConfig, DEFAULT_KERNEL_CMDLINE,
     };
-    use crate::vmm_config::drive::{BlockBuilder, BlockDeviceConfig, FileEngineType};
+    use crate::vmm_config::drive::{
+        BlockBackendType, BlockBuilder, BlockDeviceConfig, FileEngineType, ImageFormat,
+    };
     use crate::vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig, VmConfigError};
-    use crate::vmm_config::net::{NetBuilder, NetworkInterfaceConfig};
+    use crate::vmm_config::net::{NetBuilder, NetworkBackendType, NetworkInterfaceConfig};
     use crate::vmm_config::vsock::tests::default_config;
     use crate::vmm_config::RateLimiterConfig;
     use crate::vstate::vcpu::VcpuConfig;
@@ -530,6 +546,11 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str("01:23:45:67:89:0a").unwrap()),
             rx_rate_limiter: Some(RateLimiterConfig::default()),
             tx_rate_limiter: Some(RateLimiterConfig::default()),
+            num_queue_pairs: 1,
+            mtu: None,
+            anti_spoofing: None,
+            backend_type: NetworkBackendType::default(),
+            user_backend: None,
         }
     }
 
@@ -552,6 +573,10 @@ mod tests {
                 is_read_only: false,
                 rate_limiter: Some(RateLimiterConfig::default()),
                 file_engine_type: FileEngineType::default(),
+                num_queues: 1,
+                image_format: ImageFormat::default(),
+                overlay_path_on_host: None,
+                backend_type: BlockBackendType::default(),
             },
             tmp_file,
         )
@@ -1285,6 +1310,7 @@ mod tests {
             smt: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
             track_dirty_pages: Some(false),
+            hotplug_slots: Some(2),
         };
 
         assert_ne!(
@@ -11,17 +11,22 @@ use seccompiler::BpfThreadMap;
 use serde_json::Value;
 #[cfg(test)]
 use tests::{
-    build_microvm_for_boot, create_snapshot, restore_from_snapshot, MockVmRes as VmResources,
-    MockVmm as Vmm,
+    build_microvm_for_boot, create_snapshot, hotplug_block_device, hotplug_net_device,
+    hotplug_vsock_device, receive_migration, restore_from_snapshot, send_migration, unplug_device,
+    MockVmRes as VmResources, MockVmm as Vmm,
 };
 
 use super::Error as VmmError;
 #[cfg(not(test))]
 use super::{
-    builder::build_microvm_for_boot, persist::create_snapshot, persist::restore_from_snapshot,
+    builder::build_microvm_for_boot, hotplug::hotplug_block_device, hotplug::hotplug_net_device,
+    hotplug::hotplug_vsock_device, hotplug::unplug_device, migration::receive_migration,
+    migration::send_migration, persist::create_snapshot, persist::restore_from_snapshot,
     resources::VmResources, Vmm,
 };
 use crate::builder::StartMicrovmError;
+use crate::hotplug::HotplugError;
+use crate::migration::{ReceiveMigrationError, SendMigrationError};
 use crate::persist::{CreateSnapshotError, RestoreFromSnapshotError, VmInfo};
 use crate::resources::VmmConfig;
 use crate::version_map::VERSION_MAP;
@@ -31,13 +36,16 @@ use crate::vmm_config::balloon::{
 };
 use crate::vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError};
 use crate::vmm_config::drive::{BlockDeviceConfig, BlockDeviceUpdateConfig, DriveError};
+use crate::vmm_config::hotplug::UnplugDeviceConfig;
 use crate::vmm_config::instance_info::InstanceInfo;
 use crate::vmm_config::logger::{LoggerConfig, LoggerConfigError};
 use crate::vmm_config::machine_config::{VmConfig, VmConfigError, VmUpdateConfig};
 use crate::vmm_config::metrics::{MetricsConfig, MetricsConfigError};
+use crate::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
 use crate::vmm_config::mmds::{MmdsConfig, MmdsConfigError};
 use crate::vmm_config::net::{
-    NetworkInterfaceConfig, NetworkInterfaceError, NetworkInterfaceUpdateConfig,
+    NetworkCaptureConfig, NetworkInterfaceConfig, NetworkInterfaceError,
+    NetworkInterfaceUpdateConfig,
 };
 use crate::vmm_config::snapshot::{CreateSnapshotParams, LoadSnapshotParams, SnapshotType};
 use crate::vmm_config::vsock::{VsockConfigError, VsockDeviceConfig};
@@ -58,7 +66,8 @@ pub enum VmmAction {
     /// before the microVM has booted.
     ConfigureMetrics(MetricsConfig),
     /// Create a snapshot using as input the `CreateSnapshotParams`. This action can only be called
-    /// after the microVM has booted and only when the microVM is in `Paused` state.
+    /// after the microVM has booted and only when the microVM is in `Paused` state, unless a
+    /// `Live` snapshot is requested.
     CreateSnapshot(CreateSnapshotParams),
     /// Get the balloon device configuration.
     GetBalloonConfig,
@@ -77,11 +86,11 @@ pub enum VmmAction {
     /// Flush the metrics. This action can only be called after the logger has been configured.
     FlushMetrics,
     /// Add a new block device or update one that already exists using the `BlockDeviceConfig` as
-    /// input. This action can only be called before the microVM has booted.
+    /// input. After the microVM has booted, this action hot-plugs a new block device.
     InsertBlockDevice(BlockDeviceConfig),
     /// Add a new network interface config or update one that already exists using the
-    /// `NetworkInterfaceConfig` as input. This action can only be called before the microVM has
-    /// booted.
+    /// `NetworkInterfaceConfig` as input. After the microVM has booted, this action hot-plugs a
+    /// new network interface.
     InsertNetworkDevice(NetworkInterfaceConfig),
     /// Load the microVM state using as input the `LoadSnapshotParams`. This action can only be
     /// called before the microVM has booted. If this action is successful, the loaded microVM will
@@ -93,17 +102,27 @@ pub enum VmmAction {
     Pause,
     /// Repopulate the MMDS contents.
     PutMMDS(Value),
+    /// Wait for a microVM sent by another Firecracker process, using as input the
+    /// `ReceiveMigrationParams`. This action can only be called before the microVM has booted.
+    ReceiveMigration(ReceiveMigrationParams),
     /// Resume the guest, by resuming the microVM VCPUs.
     Resume,
+    /// Send the running microVM to another Firecracker process, using as input the
+    /// `SendMigrationParams`. This action can only be called after the microVM has booted. If
+    /// this action is successful, the microVM is left in `Paused` state.
+    SendMigration(SendMigrationParams),
     /// Set the balloon device or update the one that already exists using the
     /// `BalloonDeviceConfig` as input. This action can only be called before the microVM
     /// has booted.
     SetBalloonDevice(BalloonDeviceConfig),
     /// Set the MMDS configuration.
     SetMmdsConfiguration(MmdsConfig),
+    /// Start or stop writing the frames going through a network interface to a pcap file, using
+    /// the `NetworkCaptureConfig` as input.
+    SetNetworkCapture(NetworkCaptureConfig),
     /// Set the vsock device or update the one that already exists using the
-    /// `VsockDeviceConfig` as input. This action can only be called before the microVM has
-    /// booted.
+    /// `VsockDeviceConfig` as input. After the microVM has booted, this action hot-plugs the
+    /// vsock device.
     SetVsockDevice(VsockDeviceConfig),
     /// Launch the microVM. This action can only be called before the microVM has booted.
     StartMicroVm,
@@ -111,6 +130,9 @@ pub enum VmmAction {
     /// driver is listening on the guest end, this can be used to shut down the microVM gracefully.
     #[cfg(target_arch = "x86_64")]
     SendCtrlAltDel,
+    /// Unplug a hot-plugged device, using the `UnplugDeviceConfig` as input. This action can only
+    /// be called after the microVM has booted.
+    UnplugDevice(UnplugDeviceConfig),
     /// Update the balloon size, after microVM start.
     UpdateBalloon(BalloonUpdateConfig),
     /// Update the balloon statistics polling interval, after microVM start.
@@ -137,6 +159,8 @@ pub enum VmmActionError {
     /// One of the actions `InsertBlockDevice` or `UpdateBlockDevicePath`
     /// failed because of bad user input.
     DriveConfig(DriveError),
+    /// Hot-plugging or hot-unplugging a device failed.
+    Hotplug(HotplugError),
     /// Internal Vmm error.
     InternalVmm(VmmError),
     /// Loading a microVM snapshot failed.
@@ -164,6 +188,10 @@ pub enum VmmActionError {
     OperationNotSupportedPostBoot,
     /// The requested operation is not supported before starting the microVM.
     OperationNotSupportedPreBoot,
+    /// The action `ReceiveMigration` failed.
+    ReceiveMigration(ReceiveMigrationError),
+    /// The action `SendMigration` failed.
+    SendMigration(SendMigrationError),
     /// The action `StartMicroVm` failed because of an internal error.
     StartMicrovm(StartMicrovmError),
     /// The action `SetVsockDevice` failed because of bad user input.
@@ -182,6 +210,7 @@ impl Display for VmmActionError {
                 BootSource(err) => err.to_string(),
                 CreateSnapshot(err) => err.to_string(),
                 DriveConfig(err) => err.to_string(),
+                Hotplug(err) => format!("Device hot-plug error: {}", err),
                 InternalVmm(err) => format!("Internal Vmm error: {}", err),
                 LoadSnapshot(err) => format!("Load microVM snapshot error: {}", err),
                 Logger(err) => err.to_string(),
@@ -200,6 +229,8 @@ impl Display for VmmActionError {
                     "The requested operation is not supported before starting the microVM."
                         .to_string()
                 }
+                ReceiveMigration(err) => format!("Receive microVM migration error: {}", err),
+                SendMigration(err) => format!("Send microVM migration error: {}", err),
                 StartMicrovm(err) => err.to_string(),
                 // The action `SetVsockDevice` failed because of bad user input.
                 VsockConfig(err) => err.to_string(),
@@ -428,9 +459,13 @@ impl<'a> PrebootApiController<'a> {
                 .map_err(VmmActionError::LoadSnapshot),
             PatchMMDS(value) => self.patch_mmds(value),
             PutMMDS(value) => self.put_mmds(value),
+            ReceiveMigration(config) => self
+                .receive_migration(&config)
+                .map_err(VmmActionError::ReceiveMigration),
             SetBalloonDevice(config) => self.set_balloon_device(config),
             SetVsockDevice(config) => self.set_vsock_device(config),
             SetMmdsConfiguration(config) => self.set_mmds_config(config),
+            SetNetworkCapture(config) => self.set_net_capture(&config),
             StartMicroVm => self.start_microvm(),
             UpdateVmConfiguration(config) => self.update_vm_config(config),
             // Operations not allowed pre-boot.
@@ -438,9 +473,11 @@ impl<'a> PrebootApiController<'a> {
             | FlushMetrics
             | Pause
             | Resume
+            | SendMigration(_)
             | GetBalloonStats
             | UpdateBalloon(_)
             | UpdateBalloonStatistics(_)
+            | UnplugDevice(_)
             | UpdateBlockDevice(_)
             | UpdateNetworkInterface(_) => Err(VmmActionError::OperationNotSupportedPreBoot),
             #[cfg(target_arch = "x86_64")]
@@ -488,6 +525,14 @@ impl<'a> PrebootApiController<'a> {
             .map_err(VmmActionError::BootSource)
     }
 
+    fn set_net_capture(&mut self, cfg: &NetworkCaptureConfig) -> ActionResult {
+        self.boot_path = true;
+        self.vm_resources
+            .set_net_capture(cfg)
+            .map(|()| VmmData::Empty)
+            .map_err(VmmActionError::NetworkConfig)
+    }
+
     fn set_mmds_config(&mut self, cfg: MmdsConfig) -> ActionResult {
         self.boot_path = true;
         self.vm_resources
@@ -589,6 +634,53 @@ impl<'a> PrebootApiController<'a> {
 
         Ok(VmmData::Empty)
     }
+
+    // On success, this command will end the pre-boot stage and this controller
+    // will be replaced by a runtime controller.
+    fn receive_migration(
+        &mut self,
+        params: &ReceiveMigrationParams,
+    ) -> std::result::Result<VmmData, ReceiveMigrationError> {
+        let receive_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+
+        if self.boot_path {
+            let err = ReceiveMigrationError::ReceiveMigrationNotAllowed;
+            info!("{}", err);
+            return Err(err);
+        }
+
+        if params.enable_diff_snapshots {
+            self.vm_resources.set_track_dirty_pages(true);
+        }
+
+        let vmm = receive_migration(
+            &self.instance_info,
+            self.event_manager,
+            self.seccomp_filters,
+            params,
+            VERSION_MAP.clone(),
+            self.vm_resources,
+        )
+        .map_err(|err| {
+            // If the microVM was partially built, the process is too dirty to recover.
+            if err.is_fatal() {
+                self.fatal_error = Some(FcExitCode::BadConfiguration);
+            }
+            err
+        })?;
+        self.built_vmm = Some(vmm);
+
+        let elapsed_time_us = update_metric_with_elapsed_time(
+            &METRICS.latencies_us.vmm_receive_migration,
+            receive_start_us,
+        );
+        info!(
+            "'receive migration' VMM action took {} us.",
+            elapsed_time_us
+        );
+
+        Ok(VmmData::Empty)
+    }
 }
 
 /// Enables RPC interaction with a running Firecracker VMM.
@@ -636,12 +728,18 @@ impl RuntimeApiController {
             GetVmmVersion => Ok(VmmData::VmmVersion(
                 self.vmm.lock().expect("Poisoned lock").version(),
             )),
+            InsertBlockDevice(config) => self.hotplug(hotplug_block_device, config),
+            InsertNetworkDevice(config) => self.hotplug(hotplug_net_device, config),
             PatchMMDS(value) => self.patch_mmds(value),
             Pause => self.pause(),
             PutMMDS(value) => self.put_mmds(value),
             Resume => self.resume(),
             #[cfg(target_arch = "x86_64")]
             SendCtrlAltDel => self.send_ctrl_alt_del(),
+            SendMigration(send_migration_cfg) => self.send_migration(&send_migration_cfg),
+            SetNetworkCapture(config) => self.set_net_capture(&config),
+            SetVsockDevice(config) => self.hotplug(hotplug_vsock_device, config),
+            UnplugDevice(config) => self.hotplug(unplug_device, config),
             UpdateBalloon(balloon_update) => self
                 .vmm
                 .lock()
@@ -657,17 +755,15 @@ impl RuntimeApiController {
                 .map(|_| VmmData::Empty)
                 .map_err(|err| VmmActionError::BalloonConfig(BalloonConfigError::from(err))),
             UpdateBlockDevice(new_cfg) => self.update_block_device(new_cfg),
-            UpdateNetworkInterface(netif_update) => self.update_net_rate_limiters(netif_update),
+            UpdateNetworkInterface(netif_update) => self.update_net_interface(netif_update),
 
             // Operations not allowed post-boot.
             ConfigureBootSource(_)
             | ConfigureLogger(_)
             | ConfigureMetrics(_)
-            | InsertBlockDevice(_)
-            | InsertNetworkDevice(_)
             | LoadSnapshot(_)
+            | ReceiveMigration(_)
             | SetBalloonDevice(_)
-            | SetVsockDevice(_)
             | SetMmdsConfiguration(_)
             | StartMicroVm
             | UpdateVmConfiguration(_) => Err(VmmActionError::OperationNotSupportedPostBoot),
@@ -705,6 +801,18 @@ impl RuntimeApiController {
         Ok(VmmData::Empty)
     }
 
+    /// Plugs a device into, or unplugs it from, the running microVM.
+    fn hotplug<C>(
+        &mut self,
+        hotplug_fn: fn(&mut Vmm, &mut VmResources, C) -> result::Result<(), HotplugError>,
+        config: C,
+    ) -> ActionResult {
+        let mut locked_vmm = self.vmm.lock().expect("Poisoned lock");
+        hotplug_fn(&mut locked_vmm, &mut self.vm_resources, config)
+            .map(|()| VmmData::Empty)
+            .map_err(VmmActionError::Hotplug)
+    }
+
     /// Write the metrics on user demand (flush). We use the word `flush` here to highlight the fact
     /// that the metrics will be written immediately.
     /// Defer to inner Vmm. We'll move to a variant where the Vmm simply exposes functionality like
@@ -742,6 +850,15 @@ impl RuntimeApiController {
             ));
         }
 
+        if create_params.snapshot_type == SnapshotType::Live
+            && !self.vm_resources.track_dirty_pages()
+        {
+            return Err(VmmActionError::NotSupported(
+                "Live snapshots are not allowed on uVMs with dirty page tracking disabled."
+                    .to_string(),
+            ));
+        }
+
         let mut locked_vmm = self.vmm.lock().unwrap();
         let vm_cfg = self.vm_resources.vm_config();
         let vm_info = VmInfo {
@@ -780,13 +897,53 @@ impl RuntimeApiController {
                     elapsed_time_us
                 );
             }
+            SnapshotType::Live => {
+                let elapsed_time_us = update_metric_with_elapsed_time(
+                    &METRICS.latencies_us.vmm_live_create_snapshot,
+                    create_start_us,
+                );
+                info!(
+                    "'create live snapshot' VMM action took {} us.",
+                    elapsed_time_us
+                );
+            }
         }
         Ok(VmmData::Empty)
     }
 
+    fn send_migration(&mut self, params: &SendMigrationParams) -> ActionResult {
+        if !self.vm_resources.track_dirty_pages() {
+            return Err(VmmActionError::NotSupported(
+                "Live migration is not allowed on uVMs with dirty page tracking disabled."
+                    .to_string(),
+            ));
+        }
+
+        let mut locked_vmm = self.vmm.lock().unwrap();
+        let vm_cfg = self.vm_resources.vm_config();
+        let vm_info = VmInfo {
+            mem_size_mib: vm_cfg.mem_size_mib as u64,
+            smt: vm_cfg.smt,
+            cpu_template: vm_cfg.cpu_template,
+            boot_source: self.vm_resources.boot_source_config().clone(),
+        };
+        let send_start_us = utils::time::get_time_us(utils::time::ClockType::Monotonic);
+
+        send_migration(&mut locked_vmm, &vm_info, params, VERSION_MAP.clone())?;
+
+        let elapsed_time_us = update_metric_with_elapsed_time(
+            &METRICS.latencies_us.vmm_send_migration,
+            send_start_us,
+        );
+        info!("'send migration' VMM action took {} us.", elapsed_time_us);
+        Ok(VmmData::Empty)
+    }
+
     /// Updates block device properties:
     ///  - path of the host file backing the emulated block device, update the disk image on the
     ///    device and its virtio configuration
+    ///  - size of the host file backing the emulated block device, update the capacity in its
+    ///    virtio configuration
     ///  - rate limiter configuration.
     fn update_block_device(&mut self, new_cfg: BlockDeviceUpdateConfig) -> ActionResult {
         let mut vmm = self.vmm.lock().expect("Poisoned lock");
@@ -795,6 +952,10 @@ impl RuntimeApiController {
                 .map(|()| VmmData::Empty)
                 .map_err(DriveError::DeviceUpdate)?;
         }
+        if new_cfg.refresh_size {
+            vmm.update_block_device_size(&new_cfg.drive_id)
+                .map_err(DriveError::DeviceUpdate)?;
+        }
         if new_cfg.rate_limiter.is_some() {
             vmm.update_block_rate_limiter(
                 &new_cfg.drive_id,
@@ -807,22 +968,43 @@ impl RuntimeApiController {
         Ok(VmmData::Empty)
     }
 
-    /// Updates configuration for an emulated net device as described in `new_cfg`.
-    fn update_net_rate_limiters(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
-        self.vmm
-            .lock()
-            .expect("Poisoned lock")
-            .update_net_rate_limiters(
-                &new_cfg.iface_id,
-                RateLimiterUpdate::from(new_cfg.rx_rate_limiter).bandwidth,
-                RateLimiterUpdate::from(new_cfg.rx_rate_limiter).ops,
-                RateLimiterUpdate::from(new_cfg.tx_rate_limiter).bandwidth,
-                RateLimiterUpdate::from(new_cfg.tx_rate_limiter).ops,
-            )
+    /// Starts or stops the packet capture of an emulated net device.
+    fn set_net_capture(&mut self, cfg: &NetworkCaptureConfig) -> ActionResult {
+        let mut vmm = self.vmm.lock().expect("Poisoned lock");
+        // Don't create the capture file of a missing device.
+        if !vmm.has_net_device(&cfg.iface_id) {
+            return Err(VmmActionError::NetworkConfig(
+                NetworkInterfaceError::DeviceNotFound(cfg.iface_id.clone()),
+            ));
+        }
+        let capture = cfg.open_capture().map_err(VmmActionError::NetworkConfig)?;
+        vmm.update_net_capture(&cfg.iface_id, capture)
             .map(|()| VmmData::Empty)
             .map_err(NetworkInterfaceError::DeviceUpdate)
             .map_err(VmmActionError::NetworkConfig)
     }
+
+    /// Updates configuration for an emulated net device as described in `new_cfg`:
+    ///  - link state reported to the guest, which is notified of the change
+    ///  - rate limiters configuration.
+    fn update_net_interface(&mut self, new_cfg: NetworkInterfaceUpdateConfig) -> ActionResult {
+        let mut vmm = self.vmm.lock().expect("Poisoned lock");
+        if let Some(link_up) = new_cfg.link_up {
+            vmm.update_net_link_state(&new_cfg.iface_id, link_up)
+                .map_err(NetworkInterfaceError::DeviceUpdate)
+                .map_err(VmmActionError::NetworkConfig)?;
+        }
+        vmm.update_net_rate_limiters(
+            &new_cfg.iface_id,
+            RateLimiterUpdate::from(new_cfg.rx_rate_limiter).bandwidth,
+            RateLimiterUpdate::from(new_cfg.rx_rate_limiter).ops,
+            RateLimiterUpdate::from(new_cfg.tx_rate_limiter).bandwidth,
+            RateLimiterUpdate::from(new_cfg.tx_rate_limiter).ops,
+        )
+        .map(|()| VmmData::Empty)
+        .map_err(NetworkInterfaceError::DeviceUpdate)
+        .map_err(VmmActionError::NetworkConfig)
+    }
 }
 
 #[cfg(test)]
@@ -833,12 +1015,16 @@ mod tests {
     use devices::virtio::VsockError;
     use mmds::data_store::MmdsVersion;
     use seccompiler::BpfThreadMap;
+    use utils::tempfile::TempFile;
 
     use super::*;
     use crate::vmm_config::balloon::BalloonBuilder;
-    use crate::vmm_config::drive::{CacheType, FileEngineType};
+    use crate::vmm_config::drive::{BlockBackendType, CacheType, FileEngineType, ImageFormat};
     use crate::vmm_config::logger::LoggerLevel;
-    use crate::vmm_config::snapshot::{MemBackendConfig, MemBackendType};
+    use crate::vmm_config::net::{NetworkBackendType, NetworkCaptureState};
+    use crate::vmm_config::snapshot::{
+        DeviceOverrides, MemBackendConfig, MemBackendType, MemFileFormat,
+    };
     use crate::vmm_config::vsock::VsockBuilder;
     use crate::HTTP_MAX_PAYLOAD_SIZE;
 
@@ -851,6 +1037,7 @@ mod tests {
                     | (BootSource(_), BootSource(_))
                     | (CreateSnapshot(_), CreateSnapshot(_))
                     | (DriveConfig(_), DriveConfig(_))
+                    | (Hotplug(_), Hotplug(_))
                     | (InternalVmm(_), InternalVmm(_))
                     | (LoadSnapshot(_), LoadSnapshot(_))
                     | (Logger(_), Logger(_))
@@ -863,6 +1050,8 @@ mod tests {
                     | (NotSupported(_), NotSupported(_))
                     | (OperationNotSupportedPostBoot, OperationNotSupportedPostBoot)
                     | (OperationNotSupportedPreBoot, OperationNotSupportedPreBoot)
+                    | (ReceiveMigration(_), ReceiveMigration(_))
+                    | (SendMigration(_), SendMigration(_))
                     | (StartMicrovm(_), StartMicrovm(_))
                     | (VsockConfig(_), VsockConfig(_))
             )
@@ -882,6 +1071,7 @@ mod tests {
         block_set: bool,
         vsock_set: bool,
         net_set: bool,
+        net_capture_set: bool,
         pub mmds: Option<Arc<Mutex<Mmds>>>,
         pub mmds_size_limit: usize,
         pub boot_timer: bool,
@@ -975,6 +1165,17 @@ mod tests {
             Ok(())
         }
 
+        pub fn set_net_capture(
+            &mut self,
+            _: &NetworkCaptureConfig,
+        ) -> Result<(), NetworkInterfaceError> {
+            if self.force_errors {
+                return Err(NetworkInterfaceError::DeviceNotFound(String::new()));
+            }
+            self.net_capture_set = true;
+            Ok(())
+        }
+
         pub fn set_vsock_device(&mut self, _: VsockDeviceConfig) -> Result<(), VsockConfigError> {
             if self.force_errors {
                 return Err(VsockConfigError::CreateVsockDevice(
@@ -1033,7 +1234,12 @@ mod tests {
         pub update_balloon_config_called: bool,
         pub update_balloon_stats_config_called: bool,
         pub update_block_device_path_called: bool,
+        pub update_block_device_size_called: bool,
         pub update_net_rate_limiters_called: bool,
+        pub update_net_link_state_called: bool,
+        pub update_net_capture_called: bool,
+        pub hotplug_device_called: bool,
+        pub unplug_device_called: bool,
         // when `true`, all self methods are forced to fail
         pub force_errors: bool,
     }
@@ -1108,6 +1314,16 @@ mod tests {
             Ok(())
         }
 
+        pub fn update_block_device_size(&mut self, _: &str) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_block_device_size_called = true;
+            Ok(())
+        }
+
         pub fn update_block_rate_limiter(
             &mut self,
             _: &str,
@@ -1134,6 +1350,34 @@ mod tests {
             Ok(())
         }
 
+        pub fn update_net_link_state(&mut self, _: &str, _: bool) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_net_link_state_called = true;
+            Ok(())
+        }
+
+        pub fn has_net_device(&self, net_id: &str) -> bool {
+            net_id != "missing"
+        }
+
+        pub fn update_net_capture(
+            &mut self,
+            _: &str,
+            _: Option<devices::virtio::net::PacketCapture>,
+        ) -> Result<(), VmmError> {
+            if self.force_errors {
+                return Err(VmmError::DeviceManager(
+                    crate::device_manager::mmio::Error::IncorrectDeviceType,
+                ));
+            }
+            self.update_net_capture_called = true;
+            Ok(())
+        }
+
         pub fn instance_info(&self) -> InstanceInfo {
             InstanceInfo::default()
         }
@@ -1178,6 +1422,76 @@ mod tests {
         Ok(Arc::new(Mutex::new(MockVmm::default())))
     }
 
+    // Need to redefine this since the non-test one uses real Vmm
+    // instead of our mocks.
+    pub fn send_migration(
+        _: &mut Vmm,
+        _: &VmInfo,
+        _: &SendMigrationParams,
+        _: versionize::VersionMap,
+    ) -> std::result::Result<(), SendMigrationError> {
+        Ok(())
+    }
+
+    fn mock_hotplug(vmm: &mut Vmm) -> std::result::Result<(), HotplugError> {
+        if vmm.force_errors {
+            return Err(HotplugError::RootBlockDevice);
+        }
+        vmm.hotplug_device_called = true;
+        Ok(())
+    }
+
+    // Need to redefine these since the non-test ones use real VmResources
+    // and real Vmm instead of our mocks.
+    pub fn hotplug_block_device(
+        vmm: &mut Vmm,
+        _: &mut MockVmRes,
+        _: BlockDeviceConfig,
+    ) -> std::result::Result<(), HotplugError> {
+        mock_hotplug(vmm)
+    }
+
+    pub fn hotplug_net_device(
+        vmm: &mut Vmm,
+        _: &mut MockVmRes,
+        _: NetworkInterfaceConfig,
+    ) -> std::result::Result<(), HotplugError> {
+        mock_hotplug(vmm)
+    }
+
+    pub fn hotplug_vsock_device(
+        vmm: &mut Vmm,
+        _: &mut MockVmRes,
+        _: VsockDeviceConfig,
+    ) -> std::result::Result<(), HotplugError> {
+        mock_hotplug(vmm)
+    }
+
+    pub fn unplug_device(
+        vmm: &mut Vmm,
+        _: &mut MockVmRes,
+        _: UnplugDeviceConfig,
+    ) -> std::result::Result<(), HotplugError> {
+        if vmm.force_errors {
+            return Err(HotplugError::RootBlockDevice);
+        }
+        vmm.unplug_device_called = true;
+        Ok(())
+    }
+
+    // Need to redefine this since the non-test one uses real VmResources
+    // and real Vmm instead of our mocks.
+    pub fn receive_migration(
+        _: &InstanceInfo,
+        _: &mut EventManager,
+        _: &BpfThreadMap,
+        _: &ReceiveMigrationParams,
+        _: versionize::VersionMap,
+        _: &mut MockVmRes,
+    ) -> Result<Arc<Mutex<Vmm>>, ReceiveMigrationError> {
+        Ok(Arc::new(Mutex::new(MockVmm::default())))
+    }
+
     fn default_preboot<'a>(
         vm_resources: &'a mut VmResources,
         event_manager: &'a mut EventManager,
@@ -1316,6 +1630,10 @@ mod tests {
             drive_id: String::new(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1331,6 +1649,10 @@ mod tests {
             drive_id: String::new(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         });
         check_preboot_request_err(
             req,
@@ -1346,6 +1668,11 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            mtu: None,
+            anti_spoofing: None,
+            backend_type: NetworkBackendType::default(),
+            user_backend: None,
         });
         check_preboot_request(req, |result, vm_res| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1358,6 +1685,11 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            mtu: None,
+            anti_spoofing: None,
+            backend_type: NetworkBackendType::default(),
+            user_backend: None,
         });
         check_preboot_request_err(
             req,
@@ -1367,6 +1699,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_preboot_set_net_capture() {
+        let req = VmmAction::SetNetworkCapture(NetworkCaptureConfig {
+            iface_id: String::new(),
+            state: NetworkCaptureState::Stopped,
+            path_on_host: None,
+            max_bytes: None,
+            max_packets: None,
+        });
+        check_preboot_request(req, |result, vm_res| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vm_res.net_capture_set)
+        });
+
+        let req = VmmAction::SetNetworkCapture(NetworkCaptureConfig {
+            iface_id: String::new(),
+            state: NetworkCaptureState::Stopped,
+            path_on_host: None,
+            max_bytes: None,
+            max_packets: None,
+        });
+        check_preboot_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceNotFound(String::new())),
+        );
+    }
+
     #[test]
     fn test_preboot_set_vsock_dev() {
         let req = VmmAction::SetVsockDevice(VsockDeviceConfig {
@@ -1649,9 +2008,12 @@ mod tests {
             mem_backend: MemBackendConfig {
                 backend_type: MemBackendType::File,
                 backend_path: PathBuf::new(),
+                diff_layers: vec![],
             },
             enable_diff_snapshots: false,
             resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            clone_identity: None,
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1665,9 +2027,12 @@ mod tests {
             mem_backend: MemBackendConfig {
                 backend_type: MemBackendType::File,
                 backend_path: PathBuf::new(),
+                diff_layers: vec![],
             },
             enable_diff_snapshots: false,
             resume_vm: true,
+            device_overrides: DeviceOverrides::default(),
+            clone_identity: None,
         });
         // Request should succeed.
         preboot.handle_preboot_request(req).unwrap();
@@ -1678,12 +2043,56 @@ mod tests {
         assert!(!vmm.pause_called);
     }
 
+    #[test]
+    fn test_preboot_receive_migration() {
+        let mut vm_resources = MockVmRes::default();
+        let mut evmgr = EventManager::new().unwrap();
+        let seccomp_filters = BpfThreadMap::new();
+        let mut preboot = default_preboot(&mut vm_resources, &mut evmgr, &seccomp_filters);
+
+        let req = VmmAction::ReceiveMigration(ReceiveMigrationParams {
+            socket_path: Some(PathBuf::new()),
+            socket_fd: None,
+            enable_diff_snapshots: true,
+            resume_vm: false,
+        });
+        // Request should succeed.
+        preboot.handle_preboot_request(req).unwrap();
+        // Should have built default mock vmm.
+        let vmm = preboot.built_vmm.take().unwrap();
+        assert_eq!(*vmm.lock().unwrap(), MockVmm::default());
+        assert!(vm_resources.track_dirty_pages());
+
+        // Receiving a migration is not allowed after configuring boot-specific resources.
+        let mut vm_resources = MockVmRes::default();
+        let mut preboot = default_preboot(&mut vm_resources, &mut evmgr, &seccomp_filters);
+        preboot
+            .handle_preboot_request(VmmAction::ConfigureBootSource(BootSourceConfig::default()))
+            .unwrap();
+        let req = VmmAction::ReceiveMigration(ReceiveMigrationParams {
+            socket_path: Some(PathBuf::new()),
+            socket_fd: None,
+            enable_diff_snapshots: false,
+            resume_vm: false,
+        });
+        assert!(matches!(
+            preboot.handle_preboot_request(req),
+            Err(VmmActionError::ReceiveMigration(
+                ReceiveMigrationError::ReceiveMigrationNotAllowed
+            ))
+        ));
+    }
+
     #[test]
     fn test_preboot_disallowed() {
         check_preboot_request_err(
             VmmAction::FlushMetrics,
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::UnplugDevice(UnplugDeviceConfig::Vsock),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         check_preboot_request_err(
             VmmAction::Pause,
             VmmActionError::OperationNotSupportedPreBoot,
@@ -1715,6 +2124,7 @@ mod tests {
                 iface_id: String::new(),
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                link_up: None,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
@@ -1723,10 +2133,19 @@ mod tests {
                 snapshot_type: SnapshotType::Full,
                 snapshot_path: PathBuf::new(),
                 mem_file_path: PathBuf::new(),
+                mem_file_format: MemFileFormat::Raw,
+                diff_manifest_path: None,
                 version: None,
             }),
             VmmActionError::OperationNotSupportedPreBoot,
         );
+        check_preboot_request_err(
+            VmmAction::SendMigration(SendMigrationParams {
+                socket_path: Some(PathBuf::new()),
+                socket_fd: None,
+            }),
+            VmmActionError::OperationNotSupportedPreBoot,
+        );
         #[cfg(target_arch = "x86_64")]
         check_preboot_request_err(
             VmmAction::SendCtrlAltDel,
@@ -1861,6 +2280,58 @@ mod tests {
         check_runtime_request_err(req, VmmActionError::InternalVmm(VmmError::VcpuResume));
     }
 
+    #[test]
+    fn test_runtime_create_live_snapshot() {
+        let req = VmmAction::CreateSnapshot(CreateSnapshotParams {
+            snapshot_type: SnapshotType::Live,
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            mem_file_format: MemFileFormat::Raw,
+            diff_manifest_path: None,
+            version: None,
+        });
+        // Live snapshots need dirty page tracking.
+        check_runtime_request(req, |result, _| {
+            assert_eq!(result, Err(VmmActionError::NotSupported(String::new())));
+        });
+
+        let req = VmmAction::CreateSnapshot(CreateSnapshotParams {
+            snapshot_type: SnapshotType::Live,
+            snapshot_path: PathBuf::new(),
+            mem_file_path: PathBuf::new(),
+            mem_file_format: MemFileFormat::Raw,
+            diff_manifest_path: None,
+            version: None,
+        });
+        let mut vm_res = MockVmRes::default();
+        vm_res.set_track_dirty_pages(true);
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(vm_res, vmm);
+        assert_eq!(runtime.handle_request(req), Ok(VmmData::Empty));
+    }
+
+    #[test]
+    fn test_runtime_send_migration() {
+        let req = VmmAction::SendMigration(SendMigrationParams {
+            socket_path: Some(PathBuf::new()),
+            socket_fd: None,
+        });
+        // Live migration needs dirty page tracking.
+        check_runtime_request(req, |result, _| {
+            assert_eq!(result, Err(VmmActionError::NotSupported(String::new())));
+        });
+
+        let req = VmmAction::SendMigration(SendMigrationParams {
+            socket_path: Some(PathBuf::new()),
+            socket_fd: None,
+        });
+        let mut vm_res = MockVmRes::default();
+        vm_res.set_track_dirty_pages(true);
+        let vmm = Arc::new(Mutex::new(MockVmm::default()));
+        let mut runtime = RuntimeApiController::new(vm_res, vmm);
+        assert_eq!(runtime.handle_request(req), Ok(VmmData::Empty));
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn test_runtime_ctrl_alt_del() {
@@ -1969,12 +2440,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_update_block_device_size() {
+        let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
+            refresh_size: true,
+            ..Default::default()
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_block_device_size_called);
+            assert!(!vmm.update_block_device_path_called);
+        });
+
+        let req = VmmAction::UpdateBlockDevice(BlockDeviceUpdateConfig {
+            refresh_size: true,
+            ..Default::default()
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::DriveConfig(DriveError::DeviceUpdate(VmmError::DeviceManager(
+                crate::device_manager::mmio::Error::IncorrectDeviceType,
+            ))),
+        );
+    }
+
     #[test]
     fn test_runtime_update_net_rate_limiters() {
         let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
             iface_id: String::new(),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            link_up: None,
         });
         check_runtime_request(req, |result, vmm| {
             assert_eq!(result, Ok(VmmData::Empty));
@@ -1985,6 +2481,104 @@ mod tests {
             iface_id: String::new(),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            link_up: None,
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceUpdate(
+                VmmError::DeviceManager(crate::device_manager::mmio::Error::IncorrectDeviceType),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_runtime_set_net_capture() {
+        let req = VmmAction::SetNetworkCapture(NetworkCaptureConfig {
+            iface_id: String::new(),
+            state: NetworkCaptureState::Stopped,
+            path_on_host: None,
+            max_bytes: None,
+            max_packets: None,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_net_capture_called);
+        });
+
+        // Starting a capture requires the path of the capture file.
+        let req = VmmAction::SetNetworkCapture(NetworkCaptureConfig {
+            iface_id: String::new(),
+            state: NetworkCaptureState::Started,
+            path_on_host: None,
+            max_bytes: None,
+            max_packets: None,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Err(VmmActionError::NetworkConfig(
+                    NetworkInterfaceError::CapturePathMissing
+                ))
+            );
+            assert!(!vmm.update_net_capture_called);
+        });
+
+        // The capture file of a missing device is not created.
+        let file = TempFile::new().unwrap();
+        let path = file.as_path().to_path_buf();
+        drop(file);
+        let req = VmmAction::SetNetworkCapture(NetworkCaptureConfig {
+            iface_id: "missing".to_string(),
+            state: NetworkCaptureState::Started,
+            path_on_host: Some(path.to_str().unwrap().to_string()),
+            max_bytes: None,
+            max_packets: None,
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(
+                result,
+                Err(VmmActionError::NetworkConfig(
+                    NetworkInterfaceError::DeviceNotFound("missing".to_string())
+                ))
+            );
+            assert!(!vmm.update_net_capture_called);
+        });
+        assert!(!path.exists());
+
+        let req = VmmAction::SetNetworkCapture(NetworkCaptureConfig {
+            iface_id: String::new(),
+            state: NetworkCaptureState::Stopped,
+            path_on_host: None,
+            max_bytes: None,
+            max_packets: None,
+        });
+        check_runtime_request_err(
+            req,
+            VmmActionError::NetworkConfig(NetworkInterfaceError::DeviceUpdate(
+                VmmError::DeviceManager(crate::device_manager::mmio::Error::IncorrectDeviceType),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_runtime_update_net_link_state() {
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            iface_id: String::new(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            link_up: Some(false),
+        });
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.update_net_link_state_called);
+            assert!(vmm.update_net_rate_limiters_called);
+        });
+
+        let req = VmmAction::UpdateNetworkInterface(NetworkInterfaceUpdateConfig {
+            iface_id: String::new(),
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            link_up: Some(true),
         });
         check_runtime_request_err(
             req,
@@ -1994,6 +2588,79 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_runtime_hotplug() {
+        let block_config = BlockDeviceConfig {
+            path_on_host: String::new(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::new(),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
+        };
+        let net_config = NetworkInterfaceConfig {
+            iface_id: String::new(),
+            host_dev_name: String::new(),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            mtu: None,
+            anti_spoofing: None,
+            backend_type: NetworkBackendType::default(),
+            user_backend: None,
+        };
+        let vsock_config = VsockDeviceConfig {
+            vsock_id: None,
+            guest_cid: 3,
+            uds_path: String::new(),
+        };
+
+        let req = VmmAction::InsertBlockDevice(block_config.clone());
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.hotplug_device_called);
+        });
+        let req = VmmAction::InsertNetworkDevice(net_config.clone());
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.hotplug_device_called);
+        });
+        let req = VmmAction::SetVsockDevice(vsock_config.clone());
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.hotplug_device_called);
+        });
+        let req = VmmAction::UnplugDevice(UnplugDeviceConfig::Vsock);
+        check_runtime_request(req, |result, vmm| {
+            assert_eq!(result, Ok(VmmData::Empty));
+            assert!(vmm.unplug_device_called);
+        });
+
+        check_runtime_request_err(
+            VmmAction::InsertBlockDevice(block_config),
+            VmmActionError::Hotplug(HotplugError::RootBlockDevice),
+        );
+        check_runtime_request_err(
+            VmmAction::InsertNetworkDevice(net_config),
+            VmmActionError::Hotplug(HotplugError::RootBlockDevice),
+        );
+        check_runtime_request_err(
+            VmmAction::SetVsockDevice(vsock_config),
+            VmmActionError::Hotplug(HotplugError::RootBlockDevice),
+        );
+        check_runtime_request_err(
+            VmmAction::UnplugDevice(UnplugDeviceConfig::Vsock),
+            VmmActionError::Hotplug(HotplugError::RootBlockDevice),
+        );
+    }
+
     #[test]
     fn test_runtime_disallowed() {
         check_runtime_request_err(
@@ -2015,49 +2682,10 @@ mod tests {
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
-        check_runtime_request_err(
-            VmmAction::InsertBlockDevice(BlockDeviceConfig {
-                path_on_host: String::new(),
-                is_root_device: false,
-                partuuid: None,
-                cache_type: CacheType::Unsafe,
-                is_read_only: false,
-                drive_id: String::new(),
-                rate_limiter: None,
-                file_engine_type: FileEngineType::default(),
-            }),
-            VmmActionError::OperationNotSupportedPostBoot,
-        );
-        check_runtime_request_err(
-            VmmAction::InsertNetworkDevice(NetworkInterfaceConfig {
-                iface_id: String::new(),
-                host_dev_name: String::new(),
-                guest_mac: None,
-                rx_rate_limiter: None,
-                tx_rate_limiter: None,
-            }),
-            VmmActionError::OperationNotSupportedPostBoot,
-        );
-        check_runtime_request_err(
-            VmmAction::SetVsockDevice(VsockDeviceConfig {
-                vsock_id: Some(String::new()),
-                guest_cid: 0,
-                uds_path: String::new(),
-            }),
-            VmmActionError::OperationNotSupportedPostBoot,
-        );
         check_runtime_request_err(
             VmmAction::SetBalloonDevice(BalloonDeviceConfig::default()),
             VmmActionError::OperationNotSupportedPostBoot,
         );
-        check_runtime_request_err(
-            VmmAction::SetVsockDevice(VsockDeviceConfig {
-                vsock_id: Some(String::new()),
-                guest_cid: 0,
-                uds_path: String::new(),
-            }),
-            VmmActionError::OperationNotSupportedPostBoot,
-        );
         check_runtime_request_err(
             VmmAction::SetMmdsConfiguration(MmdsConfig {
                 ipv4_address: None,
@@ -2076,9 +2704,21 @@ mod tests {
                 mem_backend: MemBackendConfig {
                     backend_type: MemBackendType::File,
                     backend_path: PathBuf::new(),
+                    diff_layers: vec![],
                 },
                 enable_diff_snapshots: false,
                 resume_vm: false,
+                device_overrides: DeviceOverrides::default(),
+                clone_identity: None,
+            }),
+            VmmActionError::OperationNotSupportedPostBoot,
+        );
+        check_runtime_request_err(
+            VmmAction::ReceiveMigration(ReceiveMigrationParams {
+                socket_path: Some(PathBuf::new()),
+                socket_fd: None,
+                enable_diff_snapshots: false,
+                resume_vm: false,
             }),
             VmmActionError::OperationNotSupportedPostBoot,
         );
@@ -2098,9 +2738,12 @@ mod tests {
             mem_backend: MemBackendConfig {
                 backend_type: MemBackendType::File,
                 backend_path: PathBuf::new(),
+                diff_layers: vec![],
             },
             enable_diff_snapshots: false,
             resume_vm: false,
+            device_overrides: DeviceOverrides::default(),
+            clone_identity: None,
         });
         let err = preboot.handle_preboot_request(req);
         assert_eq!(
@@ -2128,6 +2771,10 @@ mod tests {
             drive_id: String::new(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertBlockDevice");
 
@@ -2137,6 +2784,11 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            num_queue_pairs: 1,
+            mtu: None,
+            anti_spoofing: None,
+            backend_type: NetworkBackendType::default(),
+            user_backend: None,
         });
         verify_load_snap_disallowed_after_boot_resources(req, "InsertNetworkDevice");
 
@@ -9,10 +9,11 @@ use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::{io, result};
 
-pub use devices::virtio::block::device::FileEngineType;
-use devices::virtio::block::Error as BlockError;
-use devices::virtio::Block;
+pub use devices::virtio::block::device::{FileEngineType, ImageFormat};
+use devices::virtio::block::{is_nbd_uri, Error as BlockError, DEFAULT_NUM_QUEUES};
+use devices::virtio::vhost_user_blk::Error as VhostUserBlockError;
 pub use devices::virtio::CacheType;
+use devices::virtio::{Block, VhostUserBlock};
 use serde::{Deserialize, Serialize};
 
 use super::RateLimiterConfig;
@@ -30,6 +31,8 @@ pub enum DriveError {
     CreateBlockDevice(BlockError),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(io::Error),
+    /// Cannot set up the block device with its vhost-user backend.
+    CreateVhostUserBlockDevice(VhostUserBlockError),
     /// Error during drive update (patch).
     DeviceUpdate(VmmError),
     /// The block device path is invalid.
@@ -38,6 +41,8 @@ pub enum DriveError {
     OpenBlockDevice(io::Error),
     /// A root block device was already added.
     RootBlockDeviceAlreadyAdded,
+    /// The option is not supported by drives with a vhost-user backend.
+    UnsupportedVhostUserOption(&'static str),
 }
 
 impl Display for DriveError {
@@ -47,6 +52,11 @@ impl Display for DriveError {
             CreateBlockDevice(err) => write!(f, "Unable to create the block device {:?}", err),
             BlockDeviceUpdateFailed(err) => write!(f, "The update operation failed: {}", err),
             CreateRateLimiter(err) => write!(f, "Cannot create RateLimiter: {}", err),
+            CreateVhostUserBlockDevice(err) => write!(
+                f,
+                "Unable to set up the block device with its vhost-user backend: {:?}",
+                err
+            ),
             DeviceUpdate(err) => write!(f, "Error during drive update (patch): {}", err),
             InvalidBlockDevicePath(path) => write!(f, "Invalid block device path: {}", path),
             OpenBlockDevice(err) => write!(
@@ -55,17 +65,39 @@ impl Display for DriveError {
                 err
             ),
             RootBlockDeviceAlreadyAdded => write!(f, "A root block device already exists!"),
+            UnsupportedVhostUserOption(option) => write!(
+                f,
+                "Drives with a vhost-user backend do not support the {} option",
+                option
+            ),
         }
     }
 }
 
+/// The kind of backend processing the requests of a drive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum BlockBackendType {
+    /// Firecracker processes the requests on the file, or NBD export, found at `path_on_host`.
+    File,
+    /// A vhost-user backend listening on the Unix domain socket found at `path_on_host`
+    /// processes the requests.
+    VhostUser,
+}
+
+impl Default for BlockBackendType {
+    fn default() -> Self {
+        Self::File
+    }
+}
+
 /// Use this structure to set up the Block Device before booting the kernel.
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct BlockDeviceConfig {
     /// Unique identifier of the drive.
     pub drive_id: String,
-    /// Path of the drive.
+    /// Path of the drive, NBD URI of the export backing it, or path of the socket of its
+    /// vhost-user backend.
     pub path_on_host: String,
     /// If set to true, it makes the current device the root block device.
     /// Setting this flag to true will mount the block device in the
@@ -87,6 +119,22 @@ pub struct BlockDeviceConfig {
     #[serde(default)]
     #[serde(rename = "io_engine")]
     pub file_engine_type: FileEngineType,
+    /// The number of virtio queues of the device, each with its own IO engine.
+    #[serde(default = "default_num_queues")]
+    pub num_queues: usize,
+    /// The format of the disk image found at `path_on_host`.
+    #[serde(default)]
+    pub image_format: ImageFormat,
+    /// Path of a file the writes to the drive are redirected to, leaving the disk image found
+    /// at `path_on_host` unmodified.
+    pub overlay_path_on_host: Option<String>,
+    /// The kind of backend processing the requests of the drive.
+    #[serde(default)]
+    pub backend_type: BlockBackendType,
+}
+
+fn default_num_queues() -> usize {
+    DEFAULT_NUM_QUEUES
 }
 
 impl From<&Block> for BlockDeviceConfig {
@@ -101,6 +149,29 @@ impl From<&Block> for BlockDeviceConfig {
             cache_type: block.cache_type(),
             rate_limiter: rl.into_option(),
             file_engine_type: block.file_engine_type(),
+            num_queues: block.num_queues(),
+            image_format: block.image_format(),
+            overlay_path_on_host: block.overlay_path().cloned(),
+            backend_type: BlockBackendType::File,
+        }
+    }
+}
+
+impl From<&VhostUserBlock> for BlockDeviceConfig {
+    fn from(block: &VhostUserBlock) -> Self {
+        BlockDeviceConfig {
+            drive_id: block.id().clone(),
+            path_on_host: block.socket_path().clone(),
+            is_root_device: block.is_root_device(),
+            partuuid: block.partuuid().cloned(),
+            is_read_only: block.is_read_only(),
+            cache_type: block.cache_type(),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            num_queues: block.num_queues(),
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::VhostUser,
         }
     }
 }
@@ -116,6 +187,10 @@ pub struct BlockDeviceUpdateConfig {
     pub path_on_host: Option<String>,
     /// New rate limiter config.
     pub rate_limiter: Option<RateLimiterConfig>,
+    /// Re-read the size of the block file after it was resized on the host, and notify the
+    /// guest of the new capacity.
+    #[serde(default)]
+    pub refresh_size: bool,
 }
 
 /// Wrapper for the collection that holds all the Block Devices
@@ -127,6 +202,39 @@ pub struct BlockBuilder {
     // specified in order to avoid bugs in case of switching from partuuid boot
     // scenarios to /dev/vda boot type.
     pub list: VecDeque<Arc<Mutex<Block>>>,
+    /// The list of block devices with a vhost-user backend. If the root block device has a
+    /// vhost-user backend, it is the first in this list.
+    pub vhost_user_list: VecDeque<Arc<Mutex<VhostUserBlock>>>,
+}
+
+// Inserts a device in `list`, at `position` if it replaces another one, or at the front if it
+// is the root device.
+fn insert_in_list<T>(
+    list: &mut VecDeque<Arc<Mutex<T>>>,
+    position: Option<usize>,
+    is_root_device: bool,
+    device: Arc<Mutex<T>>,
+) {
+    match position {
+        // New block device.
+        None => {
+            if is_root_device {
+                list.push_front(device);
+            } else {
+                list.push_back(device);
+            }
+        }
+        // Update existing block device.
+        Some(index) => {
+            // Update the slot with the new block.
+            list[index] = device;
+            // Check if the root block device is being updated.
+            if index != 0 && is_root_device {
+                // Make sure the root device is on the first position.
+                list.swap(0, index);
+            }
+        }
+    }
 }
 
 impl BlockBuilder {
@@ -134,17 +242,44 @@ impl BlockBuilder {
     pub fn new() -> Self {
         Self {
             list: VecDeque::<Arc<Mutex<Block>>>::new(),
+            vhost_user_list: VecDeque::<Arc<Mutex<VhostUserBlock>>>::new(),
         }
     }
 
     /// Specifies whether there is a root block device already present in the list.
     fn has_root_device(&self) -> bool {
-        // If there is a root device, it would be at the top of the list.
+        self.root_device_id().is_some()
+    }
+
+    /// Gets the id of the root block device, if any.
+    fn root_device_id(&self) -> Option<String> {
+        // If there is a root device, it would be at the top of one of the lists.
         if let Some(block) = self.list.get(0) {
-            block.lock().expect("Poisoned lock").is_root_device()
-        } else {
-            false
+            let block = block.lock().expect("Poisoned lock");
+            if block.is_root_device() {
+                return Some(block.id().clone());
+            }
+        }
+        if let Some(block) = self.vhost_user_list.get(0) {
+            let block = block.lock().expect("Poisoned lock");
+            if block.is_root_device() {
+                return Some(block.id().clone());
+            }
         }
+        None
+    }
+
+    /// Specifies whether the root block device has a vhost-user backend.
+    pub fn has_vhost_user_root_device(&self) -> bool {
+        self.vhost_user_list.get(0).map_or(false, |block| {
+            block.lock().expect("Poisoned lock").is_root_device()
+        })
+    }
+
+    /// Specifies whether a block device has the specified `drive_id`.
+    pub fn has_drive_id(&self, drive_id: &str) -> bool {
+        self.get_index_of_drive_id(drive_id).is_some()
+            || self.get_index_of_vhost_user_drive_id(drive_id).is_some()
     }
 
     /// Gets the index of the device with the specified `drive_id` if it exists in the list.
@@ -154,49 +289,92 @@ impl BlockBuilder {
             .position(|b| b.lock().expect("Poisoned lock").id().eq(drive_id))
     }
 
+    /// Gets the index of the device with the specified `drive_id` if it exists in the list of
+    /// devices with a vhost-user backend.
+    fn get_index_of_vhost_user_drive_id(&self, drive_id: &str) -> Option<usize> {
+        self.vhost_user_list
+            .iter()
+            .position(|b| b.lock().expect("Poisoned lock").id().eq(drive_id))
+    }
+
     /// Inserts an existing block device.
     pub fn add_device(&mut self, block_device: Arc<Mutex<Block>>) {
-        if block_device.lock().expect("Poisoned lock").is_root_device() {
-            self.list.push_front(block_device);
+        let is_root_device = block_device.lock().expect("Poisoned lock").is_root_device();
+        insert_in_list(&mut self.list, None, is_root_device, block_device);
+    }
+
+    /// Inserts an existing block device with a vhost-user backend.
+    pub fn add_vhost_user_device(&mut self, block_device: Arc<Mutex<VhostUserBlock>>) {
+        let is_root_device = block_device.lock().expect("Poisoned lock").is_root_device();
+        insert_in_list(
+            &mut self.vhost_user_list,
+            None,
+            is_root_device,
+            block_device,
+        );
+    }
+
+    /// Removes the block device with the specified `drive_id`, whatever its backend, if
+    /// present. Returns whether a device was removed.
+    pub fn remove(&mut self, drive_id: &str) -> bool {
+        if let Some(index) = self.get_index_of_drive_id(drive_id) {
+            self.list.remove(index);
+            true
+        } else if let Some(index) = self.get_index_of_vhost_user_drive_id(drive_id) {
+            self.vhost_user_list.remove(index);
+            true
         } else {
-            self.list.push_back(block_device);
+            false
         }
     }
 
+    /// Specifies whether any block device has a vhost-user backend, which needs to share the
+    /// guest memory.
+    pub fn has_vhost_user_devices(&self) -> bool {
+        !self.vhost_user_list.is_empty()
+    }
+
     /// Inserts a `Block` in the block devices list using the specified configuration.
     /// If a block with the same id already exists, it will overwrite it.
     /// Inserting a secondary root block device will fail.
     pub fn insert(&mut self, config: BlockDeviceConfig) -> Result<()> {
         let is_root_device = config.is_root_device;
-        let position = self.get_index_of_drive_id(&config.drive_id);
-        let has_root_block = self.has_root_device();
 
         // Don't allow adding a second root block device.
         // If the new device cfg is root and not an update to the existing root, fail fast.
-        if is_root_device && has_root_block && position != Some(0) {
-            return Err(DriveError::RootBlockDeviceAlreadyAdded);
+        if is_root_device {
+            if let Some(root_id) = self.root_device_id() {
+                if root_id != config.drive_id {
+                    return Err(DriveError::RootBlockDeviceAlreadyAdded);
+                }
+            }
         }
 
-        let block_dev = Arc::new(Mutex::new(Self::create_block(config)?));
         // If the id of the drive already exists in the list, the operation is update/overwrite.
-        match position {
-            // New block device.
-            None => {
-                if is_root_device {
-                    self.list.push_front(block_dev);
-                } else {
-                    self.list.push_back(block_dev);
+        // The drive may also have been configured with another kind of backend before.
+        match config.backend_type {
+            BlockBackendType::File => {
+                let block_dev = Arc::new(Mutex::new(Self::create_block(config)?));
+                let drive_id = block_dev.lock().expect("Poisoned lock").id().clone();
+                if let Some(index) = self.get_index_of_vhost_user_drive_id(&drive_id) {
+                    self.vhost_user_list.remove(index);
                 }
+                let position = self.get_index_of_drive_id(&drive_id);
+                insert_in_list(&mut self.list, position, is_root_device, block_dev);
             }
-            // Update existing block device.
-            Some(index) => {
-                // Update the slot with the new block.
-                self.list[index] = block_dev;
-                // Check if the root block device is being updated.
-                if index != 0 && is_root_device {
-                    // Make sure the root device is on the first position.
-                    self.list.swap(0, index);
+            BlockBackendType::VhostUser => {
+                let block_dev = Arc::new(Mutex::new(Self::create_vhost_user_block(config)?));
+                let drive_id = block_dev.lock().expect("Poisoned lock").id().clone();
+                if let Some(index) = self.get_index_of_drive_id(&drive_id) {
+                    self.list.remove(index);
                 }
+                let position = self.get_index_of_vhost_user_drive_id(&drive_id);
+                insert_in_list(
+                    &mut self.vhost_user_list,
+                    position,
+                    is_root_device,
+                    block_dev,
+                );
             }
         }
         Ok(())
@@ -204,14 +382,19 @@ impl BlockBuilder {
 
     /// Creates a Block device from a BlockDeviceConfig.
     pub fn create_block(block_device_config: BlockDeviceConfig) -> Result<Block> {
-        // check if the path exists
+        // check if the path exists, unless the drive is an NBD export
         let path_on_host = PathBuf::from(&block_device_config.path_on_host);
-        if !path_on_host.exists() {
+        if !is_nbd_uri(&block_device_config.path_on_host) && !path_on_host.exists() {
             return Err(DriveError::InvalidBlockDevicePath(format!(
                 "{}",
                 path_on_host.display()
             )));
         }
+        if let Some(overlay_path) = &block_device_config.overlay_path_on_host {
+            if !PathBuf::from(overlay_path).exists() {
+                return Err(DriveError::InvalidBlockDevicePath(overlay_path.clone()));
+            }
+        }
 
         let rate_limiter = block_device_config
             .rate_limiter
@@ -229,16 +412,57 @@ impl BlockBuilder {
             block_device_config.is_root_device,
             rate_limiter.unwrap_or_default(),
             block_device_config.file_engine_type,
+            block_device_config.num_queues,
+            block_device_config.image_format,
+            block_device_config.overlay_path_on_host,
         )
         .map_err(DriveError::CreateBlockDevice)
     }
 
+    /// Creates a block device with a vhost-user backend from a BlockDeviceConfig.
+    pub fn create_vhost_user_block(
+        block_device_config: BlockDeviceConfig,
+    ) -> Result<VhostUserBlock> {
+        // The backend owns the disk image, and processes the requests as it sees fit.
+        if block_device_config.image_format != ImageFormat::Raw {
+            return Err(DriveError::UnsupportedVhostUserOption("image_format"));
+        }
+        if block_device_config.overlay_path_on_host.is_some() {
+            return Err(DriveError::UnsupportedVhostUserOption(
+                "overlay_path_on_host",
+            ));
+        }
+        if block_device_config.cache_type == CacheType::Direct {
+            return Err(DriveError::UnsupportedVhostUserOption("cache_type"));
+        }
+        if block_device_config.file_engine_type != FileEngineType::Sync {
+            return Err(DriveError::UnsupportedVhostUserOption("io_engine"));
+        }
+        if block_device_config.rate_limiter.is_some() {
+            return Err(DriveError::UnsupportedVhostUserOption("rate_limiter"));
+        }
+
+        VhostUserBlock::new(
+            block_device_config.drive_id,
+            block_device_config.partuuid,
+            block_device_config.cache_type,
+            block_device_config.path_on_host,
+            block_device_config.is_read_only,
+            block_device_config.is_root_device,
+            block_device_config.num_queues,
+        )
+        .map_err(DriveError::CreateVhostUserBlockDevice)
+    }
+
     /// Returns a vec with the structures used to configure the devices.
     pub fn configs(&self) -> Vec<BlockDeviceConfig> {
         let mut ret = vec![];
         for block in &self.list {
             ret.push(BlockDeviceConfig::from(block.lock().unwrap().deref()));
         }
+        for block in &self.vhost_user_list {
+            ret.push(BlockDeviceConfig::from(block.lock().unwrap().deref()));
+        }
         ret
     }
 }
@@ -269,6 +493,10 @@ mod tests {
                 drive_id: self.drive_id.clone(),
                 rate_limiter: None,
                 file_engine_type: FileEngineType::default(),
+                num_queues: self.num_queues,
+                image_format: self.image_format,
+                overlay_path_on_host: self.overlay_path_on_host.clone(),
+                backend_type: self.backend_type,
             }
         }
     }
@@ -293,6 +521,10 @@ mod tests {
             drive_id: dummy_id.clone(),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -310,6 +542,156 @@ mod tests {
         assert_eq!(block_devs.get_index_of_drive_id(&dummy_id), Some(0));
     }
 
+    #[test]
+    fn test_add_multi_queue_block_device() {
+        let dummy_file = TempFile::new().unwrap();
+        let dummy_path = dummy_file.as_path().to_str().unwrap().to_string();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_path,
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            num_queues: 4,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
+        };
+
+        let mut block_devs = BlockBuilder::new();
+        block_devs.insert(dummy_block_device.clone()).unwrap();
+        assert_eq!(block_devs.list[0].lock().unwrap().num_queues(), 4);
+        assert_eq!(block_devs.configs()[0].num_queues, 4);
+
+        // A device needs at least one queue.
+        dummy_block_device.num_queues = 0;
+        assert!(matches!(
+            block_devs.insert(dummy_block_device),
+            Err(DriveError::CreateBlockDevice(BlockError::InvalidNumQueues(
+                0
+            )))
+        ));
+
+        // The number of queues defaults to one.
+        let config: BlockDeviceConfig = serde_json::from_str(
+            r#"{
+                "drive_id": "1",
+                "path_on_host": "dummy",
+                "is_root_device": false,
+                "is_read_only": false
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.num_queues, 1);
+        assert_eq!(config.image_format, ImageFormat::Raw);
+        assert_eq!(config.overlay_path_on_host, None);
+        assert_eq!(config.backend_type, BlockBackendType::File);
+    }
+
+    #[test]
+    fn test_add_vhost_user_block_device() {
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: String::from("/invalid/socket"),
+            is_root_device: true,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::Sync,
+            num_queues: 1,
+            image_format: ImageFormat::Qcow2,
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::VhostUser,
+        };
+
+        // The backend owns the disk image.
+        let mut block_devs = BlockBuilder::new();
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()).unwrap_err(),
+            DriveError::UnsupportedVhostUserOption("image_format")
+        );
+        dummy_block_device.image_format = ImageFormat::Raw;
+        dummy_block_device.cache_type = CacheType::Direct;
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()).unwrap_err(),
+            DriveError::UnsupportedVhostUserOption("cache_type")
+        );
+        dummy_block_device.cache_type = CacheType::Writeback;
+        dummy_block_device.overlay_path_on_host = Some(String::from("overlay"));
+        assert_eq!(
+            block_devs.insert(dummy_block_device.clone()).unwrap_err(),
+            DriveError::UnsupportedVhostUserOption("overlay_path_on_host")
+        );
+
+        // No backend listens on the socket.
+        dummy_block_device.overlay_path_on_host = None;
+        assert!(matches!(
+            block_devs.insert(dummy_block_device.clone()),
+            Err(DriveError::CreateVhostUserBlockDevice(
+                VhostUserBlockError::Connect(_)
+            ))
+        ));
+        assert!(!block_devs.has_vhost_user_devices());
+        assert!(!block_devs.has_root_device());
+
+        // A file-backed drive cannot be a second root device.
+        let dummy_file = TempFile::new().unwrap();
+        let mut root_block_device = dummy_block_device.clone();
+        root_block_device.path_on_host = dummy_file.as_path().to_str().unwrap().to_string();
+        root_block_device.backend_type = BlockBackendType::File;
+        block_devs.insert(root_block_device).unwrap();
+        dummy_block_device.drive_id = String::from("2");
+        assert_eq!(
+            block_devs.insert(dummy_block_device).unwrap_err(),
+            DriveError::RootBlockDeviceAlreadyAdded
+        );
+        assert!(block_devs.has_drive_id("1"));
+        assert!(!block_devs.has_drive_id("2"));
+        assert!(!block_devs.has_vhost_user_root_device());
+    }
+
+    #[test]
+    fn test_add_overlay_block_device() {
+        let base_file = TempFile::new().unwrap();
+        base_file.as_file().set_len(0x1000).unwrap();
+        let overlay_file = TempFile::new().unwrap();
+        let overlay_path = overlay_file.as_path().to_str().unwrap().to_string();
+        let mut dummy_block_device = BlockDeviceConfig {
+            path_on_host: base_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            cache_type: CacheType::Unsafe,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::Sync,
+            num_queues: 1,
+            image_format: ImageFormat::Raw,
+            overlay_path_on_host: Some(String::from("/invalid/overlay")),
+            backend_type: BlockBackendType::default(),
+        };
+
+        // The overlay file must exist.
+        let mut block_devs = BlockBuilder::new();
+        assert!(matches!(
+            block_devs.insert(dummy_block_device.clone()),
+            Err(DriveError::InvalidBlockDevicePath(path)) if path == "/invalid/overlay"
+        ));
+
+        dummy_block_device.overlay_path_on_host = Some(overlay_path.clone());
+        block_devs.insert(dummy_block_device).unwrap();
+        assert_eq!(
+            block_devs.configs()[0].overlay_path_on_host,
+            Some(overlay_path)
+        );
+        // The empty overlay file is initialized.
+        assert!(overlay_file.as_file().metadata().unwrap().len() > 0);
+    }
+
     #[test]
     fn test_add_one_root_block_device() {
         let dummy_file = TempFile::new().unwrap();
@@ -324,6 +706,10 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -352,6 +738,10 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -365,6 +755,10 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -389,6 +783,10 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -402,6 +800,10 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
@@ -415,6 +817,10 @@ mod tests {
             drive_id: String::from("3"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -453,6 +859,10 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -466,6 +876,10 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let dummy_file_3 = TempFile::new().unwrap();
@@ -479,6 +893,10 @@ mod tests {
             drive_id: String::from("3"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -518,6 +936,10 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let dummy_file_2 = TempFile::new().unwrap();
@@ -531,6 +953,10 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -590,6 +1016,10 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
         // Switch roots and add a PARTUUID for the new one.
         let mut root_block_device_old = root_block_device;
@@ -603,6 +1033,10 @@ mod tests {
             drive_id: String::from("2"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
         assert!(block_devs.insert(root_block_device_old).is_ok());
         let root_block_id = root_block_device_new.drive_id.clone();
@@ -625,6 +1059,10 @@ mod tests {
             drive_id: String::from("1"),
             rate_limiter: None,
             file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
         };
 
         let mut block_devs = BlockBuilder::new();
@@ -649,6 +1087,9 @@ mod tests {
             true,
             RateLimiter::default(),
             FileEngineType::default(),
+            1,
+            ImageFormat::default(),
+            None,
         )
         .unwrap();
 
@@ -666,4 +1107,30 @@ mod tests {
             block_id
         )
     }
+
+    #[test]
+    fn test_remove() {
+        let mut block_devs = BlockBuilder::new();
+        let backing_file = TempFile::new().unwrap();
+        let dummy_block_device = BlockDeviceConfig {
+            path_on_host: backing_file.as_path().to_str().unwrap().to_string(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            cache_type: CacheType::Writeback,
+            drive_id: String::from("1"),
+            rate_limiter: None,
+            file_engine_type: FileEngineType::default(),
+            num_queues: 1,
+            image_format: ImageFormat::default(),
+            overlay_path_on_host: None,
+            backend_type: BlockBackendType::default(),
+        };
+        block_devs.insert(dummy_block_device).unwrap();
+
+        assert!(block_devs.remove("2").is_none());
+        assert_eq!(block_devs.list.len(), 1);
+        assert_eq!(block_devs.remove("1").unwrap().lock().unwrap().id(), "1");
+        assert!(block_devs.list.is_empty());
+    }
 }
@@ -0,0 +1,60 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Configurations used to hot-unplug devices from a running microVM.
+
+use serde::{Deserialize, Serialize};
+
+/// Identifies the device to unplug from a running microVM.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "device_type", deny_unknown_fields)]
+pub enum UnplugDeviceConfig {
+    /// A block device.
+    Drive {
+        /// Unique identifier of the drive.
+        drive_id: String,
+    },
+    /// A network device.
+    NetworkInterface {
+        /// Unique identifier of the network interface.
+        iface_id: String,
+    },
+    /// The vsock device.
+    Vsock,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unplug_device_config() {
+        let config: UnplugDeviceConfig =
+            serde_json::from_str(r#"{"device_type": "Drive", "drive_id": "scratch"}"#).unwrap();
+        assert_eq!(
+            config,
+            UnplugDeviceConfig::Drive {
+                drive_id: "scratch".to_string()
+            }
+        );
+        let config: UnplugDeviceConfig =
+            serde_json::from_str(r#"{"device_type": "NetworkInterface", "iface_id": "eth1"}"#)
+                .unwrap();
+        assert_eq!(
+            config,
+            UnplugDeviceConfig::NetworkInterface {
+                iface_id: "eth1".to_string()
+            }
+        );
+        let config: UnplugDeviceConfig =
+            serde_json::from_str(r#"{"device_type": "Vsock"}"#).unwrap();
+        assert_eq!(config, UnplugDeviceConfig::Vsock);
+
+        assert!(serde_json::from_str::<UnplugDeviceConfig>(r#"{"device_type": "Drive"}"#).is_err());
+        assert!(serde_json::from_str::<UnplugDeviceConfig>(r#"{"drive_id": "scratch"}"#).is_err());
+        assert!(serde_json::from_str::<UnplugDeviceConfig>(
+            r#"{"device_type": "Drive", "drive_id": "scratch", "iface_id": "eth1"}"#
+        )
+        .is_err());
+    }
+}
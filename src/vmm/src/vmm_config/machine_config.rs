@@ -75,6 +75,9 @@ pub struct VmConfig {
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(default)]
     pub track_dirty_pages: bool,
+    /// Number of MMIO slots reserved for the virtio devices attached after boot.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub hotplug_slots: u8,
 }
 
 impl Default for VmConfig {
@@ -85,6 +88,7 @@ impl Default for VmConfig {
             smt: false,
             cpu_template: CpuFeaturesTemplate::None,
             track_dirty_pages: false,
+            hotplug_slots: 0,
         }
     }
 }
@@ -94,8 +98,13 @@ impl fmt::Display for VmConfig {
         write!(
             f,
             "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?}, \"smt\": {:?}, \"cpu_template\": \
-             {:?}, \"track_dirty_pages\": {:?} }}",
-            self.vcpu_count, self.mem_size_mib, self.smt, self.cpu_template, self.track_dirty_pages
+             {:?}, \"track_dirty_pages\": {:?}, \"hotplug_slots\": {:?} }}",
+            self.vcpu_count,
+            self.mem_size_mib,
+            self.smt,
+            self.cpu_template,
+            self.track_dirty_pages,
+            self.hotplug_slots
         )
     }
 }
@@ -135,6 +144,9 @@ pub struct VmUpdateConfig {
     /// Enables or disables dirty page tracking. Enabling allows incremental snapshots.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub track_dirty_pages: Option<bool>,
+    /// Number of MMIO slots reserved for the virtio devices attached after boot.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hotplug_slots: Option<u8>,
 }
 
 impl VmUpdateConfig {
@@ -147,6 +159,7 @@ impl VmUpdateConfig {
             && self.cpu_template.is_none()
             && self.smt.is_none()
             && self.track_dirty_pages.is_none()
+            && self.hotplug_slots.is_none()
         {
             return true;
         }
@@ -163,6 +176,7 @@ impl From<VmConfig> for VmUpdateConfig {
             smt: Some(cfg.smt),
             cpu_template: Some(cfg.cpu_template),
             track_dirty_pages: Some(cfg.track_dirty_pages),
+            hotplug_slots: Some(cfg.hotplug_slots),
         }
     }
 }
@@ -236,6 +250,10 @@ where
     T::deserialize(_d)
 }
 
+fn is_zero(value: &u8) -> bool {
+    *value == 0
+}
+
 /// Template types available for configuring the CPU features that map
 /// to EC2 instances.
 #[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, Versionize)]
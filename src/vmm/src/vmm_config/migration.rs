@@ -0,0 +1,42 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Configurations used in the live migration context.
+
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+/// Stores the configuration that will be used for sending a microVM to
+/// another Firecracker process.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SendMigrationParams {
+    /// Path to the Unix domain socket the destination Firecracker process
+    /// is listening on.
+    pub socket_path: Option<PathBuf>,
+    /// File descriptor of a connected stream socket inherited by the Firecracker
+    /// process, over which to send the microVM instead of `socket_path`.
+    pub socket_fd: Option<RawFd>,
+}
+
+/// Stores the configuration that will be used for receiving a microVM from
+/// another Firecracker process.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReceiveMigrationParams {
+    /// Path of the Unix domain socket on which to wait for the source
+    /// Firecracker process.
+    pub socket_path: Option<PathBuf>,
+    /// File descriptor of a connected stream socket inherited by the Firecracker
+    /// process, over which to receive the microVM instead of `socket_path`.
+    pub socket_fd: Option<RawFd>,
+    /// Setting this flag will enable KVM dirty page tracking on the
+    /// migrated microVM.
+    #[serde(default)]
+    pub enable_diff_snapshots: bool,
+    /// When set to true, the microVM is resumed once the migration completes.
+    #[serde(default)]
+    pub resume_vm: bool,
+}
@@ -17,6 +17,8 @@ pub mod balloon;
 pub mod boot_source;
 /// Wrapper for configuring the block devices.
 pub mod drive;
+/// Wrapper for configuring the devices unplugged from a running microVM.
+pub mod hotplug;
 /// Wrapper over the microVM general information attached to the microVM.
 pub mod instance_info;
 /// Wrapper for configuring the logger.
@@ -25,6 +27,8 @@ pub mod logger;
 pub mod machine_config;
 /// Wrapper for configuring the metrics.
 pub mod metrics;
+/// Wrapper for configuring live migrations of the microVM.
+pub mod migration;
 /// Wrapper for configuring the MMDS.
 pub mod mmds;
 /// Wrapper for configuring the network devices attached to the microVM.
@@ -2,18 +2,39 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::convert::TryInto;
+use std::net::Ipv4Addr;
 use std::ops::Deref;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::{fmt, result};
+use std::{fmt, io, result};
 
-use devices::virtio::net::TapError;
+use devices::virtio::net::{
+    AntiSpoofingFilter, PacketCapture, TapError, UserNetworkConfig, DEFAULT_NUM_QUEUE_PAIRS,
+};
 use devices::virtio::Net;
 use serde::{Deserialize, Serialize};
+use utils::net::ipv4addr::Ipv4Network;
 use utils::net::mac::MacAddr;
 
 use super::RateLimiterConfig;
 use crate::Error as VmmError;
 
+/// The kind of backend the frames of a network interface go through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum NetworkBackendType {
+    /// The frames go through the host tap device named `host_dev_name`.
+    Tap,
+    /// The TCP connections and UDP flows of the guest are proxied to host sockets by a
+    /// userspace network stack, which needs no tap device.
+    User,
+}
+
+impl Default for NetworkBackendType {
+    fn default() -> Self {
+        Self::Tap
+    }
+}
+
 /// This struct represents the strongly typed equivalent of the json body from net iface
 /// related requests.
 #[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -22,6 +43,7 @@ pub struct NetworkInterfaceConfig {
     /// ID of the guest network interface.
     pub iface_id: String,
     /// Host level path for the guest network interface.
+    #[serde(default)]
     pub host_dev_name: String,
     /// Guest MAC address.
     pub guest_mac: Option<MacAddr>,
@@ -29,6 +51,62 @@ pub struct NetworkInterfaceConfig {
     pub rx_rate_limiter: Option<RateLimiterConfig>,
     /// Rate Limiter for transmitted packages.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// The number of receive and transmit queue pairs, each with its own tap queue.
+    #[serde(default = "default_num_queue_pairs")]
+    pub num_queue_pairs: usize,
+    /// The largest MTU the guest driver is told to use.
+    pub mtu: Option<u16>,
+    /// Drops the frames transmitted by the guest from addresses it does not own.
+    pub anti_spoofing: Option<AntiSpoofingConfig>,
+    /// The kind of backend the frames of the interface go through.
+    #[serde(default)]
+    pub backend_type: NetworkBackendType,
+    /// The destinations the guest can reach through the `User` backend.
+    pub user_backend: Option<UserBackendConfig>,
+}
+
+fn default_num_queue_pairs() -> usize {
+    DEFAULT_NUM_QUEUE_PAIRS
+}
+
+/// The configuration of the filter dropping the frames transmitted by the guest whose source
+/// MAC is not the guest MAC, or whose sender IPv4 address is not allowed.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AntiSpoofingConfig {
+    /// The IPv4 addresses the guest can send IPv4 packets and ARP frames from.
+    pub ipv4_allowlist: Vec<Ipv4Addr>,
+}
+
+/// The configuration of the destinations the guest of a network interface with a `User` backend
+/// can reach.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserBackendConfig {
+    /// Whether the connections to the gateway go to the loopback interface of the host.
+    #[serde(default)]
+    pub host_loopback: bool,
+    /// The only networks the guest can reach. When missing, the guest can reach any address
+    /// but the link-local ones.
+    pub allowed_networks: Option<Vec<Ipv4Network>>,
+}
+
+impl From<UserBackendConfig> for UserNetworkConfig {
+    fn from(config: UserBackendConfig) -> Self {
+        UserNetworkConfig {
+            host_loopback: config.host_loopback,
+            allowed_networks: config.allowed_networks,
+        }
+    }
+}
+
+impl From<&UserNetworkConfig> for UserBackendConfig {
+    fn from(config: &UserNetworkConfig) -> Self {
+        UserBackendConfig {
+            host_loopback: config.host_loopback,
+            allowed_networks: config.allowed_networks.clone(),
+        }
+    }
 }
 
 impl From<&Net> for NetworkInterfaceConfig {
@@ -41,12 +119,24 @@ impl From<&Net> for NetworkInterfaceConfig {
             guest_mac: net.guest_mac().copied(),
             rx_rate_limiter: rx_rl.into_option(),
             tx_rate_limiter: tx_rl.into_option(),
+            num_queue_pairs: net.num_queue_pairs(),
+            mtu: net.mtu(),
+            anti_spoofing: net.anti_spoofing_filter().map(|filter| AntiSpoofingConfig {
+                ipv4_allowlist: filter.ipv4_allowlist().to_vec(),
+            }),
+            backend_type: match net.user_ns() {
+                Some(_) => NetworkBackendType::User,
+                None => NetworkBackendType::Tap,
+            },
+            user_backend: net
+                .user_ns()
+                .map(|user_ns| UserBackendConfig::from(user_ns.config())),
         }
     }
 }
 
 /// The data fed into a network iface update request. Currently, only the RX and TX rate limiters
-/// can be updated.
+/// and the link state can be updated.
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct NetworkInterfaceUpdateConfig {
@@ -58,33 +148,101 @@ pub struct NetworkInterfaceUpdateConfig {
     /// New TX rate limiter config. Only provided data will be updated. I.e. if any optional data
     /// is missing, it will not be nullified, but left unchanged.
     pub tx_rate_limiter: Option<RateLimiterConfig>,
+    /// New link state reported to the guest, which is notified of the change.
+    pub link_up: Option<bool>,
+}
+
+/// The state of the packet capture of a network interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum NetworkCaptureState {
+    /// The frames going through the interface are written to the capture file.
+    Started,
+    /// The frames going through the interface are not captured.
+    Stopped,
+}
+
+/// The data fed into a request starting or stopping the packet capture of a network iface.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkCaptureConfig {
+    /// The net iface ID, as provided by the user at iface creation time.
+    pub iface_id: String,
+    /// Whether the capture is started or stopped.
+    pub state: NetworkCaptureState,
+    /// Host level path of the pcap file the frames are written to, required to start a capture.
+    pub path_on_host: Option<String>,
+    /// The size the capture file can't grow past.
+    pub max_bytes: Option<u64>,
+    /// The number of frames after which the capture stops.
+    pub max_packets: Option<u64>,
+}
+
+impl NetworkCaptureConfig {
+    /// Creates the capture file of a started capture.
+    pub fn open_capture(&self) -> Result<Option<PacketCapture>> {
+        match self.state {
+            NetworkCaptureState::Started => {
+                let path = self
+                    .path_on_host
+                    .as_ref()
+                    .ok_or(NetworkInterfaceError::CapturePathMissing)?;
+                PacketCapture::new(Path::new(path), self.max_bytes, self.max_packets)
+                    .map(Some)
+                    .map_err(NetworkInterfaceError::OpenCaptureFile)
+            }
+            NetworkCaptureState::Stopped => Ok(None),
+        }
+    }
 }
 
 /// Errors associated with `NetworkInterfaceConfig`.
 #[derive(Debug, derive_more::From)]
 pub enum NetworkInterfaceError {
+    /// The anti-spoofing filter requires a guest MAC address.
+    AntiSpoofingWithoutGuestMac,
+    /// Starting a packet capture requires the path of the capture file.
+    CapturePathMissing,
     /// Could not create Network Device.
     CreateNetworkDevice(devices::virtio::net::Error),
     /// Failed to create a `RateLimiter` object.
     CreateRateLimiter(std::io::Error),
+    /// No network interface has the given id.
+    #[from(ignore)]
+    DeviceNotFound(String),
     /// The MAC address is already in use.
     GuestMacAddressInUse(String),
     /// Error during interface update (patch).
     DeviceUpdate(VmmError),
+    /// Cannot create the packet capture file.
+    #[from(ignore)]
+    OpenCaptureFile(io::Error),
     /// Cannot open/create tap device.
     OpenTap(TapError),
+    /// The option is not supported by network interfaces with a user backend.
+    UnsupportedUserBackendOption(&'static str),
+    /// The user backend configuration requires a user backend.
+    UserBackendConfigWithoutUserBackend,
 }
 
 impl fmt::Display for NetworkInterfaceError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::NetworkInterfaceError::*;
         match self {
+            AntiSpoofingWithoutGuestMac => {
+                write!(f, "The anti-spoofing filter requires a guest MAC address.")
+            }
+            CapturePathMissing => write!(
+                f,
+                "Starting a packet capture requires the path of the capture file."
+            ),
             CreateNetworkDevice(err) => write!(f, "Could not create Network Device: {:?}", err),
             CreateRateLimiter(err) => write!(f, "Cannot create RateLimiter: {}", err),
+            DeviceNotFound(iface_id) => write!(f, "No network interface has the id {iface_id}."),
             GuestMacAddressInUse(mac_addr) => {
                 write!(f, "The guest MAC address {mac_addr} is already in use.")
             }
             DeviceUpdate(err) => write!(f, "Error during interface update (patch): {}", err),
+            OpenCaptureFile(err) => write!(f, "Cannot create the packet capture file: {}", err),
             OpenTap(err) => {
                 // We are propagating the Tap Error. This error can contain
                 // imbricated quotes which would result in an invalid json.
@@ -96,6 +254,15 @@ impl fmt::Display for NetworkInterfaceError {
                     "Cannot open TAP device. Invalid name/permissions. {tap_err}",
                 )
             }
+            UnsupportedUserBackendOption(option) => write!(
+                f,
+                "Network interfaces with a user backend do not support the {} option",
+                option
+            ),
+            UserBackendConfigWithoutUserBackend => write!(
+                f,
+                "The user_backend option requires a network interface with a user backend."
+            ),
         }
     }
 }
@@ -132,6 +299,14 @@ impl NetBuilder {
         self.net_devices.push(device);
     }
 
+    /// Removes the network device with the specified `iface_id`, if present.
+    pub fn remove(&mut self, iface_id: &str) -> Option<Arc<Mutex<Net>>> {
+        self.net_devices
+            .iter()
+            .position(|net| net.lock().expect("Poisoned lock").id() == iface_id)
+            .map(|index| self.net_devices.remove(index))
+    }
+
     /// Builds a network device based on a network interface config. Keeps a device reference
     /// in the builder's internal list.
     pub fn build(&mut self, netif_config: NetworkInterfaceConfig) -> Result<Arc<Mutex<Net>>> {
@@ -169,6 +344,29 @@ impl NetBuilder {
 
     /// Creates a Net device from a NetworkInterfaceConfig.
     pub fn create_net(cfg: NetworkInterfaceConfig) -> Result<Net> {
+        if cfg.backend_type == NetworkBackendType::User {
+            if !cfg.host_dev_name.is_empty() {
+                return Err(NetworkInterfaceError::UnsupportedUserBackendOption(
+                    "host_dev_name",
+                ));
+            }
+            if cfg.num_queue_pairs != DEFAULT_NUM_QUEUE_PAIRS {
+                return Err(NetworkInterfaceError::UnsupportedUserBackendOption(
+                    "num_queue_pairs",
+                ));
+            }
+        } else if cfg.user_backend.is_some() {
+            return Err(NetworkInterfaceError::UserBackendConfigWithoutUserBackend);
+        }
+
+        let anti_spoofing_filter = cfg
+            .anti_spoofing
+            .map(|anti_spoofing| {
+                cfg.guest_mac
+                    .map(|mac| AntiSpoofingFilter::new(mac, anti_spoofing.ipv4_allowlist))
+                    .ok_or(NetworkInterfaceError::AntiSpoofingWithoutGuestMac)
+            })
+            .transpose()?;
         let rx_rate_limiter = cfg
             .rx_rate_limiter
             .map(super::RateLimiterConfig::try_into)
@@ -179,14 +377,40 @@ impl NetBuilder {
             .transpose()?;
 
         // Create and return the Net device
-        devices::virtio::net::Net::new_with_tap(
-            cfg.iface_id,
-            cfg.host_dev_name.clone(),
-            cfg.guest_mac,
-            rx_rate_limiter.unwrap_or_default(),
-            tx_rate_limiter.unwrap_or_default(),
-        )
-        .map_err(NetworkInterfaceError::CreateNetworkDevice)
+        let mut net = match cfg.backend_type {
+            NetworkBackendType::Tap => devices::virtio::net::Net::new_with_tap(
+                cfg.iface_id,
+                cfg.host_dev_name.clone(),
+                cfg.guest_mac,
+                rx_rate_limiter.unwrap_or_default(),
+                tx_rate_limiter.unwrap_or_default(),
+                cfg.num_queue_pairs,
+                cfg.mtu,
+            ),
+            NetworkBackendType::User => devices::virtio::net::Net::new_with_user_ns(
+                cfg.iface_id,
+                cfg.guest_mac,
+                rx_rate_limiter.unwrap_or_default(),
+                tx_rate_limiter.unwrap_or_default(),
+                cfg.mtu,
+                cfg.user_backend.unwrap_or_default().into(),
+            ),
+        }
+        .map_err(NetworkInterfaceError::CreateNetworkDevice)?;
+        net.set_anti_spoofing_filter(anti_spoofing_filter);
+        Ok(net)
+    }
+
+    /// Starts or stops the packet capture of the network device with the specified `iface_id`.
+    pub fn set_capture(&mut self, capture_cfg: &NetworkCaptureConfig) -> Result<()> {
+        let net = self
+            .net_devices
+            .iter()
+            .find(|net| net.lock().expect("Poisoned lock").id() == &capture_cfg.iface_id)
+            .ok_or_else(|| NetworkInterfaceError::DeviceNotFound(capture_cfg.iface_id.clone()))?;
+        let capture = capture_cfg.open_capture()?;
+        net.lock().expect("Poisoned lock").set_capture(capture);
+        Ok(())
     }
 
     /// Returns a vec with the structures used to configure the net devices.
@@ -204,6 +428,7 @@ mod tests {
     use std::str;
 
     use rate_limiter::RateLimiter;
+    use utils::tempfile::TempFile;
 
     use super::*;
 
@@ -224,6 +449,11 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str(mac).unwrap()),
             rx_rate_limiter: RateLimiterConfig::default().into_option(),
             tx_rate_limiter: RateLimiterConfig::default().into_option(),
+            num_queue_pairs: 1,
+            mtu: None,
+            anti_spoofing: None,
+            backend_type: NetworkBackendType::Tap,
+            user_backend: None,
         }
     }
 
@@ -235,6 +465,11 @@ mod tests {
                 guest_mac: self.guest_mac,
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                num_queue_pairs: self.num_queue_pairs,
+                mtu: self.mtu,
+                anti_spoofing: self.anti_spoofing.clone(),
+                backend_type: self.backend_type,
+                user_backend: self.user_backend.clone(),
             }
         }
     }
@@ -266,6 +501,23 @@ mod tests {
         assert_eq!(net_builder.net_devices.len(), 1);
     }
 
+    #[test]
+    fn test_remove() {
+        let mut net_builder = NetBuilder::new();
+        let netif_1 = create_netif("id_1", "dev5", "01:23:45:67:89:0c");
+        let netif_2 = create_netif("id_2", "dev6", "01:23:45:67:89:0d");
+        net_builder.build(netif_1).unwrap();
+        net_builder.build(netif_2).unwrap();
+
+        assert!(net_builder.remove("id_3").is_none());
+        assert_eq!(
+            net_builder.remove("id_1").unwrap().lock().unwrap().id(),
+            "id_1"
+        );
+        assert_eq!(net_builder.configs().len(), 1);
+        assert_eq!(net_builder.configs()[0].iface_id, "id_2");
+    }
+
     #[test]
     fn test_insert_error_cases() {
         let mut net_builder = NetBuilder::new();
@@ -346,6 +598,16 @@ mod tests {
             NetworkInterfaceError::OpenTap(TapError::InvalidIfname),
             NetworkInterfaceError::OpenTap(TapError::InvalidIfname)
         );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::UnsupportedUserBackendOption("host_dev_name"),
+            NetworkInterfaceError::UnsupportedUserBackendOption("host_dev_name")
+        );
+        let _ = format!(
+            "{}{:?}",
+            NetworkInterfaceError::UserBackendConfigWithoutUserBackend,
+            NetworkInterfaceError::UserBackendConfigWithoutUserBackend
+        );
     }
 
     #[test]
@@ -369,6 +631,218 @@ mod tests {
         assert_eq!(configs.first().unwrap(), &net_if_cfg);
     }
 
+    #[test]
+    fn test_multi_queue_net_config() {
+        let mut netif = create_netif("id_mq", "dev_mq", "01:23:45:67:89:0e");
+        netif.num_queue_pairs = 4;
+
+        let mut net_builder = NetBuilder::new();
+        let net = net_builder.build(netif.clone()).unwrap();
+        assert_eq!(net.lock().unwrap().num_queue_pairs(), 4);
+        assert_eq!(net_builder.configs()[0].num_queue_pairs, 4);
+
+        // A device needs at least one queue pair.
+        netif.num_queue_pairs = 0;
+        assert!(matches!(
+            net_builder.build(netif),
+            Err(NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::InvalidNumQueuePairs(0)
+            ))
+        ));
+
+        // The number of queue pairs defaults to one.
+        let config: NetworkInterfaceConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id_mq",
+                "host_dev_name": "dev_mq"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.num_queue_pairs, 1);
+    }
+
+    #[test]
+    fn test_mtu_net_config() {
+        let mut netif = create_netif("id_mtu", "dev_mtu", "01:23:45:67:89:0f");
+        netif.mtu = Some(9000);
+
+        let mut net_builder = NetBuilder::new();
+        let net = net_builder.build(netif.clone()).unwrap();
+        assert_eq!(net.lock().unwrap().mtu(), Some(9000));
+        assert_eq!(net_builder.configs()[0].mtu, Some(9000));
+
+        // The guest driver must support the MTU.
+        netif.mtu = Some(67);
+        assert!(matches!(
+            net_builder.build(netif),
+            Err(NetworkInterfaceError::CreateNetworkDevice(
+                devices::virtio::net::Error::InvalidMtu(67)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_anti_spoofing_net_config() {
+        let mut netif: NetworkInterfaceConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id_spoof",
+                "host_dev_name": "dev_spoof",
+                "guest_mac": "01:23:45:67:89:11",
+                "anti_spoofing": {
+                    "ipv4_allowlist": ["10.1.2.3", "0.0.0.0"]
+                }
+            }"#,
+        )
+        .unwrap();
+        let allowlist = vec![Ipv4Addr::new(10, 1, 2, 3), Ipv4Addr::new(0, 0, 0, 0)];
+
+        let mut net_builder = NetBuilder::new();
+        let net = net_builder.build(netif.clone()).unwrap();
+        let filter = net.lock().unwrap().anti_spoofing_filter().cloned().unwrap();
+        assert_eq!(filter.guest_mac(), netif.guest_mac.unwrap());
+        assert_eq!(filter.ipv4_allowlist(), allowlist.as_slice());
+        assert_eq!(net_builder.configs()[0], netif);
+
+        // The filter needs to know the guest MAC.
+        netif.guest_mac = None;
+        assert!(matches!(
+            net_builder.build(netif),
+            Err(NetworkInterfaceError::AntiSpoofingWithoutGuestMac)
+        ));
+    }
+
+    #[test]
+    fn test_user_backend_net_config() {
+        let mut netif: NetworkInterfaceConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id_user",
+                "guest_mac": "01:23:45:67:89:12",
+                "backend_type": "User",
+                "user_backend": {
+                    "host_loopback": true,
+                    "allowed_networks": ["192.0.2.0/24", "169.254.169.254/32"]
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(netif.host_dev_name, "");
+
+        let mut net_builder = NetBuilder::new();
+        let net = net_builder.build(netif.clone()).unwrap();
+        let user_config = net.lock().unwrap().user_ns().unwrap().config().clone();
+        assert!(user_config.host_loopback);
+        assert_eq!(
+            user_config.allowed_networks,
+            Some(vec![
+                Ipv4Network::parse_str("192.0.2.0/24").unwrap(),
+                Ipv4Network::parse_str("169.254.169.254/32").unwrap(),
+            ])
+        );
+        assert_eq!(net_builder.configs()[0], netif);
+
+        // The loopback interface of the host is not reachable by default.
+        let config: NetworkInterfaceConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id_user",
+                "backend_type": "User"
+            }"#,
+        )
+        .unwrap();
+        let net = net_builder.build(config).unwrap();
+        assert_eq!(
+            net.lock().unwrap().user_ns().unwrap().config(),
+            &UserNetworkConfig::default()
+        );
+        assert!(serde_json::from_str::<NetworkInterfaceConfig>(
+            r#"{
+                "iface_id": "id_user",
+                "backend_type": "User",
+                "user_backend": {
+                    "allowed_networks": ["192.0.2.1/24"]
+                }
+            }"#,
+        )
+        .is_err());
+
+        // The backend type defaults to a tap.
+        let config: NetworkInterfaceConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id_user",
+                "host_dev_name": "dev_user"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.backend_type, NetworkBackendType::Tap);
+
+        // The user backend has neither a tap nor several queue pairs.
+        netif.host_dev_name = "dev_user".to_string();
+        assert!(matches!(
+            net_builder.build(netif.clone()),
+            Err(NetworkInterfaceError::UnsupportedUserBackendOption(
+                "host_dev_name"
+            ))
+        ));
+        netif.host_dev_name = String::new();
+        netif.num_queue_pairs = 2;
+        assert!(matches!(
+            net_builder.build(netif.clone()),
+            Err(NetworkInterfaceError::UnsupportedUserBackendOption(
+                "num_queue_pairs"
+            ))
+        ));
+
+        // Tap interfaces don't take the configuration of the user backend.
+        netif.host_dev_name = "dev_user".to_string();
+        netif.num_queue_pairs = 1;
+        netif.backend_type = NetworkBackendType::Tap;
+        assert!(matches!(
+            net_builder.build(netif),
+            Err(NetworkInterfaceError::UserBackendConfigWithoutUserBackend)
+        ));
+    }
+
+    #[test]
+    fn test_net_capture() {
+        let file = TempFile::new().unwrap();
+        let mut net_builder = NetBuilder::new();
+        let net = net_builder
+            .build(create_netif("id_pcap", "dev_pcap", "01:23:45:67:89:10"))
+            .unwrap();
+
+        let mut capture_cfg: NetworkCaptureConfig = serde_json::from_str(&format!(
+            r#"{{
+                "iface_id": "id_pcap",
+                "state": "Started",
+                "path_on_host": "{}",
+                "max_packets": 10
+            }}"#,
+            file.as_path().to_str().unwrap()
+        ))
+        .unwrap();
+        net_builder.set_capture(&capture_cfg).unwrap();
+        assert!(net.lock().unwrap().is_capturing());
+        // The pcap header was written.
+        assert_eq!(file.as_file().metadata().unwrap().len(), 24);
+
+        capture_cfg.state = NetworkCaptureState::Stopped;
+        net_builder.set_capture(&capture_cfg).unwrap();
+        assert!(!net.lock().unwrap().is_capturing());
+
+        // Starting a capture requires a path.
+        capture_cfg.state = NetworkCaptureState::Started;
+        capture_cfg.path_on_host = None;
+        assert!(matches!(
+            net_builder.set_capture(&capture_cfg),
+            Err(NetworkInterfaceError::CapturePathMissing)
+        ));
+
+        capture_cfg.iface_id = "id_unknown".to_string();
+        assert!(matches!(
+            net_builder.set_capture(&capture_cfg),
+            Err(NetworkInterfaceError::DeviceNotFound(id)) if id == "id_unknown"
+        ));
+    }
+
     #[test]
     fn test_add_device() {
         let mut net_builder = NetBuilder::new();
@@ -382,6 +856,8 @@ mod tests {
             Some(MacAddr::parse_str(guest_mac).unwrap()),
             RateLimiter::default(),
             RateLimiter::default(),
+            1,
+            None,
         )
         .unwrap();
 
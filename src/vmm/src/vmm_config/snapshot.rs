@@ -3,9 +3,11 @@
 
 //! Configurations used in the snapshotting context.
 
+use std::collections::HashMap;
 use std::path::PathBuf;
 
 use serde::{Deserialize, Serialize};
+use utils::net::mac::MacAddr;
 
 /// The snapshot type options that are available when
 /// creating a new snapshot.
@@ -15,6 +17,11 @@ pub enum SnapshotType {
     Diff,
     /// Full snapshot.
     Full,
+    /// Full snapshot taken without pausing the microVM for the whole memory dump.
+    /// Guest memory is copied while the vCPUs are running, using dirty page
+    /// tracking, and the microVM is only paused for the last dirty set and
+    /// the vCPU and device state.
+    Live,
 }
 
 impl Default for SnapshotType {
@@ -23,17 +30,41 @@ impl Default for SnapshotType {
     }
 }
 
+/// The format of the guest memory file written when creating a snapshot.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum MemFileFormat {
+    /// Guest memory is written as is, so that the file can be directly
+    /// mapped when loading the snapshot.
+    Raw,
+    /// Guest memory is written in checksummed chunks, with the chunks
+    /// containing only zeroes left out and the others LZ4 compressed.
+    /// Only supported for full snapshots.
+    Compressed,
+}
+
+impl Default for MemFileFormat {
+    fn default() -> Self {
+        MemFileFormat::Raw
+    }
+}
+
 /// Specifies the method through which guest memory will get populated when
 /// resuming from a snapshot:
 /// 1) A file that contains the guest memory to be loaded,
 /// 2) An UDS where a custom page-fault handler process is listening for
-///    the UFFD set up by Firecracker to handle its guest memory page faults.
+///    the UFFD set up by Firecracker to handle its guest memory page faults,
+/// 3) A base memory file with a chain of diff memory files on top of it.
 #[derive(Debug, PartialEq, Eq, Deserialize)]
 pub enum MemBackendType {
     /// Guest memory contents will be loaded from a file.
     File,
     /// Guest memory will be served through UFFD by a separate process.
+    /// The process serves pages at the offsets of a raw memory file, so
+    /// compressed memory files cannot be used with this backend.
     Uffd,
+    /// Guest memory contents will be loaded from a base file, overlaid with
+    /// the pages of a chain of diff files.
+    Layered,
 }
 
 /// Stores the configuration that will be used for creating a snapshot.
@@ -48,6 +79,13 @@ pub struct CreateSnapshotParams {
     pub snapshot_path: PathBuf,
     /// Path to the file that will contain the guest memory.
     pub mem_file_path: PathBuf,
+    /// Format of the guest memory file.
+    /// The default value is `Raw`, which means an uncompressed file.
+    #[serde(default = "MemFileFormat::default")]
+    pub mem_file_format: MemFileFormat,
+    /// Optional path to the file that will contain the manifest of the pages
+    /// present in the memory file. Only allowed for diff snapshots.
+    pub diff_manifest_path: Option<PathBuf>,
     /// Optional field for the microVM version. The default
     /// value is the current version.
     pub version: Option<String>,
@@ -66,6 +104,10 @@ pub struct LoadSnapshotParams {
     /// When set to true, the vm is also resumed if the snapshot load
     /// is successful.
     pub resume_vm: bool,
+    /// Host resources replacing the ones saved in the snapshot for the devices.
+    pub device_overrides: DeviceOverrides,
+    /// When set, a new guest identity is assigned to the restored microVM.
+    pub clone_identity: Option<CloneIdentity>,
 }
 
 /// Stores the configuration for loading a snapshot that is provided by the user.
@@ -88,6 +130,42 @@ pub struct LoadSnapshotConfig {
     /// Whether or not to resume the vm post snapshot load.
     #[serde(default)]
     pub resume_vm: bool,
+    /// Host resources replacing the ones saved in the snapshot for the devices.
+    #[serde(default)]
+    pub device_overrides: DeviceOverrides,
+    /// When set, a new guest identity is assigned to the restored microVM.
+    pub clone_identity: Option<CloneIdentity>,
+}
+
+/// Host resources of the restored devices overriding the ones saved in the snapshot,
+/// which usually do not exist when restoring on another host.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceOverrides {
+    /// New host paths of the block device backing files, by drive ID.
+    #[serde(default)]
+    pub drives: HashMap<String, String>,
+    /// New host tap device names of the network interfaces, by interface ID.
+    #[serde(default)]
+    pub network_interfaces: HashMap<String, String>,
+    /// New path of the Unix domain socket backing the vsock device.
+    pub vsock_uds_path: Option<String>,
+}
+
+/// Guest identity assigned to a restored microVM, so that the microVMs restored
+/// from the same snapshot can be told apart by their peers and by themselves.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CloneIdentity {
+    /// Guest MAC addresses of the network interfaces, by interface ID. The interfaces
+    /// not listed are assigned a random locally administered MAC address.
+    #[serde(default)]
+    pub guest_macs: HashMap<String, MacAddr>,
+    /// Guest CID of the vsock device. A random one is assigned if not set.
+    pub vsock_guest_cid: Option<u32>,
+    /// Top level MMDS key under which the new identity is published, so that the
+    /// guest can learn that it was cloned.
+    pub mmds_key: Option<String>,
 }
 
 /// Stores the configuration used for managing snapshot memory.
@@ -98,6 +176,21 @@ pub struct MemBackendConfig {
     pub backend_path: PathBuf,
     /// Specifies the guest memory backend type.
     pub backend_type: MemBackendType,
+    /// Diff memory files applied on top of the backend file, from the oldest
+    /// to the newest. Only allowed for the `Layered` backend type.
+    #[serde(default)]
+    pub diff_layers: Vec<MemLayerConfig>,
+}
+
+/// Stores the configuration of a diff memory file of a layered memory backend.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MemLayerConfig {
+    /// Path to the diff memory file.
+    pub mem_file_path: PathBuf,
+    /// Optional path to the manifest of the pages present in the diff memory
+    /// file. Without it, the pages are found from the holes of the file.
+    pub diff_manifest_path: Option<PathBuf>,
 }
 
 /// The microVM state options.
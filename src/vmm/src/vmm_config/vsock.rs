@@ -103,6 +103,17 @@ impl VsockBuilder {
         Ok(())
     }
 
+    /// Removes the Vsock, along with its socket, if present.
+    pub fn remove(&mut self) -> Result<Option<MutexVsockUnix>> {
+        match self.inner.take() {
+            Some(existing) => {
+                std::fs::remove_file(existing.uds_path).map_err(VsockUnixBackendError::UnixBind)?;
+                Ok(Some(existing.vsock))
+            }
+            None => Ok(None),
+        }
+    }
+
     /// Provides a reference to the Vsock if present.
     pub fn get(&self) -> Option<&MutexVsockUnix> {
         self.inner.as_ref().map(|pair| &pair.vsock)
@@ -162,6 +173,21 @@ pub(crate) mod tests {
         assert_eq!(vsock.lock().unwrap().cid(), u64::from(new_cid));
     }
 
+    #[test]
+    fn test_vsock_remove() {
+        let mut store = VsockBuilder::new();
+        assert!(store.remove().unwrap().is_none());
+
+        let mut tmp_sock_file = TempFile::new().unwrap();
+        tmp_sock_file.remove().unwrap();
+        store.insert(default_config(&tmp_sock_file)).unwrap();
+        assert!(tmp_sock_file.as_path().exists());
+
+        assert!(store.remove().unwrap().is_some());
+        assert!(store.get().is_none());
+        assert!(!tmp_sock_file.as_path().exists());
+    }
+
     #[test]
     fn test_vsock_config() {
         let mut vsock_builder = VsockBuilder::new();
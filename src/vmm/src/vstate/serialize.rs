@@ -0,0 +1,265 @@
+// Copyright 2022 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Serializers describing the KVM structures saved in the microVM state as JSON.
+//!
+//! The KVM bindings do not implement `serde`, so the saved structures are described
+//! field by field. Opaque register pages are described as hexadecimal strings.
+
+use kvm_bindings::kvm_mp_state;
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::{
+    kvm_clock_data, kvm_debugregs, kvm_dtable, kvm_irqchip, kvm_lapic_state, kvm_pit_state2,
+    kvm_regs, kvm_segment, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave, CpuId, Msrs,
+};
+use serde::{Serialize, Serializer};
+#[cfg(target_arch = "x86_64")]
+use serde_json::{json, Value};
+
+#[cfg(target_arch = "x86_64")]
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub(crate) fn mp_state<S: Serializer>(
+    state: &kvm_mp_state,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    state.mp_state.serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn cpuid<S: Serializer>(cpuid: &CpuId, serializer: S) -> Result<S::Ok, S::Error> {
+    let entries: Vec<Value> = cpuid
+        .as_slice()
+        .iter()
+        .map(|entry| {
+            json!({
+                "function": entry.function,
+                "index": entry.index,
+                "flags": entry.flags,
+                "eax": entry.eax,
+                "ebx": entry.ebx,
+                "ecx": entry.ecx,
+                "edx": entry.edx,
+            })
+        })
+        .collect();
+    entries.serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn msrs<S: Serializer>(msrs: &[Msrs], serializer: S) -> Result<S::Ok, S::Error> {
+    // The MSRs are saved in chunks, which are only an artifact of the KVM interface.
+    let entries: Vec<Value> = msrs
+        .iter()
+        .flat_map(|chunk| chunk.as_slice().iter())
+        .map(|entry| json!({ "index": entry.index, "data": entry.data }))
+        .collect();
+    entries.serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn debug_regs<S: Serializer>(
+    regs: &kvm_debugregs,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    json!({
+        "db": regs.db,
+        "dr6": regs.dr6,
+        "dr7": regs.dr7,
+        "flags": regs.flags,
+    })
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn lapic<S: Serializer>(
+    lapic: &kvm_lapic_state,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let regs: Vec<u8> = lapic.regs.iter().map(|&byte| byte as u8).collect();
+    hex_bytes(&regs).serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn regs<S: Serializer>(regs: &kvm_regs, serializer: S) -> Result<S::Ok, S::Error> {
+    json!({
+        "rax": regs.rax,
+        "rbx": regs.rbx,
+        "rcx": regs.rcx,
+        "rdx": regs.rdx,
+        "rsi": regs.rsi,
+        "rdi": regs.rdi,
+        "rsp": regs.rsp,
+        "rbp": regs.rbp,
+        "r8": regs.r8,
+        "r9": regs.r9,
+        "r10": regs.r10,
+        "r11": regs.r11,
+        "r12": regs.r12,
+        "r13": regs.r13,
+        "r14": regs.r14,
+        "r15": regs.r15,
+        "rip": regs.rip,
+        "rflags": regs.rflags,
+    })
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+fn segment(segment: &kvm_segment) -> Value {
+    json!({
+        "base": segment.base,
+        "limit": segment.limit,
+        "selector": segment.selector,
+        "type": segment.type_,
+        "present": segment.present,
+        "dpl": segment.dpl,
+        "db": segment.db,
+        "s": segment.s,
+        "l": segment.l,
+        "g": segment.g,
+        "avl": segment.avl,
+        "unusable": segment.unusable,
+    })
+}
+
+#[cfg(target_arch = "x86_64")]
+fn dtable(dtable: &kvm_dtable) -> Value {
+    json!({ "base": dtable.base, "limit": dtable.limit })
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn sregs<S: Serializer>(sregs: &kvm_sregs, serializer: S) -> Result<S::Ok, S::Error> {
+    json!({
+        "cs": segment(&sregs.cs),
+        "ds": segment(&sregs.ds),
+        "es": segment(&sregs.es),
+        "fs": segment(&sregs.fs),
+        "gs": segment(&sregs.gs),
+        "ss": segment(&sregs.ss),
+        "tr": segment(&sregs.tr),
+        "ldt": segment(&sregs.ldt),
+        "gdt": dtable(&sregs.gdt),
+        "idt": dtable(&sregs.idt),
+        "cr0": sregs.cr0,
+        "cr2": sregs.cr2,
+        "cr3": sregs.cr3,
+        "cr4": sregs.cr4,
+        "cr8": sregs.cr8,
+        "efer": sregs.efer,
+        "apic_base": sregs.apic_base,
+        "interrupt_bitmap": sregs.interrupt_bitmap,
+    })
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn vcpu_events<S: Serializer>(
+    events: &kvm_vcpu_events,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    json!({
+        "exception": {
+            "injected": events.exception.injected,
+            "nr": events.exception.nr,
+            "has_error_code": events.exception.has_error_code,
+            "pending": events.exception.pending,
+            "error_code": events.exception.error_code,
+        },
+        "interrupt": {
+            "injected": events.interrupt.injected,
+            "nr": events.interrupt.nr,
+            "soft": events.interrupt.soft,
+            "shadow": events.interrupt.shadow,
+        },
+        "nmi": {
+            "injected": events.nmi.injected,
+            "pending": events.nmi.pending,
+            "masked": events.nmi.masked,
+        },
+        "sipi_vector": events.sipi_vector,
+        "flags": events.flags,
+        "smi": {
+            "smm": events.smi.smm,
+            "pending": events.smi.pending,
+            "smm_inside_nmi": events.smi.smm_inside_nmi,
+            "latched_init": events.smi.latched_init,
+        },
+        "exception_has_payload": events.exception_has_payload,
+        "exception_payload": events.exception_payload,
+    })
+    .serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn xcrs<S: Serializer>(xcrs: &kvm_xcrs, serializer: S) -> Result<S::Ok, S::Error> {
+    let entries: Vec<Value> = xcrs
+        .xcrs
+        .iter()
+        .take(xcrs.nr_xcrs as usize)
+        .map(|xcr| json!({ "xcr": xcr.xcr, "value": xcr.value }))
+        .collect();
+    json!({ "flags": xcrs.flags, "xcrs": entries }).serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn xsave<S: Serializer>(xsave: &kvm_xsave, serializer: S) -> Result<S::Ok, S::Error> {
+    let region: Vec<u8> = xsave
+        .region
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+    hex_bytes(&region).serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn pit_state<S: Serializer>(
+    state: &kvm_pit_state2,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let channels: Vec<Value> = state
+        .channels
+        .iter()
+        .map(|channel| {
+            json!({
+                "count": channel.count,
+                "latched_count": channel.latched_count,
+                "count_latched": channel.count_latched,
+                "status_latched": channel.status_latched,
+                "status": channel.status,
+                "read_state": channel.read_state,
+                "write_state": channel.write_state,
+                "write_latch": channel.write_latch,
+                "rw_mode": channel.rw_mode,
+                "mode": channel.mode,
+                "bcd": channel.bcd,
+                "gate": channel.gate,
+                "count_load_time": channel.count_load_time,
+            })
+        })
+        .collect();
+    json!({ "channels": channels, "flags": state.flags }).serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn clock<S: Serializer>(
+    clock: &kvm_clock_data,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    json!({ "clock": clock.clock, "flags": clock.flags }).serialize(serializer)
+}
+
+#[cfg(target_arch = "x86_64")]
+pub(crate) fn irqchip<S: Serializer>(
+    irqchip: &kvm_irqchip,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    // SAFETY: Safe because `dummy` spans the whole union and any byte value is valid.
+    let chip: Vec<u8> = unsafe { irqchip.chip.dummy }
+        .iter()
+        .map(|&byte| byte as u8)
+        .collect();
+    json!({ "chip_id": irqchip.chip_id, "chip": hex_bytes(&chip) }).serialize(serializer)
+}
@@ -11,10 +11,12 @@ use std::result;
 use arch::aarch64::regs::Aarch64Register;
 use kvm_ioctls::*;
 use logger::{error, IncMetric, METRICS};
+use serde::Serialize;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{Address, GuestAddress, GuestMemoryMmap};
 
+use crate::vstate::serialize;
 use crate::vstate::vcpu::VcpuEmulation;
 use crate::vstate::vm::Vm;
 
@@ -177,8 +179,9 @@ impl KvmVcpu {
 }
 
 /// Structure holding VCPU kvm state.
-#[derive(Clone, Default, Versionize)]
+#[derive(Clone, Default, Serialize, Versionize)]
 pub struct VcpuState {
+    #[serde(serialize_with = "serialize::mp_state")]
     pub mp_state: kvm_bindings::kvm_mp_state,
     pub regs: Vec<Aarch64Register>,
     // We will be using the mpidr for passing it to the VmState.
@@ -19,11 +19,13 @@ use kvm_bindings::{
 };
 use kvm_ioctls::{VcpuExit, VcpuFd};
 use logger::{error, warn, IncMetric, METRICS};
+use serde::Serialize;
 use versionize::{VersionMap, Versionize, VersionizeError, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{Address, GuestAddress, GuestMemoryMmap};
 
 use crate::vmm_config::machine_config::CpuFeaturesTemplate;
+use crate::vstate::serialize;
 use crate::vstate::vcpu::{VcpuConfig, VcpuEmulation};
 use crate::vstate::vm::Vm;
 
@@ -535,22 +537,34 @@ impl KvmVcpu {
     }
 }
 
-#[derive(Clone, Versionize)]
+#[derive(Clone, Serialize, Versionize)]
 /// Structure holding VCPU kvm state.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VcpuState {
+    #[serde(serialize_with = "serialize::cpuid")]
     pub cpuid: CpuId,
+    // Only used by older snapshot versions, the MSRs end up in `saved_msrs`.
+    #[serde(skip)]
     #[version(end = 3, default_fn = "default_msrs")]
     msrs: Msrs,
+    #[serde(rename = "msrs", serialize_with = "serialize::msrs")]
     #[version(start = 3, de_fn = "de_saved_msrs", ser_fn = "ser_saved_msrs")]
     saved_msrs: Vec<Msrs>,
+    #[serde(serialize_with = "serialize::debug_regs")]
     debug_regs: kvm_debugregs,
+    #[serde(serialize_with = "serialize::lapic")]
     lapic: kvm_lapic_state,
+    #[serde(serialize_with = "serialize::mp_state")]
     mp_state: kvm_mp_state,
+    #[serde(serialize_with = "serialize::regs")]
     regs: kvm_regs,
+    #[serde(serialize_with = "serialize::sregs")]
     sregs: kvm_sregs,
+    #[serde(serialize_with = "serialize::vcpu_events")]
     vcpu_events: kvm_vcpu_events,
+    #[serde(serialize_with = "serialize::xcrs")]
     xcrs: kvm_xcrs,
+    #[serde(serialize_with = "serialize::xsave")]
     xsave: kvm_xsave,
     #[version(start = 2, default_fn = "default_tsc_khz", ser_fn = "ser_tsc")]
     pub tsc_khz: Option<u32>,
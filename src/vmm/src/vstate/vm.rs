@@ -20,10 +20,14 @@ use kvm_bindings::{
 };
 use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
 use kvm_ioctls::{Kvm, VmFd};
+use serde::Serialize;
 use versionize::{VersionMap, Versionize, VersionizeResult};
 use versionize_derive::Versionize;
 use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
 
+#[cfg(target_arch = "x86_64")]
+use crate::vstate::serialize;
+
 /// Errors associated with the wrappers over KVM ioctls.
 #[derive(Debug)]
 pub enum Error {
@@ -374,22 +378,27 @@ impl Vm {
 }
 
 #[cfg(target_arch = "x86_64")]
-#[derive(Versionize)]
+#[derive(Serialize, Versionize)]
 /// Structure holding VM kvm state.
 // NOTICE: Any changes to this structure require a snapshot version bump.
 pub struct VmState {
+    #[serde(serialize_with = "serialize::pit_state")]
     pitstate: kvm_pit_state2,
+    #[serde(serialize_with = "serialize::clock")]
     clock: kvm_clock_data,
     // TODO: rename this field to adopt inclusive language once Linux updates it, too.
+    #[serde(serialize_with = "serialize::irqchip")]
     pic_master: kvm_irqchip,
     // TODO: rename this field to adopt inclusive language once Linux updates it, too.
+    #[serde(serialize_with = "serialize::irqchip")]
     pic_slave: kvm_irqchip,
+    #[serde(serialize_with = "serialize::irqchip")]
     ioapic: kvm_irqchip,
 }
 
 /// Structure holding an general specific VM state.
 #[cfg(target_arch = "aarch64")]
-#[derive(Default, Versionize)]
+#[derive(Default, Serialize, Versionize)]
 pub struct VmState {
     gic: GicState,
 }
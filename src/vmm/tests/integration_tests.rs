@@ -4,6 +4,7 @@ use std::io::{Seek, SeekFrom};
 use std::time::Duration;
 use std::{io, thread};
 
+use snapshot::diff_manifest::DiffManifest;
 use snapshot::Snapshot;
 use utils::tempfile::TempFile;
 use vmm::builder::{build_microvm_for_boot, build_microvm_from_snapshot, setup_serial_device};
@@ -17,7 +18,7 @@ use vmm::utilities::test_utils::dirty_tracking_vmm;
 use vmm::utilities::test_utils::{create_vmm, default_vmm};
 use vmm::version_map::VERSION_MAP;
 use vmm::vmm_config::instance_info::InstanceInfo;
-use vmm::vmm_config::snapshot::{CreateSnapshotParams, SnapshotType};
+use vmm::vmm_config::snapshot::{CreateSnapshotParams, MemFileFormat, SnapshotType};
 use vmm::{EventManager, FcExitCode};
 
 #[test]
@@ -142,9 +143,10 @@ fn test_disallow_snapshots_without_pausing() {
     vmm.lock().unwrap().stop(FcExitCode::Ok);
 }
 
-fn verify_create_snapshot(is_diff: bool) -> (TempFile, TempFile) {
+fn verify_create_snapshot(is_diff: bool, mem_file_format: MemFileFormat) -> (TempFile, TempFile) {
     let snapshot_file = TempFile::new().unwrap();
     let memory_file = TempFile::new().unwrap();
+    let manifest_file = TempFile::new().unwrap();
 
     let (vmm, _) = create_vmm(Some(NOISY_KERNEL_IMAGE), is_diff);
 
@@ -163,6 +165,11 @@ fn verify_create_snapshot(is_diff: bool) -> (TempFile, TempFile) {
         snapshot_type,
         snapshot_path: snapshot_file.as_path().to_path_buf(),
         mem_file_path: memory_file.as_path().to_path_buf(),
+        mem_file_format,
+        diff_manifest_path: match is_diff {
+            true => Some(manifest_file.as_path().to_path_buf()),
+            false => None,
+        },
         version: Some(String::from("0.24.0")),
     };
     let vm_info = VmInfo {
@@ -203,12 +210,36 @@ fn verify_create_snapshot(is_diff: bool) -> (TempFile, TempFile) {
     assert!(restored_microvm_state.device_states.vsock_device.is_none());
     assert_eq!(restored_microvm_state.vcpu_states.len(), 1);
 
+    // Check that the microVM state can be described as JSON.
+    let description = serde_json::to_value(&restored_microvm_state).unwrap();
+    assert_eq!(description["vm_info"]["mem_size_mib"], vm_info.mem_size_mib);
+    assert_eq!(description["vcpu_states"].as_array().unwrap().len(), 1);
+    assert!(description["device_states"]["block_devices"]
+        .as_array()
+        .unwrap()
+        .is_empty());
+
+    // Check that the diff manifest describes the guest memory of the microVM.
+    if is_diff {
+        let manifest_len = manifest_file.as_file().metadata().unwrap().len() as usize;
+        let manifest = DiffManifest::load(&mut manifest_file.as_file(), manifest_len).unwrap();
+        manifest.validate().unwrap();
+        let memory_regions = &restored_microvm_state.memory_state.regions;
+        assert_eq!(manifest.regions.len(), memory_regions.len());
+        for (dirty_pages, region) in manifest.regions.iter().zip(memory_regions.iter()) {
+            assert_eq!(dirty_pages.base_address, region.base_address);
+            assert_eq!(dirty_pages.offset, region.offset);
+            assert_eq!(dirty_pages.size, region.size as u64);
+        }
+        assert_ne!(manifest.dirty_pages(), 0);
+    }
+
     (snapshot_file, memory_file)
 }
 
 fn verify_load_snapshot(snapshot_file: TempFile, memory_file: TempFile) {
     use vm_memory::GuestMemoryMmap;
-    use vmm::memory_snapshot::SnapshotMemory;
+    use vmm::memory_snapshot::{is_compressed_memory_file, SnapshotMemory};
 
     let mut event_manager = EventManager::new().unwrap();
     let empty_seccomp_filters = get_filters(SeccompConfig::None).unwrap();
@@ -223,11 +254,19 @@ fn verify_load_snapshot(snapshot_file: TempFile, memory_file: TempFile) {
         VERSION_MAP.clone(),
     )
     .unwrap();
-    let mem = GuestMemoryMmap::restore(
-        Some(memory_file.as_file()),
-        &microvm_state.memory_state,
-        false,
-    )
+    let mem = if is_compressed_memory_file(memory_file.as_file()).unwrap() {
+        GuestMemoryMmap::restore_compressed(
+            &mut memory_file.as_file(),
+            &microvm_state.memory_state,
+            false,
+        )
+    } else {
+        GuestMemoryMmap::restore(
+            Some(memory_file.as_file()),
+            &microvm_state.memory_state,
+            false,
+        )
+    }
     .unwrap();
 
     let vm_resources = &mut VmResources::default();
@@ -251,7 +290,7 @@ fn verify_load_snapshot(snapshot_file: TempFile, memory_file: TempFile) {
 #[test]
 fn test_create_and_load_snapshot() {
     // Create diff snapshot.
-    let (snapshot_file, memory_file) = verify_create_snapshot(true);
+    let (snapshot_file, memory_file) = verify_create_snapshot(true, MemFileFormat::Raw);
     // Create a new microVm from snapshot. This only tests code-level logic; it verifies
     // that a microVM can be built with no errors from given snapshot.
     // It does _not_ verify that the guest is actually restored properly. We're using
@@ -259,12 +298,80 @@ fn test_create_and_load_snapshot() {
     verify_load_snapshot(snapshot_file, memory_file);
 
     // Create full snapshot.
-    let (snapshot_file, memory_file) = verify_create_snapshot(false);
+    let (snapshot_file, memory_file) = verify_create_snapshot(false, MemFileFormat::Raw);
     // Create a new microVm from snapshot. This only tests code-level logic; it verifies
     // that a microVM can be built with no errors from given snapshot.
     // It does _not_ verify that the guest is actually restored properly. We're using
     // python integration tests for that.
     verify_load_snapshot(snapshot_file, memory_file);
+
+    // Create full snapshot with a compressed memory file.
+    let (snapshot_file, memory_file) = verify_create_snapshot(false, MemFileFormat::Compressed);
+    // The 1 MiB guest memory is mostly empty.
+    assert!(memory_file.as_file().metadata().unwrap().len() < 1 << 20);
+    verify_load_snapshot(snapshot_file, memory_file);
+}
+
+#[test]
+#[cfg(target_arch = "x86_64")]
+fn test_live_migration() {
+    use vmm::migration::{receive_migration, send_migration};
+    use vmm::vmm_config::instance_info::VmState;
+    use vmm::vmm_config::migration::{ReceiveMigrationParams, SendMigrationParams};
+
+    // The temporary file is removed right away, only its unique path is used.
+    let socket_path = TempFile::new().unwrap().as_path().to_path_buf();
+
+    let destination = {
+        let socket_path = socket_path.clone();
+        thread::spawn(move || {
+            let mut event_manager = EventManager::new().unwrap();
+            let empty_seccomp_filters = get_filters(SeccompConfig::None).unwrap();
+            let params = ReceiveMigrationParams {
+                socket_path: Some(socket_path),
+                socket_fd: None,
+                enable_diff_snapshots: false,
+                resume_vm: true,
+            };
+            let vmm = receive_migration(
+                &InstanceInfo::default(),
+                &mut event_manager,
+                &empty_seccomp_filters,
+                &params,
+                VERSION_MAP.clone(),
+                &mut VmResources::default(),
+            )
+            .unwrap();
+            assert_eq!(vmm.lock().unwrap().instance_info().state, VmState::Running);
+            vmm.lock().unwrap().stop(FcExitCode::Ok);
+        })
+    };
+    while !socket_path.exists() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // Migrate a running microVM.
+    let (vmm, _) = dirty_tracking_vmm(Some(NOISY_KERNEL_IMAGE));
+    thread::sleep(Duration::from_millis(200));
+    let vm_info = VmInfo {
+        mem_size_mib: 1u64,
+        ..Default::default()
+    };
+    send_migration(
+        &mut vmm.lock().unwrap(),
+        &vm_info,
+        &SendMigrationParams {
+            socket_path: Some(socket_path),
+            socket_fd: None,
+        },
+        VERSION_MAP.clone(),
+    )
+    .unwrap();
+    // The source microVM is left paused once the destination took over.
+    assert_eq!(vmm.lock().unwrap().instance_info().state, VmState::Paused);
+    vmm.lock().unwrap().stop(FcExitCode::Ok);
+
+    destination.join().unwrap();
 }
 
 #[test]
@@ -315,7 +422,7 @@ fn test_snapshot_load_sanity_checks() {
 
 fn get_microvm_state_from_snapshot() -> MicrovmState {
     // Create a diff snapshot
-    let (snapshot_file, _) = verify_create_snapshot(true);
+    let (snapshot_file, _) = verify_create_snapshot(true, MemFileFormat::Raw);
 
     // Deserialize the microVM state.
     let snapshot_file_metadata = snapshot_file.as_file().metadata().unwrap();
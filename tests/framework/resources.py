@@ -215,6 +215,7 @@ class Drive:
         rate_limiter=None,
         cache_type=None,
         io_engine=None,
+        refresh_size=None,
     ):
         """Compose the json associated to this type of API request."""
         datax = {}
@@ -243,6 +244,9 @@ class Drive:
         if io_engine is not None:
             datax["io_engine"] = io_engine
 
+        if refresh_size is not None:
+            datax["refresh_size"] = refresh_size
+
         return datax
 
 
@@ -643,6 +647,11 @@ class Network:
         rx_rate_limiter=None,
         tx_rate_limiter=None,
         allow_mmds_requests=None,
+        mtu=None,
+        link_up=None,
+        anti_spoofing=None,
+        backend_type=None,
+        user_backend=None,
     ):
         """Create the json for the net specific API request."""
         datax = {"iface_id": iface_id}
@@ -659,6 +668,21 @@ class Network:
         if rx_rate_limiter is not None:
             datax["rx_rate_limiter"] = rx_rate_limiter
 
+        if mtu is not None:
+            datax["mtu"] = mtu
+
+        if link_up is not None:
+            datax["link_up"] = link_up
+
+        if anti_spoofing is not None:
+            datax["anti_spoofing"] = anti_spoofing
+
+        if backend_type is not None:
+            datax["backend_type"] = backend_type
+
+        if user_backend is not None:
+            datax["user_backend"] = user_backend
+
         # Keep this for interacting with older FC versions in snapshot tests.
         if allow_mmds_requests is not None:
             datax["allow_mmds_requests"] = allow_mmds_requests
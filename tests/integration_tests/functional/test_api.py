@@ -954,6 +954,11 @@ def _drive_patch(test_microvm):
     assert test_microvm.api_session.is_status_bad_request(response.status_code)
     assert "No such file or directory" in response.text
 
+    # Refreshing the size of the drive after growing its backing file is allowed.
+    os.truncate(fs.path, os.path.getsize(fs.path) + 1024 * 1024)
+    response = test_microvm.drive.patch(drive_id="scratch", refresh_size=True)
+    assert test_microvm.api_session.is_status_no_content(response.status_code)
+
     # Validate full vm configuration after patching drives.
     response = test_microvm.full_cfg.get()
     assert test_microvm.api_session.is_status_ok(response.status_code)
@@ -966,6 +971,9 @@ def _drive_patch(test_microvm):
             "is_read_only": False,
             "cache_type": "Unsafe",
             "io_engine": "Sync",
+            "num_queues": 1,
+            "image_format": "Raw",
+            "overlay_path_on_host": None,
             "rate_limiter": None,
         },
         {
@@ -976,6 +984,9 @@ def _drive_patch(test_microvm):
             "is_read_only": False,
             "cache_type": "Unsafe",
             "io_engine": "Async" if is_io_uring_supported() else "Sync",
+            "num_queues": 1,
+            "image_format": "Raw",
+            "overlay_path_on_host": None,
             "rate_limiter": {
                 "bandwidth": {"size": 5000, "one_time_burst": None, "refill_time": 100},
                 "ops": {"size": 500, "one_time_burst": None, "refill_time": 100},
@@ -1217,6 +1228,9 @@ def test_get_full_config_after_restoring_snapshot(bin_cloner_path):
             "cache_type": "Unsafe",
             "rate_limiter": None,
             "io_engine": "Sync",
+            "num_queues": 1,
+            "image_format": "Raw",
+            "overlay_path_on_host": None,
         }
     ]
 
@@ -1264,6 +1278,11 @@ def test_get_full_config_after_restoring_snapshot(bin_cloner_path):
             "host_dev_name": DEFAULT_TAP_NAME,
             "rx_rate_limiter": None,
             "tx_rate_limiter": tx_rl,
+            "num_queue_pairs": 1,
+            "mtu": None,
+            "anti_spoofing": None,
+            "backend_type": "Tap",
+            "user_backend": None,
         }
     ]
     # Create a snapshot builder from a microvm.
@@ -1334,6 +1353,9 @@ def test_get_full_config(test_microvm_with_api):
             "cache_type": "Unsafe",
             "rate_limiter": None,
             "io_engine": "Sync",
+            "num_queues": 1,
+            "image_format": "Raw",
+            "overlay_path_on_host": None,
         }
     ]
 
@@ -1374,6 +1396,11 @@ def test_get_full_config(test_microvm_with_api):
             "guest_mac": "06:00:00:00:00:01",
             "rx_rate_limiter": None,
             "tx_rate_limiter": tx_rl,
+            "num_queue_pairs": 1,
+            "mtu": None,
+            "anti_spoofing": None,
+            "backend_type": "Tap",
+            "user_backend": None,
         }
     ]
 
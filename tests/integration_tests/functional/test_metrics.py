@@ -37,16 +37,20 @@ def test_flush_metrics(test_microvm_with_api):
         "api_server",
         "balloon",
         "block",
+        "block_drives",
         "deprecated_api",
         "get_api_requests",
         "i8042",
         "latencies_us",
         "logger",
+        "migration",
         "mmds",
         "net",
         "patch_api_requests",
         "put_api_requests",
         "seccomp",
+        "snapshot",
+        "user_net",
         "vcpu",
         "vmm",
         "uart",
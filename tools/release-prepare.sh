@@ -55,6 +55,7 @@ files_to_change=(
     "$FC_ROOT_DIR/src/firecracker/Cargo.toml"
     "$FC_ROOT_DIR/src/jailer/Cargo.toml"
     "$FC_ROOT_DIR/src/rebase-snap/Cargo.toml"
+    "$FC_ROOT_DIR/src/uffd-handler/Cargo.toml"
     "$FC_ROOT_DIR/src/seccompiler/Cargo.toml"
 )
 say "Updating source files:"